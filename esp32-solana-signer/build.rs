@@ -1,3 +1,37 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 fn main() {
+    // Vendor key for verifying OTA images (feature `ota`)
+    println!("cargo:rerun-if-env-changed=ESP32_SIGNER_OTA_PUBKEY");
+
+    // Commands queued behind a busy one before the rest get ERROR:BUSY
+    println!("cargo:rerun-if-env-changed=ESP32_SIGNER_QUEUE_DEPTH");
+    let queue_depth = std::env::var("ESP32_SIGNER_QUEUE_DEPTH").unwrap_or_else(|_| "4".into());
+    match queue_depth.parse::<usize>() {
+        Ok(1..=16) => {}
+        _ => panic!("ESP32_SIGNER_QUEUE_DEPTH must be 1-16, got {:?}", queue_depth),
+    }
+    println!("cargo:rustc-env=SIGNER_QUEUE_DEPTH={}", queue_depth);
+
+    // Build metadata reported by GET_INFO
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let built = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    println!("cargo:rustc-env=SIGNER_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=SIGNER_BUILD_TIMESTAMP={}", built);
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+    println!("cargo:rerun-if-changed=src");
+
     embuild::espidf::sysenv::output();
 }
@@ -0,0 +1,77 @@
+//! The on-device audit log (`signer_core::audit`, `audit` feature): an entry
+//! for every signature and setting change, and AUDIT_EXPORT, signed by the
+//! device key and sealed to the auditor registered with AUDIT_REGISTER.
+//!
+//! Recording never stands in the way of the action it records: a failed
+//! append is logged, and the gap shows as a missing sequence number.
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::{debug, error};
+use signer_core::audit::{self, Event, Export, AUDITOR_KEY_NAME};
+use signer_core::platform::{EntropySource, Storage};
+
+use crate::crypto::Signer;
+use crate::platform::NvsStorage;
+
+/// Adds an entry for `event`, with the digest of `subject` (zeros without
+/// one).
+pub fn record(nvs: &mut EspNvs<NvsDefault>, event: Event, subject: Option<&[u8]>) {
+    let digest = subject.map_or([0; 32], audit::digest);
+    match audit::append(
+        &mut NvsStorage(nvs),
+        crate::device_unix_time(),
+        event,
+        digest,
+    ) {
+        Ok(entry) => debug!("Audit entry {}: {}", entry.seq, event.name()),
+        Err(e) => error!("Audit entry not stored: {:?}", e),
+    }
+}
+
+/// Stores the auditor's X25519 key; later exports are sealed to it.
+pub fn register(nvs: &mut EspNvs<NvsDefault>, auditor: &[u8; 32]) -> Result<()> {
+    NvsStorage(nvs).set(AUDITOR_KEY_NAME, auditor)?;
+    record(nvs, Event::AuditorSet, Some(auditor));
+    Ok(())
+}
+
+fn auditor(nvs: &mut EspNvs<NvsDefault>) -> Result<Option<[u8; 32]>> {
+    let mut key = [0u8; 32];
+    match NvsStorage(nvs).get(AUDITOR_KEY_NAME, &mut key)? {
+        None => Ok(None),
+        Some(32) => Ok(Some(key)),
+        Some(_) => Err(anyhow!("auditor key is not 32 bytes")),
+    }
+}
+
+/// The kept entries, signed and sealed to the auditor; `None` until an
+/// auditor is registered.
+pub fn export<R: EntropySource>(
+    signer: &Signer,
+    nvs: &mut EspNvs<NvsDefault>,
+    rng: &mut R,
+) -> Result<Option<Vec<u8>>>
+where
+    R::Error: core::fmt::Debug,
+{
+    let Some(auditor) = auditor(nvs)? else {
+        return Ok(None);
+    };
+    let log = audit::read(&mut NvsStorage(nvs)).map_err(|e| anyhow!("audit log: {:?}", e))?;
+    let mut export = Export {
+        device: signer.pubkey(),
+        start: log.start,
+        entries: log.entries,
+        signature: [0; audit::SIGNATURE_LEN],
+    };
+    let message = export
+        .signed_message::<()>()
+        .map_err(|e| anyhow!("audit log: {:?}", e))?;
+    export.signature = signer.sign(message)?;
+
+    let mut ephemeral = [0u8; 32];
+    rng.fill(&mut ephemeral)
+        .map_err(|e| anyhow!("entropy: {:?}", e))?;
+    Ok(Some(audit::seal(&export.to_bytes(), &auditor, ephemeral)))
+}
@@ -0,0 +1,172 @@
+//! Encrypted seed and policy backups on an SPI SD card (`sd-backup` feature).
+//!
+//! The file layout, KDF and contents live in `signer_core::backup`; this
+//! module mounts the card, seals with ChaCha20-Poly1305 and does the file IO.
+//! The card is mounted only for the duration of one command, so it can be
+//! inserted and removed while the signer runs.
+//!
+//! Wiring (SPI2 on its IO MUX pins): SCK GPIO6, MOSI GPIO7, MISO GPIO2,
+//! CS GPIO10.
+
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use esp_idf_svc::fs::fatfs::Fatfs;
+use esp_idf_svc::hal::gpio::{AnyIOPin, AnyOutputPin, Gpio10, Gpio2, Gpio6, Gpio7};
+use esp_idf_svc::hal::sd::{spi::SdSpiHostDriver, SdCardConfiguration, SdCardDriver};
+use esp_idf_svc::hal::spi::{config::DriverConfig, SpiDriver, SPI2};
+use esp_idf_svc::io::vfs::MountedFatfs;
+use signer_core::backup::{self, BackupError, Contents, Header};
+use signer_core::platform::EntropySource;
+use std::fs;
+use std::path::Path;
+
+pub const MOUNT_POINT: &str = "/sdcard";
+
+/// Why a restore failed, as the `ERROR:RESTORE:<detail>` detail.
+#[derive(Debug)]
+pub enum RestoreError {
+    NoCard(anyhow::Error),
+    NoBackup,
+    /// Not a backup, or one this firmware can't read.
+    Format(BackupError),
+    /// Wrong PIN or passphrase, or a tampered file: AEAD can't tell them apart.
+    BadCredentials,
+    Io(anyhow::Error),
+}
+
+impl RestoreError {
+    pub fn detail(&self) -> &'static str {
+        match self {
+            RestoreError::NoCard(_) => "NO_CARD",
+            RestoreError::NoBackup => "NO_BACKUP",
+            RestoreError::Format(BackupError::UnsupportedVersion(_)) => "VERSION",
+            RestoreError::Format(_) => "CORRUPT",
+            RestoreError::BadCredentials => "BAD_CREDENTIALS",
+            RestoreError::Io(_) => "STORAGE",
+        }
+    }
+}
+
+pub struct SdCard {
+    spi: SpiDriver<'static>,
+    cs: AnyOutputPin,
+}
+
+impl SdCard {
+    pub fn new(spi: SPI2, sck: Gpio6, mosi: Gpio7, miso: Gpio2, cs: Gpio10) -> Result<Self> {
+        let spi = SpiDriver::new(spi, sck, mosi, Some(miso), &DriverConfig::default())?;
+        Ok(SdCard { spi, cs: cs.into() })
+    }
+
+    /// Mounts the card's FAT filesystem at [`MOUNT_POINT`] until dropped.
+    pub fn mount(
+        &mut self,
+    ) -> Result<MountedFatfs<Fatfs<SdCardDriver<SdSpiHostDriver<'_, &SpiDriver<'static>>>>>> {
+        let host = SdSpiHostDriver::new(
+            &self.spi,
+            Some(&mut self.cs),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            None,
+        )?;
+        let card =
+            SdCardDriver::new_spi(host, &SdCardConfiguration::new()).context("no SD card")?;
+        Ok(MountedFatfs::mount(
+            Fatfs::new_sdcard(0, card)?,
+            MOUNT_POINT,
+            4,
+        )?)
+    }
+
+    /// Seals `contents` under `pin` and `passphrase` and writes it as the
+    /// next backup on the card; returns the file name. The file is read back
+    /// before this returns.
+    pub fn backup<R: EntropySource>(
+        &mut self,
+        pin: &str,
+        passphrase: &str,
+        contents: &Contents,
+        rng: &mut R,
+    ) -> Result<String>
+    where
+        R::Error: core::fmt::Debug,
+    {
+        let mut header = Header {
+            iterations: backup::DEFAULT_ITERATIONS,
+            salt: [0; backup::SALT_LEN],
+            nonce: [0; backup::NONCE_LEN],
+        };
+        rng.fill(&mut header.salt)
+            .and_then(|_| rng.fill(&mut header.nonce))
+            .map_err(|e| anyhow!("entropy: {:?}", e))?;
+        let header_bytes = header.to_bytes();
+        let key = backup::derive_key(pin, passphrase, &header);
+        let plaintext = contents.to_bytes();
+        let sealed = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt(
+                Nonce::from_slice(&header.nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &header_bytes,
+                },
+            )
+            .map_err(|_| anyhow!("sealing failed"))?;
+        let mut file = header_bytes.to_vec();
+        file.extend_from_slice(&sealed);
+
+        let _mounted = self.mount()?;
+        let names = list(MOUNT_POINT)?;
+        let seq = backup::latest(names.iter().map(String::as_str)).map_or(1, |(seq, _)| seq + 1);
+        let name = backup::file_name(seq);
+        let path = Path::new(MOUNT_POINT).join(&name);
+        write_whole(&path, &file)?;
+        if fs::read(&path)? != file {
+            return Err(anyhow!("{} doesn't read back as written", name));
+        }
+        Ok(name)
+    }
+
+    /// Opens the newest backup on the card with `pin` and `passphrase`.
+    pub fn restore(&mut self, pin: &str, passphrase: &str) -> Result<Contents, RestoreError> {
+        let _mounted = self.mount().map_err(RestoreError::NoCard)?;
+        let names = list(MOUNT_POINT).map_err(RestoreError::Io)?;
+        let (_, name) =
+            backup::latest(names.iter().map(String::as_str)).ok_or(RestoreError::NoBackup)?;
+        let file =
+            fs::read(Path::new(MOUNT_POINT).join(name)).map_err(|e| RestoreError::Io(e.into()))?;
+
+        let (header, sealed) = Header::parse(&file).map_err(RestoreError::Format)?;
+        let key = backup::derive_key(pin, passphrase, &header);
+        let plaintext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(
+                Nonce::from_slice(&header.nonce),
+                Payload {
+                    msg: sealed,
+                    aad: &file[..backup::HEADER_LEN],
+                },
+            )
+            .map_err(|_| RestoreError::BadCredentials)?;
+        Contents::from_bytes(&plaintext).map_err(RestoreError::Format)
+    }
+}
+
+/// Writes `contents` under a temporary name first, then renames it to
+/// `path`, so a card pulled mid-write never leaves a truncated file under a
+/// name that counts.
+pub fn write_whole(path: &Path, contents: &[u8]) -> Result<()> {
+    let partial = path.with_extension("TMP");
+    fs::write(&partial, contents).with_context(|| format!("write {}", partial.display()))?;
+    fs::rename(&partial, path).with_context(|| format!("rename to {}", path.display()))
+}
+
+pub fn list(dir: &str) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("list {}", dir))? {
+        if let Some(name) = entry?.file_name().to_str() {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
@@ -0,0 +1,65 @@
+use crate::hwsha;
+use ed25519_dalek::{Signer, SigningKey};
+use sha2::{Digest, Sha256, Sha512};
+use std::time::Instant;
+
+const SIGN_ROUNDS: u32 = 20;
+
+/// Roughly a typical transfer message.
+const MESSAGE_LEN: usize = 256;
+
+const HASH_BYTES: usize = 16 * 1024;
+
+fn per_round_us(rounds: u32, mut f: impl FnMut()) -> u128 {
+    let start = Instant::now();
+    for _ in 0..rounds {
+        f();
+    }
+    start.elapsed().as_micros() / u128::from(rounds)
+}
+
+fn kib_per_sec(bytes: usize, mut f: impl FnMut()) -> u128 {
+    let start = Instant::now();
+    f();
+    let us = start.elapsed().as_micros().max(1);
+    bytes as u128 * 1_000_000 / 1024 / us
+}
+
+/// Fields of the BENCHMARK response (`Response::Benchmark`):
+/// `sign_us=..;verify_us=..;sha256_hw_kib_s=..;sha256_sw_kib_s=..;sha512_sw_kib_s=..`
+///
+/// Signing uses a throwaway key, never the device key, so no signature the
+/// host could reuse is ever produced. Takes around half a second.
+pub fn benchmark_fields() -> String {
+    let key = SigningKey::from_bytes(&[0x42; 32]);
+    let message = [0x5a; MESSAGE_LEN];
+    let signature = key.sign(&message);
+    let verifying_key = key.verifying_key();
+
+    let sign_us = per_round_us(SIGN_ROUNDS, || {
+        core::hint::black_box(key.sign(&message));
+    });
+    let verify_us = per_round_us(SIGN_ROUNDS, || {
+        let _ = core::hint::black_box(verifying_key.verify_strict(&message, &signature));
+    });
+
+    let data = vec![0xa5u8; HASH_BYTES];
+    let sha256_hw = match hwsha::sha256(&data) {
+        Ok(_) => kib_per_sec(HASH_BYTES, || {
+            let _ = core::hint::black_box(hwsha::sha256(&data));
+        })
+        .to_string(),
+        Err(_) => "na".to_string(),
+    };
+    let sha256_sw = kib_per_sec(HASH_BYTES, || {
+        core::hint::black_box(Sha256::digest(&data));
+    });
+    let sha512_sw = kib_per_sec(HASH_BYTES, || {
+        core::hint::black_box(Sha512::digest(&data));
+    });
+
+    format!(
+        "sign_us={};verify_us={};sha256_hw_kib_s={};sha256_sw_kib_s={};sha512_sw_kib_s={}",
+        sign_us, verify_us, sha256_hw, sha256_sw, sha512_sw
+    )
+}
@@ -0,0 +1,451 @@
+//! BLE GATT server for the companion profile (`signer_protocol::ble`).
+//!
+//! Requests written by a phone are reassembled, turned into the serial
+//! protocol's command lines and queued in the same inbox as the UART's, so
+//! the dispatcher applies pairing, 2FA, policy and the BOOT button exactly as
+//! it does for the serial link. Its responses come back through `Port::send`
+//! and leave here as compact replies. One phone is served at a time;
+//! advertising stops while it is connected.
+//!
+//! Needs Bluetooth in the ESP-IDF config: build with
+//! `ESP_IDF_SDKCONFIG_DEFAULTS="sdkconfig.defaults;sdkconfig.ble"`.
+
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use enumset::enum_set;
+use esp_idf_svc::bt::ble::gap::{AdvConfiguration, BleGapEvent, EspBleGap};
+use esp_idf_svc::bt::ble::gatt::server::{ConnectionId, EspGatts, GattsEvent};
+use esp_idf_svc::bt::ble::gatt::{
+    AutoResponse, GattCharacteristic, GattDescriptor, GattId, GattInterface, GattServiceId,
+    GattStatus, Handle, Permission, Property,
+};
+use esp_idf_svc::bt::{Ble, BtDriver, BtStatus, BtUuid};
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::{info, warn};
+use signer_core::token_labels::TokenLabel;
+use signer_core::tx_introspection::{self, TransactionInfo};
+use signer_protocol::ble::{
+    fragments, Reassembler, Reply, Request, Status, DEFAULT_MTU, MAX_MESSAGE_LEN, REPLY_UUID,
+    REQUEST_UUID, SERVICE_UUID, STATUS_UUID,
+};
+use signer_protocol::command::Command;
+use signer_protocol::error::ErrorCode;
+use signer_protocol::response::Response;
+
+use crate::codec;
+use crate::serial::Link;
+
+const APP_ID: u16 = 0;
+/// Client Characteristic Configuration, where a client turns notifications on
+const CCCD_UUID: u16 = 0x2902;
+/// Attribute handles the service needs: itself, three characteristics with
+/// their values, and two CCCDs
+const SERVICE_HANDLES: u16 = 9;
+/// Longest characteristic value, one fragment at the largest MTU
+const MAX_VALUE_LEN: usize = 512;
+const DEVICE_NAME: &str = "ESP32 Signer";
+/// Fragment header of a whole message, as `fragments` writes it
+const LAST_FRAGMENT: u8 = 0x80;
+
+type Driver = BtDriver<'static, Ble>;
+type Gap = EspBleGap<'static, Ble, Arc<Driver>>;
+type Gatts = EspGatts<'static, Ble, Arc<Driver>>;
+
+/// Handles learned as the service is built, and the connected phone
+#[derive(Default)]
+struct State {
+    gatt_if: Option<GattInterface>,
+    request: Option<Handle>,
+    reply: Option<Handle>,
+    reply_cccd: Option<Handle>,
+    status: Option<Handle>,
+    status_cccd: Option<Handle>,
+    peer: Option<Peer>,
+}
+
+struct Peer {
+    conn_id: ConnectionId,
+    mtu: usize,
+    replies_on: bool,
+    status_on: bool,
+}
+
+struct Inner {
+    gap: Gap,
+    gatts: Gatts,
+    lines: SyncSender<(Link, String)>,
+    state: Mutex<State>,
+    requests: Mutex<Reassembler<MAX_MESSAGE_LEN>>,
+}
+
+/// The running server; `Port` hands it the responses to BLE requests
+pub struct Server {
+    inner: Arc<Inner>,
+    _driver: Arc<Driver>,
+}
+
+impl Server {
+    /// Starts advertising the service; requests go to `lines`
+    pub fn start(
+        modem: Modem,
+        nvs: EspDefaultNvsPartition,
+        lines: SyncSender<(Link, String)>,
+    ) -> anyhow::Result<Self> {
+        let driver = Arc::new(BtDriver::new(modem, Some(nvs))?);
+        let inner = Arc::new(Inner {
+            gap: EspBleGap::new(driver.clone())?,
+            gatts: EspGatts::new(driver.clone())?,
+            lines,
+            state: Mutex::new(State::default()),
+            requests: Mutex::new(Reassembler::new()),
+        });
+
+        let gap = inner.clone();
+        inner.gap.subscribe(move |event| {
+            if let Err(e) = gap.on_gap_event(event) {
+                warn!("BLE GAP: {}", e);
+            }
+        })?;
+        let gatts = inner.clone();
+        inner.gatts.subscribe(move |(gatt_if, event)| {
+            if let Err(e) = gatts.on_gatts_event(gatt_if, event) {
+                warn!("BLE GATT: {}", e);
+            }
+        })?;
+        inner.gatts.register_app(APP_ID)?;
+        info!("BLE: advertising as {:?}", DEVICE_NAME);
+        Ok(Server {
+            inner,
+            _driver: driver,
+        })
+    }
+
+    /// Sends the dispatcher's response line as the reply to the current
+    /// request
+    pub fn send(&self, response: &str) {
+        let mut message = Vec::new();
+        if write_compact(response, &mut message).is_none() {
+            warn!("BLE: no compact form for {:?}", response);
+            message.clear();
+            Reply::Error(ErrorCode::UnknownCommand, None).write(&mut message);
+        }
+        self.inner
+            .notify(|state| state.reply, |peer| peer.replies_on, &message);
+    }
+
+    pub fn status(&self, status: Status) {
+        let mut message = Vec::new();
+        status.write(&mut message);
+        // What a read returns too, as a single fragment
+        if let Some(handle) = self.inner.state.lock().unwrap().status {
+            let mut value = vec![LAST_FRAGMENT];
+            value.extend_from_slice(&message);
+            if let Err(e) = self.inner.gatts.set_attr(handle, &value) {
+                warn!("BLE: status not stored: {}", e);
+            }
+        }
+        self.inner
+            .notify(|state| state.status, |peer| peer.status_on, &message);
+    }
+}
+
+impl Inner {
+    fn on_gap_event(&self, event: BleGapEvent) -> anyhow::Result<()> {
+        if let BleGapEvent::AdvertisingConfigured(status) = event {
+            check_bt(status)?;
+            self.gap.start_advertising()?;
+        }
+        Ok(())
+    }
+
+    fn on_gatts_event(&self, gatt_if: GattInterface, event: GattsEvent) -> anyhow::Result<()> {
+        match event {
+            GattsEvent::ServiceRegistered { status, app_id } if app_id == APP_ID => {
+                check_gatt(status)?;
+                self.state.lock().unwrap().gatt_if = Some(gatt_if);
+                self.gap.set_device_name(DEVICE_NAME)?;
+                self.gap.set_adv_conf(&AdvConfiguration {
+                    include_name: true,
+                    flag: 2,
+                    service_uuid: Some(BtUuid::uuid128(SERVICE_UUID)),
+                    ..Default::default()
+                })?;
+                self.gatts.create_service(
+                    gatt_if,
+                    &GattServiceId {
+                        id: GattId {
+                            uuid: BtUuid::uuid128(SERVICE_UUID),
+                            inst_id: 0,
+                        },
+                        is_primary: true,
+                    },
+                    SERVICE_HANDLES,
+                )?;
+            }
+            GattsEvent::ServiceCreated {
+                status,
+                service_handle,
+                ..
+            } => {
+                check_gatt(status)?;
+                self.gatts.start_service(service_handle)?;
+                self.add_characteristic(service_handle, REQUEST_UUID)?;
+            }
+            // One attribute at a time: a descriptor belongs to whichever
+            // characteristic was added last
+            GattsEvent::CharacteristicAdded {
+                status,
+                attr_handle,
+                service_handle,
+                char_uuid,
+            } => {
+                check_gatt(status)?;
+                if char_uuid == BtUuid::uuid128(REQUEST_UUID) {
+                    self.state.lock().unwrap().request = Some(attr_handle);
+                    self.add_characteristic(service_handle, REPLY_UUID)?;
+                } else {
+                    let mut state = self.state.lock().unwrap();
+                    if char_uuid == BtUuid::uuid128(REPLY_UUID) {
+                        state.reply = Some(attr_handle);
+                    } else if char_uuid == BtUuid::uuid128(STATUS_UUID) {
+                        state.status = Some(attr_handle);
+                    }
+                    drop(state);
+                    self.gatts.add_descriptor(
+                        service_handle,
+                        &GattDescriptor {
+                            uuid: BtUuid::uuid16(CCCD_UUID),
+                            permissions: enum_set!(Permission::Read | Permission::Write),
+                        },
+                    )?;
+                }
+            }
+            GattsEvent::DescriptorAdded {
+                status,
+                attr_handle,
+                service_handle,
+                ..
+            } => {
+                check_gatt(status)?;
+                let mut state = self.state.lock().unwrap();
+                if state.reply_cccd.is_none() {
+                    state.reply_cccd = Some(attr_handle);
+                    drop(state);
+                    self.add_characteristic(service_handle, STATUS_UUID)?;
+                } else {
+                    state.status_cccd = Some(attr_handle);
+                }
+            }
+            GattsEvent::PeerConnected { conn_id, .. } => {
+                info!("BLE: phone connected");
+                self.state.lock().unwrap().peer = Some(Peer {
+                    conn_id,
+                    mtu: DEFAULT_MTU,
+                    replies_on: false,
+                    status_on: false,
+                });
+            }
+            GattsEvent::PeerDisconnected { .. } => {
+                info!("BLE: phone disconnected");
+                self.state.lock().unwrap().peer = None;
+                self.gap.start_advertising()?;
+            }
+            GattsEvent::Mtu { conn_id, mtu } => {
+                if let Some(peer) = &mut self.state.lock().unwrap().peer {
+                    if peer.conn_id == conn_id {
+                        peer.mtu = mtu as usize;
+                    }
+                }
+            }
+            GattsEvent::Write { handle, value, .. } => self.on_write(handle, value),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn add_characteristic(&self, service: Handle, uuid: u128) -> anyhow::Result<()> {
+        let mut idle = vec![LAST_FRAGMENT];
+        Status::Idle.write(&mut idle);
+        let (permissions, properties, value) = match uuid {
+            REQUEST_UUID => (
+                enum_set!(Permission::Write),
+                enum_set!(Property::Write),
+                &[][..],
+            ),
+            REPLY_UUID => (
+                enum_set!(Permission::Read),
+                enum_set!(Property::Notify),
+                &[][..],
+            ),
+            _ => (
+                enum_set!(Permission::Read),
+                enum_set!(Property::Read | Property::Notify),
+                &idle[..],
+            ),
+        };
+        self.gatts.add_characteristic(
+            service,
+            &GattCharacteristic {
+                uuid: BtUuid::uuid128(uuid),
+                permissions,
+                properties,
+                max_len: MAX_VALUE_LEN,
+                auto_rsp: AutoResponse::ByGatt,
+            },
+            value,
+        )?;
+        Ok(())
+    }
+
+    fn on_write(&self, handle: Handle, value: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let (reply_cccd, status_cccd, request) =
+            (state.reply_cccd, state.status_cccd, state.request);
+        let Some(peer) = &mut state.peer else {
+            return;
+        };
+        // Notifications on or off: bit 0 of the CCCD value
+        let enabled = value.first().is_some_and(|flags| flags & 1 != 0);
+        if Some(handle) == reply_cccd {
+            peer.replies_on = enabled;
+            return;
+        }
+        if Some(handle) == status_cccd {
+            peer.status_on = enabled;
+            return;
+        }
+        if Some(handle) != request {
+            return;
+        }
+        drop(state);
+
+        let line = {
+            let mut requests = self.requests.lock().unwrap();
+            match requests.push(value) {
+                Ok(Some(message)) => match Request::parse(message) {
+                    Some(request) => Ok(command_line(&request)),
+                    None => Err(ErrorCode::UnknownCommand),
+                },
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("BLE: dropped a request: {:?}", e);
+                    Err(ErrorCode::BadArguments)
+                }
+            }
+        };
+        let refusal = match line {
+            Ok(line) => match self.lines.try_send((Link::Ble, line)) {
+                Ok(()) => return,
+                Err(TrySendError::Full(_)) => ErrorCode::Busy,
+                Err(TrySendError::Disconnected(_)) => return,
+            },
+            Err(code) => code,
+        };
+        let mut message = Vec::new();
+        Reply::Error(refusal, None).write(&mut message);
+        self.notify(|state| state.reply, |peer| peer.replies_on, &message);
+    }
+
+    /// Sends `message` in fragments on the characteristic `handle` picks, if
+    /// the phone asked for its notifications
+    fn notify(
+        &self,
+        handle: impl Fn(&State) -> Option<Handle>,
+        enabled: impl Fn(&Peer) -> bool,
+        message: &[u8],
+    ) {
+        let state = self.state.lock().unwrap();
+        let (Some(gatt_if), Some(handle), Some(peer)) =
+            (state.gatt_if, handle(&state), &state.peer)
+        else {
+            return;
+        };
+        if !enabled(peer) {
+            warn!("BLE: notifications are off; a message was dropped");
+            return;
+        }
+        for (header, chunk) in fragments(message, peer.mtu) {
+            let mut fragment = Vec::with_capacity(chunk.len() + 1);
+            fragment.push(header);
+            fragment.extend_from_slice(chunk);
+            if let Err(e) = self.gatts.notify(gatt_if, peer.conn_id, handle, &fragment) {
+                warn!("BLE: notification failed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// The serial command line a request stands for
+fn command_line(request: &Request) -> String {
+    match request {
+        Request::Pubkey => Command::GetPubkey.to_string(),
+        Request::Sign(message) => Command::Sign(&codec::encode_binary(message)).to_string(),
+        Request::PairBegin(key) => Command::PairBegin(&codec::encode_key(key)).to_string(),
+        Request::PairConfirm => Command::PairConfirm.to_string(),
+        Request::AuthBegin(nonce) => Command::AuthBegin(&codec::encode_binary(*nonce)).to_string(),
+        Request::AuthProof(signature) => {
+            Command::AuthProof(&codec::encode_binary(*signature)).to_string()
+        }
+    }
+}
+
+/// Writes the compact form of a response line to one of the commands
+/// above; `None` for a response that has none
+fn write_compact(response: &str, out: &mut Vec<u8>) -> Option<()> {
+    match Response::parse(response)? {
+        Response::Pubkey(key) => Reply::Pubkey(&codec::decode_key(key)?).write(out),
+        Response::Signature(signature) => Reply::Signature(&fixed(signature)?).write(out),
+        Response::Pairing(key) => Reply::Pairing(&codec::decode_key(key)?).write(out),
+        Response::Paired => Reply::Paired.write(out),
+        Response::AuthChallenge { nonce, signature } => Reply::AuthChallenge {
+            nonce: &fixed(nonce)?,
+            signature: &fixed(signature)?,
+        }
+        .write(out),
+        Response::Authenticated => Reply::Authenticated.write(out),
+        Response::Error(code) => Reply::Error(code, None).write(out),
+        Response::ErrorDetail(code, detail) => Reply::Error(code, Some(detail)).write(out),
+        _ => return None,
+    }
+    Some(())
+}
+
+/// A binary field of a known size
+fn fixed<const N: usize>(encoded: &str) -> Option<[u8; N]> {
+    codec::decode_binary(encoded)?.try_into().ok()
+}
+
+/// One line for the status characteristic: the instructions the device
+/// decoded, labeled transfers in their token's symbol, or the size of a
+/// message it couldn't
+pub fn summary(
+    tx_info: Option<&TransactionInfo>,
+    labels: &[TokenLabel],
+    message_len: usize,
+) -> String {
+    match tx_info {
+        Some(info) => tx_introspection::format_labeled_transaction_info(info, labels)
+            .lines()
+            .filter(|line| line.starts_with('#') || line.starts_with("WARNING"))
+            .collect::<Vec<_>>()
+            .join("; "),
+        None => format!("Message of {} bytes", message_len),
+    }
+}
+
+fn check_bt(status: BtStatus) -> anyhow::Result<()> {
+    match status {
+        BtStatus::Success => Ok(()),
+        status => Err(anyhow::anyhow!("{:?}", status)),
+    }
+}
+
+fn check_gatt(status: GattStatus) -> anyhow::Result<()> {
+    match status {
+        GattStatus::Ok => Ok(()),
+        status => Err(anyhow::anyhow!("{:?}", status)),
+    }
+}
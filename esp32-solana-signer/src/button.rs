@@ -0,0 +1,40 @@
+//! Short BOOT presses while the dispatcher is idle: they cancel a pending
+//! policy change or, with `presence`, send a presence token. Presses that
+//! confirm a command are theirs alone: the button has to be seen released
+//! while idle before a press counts, and holding it longer than `MAX_PRESS`
+//! gives nothing.
+
+use std::time::{Duration, Instant};
+
+/// Longest press that still counts as a touch
+const MAX_PRESS: Duration = Duration::from_millis(1500);
+
+/// Tracks the button between idle samples
+#[derive(Default)]
+pub struct Press {
+    /// The button has been seen released since the last command
+    armed: bool,
+    since: Option<Instant>,
+}
+
+impl Press {
+    /// Feeds one sample; true when a short press has just been released
+    pub fn sample(&mut self, pressed: bool) -> bool {
+        match (pressed, self.since) {
+            (true, None) if self.armed => self.since = Some(Instant::now()),
+            (false, Some(since)) => {
+                self.since = None;
+                return since.elapsed() <= MAX_PRESS;
+            }
+            (false, None) => self.armed = true,
+            _ => {}
+        }
+        false
+    }
+
+    /// Forgets any press in progress, after a command that may have waited
+    /// for the button
+    pub fn reset(&mut self) {
+        *self = Press::default();
+    }
+}
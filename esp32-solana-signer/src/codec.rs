@@ -0,0 +1,58 @@
+//! Encodings used on the serial link. Base64 (payloads) and base58 (keys) are
+//! cargo features, on by default; builds without them fall back to hex, and
+//! GET_FEATURES tells the host which encodings this image speaks.
+
+#[cfg(feature = "base64")]
+use base64::Engine;
+use signer_core::encoding::{self, KEY_ENCODING};
+
+/// Encoding of transactions, messages, signatures, and OTA data.
+pub const BINARY_ENCODING: &str = if cfg!(feature = "base64") {
+    "base64"
+} else {
+    "hex"
+};
+
+pub fn encode_binary(bytes: &[u8]) -> String {
+    #[cfg(feature = "base64")]
+    {
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    }
+    #[cfg(not(feature = "base64"))]
+    {
+        encoding::hex_encode(bytes)
+    }
+}
+
+pub fn decode_binary(s: &str) -> Option<Vec<u8>> {
+    #[cfg(feature = "base64")]
+    {
+        base64::engine::general_purpose::STANDARD.decode(s).ok()
+    }
+    #[cfg(not(feature = "base64"))]
+    {
+        encoding::hex_decode(s)
+    }
+}
+
+pub fn encode_key(key: &[u8; 32]) -> String {
+    encoding::key_to_string(key)
+}
+
+pub fn decode_key(s: &str) -> Option<[u8; 32]> {
+    encoding::key_from_str(s)
+}
+
+/// Fields of the GET_FEATURES response (`Response::Features`):
+/// `binary=<base64|hex>;keys=<base58|hex>;otp_secret=<base32|none>`
+pub fn features_fields() -> String {
+    let otp_secret = if cfg!(feature = "twofa") {
+        "base32"
+    } else {
+        "none"
+    };
+    format!(
+        "binary={};keys={};otp_secret={}",
+        BINARY_ENCODING, KEY_ENCODING, otp_secret
+    )
+}
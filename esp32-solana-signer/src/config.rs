@@ -0,0 +1,90 @@
+//! Signing policy persisted across reboots, journaled over two NVS slots so a
+//! torn write leaves the previous policy in force and a bad push can be undone
+//! with POLICY_ROLLBACK. A loosening change waiting out its delay is kept
+//! under its own key, with the powered-on seconds it still has to wait.
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use signer_core::journal::{self, Slots, MAX_PAYLOAD};
+use signer_core::platform::Storage;
+use signer_core::policy::{PendingChange, Policy};
+
+use crate::platform::NvsStorage;
+
+const POLICY_SLOTS: Slots = Slots("policy_a", "policy_b");
+
+/// Remaining seconds (u32 LE), then the policy record; empty when nothing is
+/// pending. A torn write loses the pending change, never the policy in force.
+const PENDING_KEY: &str = "policy_pend";
+
+/// The stored policy, or the permissive default if none was ever pushed.
+pub fn load_policy(nvs: &mut EspNvs<NvsDefault>) -> Result<Policy> {
+    let mut buf = [0u8; MAX_PAYLOAD];
+    match journal::load(&mut NvsStorage(nvs), &POLICY_SLOTS, &mut buf) {
+        Ok(Some(len)) => decode(&buf[..len]),
+        Ok(None) => Ok(Policy::default()),
+        Err(e) => Err(anyhow!("policy unreadable: {:?}", e)),
+    }
+}
+
+pub fn store_policy(nvs: &mut EspNvs<NvsDefault>, policy: &Policy) -> Result<()> {
+    journal::store(&mut NvsStorage(nvs), &POLICY_SLOTS, &policy.to_bytes())
+        .map_err(|e| anyhow!("policy not saved: {:?}", e))
+}
+
+/// Reinstate the policy from before the last change. `None` if there is no
+/// earlier copy to go back to.
+pub fn rollback_policy(nvs: &mut EspNvs<NvsDefault>) -> Result<Option<Policy>> {
+    let mut buf = [0u8; MAX_PAYLOAD];
+    match journal::rollback(&mut NvsStorage(nvs), &POLICY_SLOTS, &mut buf) {
+        Ok(Some(len)) => decode(&buf[..len]).map(Some),
+        Ok(None) => Ok(None),
+        Err(e) => Err(anyhow!("rollback failed: {:?}", e)),
+    }
+}
+
+/// The copy POLICY_ROLLBACK would reinstate, left where it is.
+pub fn previous_policy(nvs: &mut EspNvs<NvsDefault>) -> Result<Option<Policy>> {
+    let mut buf = [0u8; MAX_PAYLOAD];
+    match journal::previous(&mut NvsStorage(nvs), &POLICY_SLOTS, &mut buf) {
+        Ok(Some(len)) => decode(&buf[..len]).map(Some),
+        Ok(None) => Ok(None),
+        Err(e) => Err(anyhow!("previous policy unreadable: {:?}", e)),
+    }
+}
+
+pub fn load_pending(nvs: &mut EspNvs<NvsDefault>) -> Result<Option<PendingChange>> {
+    let mut buf = [0u8; 4 + MAX_PAYLOAD];
+    let len = NvsStorage(nvs)
+        .get(PENDING_KEY, &mut buf)
+        .map_err(|e| anyhow!("pending policy unreadable: {:?}", e))?;
+    match len {
+        None | Some(0) => Ok(None),
+        Some(len) if (5..=buf.len()).contains(&len) => {
+            let (remaining, record) = buf[..len].split_at(4);
+            Ok(Some(PendingChange {
+                policy: decode(record)?,
+                remaining_secs: u32::from_le_bytes(remaining.try_into()?),
+            }))
+        }
+        Some(_) => Err(anyhow!("pending policy record unrecognised")),
+    }
+}
+
+pub fn store_pending(nvs: &mut EspNvs<NvsDefault>, pending: &PendingChange) -> Result<()> {
+    let mut record = pending.remaining_secs.to_le_bytes().to_vec();
+    record.extend_from_slice(&pending.policy.to_bytes());
+    NvsStorage(nvs)
+        .set(PENDING_KEY, &record)
+        .map_err(|e| anyhow!("pending policy not saved: {:?}", e))
+}
+
+pub fn clear_pending(nvs: &mut EspNvs<NvsDefault>) -> Result<()> {
+    NvsStorage(nvs)
+        .set(PENDING_KEY, &[])
+        .map_err(|e| anyhow!("pending policy not cleared: {:?}", e))
+}
+
+fn decode(bytes: &[u8]) -> Result<Policy> {
+    Policy::from_bytes(bytes).ok_or_else(|| anyhow!("policy record unrecognised"))
+}
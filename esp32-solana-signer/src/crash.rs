@@ -0,0 +1,127 @@
+use esp_idf_svc::hal::uart::{Uart, UART0};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use esp_idf_sys as sys;
+use log::error;
+use std::ffi::CString;
+
+const CRASH_COUNT_KEY: &str = "crash_count"; // u32
+const LAST_PANIC_KEY: &str = "last_panic"; // "<hash>:<reason>"
+
+/// Longest panic reason sent over the UART or stored in NVS.
+const REASON_MAX: usize = 80;
+
+/// Ticks to wait for the PANIC line to leave the UART before rebooting.
+const TX_DRAIN_TICKS: u32 = 100;
+
+/// Crash history kept across reboots.
+pub struct CrashStats {
+    pub count: u32,
+    /// `<hash>:<reason>` of the most recent panic.
+    pub last: Option<String>,
+}
+
+pub fn stats(nvs: &EspNvs<NvsDefault>) -> CrashStats {
+    let mut buf = [0u8; REASON_MAX + 16];
+    CrashStats {
+        count: nvs.get_u32(CRASH_COUNT_KEY).ok().flatten().unwrap_or(0),
+        last: nvs
+            .get_str(LAST_PANIC_KEY, &mut buf)
+            .ok()
+            .flatten()
+            .map(str::to_string),
+    }
+}
+
+/// Replace the default panic handler. On a panic in any task we send
+/// `PANIC:<reason>:<hash>` on the protocol UART, bump the crash counter in
+/// NVS, and reboot instead of hanging with the host timing out.
+///
+/// There is no unwinder on the device, so `<hash>` is an FNV-1a hash of the
+/// panic location: stable across crashes at the same spot in the same build.
+/// The reason may itself contain colons; the hash is always the last field.
+pub fn install(namespace: &'static str) {
+    std::panic::set_hook(Box::new(move |info| {
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown");
+        let reason = sanitize(message);
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}", l.file(), l.line()))
+            .unwrap_or_default();
+        let hash = fnv1a(location.as_bytes());
+
+        error!("Panic at {}: {}", location, message);
+
+        // Leading newline so the report starts a fresh line even if it cut a
+        // response off half-way
+        let line = format!("\nPANIC:{}:{:08x}\n", reason, hash);
+        unsafe {
+            sys::uart_write_bytes(UART0::port(), line.as_ptr().cast(), line.len());
+            sys::uart_wait_tx_done(UART0::port(), TX_DRAIN_TICKS);
+        }
+
+        if let Err(e) = record(namespace, &format!("{:08x}:{}", hash, reason)) {
+            error!("Could not record panic in NVS: {}", e);
+        }
+
+        unsafe {
+            sys::esp_restart();
+        }
+    }));
+}
+
+/// Opens its own handle: the panicking task may be the one holding `EspNvs`.
+fn record(namespace: &str, last: &str) -> Result<(), sys::EspError> {
+    let (Ok(c_namespace), Ok(c_count), Ok(c_last), Ok(c_value)) = (
+        CString::new(namespace),
+        CString::new(CRASH_COUNT_KEY),
+        CString::new(LAST_PANIC_KEY),
+        CString::new(last),
+    ) else {
+        return Ok(());
+    };
+
+    let mut handle: sys::nvs_handle_t = 0;
+    unsafe {
+        sys::esp!(sys::nvs_open(
+            c_namespace.as_ptr(),
+            sys::nvs_open_mode_t_NVS_READWRITE,
+            &mut handle
+        ))?;
+        let mut count: u32 = 0;
+        // Missing on the first crash
+        let _ = sys::nvs_get_u32(handle, c_count.as_ptr(), &mut count);
+        let result = sys::esp!(sys::nvs_set_u32(
+            handle,
+            c_count.as_ptr(),
+            count.saturating_add(1)
+        ))
+        .and_then(|_| sys::esp!(sys::nvs_set_str(handle, c_last.as_ptr(), c_value.as_ptr())))
+        .and_then(|_| sys::esp!(sys::nvs_commit(handle)));
+        sys::nvs_close(handle);
+        result
+    }
+}
+
+/// One printable line, at most `REASON_MAX` bytes.
+fn sanitize(message: &str) -> String {
+    let mut out = String::with_capacity(REASON_MAX);
+    for c in message.chars() {
+        let c = if c.is_control() { ' ' } else { c };
+        if out.len() + c.len_utf8() > REASON_MAX {
+            break;
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, &b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    })
+}
@@ -0,0 +1,55 @@
+//! Signing task. After boot the private key lives only here; the dispatcher
+//! hands it messages and gets signatures back.
+
+use ed25519_dalek::{Signer as _, SigningKey};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+/// Signing jobs waiting for the task. Only the dispatcher signs, and it waits
+/// for each result, so one slot is enough.
+const QUEUE_DEPTH: usize = 1;
+
+struct Job {
+    message: Vec<u8>,
+    reply: SyncSender<[u8; 64]>,
+}
+
+/// Handle to the signing task.
+pub struct Signer {
+    jobs: SyncSender<Job>,
+    pubkey: [u8; 32],
+}
+
+impl Signer {
+    pub fn spawn(key: SigningKey) -> anyhow::Result<Self> {
+        let pubkey = key.verifying_key().to_bytes();
+        let (jobs, rx) = mpsc::sync_channel(QUEUE_DEPTH);
+        thread::Builder::new()
+            .name("crypto".into())
+            // Ed25519 scalar and field arithmetic is stack-hungry
+            .stack_size(8192)
+            .spawn(move || run(key, rx))?;
+        Ok(Signer { jobs, pubkey })
+    }
+
+    pub fn pubkey(&self) -> [u8; 32] {
+        self.pubkey
+    }
+
+    pub fn sign(&self, message: Vec<u8>) -> anyhow::Result<[u8; 64]> {
+        let (reply, result) = mpsc::sync_channel(1);
+        self.jobs
+            .send(Job { message, reply })
+            .map_err(|_| anyhow::anyhow!("crypto task stopped"))?;
+        result
+            .recv()
+            .map_err(|_| anyhow::anyhow!("crypto task stopped"))
+    }
+}
+
+fn run(key: SigningKey, jobs: Receiver<Job>) {
+    for job in jobs {
+        let signature = key.sign(&job.message).to_bytes();
+        let _ = job.reply.send(signature);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::crash::CrashStats;
+use esp_idf_sys as sys;
+use std::ffi::CString;
+
+/// NVS usage for the default partition plus the entries owned by one namespace.
+struct NvsUsage {
+    namespace_used: usize,
+    used: usize,
+    free: usize,
+    total: usize,
+}
+
+fn nvs_usage(namespace: &str) -> Option<NvsUsage> {
+    let mut stats: sys::nvs_stats_t = Default::default();
+    unsafe {
+        sys::esp!(sys::nvs_get_stats(core::ptr::null(), &mut stats)).ok()?;
+    }
+
+    // EspNvs<NvsDefault> doesn't expose its raw handle, so open a second
+    // read-only one just for counting.
+    let c_namespace = CString::new(namespace).ok()?;
+    let mut handle: sys::nvs_handle_t = 0;
+    let mut namespace_used: usize = 0;
+    unsafe {
+        sys::esp!(sys::nvs_open(
+            c_namespace.as_ptr(),
+            sys::nvs_open_mode_t_NVS_READONLY,
+            &mut handle
+        ))
+        .ok()?;
+        let counted = sys::esp!(sys::nvs_get_used_entry_count(handle, &mut namespace_used));
+        sys::nvs_close(handle);
+        counted.ok()?;
+    }
+
+    Some(NvsUsage {
+        namespace_used,
+        used: stats.used_entries,
+        free: stats.free_entries,
+        total: stats.total_entries,
+    })
+}
+
+/// Fields of the DIAG response (`Response::Diag`):
+/// `heap_free=..;heap_min=..;stack_hwm=..;nvs_ns_used=..;nvs_used=..;nvs_free=..;nvs_total=..;crashes=..;last_panic=..`
+///
+/// `stack_hwm` is the smallest amount of stack (bytes) the calling task has
+/// ever had left; NVS figures are entry counts (32 bytes each). `last_panic`
+/// is the hash from the most recent `PANIC:` line, or `none`.
+pub fn diag_fields(namespace: &str, crashes: &CrashStats) -> String {
+    let (heap_free, heap_min, stack_hwm) = unsafe {
+        (
+            sys::esp_get_free_heap_size(),
+            sys::esp_get_minimum_free_heap_size(),
+            sys::uxTaskGetStackHighWaterMark(core::ptr::null_mut()),
+        )
+    };
+
+    let nvs = match nvs_usage(namespace) {
+        Some(n) => format!(
+            "nvs_ns_used={};nvs_used={};nvs_free={};nvs_total={}",
+            n.namespace_used, n.used, n.free, n.total
+        ),
+        None => "nvs=unavailable".to_string(),
+    };
+
+    let last_panic = crashes
+        .last
+        .as_deref()
+        .and_then(|last| last.split(':').next())
+        .unwrap_or("none");
+
+    format!(
+        "heap_free={};heap_min={};stack_hwm={};{};crashes={};last_panic={}",
+        heap_free, heap_min, stack_hwm, nvs, crashes.count, last_panic
+    )
+}
@@ -0,0 +1,69 @@
+//! SHA-256 on the chip's SHA accelerator, through mbedtls (which drives the
+//! engine when `CONFIG_MBEDTLS_HARDWARE_SHA` is set). The ESP32-C3 engine has
+//! no SHA-512, so Ed25519's internal hashing stays in software.
+
+use anyhow::{anyhow, Result};
+use esp_idf_sys as sys;
+
+fn check(ret: i32) -> Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(anyhow!("mbedtls error -0x{:04x}", -ret))
+    }
+}
+
+pub struct Sha256 {
+    // Boxed so the context mbedtls set up never moves
+    ctx: Box<sys::mbedtls_md_context_t>,
+}
+
+impl Sha256 {
+    pub fn new() -> Result<Self> {
+        let mut hasher = Self::init();
+        unsafe {
+            check(sys::mbedtls_md_setup(&mut *hasher.ctx, sha256_info(), 0))?;
+            check(sys::mbedtls_md_starts(&mut *hasher.ctx))?;
+        }
+        Ok(hasher)
+    }
+
+    /// An initialised but not set up context; dropping it is always safe.
+    fn init() -> Self {
+        let mut ctx = Box::new(unsafe { core::mem::zeroed::<sys::mbedtls_md_context_t>() });
+        unsafe { sys::mbedtls_md_init(&mut *ctx) };
+        Sha256 { ctx }
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Result<()> {
+        check(unsafe { sys::mbedtls_md_update(&mut *self.ctx, data.as_ptr(), data.len()) })
+    }
+
+    /// Digest of everything hashed so far. The hasher can keep going.
+    pub fn digest(&self) -> Result<[u8; 32]> {
+        let mut copy = Self::init();
+        let mut out = [0u8; 32];
+        unsafe {
+            check(sys::mbedtls_md_setup(&mut *copy.ctx, sha256_info(), 0))?;
+            check(sys::mbedtls_md_clone(&mut *copy.ctx, &*self.ctx))?;
+            check(sys::mbedtls_md_finish(&mut *copy.ctx, out.as_mut_ptr()))?;
+        }
+        Ok(out)
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { sys::mbedtls_md_free(&mut *self.ctx) };
+    }
+}
+
+fn sha256_info() -> *const sys::mbedtls_md_info_t {
+    unsafe { sys::mbedtls_md_info_from_type(sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256) }
+}
+
+pub fn sha256(data: &[u8]) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new()?;
+    hasher.update(data)?;
+    hasher.digest()
+}
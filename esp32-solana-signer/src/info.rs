@@ -0,0 +1,131 @@
+use esp_idf_svc::hal::reset::ResetReason;
+use esp_idf_sys as sys;
+
+use crate::lockdown::{DebugState, Protection};
+
+// Build metadata stamped in by build.rs
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("SIGNER_GIT_COMMIT");
+pub const BUILD_TIMESTAMP: &str = env!("SIGNER_BUILD_TIMESTAMP");
+
+/// Cargo features compiled into this image, in a stable order.
+pub fn enabled_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "twofa") {
+        features.push("twofa");
+    }
+    if cfg!(feature = "ota") {
+        features.push("ota");
+    }
+    if cfg!(feature = "light-sleep") {
+        features.push("light-sleep");
+    }
+    if cfg!(feature = "production") {
+        features.push("production");
+    }
+    if cfg!(feature = "vsense") {
+        features.push("vsense");
+    }
+    if cfg!(feature = "sd-backup") {
+        features.push("sd-backup");
+    }
+    if cfg!(feature = "sneakernet") {
+        features.push("sneakernet");
+    }
+    if cfg!(feature = "presence") {
+        features.push("presence");
+    }
+    if cfg!(feature = "audit") {
+        features.push("audit");
+    }
+    if cfg!(feature = "ble") {
+        features.push("ble");
+    }
+    if cfg!(feature = "base64") {
+        features.push("base64");
+    }
+    if cfg!(feature = "bs58") {
+        features.push("bs58");
+    }
+    features
+}
+
+#[allow(non_upper_case_globals)]
+fn chip_model(model: sys::esp_chip_model_t) -> &'static str {
+    match model {
+        sys::esp_chip_model_t_CHIP_ESP32 => "esp32",
+        sys::esp_chip_model_t_CHIP_ESP32S2 => "esp32s2",
+        sys::esp_chip_model_t_CHIP_ESP32S3 => "esp32s3",
+        sys::esp_chip_model_t_CHIP_ESP32C3 => "esp32c3",
+        sys::esp_chip_model_t_CHIP_ESP32C2 => "esp32c2",
+        sys::esp_chip_model_t_CHIP_ESP32C6 => "esp32c6",
+        sys::esp_chip_model_t_CHIP_ESP32H2 => "esp32h2",
+        _ => "unknown",
+    }
+}
+
+/// Size of the main SPI flash chip in bytes, or 0 if it can't be read.
+fn flash_size() -> u32 {
+    let mut size: u32 = 0;
+    unsafe {
+        if sys::esp!(sys::esp_flash_get_size(core::ptr::null_mut(), &mut size)).is_err() {
+            return 0;
+        }
+    }
+    size
+}
+
+fn enabled_or_disabled(disabled: bool) -> &'static str {
+    if disabled {
+        "disabled"
+    } else {
+        "enabled"
+    }
+}
+
+fn on_or_off(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Fields of the GET_INFO response (`Response::Info`):
+/// `fw=..;proto=..;commit=..;built=..;features=..;chip=..;rev=..;cores=..;flash=..;reset=..;jtag=..;download=..;flash_enc=..;secure_boot=..;device_id=..`
+pub fn info_fields(device_id: &str) -> String {
+    let mut chip: sys::esp_chip_info_t = Default::default();
+    unsafe {
+        sys::esp_chip_info(&mut chip);
+    }
+
+    let features = enabled_features();
+    let features = if features.is_empty() {
+        "none".to_string()
+    } else {
+        features.join(",")
+    };
+
+    let debug = DebugState::read();
+    let protection = Protection::read();
+
+    format!(
+        "fw={};proto={};commit={};built={};features={};chip={};rev=v{}.{};cores={};flash={};reset={:?};jtag={};download={};flash_enc={};secure_boot={};device_id={}",
+        FIRMWARE_VERSION,
+        signer_protocol::VERSION,
+        GIT_COMMIT,
+        BUILD_TIMESTAMP,
+        features,
+        chip_model(chip.model),
+        chip.revision / 100,
+        chip.revision % 100,
+        chip.cores,
+        flash_size(),
+        ResetReason::get(),
+        enabled_or_disabled(debug.jtag_disabled()),
+        enabled_or_disabled(debug.download_mode_disabled),
+        on_or_off(protection.flash_encryption),
+        on_or_off(protection.secure_boot),
+        device_id,
+    )
+}
@@ -0,0 +1,151 @@
+use esp_idf_svc::hal::gpio::{Gpio8, Output, PinDriver};
+use log::warn;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// A blink sequence: each `(on_ms, off_ms)` pair lights the LED and then
+/// darkens it, and the whole sequence plays `times` times (0 = until
+/// replaced).
+#[derive(Clone, Copy)]
+pub struct Pattern {
+    blinks: &'static [(u32, u32)],
+    times: u32,
+}
+
+const fn pattern(blinks: &'static [(u32, u32)], times: u32) -> Pattern {
+    Pattern { blinks, times }
+}
+
+pub const STARTUP: Pattern = pattern(&[(300, 0)], 1);
+/// Short acknowledgement (GET_INFO, TX_INFO, OTA_BEGIN)
+pub const ACK: Pattern = pattern(&[(100, 0)], 1);
+pub const PUBKEY: Pattern = pattern(&[(150, 150)], 2);
+pub const TX_CREATED: Pattern = pattern(&[(150, 150)], 3);
+/// Five rapid blinks: request rejected or failed
+pub const ERROR: Pattern = pattern(&[(100, 100)], 5);
+pub const LOCKED: Pattern = pattern(&[(100, 100)], 3);
+pub const OTP_SECRET_ISSUED: Pattern = pattern(&[(180, 0)], 1);
+pub const OTP_ERROR: Pattern = pattern(&[(120, 120)], 3);
+/// Short, long
+pub const OTP_CONFIRMED: Pattern = pattern(&[(120, 120), (300, 0)], 1);
+pub const OTP_BAD_CODE: Pattern = pattern(&[(80, 80)], 4);
+/// Two short + one long
+pub const UNLOCKED: Pattern = pattern(&[(120, 120), (120, 120), (350, 0)], 1);
+/// Fast blink while a signature waits for the BOOT button
+pub const AWAIT_SIGN: Pattern = pattern(&[(200, 200)], 0);
+/// Triple flash with a longer third
+pub const SIGNED: Pattern = pattern(&[(150, 150), (150, 150), (450, 0)], 1);
+/// Slow blink while an OTA flash or provisioning waits for the BOOT button
+pub const AWAIT_CONFIRM: Pattern = pattern(&[(500, 500)], 0);
+/// Long, short: a pending policy change was cancelled
+pub const CANCELLED: Pattern = pattern(&[(600, 200), (100, 0)], 1);
+/// Long blink before a reboot or deep sleep
+pub const FAREWELL: Pattern = pattern(&[(1000, 0)], 1);
+/// UART driver error
+pub const UART_FAULT: Pattern = pattern(&[(100, 100)], 10);
+/// Slow double pulse while waiting out a brownout
+pub const BROWNOUT: Pattern = pattern(&[(100, 100), (100, 700)], 0);
+/// Frantic blink until power-cycled: the RNG can't be trusted
+pub const ENTROPY_FAULT: Pattern = pattern(&[(50, 50)], 0);
+
+/// Blinks a pairing code until replaced: each digit (1-9) as that many short
+/// flashes, a pause between digits and a longer one before it repeats. Built
+/// at run time and leaked, which is fine for something done once per pairing.
+pub fn code(digits: &[u8]) -> Pattern {
+    let mut blinks = Vec::new();
+    for &digit in digits {
+        for n in 1..=digit {
+            blinks.push((250, if n == digit { 1000 } else { 250 }));
+        }
+    }
+    if let Some(last) = blinks.last_mut() {
+        last.1 = 2500;
+    }
+    pattern(blinks.leak(), 0)
+}
+
+enum Msg {
+    Play(Pattern),
+    Off,
+}
+
+/// Handle to the LED task. Patterns play in the background so the main loop
+/// keeps reading the UART; starting a pattern cuts off whatever was playing.
+#[derive(Clone)]
+pub struct Led {
+    tx: Sender<Msg>,
+}
+
+impl Led {
+    pub fn spawn(pin: PinDriver<'static, Gpio8, Output>) -> anyhow::Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("led".into())
+            .stack_size(3072)
+            .spawn(move || run(pin, rx))?;
+        Ok(Led { tx })
+    }
+
+    pub fn play(&self, pattern: Pattern) {
+        // The task only exits if the pin errored; feedback is best-effort
+        let _ = self.tx.send(Msg::Play(pattern));
+    }
+
+    pub fn off(&self) {
+        let _ = self.tx.send(Msg::Off);
+    }
+}
+
+fn run(mut pin: PinDriver<'static, Gpio8, Output>, rx: Receiver<Msg>) {
+    let mut next: Option<Msg> = None;
+    loop {
+        let msg = match next.take() {
+            Some(msg) => msg,
+            None => match rx.recv() {
+                Ok(msg) => msg,
+                Err(_) => return,
+            },
+        };
+        let pattern = match msg {
+            Msg::Play(pattern) => pattern,
+            Msg::Off => {
+                let _ = pin.set_low();
+                continue;
+            }
+        };
+
+        let mut played = 0;
+        'playing: loop {
+            for &(on_ms, off_ms) in pattern.blinks {
+                for (level_high, ms) in [(true, on_ms), (false, off_ms)] {
+                    let set = if level_high {
+                        pin.set_high()
+                    } else {
+                        pin.set_low()
+                    };
+                    if let Err(e) = set {
+                        warn!("LED task stopping: {}", e);
+                        return;
+                    }
+                    if ms == 0 {
+                        continue;
+                    }
+                    match rx.recv_timeout(Duration::from_millis(ms.into())) {
+                        Ok(msg) => {
+                            next = Some(msg);
+                            break 'playing;
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            }
+            played += 1;
+            if pattern.times != 0 && played >= pattern.times {
+                break;
+            }
+        }
+        let _ = pin.set_low();
+    }
+}
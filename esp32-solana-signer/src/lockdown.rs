@@ -0,0 +1,107 @@
+use esp_idf_sys as sys;
+use signer_core::policy::Policy;
+
+/// Debug/re-flash interfaces as recorded in eFuse. Every bit here is one-way:
+/// once burned it can never be cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugState {
+    pub pad_jtag_disabled: bool,
+    pub usb_jtag_disabled: bool,
+    pub download_mode_disabled: bool,
+}
+
+impl DebugState {
+    pub fn read() -> Self {
+        unsafe {
+            DebugState {
+                pad_jtag_disabled: sys::esp_efuse_read_field_bit(
+                    sys::ESP_EFUSE_DIS_PAD_JTAG.as_ptr(),
+                ),
+                usb_jtag_disabled: sys::esp_efuse_read_field_bit(
+                    sys::ESP_EFUSE_DIS_USB_JTAG.as_ptr(),
+                ),
+                download_mode_disabled: sys::esp_efuse_read_field_bit(
+                    sys::ESP_EFUSE_DIS_DOWNLOAD_MODE.as_ptr(),
+                ),
+            }
+        }
+    }
+
+    pub fn jtag_disabled(&self) -> bool {
+        self.pad_jtag_disabled && self.usb_jtag_disabled
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.jtag_disabled() && self.download_mode_disabled
+    }
+}
+
+/// Flash encryption and secure boot as recorded in eFuse, which the policy's
+/// production mode requires before the key is used. Neither is turned on by
+/// this firmware: both are set up with the bootloader (see buildnflash.md).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection {
+    pub flash_encryption: bool,
+    pub secure_boot: bool,
+}
+
+impl Protection {
+    pub fn read() -> Self {
+        // Flash encryption is on while an odd number of these bits is burned
+        let mut crypt_cnt = 0usize;
+        unsafe {
+            let counted = sys::esp_efuse_read_field_cnt(
+                sys::ESP_EFUSE_SPI_BOOT_CRYPT_CNT.as_ptr(),
+                &mut crypt_cnt,
+            );
+            Protection {
+                flash_encryption: counted == sys::ESP_OK && crypt_cnt % 2 == 1,
+                secure_boot: sys::esp_efuse_read_field_bit(sys::ESP_EFUSE_SECURE_BOOT_EN.as_ptr()),
+            }
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.flash_encryption && self.secure_boot
+    }
+
+    /// Whether `policy` lets the key be used on this chip; `None` (no usable
+    /// policy) is left to the commands' own checks.
+    pub fn allows(&self, policy: Option<&Policy>) -> bool {
+        self.is_complete() || !policy.is_some_and(|policy| policy.production)
+    }
+}
+
+/// Burn whatever lockdown eFuses aren't burned yet, then read them back.
+/// Errors if the chip still isn't locked afterwards; the caller must not go
+/// on to load keys in that case.
+#[cfg(feature = "production")]
+pub fn enforce() -> anyhow::Result<DebugState> {
+    let before = DebugState::read();
+    if before.is_locked() {
+        return Ok(before);
+    }
+
+    log::warn!("Burning debug lockdown eFuses: {:?}", before);
+    unsafe {
+        if !before.pad_jtag_disabled {
+            sys::esp!(sys::esp_efuse_write_field_bit(
+                sys::ESP_EFUSE_DIS_PAD_JTAG.as_ptr()
+            ))?;
+        }
+        if !before.usb_jtag_disabled {
+            sys::esp!(sys::esp_efuse_write_field_bit(
+                sys::ESP_EFUSE_DIS_USB_JTAG.as_ptr()
+            ))?;
+        }
+        if !before.download_mode_disabled {
+            sys::esp!(sys::esp_efuse_disable_rom_download_mode())?;
+        }
+    }
+
+    let after = DebugState::read();
+    if !after.is_locked() {
+        return Err(anyhow::anyhow!("eFuse lockdown did not stick: {:?}", after));
+    }
+    Ok(after)
+}
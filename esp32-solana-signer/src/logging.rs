@@ -0,0 +1,61 @@
+use anyhow::{anyhow, Result};
+use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::LevelFilter;
+
+const LOG_LEVEL_KEY: &str = "log_level"; // raw u8 (LevelFilter as usize)
+
+/// Level used until someone sends LOG_LEVEL.
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Install EspLogger and apply the level persisted in NVS (or the default).
+pub fn init(nvs: &mut EspNvs<NvsDefault>) -> Result<LevelFilter> {
+    EspLogger::initialize_default();
+    let level = load(nvs)?.unwrap_or(DEFAULT_LEVEL);
+    apply(level)?;
+    Ok(level)
+}
+
+/// Apply a new level immediately and persist it across reboots.
+pub fn set(nvs: &mut EspNvs<NvsDefault>, level: LevelFilter) -> Result<()> {
+    apply(level)?;
+    nvs.set_raw(LOG_LEVEL_KEY, &[level as usize as u8])?;
+    Ok(())
+}
+
+/// Accepts the `log` crate names plus `none`/`verbose` aliases, any case.
+pub fn parse_level(s: &str) -> Result<LevelFilter> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "off" | "none" => Ok(LevelFilter::Off),
+        "error" => Ok(LevelFilter::Error),
+        "warn" => Ok(LevelFilter::Warn),
+        "info" => Ok(LevelFilter::Info),
+        "debug" => Ok(LevelFilter::Debug),
+        "trace" | "verbose" => Ok(LevelFilter::Trace),
+        other => Err(anyhow!("unknown level '{}'", other)),
+    }
+}
+
+fn apply(level: LevelFilter) -> Result<()> {
+    // "*" also covers the ESP-IDF C components' own tags
+    esp_idf_svc::log::set_target_level("*", level)?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+fn load(nvs: &mut EspNvs<NvsDefault>) -> Result<Option<LevelFilter>> {
+    let mut b = [0u8; 1];
+    let level = match nvs.get_raw(LOG_LEVEL_KEY, &mut b)? {
+        Some(slice) if slice.len() == 1 => match b[0] {
+            0 => Some(LevelFilter::Off),
+            1 => Some(LevelFilter::Error),
+            2 => Some(LevelFilter::Warn),
+            3 => Some(LevelFilter::Info),
+            4 => Some(LevelFilter::Debug),
+            5 => Some(LevelFilter::Trace),
+            _ => None,
+        },
+        _ => None,
+    };
+    Ok(level)
+}
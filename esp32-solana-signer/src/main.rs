@@ -1,54 +1,100 @@
-use base64;
-use base64::Engine;
-use bs58;
-use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use ed25519_dalek::SigningKey;
 use esp_idf_svc::hal::gpio::{PinDriver, Pull};
 use esp_idf_svc::hal::prelude::Peripherals;
+use esp_idf_svc::hal::reset::ResetReason;
 use esp_idf_svc::hal::uart::UartDriver;
-use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
-use esp_idf_svc::sys::ESP_ERR_TIMEOUT;
-use rand_core::OsRng;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use log::{debug, error, info, warn};
+#[cfg(feature = "audit")]
+use signer_core::audit::Event;
+use signer_core::entropy::HealthChecked;
+use signer_core::identity::{self, ProvisionError};
+use signer_core::keys::{self, KeyError};
+#[cfg(feature = "sd-backup")]
+use signer_core::platform::Storage;
+use signer_core::policy::{ChainProfile, Policy, PolicyViolation};
+use signer_core::token_labels::{self, LabelError};
+use signer_core::tx_introspection::{self, MEMO_PROGRAM_ID};
+#[cfg(feature = "ble")]
+use signer_protocol::ble::Status;
+use signer_protocol::command::Command;
+use signer_protocol::error::ErrorCode;
+use signer_protocol::response::Response;
 
 // Add imports for deep sleep from ESP-IDF sys bindings
 use esp_idf_sys::esp_deep_sleep_start;
 
+#[cfg(feature = "audit")]
+mod audit;
+#[cfg(feature = "sd-backup")]
+mod backup;
+mod bench;
+#[cfg(feature = "ble")]
+mod ble;
+mod button;
+mod codec;
+mod config;
+mod crash;
+mod crypto;
+mod diag;
+mod hwsha;
+mod info;
+mod led;
+mod lockdown;
+mod logging;
+#[cfg(feature = "ota")]
+mod ota;
+mod pairing;
+mod pending;
+mod platform;
+#[cfg(feature = "light-sleep")]
+mod power;
+#[cfg(feature = "presence")]
+mod presence;
+mod serial;
+#[cfg(feature = "sneakernet")]
+mod sneakernet;
+mod telemetry;
 #[cfg(feature = "twofa")]
 mod twofa;
 
+// The radio can't wake the chip from light sleep, so a sleeping phone link
+// would drop every request
+#[cfg(all(feature = "ble", feature = "light-sleep"))]
+compile_error!("features `ble` and `light-sleep` can't be enabled together");
+
+// NVS namespace holding the key and all signer state
+const NVS_NAMESPACE: &str = "solana_signer";
+
 // Const nonce to use as blockhash for placeholder transactions
 // This is a valid base58-encoded 32-byte hash that we use as a dummy blockhash
 const PLACEHOLDER_BLOCKHASH: &str = "11111111111111111111111111111112";
+// The same hash as raw bytes, so builds without base58 can still use it
+const PLACEHOLDER_BLOCKHASH_BYTES: [u8; 32] = {
+    let mut hash = [0u8; 32];
+    hash[31] = 1;
+    hash
+};
 
-// Solana memo program ID (32 bytes)
-// MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr in bytes
-const MEMO_PROGRAM_ID: [u8; 32] = [
-    5, 74, 83, 90, 153, 41, 33, 6, 77, 36, 232, 113, 96, 218, 56, 124, 124, 53, 181, 221, 188, 146,
-    187, 129, 228, 31, 168, 64, 65, 5, 68, 141,
-];
-
-fn load_or_generate_key(nvs: &mut EspNvs<NvsDefault>) -> anyhow::Result<SigningKey> {
-    let key_name = "solana_key";
-    let mut key_bytes = [0u8; 32];
-    match nvs.get_raw(key_name, &mut key_bytes)? {
-        Some(_) => Ok(SigningKey::from_bytes(&key_bytes)),
-        None => {
-            let mut csprng = OsRng;
-            let signing_key = SigningKey::generate(&mut csprng);
-            let key_bytes = signing_key.to_bytes();
-            nvs.set_raw(key_name, &key_bytes)?;
-            Ok(signing_key)
-        }
-    }
-}
+// How often to sample the BOOT button while waiting for a confirmation
+const BUTTON_POLL_MS: u32 = 20;
 
-fn send_response(uart: &mut UartDriver, response: &str) -> anyhow::Result<()> {
-    let response_with_newline = response.to_string() + "\n";
-    let data = response_with_newline.as_bytes();
-    let mut written = 0;
-    while written < data.len() {
-        written += uart.write(&data[written..])?;
+// Idle time after a brownout reset before anything is persisted
+const BROWNOUT_SETTLE_MS: u32 = 2000;
+
+/// Safe state for when the RNG failed its health tests while we needed a new
+/// key. Nothing was persisted; keep refusing every command and flashing the
+/// LED until someone power-cycles the device.
+fn entropy_failure_halt(uart: &UartDriver, led: &led::Led) -> ! {
+    led.play(led::ENTROPY_FAULT);
+    loop {
+        let mut byte = [0u8; 1];
+        if let Ok(1) = uart.read(&mut byte, 100) {
+            if byte[0] == b'\n' {
+                let _ = serial::write_line(uart, &Response::Error(ErrorCode::Entropy).to_string());
+            }
+        }
     }
-    Ok(())
 }
 
 /// Creates a placeholder Solana transaction with a memo instruction
@@ -56,22 +102,12 @@ fn send_response(uart: &mut UartDriver, response: &str) -> anyhow::Result<()> {
 /// This function creates a complete Solana transaction containing:
 /// - A memo instruction with the text "Hello from ESP32 Solana Signer!"
 /// - Uses the const PLACEHOLDER_BLOCKHASH as the recent blockhash
-/// - Signs the transaction with the provided signing key
+/// - Signs the transaction on the crypto task
 ///
 /// Returns the serialized transaction bytes ready for transmission
-fn create_placeholder_transaction(signing_key: &SigningKey) -> anyhow::Result<Vec<u8>> {
+fn create_placeholder_transaction(signer: &crypto::Signer) -> anyhow::Result<Vec<u8>> {
     let memo_text = "Hello from ESP32 Solana Signer!";
-    let verifying_key = signing_key.verifying_key();
-    let pubkey_bytes = verifying_key.to_bytes();
-
-    // Parse const blockhash from base58
-    let blockhash = bs58::decode(PLACEHOLDER_BLOCKHASH)
-        .into_vec()
-        .map_err(|e| anyhow::anyhow!("Invalid blockhash: {}", e))?;
-
-    if blockhash.len() != 32 {
-        return Err(anyhow::anyhow!("Blockhash must be 32 bytes"));
-    }
+    let pubkey_bytes = signer.pubkey();
 
     // Create a Solana transaction message following the wire format
     let mut message = Vec::new();
@@ -91,7 +127,7 @@ fn create_placeholder_transaction(signing_key: &SigningKey) -> anyhow::Result<Ve
     message.extend_from_slice(&MEMO_PROGRAM_ID);
 
     // Recent blockhash (32 bytes)
-    message.extend_from_slice(&blockhash);
+    message.extend_from_slice(&PLACEHOLDER_BLOCKHASH_BYTES);
 
     // Instructions (compact array format)
     message.push(1); // Number of instructions
@@ -108,8 +144,7 @@ fn create_placeholder_transaction(signing_key: &SigningKey) -> anyhow::Result<Ve
 
     // Sign the message directly (Solana signs the raw message bytes)
     // Ed25519 handles internal hashing, no need for SHA-256 pre-hashing
-    let signature = signing_key.sign(&message);
-    let signature_bytes = signature.to_bytes();
+    let signature_bytes = signer.sign(message.clone())?;
 
     // Build complete transaction (signatures + message)
     let mut transaction = Vec::new();
@@ -138,13 +173,39 @@ fn device_unix_time() -> u64 {
 fn main() -> anyhow::Result<()> {
     let peripherals = Peripherals::take().unwrap();
     let nvs_partition = EspDefaultNvsPartition::take()?;
-    let mut nvs = EspNvs::new(nvs_partition, "solana_signer", true)?;
-    let signing_key = load_or_generate_key(&mut nvs)?;
-    let verifying_key: VerifyingKey = signing_key.verifying_key();
-    let pubkey_bytes = verifying_key.to_bytes();
-    let pubkey_base58 = bs58::encode(pubkey_bytes).into_string();
+    let mut nvs = EspNvs::new(nvs_partition.clone(), NVS_NAMESPACE, true)?;
+    let log_level = logging::init(&mut nvs)?;
+    crash::install(NVS_NAMESPACE);
+    let crashes = crash::stats(&nvs);
+    if let Some(last) = &crashes.last {
+        info!("{} panic(s) recorded, last {}", crashes.count, last);
+    }
+
+    // Configure built-in LED on GPIO 8 as output (ESP32-C3 built-in LED)
+    let mut led_pin = PinDriver::output(peripherals.pins.gpio8)?;
+
+    // Initial LED state - off when idle
+    led_pin.set_low()?;
+    let led = led::Led::spawn(led_pin)?;
+
+    // The supply sagged far enough to reset us, possibly in the middle of a
+    // flash write. Sit in a safe idle before writing NVS or eFuses so a
+    // supply that is still unstable browns out again before we touch them.
+    if ResetReason::get() == ResetReason::Brownout {
+        warn!("Brownout reset; holding off for {} ms", BROWNOUT_SETTLE_MS);
+        led.play(led::BROWNOUT);
+        esp_idf_svc::hal::delay::FreeRtos::delay_ms(BROWNOUT_SETTLE_MS);
+        led.off();
+    }
+
+    // Production images lock out JTAG and ROM download mode before any key exists
+    #[cfg(feature = "production")]
+    {
+        let state = lockdown::enforce().inspect_err(|e| error!("Debug lockdown failed: {}", e))?;
+        info!("Debug lockdown verified: {:?}", state);
+    }
 
-    let mut uart = UartDriver::new(
+    let uart = UartDriver::new(
         peripherals.uart0,
         peripherals.pins.gpio21, // ESP32-C3 UART0 TX
         peripherals.pins.gpio20, // ESP32-C3 UART0 RX
@@ -157,304 +218,1261 @@ fn main() -> anyhow::Result<()> {
     let mut button = PinDriver::input(peripherals.pins.gpio9)?;
     button.set_pull(Pull::Up)?;
 
-    // Configure built-in LED on GPIO 8 as output (ESP32-C3 built-in LED)
-    let mut led = PinDriver::output(peripherals.pins.gpio8)?;
+    let mut sensors = telemetry::Sensors::new(
+        peripherals.temp_sensor,
+        #[cfg(feature = "vsense")]
+        peripherals.adc1,
+        #[cfg(feature = "vsense")]
+        peripherals.pins.gpio3,
+    );
 
-    // Initial LED state - off when idle
-    led.set_low()?;
+    #[cfg(feature = "sd-backup")]
+    let mut sd_card = backup::SdCard::new(
+        peripherals.spi2,
+        peripherals.pins.gpio6,
+        peripherals.pins.gpio7,
+        peripherals.pins.gpio2,
+        peripherals.pins.gpio10,
+    )?;
+
+    // Hardware RNG behind continuous health tests. A startup failure is
+    // latched, so any later key or OTP secret generation will refuse.
+    let mut rng = HealthChecked::new(platform::HwRng::enable());
+    if let Err(e) = rng.startup() {
+        error!("Entropy startup test failed: {}", e);
+    }
+
+    let seed = match keys::load_or_generate_seed(&mut platform::NvsStorage(&mut nvs), &mut rng) {
+        Ok(seed) => seed,
+        Err(KeyError::Entropy(e)) => {
+            error!("Refusing to generate signing key: {}", e);
+            entropy_failure_halt(&uart, &led);
+        }
+        Err(e) => return Err(anyhow::anyhow!("Signing key unavailable: {:?}", e)),
+    };
+    let signer = crypto::Signer::spawn(SigningKey::from_bytes(&seed))?;
+    let pubkey_bytes = signer.pubkey();
+    let pubkey_string = codec::encode_key(&pubkey_bytes);
+
+    // Separate key for proving ourselves to the paired host
+    let session_seed = match keys::load_or_generate_named(
+        &mut platform::NvsStorage(&mut nvs),
+        &mut rng,
+        signer_core::pairing::SESSION_KEY_NAME,
+    ) {
+        Ok(seed) => seed,
+        Err(KeyError::Entropy(e)) => {
+            error!("Refusing to generate session key: {}", e);
+            entropy_failure_halt(&uart, &led);
+        }
+        Err(e) => return Err(anyhow::anyhow!("Session key unavailable: {:?}", e)),
+    };
+    let mut trust = pairing::Pairing::new(&session_seed, &mut nvs)?;
+
+    let mut provisioning =
+        match identity::load_or_create_record(&mut platform::NvsStorage(&mut nvs), &mut rng) {
+            Ok(record) => record,
+            Err(ProvisionError::Entropy(e)) => {
+                error!("Refusing to generate device ID salt: {}", e);
+                entropy_failure_halt(&uart, &led);
+            }
+            Err(e) => return Err(anyhow::anyhow!("Provisioning record unavailable: {:?}", e)),
+        };
+    let device_id_bytes = provisioning.device_id(&platform::factory_mac());
+    let device_id = identity::format_device_id(&device_id_bytes);
+    info!(
+        "Signer ready: pubkey={} device_id={} log_level={}",
+        pubkey_string, device_id, log_level
+    );
+
+    #[cfg(feature = "audit")]
+    audit::record(&mut nvs, Event::Boot, None);
 
     // Startup: Brief blink when ready
-    led.set_high()?;
-    esp_idf_svc::hal::delay::FreeRtos::delay_ms(300);
-    led.set_low()?;
+    led.play(led::STARTUP);
 
-    let mut buffer = String::new();
+    // Both journal copies unreadable: refuse to sign rather than silently
+    // falling back to the permissive default. SET_POLICY recovers.
+    let mut policy = config::load_policy(&mut nvs)
+        .inspect(|policy| info!("Signing policy: {}", policy))
+        .inspect_err(|e| error!("No usable signing policy: {}", e))
+        .ok();
+
+    // A production policy keeps the key unused on a chip anyone could read
+    // or reflash
+    let protection = lockdown::Protection::read();
+    info!("Flash protection: {:?}", protection);
+    if !protection.allows(policy.as_ref()) {
+        error!("Production policy on unprotected hardware: the key stays unused");
+    }
 
     #[cfg(feature = "twofa")]
     let mut unlocked_until: u64 = 0;
 
+    #[cfg(feature = "ota")]
+    let mut ota_session: Option<ota::OtaSession> = None;
+
+    // We made it through boot: keep this image if it was just installed by OTA
+    #[cfg(feature = "ota")]
+    ota::confirm_running_image()?;
+
+    // Requests carried in on the SD card are answered before the serial link
+    // opens; a broken card is logged and otherwise ignored
+    #[cfg(feature = "sneakernet")]
+    if protection.allows(policy.as_ref()) {
+        // The same rule as SIGN_TX: with 2FA built in, signing waits for an
+        // OTP_UNLOCK, which a card can't carry and boot hasn't seen yet
+        #[cfg(feature = "twofa")]
+        let locked = twofa::TwoFa::is_locked(unlocked_until);
+        #[cfg(not(feature = "twofa"))]
+        let locked = false;
+        let mut on_signed = |_message: &[u8]| {
+            #[cfg(feature = "audit")]
+            audit::record(&mut nvs, Event::Signed, Some(_message));
+        };
+        match sneakernet::run(
+            &mut sd_card,
+            &signer,
+            policy.as_ref(),
+            locked,
+            &button,
+            &led,
+            &mut on_signed,
+        ) {
+            Ok(0) => {}
+            Ok(signed) => info!("Signed {} transaction(s) from the SD card", signed),
+            Err(e) => {
+                error!("SD card transactions: {:#}", e);
+                led.play(led::ERROR);
+            }
+        }
+    }
+
+    // UART reception and signing run on their own tasks; this one dispatches
+    #[allow(unused_mut)]
+    let mut port = serial::Port::spawn(uart, led.clone())?;
+    #[cfg(feature = "ble")]
+    {
+        let server = ble::Server::start(peripherals.modem, nvs_partition, port.lines())?;
+        port.attach_ble(server);
+    }
+
+    let mut pending = pending::Pending::load(&mut nvs);
+    let mut press = button::Press::default();
+
+    let mut last_link = serial::Link::Uart;
     loop {
-        let mut byte = [0u8; 1];
-        match uart.read(&mut byte, 1000) {
-            Ok(1) => {
-                let ch = byte[0] as char;
-                if ch == '\n' {
-                    let input = buffer.trim();
-
-                    // ======== PUBKEY ========
-                    if input == "GET_PUBKEY" {
-                        // During pubkey request: Double flash
-                        for _ in 0..2 {
-                            led.set_high()?;
-                            esp_idf_svc::hal::delay::FreeRtos::delay_ms(150);
-                            led.set_low()?;
-                            esp_idf_svc::hal::delay::FreeRtos::delay_ms(150);
+        // While idle, a pending policy change counts down, and a short BOOT
+        // press cancels it or else sends a presence token
+        let request = port.recv_idle(
+            std::time::Duration::from_millis(BUTTON_POLL_MS.into()),
+            || {
+                pending.tick(&mut nvs, &mut policy);
+                if !press.sample(button.is_low()) {
+                    return;
+                }
+                if pending.clear(&mut nvs) {
+                    info!("Pending policy change cancelled");
+                    led.play(led::CANCELLED);
+                    return;
+                }
+                // The token is signed with the device key, so it waits on
+                // the same eFuse check as the signing commands
+                #[cfg(feature = "presence")]
+                if !protection.allows(policy.as_ref()) {
+                    warn!("Presence token refused: device not protected");
+                    led.play(led::LOCKED);
+                    return;
+                }
+                #[cfg(feature = "presence")]
+                match presence::token(&signer, &mut nvs, &device_id_bytes) {
+                    Ok(line) => {
+                        led.play(led::ACK);
+                        if let Err(e) = port.announce(&line) {
+                            warn!("Presence token not sent: {}", e);
                         }
-                        let response = format!("PUBKEY:{}", pubkey_base58);
-                        send_response(&mut uart, &response)?;
-
-                    // ======== CREATE_TX ========
-                    } else if input == "CREATE_TX" {
-                        // Create placeholder transaction with memo
-                        match create_placeholder_transaction(&signing_key) {
-                            Ok(tx_bytes) => {
-                                let tx_base64 =
-                                    base64::engine::general_purpose::STANDARD.encode(&tx_bytes);
-
-                                // Success pattern: Triple blink
-                                for _ in 0..3 {
-                                    led.set_high()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(150);
-                                    led.set_low()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(150);
-                                }
+                    }
+                    Err(e) => {
+                        error!("Presence token: {:#}", e);
+                        led.play(led::ERROR);
+                    }
+                }
+            },
+        );
+        let Some(request) = request else {
+            break;
+        };
+        press.reset();
+        pending.tick(&mut nvs, &mut policy);
+
+        // Authenticating over one link doesn't vouch for the other
+        if request.link() != last_link {
+            trust.end_session();
+            last_link = request.link();
+        }
+        // SIGN is SIGN_TX without token labels
+        let command = match Command::parse(request.as_str()) {
+            Command::Sign(message) => Command::SignTx {
+                message,
+                tokens: "",
+            },
+            command => command,
+        };
+        match command {
+            // ======== PING: no LED, so hosts can poll it ========
+            Command::Ping => port.send(&Response::Pong.to_string())?,
+
+            // ======== PUBKEY ========
+            Command::GetPubkey => {
+                // During pubkey request: Double flash
+                led.play(led::PUBKEY);
+                let response = Response::Pubkey(&pubkey_string).to_string();
+                port.send(&response)?;
+            }
+
+            // ======== INFO ========
+            Command::GetInfo => {
+                led.play(led::ACK);
+                let fields = info::info_fields(&device_id);
+                port.send(&Response::Info(&fields).to_string())?;
+            }
+
+            // ======== DEVICE ID ========
+            Command::GetDeviceId => {
+                let resp = Response::DeviceId {
+                    id: &device_id,
+                    serial: provisioning.serial.as_deref(),
+                }
+                .to_string();
+                port.send(&resp)?;
+            }
+
+            // ======== PROVISION:SERIAL (write-once, button-confirmed) ========
+            Command::Provision(serial) => {
+                if provisioning.serial.is_some() {
+                    let resp = Response::ErrorDetail(ErrorCode::Provision, "already provisioned");
+                    port.send(&resp.to_string())?;
+                    continue;
+                }
+                if !identity::validate_serial(serial) {
+                    let resp = Response::ErrorDetail(ErrorCode::Provision, "invalid serial");
+                    port.send(&resp.to_string())?;
+                    continue;
+                }
+
+                // Slow blink until the operator confirms on the device
+                led.play(led::AWAIT_CONFIRM);
+                while !button.is_low() {
+                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(BUTTON_POLL_MS);
+                }
+                led.off();
+
+                match identity::set_serial::<_, ()>(
+                    &mut platform::NvsStorage(&mut nvs),
+                    &mut provisioning,
+                    serial,
+                ) {
+                    Ok(()) => {
+                        info!("Provisioned serial {}", serial);
+                        let resp = Response::Provisioned(serial).to_string();
+                        port.send(&resp)?;
+                    }
+                    Err(e) => {
+                        let detail = format!("{:?}", e);
+                        let resp = Response::ErrorDetail(ErrorCode::Provision, &detail).to_string();
+                        port.send(&resp)?;
+                    }
+                }
+            }
+
+            // ======== DIAG ========
+            Command::Diag => {
+                let fields = diag::diag_fields(NVS_NAMESPACE, &crash::stats(&nvs));
+                port.send(&Response::Diag(&fields).to_string())?;
+            }
+
+            // ======== GET_FEATURES ========
+            Command::GetFeatures => {
+                let fields = codec::features_fields();
+                port.send(&Response::Features(&fields).to_string())?;
+            }
+
+            // ======== BENCHMARK ========
+            Command::Benchmark => {
+                let fields = bench::benchmark_fields();
+                port.send(&Response::Benchmark(&fields).to_string())?;
+            }
+
+            // ======== TELEMETRY ========
+            Command::Telemetry => {
+                port.send(&sensors.telemetry_response())?;
+            }
+
+            // ======== LOG_LEVEL:LEVEL ========
+            Command::LogLevel(requested) => {
+                match logging::parse_level(requested)
+                    .and_then(|level| logging::set(&mut nvs, level).map(|_| level))
+                {
+                    Ok(level) => {
+                        let level = level.to_string();
+                        let resp = Response::LogLevelOk(&level).to_string();
+                        port.send(&resp)?;
+                    }
+                    Err(e) => {
+                        let e = e.to_string();
+                        let resp = Response::ErrorDetail(ErrorCode::LogLevel, &e).to_string();
+                        port.send(&resp)?;
+                    }
+                }
+            }
+
+            // ======== SET_POLICY:SPEC (after button press) ========
+            Command::SetPolicy(spec) => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                let new_policy = match Policy::parse(spec) {
+                    Ok(new_policy) => new_policy,
+                    Err(e) => {
+                        let e = e.to_string();
+                        let resp = Response::ErrorDetail(ErrorCode::SetPolicy, &e).to_string();
+                        port.send(&resp)?;
+                        continue;
+                    }
+                };
+
+                // Slow blink until the operator confirms on the device
+                led.play(led::AWAIT_CONFIRM);
+                while !button.is_low() {
+                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(BUTTON_POLL_MS);
+                }
+                led.off();
+
+                // Loosening the policy waits out the current one's delay. With
+                // no usable policy, signing is refused anyway, so this recovers
+                // at once
+                let delay = policy
+                    .as_ref()
+                    .map_or(0, |current| new_policy.change_delay_secs(current));
+                if delay > 0 {
+                    let text = new_policy.to_string();
+                    let resp = match pending.hold(&mut nvs, new_policy, delay) {
+                        Ok(()) => Response::PolicyPending {
+                            remaining_secs: delay,
+                            policy: &text,
+                        },
+                        Err(e) => {
+                            error!("{:#}", e);
+                            Response::ErrorDetail(ErrorCode::SetPolicy, "STORAGE")
+                        }
+                    };
+                    port.send(&resp.to_string())?;
+                    continue;
+                }
+
+                // Persisted before it takes effect, so a reset can't lose it
+                match config::store_policy(&mut nvs, &new_policy) {
+                    Ok(()) => {
+                        let text = new_policy.to_string();
+                        info!("Signing policy set: {}", text);
+                        #[cfg(feature = "audit")]
+                        audit::record(&mut nvs, Event::PolicySet, Some(&new_policy.to_bytes()));
+                        policy = Some(new_policy);
+                        pending.clear(&mut nvs);
+                        port.send(&Response::Policy(&text).to_string())?;
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        let resp = Response::ErrorDetail(ErrorCode::SetPolicy, "STORAGE");
+                        port.send(&resp.to_string())?;
+                    }
+                }
+            }
+
+            // ======== POLICY_ROLLBACK (after button press) ========
+            Command::PolicyRollback => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                led.play(led::AWAIT_CONFIRM);
+                while !button.is_low() {
+                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(BUTTON_POLL_MS);
+                }
+                led.off();
+
+                // Going back to a looser policy waits like any other loosening
+                let delay = match (config::previous_policy(&mut nvs), &policy) {
+                    (Ok(Some(previous)), Some(current)) => {
+                        Some((previous.change_delay_secs(current), previous))
+                    }
+                    _ => None,
+                };
+                if let Some((delay @ 1.., previous)) = delay {
+                    let text = previous.to_string();
+                    let resp = match pending.hold(&mut nvs, previous, delay) {
+                        Ok(()) => Response::PolicyPending {
+                            remaining_secs: delay,
+                            policy: &text,
+                        },
+                        Err(e) => {
+                            error!("{:#}", e);
+                            Response::ErrorDetail(ErrorCode::PolicyRollback, "STORAGE")
+                        }
+                    };
+                    port.send(&resp.to_string())?;
+                    continue;
+                }
+
+                match config::rollback_policy(&mut nvs) {
+                    Ok(Some(previous)) => {
+                        let text = previous.to_string();
+                        info!("Signing policy rolled back: {}", text);
+                        #[cfg(feature = "audit")]
+                        audit::record(&mut nvs, Event::PolicySet, Some(&previous.to_bytes()));
+                        policy = Some(previous);
+                        pending.clear(&mut nvs);
+                        port.send(&Response::Policy(&text).to_string())?;
+                    }
+                    Ok(None) => {
+                        let resp = Response::ErrorDetail(ErrorCode::PolicyRollback, "NO_PREVIOUS");
+                        port.send(&resp.to_string())?;
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        let resp = Response::ErrorDetail(ErrorCode::PolicyRollback, "STORAGE");
+                        port.send(&resp.to_string())?;
+                    }
+                }
+            }
 
-                                let response = format!("TRANSACTION:{}", tx_base64);
-                                send_response(&mut uart, &response)?;
+            // ======== GET_POLICY ========
+            Command::GetPolicy => match &policy {
+                Some(policy) => {
+                    let text = policy.to_string();
+                    port.send(&Response::Policy(&text).to_string())?;
+                }
+                None => {
+                    let resp = Response::ErrorDetail(ErrorCode::Policy, "UNAVAILABLE");
+                    port.send(&resp.to_string())?;
+                }
+            },
+
+            // ======== GET_PENDING_POLICY ========
+            Command::GetPendingPolicy => {
+                let text;
+                let resp = match pending.get() {
+                    Some(change) => {
+                        text = change.policy.to_string();
+                        Response::PolicyPending {
+                            remaining_secs: change.remaining_secs,
+                            policy: &text,
+                        }
+                    }
+                    None => Response::NoPendingPolicy,
+                };
+                port.send(&resp.to_string())?;
+            }
+
+            // ======== CREATE_TX ========
+            Command::CreateTx => {
+                if !protection.allows(policy.as_ref()) {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(ErrorCode::UnprotectedDevice).to_string())?;
+                    continue;
+                }
+                // The placeholder is a Solana transaction
+                if policy
+                    .as_ref()
+                    .is_some_and(|policy| policy.chain != ChainProfile::Solana)
+                {
+                    led.play(led::ERROR);
+                    let violation = PolicyViolation::ChainMismatch.to_string();
+                    let resp = Response::ErrorDetail(ErrorCode::CreateTx, &violation);
+                    port.send(&resp.to_string())?;
+                    continue;
+                }
+                // Create placeholder transaction with memo
+                match create_placeholder_transaction(&signer) {
+                    Ok(tx_bytes) => {
+                        let tx_encoded = codec::encode_binary(&tx_bytes);
+
+                        // Success pattern: Triple blink
+                        led.play(led::TX_CREATED);
+
+                        let response = Response::Transaction(&tx_encoded).to_string();
+                        port.send(&response)?;
+                    }
+                    Err(e) => {
+                        // Error pattern: Five rapid blinks
+                        led.play(led::ERROR);
+                        let e = e.to_string();
+                        let error_response = Response::ErrorDetail(ErrorCode::CreateTx, &e);
+                        port.send(&error_response.to_string())?;
+                    }
+                }
+            }
+
+            // ======== TX_INFO ========
+            Command::TxInfo => {
+                // Display transaction information
+                led.play(led::ACK);
+
+                let info = format!(
+                    "memo='Hello from ESP32 Solana Signer!';blockhash={};program=MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr",
+                    PLACEHOLDER_BLOCKHASH
+                );
+                port.send(&Response::TxInfo(&info).to_string())?;
+            }
+
+            // ======== 2FA: OTP_BEGIN ========
+            Command::OtpBegin => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                #[cfg(feature = "twofa")]
+                {
+                    match twofa::TwoFa::begin(&mut nvs, &mut rng) {
+                        Ok(b32) => {
+                            // short blink
+                            led.play(led::OTP_SECRET_ISSUED);
+                            let resp = Response::OtpSecret {
+                                secret: &b32,
+                                digits: twofa::OTP_DIGITS,
+                                period: twofa::OTP_PERIOD,
                             }
-                            Err(e) => {
-                                // Error pattern: Five rapid blinks
-                                for _ in 0..5 {
-                                    led.set_high()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(100);
-                                    led.set_low()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(100);
+                            .to_string();
+                            port.send(&resp)?;
+                        }
+                        Err(e) => {
+                            led.play(led::OTP_ERROR);
+                            let resp = match e.downcast_ref::<ErrorCode>() {
+                                Some(code) => Response::Error(*code).to_string(),
+                                None => {
+                                    let e = e.to_string();
+                                    Response::ErrorDetail(ErrorCode::OtpBegin, &e).to_string()
                                 }
-                                let error_response =
-                                    format!("ERROR:Transaction creation failed: {}", e);
-                                send_response(&mut uart, &error_response)?;
-                            }
+                            };
+                            port.send(&resp)?;
+                        }
+                    }
+                }
+                #[cfg(not(feature = "twofa"))]
+                {
+                    port.send(&Response::Error(ErrorCode::OtpDisabled).to_string())?;
+                }
+            }
+
+            // ======== 2FA: OTP_CONFIRM:CODE[:UNIX] ========
+            #[allow(unused_variables)]
+            Command::OtpConfirm { code, unix } => {
+                #[cfg(feature = "twofa")]
+                {
+                    match twofa::TwoFa::confirm(&mut nvs, code, unix) {
+                        Ok(()) => {
+                            // confirm blink (short, short, long)
+                            led.play(led::OTP_CONFIRMED);
+                            port.send(&Response::OtpConfirmed.to_string())?;
+                        }
+                        Err(_) => {
+                            led.play(led::OTP_BAD_CODE);
+                            port.send(&Response::Error(ErrorCode::OtpBadCode).to_string())?;
+                        }
+                    }
+                }
+                #[cfg(not(feature = "twofa"))]
+                {
+                    port.send(&Response::Error(ErrorCode::OtpDisabled).to_string())?;
+                }
+            }
+
+            // ======== 2FA: OTP_UNLOCK:CODE[:UNIX] ========
+            #[allow(unused_variables)]
+            Command::OtpUnlock { code, unix } => {
+                #[cfg(feature = "twofa")]
+                {
+                    match twofa::TwoFa::unlock(&mut nvs, code, unix) {
+                        Ok(until) => {
+                            unlocked_until = until;
+                            // Two short + one long blink
+                            led.play(led::UNLOCKED);
+                            let resp = Response::UnlockedUntil(unlocked_until).to_string();
+                            port.send(&resp)?;
+                        }
+                        Err(_) => {
+                            led.play(led::OTP_BAD_CODE);
+                            port.send(&Response::Error(ErrorCode::OtpBadCode).to_string())?;
+                        }
+                    }
+                }
+                #[cfg(not(feature = "twofa"))]
+                {
+                    port.send(&Response::Error(ErrorCode::OtpDisabled).to_string())?;
+                }
+            }
+
+            // ======== 2FA: OTP_STATUS ========
+            Command::OtpStatus => {
+                #[cfg(feature = "twofa")]
+                {
+                    match twofa::TwoFa::is_enrolled(&mut nvs) {
+                        Ok(enrolled) => {
+                            let fields = format!(
+                                "enrolled={};unlocked_until={};now={}",
+                                if enrolled { "yes" } else { "no" },
+                                unlocked_until,
+                                twofa::TwoFa::device_unix_time()
+                            );
+                            port.send(&Response::OtpStatus(&fields).to_string())?;
                         }
+                        Err(e) => {
+                            error!("{}", e);
+                            let resp = Response::ErrorDetail(ErrorCode::OtpBegin, "STORAGE");
+                            port.send(&resp.to_string())?;
+                        }
+                    }
+                }
+                #[cfg(not(feature = "twofa"))]
+                {
+                    port.send(&Response::Error(ErrorCode::OtpDisabled).to_string())?;
+                }
+            }
 
-                    // ======== TX_INFO ========
-                    } else if input == "TX_INFO" {
-                        // Display transaction information
-                        led.set_high()?;
-                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(100);
-                        led.set_low()?;
+            // ======== SIGN / SIGN_TX (gated by pairing, hardware protection, 2FA window and policy) ========
+            Command::SignTx {
+                message: encoded_message,
+                tokens,
+            } => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                if !protection.allows(policy.as_ref()) {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(ErrorCode::UnprotectedDevice).to_string())?;
+                    continue;
+                }
+                // If 2FA is enabled, require unlocked session
+                #[cfg(feature = "twofa")]
+                {
+                    if twofa::TwoFa::is_locked(unlocked_until) {
+                        led.play(led::LOCKED);
+                        port.send(&Response::Error(ErrorCode::Locked).to_string())?;
+                        continue;
+                    }
+                }
+
+                let Some(message_bytes) = codec::decode_binary(encoded_message) else {
+                    led.play(led::ERROR);
+                    let resp = Response::ErrorDetail(ErrorCode::Encoding, codec::BINARY_ENCODING);
+                    port.send(&resp.to_string())?;
+                    continue;
+                };
 
-                        let info = format!(
-                            "TX_INFO:memo='Hello from ESP32 Solana Signer!';blockhash={};program=MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr",
-                            PLACEHOLDER_BLOCKHASH
+                // Look at what we're signing before bothering the human
+                let tx_info =
+                    tx_introspection::introspect_transaction(&message_bytes, &pubkey_bytes);
+                // Labels have to fit the message, or they'd describe something else
+                let labels = token_labels::parse(tokens).and_then(|labels| {
+                    match &tx_info {
+                        Ok(info) => token_labels::bind(&labels, info)?,
+                        Err(_) if !labels.is_empty() => return Err(LabelError::UnknownMint),
+                        Err(_) => {}
+                    }
+                    Ok(labels)
+                });
+                let labels = match labels {
+                    Ok(labels) => labels,
+                    Err(e) => {
+                        led.play(led::ERROR);
+                        let e = e.to_string();
+                        let resp = Response::ErrorDetail(ErrorCode::Labels, &e).to_string();
+                        port.send(&resp)?;
+                        continue;
+                    }
+                };
+                match &tx_info {
+                    Ok(info) => {
+                        if !info.fee_payer_is_signer {
+                            warn!("Fee payer does not match signer!");
+                        }
+                        debug!(
+                            "Signing:\n{}",
+                            tx_introspection::format_labeled_transaction_info(info, &labels)
                         );
-                        send_response(&mut uart, &info)?;
-
-                    // ======== 2FA: OTP_BEGIN ========
-                    } else if input == "OTP_BEGIN" {
-                        #[cfg(feature = "twofa")]
-                        {
-                            match twofa::TwoFa::begin(&mut nvs) {
-                                Ok(b32) => {
-                                    // short blink
-                                    led.set_high()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(180);
-                                    led.set_low()?;
-                                    let resp = format!(
-                                        "OTP_SECRET:{};ALGO=SHA1;DIGITS={};PERIOD={}",
-                                        b32,
-                                        twofa::OTP_DIGITS,
-                                        twofa::OTP_PERIOD
-                                    );
-                                    send_response(&mut uart, &resp)?;
-                                }
-                                Err(e) => {
-                                    for _ in 0..3 {
-                                        led.set_high()?;
-                                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(120);
-                                        led.set_low()?;
-                                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(120);
-                                    }
-                                    send_response(&mut uart, &format!("ERROR:{}", e))?;
-                                }
+                    }
+                    Err(e) => debug!("Signing undecoded message: {}", e),
+                }
+                let Some(policy) = &policy else {
+                    led.play(led::ERROR);
+                    let resp = Response::ErrorDetail(ErrorCode::Policy, "UNAVAILABLE");
+                    port.send(&resp.to_string())?;
+                    continue;
+                };
+                // The key's chain profile decides what is signed, if anything
+                let signing_input = match policy
+                    .signing_input(&message_bytes, tx_info.as_ref().ok())
+                {
+                    Ok(input) => input.into_owned(),
+                    Err(violation) => {
+                        led.play(led::ERROR);
+                        let violation = violation.to_string();
+                        let resp = Response::ErrorDetail(ErrorCode::Policy, &violation).to_string();
+                        port.send(&resp)?;
+                        continue;
+                    }
+                };
+
+                // Waiting for the BOOT button: fast blink until pressed
+                led.play(led::AWAIT_SIGN);
+                #[cfg(feature = "ble")]
+                port.status(Status::AwaitingButton(&ble::summary(
+                    tx_info.as_ref().ok(),
+                    &labels,
+                    message_bytes.len(),
+                )));
+                while !button.is_low() {
+                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(BUTTON_POLL_MS);
+                }
+                #[cfg(feature = "ble")]
+                port.status(Status::Idle);
+
+                // Sign
+                let signature_bytes = signer.sign(signing_input)?;
+                let encoded_signature = codec::encode_binary(&signature_bytes);
+                #[cfg(feature = "audit")]
+                audit::record(&mut nvs, Event::Signed, Some(&message_bytes));
+
+                // Success: triple flash with longer third
+                led.play(led::SIGNED);
+
+                let response = Response::Signature(&encoded_signature).to_string();
+                port.send(&response)?;
+            }
+
+            // Handled as SIGN_TX
+            Command::Sign(_) => unreachable!(),
+
+            // ======== OTA: OTA_BEGIN:SIZE ========
+            #[allow(unused_variables)]
+            Command::OtaBegin { size } => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                #[cfg(feature = "ota")]
+                {
+                    // Starting over discards any half-finished upload
+                    ota_session = None;
+                    match ota::OtaSession::begin(size) {
+                        Ok(session) => {
+                            ota_session = Some(session);
+                            led.play(led::ACK);
+                            let resp = Response::OtaReady {
+                                chunk_max: ota::OTA_CHUNK_MAX,
                             }
+                            .to_string();
+                            port.send(&resp)?;
                         }
-                        #[cfg(not(feature = "twofa"))]
-                        {
-                            send_response(&mut uart, "ERROR:OTP_DISABLED")?;
+                        Err(e) => {
+                            let e = e.to_string();
+                            let resp = Response::ErrorDetail(ErrorCode::OtaBegin, &e).to_string();
+                            port.send(&resp)?;
                         }
+                    }
+                }
+                #[cfg(not(feature = "ota"))]
+                {
+                    port.send(&Response::Error(ErrorCode::OtaDisabled).to_string())?;
+                }
+            }
 
-                    // ======== 2FA: OTP_CONFIRM:CODE[:UNIX] ========
-                    } else if input.starts_with("OTP_CONFIRM:") {
-                        #[cfg(feature = "twofa")]
-                        {
-                            let rest = &input["OTP_CONFIRM:".len()..];
-                            let parts: Vec<&str> = rest.split(':').collect();
-                            let code = parts.get(0).copied().unwrap_or("");
-                            let unix = parts.get(1).and_then(|s| s.parse::<u64>().ok());
-                            match twofa::TwoFa::confirm(&mut nvs, code, unix) {
+            // ======== OTA: OTA_CHUNK:OFFSET:BASE64 ========
+            #[allow(unused_variables)]
+            Command::OtaChunk(args) => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                #[cfg(feature = "ota")]
+                {
+                    let written = match ota_session.as_mut() {
+                        Some(session) => session.write_chunk(args),
+                        None => Err(anyhow::anyhow!("no update in progress")),
+                    };
+                    match written {
+                        Ok(next) => {
+                            let resp = Response::OtaAck { next }.to_string();
+                            port.send(&resp)?;
+                        }
+                        Err(e) => {
+                            let e = e.to_string();
+                            let resp = Response::ErrorDetail(ErrorCode::OtaChunk, &e).to_string();
+                            port.send(&resp)?;
+                        }
+                    }
+                }
+                #[cfg(not(feature = "ota"))]
+                {
+                    port.send(&Response::Error(ErrorCode::OtaDisabled).to_string())?;
+                }
+            }
+
+            // ======== OTA: OTA_END:SIGNATURE (button-confirmed) ========
+            #[allow(unused_variables)]
+            Command::OtaEnd(signature_b64) => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                #[cfg(feature = "ota")]
+                {
+                    let verified = match ota_session.take() {
+                        Some(session) => session.verify(signature_b64).map(|_| session),
+                        None => Err(anyhow::anyhow!("no update in progress")),
+                    };
+                    match verified {
+                        Ok(session) => {
+                            // Signature is good; the human still has to approve the flash
+                            led.play(led::AWAIT_CONFIRM);
+                            while !button.is_low() {
+                                esp_idf_svc::hal::delay::FreeRtos::delay_ms(BUTTON_POLL_MS);
+                            }
+                            led.off();
+
+                            match session.activate() {
                                 Ok(()) => {
-                                    // confirm blink (short, short, long)
-                                    led.set_high()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(120);
-                                    led.set_low()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(120);
-                                    led.set_high()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(300);
-                                    led.set_low()?;
-                                    send_response(&mut uart, "OTP_CONFIRMED")?;
-                                }
-                                Err(_) => {
-                                    for _ in 0..4 {
-                                        led.set_high()?;
-                                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(80);
-                                        led.set_low()?;
-                                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(80);
+                                    // Let the blink finish before the chip goes away
+                                    led.play(led::FAREWELL);
+                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(1000);
+                                    port.send(&Response::OtaOk.to_string())?;
+                                    port.flush()?;
+                                    unsafe {
+                                        esp_idf_sys::esp_restart();
                                     }
-                                    send_response(&mut uart, "ERROR:OTP_BAD_CODE")?;
+                                }
+                                Err(e) => {
+                                    let e = e.to_string();
+                                    let resp = Response::ErrorDetail(ErrorCode::OtaActivate, &e)
+                                        .to_string();
+                                    port.send(&resp)?;
                                 }
                             }
                         }
-                        #[cfg(not(feature = "twofa"))]
-                        {
-                            send_response(&mut uart, "ERROR:OTP_DISABLED")?;
+                        Err(e) => {
+                            led.play(led::ERROR);
+                            let e = e.to_string();
+                            let resp = Response::ErrorDetail(ErrorCode::OtaVerify, &e).to_string();
+                            port.send(&resp)?;
                         }
+                    }
+                }
+                #[cfg(not(feature = "ota"))]
+                {
+                    port.send(&Response::Error(ErrorCode::OtaDisabled).to_string())?;
+                }
+            }
 
-                    // ======== 2FA: OTP_UNLOCK:CODE[:UNIX] ========
-                    } else if input.starts_with("OTP_UNLOCK:") {
-                        #[cfg(feature = "twofa")]
-                        {
-                            let rest = &input["OTP_UNLOCK:".len()..];
-                            let parts: Vec<&str> = rest.split(':').collect();
-                            let code = parts.get(0).copied().unwrap_or("");
-                            let unix = parts.get(1).and_then(|s| s.parse::<u64>().ok());
-
-                            match twofa::TwoFa::unlock(&mut nvs, code, unix) {
-                                Ok(until) => {
-                                    unlocked_until = until;
-                                    // Two short + one long blink
-                                    led.set_high()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(120);
-                                    led.set_low()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(120);
-                                    led.set_high()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(120);
-                                    led.set_low()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(120);
-                                    led.set_high()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(350);
-                                    led.set_low()?;
-                                    let resp = format!("UNLOCKED_UNTIL:{}", unlocked_until);
-                                    send_response(&mut uart, &resp)?;
-                                }
-                                Err(_) => {
-                                    for _ in 0..4 {
-                                        led.set_high()?;
-                                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(80);
-                                        led.set_low()?;
-                                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(80);
-                                    }
-                                    send_response(&mut uart, "ERROR:OTP_BAD_CODE")?;
-                                }
-                            }
+            // ======== OTA: OTA_ABORT ========
+            Command::OtaAbort => {
+                #[cfg(feature = "ota")]
+                {
+                    ota_session = None;
+                    port.send(&Response::OtaAborted.to_string())?;
+                }
+                #[cfg(not(feature = "ota"))]
+                {
+                    port.send(&Response::Error(ErrorCode::OtaDisabled).to_string())?;
+                }
+            }
+
+            // ======== PAIR_BEGIN:HOST_KEY ========
+            Command::PairBegin(host_key) => {
+                let Some(host_key) = codec::decode_key(host_key) else {
+                    let resp = Response::ErrorDetail(ErrorCode::Pair, "BAD_KEY");
+                    port.send(&resp.to_string())?;
+                    continue;
+                };
+                match trust.begin(host_key) {
+                    Ok(code) => {
+                        // Blinks until PAIR_CONFIRM's button press, for the
+                        // operator to compare with the host's
+                        led.play(led::code(&code));
+                        let session_key = codec::encode_key(&trust.session_key());
+                        port.send(&Response::Pairing(&session_key).to_string())?;
+                    }
+                    Err(e) => {
+                        let e = e.to_string();
+                        let resp = Response::ErrorDetail(ErrorCode::Pair, &e).to_string();
+                        port.send(&resp)?;
+                    }
+                }
+            }
+
+            // ======== PAIR_CONFIRM (after button press) ========
+            Command::PairConfirm => {
+                if !trust.is_pending() {
+                    let resp = Response::ErrorDetail(ErrorCode::Pair, "NOT_STARTED");
+                    port.send(&resp.to_string())?;
+                    continue;
+                }
+
+                // The code keeps blinking until the operator confirms
+                while !button.is_low() {
+                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(BUTTON_POLL_MS);
+                }
+                led.off();
+
+                match trust.confirm(&mut nvs) {
+                    Ok(host_key) => {
+                        info!("Paired with host {}", codec::encode_key(&host_key));
+                        #[cfg(feature = "audit")]
+                        audit::record(&mut nvs, Event::Paired, Some(&host_key));
+                        led.play(led::ACK);
+                        port.send(&Response::Paired.to_string())?;
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        let resp = Response::ErrorDetail(ErrorCode::Pair, "STORAGE");
+                        port.send(&resp.to_string())?;
+                    }
+                }
+            }
+
+            // ======== UNPAIR (after button press) ========
+            Command::Unpair => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                led.play(led::AWAIT_CONFIRM);
+                while !button.is_low() {
+                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(BUTTON_POLL_MS);
+                }
+                led.off();
+
+                match trust.unpair(&mut nvs) {
+                    Ok(()) => {
+                        info!("Unpaired");
+                        #[cfg(feature = "audit")]
+                        audit::record(&mut nvs, Event::Unpaired, None);
+                        port.send(&Response::Unpaired.to_string())?;
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        let resp = Response::ErrorDetail(ErrorCode::Pair, "STORAGE");
+                        port.send(&resp.to_string())?;
+                    }
+                }
+            }
+
+            // ======== AUTH_BEGIN:HOST_NONCE ========
+            Command::AuthBegin(encoded_nonce) => {
+                let Some(host_nonce) = codec::decode_binary(encoded_nonce) else {
+                    let resp = Response::ErrorDetail(ErrorCode::Encoding, codec::BINARY_ENCODING);
+                    port.send(&resp.to_string())?;
+                    continue;
+                };
+                match trust.challenge(&host_nonce, &mut rng) {
+                    Ok((device_nonce, signature)) => {
+                        let nonce = codec::encode_binary(&device_nonce);
+                        let signature = codec::encode_binary(&signature);
+                        let resp = Response::AuthChallenge {
+                            nonce: &nonce,
+                            signature: &signature,
+                        };
+                        port.send(&resp.to_string())?;
+                    }
+                    Err(code) => {
+                        port.send(&Response::Error(code).to_string())?;
+                    }
+                }
+            }
+
+            // ======== AUTH_PROOF:SIGNATURE ========
+            Command::AuthProof(encoded_signature) => {
+                let Some(signature) = codec::decode_binary(encoded_signature) else {
+                    let resp = Response::ErrorDetail(ErrorCode::Encoding, codec::BINARY_ENCODING);
+                    port.send(&resp.to_string())?;
+                    continue;
+                };
+                match trust.prove(&signature) {
+                    Ok(()) => {
+                        port.send(&Response::Authenticated.to_string())?;
+                    }
+                    Err(code) => {
+                        warn!("Host failed to authenticate");
+                        led.play(led::ERROR);
+                        port.send(&Response::Error(code).to_string())?;
+                    }
+                }
+            }
+
+            // ======== BACKUP_TO_SD:PIN:PASSPHRASE (after button press) ========
+            #[allow(unused_variables)]
+            Command::BackupToSd { pin, passphrase } => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                if !protection.allows(policy.as_ref()) {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(ErrorCode::UnprotectedDevice).to_string())?;
+                    continue;
+                }
+                #[cfg(feature = "sd-backup")]
+                {
+                    if !signer_core::backup::valid_pin(pin) || passphrase.is_empty() {
+                        let resp = Response::ErrorDetail(ErrorCode::Backup, "BAD_CREDENTIALS");
+                        port.send(&resp.to_string())?;
+                        continue;
+                    }
+
+                    // The seed leaves the device: the operator has to agree
+                    led.play(led::AWAIT_CONFIRM);
+                    while !button.is_low() {
+                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(BUTTON_POLL_MS);
+                    }
+                    led.off();
+
+                    let contents = signer_core::backup::Contents {
+                        seed,
+                        policy: policy.as_ref().map(Policy::to_bytes).unwrap_or_default(),
+                    };
+                    match sd_card.backup(pin, passphrase, &contents, &mut rng) {
+                        Ok(name) => {
+                            info!("Backed up to {}", name);
+                            led.play(led::ACK);
+                            port.send(&Response::BackedUp(&name).to_string())?;
                         }
-                        #[cfg(not(feature = "twofa"))]
-                        {
-                            send_response(&mut uart, "ERROR:OTP_DISABLED")?;
+                        Err(e) => {
+                            error!("Backup failed: {:#}", e);
+                            led.play(led::ERROR);
+                            let e = e.to_string();
+                            let resp = Response::ErrorDetail(ErrorCode::Backup, &e).to_string();
+                            port.send(&resp)?;
                         }
+                    }
+                }
+                #[cfg(not(feature = "sd-backup"))]
+                {
+                    port.send(&Response::Error(ErrorCode::SdDisabled).to_string())?;
+                }
+            }
 
-                    // ======== SIGN (gated by 2FA window if enabled) ========
-                    } else if input.starts_with("SIGN:") {
-                        // If 2FA is enabled, require unlocked session
-                        #[cfg(feature = "twofa")]
-                        {
-                            let now = twofa::TwoFa::device_unix_time();
-                            if now > unlocked_until {
-                                for _ in 0..3 {
-                                    led.set_high()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(100);
-                                    led.set_low()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(100);
-                                }
-                                send_response(&mut uart, "ERROR:LOCKED")?;
-                                buffer.clear();
+            // ======== RESTORE_FROM_SD:PIN:PASSPHRASE (after button press) ========
+            #[allow(unused_variables)]
+            Command::RestoreFromSd { pin, passphrase } => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                if !protection.allows(policy.as_ref()) {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(ErrorCode::UnprotectedDevice).to_string())?;
+                    continue;
+                }
+                #[cfg(feature = "sd-backup")]
+                {
+                    if !signer_core::backup::valid_pin(pin) {
+                        let resp = Response::ErrorDetail(ErrorCode::Restore, "BAD_CREDENTIALS");
+                        port.send(&resp.to_string())?;
+                        continue;
+                    }
+
+                    // The current key is overwritten: the operator has to agree
+                    // before anything is read
+                    led.play(led::AWAIT_CONFIRM);
+                    while !button.is_low() {
+                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(BUTTON_POLL_MS);
+                    }
+                    led.off();
+
+                    let contents = match sd_card.restore(pin, passphrase) {
+                        Ok(contents) => contents,
+                        Err(e) => {
+                            warn!("Restore failed: {:?}", e);
+                            led.play(led::ERROR);
+                            let resp = Response::ErrorDetail(ErrorCode::Restore, e.detail());
+                            port.send(&resp.to_string())?;
+                            continue;
+                        }
+                    };
+                    let restored_policy = if contents.policy.is_empty() {
+                        None
+                    } else {
+                        match Policy::from_bytes(&contents.policy) {
+                            Some(policy) => Some(policy),
+                            None => {
+                                led.play(led::ERROR);
+                                let resp = Response::ErrorDetail(ErrorCode::Restore, "CORRUPT");
+                                port.send(&resp.to_string())?;
                                 continue;
                             }
                         }
+                    };
 
-                        // Extract the base64 message after "SIGN:"
-                        let base64_message = &input[5..];
-                        match base64::engine::general_purpose::STANDARD.decode(base64_message) {
-                            Ok(message_bytes) => {
-                                // Waiting for the BOOT button: fast blink until pressed
-                                let mut led_state = false;
-                                while !button.is_low() {
-                                    led_state = !led_state;
-                                    if led_state {
-                                        led.set_high()?;
-                                    } else {
-                                        led.set_low()?;
-                                    }
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(200);
-                                }
+                    // A restored policy looser than the current one waits out its
+                    // delay like SET_POLICY. Either way a change queued against the
+                    // old policy doesn't carry over.
+                    let delay = match (&restored_policy, &policy) {
+                        (Some(restored), Some(current)) => restored.change_delay_secs(current),
+                        _ => 0,
+                    };
+                    let mut stored = platform::NvsStorage(&mut nvs)
+                        .set(keys::SIGNING_KEY_NAME, &contents.seed)
+                        .map_err(anyhow::Error::from);
+                    if stored.is_ok() {
+                        stored = match restored_policy {
+                            Some(restored) if delay > 0 => pending.hold(&mut nvs, restored, delay),
+                            Some(restored) => config::store_policy(&mut nvs, &restored)
+                                .and_then(|()| config::clear_pending(&mut nvs)),
+                            None => config::clear_pending(&mut nvs),
+                        };
+                    }
+                    if let Err(e) = stored {
+                        error!("Restore not saved: {:#}", e);
+                        let resp = Response::ErrorDetail(ErrorCode::Restore, "STORAGE");
+                        port.send(&resp.to_string())?;
+                        continue;
+                    }
 
-                                // Sign
-                                let signature = signing_key.sign(&message_bytes);
-                                let signature_bytes = signature.to_bytes();
-                                let base64_signature = base64::engine::general_purpose::STANDARD
-                                    .encode(&signature_bytes);
-
-                                // Success: triple flash with longer third
-                                led.set_high()?;
-                                esp_idf_svc::hal::delay::FreeRtos::delay_ms(150);
-                                led.set_low()?;
-                                esp_idf_svc::hal::delay::FreeRtos::delay_ms(150);
-                                led.set_high()?;
-                                esp_idf_svc::hal::delay::FreeRtos::delay_ms(150);
-                                led.set_low()?;
-                                esp_idf_svc::hal::delay::FreeRtos::delay_ms(150);
-                                led.set_high()?;
-                                esp_idf_svc::hal::delay::FreeRtos::delay_ms(450);
-                                led.set_low()?;
-
-                                let response = format!("SIGNATURE:{}", base64_signature);
-                                send_response(&mut uart, &response)?;
-                            }
-                            Err(_) => {
-                                for _ in 0..5 {
-                                    led.set_high()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(100);
-                                    led.set_low()?;
-                                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(100);
-                                }
-                                send_response(&mut uart, "ERROR:Invalid base64 encoding")?;
-                            }
-                        }
+                    // The signing task holds the old key; start over with the new one
+                    let restored = SigningKey::from_bytes(&contents.seed).verifying_key();
+                    #[cfg(feature = "audit")]
+                    audit::record(&mut nvs, Event::Restored, Some(restored.as_bytes()));
+                    let restored = codec::encode_key(restored.as_bytes());
+                    info!("Restored {} from SD; restarting", restored);
+                    led.play(led::FAREWELL);
+                    esp_idf_svc::hal::delay::FreeRtos::delay_ms(1000);
+                    port.send(&Response::Restored(&restored).to_string())?;
+                    port.flush()?;
+                    unsafe {
+                        esp_idf_sys::esp_restart();
+                    }
+                }
+                #[cfg(not(feature = "sd-backup"))]
+                {
+                    port.send(&Response::Error(ErrorCode::SdDisabled).to_string())?;
+                }
+            }
 
-                    // ======== SHUTDOWN ========
-                    } else if input == "SHUTDOWN" {
-                        // Long blink then deep sleep
-                        led.set_high()?;
-                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(1000);
-                        led.set_low()?;
+            // ======== AUDIT_REGISTER:AUDITOR_KEY (after button press) ========
+            #[allow(unused_variables)]
+            Command::AuditRegister(auditor_key) => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                #[cfg(feature = "audit")]
+                {
+                    let Some(auditor_key) = codec::decode_key(auditor_key) else {
+                        let resp = Response::ErrorDetail(ErrorCode::Audit, "BAD_KEY");
+                        port.send(&resp.to_string())?;
+                        continue;
+                    };
 
-                        send_response(&mut uart, "SHUTDOWN_OK")?;
-                        unsafe {
-                            esp_deep_sleep_start();
-                        }
-                    } else if !input.is_empty() {
-                        // Unknown command
-                        println!("Received unknown command: '{}'", input);
-                        send_response(&mut uart, "ERROR:Unknown command")?;
+                    // Whoever holds this key reads the log: the operator has
+                    // to agree
+                    led.play(led::AWAIT_CONFIRM);
+                    while !button.is_low() {
+                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(BUTTON_POLL_MS);
                     }
+                    led.off();
 
-                    buffer.clear();
-                } else {
-                    buffer.push(ch);
+                    match audit::register(&mut nvs, &auditor_key) {
+                        Ok(()) => {
+                            info!("Auditor key registered");
+                            port.send(&Response::AuditRegistered.to_string())?;
+                        }
+                        Err(e) => {
+                            error!("{:#}", e);
+                            let resp = Response::ErrorDetail(ErrorCode::Audit, "STORAGE");
+                            port.send(&resp.to_string())?;
+                        }
+                    }
+                }
+                #[cfg(not(feature = "audit"))]
+                {
+                    port.send(&Response::Error(ErrorCode::AuditDisabled).to_string())?;
                 }
             }
-            Ok(0) => {}
-            Ok(n) => unreachable!("Unexpected read size: {}", n),
-            Err(e) => {
-                if e.code() != ESP_ERR_TIMEOUT {
-                    // Simplified error state: Rapid blinking
-                    for _ in 0..10 {
-                        led.set_high()?;
-                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(100);
-                        led.set_low()?;
-                        esp_idf_svc::hal::delay::FreeRtos::delay_ms(100);
+
+            // ======== AUDIT_EXPORT ========
+            Command::AuditExport => {
+                if let Err(code) = trust.check() {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(code).to_string())?;
+                    continue;
+                }
+                if !protection.allows(policy.as_ref()) {
+                    led.play(led::LOCKED);
+                    port.send(&Response::Error(ErrorCode::UnprotectedDevice).to_string())?;
+                    continue;
+                }
+                #[cfg(feature = "audit")]
+                match audit::export(&signer, &mut nvs, &mut rng) {
+                    Ok(Some(sealed)) => {
+                        let sealed = codec::encode_binary(&sealed);
+                        port.send(&Response::Audit(&sealed).to_string())?;
                     }
+                    Ok(None) => {
+                        let resp = Response::ErrorDetail(ErrorCode::Audit, "NO_AUDITOR");
+                        port.send(&resp.to_string())?;
+                    }
+                    Err(e) => {
+                        error!("Audit export failed: {:#}", e);
+                        led.play(led::ERROR);
+                        let resp = Response::ErrorDetail(ErrorCode::Audit, "STORAGE");
+                        port.send(&resp.to_string())?;
+                    }
+                }
+                #[cfg(not(feature = "audit"))]
+                {
+                    port.send(&Response::Error(ErrorCode::AuditDisabled).to_string())?;
+                }
+            }
+
+            // ======== SHUTDOWN ========
+            Command::Shutdown => {
+                // Long blink then deep sleep; let it finish before the chip goes away
+                led.play(led::FAREWELL);
+                esp_idf_svc::hal::delay::FreeRtos::delay_ms(1000);
+
+                port.send(&Response::ShutdownOk.to_string())?;
+                port.flush()?;
+                unsafe {
+                    esp_deep_sleep_start();
                 }
             }
+
+            // Blank line (e.g. the wake preamble): nothing to do
+            Command::Empty => {}
+
+            Command::Malformed(name) => {
+                let resp = Response::ErrorDetail(ErrorCode::BadArguments, name).to_string();
+                port.send(&resp)?;
+            }
+
+            Command::Unknown(input) => {
+                warn!("Received unknown command: '{}'", input);
+                port.send(&Response::Error(ErrorCode::UnknownCommand).to_string())?;
+            }
         }
     }
+
+    Err(anyhow::anyhow!("serial task stopped"))
 }
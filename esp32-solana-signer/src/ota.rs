@@ -0,0 +1,159 @@
+#![cfg(feature = "ota")]
+
+use crate::{codec, hwsha};
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+use esp_idf_sys as sys;
+
+/// Largest decoded payload accepted in a single OTA_CHUNK line.
+pub const OTA_CHUNK_MAX: usize = 512;
+
+/// Domain tag prepended to the signed OTA digest so a vendor signature can
+/// never be confused with anything else signed by the same key.
+const OTA_DOMAIN: &[u8] = b"ESP32_SIGNER_OTA_V1";
+
+/// Vendor public key baked in at build time, base58 (hex without the `bs58`
+/// feature). Without it the device refuses every update.
+const VENDOR_PUBKEY: Option<&str> = option_env!("ESP32_SIGNER_OTA_PUBKEY");
+
+/// An in-progress image upload into the inactive OTA partition.
+///
+/// Dropping the session without calling `activate` aborts the update and
+/// leaves the running image as the boot target.
+pub struct OtaSession {
+    handle: sys::esp_ota_handle_t,
+    partition: *const sys::esp_partition_t,
+    expected_len: u32,
+    written: u32,
+    hasher: hwsha::Sha256,
+    finished: bool,
+}
+
+impl OtaSession {
+    /// Open the next update partition for an image of `image_len` bytes.
+    pub fn begin(image_len: u32) -> Result<Self> {
+        vendor_key()?;
+
+        let partition = unsafe { sys::esp_ota_get_next_update_partition(core::ptr::null()) };
+        if partition.is_null() {
+            return Err(anyhow!("no OTA partition"));
+        }
+        let capacity = unsafe { (*partition).size };
+        if image_len == 0 || image_len > capacity {
+            return Err(anyhow!("image size {} exceeds partition ({})", image_len, capacity));
+        }
+
+        let hasher = hwsha::Sha256::new()?;
+        let mut handle: sys::esp_ota_handle_t = Default::default();
+        sys::esp!(unsafe { sys::esp_ota_begin(partition, image_len as usize, &mut handle) })?;
+
+        Ok(Self {
+            handle,
+            partition,
+            expected_len: image_len,
+            written: 0,
+            hasher,
+            finished: false,
+        })
+    }
+
+    /// Handle the `<offset>:<data>` argument of OTA_CHUNK.
+    /// Returns the offset the host should send next.
+    pub fn write_chunk(&mut self, args: &str) -> Result<u32> {
+        let (offset_str, encoded) = args
+            .split_once(':')
+            .ok_or_else(|| anyhow!("malformed chunk"))?;
+        let offset: u32 = offset_str.parse().map_err(|_| anyhow!("bad offset"))?;
+        let data = codec::decode_binary(encoded)
+            .ok_or_else(|| anyhow!("bad {}", codec::BINARY_ENCODING))?;
+        if data.is_empty() || data.len() > OTA_CHUNK_MAX {
+            return Err(anyhow!("chunk size {} out of range", data.len()));
+        }
+
+        // A retransmit of the chunk we just wrote (host missed our ACK)
+        if offset + data.len() as u32 == self.written {
+            return Ok(self.written);
+        }
+        if offset != self.written {
+            return Err(anyhow!("expected offset {}", self.written));
+        }
+        if self.written + data.len() as u32 > self.expected_len {
+            return Err(anyhow!("chunk overruns declared size"));
+        }
+
+        sys::esp!(unsafe {
+            sys::esp_ota_write(self.handle, data.as_ptr() as _, data.len() as _)
+        })?;
+        self.hasher.update(&data)?;
+        self.written += data.len() as u32;
+        Ok(self.written)
+    }
+
+    /// Check the vendor signature over the received image without touching
+    /// the boot partition.
+    pub fn verify(&self, signature: &str) -> Result<()> {
+        if self.written != self.expected_len {
+            return Err(anyhow!(
+                "incomplete image: {} of {} bytes",
+                self.written,
+                self.expected_len
+            ));
+        }
+        let sig_bytes = codec::decode_binary(signature)
+            .ok_or_else(|| anyhow!("bad {}", codec::BINARY_ENCODING))?;
+        let signature =
+            Signature::from_slice(&sig_bytes).map_err(|_| anyhow!("bad signature length"))?;
+
+        let digest = self.hasher.digest()?;
+        let mut signed = Vec::with_capacity(OTA_DOMAIN.len() + 4 + digest.len());
+        signed.extend_from_slice(OTA_DOMAIN);
+        signed.extend_from_slice(&self.expected_len.to_le_bytes());
+        signed.extend_from_slice(&digest);
+
+        vendor_key()?
+            .verify_strict(&signed, &signature)
+            .map_err(|_| anyhow!("signature mismatch"))
+    }
+
+    /// Validate the image headers and make it the next boot target.
+    /// The new image boots in PENDING_VERIFY and is rolled back unless it
+    /// reaches `confirm_running_image`.
+    pub fn activate(mut self) -> Result<()> {
+        self.finished = true;
+        sys::esp!(unsafe { sys::esp_ota_end(self.handle) })?;
+        sys::esp!(unsafe { sys::esp_ota_set_boot_partition(self.partition) })?;
+        Ok(())
+    }
+}
+
+impl Drop for OtaSession {
+    fn drop(&mut self) {
+        if !self.finished {
+            unsafe {
+                sys::esp_ota_abort(self.handle);
+            }
+        }
+    }
+}
+
+/// Cancel the bootloader's pending rollback once the firmware has come up
+/// far enough to talk to the host. A freshly flashed image that crashes
+/// before this point is reverted on the next reset.
+pub fn confirm_running_image() -> Result<()> {
+    unsafe {
+        let running = sys::esp_ota_get_running_partition();
+        let mut state: sys::esp_ota_img_states_t = Default::default();
+        if sys::esp!(sys::esp_ota_get_state_partition(running, &mut state)).is_ok()
+            && state == sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+        {
+            sys::esp!(sys::esp_ota_mark_app_valid_cancel_rollback())?;
+        }
+    }
+    Ok(())
+}
+
+fn vendor_key() -> Result<VerifyingKey> {
+    let encoded = VENDOR_PUBKEY.ok_or_else(|| anyhow!("no vendor key in this build"))?;
+    let bytes = codec::decode_key(encoded).ok_or_else(|| anyhow!("invalid vendor key"))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| anyhow!("invalid vendor key"))
+}
@@ -0,0 +1,138 @@
+//! The paired host and this connection's proof of being it.
+//!
+//! The session key (not the Solana key) answers the host's challenges; the
+//! paired host's key, kept in NVS, checks its answers to ours. Being
+//! authenticated lasts until the next AUTH_BEGIN, pairing change, reset, or
+//! a command arriving over the other link.
+
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signature, Signer as _, SigningKey, VerifyingKey};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use signer_core::pairing::{self, CODE_DIGITS, KEY_LEN, NONCE_LEN};
+use signer_core::platform::EntropySource;
+use signer_protocol::error::ErrorCode;
+
+use crate::platform::NvsStorage;
+
+pub struct Pairing {
+    session: SigningKey,
+    host: Option<VerifyingKey>,
+    /// Host key from PAIR_BEGIN, waiting for PAIR_CONFIRM
+    pending: Option<[u8; KEY_LEN]>,
+    /// Nonces of the last AUTH_BEGIN, waiting for AUTH_PROOF
+    challenge: Option<([u8; NONCE_LEN], [u8; NONCE_LEN])>,
+    authenticated: bool,
+}
+
+impl Pairing {
+    pub fn new(session_seed: &[u8; 32], nvs: &mut EspNvs<NvsDefault>) -> Result<Self> {
+        let host = match pairing::load_host(&mut NvsStorage(nvs)) {
+            Ok(host) => host,
+            Err(e) => return Err(anyhow!("paired host unreadable: {:?}", e)),
+        };
+        let host = host
+            .map(|key| VerifyingKey::from_bytes(&key))
+            .transpose()
+            .map_err(|_| anyhow!("paired host key is not a valid Ed25519 key"))?;
+        Ok(Pairing {
+            session: SigningKey::from_bytes(session_seed),
+            host,
+            pending: None,
+            challenge: None,
+            authenticated: false,
+        })
+    }
+
+    pub fn session_key(&self) -> [u8; KEY_LEN] {
+        self.session.verifying_key().to_bytes()
+    }
+
+    /// Refusal for commands that need the paired host on this connection
+    pub fn check(&self) -> Result<(), ErrorCode> {
+        match (&self.host, self.authenticated) {
+            (None, _) => Err(ErrorCode::NotPaired),
+            (Some(_), false) => Err(ErrorCode::Unauthenticated),
+            (Some(_), true) => Ok(()),
+        }
+    }
+
+    /// Remembers `host_key` until PAIR_CONFIRM; returns the code to blink
+    pub fn begin(&mut self, host_key: [u8; KEY_LEN]) -> Result<[u8; CODE_DIGITS]> {
+        VerifyingKey::from_bytes(&host_key).map_err(|_| anyhow!("not a valid Ed25519 key"))?;
+        self.pending = Some(host_key);
+        Ok(pairing::pairing_code(&host_key, &self.session_key()))
+    }
+
+    /// Whether a PAIR_BEGIN is waiting to be confirmed
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Stores the pending host in place of any earlier one; returns its key
+    pub fn confirm(&mut self, nvs: &mut EspNvs<NvsDefault>) -> Result<[u8; KEY_LEN]> {
+        let host_key = self
+            .pending
+            .take()
+            .ok_or_else(|| anyhow!("no pairing in progress"))?;
+        pairing::store_host(&mut NvsStorage(nvs), &host_key)
+            .map_err(|e| anyhow!("host not saved: {:?}", e))?;
+        self.host = Some(
+            VerifyingKey::from_bytes(&host_key).map_err(|_| anyhow!("not a valid Ed25519 key"))?,
+        );
+        self.challenge = None;
+        self.authenticated = false;
+        Ok(host_key)
+    }
+
+    pub fn unpair(&mut self, nvs: &mut EspNvs<NvsDefault>) -> Result<()> {
+        pairing::clear_host(&mut NvsStorage(nvs))
+            .map_err(|e| anyhow!("host not forgotten: {:?}", e))?;
+        self.host = None;
+        self.pending = None;
+        self.challenge = None;
+        self.authenticated = false;
+        Ok(())
+    }
+
+    /// Answers the host's nonce: our nonce, and our signature over both
+    pub fn challenge<R: EntropySource>(
+        &mut self,
+        host_nonce: &[u8],
+        rng: &mut R,
+    ) -> Result<([u8; NONCE_LEN], [u8; 64]), ErrorCode> {
+        self.authenticated = false;
+        self.challenge = None;
+        let Some(host) = &self.host else {
+            return Err(ErrorCode::NotPaired);
+        };
+        let host_nonce: [u8; NONCE_LEN] =
+            host_nonce.try_into().map_err(|_| ErrorCode::BadArguments)?;
+        let mut device_nonce = [0u8; NONCE_LEN];
+        rng.fill(&mut device_nonce)
+            .map_err(|_| ErrorCode::Entropy)?;
+        let message = pairing::device_proof_message(host.as_bytes(), &host_nonce, &device_nonce);
+        let signature = self.session.sign(&message).to_bytes();
+        self.challenge = Some((host_nonce, device_nonce));
+        Ok((device_nonce, signature))
+    }
+
+    /// Forgets any challenge and authentication, as when the link changes
+    pub fn end_session(&mut self) {
+        self.challenge = None;
+        self.authenticated = false;
+    }
+
+    /// Checks the host's signature over our nonce from the last challenge
+    pub fn prove(&mut self, signature: &[u8]) -> Result<(), ErrorCode> {
+        let (Some(host), Some((host_nonce, device_nonce))) = (&self.host, self.challenge.take())
+        else {
+            return Err(ErrorCode::Auth);
+        };
+        let signature = Signature::from_slice(signature).map_err(|_| ErrorCode::Auth)?;
+        let message = pairing::host_proof_message(&self.session_key(), &device_nonce, &host_nonce);
+        host.verify_strict(&message, &signature)
+            .map_err(|_| ErrorCode::Auth)?;
+        self.authenticated = true;
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+//! Loosening policy changes held back by the current policy's `delay=`
+//! (`signer_core::policy`). The wait counts powered-on time, as there is no
+//! clock to trust: the remaining seconds are saved every few minutes, so a
+//! reset costs at most that much of the wait, never shortens it. A short
+//! BOOT press while idle cancels the change.
+
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::{error, info, warn};
+use signer_core::policy::{PendingChange, Policy};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "audit")]
+use crate::audit;
+use crate::config;
+
+/// How much counted time a reset may lose
+const SAVE_EVERY_SECS: u32 = 300;
+
+pub struct Pending {
+    change: Option<PendingChange>,
+    last_tick: Instant,
+    unsaved_secs: u32,
+}
+
+impl Pending {
+    /// The change saved before the last reset, if any. An unreadable record
+    /// is dropped: the policy in force stays.
+    pub fn load(nvs: &mut EspNvs<NvsDefault>) -> Self {
+        let change = config::load_pending(nvs)
+            .inspect_err(|e| error!("Pending policy change dropped: {:#}", e))
+            .ok()
+            .flatten();
+        if let Some(change) = &change {
+            info!(
+                "Policy change pending ({} s left): {}",
+                change.remaining_secs, change.policy
+            );
+        }
+        Pending {
+            change,
+            last_tick: Instant::now(),
+            unsaved_secs: 0,
+        }
+    }
+
+    pub fn get(&self) -> Option<&PendingChange> {
+        self.change.as_ref()
+    }
+
+    /// Holds `policy` back for `secs`, replacing any change already pending.
+    pub fn hold(
+        &mut self,
+        nvs: &mut EspNvs<NvsDefault>,
+        policy: Policy,
+        secs: u32,
+    ) -> anyhow::Result<()> {
+        let change = PendingChange {
+            policy,
+            remaining_secs: secs,
+        };
+        config::store_pending(nvs, &change)?;
+        info!("Policy change pending ({} s): {}", secs, change.policy);
+        self.change = Some(change);
+        self.last_tick = Instant::now();
+        self.unsaved_secs = 0;
+        Ok(())
+    }
+
+    /// Drops the pending change, if any; true if there was one.
+    pub fn clear(&mut self, nvs: &mut EspNvs<NvsDefault>) -> bool {
+        if self.change.take().is_none() {
+            return false;
+        }
+        if let Err(e) = config::clear_pending(nvs) {
+            error!("{:#}", e);
+        }
+        true
+    }
+
+    /// Counts the time since the last tick, and puts the change into effect
+    /// once it is due.
+    pub fn tick(&mut self, nvs: &mut EspNvs<NvsDefault>, policy: &mut Option<Policy>) {
+        let secs = self.last_tick.elapsed().as_secs() as u32;
+        // Whole seconds only, so frequent ticks don't round the wait away
+        self.last_tick += Duration::from_secs(secs.into());
+        let Some(change) = &mut self.change else {
+            return;
+        };
+        if !change.elapse(secs) {
+            self.unsaved_secs += secs;
+            if self.unsaved_secs >= SAVE_EVERY_SECS {
+                self.unsaved_secs = 0;
+                if let Err(e) = config::store_pending(nvs, change) {
+                    warn!("{:#}", e);
+                }
+            }
+            return;
+        }
+
+        // Stored before the pending record goes, so a reset in between
+        // applies it again rather than losing it
+        match config::store_policy(nvs, &change.policy) {
+            Ok(()) => {
+                info!("Signing policy set after its delay: {}", change.policy);
+                #[cfg(feature = "audit")]
+                audit::record(
+                    nvs,
+                    signer_core::audit::Event::PolicySet,
+                    Some(&change.policy.to_bytes()),
+                );
+                *policy = Some(change.policy.clone());
+                self.clear(nvs);
+            }
+            Err(e) => error!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! ESP-IDF implementations of the `signer_core::platform` traits.
+
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use esp_idf_sys::{self as sys, EspError};
+use signer_core::platform::{Clock, EntropySource, Storage};
+use std::convert::Infallible;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Borrowed view of the signer's NVS namespace.
+pub struct NvsStorage<'a>(pub &'a mut EspNvs<NvsDefault>);
+
+impl Storage for NvsStorage<'_> {
+    type Error = EspError;
+
+    fn get(&mut self, key: &str, buf: &mut [u8]) -> Result<Option<usize>, EspError> {
+        // get_raw fails outright on blobs bigger than buf; report the length instead
+        match self.0.blob_len(key)? {
+            Some(len) if len > buf.len() => Ok(Some(len)),
+            Some(_) => Ok(self.0.get_raw(key, buf)?.map(|s| s.len())),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<(), EspError> {
+        self.0.set_raw(key, value)?;
+        Ok(())
+    }
+}
+
+/// Raw output of the hardware RNG. Wrap it in
+/// `signer_core::entropy::HealthChecked` before using it for secrets.
+pub struct HwRng;
+
+impl HwRng {
+    /// Without Wi-Fi/BT running the RNG has no RF noise to sample, so turn on
+    /// the SAR ADC entropy source for as long as the signer runs. Nothing else
+    /// on this board uses the ADC.
+    pub fn enable() -> Self {
+        unsafe {
+            sys::bootloader_random_enable();
+        }
+        HwRng
+    }
+}
+
+impl EntropySource for HwRng {
+    type Error = Infallible;
+
+    fn fill(&mut self, buf: &mut [u8]) -> Result<(), Infallible> {
+        unsafe {
+            sys::esp_fill_random(buf.as_mut_ptr().cast(), buf.len());
+        }
+        Ok(())
+    }
+}
+
+/// Base MAC burned into eFuse at the factory.
+pub fn factory_mac() -> [u8; 6] {
+    let mut mac = [0u8; 6];
+    unsafe {
+        sys::esp_efuse_mac_get_default(mac.as_mut_ptr());
+    }
+    mac
+}
+
+/// RTC time if it has been set, otherwise `SystemTime`.
+pub struct DeviceClock;
+
+impl Clock for DeviceClock {
+    fn unix_time(&self) -> u64 {
+        unsafe {
+            let mut tv = sys::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            };
+            if sys::gettimeofday(&mut tv, core::ptr::null_mut()) == 0 && tv.tv_sec > 0 {
+                return tv.tv_sec as u64;
+            }
+        }
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
@@ -0,0 +1,76 @@
+#![cfg(feature = "light-sleep")]
+
+use anyhow::Result;
+use esp_idf_svc::hal::uart::UartDriver;
+use esp_idf_sys as sys;
+use std::time::{Duration, Instant};
+
+/// How long the link must be quiet before we drop into light sleep.
+pub const IDLE_BEFORE_SLEEP: Duration = Duration::from_secs(5);
+
+/// Rising edges on RX needed to wake the chip. A bare `\n` (0x0A) produces
+/// exactly three, so hosts send one newline as the wake preamble.
+pub const UART_WAKE_THRESHOLD: i32 = 3;
+
+/// The BOOT button, whose press wakes the chip for a presence token
+#[cfg(feature = "presence")]
+const BUTTON_GPIO: i32 = 9;
+
+/// Ticks to wait for pending TX bytes before gating the clocks.
+const TX_DRAIN_TICKS: u32 = 100;
+
+/// Tracks UART activity and puts the chip into light sleep when idle.
+///
+/// The bytes that trip the wake logic are not received intact, so the
+/// protocol requires a wake preamble: the host sends `\n`, waits a few
+/// milliseconds, then sends the real command. Empty lines are already
+/// ignored by the command parser, so awake devices are unaffected.
+pub struct IdleSleep {
+    last_activity: Instant,
+}
+
+impl IdleSleep {
+    pub fn new() -> Self {
+        Self {
+            last_activity: Instant::now(),
+        }
+    }
+
+    /// Record that a byte arrived (or a response went out).
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    pub fn should_sleep(&self) -> bool {
+        self.last_activity.elapsed() >= IDLE_BEFORE_SLEEP
+    }
+
+    /// Enter light sleep until the UART wakes us, then discard whatever
+    /// fragments of the wake preamble made it into the RX FIFO.
+    pub fn sleep(&mut self, uart: &UartDriver) -> Result<()> {
+        let port = uart.port();
+        unsafe {
+            sys::esp!(sys::uart_set_wakeup_threshold(port, UART_WAKE_THRESHOLD))?;
+            sys::esp!(sys::esp_sleep_enable_uart_wakeup(port as _))?;
+            #[cfg(feature = "presence")]
+            {
+                sys::esp!(sys::gpio_wakeup_enable(
+                    BUTTON_GPIO,
+                    sys::gpio_int_type_t_GPIO_INTR_LOW_LEVEL
+                ))?;
+                sys::esp!(sys::esp_sleep_enable_gpio_wakeup())?;
+            }
+        }
+
+        // Don't cut off the tail of the last response.
+        uart.wait_tx_done(TX_DRAIN_TICKS)?;
+
+        unsafe {
+            sys::esp!(sys::esp_light_sleep_start())?;
+        }
+
+        uart.clear_rx()?;
+        self.touch();
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+//! "Touch to prove presence": a short BOOT press while the dispatcher is idle
+//! (`button::Press`) sends a signed presence token (`signer_core::presence`)
+//! over the UART, unasked.
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use signer_core::identity::{self, DEVICE_ID_LEN};
+use signer_core::presence::{self, Token};
+use signer_protocol::response::Response;
+
+use crate::codec;
+use crate::crypto::Signer;
+use crate::platform::NvsStorage;
+
+/// Signs the next token; the whole `PRESENCE:` line
+pub fn token(
+    signer: &Signer,
+    nvs: &mut EspNvs<NvsDefault>,
+    device_id: &[u8; DEVICE_ID_LEN],
+) -> Result<String> {
+    let counter = presence::next_counter(&mut NvsStorage(nvs))
+        .map_err(|e| anyhow!("presence counter: {:?}", e))?;
+    let token = Token {
+        counter,
+        device_id: *device_id,
+        time: crate::device_unix_time(),
+    };
+    let signature = signer.sign(token.message().to_vec())?;
+    let fields = format!(
+        "counter={};device_id={};time={};sig={}",
+        token.counter,
+        identity::format_device_id(&token.device_id),
+        token.time,
+        codec::encode_binary(&signature)
+    );
+    Ok(Response::Presence(&fields).to_string())
+}
@@ -0,0 +1,327 @@
+//! UART I/O task. It owns the UART, assembles incoming bytes into lines for
+//! the dispatcher and writes the dispatcher's responses back out, so a slow
+//! command (button wait, signing, NVS write) never stops reception.
+//!
+//! With `ble`, the BLE server queues lines in the same inbox, and responses
+//! go back over the link their line came in on.
+
+#[cfg(feature = "ble")]
+use crate::ble;
+use crate::led::{self, Led};
+#[cfg(feature = "light-sleep")]
+use crate::power;
+use esp_idf_svc::hal::uart::UartDriver;
+use esp_idf_svc::sys::ESP_ERR_TIMEOUT;
+use log::warn;
+#[cfg(feature = "ble")]
+use signer_protocol::ble::Status;
+use signer_protocol::error::ErrorCode;
+use signer_protocol::response::Response;
+use std::cell::Cell;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+/// Complete lines waiting for the dispatcher, set at build time with
+/// `ESP32_SIGNER_QUEUE_DEPTH` (default 4). Lines arriving while it is full
+/// are dropped and answered with `ERROR:BUSY` straight away, which means that
+/// reply can overtake the replies to earlier lines.
+pub const INBOX_DEPTH: usize = parse_depth(env!("SIGNER_QUEUE_DEPTH"));
+
+/// Longest line we buffer: a SIGN of a maximum-size (1232 byte) transaction
+/// is about 1650 base64 characters, or 2464 in hex-only builds. Anything
+/// longer is discarded up to the next newline and answered with
+/// `ERROR:LINE_TOO_LONG`.
+pub const MAX_LINE_LEN: usize = 2560;
+
+/// Responses waiting to be written. `Port::send` blocks while it is full.
+const OUTBOX_DEPTH: usize = 8;
+
+/// Ticks to wait for RX bytes before checking the outbox again.
+const READ_TICKS: u32 = 10;
+
+/// Ticks to wait for the TX FIFO to drain on a flush.
+const FLUSH_TICKS: u32 = 100;
+
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum Outgoing {
+    Line(String),
+    /// Reply once everything queued before this is on the wire.
+    Flush(SyncSender<()>),
+    /// The dispatcher is done with one inbox line.
+    Done,
+}
+
+/// Where a line came from, and so where its responses go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Uart,
+    #[cfg(feature = "ble")]
+    Ble,
+}
+
+/// Dispatcher side of the I/O task.
+pub struct Port {
+    inbox: Receiver<(Link, String)>,
+    outbox: SyncSender<Outgoing>,
+    /// The link of the line being dispatched
+    link: Cell<Link>,
+    #[cfg(feature = "ble")]
+    lines: SyncSender<(Link, String)>,
+    #[cfg(feature = "ble")]
+    ble: Option<ble::Server>,
+}
+
+/// A line taken from the inbox. The I/O task counts a UART line as in
+/// progress (and won't light-sleep) until it is dropped and its responses
+/// have gone out.
+pub struct Request {
+    line: String,
+    link: Link,
+    outbox: SyncSender<Outgoing>,
+}
+
+impl Request {
+    pub fn as_str(&self) -> &str {
+        &self.line
+    }
+
+    pub fn link(&self) -> Link {
+        self.link
+    }
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        if self.link == Link::Uart {
+            let _ = self.outbox.send(Outgoing::Done);
+        }
+    }
+}
+
+impl Port {
+    pub fn spawn(uart: UartDriver<'static>, led: Led) -> anyhow::Result<Self> {
+        let (line_tx, inbox) = mpsc::sync_channel(INBOX_DEPTH);
+        let (outbox, out_rx) = mpsc::sync_channel(OUTBOX_DEPTH);
+        #[cfg(feature = "ble")]
+        let lines = line_tx.clone();
+        thread::Builder::new()
+            .name("uart".into())
+            .stack_size(4096)
+            .spawn(move || run(uart, led, line_tx, out_rx))?;
+        Ok(Port {
+            inbox,
+            outbox,
+            link: Cell::new(Link::Uart),
+            #[cfg(feature = "ble")]
+            lines,
+            #[cfg(feature = "ble")]
+            ble: None,
+        })
+    }
+
+    /// Where the BLE server queues the lines it receives.
+    #[cfg(feature = "ble")]
+    pub fn lines(&self) -> SyncSender<(Link, String)> {
+        self.lines.clone()
+    }
+
+    /// Answers BLE lines through `server` from now on.
+    #[cfg(feature = "ble")]
+    pub fn attach_ble(&mut self, server: ble::Server) {
+        self.ble = Some(server);
+    }
+
+    /// Next complete line, or `None` if the I/O task has stopped.
+    pub fn recv(&self) -> Option<Request> {
+        let (link, line) = self.inbox.recv().ok()?;
+        self.link.set(link);
+        Some(Request {
+            line,
+            link,
+            outbox: self.outbox.clone(),
+        })
+    }
+
+    /// As [`Port::recv`], calling `idle` every `poll` while no line is
+    /// waiting.
+    pub fn recv_idle(&self, poll: Duration, mut idle: impl FnMut()) -> Option<Request> {
+        loop {
+            match self.inbox.recv_timeout(poll) {
+                Ok((link, line)) => {
+                    self.link.set(link);
+                    return Some(Request {
+                        line,
+                        link,
+                        outbox: self.outbox.clone(),
+                    });
+                }
+                Err(RecvTimeoutError::Timeout) => idle(),
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    /// Writes a line nobody asked for to the UART, whichever link the last
+    /// line came in on.
+    #[cfg(feature = "presence")]
+    pub fn announce(&self, line: &str) -> anyhow::Result<()> {
+        self.outbox
+            .send(Outgoing::Line(line.to_string()))
+            .map_err(|_| anyhow::anyhow!("serial task stopped"))
+    }
+
+    /// Sends a response over the link the current line came in on.
+    pub fn send(&self, response: &str) -> anyhow::Result<()> {
+        #[cfg(feature = "ble")]
+        if self.link.get() == Link::Ble {
+            if let Some(server) = &self.ble {
+                server.send(response);
+            }
+            return Ok(());
+        }
+        self.outbox
+            .send(Outgoing::Line(response.to_string()))
+            .map_err(|_| anyhow::anyhow!("serial task stopped"))
+    }
+
+    /// Tells a phone what the current line is waiting for; nothing for UART
+    /// lines.
+    #[cfg(feature = "ble")]
+    pub fn status(&self, status: Status) {
+        if let (Link::Ble, Some(server)) = (self.link.get(), &self.ble) {
+            server.status(status);
+        }
+    }
+
+    /// Block until every response sent so far has left the UART. Call this
+    /// before restarting or deep-sleeping.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let (done_tx, done_rx) = mpsc::sync_channel(1);
+        self.outbox
+            .send(Outgoing::Flush(done_tx))
+            .map_err(|_| anyhow::anyhow!("serial task stopped"))?;
+        done_rx
+            .recv_timeout(FLUSH_TIMEOUT)
+            .map_err(|_| anyhow::anyhow!("serial flush timed out"))
+    }
+}
+
+/// Write one newline-terminated response directly. Only for use before the
+/// I/O task owns the UART.
+pub fn write_line(uart: &UartDriver, response: &str) -> anyhow::Result<()> {
+    let response_with_newline = response.to_string() + "\n";
+    let data = response_with_newline.as_bytes();
+    let mut written = 0;
+    while written < data.len() {
+        written += uart.write(&data[written..])?;
+    }
+    Ok(())
+}
+
+/// build.rs has already checked the value is a small decimal number.
+const fn parse_depth(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        depth = depth * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    depth
+}
+
+fn run(
+    uart: UartDriver<'static>,
+    led: Led,
+    lines: SyncSender<(Link, String)>,
+    outgoing: Receiver<Outgoing>,
+) {
+    #[cfg(feature = "light-sleep")]
+    let mut idle = power::IdleSleep::new();
+
+    let mut buffer = String::new();
+    // Set once the current line has outgrown MAX_LINE_LEN
+    let mut overlong = false;
+    let mut chunk = [0u8; 64];
+    // Lines handed to the dispatcher that it hasn't finished with
+    let mut in_progress = 0usize;
+
+    loop {
+        // Drain responses first so replies never wait behind input
+        loop {
+            match outgoing.try_recv() {
+                Ok(Outgoing::Line(response)) => {
+                    if let Err(e) = write_line(&uart, &response) {
+                        warn!("Dropped response: {}", e);
+                    }
+                    #[cfg(feature = "light-sleep")]
+                    idle.touch();
+                }
+                Ok(Outgoing::Flush(done)) => {
+                    let _ = uart.wait_tx_done(FLUSH_TICKS);
+                    let _ = done.send(());
+                }
+                Ok(Outgoing::Done) => in_progress = in_progress.saturating_sub(1),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        match uart.read(&mut chunk, READ_TICKS) {
+            Ok(n) => {
+                #[cfg(feature = "light-sleep")]
+                if n > 0 {
+                    idle.touch();
+                }
+
+                for &byte in &chunk[..n] {
+                    if byte != b'\n' {
+                        if buffer.len() < MAX_LINE_LEN {
+                            buffer.push(byte as char);
+                        } else {
+                            overlong = true;
+                        }
+                        continue;
+                    }
+                    if overlong {
+                        overlong = false;
+                        buffer.clear();
+                        let _ =
+                            write_line(&uart, &Response::Error(ErrorCode::LineTooLong).to_string());
+                        continue;
+                    }
+                    match lines.try_send((Link::Uart, std::mem::take(&mut buffer))) {
+                        Ok(()) => in_progress += 1,
+                        Err(TrySendError::Full((_, line))) => {
+                            warn!("Inbox full, dropping {}-byte line", line.len());
+                            let _ =
+                                write_line(&uart, &Response::Error(ErrorCode::Busy).to_string());
+                        }
+                        Err(TrySendError::Disconnected(_)) => return,
+                    }
+                }
+            }
+            Err(e) => {
+                if e.code() != ESP_ERR_TIMEOUT {
+                    // Simplified error state: Rapid blinking
+                    led.play(led::UART_FAULT);
+                }
+            }
+        }
+
+        // ======== Idle: light sleep until the next wake preamble ========
+        #[cfg(feature = "light-sleep")]
+        {
+            if in_progress == 0 && idle.should_sleep() {
+                // A half-received line is stale by now; don't replay it after wake
+                buffer.clear();
+                overlong = false;
+                if let Err(e) = idle.sleep(&uart) {
+                    warn!("Light sleep failed: {}", e);
+                }
+            }
+        }
+    }
+}
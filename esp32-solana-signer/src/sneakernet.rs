@@ -0,0 +1,136 @@
+//! Signing transactions carried on the SD card (`sneakernet` feature), for a
+//! signer that never touches an online machine: it only needs power.
+//!
+//! At boot the card is checked for requests without an answer (file names
+//! and layout in `signer_core::sneakernet`). Each one goes through the same
+//! introspection and policy as SIGN, then waits for the BOOT button: a short
+//! press signs, holding it for [`DECLINE_HOLD_MS`] declines. No card, or no
+//! pending requests, and boot carries on as usual.
+//!
+//! Nothing on a card can unlock 2FA, so while it is enrolled every request
+//! is refused with `LOCKED` and the key stays behind the OTP, as it does for
+//! SIGN_TX.
+
+use crate::backup::{self, SdCard, MOUNT_POINT};
+use crate::crypto;
+use crate::led::{self, Led};
+use anyhow::{Context, Result};
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio::{Gpio9, Input, PinDriver};
+use log::{debug, info, warn};
+use signer_core::policy::Policy;
+use signer_core::sneakernet::{self, Rejection};
+use signer_core::tx_introspection;
+use std::fs;
+use std::path::Path;
+
+/// Holding BOOT at least this long declines the request instead of signing.
+pub const DECLINE_HOLD_MS: u32 = 2000;
+
+const BUTTON_POLL_MS: u32 = 20;
+
+/// Answers every pending request on the card; returns how many were signed.
+/// `on_signed` sees each message once its signature is on the card. With
+/// `locked` (as `SIGN_TX` would be: 2FA built in and not unlocked) every
+/// request is refused.
+pub fn run(
+    card: &mut SdCard,
+    signer: &crypto::Signer,
+    policy: Option<&Policy>,
+    locked: bool,
+    button: &PinDriver<'_, Gpio9, Input>,
+    led: &Led,
+    on_signed: &mut dyn FnMut(&[u8]),
+) -> Result<usize> {
+    let _mounted = match card.mount() {
+        Ok(mounted) => mounted,
+        Err(e) => {
+            debug!("No SD card for sneakernet: {:#}", e);
+            return Ok(0);
+        }
+    };
+    let names = backup::list(MOUNT_POINT)?;
+    let pending = sneakernet::pending(names.iter().map(String::as_str));
+    if pending.is_empty() {
+        return Ok(0);
+    }
+    info!("{} transaction request(s) on the SD card", pending.len());
+
+    let pubkey = signer.pubkey();
+    let mut signed = 0;
+    for seq in pending {
+        let request = Path::new(MOUNT_POINT).join(sneakernet::request_name(seq));
+        let message = fs::read(&request).with_context(|| format!("read {}", request.display()))?;
+        let outcome = if locked {
+            Err(Rejection::Locked)
+        } else {
+            answer(&message, &pubkey, policy, button, led)
+        };
+        match outcome {
+            Ok(signing_input) => {
+                let signature = signer.sign(signing_input)?;
+                let path = Path::new(MOUNT_POINT).join(sneakernet::signature_name(seq));
+                backup::write_whole(&path, &signature)?;
+                on_signed(&message);
+                info!("Signed {}", request.display());
+                led.play(led::SIGNED);
+                signed += 1;
+            }
+            Err(rejection) => {
+                let text = rejection.to_text();
+                let path = Path::new(MOUNT_POINT).join(sneakernet::rejection_name(seq));
+                backup::write_whole(&path, text.as_bytes())?;
+                warn!("Rejected {}: {}", request.display(), text);
+                led.play(led::ERROR);
+            }
+        }
+        // Let the result pattern show before the next request starts blinking
+        FreeRtos::delay_ms(1000);
+    }
+    Ok(signed)
+}
+
+/// The checks SIGN makes, then the operator's decision; the bytes to sign.
+fn answer(
+    message: &[u8],
+    pubkey: &[u8; 32],
+    policy: Option<&Policy>,
+    button: &PinDriver<'_, Gpio9, Input>,
+    led: &Led,
+) -> Result<Vec<u8>, Rejection> {
+    if message.is_empty() || message.len() > sneakernet::MAX_MESSAGE_LEN {
+        return Err(Rejection::BadSize(message.len()));
+    }
+    let tx_info = tx_introspection::introspect_transaction(message, pubkey);
+    match &tx_info {
+        Ok(info) => debug!(
+            "Signing from SD:\n{}",
+            tx_introspection::format_transaction_info(info)
+        ),
+        Err(e) => debug!("Signing undecoded message from SD: {}", e),
+    }
+    let policy = policy.ok_or(Rejection::NoPolicy)?;
+    let signing_input = policy
+        .signing_input(message, tx_info.as_ref().ok())
+        .map_err(|violation| Rejection::Policy(violation.to_string()))?;
+
+    // Fast blink until pressed, then time the press
+    led.play(led::AWAIT_SIGN);
+    while !button.is_low() {
+        FreeRtos::delay_ms(BUTTON_POLL_MS);
+    }
+    led.off();
+    let mut held = 0;
+    while button.is_low() {
+        FreeRtos::delay_ms(BUTTON_POLL_MS);
+        held += BUTTON_POLL_MS;
+        if held == DECLINE_HOLD_MS {
+            // Tell the operator they can let go now
+            led.play(led::LOCKED);
+        }
+    }
+    if held >= DECLINE_HOLD_MS {
+        return Err(Rejection::Declined);
+    }
+    Ok(signing_input.into_owned())
+}
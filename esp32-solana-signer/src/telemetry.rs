@@ -0,0 +1,84 @@
+use esp_idf_svc::hal::temp_sensor::{TempSensor, TempSensorConfig, TempSensorDriver};
+use log::warn;
+use signer_core::telemetry::{self, Reading, Thresholds};
+
+#[cfg(feature = "vsense")]
+use esp_idf_svc::hal::adc::{
+    attenuation::DB_11,
+    oneshot::{config::AdcChannelConfig, config::Calibration, AdcChannelDriver, AdcDriver},
+    ADC1,
+};
+#[cfg(feature = "vsense")]
+use esp_idf_svc::hal::gpio::Gpio3;
+
+/// The supply is measured through a 1:1 resistor divider into GPIO3, since
+/// 3.3 V is above what the ADC reads accurately.
+#[cfg(feature = "vsense")]
+const VSENSE_DIVIDER: u32 = 2;
+
+/// Die temperature and (with the `vsense` feature) supply voltage. Either
+/// sensor failing to start just reports `na`; telemetry never stops boot.
+pub struct Sensors {
+    temp: Option<TempSensorDriver<'static>>,
+    #[cfg(feature = "vsense")]
+    vdd: Option<AdcChannelDriver<'static, Gpio3, AdcDriver<'static, ADC1>>>,
+    thresholds: Thresholds,
+}
+
+impl Sensors {
+    pub fn new(
+        temp_sensor: TempSensor,
+        #[cfg(feature = "vsense")] adc: ADC1,
+        #[cfg(feature = "vsense")] pin: Gpio3,
+    ) -> Self {
+        let temp = TempSensorDriver::new(&TempSensorConfig::default(), temp_sensor)
+            .and_then(|mut driver| driver.enable().map(|_| driver))
+            .inspect_err(|e| warn!("Temperature sensor unavailable: {}", e))
+            .ok();
+
+        #[cfg(feature = "vsense")]
+        let vdd = AdcDriver::new(adc)
+            .and_then(|adc| {
+                let config = AdcChannelConfig {
+                    attenuation: DB_11,
+                    calibration: Calibration::Curve,
+                    ..Default::default()
+                };
+                AdcChannelDriver::new(adc, pin, &config)
+            })
+            .inspect_err(|e| warn!("Supply voltage sense unavailable: {}", e))
+            .ok();
+
+        Sensors {
+            temp,
+            #[cfg(feature = "vsense")]
+            vdd,
+            thresholds: Thresholds::default(),
+        }
+    }
+
+    pub fn read(&mut self) -> Reading {
+        let temp_c = self.temp.as_ref().and_then(|t| t.get_celsius().ok());
+
+        #[cfg(feature = "vsense")]
+        let vdd_mv = self
+            .vdd
+            .as_mut()
+            .and_then(|ch| ch.read().ok())
+            .map(|mv| u32::from(mv) * VSENSE_DIVIDER);
+        #[cfg(not(feature = "vsense"))]
+        let vdd_mv = None;
+
+        Reading { temp_c, vdd_mv }
+    }
+
+    /// Build the TELEMETRY response line, logging any threshold warnings.
+    pub fn telemetry_response(&mut self) -> String {
+        let reading = self.read();
+        let warnings = self.thresholds.check(&reading);
+        for w in &warnings {
+            warn!("Telemetry out of range: {} ({:?})", w, reading);
+        }
+        telemetry::format_telemetry(&reading, &warnings)
+    }
+}
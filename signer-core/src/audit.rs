@@ -0,0 +1,536 @@
+//! Audit log: what the device did with its key and settings, kept on the
+//! device as a hash chain and exported sealed to an auditor.
+//!
+//! Every [`Entry`] is chained to the one before it (`hash = SHA-256(prev ||
+//! entry)`), and the newest [`CAPACITY`] entries are kept in storage, each
+//! with the hash it was chained to, so an export can start anywhere in the
+//! chain. An [`Export`] holds those entries and the device's signature over
+//! where the chain starts and ends ([`Export::signed_message`]), so a host
+//! can't drop, reorder or alter entries without the signature failing.
+//!
+//! With the `audit` feature, [`seal`] encrypts an export to the registered
+//! auditor's X25519 key (ephemeral ECDH, HKDF-SHA256, ChaCha20-Poly1305), so
+//! the host carrying it can't read it either. The signed bytes start with
+//! 0xff, like presence tokens, and the policy refuses to `SIGN` them.
+
+use alloc::format;
+use alloc::vec::Vec;
+use sha2::{Digest, Sha256};
+
+use crate::platform::Storage;
+
+/// Entries kept on the device; older ones are overwritten.
+pub const CAPACITY: u32 = 32;
+/// Storage key for the next sequence number (u32 LE) and the chain head.
+pub const HEAD_KEY_NAME: &str = "audit_head";
+/// Storage key for the registered auditor's X25519 public key.
+pub const AUDITOR_KEY_NAME: &str = "audit_key";
+
+pub const DOMAIN: &[u8] = b"\xffESP32_SIGNER_AUDIT_V1";
+/// Sequence number, time, event, digest
+pub const ENTRY_LEN: usize = 4 + 8 + 1 + 32;
+/// An entry as stored: the entry, then the hash it was chained to
+const SLOT_LEN: usize = ENTRY_LEN + 32;
+const HEAD_LEN: usize = 4 + 32;
+
+pub const EXPORT_MAGIC: [u8; 4] = *b"ESAL";
+pub const EXPORT_VERSION: u8 = 1;
+/// Magic, version, device key, start hash, entry count
+const EXPORT_HEADER_LEN: usize = 4 + 1 + 32 + 32 + 1;
+pub const SIGNATURE_LEN: usize = 64;
+/// The largest export: every entry kept.
+pub const MAX_EXPORT_LEN: usize = EXPORT_HEADER_LEN + CAPACITY as usize * ENTRY_LEN + SIGNATURE_LEN;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuditError<S> {
+    Storage(S),
+    /// A stored entry or the head doesn't read back.
+    Corrupt,
+    Truncated,
+    /// Not an export, or one this build doesn't know.
+    BadFormat,
+    /// The sealed export didn't open with this key, or was altered.
+    Unsealed,
+    /// The chain doesn't link up from one entry to the next.
+    Broken {
+        seq: u32,
+    },
+}
+
+/// What an entry records; its digest says which message, policy or key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The device started; no digest.
+    Boot,
+    /// A message was signed; digest of the message.
+    Signed,
+    /// A policy took effect; digest of its record.
+    PolicySet,
+    /// A host was paired; digest of its session key.
+    Paired,
+    Unpaired,
+    /// The seed and policy were restored from a backup; digest of the new
+    /// public key.
+    Restored,
+    /// A new auditor key was registered; digest of the key.
+    AuditorSet,
+}
+
+impl Event {
+    pub fn code(self) -> u8 {
+        match self {
+            Event::Boot => 0,
+            Event::Signed => 1,
+            Event::PolicySet => 2,
+            Event::Paired => 3,
+            Event::Unpaired => 4,
+            Event::Restored => 5,
+            Event::AuditorSet => 6,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            0 => Event::Boot,
+            1 => Event::Signed,
+            2 => Event::PolicySet,
+            3 => Event::Paired,
+            4 => Event::Unpaired,
+            5 => Event::Restored,
+            6 => Event::AuditorSet,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Boot => "boot",
+            Event::Signed => "signed",
+            Event::PolicySet => "policy_set",
+            Event::Paired => "paired",
+            Event::Unpaired => "unpaired",
+            Event::Restored => "restored",
+            Event::AuditorSet => "auditor_set",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub seq: u32,
+    /// Device clock in Unix seconds, 0 if it was never set
+    pub time: u64,
+    pub event: Event,
+    pub digest: [u8; 32],
+}
+
+impl Entry {
+    pub fn to_bytes(&self) -> [u8; ENTRY_LEN] {
+        let mut out = [0u8; ENTRY_LEN];
+        out[..4].copy_from_slice(&self.seq.to_le_bytes());
+        out[4..12].copy_from_slice(&self.time.to_le_bytes());
+        out[12] = self.event.code();
+        out[13..].copy_from_slice(&self.digest);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Entry> {
+        if bytes.len() != ENTRY_LEN {
+            return None;
+        }
+        Some(Entry {
+            seq: u32::from_le_bytes(bytes[..4].try_into().ok()?),
+            time: u64::from_le_bytes(bytes[4..12].try_into().ok()?),
+            event: Event::from_code(bytes[12])?,
+            digest: bytes[13..].try_into().ok()?,
+        })
+    }
+
+    /// The chain hash after this entry, given the one before it.
+    pub fn chain(&self, prev: &[u8; 32]) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(prev)
+            .chain_update(self.to_bytes())
+            .finalize()
+            .into()
+    }
+}
+
+/// Digest for an entry's subject, e.g. a signed message.
+pub fn digest(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+/// Whether `message` is in the audit export domain, which only an export may
+/// sign.
+pub fn is_reserved(message: &[u8]) -> bool {
+    message.starts_with(DOMAIN)
+}
+
+fn slot_key(seq: u32) -> alloc::string::String {
+    format!("audit_{:02}", seq % CAPACITY)
+}
+
+/// Next sequence number and chain head; zeros before the first entry.
+fn head<S: Storage>(storage: &mut S) -> Result<(u32, [u8; 32]), AuditError<S::Error>> {
+    let mut buf = [0u8; HEAD_LEN];
+    match storage
+        .get(HEAD_KEY_NAME, &mut buf)
+        .map_err(AuditError::Storage)?
+    {
+        None => Ok((0, [0; 32])),
+        Some(HEAD_LEN) => Ok((
+            u32::from_le_bytes(buf[..4].try_into().unwrap()),
+            buf[4..].try_into().unwrap(),
+        )),
+        Some(_) => Err(AuditError::Corrupt),
+    }
+}
+
+/// Adds an entry. The entry is stored before the head moves past it, so a
+/// reset in between loses the entry (and, once the log has wrapped, the
+/// oldest one it overwrote) but never breaks the chain.
+pub fn append<S: Storage>(
+    storage: &mut S,
+    time: u64,
+    event: Event,
+    digest: [u8; 32],
+) -> Result<Entry, AuditError<S::Error>> {
+    let (seq, prev) = head(storage)?;
+    let entry = Entry {
+        seq,
+        time,
+        event,
+        digest,
+    };
+    let mut slot = [0u8; SLOT_LEN];
+    slot[..ENTRY_LEN].copy_from_slice(&entry.to_bytes());
+    slot[ENTRY_LEN..].copy_from_slice(&prev);
+    storage
+        .set(&slot_key(seq), &slot)
+        .map_err(AuditError::Storage)?;
+
+    let mut next = [0u8; HEAD_LEN];
+    next[..4].copy_from_slice(&seq.wrapping_add(1).to_le_bytes());
+    next[4..].copy_from_slice(&entry.chain(&prev));
+    storage
+        .set(HEAD_KEY_NAME, &next)
+        .map_err(AuditError::Storage)?;
+    Ok(entry)
+}
+
+/// The entries still kept, oldest first, with the chain hash before the
+/// first of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Log {
+    pub start: [u8; 32],
+    pub entries: Vec<Entry>,
+}
+
+pub fn read<S: Storage>(storage: &mut S) -> Result<Log, AuditError<S::Error>> {
+    let (next, head) = head(storage)?;
+    let first = next.saturating_sub(CAPACITY);
+    let mut start = head;
+    let mut entries = Vec::new();
+    for seq in first..next {
+        let mut slot = [0u8; SLOT_LEN];
+        let stored = storage
+            .get(&slot_key(seq), &mut slot)
+            .map_err(AuditError::Storage)?;
+        let entry = match stored {
+            Some(SLOT_LEN) => Entry::from_bytes(&slot[..ENTRY_LEN]),
+            _ => None,
+        };
+        match entry {
+            Some(entry) if entry.seq == seq => {
+                if entries.is_empty() {
+                    start = slot[ENTRY_LEN..].try_into().unwrap();
+                }
+                entries.push(entry);
+            }
+            // The oldest slot, overwritten by an append cut short
+            _ if entries.is_empty() => continue,
+            _ => return Err(AuditError::Corrupt),
+        }
+    }
+    Ok(Log { start, entries })
+}
+
+/// What an export holds: the device's key, the kept entries and the chain
+/// hash before them, signed by the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Export {
+    pub device: [u8; 32],
+    pub start: [u8; 32],
+    pub entries: Vec<Entry>,
+    pub signature: [u8; SIGNATURE_LEN],
+}
+
+impl Export {
+    /// The chain hash after the last entry, or an error naming the first
+    /// entry out of sequence.
+    pub fn head<E>(&self) -> Result<[u8; 32], AuditError<E>> {
+        let mut hash = self.start;
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 && entry.seq != self.entries[i - 1].seq.wrapping_add(1) {
+                return Err(AuditError::Broken { seq: entry.seq });
+            }
+            hash = entry.chain(&hash);
+        }
+        Ok(hash)
+    }
+
+    /// What the device signs: the domain, the chain hash before the first
+    /// entry, the first sequence number and the head. The head covers every
+    /// entry in between.
+    pub fn signed_message<E>(&self) -> Result<Vec<u8>, AuditError<E>> {
+        let head = self.head()?;
+        let first = self.entries.first().map_or(0, |entry| entry.seq);
+        let mut out = Vec::with_capacity(DOMAIN.len() + 32 + 4 + 32);
+        out.extend_from_slice(DOMAIN);
+        out.extend_from_slice(&self.start);
+        out.extend_from_slice(&first.to_le_bytes());
+        out.extend_from_slice(&head);
+        Ok(out)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out =
+            Vec::with_capacity(EXPORT_HEADER_LEN + self.entries.len() * ENTRY_LEN + SIGNATURE_LEN);
+        out.extend_from_slice(&EXPORT_MAGIC);
+        out.push(EXPORT_VERSION);
+        out.extend_from_slice(&self.device);
+        out.extend_from_slice(&self.start);
+        out.push(self.entries.len() as u8);
+        for entry in &self.entries {
+            out.extend_from_slice(&entry.to_bytes());
+        }
+        out.extend_from_slice(&self.signature);
+        out
+    }
+
+    pub fn parse<E>(bytes: &[u8]) -> Result<Export, AuditError<E>> {
+        if bytes.len() < EXPORT_HEADER_LEN + SIGNATURE_LEN {
+            return Err(AuditError::Truncated);
+        }
+        if bytes[..4] != EXPORT_MAGIC || bytes[4] != EXPORT_VERSION {
+            return Err(AuditError::BadFormat);
+        }
+        let count = usize::from(bytes[EXPORT_HEADER_LEN - 1]);
+        if bytes.len() != EXPORT_HEADER_LEN + count * ENTRY_LEN + SIGNATURE_LEN {
+            return Err(AuditError::Truncated);
+        }
+        let (body, signature) = bytes.split_at(bytes.len() - SIGNATURE_LEN);
+        let entries = body[EXPORT_HEADER_LEN..]
+            .chunks_exact(ENTRY_LEN)
+            .map(Entry::from_bytes)
+            .collect::<Option<Vec<_>>>()
+            .ok_or(AuditError::BadFormat)?;
+        Ok(Export {
+            device: bytes[5..37].try_into().unwrap(),
+            start: bytes[37..69].try_into().unwrap(),
+            entries,
+            signature: signature.try_into().unwrap(),
+        })
+    }
+}
+
+#[cfg(feature = "audit")]
+mod seal {
+    use super::*;
+    use chacha20poly1305::aead::{Aead, NewAead, Payload};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+    use hkdf::Hkdf;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    pub const SEALED_MAGIC: [u8; 4] = *b"ESAX";
+    pub const SEALED_VERSION: u8 = 1;
+    /// Magic, version, ephemeral public key
+    pub const SEALED_HEADER_LEN: usize = 4 + 1 + 32;
+    pub const TAG_LEN: usize = 16;
+    const KDF_INFO: &[u8] = b"esp32-signer audit export v1";
+
+    /// Key and nonce for one ephemeral key. Each export uses a fresh one, so
+    /// the nonce never repeats under a key.
+    fn derive(shared: &[u8; 32], ephemeral: &[u8; 32], auditor: &[u8; 32]) -> ([u8; 32], [u8; 12]) {
+        let mut salt = [0u8; 64];
+        salt[..32].copy_from_slice(ephemeral);
+        salt[32..].copy_from_slice(auditor);
+        let mut okm = [0u8; 44];
+        Hkdf::<Sha256>::new(Some(&salt), shared)
+            .expand(KDF_INFO, &mut okm)
+            .expect("44 bytes is a valid HKDF-SHA256 length");
+        (okm[..32].try_into().unwrap(), okm[32..].try_into().unwrap())
+    }
+
+    /// The X25519 public key for an auditor's secret.
+    pub fn auditor_public(secret: &[u8; 32]) -> [u8; 32] {
+        PublicKey::from(&StaticSecret::from(*secret)).to_bytes()
+    }
+
+    /// Encrypts `plaintext` (an [`Export`]) to `auditor`, with
+    /// `ephemeral_secret` fresh from the RNG.
+    pub fn seal(plaintext: &[u8], auditor: &[u8; 32], ephemeral_secret: [u8; 32]) -> Vec<u8> {
+        let ephemeral = StaticSecret::from(ephemeral_secret);
+        let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+        let shared = ephemeral.diffie_hellman(&PublicKey::from(*auditor));
+        let (key, nonce) = derive(shared.as_bytes(), &ephemeral_public, auditor);
+
+        let mut out = Vec::with_capacity(SEALED_HEADER_LEN + plaintext.len() + TAG_LEN);
+        out.extend_from_slice(&SEALED_MAGIC);
+        out.push(SEALED_VERSION);
+        out.extend_from_slice(&ephemeral_public);
+        let sealed = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &out,
+                },
+            )
+            .expect("ChaCha20-Poly1305 takes any export length");
+        out.extend_from_slice(&sealed);
+        out
+    }
+
+    /// Decrypts what [`seal`] wrote, with the auditor's secret key.
+    pub fn open<E>(sealed: &[u8], auditor_secret: &[u8; 32]) -> Result<Vec<u8>, AuditError<E>> {
+        if sealed.len() < SEALED_HEADER_LEN + TAG_LEN {
+            return Err(AuditError::Truncated);
+        }
+        let (header, ciphertext) = sealed.split_at(SEALED_HEADER_LEN);
+        if header[..4] != SEALED_MAGIC || header[4] != SEALED_VERSION {
+            return Err(AuditError::BadFormat);
+        }
+        let ephemeral_public: [u8; 32] = header[5..].try_into().unwrap();
+        let secret = StaticSecret::from(*auditor_secret);
+        let auditor = PublicKey::from(&secret).to_bytes();
+        let shared = secret.diffie_hellman(&PublicKey::from(ephemeral_public));
+        let (key, nonce) = derive(shared.as_bytes(), &ephemeral_public, &auditor);
+        ChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| AuditError::Unsealed)
+    }
+}
+
+#[cfg(feature = "audit")]
+pub use seal::{auditor_public, open, seal, SEALED_HEADER_LEN, SEALED_MAGIC, TAG_LEN};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::MemoryStorage;
+    use core::convert::Infallible;
+
+    fn log(storage: &mut MemoryStorage, count: u32) {
+        for i in 0..count {
+            append(storage, u64::from(i), Event::Signed, [i as u8; 32]).unwrap();
+        }
+    }
+
+    fn export(storage: &mut MemoryStorage) -> Export {
+        let log = read(storage).unwrap();
+        Export {
+            device: [9; 32],
+            start: log.start,
+            entries: log.entries,
+            signature: [0; SIGNATURE_LEN],
+        }
+    }
+
+    #[test]
+    fn entries_chain_from_zero() {
+        let mut storage = MemoryStorage::default();
+        log(&mut storage, 3);
+        let export = export(&mut storage);
+        assert_eq!(export.start, [0; 32]);
+        assert_eq!(
+            export.entries.iter().map(|e| e.seq).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+
+        let mut head = [0u8; HEAD_LEN];
+        storage.get(HEAD_KEY_NAME, &mut head).unwrap();
+        assert_eq!(export.head::<Infallible>().unwrap(), head[4..]);
+        assert_eq!(Export::parse::<Infallible>(&export.to_bytes()), Ok(export));
+    }
+
+    #[test]
+    fn keeps_the_newest_entries() {
+        let mut storage = MemoryStorage::default();
+        log(&mut storage, CAPACITY + 5);
+        let export = export(&mut storage);
+        assert_eq!(export.entries.len(), CAPACITY as usize);
+        assert_eq!(export.entries[0].seq, 5);
+        assert_ne!(export.start, [0; 32]);
+        assert!(export.to_bytes().len() <= MAX_EXPORT_LEN);
+
+        // The chain carries on from the hash kept with the oldest entry
+        let mut full = MemoryStorage::default();
+        log(&mut full, 5);
+        let mut head = [0u8; HEAD_LEN];
+        full.get(HEAD_KEY_NAME, &mut head).unwrap();
+        assert_eq!(export.start, head[4..]);
+    }
+
+    #[test]
+    fn altered_logs_change_the_signed_message() {
+        let mut storage = MemoryStorage::default();
+        log(&mut storage, 4);
+        let export = export(&mut storage);
+        let signed = export.signed_message::<Infallible>().unwrap();
+        assert!(is_reserved(&signed));
+
+        let mut altered = export.clone();
+        altered.entries[1].digest[0] ^= 1;
+        assert_ne!(altered.signed_message::<Infallible>().unwrap(), signed);
+
+        let mut dropped = export.clone();
+        dropped.entries.remove(1);
+        assert_eq!(
+            dropped.head::<Infallible>(),
+            Err(AuditError::Broken { seq: 2 })
+        );
+
+        let mut truncated = export.clone();
+        truncated.entries.pop();
+        assert_ne!(truncated.signed_message::<Infallible>().unwrap(), signed);
+    }
+
+    #[cfg(feature = "audit")]
+    #[test]
+    fn sealed_exports_open_only_for_the_auditor() {
+        let auditor_secret = [7u8; 32];
+        let auditor = auditor_public(&auditor_secret);
+        let plaintext = b"export bytes";
+        let sealed = seal(plaintext, &auditor, [3; 32]);
+        assert!(!sealed.windows(plaintext.len()).any(|w| w == plaintext));
+        assert_eq!(
+            open::<Infallible>(&sealed, &auditor_secret).as_deref(),
+            Ok(&plaintext[..])
+        );
+        assert_eq!(
+            open::<Infallible>(&sealed, &[8; 32]),
+            Err(AuditError::Unsealed)
+        );
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            open::<Infallible>(&tampered, &auditor_secret),
+            Err(AuditError::Unsealed)
+        );
+        let mut swapped_key = sealed;
+        swapped_key[SEALED_HEADER_LEN - 1] ^= 1;
+        assert_eq!(
+            open::<Infallible>(&swapped_key, &auditor_secret),
+            Err(AuditError::Unsealed)
+        );
+    }
+}
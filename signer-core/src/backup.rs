@@ -0,0 +1,324 @@
+//! Encrypted backups of the signing seed and policy, as written to an SD card
+//! by BACKUP_TO_SD and read back by RESTORE_FROM_SD.
+//!
+//! A backup file is a [`Header`] followed by the AEAD-sealed [`Contents`];
+//! the header is the associated data, so its KDF parameters can't be swapped
+//! either. The key is PBKDF2-HMAC-SHA256 over the PIN and the passphrase,
+//! so both are needed to restore: the passphrase can be kept with the card,
+//! the PIN in someone's head. The firmware does the sealing; this module
+//! only fixes the layout, so it can be checked on the host.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const MAGIC: [u8; 4] = *b"ESSB";
+/// Bumped whenever the header, KDF or contents layout changes.
+pub const VERSION: u8 = 1;
+
+pub const SALT_LEN: usize = 16;
+pub const NONCE_LEN: usize = 12;
+pub const KEY_LEN: usize = 32;
+/// Magic, version, iterations, salt and nonce.
+pub const HEADER_LEN: usize = 4 + 1 + 4 + SALT_LEN + NONCE_LEN;
+
+/// A few seconds on the ESP32-C3's software HMAC.
+pub const DEFAULT_ITERATIONS: u32 = 20_000;
+/// Restores refuse more, so a doctored header can't stall the device.
+pub const MAX_ITERATIONS: u32 = 1_000_000;
+
+pub const PIN_DIGITS: core::ops::RangeInclusive<usize> = 4..=8;
+
+/// Backups are `BKP<seq>.ESB` in the card's root: 8.3 names, as FAT without
+/// long file names wants.
+const FILE_PREFIX: &str = "BKP";
+const FILE_SUFFIX: &str = ".ESB";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BackupError {
+    /// Not a backup file.
+    BadMagic,
+    /// A format this build doesn't know, e.g. from newer firmware.
+    UnsupportedVersion(u8),
+    Truncated,
+    /// KDF iterations of zero or above [`MAX_ITERATIONS`].
+    Iterations(u32),
+    /// The decrypted contents don't parse.
+    Corrupt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub iterations: u32,
+    pub salt: [u8; SALT_LEN],
+    pub nonce: [u8; NONCE_LEN],
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut out = [0u8; HEADER_LEN];
+        out[..4].copy_from_slice(&MAGIC);
+        out[4] = VERSION;
+        out[5..9].copy_from_slice(&self.iterations.to_le_bytes());
+        out[9..9 + SALT_LEN].copy_from_slice(&self.salt);
+        out[9 + SALT_LEN..].copy_from_slice(&self.nonce);
+        out
+    }
+
+    /// Splits a backup file into its header and the sealed contents after it.
+    pub fn parse(file: &[u8]) -> Result<(Header, &[u8]), BackupError> {
+        if file.len() < 5 {
+            return Err(BackupError::Truncated);
+        }
+        if file[..4] != MAGIC {
+            return Err(BackupError::BadMagic);
+        }
+        if file[4] != VERSION {
+            return Err(BackupError::UnsupportedVersion(file[4]));
+        }
+        if file.len() < HEADER_LEN {
+            return Err(BackupError::Truncated);
+        }
+        let iterations = u32::from_le_bytes([file[5], file[6], file[7], file[8]]);
+        if iterations == 0 || iterations > MAX_ITERATIONS {
+            return Err(BackupError::Iterations(iterations));
+        }
+        let mut header = Header {
+            iterations,
+            salt: [0; SALT_LEN],
+            nonce: [0; NONCE_LEN],
+        };
+        header.salt.copy_from_slice(&file[9..9 + SALT_LEN]);
+        header
+            .nonce
+            .copy_from_slice(&file[9 + SALT_LEN..HEADER_LEN]);
+        Ok((header, &file[HEADER_LEN..]))
+    }
+}
+
+/// What a backup holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contents {
+    pub seed: [u8; 32],
+    /// The policy record as `Policy::to_bytes` writes it.
+    pub policy: Vec<u8>,
+}
+
+impl Contents {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + 2 + self.policy.len());
+        out.extend_from_slice(&self.seed);
+        out.extend_from_slice(&(self.policy.len() as u16).to_le_bytes());
+        out.extend_from_slice(&self.policy);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BackupError> {
+        if bytes.len() < 34 {
+            return Err(BackupError::Corrupt);
+        }
+        let len = usize::from(u16::from_le_bytes([bytes[32], bytes[33]]));
+        if bytes.len() != 34 + len {
+            return Err(BackupError::Corrupt);
+        }
+        let mut seed = [0u8; 32];
+        seed.copy_from_slice(&bytes[..32]);
+        Ok(Contents {
+            seed,
+            policy: bytes[34..].to_vec(),
+        })
+    }
+}
+
+/// 4 to 8 digits.
+pub fn valid_pin(pin: &str) -> bool {
+    PIN_DIGITS.contains(&pin.len()) && pin.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The sealing key for `pin` and `passphrase`.
+pub fn derive_key(pin: &str, passphrase: &str, header: &Header) -> [u8; KEY_LEN] {
+    let mut password = Vec::with_capacity(pin.len() + 1 + passphrase.len());
+    password.extend_from_slice(pin.as_bytes());
+    // PINs are digits only, so the separator keeps ("12", "34x") and
+    // ("123", "4x") apart
+    password.push(0);
+    password.extend_from_slice(passphrase.as_bytes());
+    pbkdf2_sha256(&password, &header.salt, header.iterations)
+}
+
+/// PBKDF2 (RFC 8018) with HMAC-SHA256, one output block.
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; KEY_LEN] {
+    let prf = Hmac::<Sha256>::new_from_slice(password).expect("HMAC takes keys of any length");
+    let mut u: [u8; KEY_LEN] = prf
+        .clone()
+        .chain_update(salt)
+        .chain_update(1u32.to_be_bytes())
+        .finalize()
+        .into_bytes()
+        .into();
+    let mut out = u;
+    for _ in 1..iterations {
+        u = prf.clone().chain_update(u).finalize().into_bytes().into();
+        for (o, b) in out.iter_mut().zip(u) {
+            *o ^= b;
+        }
+    }
+    out
+}
+
+pub fn file_name(seq: u32) -> String {
+    format!("{}{:05}{}", FILE_PREFIX, seq, FILE_SUFFIX)
+}
+
+/// The sequence number in a backup's file name; case-insensitive, as FAT is.
+pub fn parse_file_name(name: &str) -> Option<u32> {
+    let upper = name.to_ascii_uppercase();
+    let digits = upper.strip_prefix(FILE_PREFIX)?.strip_suffix(FILE_SUFFIX)?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// The newest backup among `names` (a directory listing), by sequence number.
+pub fn latest<'a>(names: impl IntoIterator<Item = &'a str>) -> Option<(u32, &'a str)> {
+    names
+        .into_iter()
+        .filter_map(|name| parse_file_name(name).map(|seq| (seq, name)))
+        .max_by_key(|(seq, _)| *seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::hex_decode;
+
+    fn header() -> Header {
+        Header {
+            iterations: 2,
+            salt: [7; SALT_LEN],
+            nonce: [9; NONCE_LEN],
+        }
+    }
+
+    #[test]
+    fn pbkdf2_matches_published_vectors() {
+        // The RFC 6070 inputs, with SHA-256 in place of SHA-1
+        for (iterations, expected) in [
+            (
+                1,
+                "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b",
+            ),
+            (
+                2,
+                "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43",
+            ),
+            (
+                4096,
+                "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a",
+            ),
+        ] {
+            assert_eq!(
+                pbkdf2_sha256(b"password", b"salt", iterations).to_vec(),
+                hex_decode(expected).unwrap(),
+                "{} iterations",
+                iterations
+            );
+        }
+    }
+
+    #[test]
+    fn key_needs_pin_and_passphrase() {
+        let key = derive_key("1234", "correct horse", &header());
+        assert_eq!(key, derive_key("1234", "correct horse", &header()));
+        assert_ne!(key, derive_key("1235", "correct horse", &header()));
+        assert_ne!(key, derive_key("1234", "correct horsf", &header()));
+        assert_ne!(
+            derive_key("12", "34x", &header()),
+            derive_key("123", "4x", &header())
+        );
+        let other_salt = Header {
+            salt: [8; SALT_LEN],
+            ..header()
+        };
+        assert_ne!(key, derive_key("1234", "correct horse", &other_salt));
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let mut file = header().to_bytes().to_vec();
+        file.extend_from_slice(b"sealed");
+        assert_eq!(Header::parse(&file), Ok((header(), &b"sealed"[..])));
+    }
+
+    #[test]
+    fn rejects_foreign_and_doctored_headers() {
+        let good = header().to_bytes();
+        assert_eq!(Header::parse(&good[..3]), Err(BackupError::Truncated));
+        assert_eq!(
+            Header::parse(&good[..HEADER_LEN - 1]),
+            Err(BackupError::Truncated)
+        );
+
+        let mut foreign = good;
+        foreign[0] = b'X';
+        assert_eq!(Header::parse(&foreign), Err(BackupError::BadMagic));
+
+        let mut newer = good;
+        newer[4] = VERSION + 1;
+        assert_eq!(
+            Header::parse(&newer),
+            Err(BackupError::UnsupportedVersion(VERSION + 1))
+        );
+
+        for iterations in [0, MAX_ITERATIONS + 1] {
+            let doctored = Header {
+                iterations,
+                ..header()
+            };
+            assert_eq!(
+                Header::parse(&doctored.to_bytes()),
+                Err(BackupError::Iterations(iterations))
+            );
+        }
+    }
+
+    #[test]
+    fn contents_round_trip() {
+        let contents = Contents {
+            seed: [3; 32],
+            policy: b"policy record".to_vec(),
+        };
+        let bytes = contents.to_bytes();
+        assert_eq!(Contents::from_bytes(&bytes), Ok(contents));
+        assert_eq!(
+            Contents::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(BackupError::Corrupt)
+        );
+        assert_eq!(Contents::from_bytes(&[0; 33]), Err(BackupError::Corrupt));
+    }
+
+    #[test]
+    fn pins_are_four_to_eight_digits() {
+        assert!(valid_pin("1234"));
+        assert!(valid_pin("12345678"));
+        assert!(!valid_pin("123"));
+        assert!(!valid_pin("123456789"));
+        assert!(!valid_pin("12a4"));
+    }
+
+    #[test]
+    fn finds_the_latest_backup() {
+        assert_eq!(file_name(7), "BKP00007.ESB");
+        assert_eq!(parse_file_name("bkp00012.esb"), Some(12));
+        assert_eq!(parse_file_name("BKP.ESB"), None);
+        assert_eq!(parse_file_name("BKP0001A.ESB"), None);
+        assert_eq!(parse_file_name("BKP00001.TMP"), None);
+
+        let names = ["BKP00002.ESB", "NOTES.TXT", "BKP00010.ESB", "BKP00003.ESB"];
+        assert_eq!(latest(names), Some((10, "BKP00010.ESB")));
+        assert_eq!(latest(["NOTES.TXT"]), None);
+    }
+}
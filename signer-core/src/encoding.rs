@@ -0,0 +1,93 @@
+//! Text encodings for keys and binary payloads.
+//!
+//! Hex is always available. Base58 for keys is behind the `bs58` feature so
+//! minimal firmware builds can leave it out; without it keys print as hex.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Lowercase hex.
+pub fn hex_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for &b in bytes {
+        out.push(char::from(HEX_DIGITS[usize::from(b >> 4)]));
+        out.push(char::from(HEX_DIGITS[usize::from(b & 0x0f)]));
+    }
+    out
+}
+
+/// Hex in either case. `None` on odd length or a non-hex character.
+pub fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    fn nibble(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+    let s = s.as_bytes();
+    if s.len() & 1 != 0 {
+        return None;
+    }
+    s.chunks_exact(2)
+        .map(|pair| Some(nibble(pair[0])? << 4 | nibble(pair[1])?))
+        .collect()
+}
+
+/// Name of the encoding [`key_to_string`] uses.
+pub const KEY_ENCODING: &str = if cfg!(feature = "bs58") {
+    "base58"
+} else {
+    "hex"
+};
+
+/// A public key or hash as printed in logs and protocol replies.
+pub fn key_to_string(key: &[u8; 32]) -> String {
+    #[cfg(feature = "bs58")]
+    {
+        bs58::encode(key).into_string()
+    }
+    #[cfg(not(feature = "bs58"))]
+    {
+        hex_encode(key)
+    }
+}
+
+/// Inverse of [`key_to_string`]. `None` unless it decodes to exactly 32 bytes.
+pub fn key_from_str(s: &str) -> Option<[u8; 32]> {
+    #[cfg(feature = "bs58")]
+    let bytes = bs58::decode(s).into_vec().ok()?;
+    #[cfg(not(feature = "bs58"))]
+    let bytes = hex_decode(s)?;
+    bytes.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trips() {
+        let bytes = [0x00, 0x01, 0x7f, 0x80, 0xab, 0xff];
+        assert_eq!(hex_encode(&bytes), "00017f80abff");
+        assert_eq!(hex_decode("00017F80abFF").as_deref(), Some(&bytes[..]));
+        assert_eq!(hex_decode("").as_deref(), Some(&[][..]));
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        let key = [7u8; 32];
+        assert_eq!(key_from_str(&key_to_string(&key)), Some(key));
+        assert_eq!(key_from_str(&key_to_string(&[7u8; 32])[1..]), None);
+    }
+
+    #[test]
+    fn hex_rejects_bad_input() {
+        assert_eq!(hex_decode("abc"), None);
+        assert_eq!(hex_decode("zz"), None);
+        assert_eq!(hex_decode("0x00"), None);
+    }
+}
@@ -0,0 +1,276 @@
+//! Continuous health tests for the raw hardware RNG (NIST SP 800-90B §4.4).
+//!
+//! [`HealthChecked`] wraps any [`EntropySource`], runs a startup test before
+//! its first output, and checks every byte after that. Once a test fails the
+//! wrapper stays failed until reboot: a source that has produced a stuck or
+//! biased run once can't be trusted for keys again.
+
+use core::fmt;
+
+use crate::platform::EntropySource;
+
+/// Min-entropy per byte we assume the source delivers. The cutoffs below are
+/// derived from it with a false-positive rate of 2^-20.
+pub const ASSUMED_ENTROPY_BITS: u32 = 4;
+
+/// Repetition Count Test: 1 + ceil(20 / H) identical bytes in a row fails.
+pub const RCT_CUTOFF: u32 = 1 + 20_u32.div_ceil(ASSUMED_ENTROPY_BITS);
+
+/// Adaptive Proportion Test window (non-binary sources).
+pub const APT_WINDOW: u32 = 512;
+
+/// Occurrences of a window's first byte that fail the APT, from the binomial
+/// critical value for W = 512, H = 4.
+pub const APT_CUTOFF: u32 = 63;
+
+/// Bytes drawn and discarded by the startup test (SP 800-90B asks for at
+/// least 1024 samples).
+pub const STARTUP_SAMPLES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthFailure {
+    /// The source got stuck on one value.
+    RepetitionCount,
+    /// One value showed up far too often within a window.
+    AdaptiveProportion,
+}
+
+impl fmt::Display for HealthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthFailure::RepetitionCount => f.write_str("repetition count test failed"),
+            HealthFailure::AdaptiveProportion => f.write_str("adaptive proportion test failed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntropyError<E> {
+    /// The underlying source returned an error.
+    Source(E),
+    /// The source failed a health test, now or earlier.
+    Degraded(HealthFailure),
+}
+
+impl<E: fmt::Debug> fmt::Display for EntropyError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntropyError::Source(e) => write!(f, "entropy source error: {:?}", e),
+            EntropyError::Degraded(failure) => write!(f, "entropy degraded: {}", failure),
+        }
+    }
+}
+
+/// Running state of the RCT and APT.
+#[derive(Debug, Default)]
+pub struct HealthTests {
+    rct_value: Option<u8>,
+    rct_count: u32,
+    apt_value: u8,
+    apt_count: u32,
+    apt_seen: u32,
+}
+
+impl HealthTests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed one raw sample through both tests.
+    pub fn feed(&mut self, sample: u8) -> Result<(), HealthFailure> {
+        if self.rct_value == Some(sample) {
+            self.rct_count += 1;
+            if self.rct_count >= RCT_CUTOFF {
+                return Err(HealthFailure::RepetitionCount);
+            }
+        } else {
+            self.rct_value = Some(sample);
+            self.rct_count = 1;
+        }
+
+        if self.apt_seen == 0 {
+            self.apt_value = sample;
+            self.apt_count = 1;
+        } else if sample == self.apt_value {
+            self.apt_count += 1;
+            if self.apt_count >= APT_CUTOFF {
+                return Err(HealthFailure::AdaptiveProportion);
+            }
+        }
+        self.apt_seen += 1;
+        if self.apt_seen == APT_WINDOW {
+            self.apt_seen = 0;
+        }
+        Ok(())
+    }
+}
+
+/// An [`EntropySource`] that refuses to hand out bytes that failed a health
+/// test.
+pub struct HealthChecked<S> {
+    source: S,
+    tests: HealthTests,
+    started: bool,
+    failure: Option<HealthFailure>,
+}
+
+impl<S: EntropySource> HealthChecked<S> {
+    pub fn new(source: S) -> Self {
+        HealthChecked {
+            source,
+            tests: HealthTests::new(),
+            started: false,
+            failure: None,
+        }
+    }
+
+    /// Run the startup test now rather than on first use. Safe to call more
+    /// than once; later calls just report the latched state.
+    pub fn startup(&mut self) -> Result<(), EntropyError<S::Error>> {
+        if let Some(failure) = self.failure {
+            return Err(EntropyError::Degraded(failure));
+        }
+        if self.started {
+            return Ok(());
+        }
+        let mut block = [0u8; 64];
+        for _ in 0..STARTUP_SAMPLES / block.len() {
+            self.draw(&mut block)?;
+        }
+        block.fill(0);
+        self.started = true;
+        Ok(())
+    }
+
+    /// The test that failed, if any. Latched until reboot.
+    pub fn failure(&self) -> Option<HealthFailure> {
+        self.failure
+    }
+
+    fn draw(&mut self, buf: &mut [u8]) -> Result<(), EntropyError<S::Error>> {
+        self.source.fill(buf).map_err(EntropyError::Source)?;
+        for &b in buf.iter() {
+            if let Err(failure) = self.tests.feed(b) {
+                self.failure = Some(failure);
+                buf.fill(0);
+                return Err(EntropyError::Degraded(failure));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: EntropySource> EntropySource for HealthChecked<S> {
+    type Error = EntropyError<S::Error>;
+
+    fn fill(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.startup()?;
+        self.draw(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift32, good enough to look random to the health tests.
+    struct Xorshift(u32);
+
+    impl EntropySource for Xorshift {
+        type Error = ();
+
+        fn fill(&mut self, buf: &mut [u8]) -> Result<(), ()> {
+            for b in buf {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 17;
+                self.0 ^= self.0 << 5;
+                *b = self.0 as u8;
+            }
+            Ok(())
+        }
+    }
+
+    struct Stuck(u8);
+
+    impl EntropySource for Stuck {
+        type Error = ();
+
+        fn fill(&mut self, buf: &mut [u8]) -> Result<(), ()> {
+            buf.fill(self.0);
+            Ok(())
+        }
+    }
+
+    /// Every other byte is zero: never repeats, but heavily biased.
+    struct Biased(u8);
+
+    impl EntropySource for Biased {
+        type Error = ();
+
+        fn fill(&mut self, buf: &mut [u8]) -> Result<(), ()> {
+            for b in buf {
+                self.0 = self.0.wrapping_add(1);
+                *b = if self.0 % 2 == 1 { 0 } else { self.0 };
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cutoffs_match_assumed_entropy() {
+        assert_eq!(RCT_CUTOFF, 6);
+    }
+
+    #[test]
+    fn healthy_source_passes() {
+        let mut rng = HealthChecked::new(Xorshift(0x1234_5678));
+        let mut buf = [0u8; 4096];
+        rng.fill(&mut buf).unwrap();
+        assert!(buf.iter().any(|&b| b != 0));
+        assert_eq!(rng.failure(), None);
+    }
+
+    #[test]
+    fn stuck_source_fails_startup() {
+        let mut rng = HealthChecked::new(Stuck(0xaa));
+        assert_eq!(
+            rng.startup(),
+            Err(EntropyError::Degraded(HealthFailure::RepetitionCount))
+        );
+    }
+
+    #[test]
+    fn biased_source_fails_proportion_test() {
+        let mut rng = HealthChecked::new(Biased(0));
+        let mut buf = [0u8; 32];
+        assert_eq!(
+            rng.fill(&mut buf),
+            Err(EntropyError::Degraded(HealthFailure::AdaptiveProportion))
+        );
+        assert_eq!(buf, [0u8; 32]);
+    }
+
+    #[test]
+    fn failure_is_latched() {
+        let mut rng = HealthChecked::new(Xorshift(1));
+        rng.startup().unwrap();
+        // Swap in a stuck run mid-stream
+        rng.source = Xorshift(0);
+        let mut buf = [0u8; 8];
+        assert!(rng.fill(&mut buf).is_err());
+        rng.source = Xorshift(42);
+        assert_eq!(
+            rng.fill(&mut buf),
+            Err(EntropyError::Degraded(HealthFailure::RepetitionCount))
+        );
+    }
+
+    #[test]
+    fn short_runs_are_fine() {
+        let mut tests = HealthTests::new();
+        for _ in 0..RCT_CUTOFF - 1 {
+            tests.feed(7).unwrap();
+        }
+        tests.feed(8).unwrap();
+    }
+}
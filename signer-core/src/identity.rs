@@ -0,0 +1,226 @@
+//! Stable per-device identifier and factory serial number.
+//!
+//! The device ID hashes the factory MAC together with a random salt kept in
+//! the provisioning record, so it is stable for the life of the device's NVS
+//! (i.e. its key) without publishing the raw MAC. The serial number is an
+//! optional label written once during provisioning.
+
+use alloc::string::String;
+use core::fmt::Write;
+use sha2::{Digest, Sha256};
+
+use crate::platform::{EntropySource, Storage};
+
+pub const SALT_KEY_NAME: &str = "prov_salt";
+pub const SERIAL_KEY_NAME: &str = "prov_serial";
+
+pub const SALT_LEN: usize = 16;
+pub const DEVICE_ID_LEN: usize = 8;
+pub const SERIAL_MAX_LEN: usize = 32;
+
+const DEVICE_ID_DOMAIN: &[u8] = b"ESP32_SIGNER_DEVICE_ID_V1";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProvisionError<S, E> {
+    Storage(S),
+    Entropy(E),
+    /// The stored salt or serial isn't in the expected format.
+    Corrupt,
+    /// Serial numbers are 1-32 characters of `[A-Za-z0-9_-]`.
+    InvalidSerial,
+    /// The serial number is write-once.
+    AlreadyProvisioned,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProvisioningRecord {
+    pub salt: [u8; SALT_LEN],
+    pub serial: Option<String>,
+}
+
+impl ProvisioningRecord {
+    pub fn device_id(&self, mac: &[u8; 6]) -> [u8; DEVICE_ID_LEN] {
+        device_id(mac, &self.salt)
+    }
+}
+
+pub fn device_id(mac: &[u8; 6], salt: &[u8; SALT_LEN]) -> [u8; DEVICE_ID_LEN] {
+    let digest = Sha256::new()
+        .chain_update(DEVICE_ID_DOMAIN)
+        .chain_update(mac)
+        .chain_update(salt)
+        .finalize();
+    let mut id = [0u8; DEVICE_ID_LEN];
+    id.copy_from_slice(&digest[..DEVICE_ID_LEN]);
+    id
+}
+
+/// Lowercase hex, as reported over the protocol.
+pub fn format_device_id(id: &[u8; DEVICE_ID_LEN]) -> String {
+    let mut out = String::with_capacity(DEVICE_ID_LEN * 2);
+    for b in id {
+        // Writing to a String can't fail
+        let _ = write!(out, "{:02x}", b);
+    }
+    out
+}
+
+pub fn validate_serial(serial: &str) -> bool {
+    !serial.is_empty()
+        && serial.len() <= SERIAL_MAX_LEN
+        && serial
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Load the provisioning record, creating the salt on first boot.
+pub fn load_or_create_record<S: Storage, R: EntropySource>(
+    storage: &mut S,
+    rng: &mut R,
+) -> Result<ProvisioningRecord, ProvisionError<S::Error, R::Error>> {
+    let mut salt = [0u8; SALT_LEN];
+    match storage
+        .get(SALT_KEY_NAME, &mut salt)
+        .map_err(ProvisionError::Storage)?
+    {
+        Some(SALT_LEN) => {}
+        Some(_) => return Err(ProvisionError::Corrupt),
+        None => {
+            rng.fill(&mut salt).map_err(ProvisionError::Entropy)?;
+            storage
+                .set(SALT_KEY_NAME, &salt)
+                .map_err(ProvisionError::Storage)?;
+        }
+    }
+
+    let mut buf = [0u8; SERIAL_MAX_LEN];
+    let serial = match storage
+        .get(SERIAL_KEY_NAME, &mut buf)
+        .map_err(ProvisionError::Storage)?
+    {
+        Some(len) if len <= SERIAL_MAX_LEN => {
+            let s = core::str::from_utf8(&buf[..len]).map_err(|_| ProvisionError::Corrupt)?;
+            if !validate_serial(s) {
+                return Err(ProvisionError::Corrupt);
+            }
+            Some(String::from(s))
+        }
+        Some(_) => return Err(ProvisionError::Corrupt),
+        None => None,
+    };
+
+    Ok(ProvisioningRecord { salt, serial })
+}
+
+/// Record the serial number. Refuses to overwrite an existing one.
+pub fn set_serial<S: Storage, E>(
+    storage: &mut S,
+    record: &mut ProvisioningRecord,
+    serial: &str,
+) -> Result<(), ProvisionError<S::Error, E>> {
+    if record.serial.is_some() {
+        return Err(ProvisionError::AlreadyProvisioned);
+    }
+    if !validate_serial(serial) {
+        return Err(ProvisionError::InvalidSerial);
+    }
+    storage
+        .set(SERIAL_KEY_NAME, serial.as_bytes())
+        .map_err(ProvisionError::Storage)?;
+    record.serial = Some(String::from(serial));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[derive(Default)]
+    struct MapStorage(BTreeMap<String, Vec<u8>>);
+
+    impl Storage for MapStorage {
+        type Error = ();
+
+        fn get(&mut self, key: &str, buf: &mut [u8]) -> Result<Option<usize>, ()> {
+            Ok(self.0.get(key).map(|v| {
+                let n = v.len().min(buf.len());
+                buf[..n].copy_from_slice(&v[..n]);
+                v.len()
+            }))
+        }
+
+        fn set(&mut self, key: &str, value: &[u8]) -> Result<(), ()> {
+            self.0.insert(key.to_string(), value.to_vec());
+            Ok(())
+        }
+    }
+
+    struct FixedRng(u8);
+
+    impl EntropySource for FixedRng {
+        type Error = ();
+
+        fn fill(&mut self, buf: &mut [u8]) -> Result<(), ()> {
+            buf.fill(self.0);
+            Ok(())
+        }
+    }
+
+    const MAC: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x01];
+
+    #[test]
+    fn id_is_stable_across_reloads() {
+        let mut storage = MapStorage::default();
+        let first = load_or_create_record(&mut storage, &mut FixedRng(1)).unwrap();
+        let second = load_or_create_record(&mut storage, &mut FixedRng(2)).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.device_id(&MAC), second.device_id(&MAC));
+        assert_eq!(format_device_id(&first.device_id(&MAC)).len(), 16);
+    }
+
+    #[test]
+    fn id_depends_on_mac_and_salt() {
+        let salt = [1u8; SALT_LEN];
+        let mut other_mac = MAC;
+        other_mac[5] = 2;
+        assert_ne!(device_id(&MAC, &salt), device_id(&other_mac, &salt));
+        assert_ne!(device_id(&MAC, &salt), device_id(&MAC, &[2u8; SALT_LEN]));
+    }
+
+    #[test]
+    fn serial_is_write_once() {
+        let mut storage = MapStorage::default();
+        let mut record = load_or_create_record(&mut storage, &mut FixedRng(1)).unwrap();
+        assert_eq!(record.serial, None);
+
+        set_serial::<_, ()>(&mut storage, &mut record, "SN-0001").unwrap();
+        assert_eq!(
+            set_serial::<_, ()>(&mut storage, &mut record, "SN-0002"),
+            Err(ProvisionError::AlreadyProvisioned)
+        );
+
+        let reloaded = load_or_create_record(&mut storage, &mut FixedRng(9)).unwrap();
+        assert_eq!(reloaded.serial.as_deref(), Some("SN-0001"));
+    }
+
+    #[test]
+    fn rejects_bad_serials() {
+        assert!(validate_serial("ABC_123-x"));
+        assert!(!validate_serial(""));
+        assert!(!validate_serial("has space"));
+        assert!(!validate_serial("semi;colon"));
+        assert!(!validate_serial(&"x".repeat(SERIAL_MAX_LEN + 1)));
+    }
+
+    #[test]
+    fn corrupt_salt_is_not_replaced() {
+        let mut storage = MapStorage::default();
+        storage.set(SALT_KEY_NAME, &[0u8; 3]).unwrap();
+        assert_eq!(
+            load_or_create_record(&mut storage, &mut FixedRng(1)),
+            Err(ProvisionError::Corrupt)
+        );
+        assert_eq!(storage.0[SALT_KEY_NAME], vec![0u8; 3]);
+    }
+}
@@ -0,0 +1,353 @@
+//! Power-loss-safe records spread over two storage slots.
+//!
+//! Each write goes to the slot *not* holding the newest copy, tagged with a
+//! sequence number and a CRC. If power dips mid-write the torn slot fails its
+//! CRC and [`load`] falls back to the other slot, which still holds the last
+//! state that was fully written. Callers must persist before acting on new
+//! state (e.g. store the accepted OTP step before unlocking), so falling back
+//! can never roll back anything the host has already seen take effect.
+//!
+//! The older copy also makes a deliberate undo possible: [`rollback`] writes
+//! it back as the newest record.
+
+use crate::platform::Storage;
+
+/// Largest payload a journaled record can carry (its length is one byte).
+pub const MAX_PAYLOAD: usize = 255;
+
+const VERSION: u8 = 1;
+/// version, seq (u32 LE), payload length
+const HEADER_LEN: usize = 1 + 4 + 1;
+const CRC_LEN: usize = 4;
+const SLOT_MAX: usize = HEADER_LEN + MAX_PAYLOAD + CRC_LEN;
+
+/// The two storage keys backing one record.
+#[derive(Debug, Clone, Copy)]
+pub struct Slots(pub &'static str, pub &'static str);
+
+impl Slots {
+    fn key(&self, seq: u32) -> &'static str {
+        if seq & 1 == 0 {
+            self.0
+        } else {
+            self.1
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JournalError<S> {
+    Storage(S),
+    TooLarge,
+    /// Both slots exist and neither is intact. A single bad slot with the
+    /// other missing is a torn first write and reads as no record.
+    Corrupt,
+}
+
+/// CRC-32 (IEEE 802.3), bitwise; records are tiny.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+enum SlotState {
+    Missing,
+    Invalid,
+    Valid { seq: u32, len: usize },
+}
+
+fn read_slot<S: Storage>(
+    storage: &mut S,
+    key: &str,
+    buf: &mut [u8; SLOT_MAX],
+) -> Result<SlotState, S::Error> {
+    let len = match storage.get(key, buf)? {
+        None => return Ok(SlotState::Missing),
+        Some(len) if (HEADER_LEN + CRC_LEN..=SLOT_MAX).contains(&len) => len,
+        Some(_) => return Ok(SlotState::Invalid),
+    };
+    let payload_len = usize::from(buf[5]);
+    if buf[0] != VERSION || HEADER_LEN + payload_len + CRC_LEN != len {
+        return Ok(SlotState::Invalid);
+    }
+    let body = HEADER_LEN + payload_len;
+    let mut stored = [0u8; CRC_LEN];
+    stored.copy_from_slice(&buf[body..len]);
+    if crc32(&buf[..body]) != u32::from_le_bytes(stored) {
+        return Ok(SlotState::Invalid);
+    }
+    let mut seq = [0u8; 4];
+    seq.copy_from_slice(&buf[1..5]);
+    Ok(SlotState::Valid {
+        seq: u32::from_le_bytes(seq),
+        len: payload_len,
+    })
+}
+
+/// Newest intact copy: (sequence number, payload length), payload in `out`.
+fn latest<S: Storage>(
+    storage: &mut S,
+    slots: &Slots,
+    out: &mut [u8],
+) -> Result<Option<(u32, usize)>, JournalError<S::Error>> {
+    let mut a = [0u8; SLOT_MAX];
+    let mut b = [0u8; SLOT_MAX];
+    let (state_a, state_b) = read_both(storage, slots, &mut a, &mut b)?;
+
+    let (seq, len, buf) = match (state_a, state_b) {
+        (SlotState::Valid { seq: sa, len: la }, SlotState::Valid { seq: sb, len: lb }) => {
+            if sb > sa {
+                (sb, lb, &b)
+            } else {
+                (sa, la, &a)
+            }
+        }
+        (SlotState::Valid { seq, len }, _) => (seq, len, &a),
+        (_, SlotState::Valid { seq, len }) => (seq, len, &b),
+        (SlotState::Invalid, SlotState::Invalid) => return Err(JournalError::Corrupt),
+        _ => return Ok(None),
+    };
+    if out.len() < len {
+        return Err(JournalError::TooLarge);
+    }
+    out[..len].copy_from_slice(&buf[HEADER_LEN..HEADER_LEN + len]);
+    Ok(Some((seq, len)))
+}
+
+fn read_both<S: Storage>(
+    storage: &mut S,
+    slots: &Slots,
+    a: &mut [u8; SLOT_MAX],
+    b: &mut [u8; SLOT_MAX],
+) -> Result<(SlotState, SlotState), JournalError<S::Error>> {
+    let state_a = read_slot(storage, slots.0, a).map_err(JournalError::Storage)?;
+    let state_b = read_slot(storage, slots.1, b).map_err(JournalError::Storage)?;
+    Ok((state_a, state_b))
+}
+
+/// Read the newest intact copy of the record into `out` and return its
+/// length, or `None` if it has never been written.
+pub fn load<S: Storage>(
+    storage: &mut S,
+    slots: &Slots,
+    out: &mut [u8],
+) -> Result<Option<usize>, JournalError<S::Error>> {
+    Ok(latest(storage, slots, out)?.map(|(_, len)| len))
+}
+
+/// Write a new version of the record without disturbing the current one.
+pub fn store<S: Storage>(
+    storage: &mut S,
+    slots: &Slots,
+    payload: &[u8],
+) -> Result<(), JournalError<S::Error>> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(JournalError::TooLarge);
+    }
+    let mut scratch = [0u8; MAX_PAYLOAD];
+    let seq = match latest(storage, slots, &mut scratch)? {
+        Some((seq, _)) => seq.wrapping_add(1),
+        None => 0,
+    };
+
+    let mut buf = [0u8; SLOT_MAX];
+    buf[0] = VERSION;
+    buf[1..5].copy_from_slice(&seq.to_le_bytes());
+    buf[5] = payload.len() as u8;
+    let body = HEADER_LEN + payload.len();
+    buf[HEADER_LEN..body].copy_from_slice(payload);
+    let crc = crc32(&buf[..body]);
+    buf[body..body + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+
+    storage
+        .set(slots.key(seq), &buf[..body + CRC_LEN])
+        .map_err(JournalError::Storage)
+}
+
+/// Read the older intact copy into `out` without making it current, and
+/// return its length, or `None` if there is no intact older copy.
+pub fn previous<S: Storage>(
+    storage: &mut S,
+    slots: &Slots,
+    out: &mut [u8],
+) -> Result<Option<usize>, JournalError<S::Error>> {
+    let mut a = [0u8; SLOT_MAX];
+    let mut b = [0u8; SLOT_MAX];
+    let (len, buf) = match read_both(storage, slots, &mut a, &mut b)? {
+        (SlotState::Valid { seq: sa, len: la }, SlotState::Valid { seq: sb, len: lb }) => {
+            if sb > sa {
+                (la, &a)
+            } else {
+                (lb, &b)
+            }
+        }
+        _ => return Ok(None),
+    };
+    if out.len() < len {
+        return Err(JournalError::TooLarge);
+    }
+    out[..len].copy_from_slice(&buf[HEADER_LEN..HEADER_LEN + len]);
+    Ok(Some(len))
+}
+
+/// Make the older copy current again: it is rewritten with the next sequence
+/// number, over itself, so the copy it displaces becomes the older one and a
+/// second rollback undoes the first. Returns the restored payload's length
+/// (payload in `out`), or `None` if there is no intact older copy.
+pub fn rollback<S: Storage>(
+    storage: &mut S,
+    slots: &Slots,
+    out: &mut [u8],
+) -> Result<Option<usize>, JournalError<S::Error>> {
+    let Some(len) = previous(storage, slots, out)? else {
+        return Ok(None);
+    };
+    store(storage, slots, &out[..len])?;
+    Ok(Some(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[derive(Default)]
+    struct MapStorage(BTreeMap<String, Vec<u8>>);
+
+    impl Storage for MapStorage {
+        type Error = ();
+
+        fn get(&mut self, key: &str, buf: &mut [u8]) -> Result<Option<usize>, ()> {
+            Ok(self.0.get(key).map(|v| {
+                let n = v.len().min(buf.len());
+                buf[..n].copy_from_slice(&v[..n]);
+                v.len()
+            }))
+        }
+
+        fn set(&mut self, key: &str, value: &[u8]) -> Result<(), ()> {
+            self.0.insert(key.to_string(), value.to_vec());
+            Ok(())
+        }
+    }
+
+    const SLOTS: Slots = Slots("rec_a", "rec_b");
+
+    fn read(storage: &mut MapStorage) -> Option<Vec<u8>> {
+        let mut out = [0u8; MAX_PAYLOAD];
+        load(storage, &SLOTS, &mut out)
+            .unwrap()
+            .map(|len| out[..len].to_vec())
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn latest_write_wins_and_slots_alternate() {
+        let mut storage = MapStorage::default();
+        assert_eq!(read(&mut storage), None);
+        store(&mut storage, &SLOTS, b"one").unwrap();
+        store(&mut storage, &SLOTS, b"two").unwrap();
+        store(&mut storage, &SLOTS, b"three").unwrap();
+        assert_eq!(read(&mut storage).as_deref(), Some(&b"three"[..]));
+        assert_eq!(storage.0.len(), 2);
+    }
+
+    #[test]
+    fn torn_write_falls_back_to_previous_state() {
+        let mut storage = MapStorage::default();
+        store(&mut storage, &SLOTS, b"step=10").unwrap();
+        store(&mut storage, &SLOTS, b"step=11").unwrap();
+        // Power fails while writing step=12 into slot a
+        store(&mut storage, &SLOTS, b"step=12").unwrap();
+        let slot = storage.0.get_mut("rec_a").unwrap();
+        let last = slot.len() - 1;
+        slot[last] ^= 0xff;
+        assert_eq!(read(&mut storage).as_deref(), Some(&b"step=11"[..]));
+
+        // The next write replaces the torn slot, not the good one
+        store(&mut storage, &SLOTS, b"step=13").unwrap();
+        assert_eq!(read(&mut storage).as_deref(), Some(&b"step=13"[..]));
+    }
+
+    #[test]
+    fn torn_first_write_reads_as_empty() {
+        let mut storage = MapStorage::default();
+        storage.set("rec_a", &[VERSION, 0, 0]).unwrap();
+        assert_eq!(read(&mut storage), None);
+    }
+
+    #[test]
+    fn both_slots_bad_is_corrupt() {
+        let mut storage = MapStorage::default();
+        storage.set("rec_a", &[0xff; 12]).unwrap();
+        storage.set("rec_b", &[0xff; 12]).unwrap();
+        let mut out = [0u8; MAX_PAYLOAD];
+        assert_eq!(
+            load(&mut storage, &SLOTS, &mut out),
+            Err(JournalError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn rollback_restores_the_previous_record() {
+        let mut storage = MapStorage::default();
+        let mut out = [0u8; MAX_PAYLOAD];
+        store(&mut storage, &SLOTS, b"good").unwrap();
+        assert_eq!(rollback(&mut storage, &SLOTS, &mut out), Ok(None));
+
+        store(&mut storage, &SLOTS, b"bad").unwrap();
+        assert_eq!(rollback(&mut storage, &SLOTS, &mut out), Ok(Some(4)));
+        assert_eq!(&out[..4], b"good");
+        assert_eq!(read(&mut storage).as_deref(), Some(&b"good"[..]));
+
+        // Rolling back again swaps back to the newer record
+        rollback(&mut storage, &SLOTS, &mut out).unwrap();
+        assert_eq!(read(&mut storage).as_deref(), Some(&b"bad"[..]));
+    }
+
+    #[test]
+    fn rollback_needs_an_intact_older_copy() {
+        let mut storage = MapStorage::default();
+        store(&mut storage, &SLOTS, b"one").unwrap();
+        store(&mut storage, &SLOTS, b"two").unwrap();
+        storage.0.get_mut("rec_a").unwrap()[6] ^= 0xff;
+        let mut out = [0u8; MAX_PAYLOAD];
+        assert_eq!(rollback(&mut storage, &SLOTS, &mut out), Ok(None));
+        assert_eq!(read(&mut storage).as_deref(), Some(&b"two"[..]));
+    }
+
+    #[test]
+    fn previous_reads_the_older_copy_in_place() {
+        let mut storage = MapStorage::default();
+        let mut out = [0u8; MAX_PAYLOAD];
+        store(&mut storage, &SLOTS, b"one").unwrap();
+        assert_eq!(previous(&mut storage, &SLOTS, &mut out), Ok(None));
+        store(&mut storage, &SLOTS, b"two").unwrap();
+        assert_eq!(previous(&mut storage, &SLOTS, &mut out), Ok(Some(3)));
+        assert_eq!(&out[..3], b"one");
+        assert_eq!(read(&mut storage).as_deref(), Some(&b"two"[..]));
+    }
+
+    #[test]
+    fn oversized_payload_is_rejected() {
+        let mut storage = MapStorage::default();
+        assert_eq!(
+            store(&mut storage, &SLOTS, &[0u8; MAX_PAYLOAD + 1]),
+            Err(JournalError::TooLarge)
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Device signing key persistence.
+
+use crate::platform::{EntropySource, Storage};
+
+/// Storage key for the 32-byte Ed25519 seed.
+pub const SIGNING_KEY_NAME: &str = "solana_key";
+
+pub const SEED_LEN: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyError<S, E> {
+    Storage(S),
+    Entropy(E),
+    /// A value exists under the key name but isn't a 32-byte seed. We never
+    /// overwrite it: that could destroy the only copy of a funded key.
+    Corrupt {
+        len: usize,
+    },
+}
+
+/// Load the device seed, generating and persisting a fresh one on first boot.
+pub fn load_or_generate_seed<S: Storage, R: EntropySource>(
+    storage: &mut S,
+    rng: &mut R,
+) -> Result<[u8; SEED_LEN], KeyError<S::Error, R::Error>> {
+    load_or_generate_named(storage, rng, SIGNING_KEY_NAME)
+}
+
+/// [`load_or_generate_seed`] for a seed stored under `name`.
+pub fn load_or_generate_named<S: Storage, R: EntropySource>(
+    storage: &mut S,
+    rng: &mut R,
+    name: &str,
+) -> Result<[u8; SEED_LEN], KeyError<S::Error, R::Error>> {
+    let mut seed = [0u8; SEED_LEN];
+    match storage.get(name, &mut seed).map_err(KeyError::Storage)? {
+        Some(SEED_LEN) => Ok(seed),
+        Some(len) => Err(KeyError::Corrupt { len }),
+        None => {
+            rng.fill(&mut seed).map_err(KeyError::Entropy)?;
+            storage.set(name, &seed).map_err(KeyError::Storage)?;
+            Ok(seed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[derive(Default)]
+    struct MapStorage(BTreeMap<String, Vec<u8>>);
+
+    impl Storage for MapStorage {
+        type Error = ();
+
+        fn get(&mut self, key: &str, buf: &mut [u8]) -> Result<Option<usize>, ()> {
+            Ok(self.0.get(key).map(|v| {
+                let n = v.len().min(buf.len());
+                buf[..n].copy_from_slice(&v[..n]);
+                v.len()
+            }))
+        }
+
+        fn set(&mut self, key: &str, value: &[u8]) -> Result<(), ()> {
+            self.0.insert(key.to_string(), value.to_vec());
+            Ok(())
+        }
+    }
+
+    struct CountingRng(u8);
+
+    impl EntropySource for CountingRng {
+        type Error = ();
+
+        fn fill(&mut self, buf: &mut [u8]) -> Result<(), ()> {
+            for b in buf {
+                self.0 = self.0.wrapping_add(1);
+                *b = self.0;
+            }
+            Ok(())
+        }
+    }
+
+    struct FailingRng;
+
+    impl EntropySource for FailingRng {
+        type Error = &'static str;
+
+        fn fill(&mut self, _buf: &mut [u8]) -> Result<(), &'static str> {
+            Err("degraded")
+        }
+    }
+
+    #[test]
+    fn generates_once_then_reloads() {
+        let mut storage = MapStorage::default();
+        let first = load_or_generate_seed(&mut storage, &mut CountingRng(0)).unwrap();
+        let second = load_or_generate_seed(&mut storage, &mut CountingRng(100)).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first[0], 1);
+    }
+
+    #[test]
+    fn refuses_to_overwrite_corrupt_seed() {
+        let mut storage = MapStorage::default();
+        storage.set(SIGNING_KEY_NAME, &[7u8; 16]).unwrap();
+        let err = load_or_generate_seed(&mut storage, &mut CountingRng(0)).unwrap_err();
+        assert_eq!(err, KeyError::Corrupt { len: 16 });
+        assert_eq!(storage.0[SIGNING_KEY_NAME], vec![7u8; 16]);
+    }
+
+    #[test]
+    fn entropy_failure_persists_nothing() {
+        let mut storage = MapStorage::default();
+        let err = load_or_generate_seed(&mut storage, &mut FailingRng).unwrap_err();
+        assert_eq!(err, KeyError::Entropy("degraded"));
+        assert!(storage.0.is_empty());
+    }
+}
@@ -0,0 +1,34 @@
+//! Hardware-independent logic for the ESP32 Solana signer.
+//!
+//! Transaction introspection, policy evaluation, and TOTP verification live
+//! here so they can be unit tested on the host (the wire protocol itself is
+//! in `signer-protocol`). The firmware supplies NVS, the RTC, and the hardware RNG through
+//! the traits in [`platform`].
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod audit;
+#[cfg(feature = "backup")]
+pub mod backup;
+pub mod encoding;
+pub mod entropy;
+pub mod identity;
+pub mod journal;
+pub mod keys;
+pub mod pairing;
+pub mod platform;
+pub mod policy;
+pub mod presence;
+pub mod sneakernet;
+pub mod telemetry;
+pub mod token_labels;
+#[cfg(feature = "totp")]
+pub mod totp;
+#[cfg(feature = "totp")]
+pub mod totp_vectors;
+#[cfg(feature = "totp")]
+pub mod twofa;
+#[cfg(test)]
+mod tx_corpus;
+pub mod tx_introspection;
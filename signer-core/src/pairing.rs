@@ -0,0 +1,196 @@
+//! Host pairing: which host may use the device, and the messages both sides
+//! sign to prove who they are.
+//!
+//! Pairing exchanges Ed25519 session keys (not the Solana signing key). Both
+//! sides then derive a short code from the two keys: the device blinks it,
+//! the host prints it, and the operator presses BOOT only if they match, so a
+//! key swapped in transit is caught. After that, each connection proves
+//! possession of both session keys by signing the other side's nonce.
+
+use sha2::{Digest, Sha256};
+
+use crate::platform::Storage;
+
+/// Storage key for the device's 32-byte session seed.
+pub const SESSION_KEY_NAME: &str = "session_key";
+/// Storage key for the paired host's session public key. Empty when unpaired.
+pub const HOST_KEY_NAME: &str = "pair_host";
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 16;
+/// Digits in a pairing code, each 1-9 so it can be counted in blinks.
+pub const CODE_DIGITS: usize = 4;
+
+const CODE_DOMAIN: &[u8] = b"ESP32_SIGNER_PAIRING_CODE_V1";
+const DEVICE_PROOF_DOMAIN: &[u8] = b"ESP32_SIGNER_DEVICE_PROOF_V1";
+const HOST_PROOF_DOMAIN: &[u8] = b"ESP32_SIGNER_HOST_PROOF_V1";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PairingError<S> {
+    Storage(S),
+    /// The stored host key isn't 32 bytes.
+    Corrupt {
+        len: usize,
+    },
+}
+
+/// The code the operator compares, from the two session public keys.
+pub fn pairing_code(host_key: &[u8; KEY_LEN], device_key: &[u8; KEY_LEN]) -> [u8; CODE_DIGITS] {
+    let digest = Sha256::new()
+        .chain_update(CODE_DOMAIN)
+        .chain_update(host_key)
+        .chain_update(device_key)
+        .finalize();
+    let mut code = [0u8; CODE_DIGITS];
+    for (digit, byte) in code.iter_mut().zip(digest.iter()) {
+        *digit = 1 + byte % 9;
+    }
+    code
+}
+
+/// What the device signs to answer the host's nonce.
+pub fn device_proof_message(
+    host_key: &[u8; KEY_LEN],
+    host_nonce: &[u8; NONCE_LEN],
+    device_nonce: &[u8; NONCE_LEN],
+) -> [u8; DEVICE_PROOF_DOMAIN.len() + KEY_LEN + 2 * NONCE_LEN] {
+    let mut message = [0u8; DEVICE_PROOF_DOMAIN.len() + KEY_LEN + 2 * NONCE_LEN];
+    concat(
+        &mut message,
+        &[DEVICE_PROOF_DOMAIN, host_key, host_nonce, device_nonce],
+    );
+    message
+}
+
+/// What the host signs to answer the device's nonce.
+pub fn host_proof_message(
+    device_key: &[u8; KEY_LEN],
+    device_nonce: &[u8; NONCE_LEN],
+    host_nonce: &[u8; NONCE_LEN],
+) -> [u8; HOST_PROOF_DOMAIN.len() + KEY_LEN + 2 * NONCE_LEN] {
+    let mut message = [0u8; HOST_PROOF_DOMAIN.len() + KEY_LEN + 2 * NONCE_LEN];
+    concat(
+        &mut message,
+        &[HOST_PROOF_DOMAIN, device_key, device_nonce, host_nonce],
+    );
+    message
+}
+
+fn concat(out: &mut [u8], parts: &[&[u8]]) {
+    let mut at = 0;
+    for part in parts {
+        out[at..at + part.len()].copy_from_slice(part);
+        at += part.len();
+    }
+}
+
+/// The paired host's session key, `None` when no host is paired.
+pub fn load_host<S: Storage>(
+    storage: &mut S,
+) -> Result<Option<[u8; KEY_LEN]>, PairingError<S::Error>> {
+    let mut key = [0u8; KEY_LEN];
+    match storage
+        .get(HOST_KEY_NAME, &mut key)
+        .map_err(PairingError::Storage)?
+    {
+        None | Some(0) => Ok(None),
+        Some(KEY_LEN) => Ok(Some(key)),
+        Some(len) => Err(PairingError::Corrupt { len }),
+    }
+}
+
+/// Pairs `host_key`, replacing any earlier host.
+pub fn store_host<S: Storage>(
+    storage: &mut S,
+    host_key: &[u8; KEY_LEN],
+) -> Result<(), PairingError<S::Error>> {
+    storage
+        .set(HOST_KEY_NAME, host_key)
+        .map_err(PairingError::Storage)
+}
+
+/// Forgets the paired host.
+pub fn clear_host<S: Storage>(storage: &mut S) -> Result<(), PairingError<S::Error>> {
+    storage
+        .set(HOST_KEY_NAME, &[])
+        .map_err(PairingError::Storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[derive(Default)]
+    struct MapStorage(BTreeMap<String, Vec<u8>>);
+
+    impl Storage for MapStorage {
+        type Error = ();
+
+        fn get(&mut self, key: &str, buf: &mut [u8]) -> Result<Option<usize>, ()> {
+            Ok(self.0.get(key).map(|v| {
+                let n = v.len().min(buf.len());
+                buf[..n].copy_from_slice(&v[..n]);
+                v.len()
+            }))
+        }
+
+        fn set(&mut self, key: &str, value: &[u8]) -> Result<(), ()> {
+            self.0.insert(key.to_string(), value.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn code_digits_can_be_blinked() {
+        for seed in 0..=255u8 {
+            let code = pairing_code(&[seed; KEY_LEN], &[!seed; KEY_LEN]);
+            assert!(code.iter().all(|digit| (1..=9).contains(digit)));
+        }
+    }
+
+    #[test]
+    fn code_depends_on_both_keys_and_their_order() {
+        let (a, b) = ([1u8; KEY_LEN], [2u8; KEY_LEN]);
+        assert_eq!(pairing_code(&a, &b), pairing_code(&a, &b));
+        let mut codes = [
+            pairing_code(&a, &b),
+            pairing_code(&b, &a),
+            pairing_code(&a, &[3u8; KEY_LEN]),
+        ];
+        codes.sort();
+        assert!(codes[0] != codes[1] && codes[1] != codes[2]);
+    }
+
+    #[test]
+    fn proofs_are_domain_separated() {
+        let key = [7u8; KEY_LEN];
+        let (n1, n2) = ([1u8; NONCE_LEN], [2u8; NONCE_LEN]);
+        let device = device_proof_message(&key, &n1, &n2);
+        let host = host_proof_message(&key, &n1, &n2);
+        assert!(device.starts_with(DEVICE_PROOF_DOMAIN));
+        assert!(host.starts_with(HOST_PROOF_DOMAIN));
+        assert!(device.ends_with(&[[1u8; NONCE_LEN], [2u8; NONCE_LEN]].concat()));
+        assert_ne!(&device[..], &host[..]);
+    }
+
+    #[test]
+    fn host_key_store_and_clear() {
+        let mut storage = MapStorage::default();
+        assert_eq!(load_host(&mut storage), Ok(None));
+        store_host(&mut storage, &[9u8; KEY_LEN]).unwrap();
+        assert_eq!(load_host(&mut storage), Ok(Some([9u8; KEY_LEN])));
+        clear_host(&mut storage).unwrap();
+        assert_eq!(load_host(&mut storage), Ok(None));
+    }
+
+    #[test]
+    fn rejects_corrupt_host_key() {
+        let mut storage = MapStorage::default();
+        storage.set(HOST_KEY_NAME, &[1u8; 5]).unwrap();
+        assert_eq!(
+            load_host(&mut storage),
+            Err(PairingError::Corrupt { len: 5 })
+        );
+    }
+}
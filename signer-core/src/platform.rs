@@ -0,0 +1,52 @@
+//! Seams between the signer logic and the hardware it runs on.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::Infallible;
+use core::fmt::Debug;
+
+/// Persistent key/value storage (NVS on the device).
+pub trait Storage {
+    type Error: Debug;
+
+    /// Read `key` into `buf`. Returns the stored length, or `None` if the key
+    /// doesn't exist.
+    fn get(&mut self, key: &str, buf: &mut [u8]) -> Result<Option<usize>, Self::Error>;
+
+    /// Write `value` under `key`, replacing any previous value.
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Wall-clock time in seconds since the Unix epoch.
+pub trait Clock {
+    fn unix_time(&self) -> u64;
+}
+
+/// Source of cryptographic randomness for keys and OTP secrets.
+pub trait EntropySource {
+    type Error: Debug;
+
+    fn fill(&mut self, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// [`Storage`] held in RAM, for host-side tests and emulation. Never fails.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage(pub BTreeMap<String, Vec<u8>>);
+
+impl Storage for MemoryStorage {
+    type Error = Infallible;
+
+    fn get(&mut self, key: &str, buf: &mut [u8]) -> Result<Option<usize>, Infallible> {
+        Ok(self.0.get(key).map(|v| {
+            let n = v.len().min(buf.len());
+            buf[..n].copy_from_slice(&v[..n]);
+            v.len()
+        }))
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<(), Infallible> {
+        self.0.insert(key.into(), value.to_vec());
+        Ok(())
+    }
+}
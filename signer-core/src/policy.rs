@@ -0,0 +1,922 @@
+//! Signing policy: rules a transaction must pass before the device will even
+//! ask for a button press.
+//!
+//! Hosts push a policy as text (see [`Policy::parse`]); the device keeps it as
+//! a compact binary record (see [`Policy::to_bytes`]) in a [`crate::journal`].
+//!
+//! The policy also holds the key's [`ChainProfile`]: what the device's one
+//! key slot signs for. A key kept for another ed25519 protocol then can't be
+//! talked into signing a Solana transaction, nor a Solana key into signing
+//! for that protocol.
+//!
+//! A policy can hold back its own loosening: with `delay=<hours>`, a change
+//! that lets anything through that the policy in force doesn't (see
+//! [`Policy::loosens`]) waits that long as a [`PendingChange`], and a BOOT
+//! press cancels it meanwhile. The device has no clock it can trust, so the
+//! delay counts time it is powered.
+//!
+//! `production=on` marks a key that holds real funds: the device then refuses
+//! to use it unless flash encryption and secure boot are enabled, so a unit
+//! left in its development configuration can't be deployed by mistake.
+
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::audit;
+use crate::encoding::{hex_decode, hex_encode, key_from_str, key_to_string};
+use crate::journal;
+use crate::presence;
+use crate::tx_introspection::{parse_message, TransactionInfo, TransactionType};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    /// Cap on the total SOL moved by System transfers in one transaction.
+    pub max_lamports_per_tx: Option<u64>,
+    /// Transfer destinations allowed. Empty means any. For token transfers
+    /// this is the destination token account, not the owner wallet.
+    pub allowed_recipients: Vec<[u8; 32]>,
+    /// Whether messages we can't fully decode may be signed.
+    pub allow_blind_signing: bool,
+    /// What the key signs for; the rules above only apply to Solana.
+    pub chain: ChainProfile,
+    /// Hours a loosening change waits before it takes effect; 0 applies
+    /// every change at once.
+    pub change_delay_hours: u8,
+    /// Only use the key on hardware with flash encryption and secure boot.
+    pub production: bool,
+}
+
+/// The protocol a key signs for, and how its messages are kept apart from
+/// every other protocol's.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ChainProfile {
+    /// Solana transactions and messages, signed as they are.
+    #[default]
+    Solana,
+    /// Another protocol's messages, signed as they are, as long as they
+    /// aren't a Solana transaction or off-chain message.
+    Raw,
+    /// Another protocol's messages, signed with this prefix in front, so no
+    /// signature is ever valid for a message without it. The prefix can't
+    /// start with a byte of 0x80 or more: that would begin a versioned
+    /// Solana message, or (0xff) an off-chain message or one of the device's
+    /// own signing domains.
+    Prefixed(Vec<u8>),
+}
+
+/// Start of a Solana off-chain message, which a [`ChainProfile::Raw`] key
+/// doesn't sign.
+pub const OFFCHAIN_DOMAIN: &[u8] = b"\xffsolana offchain";
+/// Longest prefix of a [`ChainProfile::Prefixed`] key.
+pub const MAX_PREFIX_LEN: usize = 16;
+
+/// Whether `prefix` may front a [`ChainProfile::Prefixed`] key's messages.
+fn valid_prefix(prefix: &[u8]) -> bool {
+    (1..=MAX_PREFIX_LEN).contains(&prefix.len()) && prefix[0] < 0x80
+}
+
+impl Default for Policy {
+    /// Permissive: matches the behaviour before policies existed.
+    fn default() -> Self {
+        Policy {
+            max_lamports_per_tx: None,
+            allowed_recipients: Vec::new(),
+            allow_blind_signing: true,
+            chain: ChainProfile::Solana,
+            change_delay_hours: 0,
+            production: false,
+        }
+    }
+}
+
+/// Solana keys are still written as version 1, which older firmware reads.
+const RECORD_VERSION: u8 = 1;
+/// Version 1 followed by the chain profile: tag, then for a prefix its
+/// length and bytes.
+const RECORD_VERSION_CHAIN: u8 = 2;
+/// Version 2 (Solana included, as its own tag) followed by the change delay.
+const RECORD_VERSION_DELAY: u8 = 3;
+/// version, flags, lamport limit (u64 LE), recipient count
+const RECORD_HEADER_LEN: usize = 1 + 1 + 8 + 1;
+const FLAG_BLIND_SIGNING: u8 = 1 << 0;
+const FLAG_LAMPORT_LIMIT: u8 = 1 << 1;
+/// Firmware from before production mode ignores it.
+const FLAG_PRODUCTION: u8 = 1 << 2;
+const CHAIN_TAG_SOLANA: u8 = 0;
+const CHAIN_TAG_RAW: u8 = 1;
+const CHAIN_TAG_PREFIXED: u8 = 2;
+const MAX_CHAIN_RECORD_LEN: usize = 1 + 1 + MAX_PREFIX_LEN;
+/// Chain profile and change delay
+const MAX_TRAILER_LEN: usize = MAX_CHAIN_RECORD_LEN + 1;
+
+/// Most allowlist entries that fit in one journaled record.
+pub const MAX_RECIPIENTS: usize = (journal::MAX_PAYLOAD - RECORD_HEADER_LEN - MAX_TRAILER_LEN) / 32;
+
+/// Why a pushed policy string was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyParseError {
+    UnknownField,
+    BadLimit,
+    BadFlag,
+    BadRecipient,
+    TooManyRecipients,
+    BadChain,
+    BadDelay,
+}
+
+impl fmt::Display for PolicyParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PolicyParseError::UnknownField => "UNKNOWN_FIELD",
+            PolicyParseError::BadLimit => "BAD_LIMIT",
+            PolicyParseError::BadFlag => "BAD_FLAG",
+            PolicyParseError::BadRecipient => "BAD_RECIPIENT",
+            PolicyParseError::TooManyRecipients => "TOO_MANY_RECIPIENTS",
+            PolicyParseError::BadChain => "BAD_CHAIN",
+            PolicyParseError::BadDelay => "BAD_DELAY",
+        })
+    }
+}
+
+impl fmt::Display for ChainProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainProfile::Solana => f.write_str("solana"),
+            ChainProfile::Raw => f.write_str("raw"),
+            ChainProfile::Prefixed(prefix) => write!(f, "prefix:{}", hex_encode(prefix)),
+        }
+    }
+}
+
+impl ChainProfile {
+    /// Parse `solana`, `raw` or `prefix:<hex>`.
+    pub fn parse(text: &str) -> Result<ChainProfile, PolicyParseError> {
+        match text {
+            "solana" => Ok(ChainProfile::Solana),
+            "raw" => Ok(ChainProfile::Raw),
+            _ => {
+                let hex = text
+                    .strip_prefix("prefix:")
+                    .ok_or(PolicyParseError::BadChain)?;
+                match hex_decode(hex) {
+                    Some(prefix) if valid_prefix(&prefix) => Ok(ChainProfile::Prefixed(prefix)),
+                    _ => Err(PolicyParseError::BadChain),
+                }
+            }
+        }
+    }
+}
+
+/// Same text as [`Policy::parse`] accepts, with every field present but the
+/// change delay, production mode and chain profile, which are left out when
+/// they are 0, off and Solana so older hosts can read it.
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max_lamports_per_tx {
+            Some(limit) => write!(f, "max={}", limit)?,
+            None => f.write_str("max=none")?,
+        }
+        let blind = if self.allow_blind_signing {
+            "on"
+        } else {
+            "off"
+        };
+        write!(f, ";blind={};allow=", blind)?;
+        if self.allowed_recipients.is_empty() {
+            f.write_str("any")?;
+        }
+        for (i, to) in self.allowed_recipients.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(&key_to_string(to))?;
+        }
+        if self.change_delay_hours != 0 {
+            write!(f, ";delay={}", self.change_delay_hours)?;
+        }
+        if self.production {
+            f.write_str(";production=on")?;
+        }
+        match self.chain {
+            ChainProfile::Solana => Ok(()),
+            _ => write!(f, ";chain={}", self.chain),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyViolation {
+    BlindSigningDisabled,
+    AmountExceedsLimit {
+        amount: u64,
+        limit: u64,
+    },
+    RecipientNotAllowed([u8; 32]),
+    /// A Solana message for a key kept for another protocol.
+    ChainMismatch,
+    /// A presence token or audit export, which only the device itself may
+    /// sign.
+    ReservedDomain,
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::BlindSigningDisabled => f.write_str("BLIND_SIGNING_DISABLED"),
+            PolicyViolation::AmountExceedsLimit { amount, limit } => {
+                write!(f, "AMOUNT_EXCEEDS_LIMIT:{}>{}", amount, limit)
+            }
+            PolicyViolation::RecipientNotAllowed(to) => {
+                write!(f, "RECIPIENT_NOT_ALLOWED:{}", key_to_string(to))
+            }
+            PolicyViolation::ChainMismatch => f.write_str("CHAIN_MISMATCH"),
+            PolicyViolation::ReservedDomain => f.write_str("RESERVED_DOMAIN"),
+        }
+    }
+}
+
+impl Policy {
+    /// Parse `max=<lamports|none>;blind=<on|off>;allow=<key>[,<key>...]|any`
+    /// with an optional `;delay=<hours>` (0-255), `;production=<on|off>` and
+    /// `;chain=<profile>` (see [`ChainProfile::parse`]).
+    /// Fields may come in any order; omitted ones keep their default.
+    pub fn parse(spec: &str) -> Result<Policy, PolicyParseError> {
+        let mut policy = Policy::default();
+        for field in spec.split(';').map(str::trim).filter(|f| !f.is_empty()) {
+            let (name, value) = field
+                .split_once('=')
+                .ok_or(PolicyParseError::UnknownField)?;
+            match name {
+                "max" => {
+                    policy.max_lamports_per_tx = match value {
+                        "none" => None,
+                        n => Some(n.parse().map_err(|_| PolicyParseError::BadLimit)?),
+                    }
+                }
+                "blind" => {
+                    policy.allow_blind_signing = match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(PolicyParseError::BadFlag),
+                    }
+                }
+                "allow" => {
+                    policy.allowed_recipients.clear();
+                    if value != "any" {
+                        for key in value.split(',') {
+                            let key = key_from_str(key).ok_or(PolicyParseError::BadRecipient)?;
+                            policy.allowed_recipients.push(key);
+                        }
+                    }
+                    if policy.allowed_recipients.len() > MAX_RECIPIENTS {
+                        return Err(PolicyParseError::TooManyRecipients);
+                    }
+                }
+                "delay" => {
+                    policy.change_delay_hours =
+                        value.parse().map_err(|_| PolicyParseError::BadDelay)?
+                }
+                "production" => {
+                    policy.production = match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(PolicyParseError::BadFlag),
+                    }
+                }
+                "chain" => policy.chain = ChainProfile::parse(value)?,
+                _ => return Err(PolicyParseError::UnknownField),
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Storage record. Fits in a journal slot as long as the allowlist is
+    /// within [`MAX_RECIPIENTS`], which [`Policy::parse`] enforces.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.allow_blind_signing {
+            flags |= FLAG_BLIND_SIGNING;
+        }
+        if self.max_lamports_per_tx.is_some() {
+            flags |= FLAG_LAMPORT_LIMIT;
+        }
+        if self.production {
+            flags |= FLAG_PRODUCTION;
+        }
+        let version = match (self.change_delay_hours, &self.chain) {
+            (0, ChainProfile::Solana) => RECORD_VERSION,
+            (0, _) => RECORD_VERSION_CHAIN,
+            _ => RECORD_VERSION_DELAY,
+        };
+        let mut out = Vec::with_capacity(
+            RECORD_HEADER_LEN + 32 * self.allowed_recipients.len() + MAX_TRAILER_LEN,
+        );
+        out.push(version);
+        out.push(flags);
+        out.extend_from_slice(&self.max_lamports_per_tx.unwrap_or(0).to_le_bytes());
+        out.push(self.allowed_recipients.len() as u8);
+        for to in &self.allowed_recipients {
+            out.extend_from_slice(to);
+        }
+        match &self.chain {
+            ChainProfile::Solana if version == RECORD_VERSION_DELAY => out.push(CHAIN_TAG_SOLANA),
+            ChainProfile::Solana => {}
+            ChainProfile::Raw => out.push(CHAIN_TAG_RAW),
+            ChainProfile::Prefixed(prefix) => {
+                out.push(CHAIN_TAG_PREFIXED);
+                out.push(prefix.len() as u8);
+                out.extend_from_slice(prefix);
+            }
+        }
+        if version == RECORD_VERSION_DELAY {
+            out.push(self.change_delay_hours);
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Policy> {
+        if bytes.len() < RECORD_HEADER_LEN {
+            return None;
+        }
+        let flags = bytes[1];
+        let mut limit = [0u8; 8];
+        limit.copy_from_slice(&bytes[2..10]);
+        let keys_len = 32 * usize::from(bytes[10]);
+        let rest = &bytes[RECORD_HEADER_LEN..];
+        let (keys, chain, delay) = match bytes[0] {
+            RECORD_VERSION if rest.len() == keys_len => (rest, ChainProfile::Solana, 0),
+            version @ (RECORD_VERSION_CHAIN | RECORD_VERSION_DELAY) if rest.len() > keys_len => {
+                let (keys, mut chain) = rest.split_at(keys_len);
+                let mut delay = 0;
+                if version == RECORD_VERSION_DELAY {
+                    let (&hours, rest) = chain.split_last()?;
+                    (chain, delay) = (rest, hours);
+                }
+                let chain = match chain {
+                    [CHAIN_TAG_SOLANA] if version == RECORD_VERSION_DELAY => ChainProfile::Solana,
+                    [CHAIN_TAG_RAW] => ChainProfile::Raw,
+                    [CHAIN_TAG_PREFIXED, len, prefix @ ..]
+                        if usize::from(*len) == prefix.len() && valid_prefix(prefix) =>
+                    {
+                        ChainProfile::Prefixed(prefix.to_vec())
+                    }
+                    _ => return None,
+                };
+                (keys, chain, delay)
+            }
+            _ => return None,
+        };
+        Some(Policy {
+            max_lamports_per_tx: (flags & FLAG_LAMPORT_LIMIT != 0)
+                .then_some(u64::from_le_bytes(limit)),
+            allowed_recipients: keys
+                .chunks_exact(32)
+                .map(|k| k.try_into().unwrap())
+                .collect(),
+            allow_blind_signing: flags & FLAG_BLIND_SIGNING != 0,
+            chain,
+            change_delay_hours: delay,
+            production: flags & FLAG_PRODUCTION != 0,
+        })
+    }
+
+    /// Whether this policy lets through anything `current` doesn't: a higher
+    /// or no limit, recipients `current` doesn't allow, blind signing, a
+    /// shorter delay, production mode off, or another chain profile.
+    pub fn loosens(&self, current: &Policy) -> bool {
+        let limit_raised = match (current.max_lamports_per_tx, self.max_lamports_per_tx) {
+            (Some(_), None) => true,
+            (Some(old), Some(new)) => new > old,
+            (None, _) => false,
+        };
+        let recipients_added = !current.allowed_recipients.is_empty()
+            && (self.allowed_recipients.is_empty()
+                || self
+                    .allowed_recipients
+                    .iter()
+                    .any(|to| !current.allowed_recipients.contains(to)));
+        limit_raised
+            || recipients_added
+            || (self.allow_blind_signing && !current.allow_blind_signing)
+            || self.change_delay_hours < current.change_delay_hours
+            || (current.production && !self.production)
+            || self.chain != current.chain
+    }
+
+    /// Powered-on seconds replacing `current` with this policy has to wait:
+    /// `current`'s delay if this loosens it, otherwise none.
+    pub fn change_delay_secs(&self, current: &Policy) -> u32 {
+        if self.loosens(current) {
+            u32::from(current.change_delay_hours) * 3600
+        } else {
+            0
+        }
+    }
+
+    /// What the key signs for `message` under this policy, or why it
+    /// doesn't: Solana messages go through [`Policy::evaluate`], other
+    /// protocols' through their chain profile alone. `info` is as for
+    /// [`Policy::evaluate`]. The checks run on the bytes that are signed,
+    /// prefix included.
+    pub fn signing_input<'a>(
+        &self,
+        message: &'a [u8],
+        info: Option<&TransactionInfo>,
+    ) -> Result<Cow<'a, [u8]>, PolicyViolation> {
+        let input = match &self.chain {
+            ChainProfile::Solana | ChainProfile::Raw => Cow::Borrowed(message),
+            ChainProfile::Prefixed(prefix) => Cow::Owned([prefix, message].concat()),
+        };
+        if presence::is_reserved(&input) || audit::is_reserved(&input) {
+            return Err(PolicyViolation::ReservedDomain);
+        }
+        match &self.chain {
+            ChainProfile::Solana => self.evaluate(info)?,
+            // Another protocol's signature must not be valid for a Solana
+            // transaction or off-chain message
+            _ if input.starts_with(OFFCHAIN_DOMAIN) || parse_message(&input).is_ok() => {
+                return Err(PolicyViolation::ChainMismatch)
+            }
+            ChainProfile::Raw if info.is_some() => return Err(PolicyViolation::ChainMismatch),
+            _ => {}
+        }
+        Ok(input)
+    }
+
+    /// `info` is `None` when the message couldn't be parsed at all.
+    pub fn evaluate(&self, info: Option<&TransactionInfo>) -> Result<(), PolicyViolation> {
+        let Some(info) = info else {
+            return if self.allow_blind_signing {
+                Ok(())
+            } else {
+                Err(PolicyViolation::BlindSigningDisabled)
+            };
+        };
+
+        let mut total_lamports: u64 = 0;
+        for ix in &info.instructions {
+            match ix {
+                TransactionType::SystemTransfer { to, lamports, .. } => {
+                    self.check_recipient(to)?;
+                    total_lamports = total_lamports.saturating_add(*lamports);
+                }
+                TransactionType::TokenTransfer { to, .. } => self.check_recipient(to)?,
+                TransactionType::Unknown { .. } if !self.allow_blind_signing => {
+                    return Err(PolicyViolation::BlindSigningDisabled);
+                }
+                _ => {}
+            }
+        }
+
+        match self.max_lamports_per_tx {
+            Some(limit) if total_lamports > limit => Err(PolicyViolation::AmountExceedsLimit {
+                amount: total_lamports,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_recipient(&self, to: &[u8; 32]) -> Result<(), PolicyViolation> {
+        if self.allowed_recipients.is_empty() || self.allowed_recipients.contains(to) {
+            Ok(())
+        } else {
+            Err(PolicyViolation::RecipientNotAllowed(*to))
+        }
+    }
+}
+
+/// A loosening change waiting out the delay of the policy it replaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingChange {
+    pub policy: Policy,
+    /// Powered-on seconds left before it takes effect.
+    pub remaining_secs: u32,
+}
+
+impl PendingChange {
+    /// Counts `secs` of powered-on time off; true once the change is due.
+    pub fn elapse(&mut self, secs: u32) -> bool {
+        self.remaining_secs = self.remaining_secs.saturating_sub(secs);
+        self.remaining_secs == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(instructions: Vec<TransactionType>) -> TransactionInfo {
+        TransactionInfo {
+            version: None,
+            fee_payer: [1; 32],
+            fee_payer_is_signer: true,
+            blockhash: [0; 32],
+            num_signatures_required: 1,
+            max_fee: 5_000,
+            instructions,
+        }
+    }
+
+    fn transfer(to: u8, lamports: u64) -> TransactionType {
+        TransactionType::SystemTransfer {
+            from: [1; 32],
+            to: [to; 32],
+            lamports,
+        }
+    }
+
+    #[test]
+    fn default_allows_everything() {
+        let policy = Policy::default();
+        assert_eq!(policy.evaluate(None), Ok(()));
+        let unknown = TransactionType::Unknown {
+            program_id: [4; 32],
+        };
+        assert_eq!(policy.evaluate(Some(&info(vec![unknown]))), Ok(()));
+    }
+
+    #[test]
+    fn limit_applies_to_the_sum_of_transfers() {
+        let policy = Policy {
+            max_lamports_per_tx: Some(100),
+            ..Policy::default()
+        };
+        assert_eq!(policy.evaluate(Some(&info(vec![transfer(2, 100)]))), Ok(()));
+        assert_eq!(
+            policy.evaluate(Some(&info(vec![transfer(2, 60), transfer(3, 60)]))),
+            Err(PolicyViolation::AmountExceedsLimit {
+                amount: 120,
+                limit: 100
+            })
+        );
+    }
+
+    #[test]
+    fn recipient_allowlist() {
+        let policy = Policy {
+            allowed_recipients: vec![[2; 32]],
+            ..Policy::default()
+        };
+        assert_eq!(policy.evaluate(Some(&info(vec![transfer(2, 5)]))), Ok(()));
+        assert_eq!(
+            policy.evaluate(Some(&info(vec![transfer(3, 5)]))),
+            Err(PolicyViolation::RecipientNotAllowed([3; 32]))
+        );
+    }
+
+    #[test]
+    fn parse_and_display_round_trip() {
+        let policy = Policy {
+            max_lamports_per_tx: Some(5_000),
+            allowed_recipients: vec![[2; 32], [3; 32]],
+            allow_blind_signing: false,
+            chain: ChainProfile::Solana,
+            change_delay_hours: 0,
+            production: false,
+        };
+        let text = policy.to_string();
+        assert!(text.starts_with("max=5000;blind=off;allow="));
+        assert_eq!(Policy::parse(&text), Ok(policy));
+        assert_eq!(Policy::default().to_string(), "max=none;blind=on;allow=any");
+    }
+
+    #[test]
+    fn parse_keeps_defaults_for_omitted_fields() {
+        let policy = Policy::parse(" blind=off ").unwrap();
+        assert_eq!(
+            policy,
+            Policy {
+                allow_blind_signing: false,
+                ..Policy::default()
+            }
+        );
+        assert_eq!(Policy::parse(""), Ok(Policy::default()));
+    }
+
+    #[test]
+    fn parse_rejects_bad_fields() {
+        assert_eq!(Policy::parse("max=lots"), Err(PolicyParseError::BadLimit));
+        assert_eq!(Policy::parse("blind=yes"), Err(PolicyParseError::BadFlag));
+        assert_eq!(
+            Policy::parse("allow=nope"),
+            Err(PolicyParseError::BadRecipient)
+        );
+        assert_eq!(
+            Policy::parse("color=red"),
+            Err(PolicyParseError::UnknownField)
+        );
+        assert_eq!(Policy::parse("max"), Err(PolicyParseError::UnknownField));
+
+        let key = key_to_string(&[2; 32]);
+        let too_many = [key.as_str(); MAX_RECIPIENTS + 1].join(",");
+        assert_eq!(
+            Policy::parse(&format!("allow={}", too_many)),
+            Err(PolicyParseError::TooManyRecipients)
+        );
+    }
+
+    #[test]
+    fn record_round_trips_and_fits_a_journal_slot() {
+        let policy = Policy {
+            max_lamports_per_tx: Some(0),
+            allowed_recipients: vec![[9; 32]; MAX_RECIPIENTS],
+            allow_blind_signing: true,
+            chain: ChainProfile::Prefixed(vec![7; MAX_PREFIX_LEN]),
+            change_delay_hours: 255,
+            production: true,
+        };
+        let bytes = policy.to_bytes();
+        assert!(bytes.len() <= journal::MAX_PAYLOAD);
+        assert_eq!(Policy::from_bytes(&bytes), Some(policy));
+        assert_eq!(
+            Policy::from_bytes(&Policy::default().to_bytes()),
+            Some(Policy::default())
+        );
+        assert_eq!(Policy::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(Policy::from_bytes(&[]), None);
+    }
+
+    #[test]
+    fn blind_signing_disabled() {
+        let policy = Policy {
+            allow_blind_signing: false,
+            ..Policy::default()
+        };
+        assert_eq!(
+            policy.evaluate(None),
+            Err(PolicyViolation::BlindSigningDisabled)
+        );
+        let unknown = TransactionType::Unknown {
+            program_id: [4; 32],
+        };
+        assert_eq!(
+            policy.evaluate(Some(&info(vec![transfer(2, 1), unknown]))),
+            Err(PolicyViolation::BlindSigningDisabled)
+        );
+        assert_eq!(
+            policy.evaluate(Some(&info(vec![TransactionType::ComputeBudget]))),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn chain_profiles_parse_and_display() {
+        let policy = Policy::parse("chain=prefix:6e6f737472;blind=off").unwrap();
+        assert_eq!(policy.chain, ChainProfile::Prefixed(b"nostr".to_vec()));
+        assert_eq!(
+            policy.to_string(),
+            "max=none;blind=off;allow=any;chain=prefix:6e6f737472"
+        );
+        assert_eq!(Policy::parse(&policy.to_string()), Ok(policy));
+        assert_eq!(Policy::parse("chain=raw").unwrap().chain, ChainProfile::Raw);
+        assert_eq!(Policy::parse("chain=solana"), Ok(Policy::default()));
+        for bad in ["chain=ethereum", "chain=prefix:", "chain=prefix:zz"] {
+            assert_eq!(Policy::parse(bad), Err(PolicyParseError::BadChain));
+        }
+        let too_long = hex_encode(&[1; MAX_PREFIX_LEN + 1]);
+        assert_eq!(
+            Policy::parse(&format!("chain=prefix:{}", too_long)),
+            Err(PolicyParseError::BadChain)
+        );
+    }
+
+    #[test]
+    fn solana_records_stay_readable_by_older_firmware() {
+        assert_eq!(Policy::default().to_bytes()[0], RECORD_VERSION);
+        let raw = Policy {
+            chain: ChainProfile::Raw,
+            ..Policy::default()
+        };
+        let bytes = raw.to_bytes();
+        assert_eq!(bytes[0], RECORD_VERSION_CHAIN);
+        assert_eq!(Policy::from_bytes(&bytes), Some(raw));
+        // A version 2 record must carry its profile
+        let mut missing = Policy::default().to_bytes();
+        missing[0] = RECORD_VERSION_CHAIN;
+        assert_eq!(Policy::from_bytes(&missing), None);
+    }
+
+    #[test]
+    fn chain_profiles_keep_domains_apart() {
+        let transaction = info(vec![transfer(2, 5)]);
+        let message = b"event:1234";
+
+        let solana = Policy::default();
+        assert_eq!(
+            solana.signing_input(message, Some(&transaction)),
+            Ok(Cow::Borrowed(&message[..]))
+        );
+
+        let raw = Policy {
+            chain: ChainProfile::Raw,
+            max_lamports_per_tx: Some(0),
+            allow_blind_signing: false,
+            ..Policy::default()
+        };
+        assert_eq!(
+            raw.signing_input(message, None),
+            Ok(Cow::Borrowed(&message[..]))
+        );
+        assert_eq!(
+            raw.signing_input(message, Some(&transaction)),
+            Err(PolicyViolation::ChainMismatch)
+        );
+        let offchain = [OFFCHAIN_DOMAIN, b"\x00hello"].concat();
+        assert_eq!(
+            raw.signing_input(&offchain, None),
+            Err(PolicyViolation::ChainMismatch)
+        );
+
+        let prefixed = Policy {
+            chain: ChainProfile::Prefixed(b"app:".to_vec()),
+            ..Policy::default()
+        };
+        assert_eq!(
+            prefixed.signing_input(message, Some(&transaction)),
+            Ok(Cow::Owned(b"app:event:1234".to_vec()))
+        );
+    }
+
+    #[test]
+    fn presence_tokens_are_never_signed_on_request() {
+        let token = presence::Token {
+            counter: 1,
+            device_id: [0; 8],
+            time: 0,
+        }
+        .message();
+        let mut export = audit::DOMAIN.to_vec();
+        export.extend_from_slice(&[0; 68]);
+        for chain in [ChainProfile::Solana, ChainProfile::Raw] {
+            let policy = Policy {
+                chain,
+                ..Policy::default()
+            };
+            for reserved in [&token[..], &export] {
+                assert_eq!(
+                    policy.signing_input(reserved, None),
+                    Err(PolicyViolation::ReservedDomain)
+                );
+            }
+        }
+    }
+
+    /// A legacy system transfer, as a Solana wallet would send it
+    fn solana_message() -> Vec<u8> {
+        let mut m = vec![1, 0, 1, 3];
+        m.extend_from_slice(&[1; 32]);
+        m.extend_from_slice(&[2; 32]);
+        m.extend_from_slice(&crate::tx_introspection::SYSTEM_PROGRAM_ID);
+        m.extend_from_slice(&[9; 32]);
+        m.extend_from_slice(&[1, 2, 2, 0, 1, 12]);
+        m.extend_from_slice(&2u32.to_le_bytes());
+        m.extend_from_slice(&5u64.to_le_bytes());
+        m
+    }
+
+    #[test]
+    fn prefixes_cant_start_a_solana_or_device_domain() {
+        for bad in ["ff", "ff4553", "80", "c0ffee"] {
+            assert_eq!(
+                ChainProfile::parse(&format!("prefix:{}", bad)),
+                Err(PolicyParseError::BadChain)
+            );
+        }
+        assert!(ChainProfile::parse("prefix:7f").is_ok());
+
+        // Nor can a stored record carry one
+        let mut record = Policy {
+            chain: ChainProfile::Prefixed(b"app".to_vec()),
+            ..Policy::default()
+        }
+        .to_bytes();
+        let prefix_at = record.len() - 3;
+        record[prefix_at] = 0xff;
+        assert_eq!(Policy::from_bytes(&record), None);
+    }
+
+    #[test]
+    fn prefixed_input_is_checked_whole() {
+        let token = presence::Token {
+            counter: 1,
+            device_id: [0; 8],
+            time: 0,
+        }
+        .message();
+        let mut export = audit::DOMAIN.to_vec();
+        export.extend_from_slice(&[0; 68]);
+        // Profiles built directly, as a parsed one can't hold these prefixes
+        for reserved in [&token[..], &export] {
+            let policy = Policy {
+                chain: ChainProfile::Prefixed(reserved[..MAX_PREFIX_LEN].to_vec()),
+                ..Policy::default()
+            };
+            assert_eq!(
+                policy.signing_input(&reserved[MAX_PREFIX_LEN..], None),
+                Err(PolicyViolation::ReservedDomain)
+            );
+        }
+
+        let offchain = [OFFCHAIN_DOMAIN, b"\x00hello"].concat();
+        let policy = Policy {
+            chain: ChainProfile::Prefixed(offchain[..4].to_vec()),
+            ..Policy::default()
+        };
+        assert_eq!(
+            policy.signing_input(&offchain[4..], None),
+            Err(PolicyViolation::ChainMismatch)
+        );
+
+        // A prefix that completes a transaction's header is as good as
+        // signing the transaction
+        let transaction = solana_message();
+        let policy = Policy {
+            chain: ChainProfile::Prefixed(transaction[..1].to_vec()),
+            ..Policy::default()
+        };
+        assert!(ChainProfile::parse("prefix:01").is_ok());
+        assert_eq!(
+            policy.signing_input(&transaction[1..], None),
+            Err(PolicyViolation::ChainMismatch)
+        );
+        assert!(policy.signing_input(b"event:1234", None).is_ok());
+    }
+
+    #[test]
+    fn delay_parses_and_survives_the_record() {
+        let policy = Policy::parse("delay=48;blind=off").unwrap();
+        assert_eq!(policy.change_delay_hours, 48);
+        assert_eq!(policy.to_string(), "max=none;blind=off;allow=any;delay=48");
+        assert_eq!(Policy::from_bytes(&policy.to_bytes()), Some(policy));
+        for bad in ["delay=256", "delay=-1", "delay=2d"] {
+            assert_eq!(Policy::parse(bad), Err(PolicyParseError::BadDelay));
+        }
+        // Without a delay, records keep the layout older firmware reads
+        assert_eq!(Policy::default().to_bytes()[0], RECORD_VERSION);
+    }
+
+    #[test]
+    fn only_loosening_changes_wait() {
+        let current = Policy::parse("max=1000;blind=off;delay=48").unwrap();
+        let tighter = Policy::parse("max=500;blind=off;delay=72").unwrap();
+        assert!(!tighter.loosens(&current));
+        assert_eq!(tighter.change_delay_secs(&current), 0);
+
+        for looser in [
+            "max=2000;blind=off;delay=48",
+            "max=none;blind=off;delay=48",
+            "max=1000;blind=on;delay=48",
+            "max=1000;blind=off;delay=1",
+            "max=1000;blind=off;delay=48;chain=raw",
+        ] {
+            let looser = Policy::parse(looser).unwrap();
+            assert!(looser.loosens(&current), "{}", looser);
+            assert_eq!(looser.change_delay_secs(&current), 48 * 3600);
+        }
+
+        let allowed = Policy {
+            allowed_recipients: vec![[2; 32], [3; 32]],
+            ..Policy::default()
+        };
+        let fewer = Policy {
+            allowed_recipients: vec![[2; 32]],
+            ..Policy::default()
+        };
+        assert!(!fewer.loosens(&allowed));
+        assert!(allowed.loosens(&fewer));
+        assert!(Policy::default().loosens(&fewer));
+    }
+
+    #[test]
+    fn pending_changes_count_down() {
+        let mut pending = PendingChange {
+            policy: Policy::default(),
+            remaining_secs: 90,
+        };
+        assert!(!pending.elapse(60));
+        assert_eq!(pending.remaining_secs, 30);
+        assert!(pending.elapse(60));
+        assert_eq!(pending.remaining_secs, 0);
+    }
+
+    #[test]
+    fn production_mode_is_a_flag() {
+        let policy = Policy::parse("production=on;max=5").unwrap();
+        assert!(policy.production);
+        assert_eq!(policy.to_string(), "max=5;blind=on;allow=any;production=on");
+        let record = policy.to_bytes();
+        // Still a version 1 record, which older firmware reads
+        assert_eq!(record[0], RECORD_VERSION);
+        assert_eq!(Policy::from_bytes(&record), Some(policy.clone()));
+        assert_eq!(
+            Policy::parse("production=yes"),
+            Err(PolicyParseError::BadFlag)
+        );
+
+        let development = Policy::parse("max=5").unwrap();
+        assert!(development.loosens(&policy));
+        assert!(!policy.loosens(&development));
+    }
+}
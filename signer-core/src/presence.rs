@@ -0,0 +1,160 @@
+//! Presence tokens: proof that someone touched the device, for release
+//! pipelines and access workflows that want a human in the loop.
+//!
+//! A short press of BOOT while nothing is waiting for it makes the device
+//! sign a token with its Solana key: a counter that goes up with every
+//! token, the device ID, and the device clock. Verifiers check the signature
+//! against the device's public key and that the counter is past the last one
+//! they accepted, so a token can't be replayed; the time is only as good as
+//! the device clock (zero if it was never set).
+//!
+//! The signed bytes start with 0xff, which no Solana transaction does, and
+//! the policy refuses `SIGN` requests whose signing input, chain prefix
+//! included, starts with [`DOMAIN`], so a token can only come from a press.
+
+use crate::identity::DEVICE_ID_LEN;
+use crate::platform::Storage;
+
+/// Storage key for the last counter handed out (u32 LE).
+pub const COUNTER_KEY_NAME: &str = "presence_ctr";
+
+pub const DOMAIN: &[u8] = b"\xffESP32_SIGNER_PRESENCE_V1";
+/// Domain, counter (u32 LE), device ID, time (u64 LE)
+pub const MESSAGE_LEN: usize = DOMAIN.len() + 4 + DEVICE_ID_LEN + 8;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PresenceError<S> {
+    Storage(S),
+    /// The stored counter isn't 4 bytes.
+    Corrupt,
+    /// Every counter value has been used.
+    Exhausted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub counter: u32,
+    pub device_id: [u8; DEVICE_ID_LEN],
+    /// Device clock in Unix seconds, 0 if it was never set
+    pub time: u64,
+}
+
+impl Token {
+    /// The bytes the device signs.
+    pub fn message(&self) -> [u8; MESSAGE_LEN] {
+        let mut out = [0u8; MESSAGE_LEN];
+        let (domain, rest) = out.split_at_mut(DOMAIN.len());
+        domain.copy_from_slice(DOMAIN);
+        let (counter, rest) = rest.split_at_mut(4);
+        counter.copy_from_slice(&self.counter.to_le_bytes());
+        let (device_id, time) = rest.split_at_mut(DEVICE_ID_LEN);
+        device_id.copy_from_slice(&self.device_id);
+        time.copy_from_slice(&self.time.to_le_bytes());
+        out
+    }
+
+    /// Reads back what [`Token::message`] wrote.
+    pub fn parse(message: &[u8]) -> Option<Token> {
+        if message.len() != MESSAGE_LEN {
+            return None;
+        }
+        let rest = message.strip_prefix(DOMAIN)?;
+        let (counter, rest) = rest.split_at(4);
+        let (device_id, time) = rest.split_at(DEVICE_ID_LEN);
+        Some(Token {
+            counter: u32::from_le_bytes(counter.try_into().ok()?),
+            device_id: device_id.try_into().ok()?,
+            time: u64::from_le_bytes(time.try_into().ok()?),
+        })
+    }
+}
+
+/// Whether `message` is in the presence token domain, which only a press
+/// may sign.
+pub fn is_reserved(message: &[u8]) -> bool {
+    message.starts_with(DOMAIN)
+}
+
+/// Takes the next counter, storing it before it is used so a reset can't
+/// hand it out twice. The first token gets 1.
+pub fn next_counter<S: Storage>(storage: &mut S) -> Result<u32, PresenceError<S::Error>> {
+    let mut buf = [0u8; 4];
+    let last = match storage
+        .get(COUNTER_KEY_NAME, &mut buf)
+        .map_err(PresenceError::Storage)?
+    {
+        None => 0,
+        Some(4) => u32::from_le_bytes(buf),
+        Some(_) => return Err(PresenceError::Corrupt),
+    };
+    let next = last.checked_add(1).ok_or(PresenceError::Exhausted)?;
+    storage
+        .set(COUNTER_KEY_NAME, &next.to_le_bytes())
+        .map_err(PresenceError::Storage)?;
+    Ok(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::MemoryStorage;
+    use crate::policy::{ChainProfile, Policy, PolicyViolation, MAX_PREFIX_LEN};
+    use crate::tx_introspection;
+
+    #[test]
+    fn message_round_trips() {
+        let token = Token {
+            counter: 7,
+            device_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            time: 1_700_000_000,
+        };
+        let message = token.message();
+        assert!(is_reserved(&message));
+        assert_eq!(Token::parse(&message), Some(token));
+        assert_eq!(Token::parse(&message[1..]), None);
+        assert!(tx_introspection::introspect_transaction(&message, &[0; 32]).is_err());
+    }
+
+    #[test]
+    fn sign_requests_never_yield_a_token() {
+        let token = Token {
+            counter: 7,
+            device_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            time: 1_700_000_000,
+        }
+        .message();
+        let mut chains = vec![ChainProfile::Solana, ChainProfile::Raw];
+        // Every split of the token into a prefix and the rest, including
+        // prefixes a parsed profile wouldn't allow
+        chains.extend((1..=MAX_PREFIX_LEN).map(|at| ChainProfile::Prefixed(token[..at].to_vec())));
+        for chain in chains {
+            let rest = match &chain {
+                ChainProfile::Prefixed(prefix) => &token[prefix.len()..],
+                _ => &token[..],
+            };
+            let policy = Policy {
+                chain,
+                allow_blind_signing: true,
+                ..Policy::default()
+            };
+            assert_eq!(
+                policy.signing_input(rest, None),
+                Err(PolicyViolation::ReservedDomain)
+            );
+        }
+    }
+
+    #[test]
+    fn counter_only_goes_up() {
+        let mut storage = MemoryStorage::default();
+        assert_eq!(next_counter(&mut storage), Ok(1));
+        assert_eq!(next_counter(&mut storage), Ok(2));
+
+        storage
+            .0
+            .insert(COUNTER_KEY_NAME.into(), u32::MAX.to_le_bytes().to_vec());
+        assert_eq!(next_counter(&mut storage), Err(PresenceError::Exhausted));
+        storage.0.insert(COUNTER_KEY_NAME.into(), vec![1, 2]);
+        assert_eq!(next_counter(&mut storage), Err(PresenceError::Corrupt));
+    }
+}
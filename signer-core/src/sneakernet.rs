@@ -0,0 +1,163 @@
+//! Transaction transfer on an SD card, for a signer that is never connected
+//! to an online machine.
+//!
+//! The online machine writes each message to be signed as `TX<seq>.REQ` in
+//! the card's root (the raw message bytes, as SIGN takes them decoded). The
+//! signer, at boot, goes through the requests that have no answer yet and
+//! writes either `TX<seq>.SIG` (the raw 64-byte signature) or `TX<seq>.REJ`
+//! (an ASCII reason) next to each. Answered requests are left alone, so a
+//! card can go back and forth without anything being signed twice.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Longest message a request may hold: a transaction has to fit a packet.
+pub const MAX_MESSAGE_LEN: usize = 1232;
+pub const SIGNATURE_LEN: usize = 64;
+
+/// 8.3 names, as FAT without long file names wants.
+const FILE_PREFIX: &str = "TX";
+const REQUEST_SUFFIX: &str = ".REQ";
+const SIGNATURE_SUFFIX: &str = ".SIG";
+const REJECTION_SUFFIX: &str = ".REJ";
+/// Six digits after the prefix fill the eight characters.
+const MAX_SEQ: u32 = 999_999;
+
+/// Why the signer didn't sign a request, as written to its `.REJ` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// Empty, or longer than [`MAX_MESSAGE_LEN`].
+    BadSize(usize),
+    /// The signing policy refused it; the violation as the policy words it.
+    Policy(String),
+    /// No usable signing policy on the device.
+    NoPolicy,
+    /// The operator declined it on the device.
+    Declined,
+    /// Signing waits for a 2FA unlock, which a card can't carry.
+    Locked,
+}
+
+impl Rejection {
+    pub fn to_text(&self) -> String {
+        match self {
+            Rejection::BadSize(len) => format!("BAD_SIZE:{}", len),
+            Rejection::Policy(violation) => format!("POLICY:{}", violation),
+            Rejection::NoPolicy => String::from("POLICY:UNAVAILABLE"),
+            Rejection::Declined => String::from("DECLINED"),
+            Rejection::Locked => String::from("LOCKED"),
+        }
+    }
+}
+
+pub fn request_name(seq: u32) -> String {
+    format!("{}{:06}{}", FILE_PREFIX, seq, REQUEST_SUFFIX)
+}
+
+pub fn signature_name(seq: u32) -> String {
+    format!("{}{:06}{}", FILE_PREFIX, seq, SIGNATURE_SUFFIX)
+}
+
+pub fn rejection_name(seq: u32) -> String {
+    format!("{}{:06}{}", FILE_PREFIX, seq, REJECTION_SUFFIX)
+}
+
+/// The sequence number in a request's file name; case-insensitive, as FAT is.
+pub fn parse_request_name(name: &str) -> Option<u32> {
+    parse_name(name, REQUEST_SUFFIX)
+}
+
+fn parse_name(name: &str, suffix: &str) -> Option<u32> {
+    let upper = name.to_ascii_uppercase();
+    let digits = upper.strip_prefix(FILE_PREFIX)?.strip_suffix(suffix)?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// The requests among `names` (a directory listing) that have neither a
+/// signature nor a rejection yet, oldest first.
+pub fn pending<'a>(names: impl IntoIterator<Item = &'a str> + Clone) -> Vec<u32> {
+    let answered: Vec<u32> = names
+        .clone()
+        .into_iter()
+        .filter_map(|name| {
+            parse_name(name, SIGNATURE_SUFFIX).or_else(|| parse_name(name, REJECTION_SUFFIX))
+        })
+        .collect();
+    let mut pending: Vec<u32> = names
+        .into_iter()
+        .filter_map(parse_request_name)
+        .filter(|seq| !answered.contains(seq))
+        .collect();
+    pending.sort_unstable();
+    pending.dedup();
+    pending
+}
+
+/// The sequence number for a new request next to those among `names`.
+pub fn next_seq<'a>(names: impl IntoIterator<Item = &'a str>) -> Option<u32> {
+    let last = names.into_iter().filter_map(parse_request_name).max();
+    match last {
+        None => Some(1),
+        Some(seq) if seq < MAX_SEQ => Some(seq + 1),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        assert_eq!(request_name(7), "TX000007.REQ");
+        assert_eq!(signature_name(7), "TX000007.SIG");
+        assert_eq!(rejection_name(7), "TX000007.REJ");
+        assert_eq!(parse_request_name("tx000012.req"), Some(12));
+        assert_eq!(parse_request_name("TX000012.SIG"), None);
+        assert_eq!(parse_request_name("TX.REQ"), None);
+        assert_eq!(parse_request_name("TX00001A.REQ"), None);
+        assert_eq!(parse_request_name("BKP00001.ESB"), None);
+    }
+
+    #[test]
+    fn answered_requests_are_not_pending() {
+        let names = [
+            "TX000003.REQ",
+            "TX000001.REQ",
+            "TX000001.SIG",
+            "TX000002.REQ",
+            "TX000002.REJ",
+            "TX000004.req",
+            "BKP00001.ESB",
+            "TX000005.SIG",
+        ];
+        assert_eq!(pending(names), [3, 4]);
+        assert!(pending(["NOTES.TXT"]).is_empty());
+    }
+
+    #[test]
+    fn new_requests_follow_the_last() {
+        assert_eq!(next_seq([]), Some(1));
+        assert_eq!(
+            next_seq(["TX000002.REQ", "TX000009.REQ", "TX000010.SIG"]),
+            Some(10)
+        );
+        assert_eq!(next_seq(["TX999999.REQ"]), None);
+    }
+
+    #[test]
+    fn rejections_are_readable() {
+        assert_eq!(Rejection::BadSize(0).to_text(), "BAD_SIZE:0");
+        assert_eq!(
+            Rejection::Policy(String::from("amount over limit")).to_text(),
+            "POLICY:amount over limit"
+        );
+        assert_eq!(Rejection::NoPolicy.to_text(), "POLICY:UNAVAILABLE");
+        assert_eq!(Rejection::Declined.to_text(), "DECLINED");
+        assert_eq!(Rejection::Locked.to_text(), "LOCKED");
+    }
+}
@@ -0,0 +1,156 @@
+//! Chip temperature and supply voltage checks for the `TELEMETRY` command.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/// One set of sensor readings. `None` means the sensor isn't fitted or the
+/// read failed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Reading {
+    pub temp_c: Option<f32>,
+    pub vdd_mv: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    TempHigh,
+    TempLow,
+    VddHigh,
+    VddLow,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Warning::TempHigh => "temp_high",
+            Warning::TempLow => "temp_low",
+            Warning::VddHigh => "vdd_high",
+            Warning::VddLow => "vdd_low",
+        })
+    }
+}
+
+/// Inclusive limits outside of which a reading is reported as a warning.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    pub temp_min_c: f32,
+    pub temp_max_c: f32,
+    pub vdd_min_mv: u32,
+    pub vdd_max_mv: u32,
+}
+
+impl Default for Thresholds {
+    /// The ESP32-C3's recommended operating range, pulled in a little on
+    /// temperature so the warning comes before the chip is out of spec.
+    fn default() -> Self {
+        Thresholds {
+            temp_min_c: -20.0,
+            temp_max_c: 80.0,
+            vdd_min_mv: 3000,
+            vdd_max_mv: 3600,
+        }
+    }
+}
+
+impl Thresholds {
+    /// Readings that are missing produce no warning.
+    pub fn check(&self, reading: &Reading) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+        if let Some(t) = reading.temp_c {
+            if t > self.temp_max_c {
+                warnings.push(Warning::TempHigh);
+            } else if t < self.temp_min_c {
+                warnings.push(Warning::TempLow);
+            }
+        }
+        if let Some(mv) = reading.vdd_mv {
+            if mv > self.vdd_max_mv {
+                warnings.push(Warning::VddHigh);
+            } else if mv < self.vdd_min_mv {
+                warnings.push(Warning::VddLow);
+            }
+        }
+        warnings
+    }
+}
+
+/// `TELEMETRY:temp_c=<c|na>;vdd_mv=<mv|na>;warn=<none|w1,w2>`
+pub fn format_telemetry(reading: &Reading, warnings: &[Warning]) -> String {
+    let mut out = String::from("TELEMETRY:temp_c=");
+    // Writing to a String can't fail
+    let _ = match reading.temp_c {
+        Some(t) => write!(out, "{:.1}", t),
+        None => out.write_str("na"),
+    };
+    out.push_str(";vdd_mv=");
+    let _ = match reading.vdd_mv {
+        Some(mv) => write!(out, "{}", mv),
+        None => out.write_str("na"),
+    };
+    out.push_str(";warn=");
+    if warnings.is_empty() {
+        out.push_str("none");
+    }
+    for (i, w) in warnings.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{}", w);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normal_readings_have_no_warnings() {
+        let reading = Reading {
+            temp_c: Some(41.3),
+            vdd_mv: Some(3300),
+        };
+        let warnings = Thresholds::default().check(&reading);
+        assert!(warnings.is_empty());
+        assert_eq!(
+            format_telemetry(&reading, &warnings),
+            "TELEMETRY:temp_c=41.3;vdd_mv=3300;warn=none"
+        );
+    }
+
+    #[test]
+    fn out_of_range_readings_warn() {
+        let reading = Reading {
+            temp_c: Some(92.0),
+            vdd_mv: Some(2800),
+        };
+        let warnings = Thresholds::default().check(&reading);
+        assert_eq!(warnings, [Warning::TempHigh, Warning::VddLow]);
+        assert_eq!(
+            format_telemetry(&reading, &warnings),
+            "TELEMETRY:temp_c=92.0;vdd_mv=2800;warn=temp_high,vdd_low"
+        );
+    }
+
+    #[test]
+    fn limits_are_inclusive() {
+        let t = Thresholds::default();
+        let reading = Reading {
+            temp_c: Some(t.temp_max_c),
+            vdd_mv: Some(t.vdd_min_mv),
+        };
+        assert!(t.check(&reading).is_empty());
+    }
+
+    #[test]
+    fn missing_sensors_report_na() {
+        let reading = Reading::default();
+        let warnings = Thresholds::default().check(&reading);
+        assert!(warnings.is_empty());
+        assert_eq!(
+            format_telemetry(&reading, &warnings),
+            "TELEMETRY:temp_c=na;vdd_mv=na;warn=none"
+        );
+    }
+}
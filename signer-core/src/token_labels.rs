@@ -0,0 +1,270 @@
+//! Token labels: the symbol and decimals of a mint, from the host, so the
+//! device can describe a token transfer as "Send 12.5 USDC" rather than a raw
+//! amount and mint address.
+//!
+//! The device can't look mints up, so labels come with `SIGN_TX` and are only
+//! as honest as the host's lookup. They are bound to the transaction: each is
+//! keyed by a hash of its mint address, which has to be the mint of one of
+//! the message's `TransferChecked` instructions, with the same decimals, or
+//! the request is refused. A label can't be carried over to another mint or
+//! move the decimal point. The hash is shorter than the address and doesn't
+//! depend on how keys are encoded.
+//!
+//! Wire form: `<hash>:<symbol>:<decimals>` per label, comma-separated, where
+//! `<hash>` is the first [`HASH_LEN`] bytes of SHA-256(mint) in hex.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use sha2::{Digest, Sha256};
+
+use crate::encoding::{hex_decode, hex_encode};
+use crate::tx_introspection::{TransactionInfo, TransactionType};
+
+/// Bytes of SHA-256(mint) a label is keyed by
+pub const HASH_LEN: usize = 8;
+pub const MAX_LABELS: usize = 4;
+pub const MAX_SYMBOL_LEN: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenLabel {
+    pub mint_hash: [u8; HASH_LEN],
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+/// Why a label list was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelError {
+    /// Not `<hash>:<symbol>:<decimals>`.
+    Format,
+    TooMany,
+    /// Empty, too long, or outside `[A-Za-z0-9._-]`.
+    BadSymbol,
+    /// No `TransferChecked` in the message moves this mint, or it is
+    /// labeled twice.
+    UnknownMint,
+    /// The message gives the mint different decimals.
+    Decimals,
+}
+
+impl fmt::Display for LabelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LabelError::Format => "FORMAT",
+            LabelError::TooMany => "TOO_MANY",
+            LabelError::BadSymbol => "BAD_SYMBOL",
+            LabelError::UnknownMint => "UNKNOWN_MINT",
+            LabelError::Decimals => "DECIMALS",
+        })
+    }
+}
+
+pub fn mint_hash(mint: &[u8; 32]) -> [u8; HASH_LEN] {
+    let digest = Sha256::digest(mint);
+    let mut hash = [0u8; HASH_LEN];
+    hash.copy_from_slice(&digest[..HASH_LEN]);
+    hash
+}
+
+fn valid_symbol(symbol: &str) -> bool {
+    !symbol.is_empty()
+        && symbol.len() <= MAX_SYMBOL_LEN
+        && symbol
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+}
+
+impl TokenLabel {
+    pub fn new(mint: &[u8; 32], symbol: &str, decimals: u8) -> Result<Self, LabelError> {
+        if !valid_symbol(symbol) {
+            return Err(LabelError::BadSymbol);
+        }
+        Ok(TokenLabel {
+            mint_hash: mint_hash(mint),
+            symbol: symbol.to_string(),
+            decimals,
+        })
+    }
+
+    pub fn is_for(&self, mint: &[u8; 32]) -> bool {
+        self.mint_hash == mint_hash(mint)
+    }
+}
+
+/// Parses the wire form; an empty string is no labels.
+pub fn parse(text: &str) -> Result<Vec<TokenLabel>, LabelError> {
+    let mut labels = Vec::new();
+    for item in text.split(',').filter(|item| !item.is_empty()) {
+        if labels.len() == MAX_LABELS {
+            return Err(LabelError::TooMany);
+        }
+        let mut fields = item.split(':');
+        let (Some(hash), Some(symbol), Some(decimals), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(LabelError::Format);
+        };
+        let mint_hash = hex_decode(hash)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(LabelError::Format)?;
+        let decimals = decimals.parse().map_err(|_| LabelError::Format)?;
+        if !valid_symbol(symbol) {
+            return Err(LabelError::BadSymbol);
+        }
+        labels.push(TokenLabel {
+            mint_hash,
+            symbol: symbol.to_string(),
+            decimals,
+        });
+    }
+    Ok(labels)
+}
+
+pub fn format(labels: &[TokenLabel]) -> String {
+    labels
+        .iter()
+        .map(|label| {
+            alloc::format!(
+                "{}:{}:{}",
+                hex_encode(&label.mint_hash),
+                label.symbol,
+                label.decimals
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Checks every label is for a mint the message's `TransferChecked`
+/// instructions move, with their decimals, and no mint is labeled twice.
+pub fn bind(labels: &[TokenLabel], info: &TransactionInfo) -> Result<(), LabelError> {
+    for (i, label) in labels.iter().enumerate() {
+        if labels[..i].iter().any(|l| l.mint_hash == label.mint_hash) {
+            return Err(LabelError::UnknownMint);
+        }
+        let mut found = false;
+        for ix in &info.instructions {
+            if let TransactionType::TokenTransfer {
+                mint: Some(mint),
+                decimals: Some(decimals),
+                ..
+            } = ix
+            {
+                if label.is_for(mint) {
+                    if *decimals != label.decimals {
+                        return Err(LabelError::Decimals);
+                    }
+                    found = true;
+                }
+            }
+        }
+        if !found {
+            return Err(LabelError::UnknownMint);
+        }
+    }
+    Ok(())
+}
+
+/// The label for `mint`, if there is one.
+pub fn find<'a>(labels: &'a [TokenLabel], mint: &[u8; 32]) -> Option<&'a TokenLabel> {
+    labels.iter().find(|label| label.is_for(mint))
+}
+
+/// `amount` base units as a decimal number, without trailing zeros.
+pub fn format_amount(amount: u64, decimals: u8) -> String {
+    let digits = amount.to_string();
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return digits;
+    }
+    let padded = alloc::format!("{:0>width$}", digits, width = decimals + 1);
+    let (whole, fraction) = padded.split_at(padded.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        alloc::format!("{}.{}", whole, fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx_introspection::format_labeled_transaction_info;
+
+    const USDC: [u8; 32] = [5; 32];
+
+    fn transfer(mint: [u8; 32], decimals: u8) -> TransactionInfo {
+        TransactionInfo {
+            version: None,
+            fee_payer: [1; 32],
+            fee_payer_is_signer: true,
+            blockhash: [9; 32],
+            num_signatures_required: 1,
+            max_fee: 5_000,
+            instructions: alloc::vec![TransactionType::TokenTransfer {
+                from: [2; 32],
+                to: [3; 32],
+                mint: Some(mint),
+                amount: 12_500_000,
+                decimals: Some(decimals),
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trips_the_wire_form() {
+        let labels = alloc::vec![
+            TokenLabel::new(&USDC, "USDC", 6).unwrap(),
+            TokenLabel::new(&[6; 32], "wSOL", 9).unwrap(),
+        ];
+        let text = format(&labels);
+        assert_eq!(parse(&text).unwrap(), labels);
+        assert_eq!(parse("").unwrap(), Vec::new());
+
+        assert_eq!(parse("00:USDC:6"), Err(LabelError::Format));
+        assert_eq!(parse("0011223344556677:USDC"), Err(LabelError::Format));
+        assert_eq!(
+            parse("0011223344556677:US DC:6"),
+            Err(LabelError::BadSymbol)
+        );
+        assert_eq!(
+            parse("0011223344556677:VERYLONGSYMBOL:6"),
+            Err(LabelError::BadSymbol)
+        );
+        let six = [text.as_str(); 3].join(",");
+        assert_eq!(parse(&six), Err(LabelError::TooMany));
+    }
+
+    #[test]
+    fn binds_labels_to_the_message() {
+        let info = transfer(USDC, 6);
+        let label = TokenLabel::new(&USDC, "USDC", 6).unwrap();
+        assert_eq!(bind(core::slice::from_ref(&label), &info), Ok(()));
+        assert_eq!(
+            bind(&[TokenLabel::new(&[6; 32], "USDC", 6).unwrap()], &info),
+            Err(LabelError::UnknownMint)
+        );
+        assert_eq!(
+            bind(&[TokenLabel::new(&USDC, "USDC", 9).unwrap()], &info),
+            Err(LabelError::Decimals)
+        );
+        assert_eq!(
+            bind(&[label.clone(), label.clone()], &info),
+            Err(LabelError::UnknownMint)
+        );
+
+        let text = format_labeled_transaction_info(&info, &[label]);
+        assert!(text.contains("#0 Send 12.5 USDC:"), "{}", text);
+    }
+
+    #[test]
+    fn formats_amounts() {
+        assert_eq!(format_amount(12_500_000, 6), "12.5");
+        assert_eq!(format_amount(1_000_000, 6), "1");
+        assert_eq!(format_amount(5, 6), "0.000005");
+        assert_eq!(format_amount(42, 0), "42");
+        assert_eq!(format_amount(u64::MAX, 19), "1.8446744073709551615");
+    }
+}
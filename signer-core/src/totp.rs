@@ -0,0 +1,215 @@
+//! RFC 4226/6238 one-time codes (HMAC-SHA1, 6 digits, 30 s steps).
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+type HmacSha1 = Hmac<Sha1>;
+
+pub const OTP_BYTES: usize = 20;
+pub const OTP_DIGITS: u32 = 6;
+pub const OTP_PERIOD: u64 = 30;
+/// Steps of clock drift tolerated either side of now.
+pub const OTP_WINDOW: i64 = 1;
+pub const UNLOCK_SECS: u64 = 120;
+
+/// HOTP value for `counter`, already reduced to `OTP_DIGITS` digits.
+pub fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = HmacSha1::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let off = (digest[19] & 0x0f) as usize;
+    let dbc = ((u32::from(digest[off]) & 0x7f) << 24)
+        | (u32::from(digest[off + 1]) << 16)
+        | (u32::from(digest[off + 2]) << 8)
+        | u32::from(digest[off + 3]);
+    dbc % 10u32.pow(OTP_DIGITS)
+}
+
+/// Zero-padded ASCII digits of a HOTP value.
+fn digits(value: u32) -> [u8; OTP_DIGITS as usize] {
+    let mut out = [b'0'; OTP_DIGITS as usize];
+    let mut v = value;
+    for d in out.iter_mut().rev() {
+        *d = b'0' + (v % 10) as u8;
+        v /= 10;
+    }
+    out
+}
+
+/// Check `code` against the steps around `now`, skipping `last_step` so an
+/// accepted code can't be replayed. Returns the matching step to persist.
+pub fn verify_code(code: &str, secret: &[u8], now: u64, last_step: u64) -> Option<u64> {
+    if code.len() != OTP_DIGITS as usize || !code.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let step_now = now / OTP_PERIOD;
+    for w in -OTP_WINDOW..=OTP_WINDOW {
+        let Some(step) = step_now.checked_add_signed(w) else {
+            continue;
+        };
+        if step == last_step {
+            continue; // prevent replay in window
+        }
+        let expected = digits(hotp(secret, step));
+        if expected.ct_eq(code.as_bytes()).into() {
+            return Some(step);
+        }
+    }
+    None
+}
+
+/// Serialized size of [`OtpState`].
+pub const OTP_STATE_LEN: usize = OTP_BYTES + 8 + 1;
+
+/// Everything 2FA persists. Stored as one journaled record so a power dip
+/// can't pair a new secret with a stale step or enrolled flag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtpState {
+    pub secret: [u8; OTP_BYTES],
+    /// Last accepted step; codes for it are refused as replays.
+    pub last_step: u64,
+    pub enrolled: bool,
+}
+
+impl OtpState {
+    /// A freshly issued secret, awaiting OTP_CONFIRM.
+    pub fn new(secret: [u8; OTP_BYTES]) -> Self {
+        OtpState {
+            secret,
+            last_step: 0,
+            enrolled: false,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; OTP_STATE_LEN] {
+        let mut out = [0u8; OTP_STATE_LEN];
+        out[..OTP_BYTES].copy_from_slice(&self.secret);
+        out[OTP_BYTES..OTP_BYTES + 8].copy_from_slice(&self.last_step.to_le_bytes());
+        out[OTP_BYTES + 8] = u8::from(self.enrolled);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != OTP_STATE_LEN || bytes[OTP_BYTES + 8] > 1 {
+            return None;
+        }
+        let mut secret = [0u8; OTP_BYTES];
+        secret.copy_from_slice(&bytes[..OTP_BYTES]);
+        let mut step = [0u8; 8];
+        step.copy_from_slice(&bytes[OTP_BYTES..OTP_BYTES + 8]);
+        Some(OtpState {
+            secret,
+            last_step: u64::from_le_bytes(step),
+            enrolled: bytes[OTP_BYTES + 8] == 1,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::totp_vectors::{self, HOTP, TOTP, WINDOW};
+
+    const RFC4226_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc4226_vectors() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, want) in expected.iter().enumerate() {
+            assert_eq!(hotp(RFC4226_SECRET, counter as u64), *want);
+        }
+    }
+
+    #[test]
+    fn pads_leading_zeros() {
+        assert_eq!(&digits(42), b"000042");
+    }
+
+    #[test]
+    fn accepts_adjacent_steps_and_rejects_replay() {
+        let now = 1_000 * OTP_PERIOD;
+        let code = |step| std::format!("{:06}", hotp(RFC4226_SECRET, step));
+
+        assert_eq!(
+            verify_code(&code(1_000), RFC4226_SECRET, now, 0),
+            Some(1_000)
+        );
+        assert_eq!(verify_code(&code(999), RFC4226_SECRET, now, 0), Some(999));
+        assert_eq!(
+            verify_code(&code(1_001), RFC4226_SECRET, now, 0),
+            Some(1_001)
+        );
+        assert_eq!(verify_code(&code(1_002), RFC4226_SECRET, now, 0), None);
+        assert_eq!(verify_code(&code(1_000), RFC4226_SECRET, now, 1_000), None);
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        assert_eq!(verify_code("", RFC4226_SECRET, 0, 0), None);
+        assert_eq!(verify_code("12345", RFC4226_SECRET, 0, 0), None);
+        assert_eq!(verify_code("12345a", RFC4226_SECRET, 0, 0), None);
+        assert_eq!(verify_code("1234567", RFC4226_SECRET, 0, 0), None);
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let state = OtpState {
+            secret: [7; OTP_BYTES],
+            last_step: 56_789_012,
+            enrolled: true,
+        };
+        assert_eq!(OtpState::from_bytes(&state.to_bytes()), Some(state));
+        assert_eq!(OtpState::from_bytes(&[0u8; OTP_STATE_LEN - 1]), None);
+    }
+
+    #[test]
+    fn step_zero_does_not_underflow() {
+        let code = std::format!("{:06}", hotp(RFC4226_SECRET, 0));
+        assert_eq!(verify_code(&code, RFC4226_SECRET, 5, u64::MAX), Some(0));
+    }
+
+    #[test]
+    fn shared_hotp_vectors() {
+        for (counter, want) in HOTP.iter().enumerate() {
+            assert_eq!(hotp(totp_vectors::SECRET, counter as u64), *want);
+        }
+    }
+
+    #[test]
+    fn shared_totp_vectors() {
+        for vector in TOTP {
+            assert_eq!(vector.unix / OTP_PERIOD, vector.step);
+            assert_eq!(
+                &digits(hotp(totp_vectors::SECRET, vector.step)),
+                vector.code.as_bytes(),
+                "at {}",
+                vector.unix
+            );
+            assert_eq!(
+                verify_code(vector.code, totp_vectors::SECRET, vector.unix, 0),
+                Some(vector.step)
+            );
+        }
+    }
+
+    #[test]
+    fn shared_window_vectors() {
+        for vector in WINDOW {
+            assert_eq!(
+                verify_code(
+                    vector.code,
+                    totp_vectors::SECRET,
+                    vector.now,
+                    vector.last_step
+                ),
+                vector.accepted,
+                "{}",
+                vector.name
+            );
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Golden one-time code vectors, shared by every implementation in the tree:
+//! [`crate::totp`] (which the firmware's `twofa` runs) tests against them, and
+//! so does the host tool's code generator, so the two can't drift apart.
+//!
+//! All codes are for [`SECRET`], SHA1 and six digits; the RFCs list eight,
+//! of which six digits are the last six.
+
+/// The ASCII secret of RFC 4226 appendix D and RFC 6238 appendix B.
+pub const SECRET: &[u8] = b"12345678901234567890";
+
+/// RFC 4226 appendix D: HOTP values for counters 0 to 9.
+pub const HOTP: [u32; 10] = [
+    755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+];
+
+/// A TOTP code at a point in time, 30 s steps.
+#[derive(Debug, Clone, Copy)]
+pub struct TotpVector {
+    pub unix: u64,
+    pub step: u64,
+    pub code: &'static str,
+}
+
+/// RFC 6238 appendix B (SHA1 rows), cut to six digits.
+pub const TOTP: [TotpVector; 6] = [
+    TotpVector {
+        unix: 59,
+        step: 1,
+        code: "287082",
+    },
+    TotpVector {
+        unix: 1_111_111_109,
+        step: 37_037_036,
+        code: "081804",
+    },
+    TotpVector {
+        unix: 1_111_111_111,
+        step: 37_037_037,
+        code: "050471",
+    },
+    TotpVector {
+        unix: 1_234_567_890,
+        step: 41_152_263,
+        code: "005924",
+    },
+    TotpVector {
+        unix: 2_000_000_000,
+        step: 66_666_666,
+        code: "279037",
+    },
+    TotpVector {
+        unix: 20_000_000_000,
+        step: 666_666_666,
+        code: "353130",
+    },
+];
+
+/// A code checked at `now`, after `last_step` was accepted: the step it
+/// should be accepted as, or `None` if it must be refused.
+#[derive(Debug, Clone, Copy)]
+pub struct WindowVector {
+    pub name: &'static str,
+    pub now: u64,
+    pub code: &'static str,
+    pub last_step: u64,
+    pub accepted: Option<u64>,
+}
+
+/// Edge cases of the drift window (one step either side) and replay check,
+/// around `now` = 1111111111, step 37037037.
+pub const WINDOW: [WindowVector; 10] = [
+    WindowVector {
+        name: "current step",
+        now: 1_111_111_111,
+        code: "050471",
+        last_step: 0,
+        accepted: Some(37_037_037),
+    },
+    WindowVector {
+        name: "one step behind",
+        now: 1_111_111_111,
+        code: "081804",
+        last_step: 0,
+        accepted: Some(37_037_036),
+    },
+    WindowVector {
+        name: "one step ahead",
+        now: 1_111_111_111,
+        code: "266759",
+        last_step: 0,
+        accepted: Some(37_037_038),
+    },
+    WindowVector {
+        name: "two steps behind",
+        now: 1_111_111_111,
+        code: "731029",
+        last_step: 0,
+        accepted: None,
+    },
+    WindowVector {
+        name: "two steps ahead",
+        now: 1_111_111_111,
+        code: "306183",
+        last_step: 0,
+        accepted: None,
+    },
+    WindowVector {
+        name: "replay of the accepted step",
+        now: 1_111_111_111,
+        code: "050471",
+        last_step: 37_037_037,
+        accepted: None,
+    },
+    WindowVector {
+        name: "earlier step after a later one was accepted",
+        now: 1_111_111_111,
+        code: "081804",
+        last_step: 37_037_037,
+        accepted: Some(37_037_036),
+    },
+    WindowVector {
+        name: "last second of a step",
+        now: 1_111_111_139,
+        code: "266759",
+        last_step: 0,
+        accepted: Some(37_037_038),
+    },
+    WindowVector {
+        name: "step zero, nothing accepted yet",
+        now: 5,
+        code: "755224",
+        last_step: u64::MAX,
+        accepted: Some(0),
+    },
+    WindowVector {
+        name: "wrong code",
+        now: 1_111_111_111,
+        code: "000000",
+        last_step: 0,
+        accepted: None,
+    },
+];
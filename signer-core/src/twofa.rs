@@ -0,0 +1,271 @@
+//! TOTP enrollment and unlocking over [`Storage`].
+//!
+//! The firmware's `twofa` module is a thin wrapper around these functions
+//! (adding NVS, the RTC and migration from its pre-journal layout), so the
+//! state machine can be tested on the host against
+//! [`MemoryStorage`](crate::platform::MemoryStorage).
+
+use crate::journal::{self, JournalError, Slots};
+use crate::platform::{EntropySource, Storage};
+use crate::totp::{verify_code, OtpState, OTP_BYTES, OTP_STATE_LEN, UNLOCK_SECS};
+
+/// Secret, last accepted step and enrolled flag, journaled as one record.
+pub const OTP_STATE_SLOTS: Slots = Slots("otp_state_a", "otp_state_b");
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TwoFaError<S, E> {
+    Storage(JournalError<S>),
+    Entropy(E),
+    /// The journaled record isn't a valid [`OtpState`].
+    Corrupt,
+    AlreadyEnrolled,
+    /// OTP_CONFIRM without a secret from OTP_BEGIN.
+    NoSecret,
+    NotEnrolled,
+    BadCode,
+}
+
+/// The stored state, or `None` if nothing has been written.
+pub fn load_state<S: Storage, E>(
+    storage: &mut S,
+) -> Result<Option<OtpState>, TwoFaError<S::Error, E>> {
+    let mut buf = [0u8; OTP_STATE_LEN];
+    match journal::load(storage, &OTP_STATE_SLOTS, &mut buf).map_err(TwoFaError::Storage)? {
+        Some(len) => OtpState::from_bytes(&buf[..len])
+            .map(Some)
+            .ok_or(TwoFaError::Corrupt),
+        None => Ok(None),
+    }
+}
+
+pub fn store_state<S: Storage, E>(
+    storage: &mut S,
+    state: &OtpState,
+) -> Result<(), TwoFaError<S::Error, E>> {
+    journal::store(storage, &OTP_STATE_SLOTS, &state.to_bytes()).map_err(TwoFaError::Storage)
+}
+
+pub fn is_enrolled<S: Storage, E>(storage: &mut S) -> Result<bool, TwoFaError<S::Error, E>> {
+    Ok(load_state(storage)?.is_some_and(|state| state.enrolled))
+}
+
+/// Generate and persist a new secret awaiting confirmation. A secret from an
+/// unconfirmed enrollment is replaced; an enrolled one never is. Nothing is
+/// persisted if the RNG fails.
+pub fn begin<S: Storage, R: EntropySource>(
+    storage: &mut S,
+    rng: &mut R,
+) -> Result<[u8; OTP_BYTES], TwoFaError<S::Error, R::Error>> {
+    if is_enrolled(storage)? {
+        return Err(TwoFaError::AlreadyEnrolled);
+    }
+    let mut secret = [0u8; OTP_BYTES];
+    rng.fill(&mut secret).map_err(TwoFaError::Entropy)?;
+    store_state(storage, &OtpState::new(secret))?;
+    Ok(secret)
+}
+
+/// Complete enrollment with a code for the secret from [`begin`]. The
+/// accepted step is persisted, so the same code can't then unlock.
+pub fn confirm<S: Storage, E>(
+    storage: &mut S,
+    code: &str,
+    now: u64,
+) -> Result<(), TwoFaError<S::Error, E>> {
+    let mut state = load_state(storage)?.ok_or(TwoFaError::NoSecret)?;
+    let accepted =
+        verify_code(code, &state.secret, now, state.last_step).ok_or(TwoFaError::BadCode)?;
+    state.last_step = accepted;
+    state.enrolled = true;
+    store_state(storage, &state)
+}
+
+/// Verify a code and return the unlock-until timestamp. The accepted step is
+/// persisted before the unlock takes effect.
+pub fn unlock<S: Storage, E>(
+    storage: &mut S,
+    code: &str,
+    now: u64,
+) -> Result<u64, TwoFaError<S::Error, E>> {
+    let mut state = match load_state(storage)? {
+        Some(state) if state.enrolled => state,
+        _ => return Err(TwoFaError::NotEnrolled),
+    };
+    let accepted =
+        verify_code(code, &state.secret, now, state.last_step).ok_or(TwoFaError::BadCode)?;
+    state.last_step = accepted;
+    store_state(storage, &state)?;
+    Ok(now + UNLOCK_SECS)
+}
+
+/// Whether signing is refused at `now` given the last [`unlock`] result
+/// (0 if never unlocked since boot). The unlock covers its last second.
+pub fn is_locked(now: u64, unlocked_until: u64) -> bool {
+    now > unlocked_until
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::MemoryStorage;
+    use crate::totp::{hotp, OTP_PERIOD};
+    use core::convert::Infallible;
+
+    type Error = TwoFaError<Infallible, &'static str>;
+
+    struct FixedRng([u8; OTP_BYTES]);
+
+    impl EntropySource for FixedRng {
+        type Error = &'static str;
+
+        fn fill(&mut self, buf: &mut [u8]) -> Result<(), &'static str> {
+            buf.copy_from_slice(&self.0[..buf.len()]);
+            Ok(())
+        }
+    }
+
+    struct FailingRng;
+
+    impl EntropySource for FailingRng {
+        type Error = &'static str;
+
+        fn fill(&mut self, _buf: &mut [u8]) -> Result<(), &'static str> {
+            Err("degraded")
+        }
+    }
+
+    const SECRET: [u8; OTP_BYTES] = *b"12345678901234567890";
+    const NOW: u64 = 1_000 * OTP_PERIOD;
+
+    fn code(step: u64) -> std::string::String {
+        std::format!("{:06}", hotp(&SECRET, step))
+    }
+
+    fn enrolled() -> MemoryStorage {
+        let mut storage = MemoryStorage::default();
+        begin(&mut storage, &mut FixedRng(SECRET)).unwrap();
+        confirm::<_, ()>(&mut storage, &code(1_000), NOW).unwrap();
+        storage
+    }
+
+    #[test]
+    fn enrollment_needs_a_confirmed_code() {
+        let mut storage = MemoryStorage::default();
+        assert_eq!(begin(&mut storage, &mut FixedRng(SECRET)), Ok(SECRET));
+        assert_eq!(is_enrolled::<_, ()>(&mut storage), Ok(false));
+        assert_eq!(
+            unlock(&mut storage, &code(1_000), NOW),
+            Err::<u64, Error>(TwoFaError::NotEnrolled)
+        );
+
+        assert_eq!(
+            confirm(&mut storage, "000000", NOW),
+            Err::<(), Error>(TwoFaError::BadCode)
+        );
+        confirm::<_, ()>(&mut storage, &code(1_000), NOW).unwrap();
+        assert_eq!(is_enrolled::<_, ()>(&mut storage), Ok(true));
+    }
+
+    #[test]
+    fn confirm_without_begin_is_refused() {
+        assert_eq!(
+            confirm(&mut MemoryStorage::default(), &code(1_000), NOW),
+            Err::<(), Error>(TwoFaError::NoSecret)
+        );
+    }
+
+    #[test]
+    fn begin_replaces_only_unconfirmed_secrets() {
+        let mut storage = MemoryStorage::default();
+        begin(&mut storage, &mut FixedRng([1; OTP_BYTES])).unwrap();
+        begin(&mut storage, &mut FixedRng(SECRET)).unwrap();
+        confirm::<_, ()>(&mut storage, &code(1_000), NOW).unwrap();
+
+        assert_eq!(
+            begin(&mut storage, &mut FixedRng([2; OTP_BYTES])),
+            Err(TwoFaError::AlreadyEnrolled)
+        );
+        let state = load_state::<_, ()>(&mut storage).unwrap().unwrap();
+        assert_eq!(state.secret, SECRET);
+    }
+
+    #[test]
+    fn failed_entropy_persists_nothing() {
+        let mut storage = MemoryStorage::default();
+        assert_eq!(
+            begin(&mut storage, &mut FailingRng),
+            Err(TwoFaError::Entropy("degraded"))
+        );
+        assert!(storage.0.is_empty());
+    }
+
+    #[test]
+    fn accepted_codes_cannot_be_replayed() {
+        let mut storage = enrolled();
+        // The confirmation code was consumed by enrollment.
+        assert_eq!(
+            unlock(&mut storage, &code(1_000), NOW),
+            Err::<u64, Error>(TwoFaError::BadCode)
+        );
+
+        let later = NOW + OTP_PERIOD;
+        assert_eq!(
+            unlock::<_, ()>(&mut storage, &code(1_001), later),
+            Ok(later + UNLOCK_SECS)
+        );
+        assert_eq!(
+            unlock(&mut storage, &code(1_001), later),
+            Err::<u64, Error>(TwoFaError::BadCode)
+        );
+    }
+
+    #[test]
+    fn unlock_accepts_one_step_of_drift() {
+        let later = NOW + 10 * OTP_PERIOD;
+        for step in [1_009, 1_010, 1_011] {
+            let mut storage = enrolled();
+            assert_eq!(
+                unlock::<_, ()>(&mut storage, &code(step), later),
+                Ok(later + UNLOCK_SECS)
+            );
+            let state = load_state::<_, ()>(&mut storage).unwrap().unwrap();
+            assert_eq!(state.last_step, step);
+        }
+        for step in [1_008, 1_012] {
+            assert_eq!(
+                unlock(&mut enrolled(), &code(step), later),
+                Err::<u64, Error>(TwoFaError::BadCode)
+            );
+        }
+    }
+
+    #[test]
+    fn failed_unlock_keeps_state() {
+        let mut storage = enrolled();
+        let before = storage.0.clone();
+        assert_eq!(
+            unlock(&mut storage, "123456", NOW + OTP_PERIOD),
+            Err::<u64, Error>(TwoFaError::BadCode)
+        );
+        assert_eq!(storage.0, before);
+    }
+
+    #[test]
+    fn unlock_expires() {
+        let until = unlock::<_, ()>(&mut enrolled(), &code(1_001), NOW).unwrap();
+        assert!(is_locked(NOW, 0));
+        assert!(!is_locked(NOW, until));
+        assert!(!is_locked(until, until));
+        assert!(is_locked(until + 1, until));
+    }
+
+    #[test]
+    fn corrupt_state_is_reported() {
+        let mut storage = MemoryStorage::default();
+        journal::store(&mut storage, &OTP_STATE_SLOTS, &[0u8; 3]).unwrap();
+        assert_eq!(
+            is_enrolled(&mut storage),
+            Err::<bool, Error>(TwoFaError::Corrupt)
+        );
+    }
+}
@@ -0,0 +1,558 @@
+//! Malformed and adversarial messages for [`crate::tx_introspection`].
+//!
+//! The firmware runs `parse_message` on whatever arrives over the serial
+//! link, before asking for BOOT, so it has to turn every input into either a
+//! [`Message`] or a [`ParseError`]: no panic, no slice past the end of the
+//! buffer (in Rust both are the same thing), no allocation the input can't
+//! back. The named cases below pin down which error each kind of damage
+//! gets; the sweeps then truncate, flip and splice bytes into the valid
+//! cases and check that whatever parses is a message the rest of the
+//! firmware can index safely, and that re-encodes to exactly the input.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::vec::Vec;
+
+use crate::tx_introspection::{
+    format_transaction_info, introspect_transaction, parse_message, Message, ParseError,
+    COMPUTE_BUDGET_PROGRAM_ID, MEMO_PROGRAM_ID, SYSTEM_PROGRAM_ID, TOKEN_PROGRAM_ID,
+};
+
+const PAYER: [u8; 32] = [1; 32];
+const RECIPIENT: [u8; 32] = [2; 32];
+const BLOCKHASH: [u8; 32] = [9; 32];
+const TABLE: [u8; 32] = [8; 32];
+
+struct Case {
+    name: &'static str,
+    bytes: Vec<u8>,
+    /// `Ok(())` for inputs that must parse
+    expect: Result<(), ParseError>,
+}
+
+/// Canonical short_vec encoding of `n`.
+fn compact(n: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut n = n;
+    loop {
+        let b = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(b);
+            return out;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+fn ix(program: u8, accounts: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut out = vec![program];
+    out.extend(compact(accounts.len()));
+    out.extend_from_slice(accounts);
+    out.extend(compact(data.len()));
+    out.extend_from_slice(data);
+    out
+}
+
+fn lookup(writable: &[u8], readonly: &[u8]) -> Vec<u8> {
+    table_lookup(&TABLE, writable, readonly)
+}
+
+fn table_lookup(table: &[u8; 32], writable: &[u8], readonly: &[u8]) -> Vec<u8> {
+    let mut out = table.to_vec();
+    out.extend(compact(writable.len()));
+    out.extend_from_slice(writable);
+    out.extend(compact(readonly.len()));
+    out.extend_from_slice(readonly);
+    out
+}
+
+/// A message from its parts, each already encoded; `lookups` is `None` for
+/// a legacy message.
+fn message(
+    header: [u8; 3],
+    keys: &[[u8; 32]],
+    ixs: &[Vec<u8>],
+    lookups: Option<&[Vec<u8>]>,
+) -> Vec<u8> {
+    message_with_blockhash(header, keys, &BLOCKHASH, ixs, lookups)
+}
+
+fn message_with_blockhash(
+    header: [u8; 3],
+    keys: &[[u8; 32]],
+    blockhash: &[u8; 32],
+    ixs: &[Vec<u8>],
+    lookups: Option<&[Vec<u8>]>,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    if lookups.is_some() {
+        out.push(0x80);
+    }
+    out.extend_from_slice(&header);
+    out.extend(compact(keys.len()));
+    for key in keys {
+        out.extend_from_slice(key);
+    }
+    out.extend_from_slice(blockhash);
+    out.extend(compact(ixs.len()));
+    for ix in ixs {
+        out.extend_from_slice(ix);
+    }
+    if let Some(lookups) = lookups {
+        out.extend(compact(lookups.len()));
+        for lookup in lookups {
+            out.extend_from_slice(lookup);
+        }
+    }
+    out
+}
+
+/// `bytes` with the byte at `at` replaced.
+fn patched(bytes: &[u8], at: usize, value: u8) -> Vec<u8> {
+    let mut out = bytes.to_vec();
+    out[at] = value;
+    out
+}
+
+fn transfer_data(lamports: u64) -> Vec<u8> {
+    let mut data = 2u32.to_le_bytes().to_vec();
+    data.extend_from_slice(&lamports.to_le_bytes());
+    data
+}
+
+fn legacy_transfer() -> Vec<u8> {
+    message(
+        [1, 0, 1],
+        &[PAYER, RECIPIENT, SYSTEM_PROGRAM_ID],
+        &[ix(2, &[0, 1], &transfer_data(1))],
+        None,
+    )
+}
+
+/// Static keys 0-2, lookup-table accounts 3 (writable) and 4 (readonly).
+fn v0_transfer_checked() -> Vec<u8> {
+    let mut data = vec![12];
+    data.extend_from_slice(&5u64.to_le_bytes());
+    data.push(6);
+    message(
+        [1, 0, 2],
+        &[PAYER, COMPUTE_BUDGET_PROGRAM_ID, TOKEN_PROGRAM_ID],
+        &[
+            ix(1, &[], &[2, 0x40, 0x0d, 0x03, 0x00]),
+            ix(2, &[0, 4, 3, 0], &data),
+        ],
+        Some(&[lookup(&[1], &[0])]),
+    )
+}
+
+/// The prefix of `bytes` up to and including the account count, the keys,
+/// and the rest; for splicing in a bad account count.
+fn with_account_count(bytes: &[u8], version: bool, count: &[u8], keys: usize) -> Vec<u8> {
+    let header = if version { 4 } else { 3 };
+    let mut out = bytes[..header].to_vec();
+    out.extend_from_slice(count);
+    // the originals all have fewer than 128 keys, so one count byte
+    out.extend_from_slice(&bytes[header + 1..header + 1 + keys * 32]);
+    out
+}
+
+fn cases() -> Vec<Case> {
+    let keys3 = [PAYER, RECIPIENT, SYSTEM_PROGRAM_ID];
+    let transfer = || ix(2, &[0, 1], &transfer_data(1));
+    let ok = |name, bytes| Case {
+        name,
+        bytes,
+        expect: Ok(()),
+    };
+    let bad = |name, bytes, error| Case {
+        name,
+        bytes,
+        expect: Err(error),
+    };
+    let legacy = legacy_transfer();
+    let v0 = v0_transfer_checked();
+
+    let mut many_keys = vec![PAYER];
+    many_keys.extend((1..300u16).map(|i| {
+        let mut key = [0u8; 32];
+        key[..2].copy_from_slice(&i.to_le_bytes());
+        key
+    }));
+    let mut trailing = legacy.clone();
+    trailing.extend(core::iter::repeat_n(0xa5, 64 * 1024));
+
+    vec![
+        // Valid seeds for the sweeps
+        ok("legacy transfer", legacy.clone()),
+        ok("v0 transfer-checked through a lookup table", v0.clone()),
+        ok(
+            "memo",
+            message(
+                [1, 0, 1],
+                &[PAYER, MEMO_PROGRAM_ID],
+                &[ix(1, &[], b"hi")],
+                None,
+            ),
+        ),
+        ok("no instructions", message([1, 0, 0], &[PAYER], &[], None)),
+        ok(
+            "v0 without lookups",
+            message([1, 0, 1], &keys3, &[transfer()], Some(&[])),
+        ),
+        // Oversized, but well-formed: the parser has no size limit of its own
+        ok(
+            "300 account keys",
+            message([1, 0, 1], &many_keys, &[], None),
+        ),
+        ok(
+            "64 KiB memo",
+            message(
+                [1, 0, 1],
+                &[PAYER, MEMO_PROGRAM_ID],
+                &[ix(1, &[], &vec![b'a'; usize::from(u16::MAX)])],
+                None,
+            ),
+        ),
+        // Truncated
+        bad("empty", vec![], ParseError::Truncated),
+        bad("version prefix only", vec![0x80], ParseError::Truncated),
+        bad("header only", vec![1, 0, 1], ParseError::Truncated),
+        bad(
+            "fewer keys than counted",
+            with_account_count(&legacy, false, &[4], 3),
+            ParseError::Truncated,
+        ),
+        bad(
+            "account count 65535",
+            with_account_count(&legacy, false, &[0xff, 0xff, 0x03], 3),
+            ParseError::Truncated,
+        ),
+        bad(
+            "no blockhash",
+            legacy[..4 + 3 * 32].to_vec(),
+            ParseError::Truncated,
+        ),
+        bad(
+            "instruction count past the end",
+            patched(&legacy, 4 + 3 * 32 + 32, 2),
+            ParseError::Truncated,
+        ),
+        bad(
+            "instruction count 65535",
+            [
+                &legacy[..4 + 3 * 32 + 32],
+                &[0xff, 0xff, 0x03][..],
+                &legacy[4 + 3 * 32 + 33..],
+            ]
+            .concat(),
+            ParseError::Truncated,
+        ),
+        bad(
+            "instruction data longer than the message",
+            [
+                &legacy[..legacy.len() - 13],
+                &[0xff, 0xff, 0x03][..],
+                &legacy[legacy.len() - 12..],
+            ]
+            .concat(),
+            ParseError::Truncated,
+        ),
+        bad(
+            "v0 missing lookup count",
+            v0[..v0.len() - 1 - lookup(&[1], &[0]).len()].to_vec(),
+            ParseError::Truncated,
+        ),
+        bad(
+            "v0 lookup count 65535",
+            [
+                &v0[..v0.len() - 1 - lookup(&[1], &[0]).len()],
+                &[0xff, 0xff, 0x03][..],
+                &lookup(&[1], &[0]),
+            ]
+            .concat(),
+            ParseError::Truncated,
+        ),
+        bad(
+            "v0 lookup indexes cut short",
+            v0[..v0.len() - 1].to_vec(),
+            ParseError::Truncated,
+        ),
+        // Bad compact-u16 lengths
+        bad(
+            "non-canonical account count",
+            with_account_count(&legacy, false, &[0x83, 0x00], 3),
+            ParseError::InvalidLength,
+        ),
+        bad(
+            "account count over u16",
+            with_account_count(&legacy, false, &[0xff, 0xff, 0x04], 3),
+            ParseError::InvalidLength,
+        ),
+        bad(
+            "four-byte account count",
+            with_account_count(&legacy, false, &[0x80, 0x80, 0x80, 0x01], 3),
+            ParseError::InvalidLength,
+        ),
+        bad(
+            "non-canonical instruction data length",
+            [
+                &legacy[..legacy.len() - 13],
+                &[0x8c, 0x00][..],
+                &legacy[legacy.len() - 12..],
+            ]
+            .concat(),
+            ParseError::InvalidLength,
+        ),
+        bad(
+            "non-canonical v0 account count",
+            with_account_count(&v0, true, &[0x83, 0x80, 0x00], 3),
+            ParseError::InvalidLength,
+        ),
+        // Versions
+        bad(
+            "version 1",
+            vec![0x81, 1, 0, 0],
+            ParseError::UnsupportedVersion(1),
+        ),
+        bad(
+            "version 127",
+            vec![0xff, 1, 0, 0],
+            ParseError::UnsupportedVersion(127),
+        ),
+        // Headers that don't fit the accounts
+        bad(
+            "no signers",
+            message([0, 0, 1], &keys3, &[transfer()], None),
+            ParseError::InvalidHeader,
+        ),
+        bad(
+            "more signers than keys",
+            message([4, 0, 0], &keys3, &[transfer()], None),
+            ParseError::InvalidHeader,
+        ),
+        bad(
+            "every signer readonly",
+            message([1, 1, 1], &keys3, &[transfer()], None),
+            ParseError::InvalidHeader,
+        ),
+        bad(
+            "more readonly than non-signers",
+            message([1, 0, 3], &keys3, &[transfer()], None),
+            ParseError::InvalidHeader,
+        ),
+        bad(
+            "no account keys",
+            message([1, 0, 0], &[], &[], None),
+            ParseError::InvalidHeader,
+        ),
+        bad(
+            "127 signers, three keys",
+            message([127, 0, 0], &keys3, &[], None),
+            ParseError::InvalidHeader,
+        ),
+        // Out-of-range account indices
+        bad(
+            "program index one past the keys",
+            message(
+                [1, 0, 1],
+                &keys3,
+                &[ix(3, &[0, 1], &transfer_data(1))],
+                None,
+            ),
+            ParseError::AccountIndexOutOfRange,
+        ),
+        bad(
+            "program index 255",
+            message([1, 0, 1], &keys3, &[ix(255, &[], &[])], None),
+            ParseError::AccountIndexOutOfRange,
+        ),
+        bad(
+            "account index 255",
+            message(
+                [1, 0, 1],
+                &keys3,
+                &[ix(2, &[0, 255], &transfer_data(1))],
+                None,
+            ),
+            ParseError::AccountIndexOutOfRange,
+        ),
+        bad(
+            "account index out of range in a later instruction",
+            message(
+                [1, 0, 1],
+                &keys3,
+                &[transfer(), ix(2, &[0, 3], &transfer_data(1))],
+                None,
+            ),
+            ParseError::AccountIndexOutOfRange,
+        ),
+        bad(
+            "v0 program from a lookup table",
+            message(
+                [1, 0, 2],
+                &[PAYER, COMPUTE_BUDGET_PROGRAM_ID, TOKEN_PROGRAM_ID],
+                &[ix(3, &[0], &[])],
+                Some(&[lookup(&[1], &[0])]),
+            ),
+            ParseError::AccountIndexOutOfRange,
+        ),
+        bad(
+            "v0 account past the lookup-table accounts",
+            message(
+                [1, 0, 2],
+                &[PAYER, COMPUTE_BUDGET_PROGRAM_ID, TOKEN_PROGRAM_ID],
+                &[ix(2, &[0, 5], &[])],
+                Some(&[lookup(&[1], &[0])]),
+            ),
+            ParseError::AccountIndexOutOfRange,
+        ),
+        bad(
+            "legacy message with lookup-table indexes",
+            message([1, 0, 1], &keys3, &[ix(2, &[0, 3], &[])], None),
+            ParseError::AccountIndexOutOfRange,
+        ),
+        // Leftovers
+        bad(
+            "trailing byte",
+            [&legacy[..], &[0]].concat(),
+            ParseError::TrailingBytes,
+        ),
+        bad(
+            "legacy message with a lookup section",
+            [&legacy[..], &[1], &lookup(&[1], &[0])].concat(),
+            ParseError::TrailingBytes,
+        ),
+        bad(
+            "64 KiB of trailing bytes",
+            trailing,
+            ParseError::TrailingBytes,
+        ),
+    ]
+}
+
+/// The parts of a parsed message, encoded again.
+fn encode(message: &Message) -> Vec<u8> {
+    let h = &message.header;
+    let header = [
+        h.num_required_signatures,
+        h.num_readonly_signed_accounts,
+        h.num_readonly_unsigned_accounts,
+    ];
+    let ixs: Vec<Vec<u8>> = message
+        .instructions
+        .iter()
+        .map(|i| ix(i.program_id_index, &i.accounts, &i.data))
+        .collect();
+    let lookups: Vec<Vec<u8>> = message
+        .address_table_lookups
+        .iter()
+        .map(|l| table_lookup(&l.account_key, &l.writable_indexes, &l.readonly_indexes))
+        .collect();
+    message_with_blockhash(
+        header,
+        &message.account_keys,
+        &message.recent_blockhash,
+        &ixs,
+        message.version.map(|_| &lookups[..]),
+    )
+}
+
+/// Parses `bytes` and everything downstream of a successful parse, without
+/// letting a panic escape, and checks what parsed is safe to index.
+fn check(name: &str, bytes: &[u8]) -> Result<(), ParseError> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let message = parse_message(bytes)?;
+        let info = introspect_transaction(bytes, &PAYER)?;
+        format_transaction_info(&info);
+        Ok(message)
+    }))
+    .unwrap_or_else(|_| panic!("{}: panicked on {:02x?}", name, bytes));
+
+    let message = result?;
+    let keys = message.account_keys.len();
+    let h = &message.header;
+    assert!(h.num_required_signatures >= 1, "{}", name);
+    assert!(usize::from(h.num_required_signatures) <= keys, "{}", name);
+    for ix in &message.instructions {
+        assert!(usize::from(ix.program_id_index) < keys, "{}", name);
+        for &a in &ix.accounts {
+            assert!(usize::from(a) < message.total_accounts(), "{}", name);
+        }
+    }
+    assert_eq!(encode(&message), bytes, "{}: doesn't re-encode", name);
+    Ok(())
+}
+
+/// Deterministic xorshift, so a failure can be rerun.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// The valid cases small enough to sweep byte by byte.
+fn seeds() -> Vec<(&'static str, Vec<u8>)> {
+    cases()
+        .into_iter()
+        .filter(|c| c.expect.is_ok() && c.bytes.len() < 1_024)
+        .map(|c| (c.name, c.bytes))
+        .collect()
+}
+
+#[test]
+fn corpus_fails_as_expected() {
+    for case in cases() {
+        assert_eq!(check(case.name, &case.bytes), case.expect, "{}", case.name);
+    }
+}
+
+#[test]
+fn truncations_never_parse() {
+    for (name, bytes) in seeds() {
+        for len in 0..bytes.len() {
+            assert!(
+                check(name, &bytes[..len]).is_err(),
+                "{} cut to {}",
+                name,
+                len
+            );
+        }
+    }
+}
+
+#[test]
+fn single_byte_changes_are_safe() {
+    for (name, bytes) in seeds() {
+        for i in 0..bytes.len() {
+            for value in [0x00, 0x01, 0x7f, 0x80, 0xff, bytes[i] ^ 0x01] {
+                let mut mutated = bytes.clone();
+                mutated[i] = value;
+                let _ = check(name, &mutated);
+            }
+        }
+    }
+}
+
+#[test]
+fn spliced_garbage_is_safe() {
+    let seeds = seeds();
+    let mut rng = Rng(0x5eed_cafe_f00d_d00d);
+    for _ in 0..20_000 {
+        let (name, seed) = &seeds[rng.below(seeds.len())];
+        let mut bytes = seed.clone();
+        let at = rng.below(bytes.len() + 1);
+        let cut = rng.below(bytes.len() - at + 1);
+        let insert: Vec<u8> = (0..rng.below(8)).map(|_| rng.next() as u8).collect();
+        bytes.splice(at..at + cut, insert);
+        let _ = check(name, &bytes);
+    }
+}
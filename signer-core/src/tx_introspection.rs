@@ -0,0 +1,770 @@
+//! Decoding of Solana transaction messages so the signer can see what it is
+//! about to sign.
+//!
+//! Handles legacy and v0 messages. The parser never panics and never
+//! allocates more than the input could actually describe, so it is safe to run
+//! on untrusted bytes straight off the serial link.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::encoding::key_to_string;
+use crate::token_labels::{self, TokenLabel};
+
+/// 11111111111111111111111111111111
+pub const SYSTEM_PROGRAM_ID: [u8; 32] = [0; 32];
+
+/// TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA
+pub const TOKEN_PROGRAM_ID: [u8; 32] = [
+    6, 221, 246, 225, 215, 101, 161, 147, 217, 203, 225, 70, 206, 235, 121, 172, 28, 180, 133, 237,
+    95, 91, 55, 145, 58, 140, 245, 133, 126, 255, 0, 169,
+];
+
+/// ComputeBudget111111111111111111111111111111
+pub const COMPUTE_BUDGET_PROGRAM_ID: [u8; 32] = [
+    3, 6, 70, 111, 229, 33, 23, 50, 255, 236, 173, 186, 114, 195, 155, 231, 188, 140, 229, 187,
+    197, 247, 18, 107, 44, 67, 155, 58, 64, 0, 0, 0,
+];
+
+/// MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr
+pub const MEMO_PROGRAM_ID: [u8; 32] = [
+    5, 74, 83, 90, 153, 41, 33, 6, 77, 36, 232, 113, 96, 218, 56, 124, 124, 53, 181, 221, 188, 146,
+    187, 129, 228, 31, 168, 64, 65, 5, 68, 141,
+];
+
+// Versioned messages set the top bit of the first byte; legacy messages can't,
+// since that byte is num_required_signatures.
+const VERSION_PREFIX_MASK: u8 = 0x80;
+
+const SYSTEM_IX_TRANSFER: u32 = 2;
+const SYSTEM_IX_ADVANCE_NONCE: u32 = 4;
+const TOKEN_IX_TRANSFER: u8 = 3;
+const TOKEN_IX_TRANSFER_CHECKED: u8 = 12;
+const COMPUTE_BUDGET_IX_SET_LIMIT: u8 = 2;
+const COMPUTE_BUDGET_IX_SET_PRICE: u8 = 3;
+
+/// Base fee per signature.
+pub const LAMPORTS_PER_SIGNATURE: u64 = 5_000;
+/// Compute units each instruction gets when no limit is set, and the most
+/// a transaction can ask for.
+const DEFAULT_CU_PER_INSTRUCTION: u64 = 200_000;
+const MAX_CU_LIMIT: u64 = 1_400_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// Input ended before the message did.
+    Truncated,
+    /// A compact-u16 length was overlong, non-canonical, or out of range.
+    InvalidLength,
+    /// Message version this parser doesn't know.
+    UnsupportedVersion(u8),
+    /// Signature/readonly counts don't fit the account list.
+    InvalidHeader,
+    /// An instruction references an account that doesn't exist.
+    AccountIndexOutOfRange,
+    /// Bytes left over after a complete message.
+    TrailingBytes,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Truncated => f.write_str("message truncated"),
+            ParseError::InvalidLength => f.write_str("invalid compact length"),
+            ParseError::UnsupportedVersion(v) => write!(f, "unsupported message version {}", v),
+            ParseError::InvalidHeader => f.write_str("invalid message header"),
+            ParseError::AccountIndexOutOfRange => f.write_str("account index out of range"),
+            ParseError::TrailingBytes => f.write_str("trailing bytes after message"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageHeader {
+    pub num_required_signatures: u8,
+    pub num_readonly_signed_accounts: u8,
+    pub num_readonly_unsigned_accounts: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledInstruction {
+    pub program_id_index: u8,
+    pub accounts: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressTableLookup {
+    pub account_key: [u8; 32],
+    pub writable_indexes: Vec<u8>,
+    pub readonly_indexes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// `None` for legacy messages.
+    pub version: Option<u8>,
+    pub header: MessageHeader,
+    pub account_keys: Vec<[u8; 32]>,
+    pub recent_blockhash: [u8; 32],
+    pub instructions: Vec<CompiledInstruction>,
+    /// Always empty for legacy messages.
+    pub address_table_lookups: Vec<AddressTableLookup>,
+}
+
+impl Message {
+    /// Static keys plus the ones loaded from lookup tables.
+    pub fn total_accounts(&self) -> usize {
+        self.account_keys.len()
+            + self
+                .address_table_lookups
+                .iter()
+                .map(|l| l.writable_indexes.len() + l.readonly_indexes.len())
+                .sum::<usize>()
+    }
+
+    /// Pubkey behind an instruction account index, if it's a static key.
+    /// Accounts loaded from lookup tables can't be resolved offline.
+    fn static_key(&self, index: u8) -> Option<&[u8; 32]> {
+        self.account_keys.get(index as usize)
+    }
+}
+
+/// What a single instruction does, as far as we can tell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionType {
+    SystemTransfer {
+        from: [u8; 32],
+        to: [u8; 32],
+        lamports: u64,
+    },
+    /// `from`/`to` are token accounts, not wallet addresses. `mint` and
+    /// `decimals` are only known for TransferChecked.
+    TokenTransfer {
+        from: [u8; 32],
+        to: [u8; 32],
+        mint: Option<[u8; 32]>,
+        amount: u64,
+        decimals: Option<u8>,
+    },
+    Memo {
+        text: String,
+    },
+    ComputeBudget,
+    /// Durable nonce transactions start with this instead of relying on a
+    /// recent blockhash.
+    AdvanceNonce {
+        nonce_account: [u8; 32],
+    },
+    Unknown {
+        program_id: [u8; 32],
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionInfo {
+    pub version: Option<u8>,
+    pub fee_payer: [u8; 32],
+    /// Whether the fee payer is the key this device signs with.
+    pub fee_payer_is_signer: bool,
+    pub blockhash: [u8; 32],
+    pub num_signatures_required: u8,
+    /// The most the fee payer can be charged, in lamports: the base fee per
+    /// signature plus the compute unit limit at the compute unit price. Read
+    /// from the message itself, so it doesn't depend on what the host says.
+    pub max_fee: u64,
+    pub instructions: Vec<TransactionType>,
+}
+
+impl TransactionInfo {
+    /// The instruction that best describes the transaction: the first one
+    /// that isn't a compute budget tweak or a nonce advance.
+    pub fn tx_type(&self) -> Option<&TransactionType> {
+        self.instructions.iter().find(|ix| {
+            !matches!(
+                ix,
+                TransactionType::ComputeBudget | TransactionType::AdvanceNonce { .. }
+            )
+        })
+    }
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Cursor { buf, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn u8(&mut self) -> Result<u8, ParseError> {
+        let b = *self.buf.get(self.pos).ok_or(ParseError::Truncated)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], ParseError> {
+        if n > self.remaining() {
+            return Err(ParseError::Truncated);
+        }
+        let out = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn array32(&mut self) -> Result<[u8; 32], ParseError> {
+        let mut out = [0u8; 32];
+        out.copy_from_slice(self.bytes(32)?);
+        Ok(out)
+    }
+
+    /// Solana's "short_vec" length: 1-3 bytes, 7 bits each, little-endian.
+    /// Rejects the aliased encodings the runtime rejects.
+    fn compact_u16(&mut self) -> Result<usize, ParseError> {
+        let mut value: u32 = 0;
+        for i in 0..3 {
+            let b = self.u8()?;
+            value |= u32::from(b & 0x7f) << (7 * i);
+            if b & 0x80 == 0 {
+                if i > 0 && b == 0 {
+                    return Err(ParseError::InvalidLength);
+                }
+                if value > u32::from(u16::MAX) {
+                    return Err(ParseError::InvalidLength);
+                }
+                return Ok(value as usize);
+            }
+        }
+        Err(ParseError::InvalidLength)
+    }
+
+    /// Compact length followed by that many bytes.
+    fn byte_vec(&mut self) -> Result<Vec<u8>, ParseError> {
+        let len = self.compact_u16()?;
+        Ok(self.bytes(len)?.to_vec())
+    }
+}
+
+/// Parse a serialized (unsigned) message.
+pub fn parse_message(message_bytes: &[u8]) -> Result<Message, ParseError> {
+    let mut cur = Cursor::new(message_bytes);
+
+    let first = cur.u8()?;
+    let (version, num_required_signatures) = if first & VERSION_PREFIX_MASK != 0 {
+        let version = first & !VERSION_PREFIX_MASK;
+        if version != 0 {
+            return Err(ParseError::UnsupportedVersion(version));
+        }
+        (Some(version), cur.u8()?)
+    } else {
+        (None, first)
+    };
+    let header = MessageHeader {
+        num_required_signatures,
+        num_readonly_signed_accounts: cur.u8()?,
+        num_readonly_unsigned_accounts: cur.u8()?,
+    };
+
+    let num_keys = cur.compact_u16()?;
+    // Check before allocating so a bogus length can't exhaust the heap
+    if num_keys * 32 > cur.remaining() {
+        return Err(ParseError::Truncated);
+    }
+    let mut account_keys = Vec::with_capacity(num_keys);
+    for _ in 0..num_keys {
+        account_keys.push(cur.array32()?);
+    }
+
+    let recent_blockhash = cur.array32()?;
+
+    let num_instructions = cur.compact_u16()?;
+    let mut instructions = Vec::new();
+    for _ in 0..num_instructions {
+        instructions.push(CompiledInstruction {
+            program_id_index: cur.u8()?,
+            accounts: cur.byte_vec()?,
+            data: cur.byte_vec()?,
+        });
+    }
+
+    let mut address_table_lookups = Vec::new();
+    if version.is_some() {
+        let num_lookups = cur.compact_u16()?;
+        for _ in 0..num_lookups {
+            address_table_lookups.push(AddressTableLookup {
+                account_key: cur.array32()?,
+                writable_indexes: cur.byte_vec()?,
+                readonly_indexes: cur.byte_vec()?,
+            });
+        }
+    }
+
+    if cur.remaining() != 0 {
+        return Err(ParseError::TrailingBytes);
+    }
+
+    let message = Message {
+        version,
+        header,
+        account_keys,
+        recent_blockhash,
+        instructions,
+        address_table_lookups,
+    };
+    validate(&message)?;
+    Ok(message)
+}
+
+fn validate(message: &Message) -> Result<(), ParseError> {
+    let h = &message.header;
+    let num_keys = message.account_keys.len();
+    let required = h.num_required_signatures as usize;
+    if required == 0
+        || required > num_keys
+        || h.num_readonly_signed_accounts >= h.num_required_signatures
+        || h.num_readonly_unsigned_accounts as usize > num_keys - required
+    {
+        return Err(ParseError::InvalidHeader);
+    }
+
+    let total = message.total_accounts();
+    for ix in &message.instructions {
+        // Program ids can't come from lookup tables
+        if ix.program_id_index as usize >= num_keys
+            || ix.accounts.iter().any(|&a| a as usize >= total)
+        {
+            return Err(ParseError::AccountIndexOutOfRange);
+        }
+    }
+    Ok(())
+}
+
+/// Fee payer is always the first account.
+pub fn is_fee_payer_signer(message: &Message, signer_pubkey: &[u8; 32]) -> bool {
+    message.account_keys.first() == Some(signer_pubkey)
+}
+
+/// The most `message` can cost its fee payer, from its signature count and
+/// compute budget instructions.
+pub fn max_fee(message: &Message) -> u64 {
+    let mut limit = None;
+    let mut price = 0u64;
+    let mut instructions = 0u64;
+    for ix in &message.instructions {
+        let program_id = message.account_keys.get(ix.program_id_index as usize);
+        if program_id != Some(&COMPUTE_BUDGET_PROGRAM_ID) {
+            instructions += 1;
+            continue;
+        }
+        match ix.data.split_first() {
+            Some((&COMPUTE_BUDGET_IX_SET_LIMIT, value)) => {
+                limit = value.try_into().ok().map(u32::from_le_bytes)
+            }
+            Some((&COMPUTE_BUDGET_IX_SET_PRICE, value)) => {
+                price = value.try_into().map_or(0, u64::from_le_bytes)
+            }
+            _ => {}
+        }
+    }
+    let units = limit.map_or_else(
+        || (DEFAULT_CU_PER_INSTRUCTION * instructions).min(MAX_CU_LIMIT),
+        u64::from,
+    );
+    // Micro-lamports per unit, rounded up
+    let priority = (u128::from(units) * u128::from(price)).div_ceil(1_000_000);
+    (LAMPORTS_PER_SIGNATURE * u64::from(message.header.num_required_signatures))
+        .saturating_add(u64::try_from(priority).unwrap_or(u64::MAX))
+}
+
+/// Parse a message and decode each instruction into something a human (or
+/// a [`Policy`](crate::policy::Policy)) can judge.
+pub fn introspect_transaction(
+    message_bytes: &[u8],
+    signer_pubkey: &[u8; 32],
+) -> Result<TransactionInfo, ParseError> {
+    let message = parse_message(message_bytes)?;
+    let instructions = message
+        .instructions
+        .iter()
+        .map(|ix| decode_instruction(&message, ix))
+        .collect();
+
+    Ok(TransactionInfo {
+        version: message.version,
+        // validate() guarantees at least one signer key
+        fee_payer: message.account_keys[0],
+        fee_payer_is_signer: is_fee_payer_signer(&message, signer_pubkey),
+        blockhash: message.recent_blockhash,
+        num_signatures_required: message.header.num_required_signatures,
+        max_fee: max_fee(&message),
+        instructions,
+    })
+}
+
+fn decode_instruction(message: &Message, ix: &CompiledInstruction) -> TransactionType {
+    // Index checked in validate()
+    let program_id = message.account_keys[ix.program_id_index as usize];
+    let account = |n: usize| ix.accounts.get(n).and_then(|&i| message.static_key(i));
+
+    let decoded = match program_id {
+        SYSTEM_PROGRAM_ID => decode_system(&ix.data, account(0), account(1)),
+        TOKEN_PROGRAM_ID => decode_token(&ix.data, &account),
+        MEMO_PROGRAM_ID => Some(TransactionType::Memo {
+            text: String::from_utf8_lossy(&ix.data).into_owned(),
+        }),
+        COMPUTE_BUDGET_PROGRAM_ID => Some(TransactionType::ComputeBudget),
+        _ => None,
+    };
+    decoded.unwrap_or(TransactionType::Unknown { program_id })
+}
+
+fn decode_system(
+    data: &[u8],
+    from: Option<&[u8; 32]>,
+    to: Option<&[u8; 32]>,
+) -> Option<TransactionType> {
+    let (tag, rest) = data.split_first_chunk::<4>()?;
+    match (u32::from_le_bytes(*tag), rest.len()) {
+        (SYSTEM_IX_TRANSFER, 8) => Some(TransactionType::SystemTransfer {
+            from: *from?,
+            to: *to?,
+            lamports: u64::from_le_bytes(rest.try_into().ok()?),
+        }),
+        // AdvanceNonceAccount: [nonce, recent blockhashes sysvar, authority]
+        (SYSTEM_IX_ADVANCE_NONCE, 0) => Some(TransactionType::AdvanceNonce {
+            nonce_account: *from?,
+        }),
+        _ => None,
+    }
+}
+
+fn decode_token<'m>(
+    data: &[u8],
+    account: &impl Fn(usize) -> Option<&'m [u8; 32]>,
+) -> Option<TransactionType> {
+    let (&tag, rest) = data.split_first()?;
+    match (tag, rest.len()) {
+        // Transfer: [source, destination, owner]
+        (TOKEN_IX_TRANSFER, 8) => Some(TransactionType::TokenTransfer {
+            from: *account(0)?,
+            to: *account(1)?,
+            mint: None,
+            amount: u64::from_le_bytes(rest.try_into().ok()?),
+            decimals: None,
+        }),
+        // TransferChecked: [source, mint, destination, owner]
+        (TOKEN_IX_TRANSFER_CHECKED, 9) => Some(TransactionType::TokenTransfer {
+            from: *account(0)?,
+            to: *account(2)?,
+            mint: Some(*account(1)?),
+            amount: u64::from_le_bytes(rest[..8].try_into().ok()?),
+            decimals: Some(rest[8]),
+        }),
+        _ => None,
+    }
+}
+
+/// Human-readable multi-line summary for logs and host display.
+pub fn format_transaction_info(tx_info: &TransactionInfo) -> String {
+    format_labeled_transaction_info(tx_info, &[])
+}
+
+/// [`format_transaction_info`], with the transfers of labeled mints given in
+/// their symbol and decimals.
+pub fn format_labeled_transaction_info(tx_info: &TransactionInfo, labels: &[TokenLabel]) -> String {
+    let mut output = String::new();
+    // Writing to a String can't fail
+    let _ = write_transaction_info(&mut output, tx_info, labels);
+    output
+}
+
+fn write_transaction_info(
+    out: &mut String,
+    tx_info: &TransactionInfo,
+    labels: &[TokenLabel],
+) -> fmt::Result {
+    match tx_info.version {
+        Some(v) => writeln!(out, "Version: v{}", v)?,
+        None => writeln!(out, "Version: legacy")?,
+    }
+    writeln!(out, "Fee payer: {}", key_to_string(&tx_info.fee_payer))?;
+    if !tx_info.fee_payer_is_signer {
+        writeln!(out, "WARNING: fee payer is not this signer")?;
+    }
+    writeln!(out, "Blockhash: {}", key_to_string(&tx_info.blockhash))?;
+    writeln!(
+        out,
+        "Signatures required: {}",
+        tx_info.num_signatures_required
+    )?;
+    writeln!(
+        out,
+        "Max fee: {}.{:09} SOL",
+        tx_info.max_fee / 1_000_000_000,
+        tx_info.max_fee % 1_000_000_000
+    )?;
+
+    for (i, ix) in tx_info.instructions.iter().enumerate() {
+        write!(out, "#{} ", i)?;
+        match ix {
+            TransactionType::SystemTransfer { from, to, lamports } => writeln!(
+                out,
+                "SOL Transfer: {} -> {} {}.{:09} SOL ({} lamports)",
+                key_to_string(from),
+                key_to_string(to),
+                lamports / 1_000_000_000,
+                lamports % 1_000_000_000,
+                lamports
+            )?,
+            TransactionType::TokenTransfer {
+                from,
+                to,
+                mint,
+                amount,
+                decimals,
+            } => {
+                let label = mint.as_ref().and_then(|m| token_labels::find(labels, m));
+                if let (Some(label), Some(_)) = (label, decimals) {
+                    writeln!(
+                        out,
+                        "Send {} {}: {} -> {}",
+                        token_labels::format_amount(*amount, label.decimals),
+                        label.symbol,
+                        key_to_string(from),
+                        key_to_string(to)
+                    )?;
+                    continue;
+                }
+                write!(
+                    out,
+                    "Token Transfer: {} -> {} amount {}",
+                    key_to_string(from),
+                    key_to_string(to),
+                    amount
+                )?;
+                if let Some(d) = decimals {
+                    write!(out, " (decimals {})", d)?;
+                }
+                if let Some(m) = mint {
+                    write!(out, " mint {}", key_to_string(m))?;
+                }
+                writeln!(out)?;
+            }
+            TransactionType::Memo { text } => writeln!(out, "Memo: {}", text)?,
+            TransactionType::ComputeBudget => writeln!(out, "Compute Budget")?,
+            TransactionType::AdvanceNonce { nonce_account } => {
+                writeln!(out, "Advance Nonce: {}", key_to_string(nonce_account))?
+            }
+            TransactionType::Unknown { program_id } => {
+                writeln!(out, "Unknown program: {}", key_to_string(program_id))?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYER: [u8; 32] = [1; 32];
+    const RECIPIENT: [u8; 32] = [2; 32];
+    const BLOCKHASH: [u8; 32] = [9; 32];
+
+    fn legacy_transfer(lamports: u64) -> Vec<u8> {
+        let mut m = vec![1, 0, 1, 3];
+        m.extend_from_slice(&PAYER);
+        m.extend_from_slice(&RECIPIENT);
+        m.extend_from_slice(&SYSTEM_PROGRAM_ID);
+        m.extend_from_slice(&BLOCKHASH);
+        m.push(1); // one instruction
+        m.extend_from_slice(&[2, 2, 0, 1, 12]);
+        m.extend_from_slice(&SYSTEM_IX_TRANSFER.to_le_bytes());
+        m.extend_from_slice(&lamports.to_le_bytes());
+        m
+    }
+
+    #[test]
+    fn decodes_legacy_system_transfer() {
+        let info = introspect_transaction(&legacy_transfer(1_500_000_000), &PAYER).unwrap();
+        assert_eq!(info.version, None);
+        assert!(info.fee_payer_is_signer);
+        assert_eq!(info.blockhash, BLOCKHASH);
+        assert_eq!(
+            info.tx_type(),
+            Some(&TransactionType::SystemTransfer {
+                from: PAYER,
+                to: RECIPIENT,
+                lamports: 1_500_000_000
+            })
+        );
+        let text = format_transaction_info(&info);
+        assert!(text.contains("1.500000000 SOL"), "{}", text);
+    }
+
+    #[test]
+    fn flags_foreign_fee_payer() {
+        let info = introspect_transaction(&legacy_transfer(1), &[7; 32]).unwrap();
+        assert!(!info.fee_payer_is_signer);
+    }
+
+    #[test]
+    fn decodes_v0_with_lookup_table() {
+        let mut m = vec![0x80, 1, 0, 2, 3];
+        m.extend_from_slice(&PAYER);
+        m.extend_from_slice(&COMPUTE_BUDGET_PROGRAM_ID);
+        m.extend_from_slice(&TOKEN_PROGRAM_ID);
+        m.extend_from_slice(&BLOCKHASH);
+        m.push(2);
+        m.extend_from_slice(&[1, 0, 5, 2, 0x40, 0x0d, 0x03, 0x00]);
+        // TransferChecked with mint and destination from the lookup table
+        m.extend_from_slice(&[2, 4, 0, 3, 4, 0, 10, TOKEN_IX_TRANSFER_CHECKED]);
+        m.extend_from_slice(&5u64.to_le_bytes());
+        m.push(6);
+        m.push(1); // one lookup
+        m.extend_from_slice(&[8; 32]);
+        m.extend_from_slice(&[2, 0, 1, 0]);
+
+        let message = parse_message(&m).unwrap();
+        assert_eq!(message.version, Some(0));
+        assert_eq!(message.total_accounts(), 5);
+
+        let info = introspect_transaction(&m, &PAYER).unwrap();
+        assert_eq!(info.instructions[0], TransactionType::ComputeBudget);
+        // 200k units at no price
+        assert_eq!(info.max_fee, LAMPORTS_PER_SIGNATURE);
+        // Lookup-table accounts can't be resolved, so we don't pretend to know
+        assert_eq!(
+            info.tx_type(),
+            Some(&TransactionType::Unknown {
+                program_id: TOKEN_PROGRAM_ID
+            })
+        );
+    }
+
+    #[test]
+    fn computes_max_fee() {
+        // No compute budget: 200k units for the one instruction, no price
+        let info = introspect_transaction(&legacy_transfer(1), &PAYER).unwrap();
+        assert_eq!(info.max_fee, LAMPORTS_PER_SIGNATURE);
+
+        let mut m = vec![1, 0, 1, 2];
+        m.extend_from_slice(&PAYER);
+        m.extend_from_slice(&COMPUTE_BUDGET_PROGRAM_ID);
+        m.extend_from_slice(&BLOCKHASH);
+        m.push(2);
+        m.extend_from_slice(&[1, 0, 5, COMPUTE_BUDGET_IX_SET_LIMIT]);
+        m.extend_from_slice(&300_000u32.to_le_bytes());
+        m.extend_from_slice(&[1, 0, 9, COMPUTE_BUDGET_IX_SET_PRICE]);
+        m.extend_from_slice(&2_500u64.to_le_bytes());
+        let info = introspect_transaction(&m, &PAYER).unwrap();
+        // 300k units at 2,500 micro-lamports each
+        assert_eq!(info.max_fee, LAMPORTS_PER_SIGNATURE + 750);
+        let text = format_transaction_info(&info);
+        assert!(text.contains("Max fee: 0.000005750 SOL"), "{}", text);
+    }
+
+    #[test]
+    fn decodes_memo() {
+        let mut m = vec![1, 0, 1, 2];
+        m.extend_from_slice(&PAYER);
+        m.extend_from_slice(&MEMO_PROGRAM_ID);
+        m.extend_from_slice(&BLOCKHASH);
+        m.extend_from_slice(&[1, 1, 1, 0, 2]);
+        m.extend_from_slice(b"hi");
+        let info = introspect_transaction(&m, &PAYER).unwrap();
+        assert_eq!(
+            info.tx_type(),
+            Some(&TransactionType::Memo { text: "hi".into() })
+        );
+    }
+
+    #[test]
+    fn skips_nonce_advance_for_tx_type() {
+        let nonce = [3; 32];
+        let mut m = vec![1, 0, 2, 4];
+        m.extend_from_slice(&PAYER);
+        m.extend_from_slice(&nonce);
+        m.extend_from_slice(&RECIPIENT);
+        m.extend_from_slice(&SYSTEM_PROGRAM_ID);
+        m.extend_from_slice(&BLOCKHASH);
+        m.push(2);
+        // Sysvar account omitted for brevity; only the nonce account is read
+        m.extend_from_slice(&[3, 2, 1, 0, 4]);
+        m.extend_from_slice(&SYSTEM_IX_ADVANCE_NONCE.to_le_bytes());
+        m.extend_from_slice(&[3, 2, 0, 2, 12]);
+        m.extend_from_slice(&SYSTEM_IX_TRANSFER.to_le_bytes());
+        m.extend_from_slice(&7u64.to_le_bytes());
+
+        let info = introspect_transaction(&m, &PAYER).unwrap();
+        assert_eq!(
+            info.instructions[0],
+            TransactionType::AdvanceNonce {
+                nonce_account: nonce
+            }
+        );
+        assert_eq!(
+            info.tx_type(),
+            Some(&TransactionType::SystemTransfer {
+                from: PAYER,
+                to: RECIPIENT,
+                lamports: 7
+            })
+        );
+    }
+
+    #[test]
+    fn compact_u16_encodings() {
+        let parse = |b: &[u8]| Cursor::new(b).compact_u16();
+        assert_eq!(parse(&[0x00]), Ok(0));
+        assert_eq!(parse(&[0x7f]), Ok(0x7f));
+        assert_eq!(parse(&[0x80, 0x01]), Ok(0x80));
+        assert_eq!(parse(&[0xff, 0xff, 0x03]), Ok(0xffff));
+        assert_eq!(parse(&[0x80, 0x00]), Err(ParseError::InvalidLength));
+        assert_eq!(parse(&[0xff, 0xff, 0x04]), Err(ParseError::InvalidLength));
+        assert_eq!(parse(&[0x80, 0x80, 0x80]), Err(ParseError::InvalidLength));
+        assert_eq!(parse(&[0x80]), Err(ParseError::Truncated));
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        let good = legacy_transfer(1);
+        for len in 0..good.len() {
+            assert!(parse_message(&good[..len]).is_err(), "prefix {}", len);
+        }
+
+        let mut trailing = good.clone();
+        trailing.push(0);
+        assert_eq!(parse_message(&trailing), Err(ParseError::TrailingBytes));
+
+        let mut bad_program = good.clone();
+        let ix_start = 4 + 3 * 32 + 32 + 1;
+        bad_program[ix_start] = 3;
+        assert_eq!(
+            parse_message(&bad_program),
+            Err(ParseError::AccountIndexOutOfRange)
+        );
+
+        let mut no_signers = good.clone();
+        no_signers[0] = 0;
+        assert_eq!(parse_message(&no_signers), Err(ParseError::InvalidHeader));
+
+        assert_eq!(
+            parse_message(&[0x81, 1, 0, 0]),
+            Err(ParseError::UnsupportedVersion(1))
+        );
+
+        // Huge account count must fail cleanly, not allocate
+        assert_eq!(
+            parse_message(&[1, 0, 0, 0xff, 0xff, 0x03]),
+            Err(ParseError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,712 @@
+//! The BLE profile: a compact binary form of the commands a phone needs,
+//! for firmware built with `ble`.
+//!
+//! One GATT service with three characteristics:
+//!
+//! - [`REQUEST_UUID`] (write): [`Request`]s from the host
+//! - [`REPLY_UUID`] (notify): one [`Reply`] per request, in order
+//! - [`STATUS_UUID`] (read, notify): the device's [`Status`], e.g. waiting
+//!   for the button with a summary of what it is about to sign
+//!
+//! A message starts with its opcode and carries raw bytes instead of the
+//! serial link's base64 or base58. Messages are longer than a BLE write or
+//! notification can be, so each travels as fragments of at most the ATT MTU
+//! less 3 bytes: a header byte, then a slice of the message. The header's low
+//! seven bits count the fragments of a message from 0 (wrapping), and its
+//! high bit marks the last one. [`fragments`] splits a message and a
+//! [`Reassembler`] puts it back together.
+//!
+//! Signing needs the paired host here as over the serial link: the phone
+//! pairs with `PAIR_BEGIN`/`PAIR_CONFIRM` (the code blinks on the LED) and
+//! proves itself with `AUTH_BEGIN`/`AUTH_PROOF` on each connection. With the
+//! `std` feature, [`client`] is a host-side implementation over any GATT
+//! stack.
+
+use core::str;
+
+use crate::error::ErrorCode;
+
+/// The signer's GATT service.
+pub const SERVICE_UUID: u128 = 0x5e1f_0001_6e8a_4c3b_9a5d_2f0e_51a9_5001;
+/// Requests, written by the host.
+pub const REQUEST_UUID: u128 = 0x5e1f_0002_6e8a_4c3b_9a5d_2f0e_51a9_5001;
+/// Replies, notified by the device.
+pub const REPLY_UUID: u128 = 0x5e1f_0003_6e8a_4c3b_9a5d_2f0e_51a9_5001;
+/// The device's status, read or notified.
+pub const STATUS_UUID: u128 = 0x5e1f_0004_6e8a_4c3b_9a5d_2f0e_51a9_5001;
+
+/// Longest message either side sends: a SIGN of the largest off-chain
+/// message the serial link carries, with its opcode.
+pub const MAX_MESSAGE_LEN: usize = 2048;
+/// Longest summary in [`Status::AwaitingButton`].
+pub const MAX_SUMMARY_LEN: usize = 240;
+/// The ATT MTU every connection starts with, before any exchange.
+pub const DEFAULT_MTU: usize = 23;
+
+const PUBKEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+const NONCE_LEN: usize = 16;
+
+/// Set in the header of a message's last fragment.
+const LAST_FRAGMENT: u8 = 0x80;
+const INDEX_MASK: u8 = 0x7f;
+
+mod opcode {
+    pub const PUBKEY: u8 = 0x01;
+    pub const SIGN: u8 = 0x02;
+    pub const PAIR_BEGIN: u8 = 0x10;
+    pub const PAIR_CONFIRM: u8 = 0x11;
+    pub const AUTH_BEGIN: u8 = 0x12;
+    pub const AUTH_PROOF: u8 = 0x13;
+    /// Replies carry their request's opcode with this bit set.
+    pub const REPLY: u8 = 0x80;
+    pub const ERROR: u8 = 0xff;
+
+    pub const STATUS_IDLE: u8 = 0x00;
+    pub const STATUS_AWAITING_BUTTON: u8 = 0x01;
+}
+
+/// A message from the host. Borrowed fields point into the received bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    Pubkey,
+    /// The message to sign, as `SIGN` takes it.
+    Sign(&'a [u8]),
+    /// The host's session public key.
+    PairBegin(&'a [u8; PUBKEY_LEN]),
+    PairConfirm,
+    /// The host's nonce.
+    AuthBegin(&'a [u8; NONCE_LEN]),
+    /// The host's signature over the device's nonce.
+    AuthProof(&'a [u8; SIGNATURE_LEN]),
+}
+
+impl<'a> Request<'a> {
+    /// `None` for an unknown opcode or a payload of the wrong size.
+    pub fn parse(message: &'a [u8]) -> Option<Self> {
+        let (&op, payload) = message.split_first()?;
+        let request = match op {
+            opcode::PUBKEY if payload.is_empty() => Request::Pubkey,
+            opcode::SIGN if !payload.is_empty() => Request::Sign(payload),
+            opcode::PAIR_BEGIN => Request::PairBegin(payload.try_into().ok()?),
+            opcode::PAIR_CONFIRM if payload.is_empty() => Request::PairConfirm,
+            opcode::AUTH_BEGIN => Request::AuthBegin(payload.try_into().ok()?),
+            opcode::AUTH_PROOF => Request::AuthProof(payload.try_into().ok()?),
+            _ => return None,
+        };
+        Some(request)
+    }
+
+    pub fn write(&self, out: &mut impl Extend<u8>) {
+        let (op, payload): (u8, &[u8]) = match self {
+            Request::Pubkey => (opcode::PUBKEY, &[]),
+            Request::Sign(message) => (opcode::SIGN, message),
+            Request::PairBegin(key) => (opcode::PAIR_BEGIN, *key),
+            Request::PairConfirm => (opcode::PAIR_CONFIRM, &[]),
+            Request::AuthBegin(nonce) => (opcode::AUTH_BEGIN, *nonce),
+            Request::AuthProof(signature) => (opcode::AUTH_PROOF, *signature),
+        };
+        out.extend([op]);
+        out.extend(payload.iter().copied());
+    }
+}
+
+/// The device's answer to a [`Request`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply<'a> {
+    Pubkey(&'a [u8; PUBKEY_LEN]),
+    Signature(&'a [u8; SIGNATURE_LEN]),
+    /// The device's session public key; the pairing code is blinking.
+    Pairing(&'a [u8; PUBKEY_LEN]),
+    Paired,
+    /// The device's nonce and its signature over the host's.
+    AuthChallenge {
+        nonce: &'a [u8; NONCE_LEN],
+        signature: &'a [u8; SIGNATURE_LEN],
+    },
+    Authenticated,
+    /// As the serial link's `ERROR:<code>[:<detail>]`.
+    Error(ErrorCode, Option<&'a str>),
+}
+
+impl<'a> Reply<'a> {
+    /// `None` for an unknown opcode or error code, or a payload of the wrong
+    /// size.
+    pub fn parse(message: &'a [u8]) -> Option<Self> {
+        let (&op, payload) = message.split_first()?;
+        if op == opcode::ERROR {
+            let text = str::from_utf8(payload).ok()?;
+            return Some(match text.split_once(':') {
+                Some((code, detail)) => Reply::Error(ErrorCode::parse(code)?, Some(detail)),
+                None => Reply::Error(ErrorCode::parse(text)?, None),
+            });
+        }
+        if op & opcode::REPLY == 0 {
+            return None;
+        }
+        let reply = match op & !opcode::REPLY {
+            opcode::PUBKEY => Reply::Pubkey(payload.try_into().ok()?),
+            opcode::SIGN => Reply::Signature(payload.try_into().ok()?),
+            opcode::PAIR_BEGIN => Reply::Pairing(payload.try_into().ok()?),
+            opcode::PAIR_CONFIRM if payload.is_empty() => Reply::Paired,
+            opcode::AUTH_BEGIN if payload.len() == NONCE_LEN + SIGNATURE_LEN => {
+                let (nonce, signature) = payload.split_at(NONCE_LEN);
+                Reply::AuthChallenge {
+                    nonce: nonce.try_into().ok()?,
+                    signature: signature.try_into().ok()?,
+                }
+            }
+            opcode::AUTH_PROOF if payload.is_empty() => Reply::Authenticated,
+            _ => return None,
+        };
+        Some(reply)
+    }
+
+    pub fn write(&self, out: &mut impl Extend<u8>) {
+        let reply = |op: u8| op | opcode::REPLY;
+        match self {
+            Reply::Pubkey(key) => {
+                out.extend([reply(opcode::PUBKEY)]);
+                out.extend(key.iter().copied());
+            }
+            Reply::Signature(signature) => {
+                out.extend([reply(opcode::SIGN)]);
+                out.extend(signature.iter().copied());
+            }
+            Reply::Pairing(key) => {
+                out.extend([reply(opcode::PAIR_BEGIN)]);
+                out.extend(key.iter().copied());
+            }
+            Reply::Paired => out.extend([reply(opcode::PAIR_CONFIRM)]),
+            Reply::AuthChallenge { nonce, signature } => {
+                out.extend([reply(opcode::AUTH_BEGIN)]);
+                out.extend(nonce.iter().copied());
+                out.extend(signature.iter().copied());
+            }
+            Reply::Authenticated => out.extend([reply(opcode::AUTH_PROOF)]),
+            Reply::Error(code, detail) => {
+                out.extend([opcode::ERROR]);
+                out.extend(code.as_str().bytes());
+                if let Some(detail) = detail {
+                    out.extend([b':']);
+                    out.extend(detail.bytes());
+                }
+            }
+        }
+    }
+}
+
+/// What the device is doing, on [`STATUS_UUID`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status<'a> {
+    Idle,
+    /// A signature waits for the BOOT button; the summary is the device's
+    /// own reading of the message, at most [`MAX_SUMMARY_LEN`] bytes.
+    AwaitingButton(&'a str),
+}
+
+impl<'a> Status<'a> {
+    pub fn parse(message: &'a [u8]) -> Option<Self> {
+        match message.split_first()? {
+            (&opcode::STATUS_IDLE, []) => Some(Status::Idle),
+            (&opcode::STATUS_AWAITING_BUTTON, summary) => {
+                Some(Status::AwaitingButton(str::from_utf8(summary).ok()?))
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes the summary cut to [`MAX_SUMMARY_LEN`] bytes, on a character
+    /// boundary.
+    pub fn write(&self, out: &mut impl Extend<u8>) {
+        match self {
+            Status::Idle => out.extend([opcode::STATUS_IDLE]),
+            Status::AwaitingButton(summary) => {
+                let mut end = summary.len().min(MAX_SUMMARY_LEN);
+                while !summary.is_char_boundary(end) {
+                    end -= 1;
+                }
+                out.extend([opcode::STATUS_AWAITING_BUTTON]);
+                out.extend(summary[..end].bytes());
+            }
+        }
+    }
+}
+
+/// Splits `message` into fragments for an ATT MTU of `mtu`; each is the
+/// header byte and a slice of the message.
+pub fn fragments(message: &[u8], mtu: usize) -> impl Iterator<Item = (u8, &[u8])> {
+    // ATT write and notification headers take 3 bytes, ours 1
+    let size = mtu.max(DEFAULT_MTU) - 4;
+    let count = message.len().div_ceil(size).max(1);
+    (0..count).map(move |i| {
+        let chunk = &message[(i * size).min(message.len())..((i + 1) * size).min(message.len())];
+        let mut header = (i as u8) & INDEX_MASK;
+        if i + 1 == count {
+            header |= LAST_FRAGMENT;
+        }
+        (header, chunk)
+    })
+}
+
+/// Why a fragment was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// Not the fragment that comes next; the partial message is dropped.
+    OutOfOrder,
+    /// The message would be longer than the buffer; it is dropped.
+    TooLong,
+    Empty,
+}
+
+/// Puts messages back together from their fragments, in a buffer of `N`
+/// bytes.
+pub struct Reassembler<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    /// The index the next fragment must carry, if a message is under way
+    next: Option<u8>,
+}
+
+impl<const N: usize> Default for Reassembler<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Reassembler<N> {
+    pub const fn new() -> Self {
+        Reassembler {
+            buffer: [0; N],
+            len: 0,
+            next: None,
+        }
+    }
+
+    /// Takes one fragment; returns the message once its last fragment is in.
+    /// A first fragment always starts a new message, so a sender that gave
+    /// up halfway is recovered from.
+    pub fn push(&mut self, fragment: &[u8]) -> Result<Option<&[u8]>, FrameError> {
+        let (&header, chunk) = fragment.split_first().ok_or(FrameError::Empty)?;
+        let index = header & INDEX_MASK;
+        if index == 0 {
+            self.len = 0;
+        } else if self.next != Some(index) {
+            self.next = None;
+            return Err(FrameError::OutOfOrder);
+        }
+        if self.len + chunk.len() > N {
+            self.next = None;
+            return Err(FrameError::TooLong);
+        }
+        self.buffer[self.len..self.len + chunk.len()].copy_from_slice(chunk);
+        self.len += chunk.len();
+        if header & LAST_FRAGMENT != 0 {
+            self.next = None;
+            return Ok(Some(&self.buffer[..self.len]));
+        }
+        self.next = Some((index + 1) & INDEX_MASK);
+        Ok(None)
+    }
+}
+
+/// A host-side client for the profile, over whatever GATT stack the platform
+/// has (CoreBluetooth, Android's BluetoothGatt, BlueZ...).
+#[cfg(any(test, feature = "std"))]
+pub mod client {
+    use std::fmt;
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    use super::{fragments, Reassembler, Reply, Request, Status, MAX_MESSAGE_LEN};
+    use super::{NONCE_LEN, PUBKEY_LEN, REPLY_UUID, REQUEST_UUID, SIGNATURE_LEN, STATUS_UUID};
+    use crate::error::ErrorCode;
+
+    /// A connection to the signer's service, with notifications on
+    /// [`REPLY_UUID`] and [`STATUS_UUID`] subscribed to
+    pub trait GattLink {
+        /// Writes `value` to the characteristic `uuid`, with response
+        fn write(&mut self, uuid: u128, value: &[u8]) -> io::Result<()>;
+        /// The next notification as the characteristic's UUID and value, or
+        /// `None` if there was none within `timeout`
+        fn notification(&mut self, timeout: Duration) -> io::Result<Option<(u128, Vec<u8>)>>;
+        /// The ATT MTU negotiated for the connection
+        fn mtu(&self) -> usize;
+    }
+
+    #[derive(Debug)]
+    pub enum BleError {
+        Io(io::Error),
+        /// No reply within the timeout
+        NoReply,
+        /// The device answered with an error
+        Device(ErrorCode, Option<String>),
+        /// A reply that doesn't answer the request, or doesn't parse
+        Unexpected,
+    }
+
+    impl fmt::Display for BleError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                BleError::Io(e) => write!(f, "BLE error: {}", e),
+                BleError::NoReply => write!(f, "no reply from the signer"),
+                BleError::Device(code, None) => write!(f, "the signer refused: {}", code),
+                BleError::Device(code, Some(detail)) => {
+                    write!(f, "the signer refused: {} ({})", code, detail)
+                }
+                BleError::Unexpected => write!(f, "unexpected reply from the signer"),
+            }
+        }
+    }
+
+    impl std::error::Error for BleError {}
+
+    impl From<io::Error> for BleError {
+        fn from(e: io::Error) -> Self {
+            BleError::Io(e)
+        }
+    }
+
+    /// How long a request waits for its reply; SIGN waits for a human
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+    const SIGN_TIMEOUT: Duration = Duration::from_secs(120);
+
+    pub struct Client<L> {
+        link: L,
+        replies: Box<Reassembler<MAX_MESSAGE_LEN>>,
+        statuses: Box<Reassembler<MAX_MESSAGE_LEN>>,
+    }
+
+    impl<L: GattLink> Client<L> {
+        pub fn new(link: L) -> Self {
+            Client {
+                link,
+                replies: Box::default(),
+                statuses: Box::default(),
+            }
+        }
+
+        pub fn pubkey(&mut self) -> Result<[u8; PUBKEY_LEN], BleError> {
+            match self.exchange(&Request::Pubkey, REPLY_TIMEOUT, &mut |_| {})? {
+                Answer::Pubkey(key) => Ok(key),
+                _ => Err(BleError::Unexpected),
+            }
+        }
+
+        /// Signs `message` once the button is pressed; `status` hears each
+        /// status change meanwhile, the summary to show included
+        pub fn sign(
+            &mut self,
+            message: &[u8],
+            status: &mut dyn FnMut(Status),
+        ) -> Result<[u8; SIGNATURE_LEN], BleError> {
+            match self.exchange(&Request::Sign(message), SIGN_TIMEOUT, status)? {
+                Answer::Signature(signature) => Ok(signature),
+                _ => Err(BleError::Unexpected),
+            }
+        }
+
+        /// Starts pairing as `host_key`; the device's session key comes back
+        /// and the pairing code blinks
+        pub fn pair_begin(
+            &mut self,
+            host_key: &[u8; PUBKEY_LEN],
+        ) -> Result<[u8; PUBKEY_LEN], BleError> {
+            match self.exchange(&Request::PairBegin(host_key), REPLY_TIMEOUT, &mut |_| {})? {
+                Answer::Pairing(key) => Ok(key),
+                _ => Err(BleError::Unexpected),
+            }
+        }
+
+        /// Completes pairing once the button is pressed
+        pub fn pair_confirm(&mut self) -> Result<(), BleError> {
+            match self.exchange(&Request::PairConfirm, SIGN_TIMEOUT, &mut |_| {})? {
+                Answer::Done => Ok(()),
+                _ => Err(BleError::Unexpected),
+            }
+        }
+
+        /// The device's nonce and its proof over `host_nonce`, for the
+        /// caller to check against the session key from pairing
+        pub fn auth_begin(
+            &mut self,
+            host_nonce: &[u8; NONCE_LEN],
+        ) -> Result<([u8; NONCE_LEN], [u8; SIGNATURE_LEN]), BleError> {
+            match self.exchange(&Request::AuthBegin(host_nonce), REPLY_TIMEOUT, &mut |_| {})? {
+                Answer::AuthChallenge(nonce, signature) => Ok((nonce, signature)),
+                _ => Err(BleError::Unexpected),
+            }
+        }
+
+        pub fn auth_proof(&mut self, signature: &[u8; SIGNATURE_LEN]) -> Result<(), BleError> {
+            match self.exchange(&Request::AuthProof(signature), REPLY_TIMEOUT, &mut |_| {})? {
+                Answer::Done => Ok(()),
+                _ => Err(BleError::Unexpected),
+            }
+        }
+
+        fn exchange(
+            &mut self,
+            request: &Request,
+            timeout: Duration,
+            status: &mut dyn FnMut(Status),
+        ) -> Result<Answer, BleError> {
+            let mut message = Vec::new();
+            request.write(&mut message);
+            let mtu = self.link.mtu();
+            for (header, chunk) in fragments(&message, mtu) {
+                let mut fragment = vec![header];
+                fragment.extend_from_slice(chunk);
+                self.link.write(REQUEST_UUID, &fragment)?;
+            }
+
+            let deadline = Instant::now() + timeout;
+            loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(BleError::NoReply);
+                }
+                let Some((uuid, value)) = self.link.notification(left)? else {
+                    continue;
+                };
+                if uuid == STATUS_UUID {
+                    if let Ok(Some(message)) = self.statuses.push(&value) {
+                        if let Some(update) = Status::parse(message) {
+                            status(update);
+                        }
+                    }
+                } else if uuid == REPLY_UUID {
+                    // A malformed fragment loses the reply; the timeout ends it
+                    if let Ok(Some(message)) = self.replies.push(&value) {
+                        return Answer::of(request, message);
+                    }
+                }
+            }
+        }
+    }
+
+    /// A reply, owned
+    enum Answer {
+        Pubkey([u8; PUBKEY_LEN]),
+        Signature([u8; SIGNATURE_LEN]),
+        Pairing([u8; PUBKEY_LEN]),
+        AuthChallenge([u8; NONCE_LEN], [u8; SIGNATURE_LEN]),
+        Done,
+    }
+
+    impl Answer {
+        fn of(request: &Request, message: &[u8]) -> Result<Answer, BleError> {
+            let answer = match (request, Reply::parse(message).ok_or(BleError::Unexpected)?) {
+                (_, Reply::Error(code, detail)) => {
+                    return Err(BleError::Device(code, detail.map(str::to_string)))
+                }
+                (Request::Pubkey, Reply::Pubkey(key)) => Answer::Pubkey(*key),
+                (Request::Sign(_), Reply::Signature(signature)) => Answer::Signature(*signature),
+                (Request::PairBegin(_), Reply::Pairing(key)) => Answer::Pairing(*key),
+                (Request::AuthBegin(_), Reply::AuthChallenge { nonce, signature }) => {
+                    Answer::AuthChallenge(*nonce, *signature)
+                }
+                (Request::PairConfirm, Reply::Paired)
+                | (Request::AuthProof(_), Reply::Authenticated) => Answer::Done,
+                _ => return Err(BleError::Unexpected),
+            };
+            Ok(answer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::client::{BleError, Client, GattLink};
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::time::Duration;
+
+    fn reassemble(message: &[u8], mtu: usize) -> Vec<u8> {
+        let mut reassembler = Reassembler::<MAX_MESSAGE_LEN>::new();
+        let mut result = None;
+        for (header, chunk) in fragments(message, mtu) {
+            assert!(chunk.len() < mtu.max(DEFAULT_MTU) - 3);
+            let mut fragment = vec![header];
+            fragment.extend_from_slice(chunk);
+            assert!(result.is_none(), "a fragment after the last");
+            result = reassembler.push(&fragment).unwrap().map(<[u8]>::to_vec);
+        }
+        result.expect("no last fragment")
+    }
+
+    #[test]
+    fn fragments_round_trip() {
+        for len in [0, 1, 19, 20, 500, MAX_MESSAGE_LEN] {
+            let message: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            for mtu in [DEFAULT_MTU, 185, 517] {
+                assert_eq!(
+                    reassemble(&message, mtu),
+                    message,
+                    "len {} mtu {}",
+                    len,
+                    mtu
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reassembler_recovers() {
+        let mut reassembler = Reassembler::<8>::new();
+        assert_eq!(reassembler.push(&[1, 9]), Err(FrameError::OutOfOrder));
+        assert_eq!(reassembler.push(&[0, 1, 2]), Ok(None));
+        // The sender gave up and started over
+        assert_eq!(reassembler.push(&[0, 3]), Ok(None));
+        assert_eq!(
+            reassembler.push(&[LAST_FRAGMENT | 1, 4]),
+            Ok(Some(&[3, 4][..]))
+        );
+        assert_eq!(
+            reassembler.push(&[LAST_FRAGMENT, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(FrameError::TooLong)
+        );
+        assert_eq!(reassembler.push(&[]), Err(FrameError::Empty));
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let key = [7u8; 32];
+        let nonce = [3u8; 16];
+        let signature = [9u8; 64];
+        for request in [
+            Request::Pubkey,
+            Request::Sign(b"message"),
+            Request::PairBegin(&key),
+            Request::PairConfirm,
+            Request::AuthBegin(&nonce),
+            Request::AuthProof(&signature),
+        ] {
+            let mut bytes = Vec::new();
+            request.write(&mut bytes);
+            assert_eq!(Request::parse(&bytes), Some(request));
+        }
+        for reply in [
+            Reply::Pubkey(&key),
+            Reply::Signature(&signature),
+            Reply::Pairing(&key),
+            Reply::Paired,
+            Reply::AuthChallenge {
+                nonce: &nonce,
+                signature: &signature,
+            },
+            Reply::Authenticated,
+            Reply::Error(ErrorCode::Locked, None),
+            Reply::Error(ErrorCode::Policy, Some("OVER_LIMIT")),
+        ] {
+            let mut bytes = Vec::new();
+            reply.write(&mut bytes);
+            assert_eq!(Reply::parse(&bytes), Some(reply));
+        }
+        for status in [Status::Idle, Status::AwaitingButton("SOL Transfer")] {
+            let mut bytes = Vec::new();
+            status.write(&mut bytes);
+            assert_eq!(Status::parse(&bytes), Some(status));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert_eq!(Request::parse(&[]), None);
+        assert_eq!(Request::parse(&[opcode::SIGN]), None);
+        assert_eq!(Request::parse(&[opcode::PUBKEY, 0]), None);
+        assert_eq!(Request::parse(&[opcode::PAIR_BEGIN, 1, 2]), None);
+        assert_eq!(Request::parse(&[0x42]), None);
+        // A request's opcode is not a reply
+        assert_eq!(Reply::parse(&[opcode::PAIR_CONFIRM]), None);
+        assert_eq!(Reply::parse(b"\xffNOT_A_CODE"), None);
+    }
+
+    #[test]
+    fn cuts_long_summaries() {
+        let summary = "é".repeat(MAX_SUMMARY_LEN);
+        let mut bytes = Vec::new();
+        Status::AwaitingButton(&summary).write(&mut bytes);
+        let Some(Status::AwaitingButton(cut)) = Status::parse(&bytes) else {
+            panic!("summary doesn't parse");
+        };
+        assert_eq!(cut.len(), MAX_SUMMARY_LEN);
+    }
+
+    /// Answers each request with the notifications queued for it
+    struct FakeLink {
+        written: Vec<Vec<u8>>,
+        notifications: VecDeque<(u128, Vec<u8>)>,
+    }
+
+    impl GattLink for FakeLink {
+        fn write(&mut self, uuid: u128, value: &[u8]) -> io::Result<()> {
+            assert_eq!(uuid, REQUEST_UUID);
+            self.written.push(value.to_vec());
+            Ok(())
+        }
+
+        fn notification(&mut self, _: Duration) -> io::Result<Option<(u128, Vec<u8>)>> {
+            Ok(self.notifications.pop_front())
+        }
+
+        fn mtu(&self) -> usize {
+            DEFAULT_MTU
+        }
+    }
+
+    fn notifications(uuid: u128, message: &[u8]) -> Vec<(u128, Vec<u8>)> {
+        fragments(message, DEFAULT_MTU)
+            .map(|(header, chunk)| {
+                let mut fragment = vec![header];
+                fragment.extend_from_slice(chunk);
+                (uuid, fragment)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn client_signs_with_status_updates() {
+        let signature = [5u8; 64];
+        let mut queued = Vec::new();
+        let mut status = Vec::new();
+        Status::AwaitingButton("SOL Transfer: A -> B 1.000000000 SOL").write(&mut status);
+        queued.extend(notifications(STATUS_UUID, &status));
+        let mut reply = Vec::new();
+        Reply::Signature(&signature).write(&mut reply);
+        queued.extend(notifications(REPLY_UUID, &reply));
+
+        let link = FakeLink {
+            written: Vec::new(),
+            notifications: queued.into(),
+        };
+        let mut client = Client::new(link);
+        let mut summaries = Vec::new();
+        let message = [1u8; 100];
+        let signed = client
+            .sign(&message, &mut |status| {
+                if let Status::AwaitingButton(summary) = status {
+                    summaries.push(summary.to_string());
+                }
+            })
+            .unwrap();
+        assert_eq!(signed, signature);
+        assert_eq!(summaries, ["SOL Transfer: A -> B 1.000000000 SOL"]);
+    }
+
+    #[test]
+    fn client_reports_refusals() {
+        let mut reply = Vec::new();
+        Reply::Error(ErrorCode::Unauthenticated, None).write(&mut reply);
+        let link = FakeLink {
+            written: Vec::new(),
+            notifications: notifications(REPLY_UUID, &reply).into(),
+        };
+        let mut client = Client::new(link);
+        assert!(matches!(
+            client.pubkey(),
+            Err(BleError::Device(ErrorCode::Unauthenticated, None))
+        ));
+    }
+}
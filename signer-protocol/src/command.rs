@@ -0,0 +1,507 @@
+//! Newline-terminated serial protocol commands: parsed by the firmware,
+//! formatted (`Display`) by host tools.
+
+use core::fmt;
+
+/// One protocol line, parsed. Borrowed fields point into the input line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    /// Liveness check; answered with `PONG` whatever the device's state.
+    Ping,
+    GetPubkey,
+    GetInfo,
+    GetDeviceId,
+    GetFeatures,
+    /// Write-once serial number.
+    Provision(&'a str),
+    Diag,
+    Benchmark,
+    Telemetry,
+    CreateTx,
+    TxInfo,
+    LogLevel(&'a str),
+    /// Policy text (parsed by signer-core's `Policy::parse`).
+    SetPolicy(&'a str),
+    PolicyRollback,
+    /// The policy in effect, so hosts can check a transaction before sending it.
+    GetPolicy,
+    /// The loosening policy change waiting out its delay, if any.
+    GetPendingPolicy,
+    OtpBegin,
+    OtpConfirm {
+        code: &'a str,
+        unix: Option<u64>,
+    },
+    OtpUnlock {
+        code: &'a str,
+        unix: Option<u64>,
+    },
+    /// Whether 2FA is enrolled and until when signing is unlocked.
+    OtpStatus,
+    /// Encoded message to sign (decoded by the caller).
+    Sign(&'a str),
+    /// [`Command::Sign`] with token labels (`signer_core::token_labels`
+    /// wire form, possibly empty) for the transfers the device describes.
+    SignTx {
+        message: &'a str,
+        tokens: &'a str,
+    },
+    OtaBegin {
+        size: u32,
+    },
+    /// `<offset>:<data>` (parsed by the OTA session).
+    OtaChunk(&'a str),
+    /// Encoded vendor signature over the uploaded image.
+    OtaEnd(&'a str),
+    OtaAbort,
+    /// Host session public key; the device answers with its own and blinks
+    /// the pairing code.
+    PairBegin(&'a str),
+    /// Stores the pending host once BOOT is pressed.
+    PairConfirm,
+    /// Forgets the paired host once BOOT is pressed.
+    Unpair,
+    /// Encoded host nonce; the device answers with its nonce and proof.
+    AuthBegin(&'a str),
+    /// Encoded host signature over the device's nonce.
+    AuthProof(&'a str),
+    /// Writes an encrypted backup of the seed and policy to the SD card once
+    /// BOOT is pressed. The passphrase is the rest of the line.
+    BackupToSd {
+        pin: &'a str,
+        passphrase: &'a str,
+    },
+    /// Replaces the seed and policy with the newest backup on the SD card
+    /// once BOOT is pressed, then restarts.
+    RestoreFromSd {
+        pin: &'a str,
+        passphrase: &'a str,
+    },
+    /// Registers the auditor's X25519 public key, which exports are sealed
+    /// to, once BOOT is pressed.
+    AuditRegister(&'a str),
+    /// The audit log, signed and sealed to the registered auditor.
+    AuditExport,
+    Shutdown,
+    /// Blank line, e.g. the light-sleep wake preamble. Ignored.
+    Empty,
+    /// A known command with unusable arguments. Only produced by parsing.
+    Malformed(&'static str),
+    /// Only produced by parsing.
+    Unknown(&'a str),
+}
+
+impl<'a> Command<'a> {
+    pub fn parse(line: &'a str) -> Self {
+        let line = line.trim();
+        match line {
+            "" => return Command::Empty,
+            "PING" => return Command::Ping,
+            "GET_PUBKEY" => return Command::GetPubkey,
+            "GET_INFO" => return Command::GetInfo,
+            "GET_DEVICE_ID" => return Command::GetDeviceId,
+            "GET_FEATURES" => return Command::GetFeatures,
+            "DIAG" => return Command::Diag,
+            "BENCHMARK" => return Command::Benchmark,
+            "TELEMETRY" => return Command::Telemetry,
+            "CREATE_TX" => return Command::CreateTx,
+            "TX_INFO" => return Command::TxInfo,
+            "OTP_BEGIN" => return Command::OtpBegin,
+            "OTP_STATUS" => return Command::OtpStatus,
+            "OTA_ABORT" => return Command::OtaAbort,
+            "POLICY_ROLLBACK" => return Command::PolicyRollback,
+            "GET_POLICY" => return Command::GetPolicy,
+            "GET_PENDING_POLICY" => return Command::GetPendingPolicy,
+            "PAIR_CONFIRM" => return Command::PairConfirm,
+            "UNPAIR" => return Command::Unpair,
+            "AUDIT_EXPORT" => return Command::AuditExport,
+            "SHUTDOWN" => return Command::Shutdown,
+            _ => {}
+        }
+
+        let Some((name, args)) = line.split_once(':') else {
+            return Command::Unknown(line);
+        };
+        match name {
+            "LOG_LEVEL" => Command::LogLevel(args),
+            "PROVISION" => Command::Provision(args),
+            "SET_POLICY" => Command::SetPolicy(args),
+            "OTP_CONFIRM" => {
+                let (code, unix) = parse_otp_args(args);
+                Command::OtpConfirm { code, unix }
+            }
+            "OTP_UNLOCK" => {
+                let (code, unix) = parse_otp_args(args);
+                Command::OtpUnlock { code, unix }
+            }
+            "SIGN" => Command::Sign(args),
+            "SIGN_TX" => match args.split_once(';') {
+                None => Command::SignTx {
+                    message: args,
+                    tokens: "",
+                },
+                Some((message, rest)) => match rest.strip_prefix("tokens=") {
+                    Some(tokens) => Command::SignTx { message, tokens },
+                    None => Command::Malformed("SIGN_TX"),
+                },
+            },
+            "OTA_BEGIN" => match args.parse() {
+                Ok(size) => Command::OtaBegin { size },
+                Err(_) => Command::Malformed("OTA_BEGIN"),
+            },
+            "OTA_CHUNK" => Command::OtaChunk(args),
+            "OTA_END" => Command::OtaEnd(args),
+            "PAIR_BEGIN" => Command::PairBegin(args),
+            "AUTH_BEGIN" => Command::AuthBegin(args),
+            "AUTH_PROOF" => Command::AuthProof(args),
+            "AUDIT_REGISTER" => Command::AuditRegister(args),
+            "BACKUP_TO_SD" => match args.split_once(':') {
+                Some((pin, passphrase)) => Command::BackupToSd { pin, passphrase },
+                None => Command::Malformed("BACKUP_TO_SD"),
+            },
+            "RESTORE_FROM_SD" => match args.split_once(':') {
+                Some((pin, passphrase)) => Command::RestoreFromSd { pin, passphrase },
+                None => Command::Malformed("RESTORE_FROM_SD"),
+            },
+            _ => Command::Unknown(line),
+        }
+    }
+}
+
+impl Command<'_> {
+    /// The command's name without its arguments, e.g. for logs, which must
+    /// not carry OTP codes
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Ping => "PING",
+            Command::GetPubkey => "GET_PUBKEY",
+            Command::GetInfo => "GET_INFO",
+            Command::GetDeviceId => "GET_DEVICE_ID",
+            Command::GetFeatures => "GET_FEATURES",
+            Command::Provision(_) => "PROVISION",
+            Command::Diag => "DIAG",
+            Command::Benchmark => "BENCHMARK",
+            Command::Telemetry => "TELEMETRY",
+            Command::CreateTx => "CREATE_TX",
+            Command::TxInfo => "TX_INFO",
+            Command::LogLevel(_) => "LOG_LEVEL",
+            Command::SetPolicy(_) => "SET_POLICY",
+            Command::PolicyRollback => "POLICY_ROLLBACK",
+            Command::GetPolicy => "GET_POLICY",
+            Command::GetPendingPolicy => "GET_PENDING_POLICY",
+            Command::OtpBegin => "OTP_BEGIN",
+            Command::OtpConfirm { .. } => "OTP_CONFIRM",
+            Command::OtpUnlock { .. } => "OTP_UNLOCK",
+            Command::OtpStatus => "OTP_STATUS",
+            Command::Sign(_) => "SIGN",
+            Command::SignTx { .. } => "SIGN_TX",
+            Command::OtaBegin { .. } => "OTA_BEGIN",
+            Command::OtaChunk(_) => "OTA_CHUNK",
+            Command::OtaEnd(_) => "OTA_END",
+            Command::OtaAbort => "OTA_ABORT",
+            Command::PairBegin(_) => "PAIR_BEGIN",
+            Command::PairConfirm => "PAIR_CONFIRM",
+            Command::Unpair => "UNPAIR",
+            Command::AuthBegin(_) => "AUTH_BEGIN",
+            Command::AuthProof(_) => "AUTH_PROOF",
+            Command::BackupToSd { .. } => "BACKUP_TO_SD",
+            Command::RestoreFromSd { .. } => "RESTORE_FROM_SD",
+            Command::AuditRegister(_) => "AUDIT_REGISTER",
+            Command::AuditExport => "AUDIT_EXPORT",
+            Command::Shutdown => "SHUTDOWN",
+            Command::Empty => "",
+            Command::Malformed(name) => name,
+            Command::Unknown(_) => "UNKNOWN",
+        }
+    }
+
+    /// Whether sending it twice does no more than sending it once, so a lost
+    /// reply can be recovered by asking again
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Command::Ping
+                | Command::GetPubkey
+                | Command::GetInfo
+                | Command::GetDeviceId
+                | Command::GetFeatures
+                | Command::Diag
+                | Command::Benchmark
+                | Command::Telemetry
+                | Command::TxInfo
+                | Command::GetPolicy
+                | Command::GetPendingPolicy
+                | Command::OtpStatus
+                | Command::Empty
+        )
+    }
+}
+
+/// The command line, without the trailing newline
+impl fmt::Display for Command<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Ping => f.write_str("PING"),
+            Command::GetPubkey => f.write_str("GET_PUBKEY"),
+            Command::GetInfo => f.write_str("GET_INFO"),
+            Command::GetDeviceId => f.write_str("GET_DEVICE_ID"),
+            Command::GetFeatures => f.write_str("GET_FEATURES"),
+            Command::Provision(serial) => write!(f, "PROVISION:{}", serial),
+            Command::Diag => f.write_str("DIAG"),
+            Command::Benchmark => f.write_str("BENCHMARK"),
+            Command::Telemetry => f.write_str("TELEMETRY"),
+            Command::CreateTx => f.write_str("CREATE_TX"),
+            Command::TxInfo => f.write_str("TX_INFO"),
+            Command::LogLevel(level) => write!(f, "LOG_LEVEL:{}", level),
+            Command::SetPolicy(spec) => write!(f, "SET_POLICY:{}", spec),
+            Command::PolicyRollback => f.write_str("POLICY_ROLLBACK"),
+            Command::GetPolicy => f.write_str("GET_POLICY"),
+            Command::GetPendingPolicy => f.write_str("GET_PENDING_POLICY"),
+            Command::OtpBegin => f.write_str("OTP_BEGIN"),
+            Command::OtpConfirm { code, unix } => write_otp(f, "OTP_CONFIRM", code, *unix),
+            Command::OtpUnlock { code, unix } => write_otp(f, "OTP_UNLOCK", code, *unix),
+            Command::OtpStatus => f.write_str("OTP_STATUS"),
+            Command::Sign(message) => write!(f, "SIGN:{}", message),
+            Command::SignTx { message, tokens } => {
+                write!(f, "SIGN_TX:{};tokens={}", message, tokens)
+            }
+            Command::OtaBegin { size } => write!(f, "OTA_BEGIN:{}", size),
+            Command::OtaChunk(args) => write!(f, "OTA_CHUNK:{}", args),
+            Command::OtaEnd(signature) => write!(f, "OTA_END:{}", signature),
+            Command::OtaAbort => f.write_str("OTA_ABORT"),
+            Command::PairBegin(key) => write!(f, "PAIR_BEGIN:{}", key),
+            Command::PairConfirm => f.write_str("PAIR_CONFIRM"),
+            Command::Unpair => f.write_str("UNPAIR"),
+            Command::AuthBegin(nonce) => write!(f, "AUTH_BEGIN:{}", nonce),
+            Command::AuthProof(signature) => write!(f, "AUTH_PROOF:{}", signature),
+            Command::BackupToSd { pin, passphrase } => {
+                write!(f, "BACKUP_TO_SD:{}:{}", pin, passphrase)
+            }
+            Command::RestoreFromSd { pin, passphrase } => {
+                write!(f, "RESTORE_FROM_SD:{}:{}", pin, passphrase)
+            }
+            Command::AuditRegister(key) => write!(f, "AUDIT_REGISTER:{}", key),
+            Command::AuditExport => f.write_str("AUDIT_EXPORT"),
+            Command::Shutdown => f.write_str("SHUTDOWN"),
+            Command::Empty => Ok(()),
+            Command::Malformed(name) => f.write_str(name),
+            Command::Unknown(line) => f.write_str(line),
+        }
+    }
+}
+
+fn write_otp(f: &mut fmt::Formatter<'_>, name: &str, code: &str, unix: Option<u64>) -> fmt::Result {
+    write!(f, "{}:{}", name, code)?;
+    match unix {
+        Some(unix) => write!(f, ":{}", unix),
+        None => Ok(()),
+    }
+}
+
+/// `CODE[:UNIX]` — an unparseable timestamp falls back to the device clock.
+fn parse_otp_args(args: &str) -> (&str, Option<u64>) {
+    let mut parts = args.split(':');
+    let code = parts.next().unwrap_or("");
+    let unix = parts.next().and_then(|s| s.parse::<u64>().ok());
+    (code, unix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_commands() {
+        assert_eq!(Command::parse("GET_PUBKEY"), Command::GetPubkey);
+        assert_eq!(Command::parse("PING"), Command::Ping);
+        assert_eq!(Command::parse("  TX_INFO \r"), Command::TxInfo);
+        assert_eq!(Command::parse("SHUTDOWN"), Command::Shutdown);
+        assert_eq!(Command::parse("TELEMETRY"), Command::Telemetry);
+        assert_eq!(Command::parse("BENCHMARK"), Command::Benchmark);
+        assert_eq!(Command::parse("GET_FEATURES"), Command::GetFeatures);
+        assert_eq!(Command::parse("POLICY_ROLLBACK"), Command::PolicyRollback);
+        assert_eq!(Command::parse("GET_POLICY"), Command::GetPolicy);
+        assert_eq!(
+            Command::parse("GET_PENDING_POLICY"),
+            Command::GetPendingPolicy
+        );
+        assert_eq!(Command::parse("OTP_STATUS"), Command::OtpStatus);
+        assert_eq!(Command::parse("PAIR_CONFIRM"), Command::PairConfirm);
+        assert_eq!(Command::parse("UNPAIR"), Command::Unpair);
+        assert_eq!(Command::parse("AUDIT_EXPORT"), Command::AuditExport);
+        assert_eq!(Command::parse(""), Command::Empty);
+        assert_eq!(Command::parse("\r"), Command::Empty);
+    }
+
+    #[test]
+    fn commands_with_arguments() {
+        assert_eq!(Command::parse("SIGN:AQID"), Command::Sign("AQID"));
+        assert_eq!(
+            Command::parse("SIGN_TX:AQID;tokens=0011223344556677:USDC:6"),
+            Command::SignTx {
+                message: "AQID",
+                tokens: "0011223344556677:USDC:6"
+            }
+        );
+        assert_eq!(
+            Command::parse("SIGN_TX:AQID"),
+            Command::SignTx {
+                message: "AQID",
+                tokens: ""
+            }
+        );
+        assert_eq!(
+            Command::parse("SIGN_TX:AQID;labels=x"),
+            Command::Malformed("SIGN_TX")
+        );
+        assert_eq!(Command::parse("GET_DEVICE_ID"), Command::GetDeviceId);
+        assert_eq!(
+            Command::parse("PROVISION:SN-0001"),
+            Command::Provision("SN-0001")
+        );
+        assert_eq!(
+            Command::parse("LOG_LEVEL:debug"),
+            Command::LogLevel("debug")
+        );
+        assert_eq!(
+            Command::parse("SET_POLICY:max=1000;blind=off"),
+            Command::SetPolicy("max=1000;blind=off")
+        );
+        assert_eq!(
+            Command::parse("OTA_BEGIN:4096"),
+            Command::OtaBegin { size: 4096 }
+        );
+        assert_eq!(
+            Command::parse("OTA_CHUNK:0:AAAA"),
+            Command::OtaChunk("0:AAAA")
+        );
+    }
+
+    #[test]
+    fn otp_arguments() {
+        assert_eq!(
+            Command::parse("OTP_CONFIRM:123456:1700000000"),
+            Command::OtpConfirm {
+                code: "123456",
+                unix: Some(1_700_000_000)
+            }
+        );
+        assert_eq!(
+            Command::parse("OTP_UNLOCK:123456"),
+            Command::OtpUnlock {
+                code: "123456",
+                unix: None
+            }
+        );
+        assert_eq!(
+            Command::parse("OTP_UNLOCK:123456:notanumber"),
+            Command::OtpUnlock {
+                code: "123456",
+                unix: None
+            }
+        );
+    }
+
+    #[test]
+    fn formatted_commands_parse_back() {
+        let commands = [
+            Command::GetPubkey,
+            Command::GetInfo,
+            Command::Provision("SN-0001"),
+            Command::SetPolicy("max=1000;blind=off"),
+            Command::PolicyRollback,
+            Command::GetPolicy,
+            Command::GetPendingPolicy,
+            Command::OtpConfirm {
+                code: "123456",
+                unix: Some(1_700_000_000),
+            },
+            Command::OtpUnlock {
+                code: "123456",
+                unix: None,
+            },
+            Command::OtpStatus,
+            Command::Sign("AQID"),
+            Command::SignTx {
+                message: "AQID",
+                tokens: "0011223344556677:USDC:6,8899aabbccddeeff:wSOL:9",
+            },
+            Command::OtaBegin { size: 4096 },
+            Command::OtaChunk("0:AAAA"),
+            Command::OtaEnd("c2ln"),
+            Command::PairBegin("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T"),
+            Command::PairConfirm,
+            Command::Unpair,
+            Command::AuthBegin("AAECAwQFBgcICQoLDA0ODw=="),
+            Command::AuthProof("c2ln"),
+            Command::BackupToSd {
+                pin: "1234",
+                passphrase: "correct horse: battery",
+            },
+            Command::RestoreFromSd {
+                pin: "1234",
+                passphrase: "correct horse: battery",
+            },
+            Command::AuditRegister("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T"),
+            Command::AuditExport,
+            Command::Shutdown,
+            Command::Ping,
+            Command::Empty,
+        ];
+        for command in commands {
+            assert_eq!(Command::parse(&command.to_string()), command);
+        }
+        assert_eq!(
+            Command::OtpUnlock {
+                code: "123456",
+                unix: Some(1_700_000_000)
+            }
+            .to_string(),
+            "OTP_UNLOCK:123456:1700000000"
+        );
+    }
+
+    #[test]
+    fn names_leave_out_arguments() {
+        assert_eq!(Command::GetPubkey.name(), "GET_PUBKEY");
+        let unlock = Command::OtpUnlock {
+            code: "123456",
+            unix: Some(1),
+        };
+        assert_eq!(unlock.name(), "OTP_UNLOCK");
+        assert!(unlock.to_string().starts_with(unlock.name()));
+    }
+
+    #[test]
+    fn only_queries_are_idempotent() {
+        assert!(Command::Ping.is_idempotent());
+        assert!(Command::GetPubkey.is_idempotent());
+        assert!(Command::GetInfo.is_idempotent());
+        assert!(Command::GetPolicy.is_idempotent());
+        assert!(!Command::Sign("AQID").is_idempotent());
+        assert!(!Command::OtpBegin.is_idempotent());
+        assert!(!Command::OtaChunk("0:AAAA").is_idempotent());
+        assert!(!Command::AuthBegin("AAAA").is_idempotent());
+        assert!(!Command::RestoreFromSd {
+            pin: "1234",
+            passphrase: "x"
+        }
+        .is_idempotent());
+    }
+
+    #[test]
+    fn malformed_and_unknown() {
+        assert_eq!(
+            Command::parse("OTA_BEGIN:-1"),
+            Command::Malformed("OTA_BEGIN")
+        );
+        assert_eq!(
+            Command::parse("BACKUP_TO_SD:1234"),
+            Command::Malformed("BACKUP_TO_SD")
+        );
+        assert_eq!(Command::parse("FOO"), Command::Unknown("FOO"));
+        assert_eq!(Command::parse("FOO:BAR"), Command::Unknown("FOO:BAR"));
+        // Prefix matches must be exact, not substring
+        assert_eq!(Command::parse("SIGNX:AA"), Command::Unknown("SIGNX:AA"));
+    }
+}
@@ -0,0 +1,162 @@
+//! Error codes, the `<code>` of `ERROR:<code>` and `ERROR:<code>:<detail>`.
+
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Not a command this firmware knows.
+    UnknownCommand,
+    /// A known command with unusable arguments; the detail names it.
+    BadArguments,
+    /// The command queue was full and the line was dropped.
+    Busy,
+    LineTooLong,
+    /// The RNG failed its health tests; nothing was generated.
+    Entropy,
+    /// Binary data not in this build's encoding; the detail names the one
+    /// expected.
+    Encoding,
+    Provision,
+    LogLevel,
+    SetPolicy,
+    PolicyRollback,
+    /// Refused by the signing policy (detail: why), or no policy is loaded
+    /// (detail: `UNAVAILABLE`).
+    Policy,
+    CreateTx,
+    /// 2FA is enrolled and the signing window is closed.
+    Locked,
+    OtpBegin,
+    OtpBadCode,
+    /// Firmware built without `twofa`.
+    OtpDisabled,
+    OtaBegin,
+    OtaChunk,
+    OtaVerify,
+    OtaActivate,
+    /// Firmware built without `ota`.
+    OtaDisabled,
+    /// The command needs a paired host and none is.
+    NotPaired,
+    /// A host is paired, but this connection hasn't proven it is that host.
+    Unauthenticated,
+    /// Pairing or unpairing failed; the detail says why.
+    Pair,
+    /// The host's proof didn't verify, or there was no challenge to answer.
+    Auth,
+    /// No backup was written; the detail says why (e.g. `NO_CARD`).
+    Backup,
+    /// Nothing was restored; the detail says why (e.g. `NO_BACKUP`,
+    /// `BAD_CREDENTIALS`).
+    Restore,
+    /// Firmware built without `sd-backup`.
+    SdDisabled,
+    /// The policy is in production mode and flash encryption or secure boot
+    /// is off, so the key isn't used.
+    UnprotectedDevice,
+    /// No audit export; the detail says why (e.g. `NO_AUDITOR`).
+    Audit,
+    /// Firmware built without `audit`.
+    AuditDisabled,
+    /// `SIGN_TX` token labels that don't fit the message; the detail says
+    /// why (e.g. `UNKNOWN_MINT`).
+    Labels,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 32] = [
+        ErrorCode::UnknownCommand,
+        ErrorCode::BadArguments,
+        ErrorCode::Busy,
+        ErrorCode::LineTooLong,
+        ErrorCode::Entropy,
+        ErrorCode::Encoding,
+        ErrorCode::Provision,
+        ErrorCode::LogLevel,
+        ErrorCode::SetPolicy,
+        ErrorCode::PolicyRollback,
+        ErrorCode::Policy,
+        ErrorCode::CreateTx,
+        ErrorCode::Locked,
+        ErrorCode::OtpBegin,
+        ErrorCode::OtpBadCode,
+        ErrorCode::OtpDisabled,
+        ErrorCode::OtaBegin,
+        ErrorCode::OtaChunk,
+        ErrorCode::OtaVerify,
+        ErrorCode::OtaActivate,
+        ErrorCode::OtaDisabled,
+        ErrorCode::NotPaired,
+        ErrorCode::Unauthenticated,
+        ErrorCode::Pair,
+        ErrorCode::Auth,
+        ErrorCode::Backup,
+        ErrorCode::Restore,
+        ErrorCode::SdDisabled,
+        ErrorCode::UnprotectedDevice,
+        ErrorCode::Audit,
+        ErrorCode::AuditDisabled,
+        ErrorCode::Labels,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::UnknownCommand => "UNKNOWN_COMMAND",
+            ErrorCode::BadArguments => "BAD_ARGUMENTS",
+            ErrorCode::Busy => "BUSY",
+            ErrorCode::LineTooLong => "LINE_TOO_LONG",
+            ErrorCode::Entropy => "ENTROPY",
+            ErrorCode::Encoding => "ENCODING",
+            ErrorCode::Provision => "PROVISION",
+            ErrorCode::LogLevel => "LOG_LEVEL",
+            ErrorCode::SetPolicy => "SET_POLICY",
+            ErrorCode::PolicyRollback => "POLICY_ROLLBACK",
+            ErrorCode::Policy => "POLICY",
+            ErrorCode::CreateTx => "CREATE_TX",
+            ErrorCode::Locked => "LOCKED",
+            ErrorCode::OtpBegin => "OTP_BEGIN",
+            ErrorCode::OtpBadCode => "OTP_BAD_CODE",
+            ErrorCode::OtpDisabled => "OTP_DISABLED",
+            ErrorCode::OtaBegin => "OTA_BEGIN",
+            ErrorCode::OtaChunk => "OTA_CHUNK",
+            ErrorCode::OtaVerify => "OTA_VERIFY",
+            ErrorCode::OtaActivate => "OTA_ACTIVATE",
+            ErrorCode::OtaDisabled => "OTA_DISABLED",
+            ErrorCode::NotPaired => "NOT_PAIRED",
+            ErrorCode::Unauthenticated => "UNAUTHENTICATED",
+            ErrorCode::Pair => "PAIR",
+            ErrorCode::Auth => "AUTH",
+            ErrorCode::Backup => "BACKUP",
+            ErrorCode::Restore => "RESTORE",
+            ErrorCode::SdDisabled => "SD_DISABLED",
+            ErrorCode::UnprotectedDevice => "UNPROTECTED_DEVICE",
+            ErrorCode::Audit => "AUDIT",
+            ErrorCode::AuditDisabled => "AUDIT_DISABLED",
+            ErrorCode::Labels => "LABELS",
+        }
+    }
+
+    /// `None` for codes this build doesn't know, e.g. from newer firmware.
+    pub fn parse(code: &str) -> Option<Self> {
+        ErrorCode::ALL.into_iter().find(|c| c.as_str() == code)
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip() {
+        for code in ErrorCode::ALL {
+            assert_eq!(ErrorCode::parse(code.as_str()), Some(code));
+        }
+        assert_eq!(ErrorCode::parse("Unknown command"), None);
+    }
+}
@@ -0,0 +1,22 @@
+//! The ESP32 Solana signer's serial protocol: one command line from the host,
+//! one response line back.
+//!
+//! The firmware parses [`command::Command`]s and formats
+//! [`response::Response`]s; the host tools format commands and parse
+//! responses with the same types, so a protocol change that one side doesn't
+//! follow fails to compile instead of failing on the wire. With the `std`
+//! feature, [`transport`] runs exchanges over a serial port for the host.
+//! [`ble`] is the compact binary profile of the same commands for phones.
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+/// Reported as `proto=` in `INFO`. Bumped whenever a command or response
+/// changes in a way a peer built against the previous version would misread;
+/// firmware from before this field existed counts as version 0.
+pub const VERSION: u32 = 2;
+
+pub mod ble;
+pub mod command;
+pub mod error;
+pub mod response;
+#[cfg(any(test, feature = "std"))]
+pub mod transport;
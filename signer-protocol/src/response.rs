@@ -0,0 +1,369 @@
+//! Protocol response lines (without the trailing newline): formatted by the
+//! firmware, parsed by host tools.
+
+use core::fmt;
+
+use crate::error::ErrorCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response<'a> {
+    /// The answer to `PING`.
+    Pong,
+    Pubkey(&'a str),
+    DeviceId {
+        id: &'a str,
+        serial: Option<&'a str>,
+    },
+    Provisioned(&'a str),
+    /// Base64 Ed25519 signature.
+    Signature(&'a str),
+    /// Base64 signed transaction.
+    Transaction(&'a str),
+    OtpSecret {
+        secret: &'a str,
+        digits: u32,
+        period: u64,
+    },
+    OtpConfirmed,
+    UnlockedUntil(u64),
+    /// `enrolled=<yes|no>;unlocked_until=<unix>;now=<unix>`, with the
+    /// device's clock as `now`.
+    OtpStatus(&'a str),
+    OtaReady {
+        chunk_max: usize,
+    },
+    OtaAck {
+        next: u32,
+    },
+    OtaOk,
+    OtaAborted,
+    LogLevelOk(&'a str),
+    /// The policy now in effect, as `Policy` displays it.
+    Policy(&'a str),
+    /// A loosening change held back until `remaining_secs` more seconds of
+    /// powered-on time have passed; `POLICY_PENDING:remaining=<secs>;<policy>`.
+    PolicyPending {
+        remaining_secs: u32,
+        policy: &'a str,
+    },
+    NoPendingPolicy,
+    ShutdownOk,
+    /// The device's session public key; the pairing code is blinking.
+    Pairing(&'a str),
+    Paired,
+    Unpaired,
+    /// The device's nonce and its signature over the host's.
+    AuthChallenge {
+        nonce: &'a str,
+        signature: &'a str,
+    },
+    Authenticated,
+    /// The name of the backup file written to the SD card.
+    BackedUp(&'a str),
+    /// The public key of the restored seed; the device restarts next.
+    Restored(&'a str),
+    AuditRegistered,
+    /// An audit log export sealed to the registered auditor, encoded.
+    Audit(&'a str),
+    /// `key=value` fields, `;`-separated, for the reports below.
+    Info(&'a str),
+    Features(&'a str),
+    Diag(&'a str),
+    Benchmark(&'a str),
+    Telemetry(&'a str),
+    TxInfo(&'a str),
+    /// `counter=<n>;device_id=<hex>;time=<unix>;sig=<signature>`: a
+    /// presence token, sent unasked when BOOT is pressed while idle.
+    Presence(&'a str),
+    /// `ERROR:<code>`
+    Error(ErrorCode),
+    /// `ERROR:<code>:<detail>`
+    ErrorDetail(ErrorCode, &'a str),
+}
+
+impl<'a> Response<'a> {
+    /// `None` for lines that aren't a response this build knows, including
+    /// errors with an unknown code.
+    pub fn parse(line: &'a str) -> Option<Self> {
+        let line = line.trim();
+        match line {
+            "PONG" => return Some(Response::Pong),
+            "OTP_CONFIRMED" => return Some(Response::OtpConfirmed),
+            "OTA_OK" => return Some(Response::OtaOk),
+            "OTA_ABORTED" => return Some(Response::OtaAborted),
+            "SHUTDOWN_OK" => return Some(Response::ShutdownOk),
+            "PAIRED" => return Some(Response::Paired),
+            "UNPAIRED" => return Some(Response::Unpaired),
+            "AUTHENTICATED" => return Some(Response::Authenticated),
+            "AUDIT_REGISTERED" => return Some(Response::AuditRegistered),
+            "NO_PENDING_POLICY" => return Some(Response::NoPendingPolicy),
+            _ => {}
+        }
+
+        let (name, payload) = line.split_once(':')?;
+        let response = match name {
+            "PUBKEY" => Response::Pubkey(payload),
+            "DEVICE_ID" => {
+                let (id, serial) = payload.split_once(";serial=")?;
+                let serial = Some(serial).filter(|s| *s != "none");
+                Response::DeviceId { id, serial }
+            }
+            "PROVISIONED" => Response::Provisioned(payload),
+            "SIGNATURE" => Response::Signature(payload),
+            "TRANSACTION" => Response::Transaction(payload),
+            "OTP_SECRET" => {
+                let mut fields = payload.split(';');
+                let secret = fields.next()?;
+                let (mut digits, mut period) = (None, None);
+                for field in fields {
+                    match field.split_once('=') {
+                        Some(("DIGITS", value)) => digits = value.parse().ok(),
+                        Some(("PERIOD", value)) => period = value.parse().ok(),
+                        _ => {}
+                    }
+                }
+                Response::OtpSecret {
+                    secret,
+                    digits: digits?,
+                    period: period?,
+                }
+            }
+            "UNLOCKED_UNTIL" => Response::UnlockedUntil(payload.parse().ok()?),
+            "OTP_STATUS" => Response::OtpStatus(payload),
+            "OTA_READY" => Response::OtaReady {
+                chunk_max: payload.parse().ok()?,
+            },
+            "OTA_ACK" => Response::OtaAck {
+                next: payload.parse().ok()?,
+            },
+            "LOG_LEVEL_OK" => Response::LogLevelOk(payload),
+            "POLICY" => Response::Policy(payload),
+            "POLICY_PENDING" => {
+                let (remaining, policy) = payload.strip_prefix("remaining=")?.split_once(';')?;
+                Response::PolicyPending {
+                    remaining_secs: remaining.parse().ok()?,
+                    policy,
+                }
+            }
+            "PAIRING" => Response::Pairing(payload),
+            "BACKED_UP" => Response::BackedUp(payload),
+            "RESTORED" => Response::Restored(payload),
+            "AUDIT" => Response::Audit(payload),
+            "AUTH_CHALLENGE" => {
+                let (nonce, signature) = payload.split_once(";sig=")?;
+                Response::AuthChallenge { nonce, signature }
+            }
+            "INFO" => Response::Info(payload),
+            "FEATURES" => Response::Features(payload),
+            "DIAG" => Response::Diag(payload),
+            "BENCHMARK" => Response::Benchmark(payload),
+            "TELEMETRY" => Response::Telemetry(payload),
+            "TX_INFO" => Response::TxInfo(payload),
+            "PRESENCE" => Response::Presence(payload),
+            "ERROR" => match payload.split_once(':') {
+                Some((code, detail)) => Response::ErrorDetail(ErrorCode::parse(code)?, detail),
+                None => Response::Error(ErrorCode::parse(payload)?),
+            },
+            _ => return None,
+        };
+        Some(response)
+    }
+}
+
+impl fmt::Display for Response<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Pong => f.write_str("PONG"),
+            Response::Pubkey(pk) => write!(f, "PUBKEY:{}", pk),
+            Response::DeviceId { id, serial } => {
+                write!(f, "DEVICE_ID:{};serial={}", id, serial.unwrap_or("none"))
+            }
+            Response::Provisioned(serial) => write!(f, "PROVISIONED:{}", serial),
+            Response::Signature(sig) => write!(f, "SIGNATURE:{}", sig),
+            Response::Transaction(tx) => write!(f, "TRANSACTION:{}", tx),
+            Response::OtpSecret {
+                secret,
+                digits,
+                period,
+            } => write!(
+                f,
+                "OTP_SECRET:{};ALGO=SHA1;DIGITS={};PERIOD={}",
+                secret, digits, period
+            ),
+            Response::OtpConfirmed => f.write_str("OTP_CONFIRMED"),
+            Response::UnlockedUntil(until) => write!(f, "UNLOCKED_UNTIL:{}", until),
+            Response::OtpStatus(fields) => write!(f, "OTP_STATUS:{}", fields),
+            Response::OtaReady { chunk_max } => write!(f, "OTA_READY:{}", chunk_max),
+            Response::OtaAck { next } => write!(f, "OTA_ACK:{}", next),
+            Response::OtaOk => f.write_str("OTA_OK"),
+            Response::OtaAborted => f.write_str("OTA_ABORTED"),
+            Response::LogLevelOk(level) => write!(f, "LOG_LEVEL_OK:{}", level),
+            Response::Policy(policy) => write!(f, "POLICY:{}", policy),
+            Response::PolicyPending {
+                remaining_secs,
+                policy,
+            } => write!(f, "POLICY_PENDING:remaining={};{}", remaining_secs, policy),
+            Response::NoPendingPolicy => f.write_str("NO_PENDING_POLICY"),
+            Response::ShutdownOk => f.write_str("SHUTDOWN_OK"),
+            Response::Pairing(key) => write!(f, "PAIRING:{}", key),
+            Response::Paired => f.write_str("PAIRED"),
+            Response::Unpaired => f.write_str("UNPAIRED"),
+            Response::AuthChallenge { nonce, signature } => {
+                write!(f, "AUTH_CHALLENGE:{};sig={}", nonce, signature)
+            }
+            Response::Authenticated => f.write_str("AUTHENTICATED"),
+            Response::BackedUp(file) => write!(f, "BACKED_UP:{}", file),
+            Response::Restored(pubkey) => write!(f, "RESTORED:{}", pubkey),
+            Response::AuditRegistered => f.write_str("AUDIT_REGISTERED"),
+            Response::Audit(export) => write!(f, "AUDIT:{}", export),
+            Response::Info(fields) => write!(f, "INFO:{}", fields),
+            Response::Features(fields) => write!(f, "FEATURES:{}", fields),
+            Response::Diag(fields) => write!(f, "DIAG:{}", fields),
+            Response::Benchmark(fields) => write!(f, "BENCHMARK:{}", fields),
+            Response::Telemetry(fields) => write!(f, "TELEMETRY:{}", fields),
+            Response::TxInfo(fields) => write!(f, "TX_INFO:{}", fields),
+            Response::Presence(fields) => write!(f, "PRESENCE:{}", fields),
+            Response::Error(code) => write!(f, "ERROR:{}", code),
+            Response::ErrorDetail(code, detail) => write!(f, "ERROR:{}:{}", code, detail),
+        }
+    }
+}
+
+/// The `key=value` pairs of a report payload such as `Response::Info`;
+/// anything without an `=` is skipped.
+pub fn fields(payload: &str) -> impl Iterator<Item = (&str, &str)> {
+    payload.split(';').filter_map(|field| field.split_once('='))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_match_wire_protocol() {
+        assert_eq!(Response::Pubkey("abc").to_string(), "PUBKEY:abc");
+        assert_eq!(Response::Signature("c2ln").to_string(), "SIGNATURE:c2ln");
+        assert_eq!(
+            Response::DeviceId {
+                id: "00112233aabbccdd",
+                serial: None
+            }
+            .to_string(),
+            "DEVICE_ID:00112233aabbccdd;serial=none"
+        );
+        assert_eq!(
+            Response::OtpSecret {
+                secret: "JBSWY3DP",
+                digits: 6,
+                period: 30
+            }
+            .to_string(),
+            "OTP_SECRET:JBSWY3DP;ALGO=SHA1;DIGITS=6;PERIOD=30"
+        );
+        assert_eq!(
+            Response::UnlockedUntil(1_700_000_120).to_string(),
+            "UNLOCKED_UNTIL:1700000120"
+        );
+        assert_eq!(
+            Response::OtaReady { chunk_max: 512 }.to_string(),
+            "OTA_READY:512"
+        );
+        assert_eq!(Response::OtaAck { next: 1024 }.to_string(), "OTA_ACK:1024");
+        assert_eq!(
+            Response::Policy("max=none;blind=on;allow=any").to_string(),
+            "POLICY:max=none;blind=on;allow=any"
+        );
+        assert_eq!(
+            Response::AuthChallenge {
+                nonce: "bm9uY2U=",
+                signature: "c2ln"
+            }
+            .to_string(),
+            "AUTH_CHALLENGE:bm9uY2U=;sig=c2ln"
+        );
+    }
+
+    #[test]
+    fn formats_errors() {
+        assert_eq!(
+            Response::Error(ErrorCode::Locked).to_string(),
+            "ERROR:LOCKED"
+        );
+        assert_eq!(
+            Response::ErrorDetail(ErrorCode::OtaBegin, "bad size").to_string(),
+            "ERROR:OTA_BEGIN:bad size"
+        );
+    }
+
+    #[test]
+    fn formatted_responses_parse_back() {
+        let responses = [
+            Response::Pong,
+            Response::Pubkey("abc"),
+            Response::DeviceId {
+                id: "00112233aabbccdd",
+                serial: None,
+            },
+            Response::DeviceId {
+                id: "00112233aabbccdd",
+                serial: Some("SN-0001"),
+            },
+            Response::OtpSecret {
+                secret: "JBSWY3DP",
+                digits: 6,
+                period: 30,
+            },
+            Response::OtpConfirmed,
+            Response::UnlockedUntil(1_700_000_120),
+            Response::OtpStatus("enrolled=yes;unlocked_until=1700000120;now=1700000000"),
+            Response::OtaReady { chunk_max: 512 },
+            Response::OtaAck { next: 1024 },
+            Response::OtaOk,
+            Response::Policy("max=none;blind=on;allow=any"),
+            Response::PolicyPending {
+                remaining_secs: 172_800,
+                policy: "max=none;blind=on;allow=any",
+            },
+            Response::NoPendingPolicy,
+            Response::Pairing("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T"),
+            Response::Paired,
+            Response::Unpaired,
+            Response::AuthChallenge {
+                nonce: "bm9uY2U=",
+                signature: "c2ln",
+            },
+            Response::Authenticated,
+            Response::BackedUp("BKP00001.ESB"),
+            Response::Restored("4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T"),
+            Response::AuditRegistered,
+            Response::Audit("RVNBWA=="),
+            Response::Info("fw=0.1.0;chip=esp32"),
+            Response::TxInfo("memo='hi';blockhash=1111"),
+            Response::Presence("counter=3;device_id=00112233aabbccdd;time=0;sig=c2ln"),
+            Response::Error(ErrorCode::Locked),
+            Response::ErrorDetail(ErrorCode::Policy, "max: 5 > 1"),
+        ];
+        for response in responses {
+            assert_eq!(Response::parse(&response.to_string()), Some(response));
+        }
+    }
+
+    #[test]
+    fn splits_report_fields() {
+        let found: Vec<_> = fields("fw=0.1.0;proto=1;features=none").collect();
+        assert_eq!(
+            found,
+            [("fw", "0.1.0"), ("proto", "1"), ("features", "none")]
+        );
+        assert_eq!(fields("").count(), 0);
+    }
+
+    #[test]
+    fn rejects_unknown_lines() {
+        assert_eq!(Response::parse("HELLO"), None);
+        assert_eq!(Response::parse("ERROR:Unknown command"), None);
+        assert_eq!(Response::parse("OTA_ACK:soon"), None);
+        assert_eq!(Response::parse("AUTH_CHALLENGE:bm9uY2U="), None);
+        assert_eq!(Response::parse("POLICY_PENDING:max=none"), None);
+    }
+}
@@ -0,0 +1,484 @@
+//! Command/reply exchanges over a serial line, for host tools.
+//!
+//! The UART also carries the bootloader's log after a reset, the tail of
+//! lines from exchanges the host gave up on, and whatever noise a replug
+//! produces. Only lines that parse as a [`Response`] (or look like an
+//! `ERROR:` reply) end an exchange; everything else is skipped, so the
+//! reader falls back into step at the next newline. Presence tokens, which
+//! the device sends unasked, are skipped too, except by
+//! [`Transport::wait_presence`].
+//!
+//! Each exchange runs in an `exchange` span (`tracing`) carrying the command
+//! name; its outcome is logged with the latency and the response code.
+
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::command::Command;
+use crate::response::Response;
+
+/// Longest line kept; longer ones are skipped whole
+pub const MAX_LINE_LEN: usize = 4096;
+
+/// Lets a light-sleeping device wake before the command line arrives
+const WAKE_DELAY: Duration = Duration::from_millis(10);
+/// Pause between polls when the port has nothing to read
+const POLL_DELAY: Duration = Duration::from_millis(10);
+
+/// A byte stream to the device
+pub trait Port: Read + Write {
+    /// Drops whatever has been received but not read yet
+    fn discard_input(&mut self) -> io::Result<()>;
+}
+
+#[cfg(feature = "serialport")]
+impl Port for std::boxed::Box<dyn serialport::SerialPort> {
+    fn discard_input(&mut self) -> io::Result<()> {
+        self.clear(serialport::ClearBuffer::Input)
+            .map_err(io::Error::from)
+    }
+}
+
+/// Why an exchange produced no reply
+#[derive(Debug)]
+pub enum TransportError {
+    /// Reading or writing the port failed
+    Io(io::Error),
+    /// No reply line arrived, and nothing at all for `idle`
+    NoReply {
+        idle: Duration,
+        /// The last line received that wasn't a reply, if any
+        skipped: Option<String>,
+    },
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(e) => write!(f, "serial port error: {}", e),
+            TransportError::NoReply {
+                idle,
+                skipped: None,
+            } => write!(
+                f,
+                "no reply from the ESP32 within {} seconds",
+                idle.as_secs()
+            ),
+            TransportError::NoReply {
+                idle,
+                skipped: Some(line),
+            } => write!(
+                f,
+                "no reply from the ESP32 within {} seconds (last unrecognised output: {:?})",
+                idle.as_secs(),
+                line
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransportError::Io(e) => Some(e),
+            TransportError::NoReply { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+/// Splits received bytes into lines and keeps only reply lines
+#[derive(Debug, Default)]
+pub struct LineReader {
+    buffer: Vec<u8>,
+    /// The current line went past `MAX_LINE_LEN` and is being dropped
+    overlong: bool,
+    skipped: Option<String>,
+}
+
+impl LineReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds one byte; returns the reply line (trimmed) it completes, if any
+    pub fn push(&mut self, byte: u8) -> Option<String> {
+        self.push_matching(byte, is_reply)
+    }
+
+    /// As [`LineReader::push`], keeping the lines `wanted` accepts instead
+    fn push_matching(&mut self, byte: u8, wanted: fn(&str) -> bool) -> Option<String> {
+        if byte != b'\n' {
+            if self.buffer.len() < MAX_LINE_LEN {
+                self.buffer.push(byte);
+            } else {
+                self.overlong = true;
+            }
+            return None;
+        }
+
+        let line = String::from_utf8_lossy(&self.buffer).trim().to_string();
+        self.buffer.clear();
+        if core::mem::take(&mut self.overlong) {
+            let start: String = line.chars().take(64).collect();
+            tracing::debug!(line = %start, "skipped an overlong line");
+            self.skipped = Some(format!("{}...", start));
+            return None;
+        }
+        if wanted(&line) {
+            return Some(line);
+        }
+        if !line.is_empty() {
+            tracing::debug!(line = %line, "skipped a line");
+            self.skipped = Some(line);
+        }
+        None
+    }
+
+    /// The last line skipped since the reader was created
+    pub fn skipped(&self) -> Option<&str> {
+        self.skipped.as_deref()
+    }
+
+    /// `NoReply` for a wait of `idle`, naming what was skipped
+    pub fn no_reply(self, idle: Duration) -> TransportError {
+        TransportError::NoReply {
+            idle,
+            skipped: self.skipped,
+        }
+    }
+}
+
+/// Replies this build parses, and errors even with a code it doesn't know
+fn is_reply(line: &str) -> bool {
+    match Response::parse(line) {
+        Some(Response::Presence(_)) => false,
+        Some(_) => true,
+        None => line.starts_with("ERROR:"),
+    }
+}
+
+fn is_presence(line: &str) -> bool {
+    matches!(Response::parse(line), Some(Response::Presence(_)))
+}
+
+/// The response name, plus the code for errors, e.g. `PUBKEY` or
+/// `ERROR:LOCKED`; never the payload
+pub fn response_code(reply: &str) -> &str {
+    let end = match reply.strip_prefix("ERROR:") {
+        Some(rest) => "ERROR:".len() + rest.find(':').unwrap_or(rest.len()),
+        None => reply.find(':').unwrap_or(reply.len()),
+    };
+    &reply[..end]
+}
+
+/// Logs how an exchange that started at `started` ended
+pub fn log_outcome(started: Instant, result: &Result<String, TransportError>) {
+    let latency_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(reply) => tracing::debug!(latency_ms, response = response_code(reply), "reply"),
+        Err(e) => tracing::debug!(latency_ms, error = %e, "no reply"),
+    }
+}
+
+/// A port speaking the signer protocol, one exchange at a time
+pub struct Transport<P> {
+    port: P,
+}
+
+impl<P: Port> Transport<P> {
+    pub fn new(port: P) -> Self {
+        Transport { port }
+    }
+
+    pub fn port(&self) -> &P {
+        &self.port
+    }
+
+    pub fn port_mut(&mut self) -> &mut P {
+        &mut self.port
+    }
+
+    /// Sends `command` and returns its (trimmed) reply line, giving up once
+    /// nothing has arrived for `max_idle`. Idempotent commands are sent once
+    /// more when the first attempt gets no reply.
+    pub fn exchange(
+        &mut self,
+        command: &Command,
+        max_idle: Duration,
+    ) -> Result<String, TransportError> {
+        let _span = tracing::debug_span!("exchange", command = command.name()).entered();
+        let line = command.to_string();
+        match self.exchange_logged(&line, max_idle) {
+            Err(TransportError::NoReply { .. }) if command.is_idempotent() => {
+                tracing::info!(command = command.name(), "no reply, sending again");
+                self.exchange_logged(&line, max_idle)
+            }
+            result => result,
+        }
+    }
+
+    fn exchange_logged(
+        &mut self,
+        line: &str,
+        max_idle: Duration,
+    ) -> Result<String, TransportError> {
+        let started = Instant::now();
+        let result = self.exchange_line(line, max_idle);
+        log_outcome(started, &result);
+        result
+    }
+
+    fn exchange_line(&mut self, line: &str, max_idle: Duration) -> Result<String, TransportError> {
+        // Drop anything left over from an earlier, abandoned exchange
+        self.port.discard_input()?;
+
+        // Wake preamble: a light-sleeping ESP32 loses the bytes that wake it,
+        // awake devices ignore the empty line
+        self.port.write_all(b"\n")?;
+        self.port.flush()?;
+        thread::sleep(WAKE_DELAY);
+
+        self.send_raw(line.as_bytes())?;
+        self.receive(max_idle)
+    }
+
+    /// Writes `bytes` and a newline as they are, without the wake preamble
+    /// or dropping stale input: for tests that send what no `Command`
+    /// formats, or several lines before reading any reply
+    pub fn send_raw(&mut self, bytes: &[u8]) -> Result<(), TransportError> {
+        self.port.write_all(bytes)?;
+        self.port.write_all(b"\n")?;
+        self.port.flush()?;
+        Ok(())
+    }
+
+    /// Reads up to the next reply line, giving up once nothing has arrived
+    /// for `max_idle`
+    pub fn receive(&mut self, max_idle: Duration) -> Result<String, TransportError> {
+        self.receive_matching(max_idle, is_reply)
+    }
+
+    /// Waits for the next presence token (the whole `PRESENCE:` line), one
+    /// made from a press after this call; `NoReply` once `max_idle` passes
+    /// without a byte from the device
+    pub fn wait_presence(&mut self, max_idle: Duration) -> Result<String, TransportError> {
+        self.port.discard_input()?;
+        self.receive_matching(max_idle, is_presence)
+    }
+
+    fn receive_matching(
+        &mut self,
+        max_idle: Duration,
+        wanted: fn(&str) -> bool,
+    ) -> Result<String, TransportError> {
+        let mut reader = LineReader::new();
+        // A byte at a time: whatever follows this reply is the next one's
+        let mut chunk = [0u8; 1];
+        let mut last_byte = Instant::now();
+        loop {
+            match self.port.read(&mut chunk) {
+                Ok(0) => thread::sleep(POLL_DELAY),
+                Ok(_) => {
+                    last_byte = Instant::now();
+                    if let Some(reply) = reader.push_matching(chunk[0], wanted) {
+                        return Ok(reply);
+                    }
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e.into()),
+            }
+            if last_byte.elapsed() >= max_idle {
+                return Err(reader.no_reply(max_idle));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Replies to the n-th command line with the n-th script entry
+    struct MockPort {
+        script: VecDeque<&'static [u8]>,
+        received: Vec<u8>,
+        pending: VecDeque<u8>,
+        /// Bytes waiting before the first command, e.g. a boot log
+        stale: &'static [u8],
+        commands: usize,
+    }
+
+    impl MockPort {
+        fn new(script: &[&'static [u8]]) -> Self {
+            MockPort {
+                script: script.iter().copied().collect(),
+                received: Vec::new(),
+                pending: VecDeque::new(),
+                stale: b"",
+                commands: 0,
+            }
+        }
+    }
+
+    impl Read for MockPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.pending.len());
+            for slot in buf.iter_mut().take(n) {
+                *slot = self.pending.pop_front().unwrap();
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &byte in buf {
+                if byte == b'\n' && !self.received.is_empty() {
+                    self.received.clear();
+                    self.commands += 1;
+                    if let Some(reply) = self.script.pop_front() {
+                        self.pending.extend(reply);
+                    }
+                } else if byte != b'\n' {
+                    self.received.push(byte);
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Port for MockPort {
+        fn discard_input(&mut self) -> io::Result<()> {
+            self.pending.clear();
+            self.pending.extend(self.stale);
+            self.stale = b"";
+            Ok(())
+        }
+    }
+
+    const IDLE: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn skips_noise_before_the_reply() {
+        let mut port = MockPort::new(&[
+            b"ets Jun  8 2016 00:22:57\r\nrst:0x1 (POWERON_RESET)\r\n\r\nPUBKEY:abc\r\n",
+        ]);
+        port.stale = b"ATURE:tail of an old line\n";
+        let mut transport = Transport::new(port);
+        assert_eq!(
+            transport.exchange(&Command::GetPubkey, IDLE).unwrap(),
+            "PUBKEY:abc"
+        );
+    }
+
+    #[test]
+    fn presence_tokens_are_not_replies() {
+        const TOKEN: &[u8] = b"PRESENCE:counter=1;device_id=00112233aabbccdd;time=0;sig=c2ln\n";
+        let mut port = MockPort::new(&[b"PONG\n"]);
+        port.stale = TOKEN;
+        let mut transport = Transport::new(port);
+        assert_eq!(transport.exchange(&Command::Ping, IDLE).unwrap(), "PONG");
+
+        // Only a token sent after the wait starts counts
+        transport.port_mut().pending.extend(TOKEN);
+        transport.port_mut().stale = b"PONG\nPRESENCE:counter=2;device_id=00;time=0;sig=c2ln\n";
+        assert_eq!(
+            transport.wait_presence(IDLE).unwrap(),
+            "PRESENCE:counter=2;device_id=00;time=0;sig=c2ln"
+        );
+        assert!(matches!(
+            transport.wait_presence(IDLE),
+            Err(TransportError::NoReply { .. })
+        ));
+    }
+
+    #[test]
+    fn passes_unknown_error_codes_through() {
+        let mut transport = Transport::new(MockPort::new(&[b"ERROR:FROM_THE_FUTURE\n"]));
+        assert_eq!(
+            transport.exchange(&Command::GetInfo, IDLE).unwrap(),
+            "ERROR:FROM_THE_FUTURE"
+        );
+    }
+
+    #[test]
+    fn response_codes_leave_out_payloads() {
+        assert_eq!(response_code("PUBKEY:abc"), "PUBKEY");
+        assert_eq!(response_code("OTA_OK"), "OTA_OK");
+        assert_eq!(response_code("ERROR:LOCKED"), "ERROR:LOCKED");
+        assert_eq!(response_code("ERROR:POLICY:OVER_MAX"), "ERROR:POLICY");
+    }
+
+    #[test]
+    fn skips_overlong_lines() {
+        let mut reader = LineReader::new();
+        for _ in 0..MAX_LINE_LEN + 10 {
+            assert_eq!(reader.push(b'A'), None);
+        }
+        assert_eq!(reader.push(b'\n'), None);
+        assert!(reader.skipped().unwrap().ends_with("..."));
+        let reply: Vec<_> = b"OTA_OK\n".iter().filter_map(|&b| reader.push(b)).collect();
+        assert_eq!(reply, ["OTA_OK"]);
+    }
+
+    #[test]
+    fn retries_idempotent_commands_once() {
+        let mut transport = Transport::new(MockPort::new(&[b"garbage\n", b"PUBKEY:abc\n"]));
+        assert_eq!(
+            transport.exchange(&Command::GetPubkey, IDLE).unwrap(),
+            "PUBKEY:abc"
+        );
+        assert_eq!(transport.port().commands, 2);
+    }
+
+    #[test]
+    fn never_resends_a_signing_request() {
+        let mut transport = Transport::new(MockPort::new(&[b"", b"SIGNATURE:c2ln\n"]));
+        match transport.exchange(&Command::Sign("AQID"), IDLE) {
+            Err(TransportError::NoReply { skipped: None, .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(transport.port().commands, 1);
+    }
+
+    #[test]
+    fn raw_lines_get_their_replies_in_order() {
+        let mut transport =
+            Transport::new(MockPort::new(&[b"PONG\n", b"ERROR:BUSY\nPUBKEY:abc\n"]));
+        transport.send_raw(b"PING").unwrap();
+        transport.send_raw(b"\xff\xfe").unwrap();
+        assert_eq!(transport.receive(IDLE).unwrap(), "PONG");
+        assert_eq!(transport.receive(IDLE).unwrap(), "ERROR:BUSY");
+        assert_eq!(transport.receive(IDLE).unwrap(), "PUBKEY:abc");
+        assert_eq!(transport.port().commands, 2);
+    }
+
+    #[test]
+    fn no_reply_names_what_was_skipped() {
+        let mut transport = Transport::new(MockPort::new(&[b"Guru Meditation Error\n"]));
+        match transport.exchange(&Command::Shutdown, IDLE) {
+            Err(TransportError::NoReply {
+                skipped: Some(line),
+                ..
+            }) => assert_eq!(line, "Guru Meditation Error"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -13,6 +13,8 @@
 use anyhow::Result;
 use base64::Engine;
 use serialport::SerialPort;
+use signer_protocol::command::Command;
+use signer_protocol::transport::Transport;
 use solana_sdk::pubkey::Pubkey;
 use std::str::FromStr;
 use std::time::Duration;
@@ -20,43 +22,18 @@ use std::time::Duration;
 // Configure your ESP32 serial port here
 const SERIAL_PORT: &str = "/dev/tty.usbserial-0001";
 
+/// How long the ESP32 may stay silent before a command is given up on
+const REPLY_IDLE: Duration = Duration::from_secs(10);
+
 /// Send a command to ESP32 and read response
-fn send_command(port: &mut Box<dyn SerialPort>, command: &str) -> Result<String> {
-    // Send command
-    port.write_all(format!("{}\n", command).as_bytes())?;
-    port.flush()?;
+fn send_command(
+    transport: &mut Transport<Box<dyn SerialPort>>,
+    command: Command,
+) -> Result<String> {
     println!("→ Sent: {}", command);
-
-    // Read response
-    let mut buffer = String::new();
-    let mut byte = [0u8; 1];
-    let mut timeout_count = 0;
-
-    while timeout_count < 20 {
-        // Increased timeout for demo
-        match port.read(&mut byte) {
-            Ok(1) => {
-                let ch = byte[0] as char;
-                if ch == '\n' {
-                    break;
-                }
-                buffer.push(ch);
-            }
-            Ok(0) => {
-                timeout_count += 1;
-                std::thread::sleep(Duration::from_millis(100));
-            }
-            Err(_) => {
-                timeout_count += 1;
-                std::thread::sleep(Duration::from_millis(100));
-            }
-            Ok(n) => unreachable!("Unexpected read size: {}", n),
-        }
-    }
-
-    let response = buffer.trim();
+    let response = transport.exchange(&command, REPLY_IDLE)?;
     println!("← Received: {}", response);
-    Ok(response.to_string())
+    Ok(response)
 }
 
 /// Decode and analyze a base64 transaction
@@ -96,15 +73,16 @@ fn main() -> Result<()> {
 
     // Open serial port
     println!("📡 Connecting to ESP32 on {}...", SERIAL_PORT);
-    let mut port = serialport::new(SERIAL_PORT, 115_200)
-        .timeout(Duration::from_millis(500))
+    let port = serialport::new(SERIAL_PORT, 115_200)
+        .timeout(Duration::from_millis(100))
         .open()?;
+    let mut port = Transport::new(port);
     println!("✅ Connected!\n");
 
     // Step 1: Get public key
     println!("1️⃣  Getting ESP32 Public Key");
     println!("{}", "-".repeat(30));
-    let response = send_command(&mut port, "GET_PUBKEY")?;
+    let response = send_command(&mut port, Command::GetPubkey)?;
 
     if let Some(pubkey_str) = response.strip_prefix("PUBKEY:") {
         let pubkey = Pubkey::from_str(pubkey_str)?;
@@ -118,7 +96,7 @@ fn main() -> Result<()> {
     // Step 2: Get transaction info
     println!("2️⃣  Getting Transaction Information");
     println!("{}", "-".repeat(35));
-    let response = send_command(&mut port, "TX_INFO")?;
+    let response = send_command(&mut port, Command::TxInfo)?;
 
     if let Some(info_str) = response.strip_prefix("TX_INFO:") {
         println!("✅ Transaction Info: {}", info_str);
@@ -126,12 +104,12 @@ fn main() -> Result<()> {
         // Parse info components
         let parts: Vec<&str> = info_str.split(';').collect();
         for part in parts {
-            if part.starts_with("memo=") {
-                println!("   📝 Memo: {}", &part[5..]);
-            } else if part.starts_with("blockhash=") {
-                println!("   🔗 Blockhash: {}", &part[10..]);
-            } else if part.starts_with("program=") {
-                println!("   🏦 Program: {}", &part[8..]);
+            if let Some(memo) = part.strip_prefix("memo=") {
+                println!("   📝 Memo: {}", memo);
+            } else if let Some(blockhash) = part.strip_prefix("blockhash=") {
+                println!("   🔗 Blockhash: {}", blockhash);
+            } else if let Some(program) = part.strip_prefix("program=") {
+                println!("   🏦 Program: {}", program);
             }
         }
         println!();
@@ -144,7 +122,7 @@ fn main() -> Result<()> {
     println!("{}", "-".repeat(38));
     println!("⏳ Requesting transaction creation (this may take a moment)...");
 
-    let response = send_command(&mut port, "CREATE_TX")?;
+    let response = send_command(&mut port, Command::CreateTx)?;
 
     if let Some(tx_base64) = response.strip_prefix("TRANSACTION:") {
         println!("✅ Transaction created successfully!");
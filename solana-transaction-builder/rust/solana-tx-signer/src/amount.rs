@@ -0,0 +1,159 @@
+//! Amounts as the command line takes them and the summaries show them, in
+//! integer base units throughout: no floating point, and arithmetic that
+//! errors instead of wrapping or saturating.
+//!
+//! SOL amounts are `0.5 SOL` (or `0.5sol`), `2_000_000 lamports`, or a bare
+//! number of lamports, as every `--amount` in lamports has always been.
+//! Token amounts are decimal numbers scaled by the mint's decimals.
+
+use anyhow::{anyhow, bail, Result};
+use solana_sdk::native_token::LAMPORTS_PER_SOL;
+
+pub const SOL_DECIMALS: u8 = 9;
+
+/// Converts a decimal amount into base units of `decimals` places.
+/// Underscores may separate digits.
+pub fn parse_units(amount: &str, decimals: u8) -> Result<u64> {
+    let invalid = || anyhow!("invalid amount '{}'", amount);
+    let bytes = amount.as_bytes();
+    let separated = bytes.iter().enumerate().all(|(i, b)| {
+        *b != b'_'
+            || (i > 0
+                && bytes[i - 1].is_ascii_digit()
+                && bytes.get(i + 1).is_some_and(u8::is_ascii_digit))
+    });
+    if !separated {
+        return Err(invalid());
+    }
+    let digits = amount.replace('_', "");
+    let (whole, fraction) = digits.split_once('.').unwrap_or((&digits, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return Err(invalid());
+    }
+    if !whole
+        .chars()
+        .chain(fraction.chars())
+        .all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+    if fraction.len() > usize::from(decimals) {
+        bail!("'{}' has more than {} decimal places", amount, decimals);
+    }
+    let scaled = format!(
+        "{}{:0<width$}",
+        whole,
+        fraction,
+        width = usize::from(decimals)
+    );
+    let raw: u64 = scaled
+        .parse()
+        .map_err(|_| anyhow!("'{}' is too large", amount))?;
+    if raw == 0 {
+        bail!("amount must be greater than zero");
+    }
+    Ok(raw)
+}
+
+/// Parses a SOL amount into lamports; see the module docs for the forms
+pub fn parse_lamports(amount: &str) -> Result<u64> {
+    let text = amount.trim();
+    let split = text
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let decimals = match unit.to_ascii_lowercase().as_str() {
+        "" | "lamport" | "lamports" => 0,
+        "sol" => SOL_DECIMALS,
+        _ => bail!(
+            "invalid amount '{}'; use lamports, or SOL as in '0.5 SOL'",
+            amount
+        ),
+    };
+    parse_units(number.trim_end(), decimals)
+}
+
+/// `amount` sent to each of `count` recipients
+pub fn total(amount: u64, count: usize) -> Result<u64> {
+    u64::try_from(count)
+        .ok()
+        .and_then(|count| amount.checked_mul(count))
+        .ok_or_else(|| anyhow!("{} times {} overflows", amount, count))
+}
+
+pub fn format_sol(lamports: u64) -> String {
+    format!(
+        "{}.{:09}",
+        lamports / LAMPORTS_PER_SOL,
+        lamports % LAMPORTS_PER_SOL
+    )
+}
+
+pub fn format_units(amount: u64, decimals: u8) -> String {
+    let scale = 10u128.pow(u32::from(decimals));
+    let amount = u128::from(amount);
+    match decimals {
+        0 => amount.to_string(),
+        _ => format!(
+            "{}.{:0width$}",
+            amount / scale,
+            amount % scale,
+            width = usize::from(decimals)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn parses_sol_and_lamports() {
+        assert_eq!(parse_lamports("0.5 SOL").unwrap(), 500_000_000);
+        assert_eq!(parse_lamports("0.5sol").unwrap(), 500_000_000);
+        assert_eq!(parse_lamports("2_000_000 lamports").unwrap(), 2_000_000);
+        assert_eq!(parse_lamports("1 lamport").unwrap(), 1);
+        assert_eq!(parse_lamports("1500").unwrap(), 1500);
+        assert_eq!(parse_lamports(".000000001 SOL").unwrap(), 1);
+        assert!(parse_lamports("1.5").is_err());
+        assert!(parse_lamports("0.0000000001 SOL").is_err());
+        assert!(parse_lamports("0 SOL").is_err());
+        assert!(parse_lamports("5 BTC").is_err());
+        assert!(parse_lamports("18446744073.709551616 SOL").is_err());
+        assert_eq!(
+            parse_lamports("18446744073.709551615 SOL").unwrap(),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn parses_token_amounts() {
+        assert_eq!(parse_units("12.5", 6).unwrap(), 12_500_000);
+        assert_eq!(parse_units("1_000.25", 2).unwrap(), 100_025);
+        assert!(parse_units("_1", 2).is_err());
+        assert!(parse_units("1_", 2).is_err());
+        assert!(parse_units("1_.5", 2).is_err());
+        assert!(parse_units("1.234", 2).is_err());
+        assert!(parse_units("-1", 2).is_err());
+        assert!(parse_units(".", 2).is_err());
+    }
+
+    #[test]
+    fn formats_and_totals_losslessly() {
+        assert_eq!(format_sol(u64::MAX), "18446744073.709551615");
+        assert_eq!(format_units(12_500_000, 6), "12.500000");
+        assert_eq!(format_units(42, 0), "42");
+        assert_eq!(total(3, 4).unwrap(), 12);
+        assert!(total(u64::MAX, 2).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn formatted_amounts_parse_back(lamports in 1..=u64::MAX, units: u64, decimals in 0..=19u8) {
+            prop_assert_eq!(parse_lamports(&format!("{} SOL", format_sol(lamports))).unwrap(), lamports);
+            prop_assert_eq!(parse_lamports(&lamports.to_string()).unwrap(), lamports);
+            prop_assert_eq!(parse_units(&format_units(units, decimals), decimals).unwrap(), units);
+        }
+    }
+}
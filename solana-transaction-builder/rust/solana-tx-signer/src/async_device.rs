@@ -0,0 +1,224 @@
+//! An async client for the serial protocol, for the server modes: callers
+//! await replies instead of parking a thread on the UART, and any number of
+//! tasks can share one connection.
+//!
+//! One task owns the port and runs exchanges strictly one at a time. A caller
+//! that stops waiting (a dropped future, a disconnected client) doesn't cut
+//! its exchange short: the task still reads that reply, so the next request
+//! can never be answered with a stale line.
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use serialport::{ClearBuffer, SerialPort};
+use signer_core::policy::Policy;
+use signer_protocol::command::Command;
+use signer_protocol::response::Response;
+use signer_protocol::transport::{self, LineReader, TransportError};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+use tracing::Instrument;
+
+use crate::device::{self, Device, BUTTON_IDLE_SECS, REPLY_IDLE_SECS};
+use crate::recording;
+
+/// Requests waiting for the port before `command` starts waiting too
+const QUEUE_DEPTH: usize = 16;
+
+/// One command line and where its reply goes
+struct Exchange {
+    line: String,
+    /// `Command::name`, for logs
+    name: &'static str,
+    max_idle: Duration,
+    /// Send it again if the first attempt gets no reply
+    retry: bool,
+    reply: oneshot::Sender<Result<String, TransportError>>,
+}
+
+/// A handle to the connection; clones share it
+#[derive(Clone)]
+pub struct AsyncDevice {
+    exchanges: mpsc::Sender<Exchange>,
+}
+
+impl AsyncDevice {
+    /// Opens `path` and starts the task that owns it. Must be called from
+    /// within a tokio runtime.
+    pub fn open(path: &str, baud: u32) -> Result<Self> {
+        let port = tokio_serial::new(path, baud)
+            .open_native_async()
+            .map_err(|e| anyhow!("Failed to open serial port '{}': {}", path, e))?;
+        let (exchanges, queue) = mpsc::channel(QUEUE_DEPTH);
+        tokio::spawn(run(port, queue));
+        Ok(AsyncDevice { exchanges })
+    }
+
+    /// Reopens the port a blocking `Device` (e.g. an auto-detected one) has
+    /// open, asynchronously. It has to be the paired device, as everything
+    /// sent through it is for signing.
+    pub fn from_device(device: Device) -> Result<Self> {
+        if !device.is_paired() {
+            return Err(anyhow!(
+                "This host isn't paired with the ESP32; pair them with `pair` first"
+            ));
+        }
+        let (path, baud) = device.port_settings()?;
+        // Release the port (and its exclusive lock) before reopening it
+        drop(device);
+        Self::open(&path, baud)
+    }
+
+    /// A handle with nothing behind it: every command fails as if the
+    /// connection had closed
+    #[cfg(test)]
+    pub fn closed() -> Self {
+        let (exchanges, _) = mpsc::channel(1);
+        AsyncDevice { exchanges }
+    }
+
+    /// Sends one command line and returns the trimmed response line, like
+    /// `Device::command`
+    pub async fn command(&self, command: Command<'_>, max_idle_secs: u32) -> Result<String> {
+        let (reply, response) = oneshot::channel();
+        self.exchanges
+            .send(Exchange {
+                line: command.to_string(),
+                name: command.name(),
+                max_idle: Duration::from_secs(max_idle_secs.into()),
+                retry: command.is_idempotent(),
+                reply,
+            })
+            .await
+            .map_err(|_| anyhow!("ESP32 connection closed"))?;
+        Ok(response
+            .await
+            .map_err(|_| anyhow!("ESP32 connection closed"))??)
+    }
+
+    /// Like `Device::request`
+    pub async fn request<T>(
+        &self,
+        command: Command<'_>,
+        max_idle_secs: u32,
+        accept: impl FnOnce(Response) -> Option<T>,
+    ) -> Result<T> {
+        let line = self.command(command, max_idle_secs).await?;
+        device::reply(&line, accept)
+    }
+
+    pub async fn pubkey(&self) -> Result<Pubkey> {
+        let pubkey = self
+            .request(
+                Command::GetPubkey,
+                REPLY_IDLE_SECS,
+                |response| match response {
+                    Response::Pubkey(pubkey) => Some(pubkey.to_string()),
+                    _ => None,
+                },
+            )
+            .await?;
+        Pubkey::from_str(&pubkey).map_err(|e| anyhow!("Failed to parse public key: {}", e))
+    }
+
+    /// Like `Device::policy`
+    pub async fn policy(&self) -> Result<Policy> {
+        let text = self
+            .request(
+                Command::GetPolicy,
+                REPLY_IDLE_SECS,
+                |response| match response {
+                    Response::Policy(text) => Some(text.to_string()),
+                    _ => None,
+                },
+            )
+            .await?;
+        Policy::parse(&text).map_err(|e| anyhow!("Failed to parse policy {}: {}", text, e))
+    }
+
+    /// Signs `message` once the BOOT button is pressed
+    pub async fn sign(&self, message: &[u8]) -> Result<Signature> {
+        let message_b64 = base64::engine::general_purpose::STANDARD.encode(message);
+        let signature_b64 = self
+            .request(
+                Command::Sign(&message_b64),
+                BUTTON_IDLE_SECS,
+                |response| match response {
+                    Response::Signature(signature) => Some(signature.to_string()),
+                    _ => None,
+                },
+            )
+            .await?;
+        let signature_bytes = base64::engine::general_purpose::STANDARD.decode(signature_b64)?;
+        Ok(Signature::try_from(signature_bytes.as_slice())?)
+    }
+}
+
+/// Runs exchanges until every handle is gone
+async fn run(mut port: SerialStream, mut queue: mpsc::Receiver<Exchange>) {
+    while let Some(exchange) = queue.recv().await {
+        let span = tracing::debug_span!("exchange", command = exchange.name);
+        let mut result = exchange_logged(&mut port, &exchange)
+            .instrument(span.clone())
+            .await;
+        if exchange.retry && matches!(result, Err(TransportError::NoReply { .. })) {
+            span.in_scope(|| tracing::info!(command = exchange.name, "no reply, sending again"));
+            result = exchange_logged(&mut port, &exchange).instrument(span).await;
+        }
+        // The caller may have stopped waiting; the exchange is complete anyway
+        let _ = exchange.reply.send(result);
+    }
+}
+
+async fn exchange_logged(
+    port: &mut SerialStream,
+    exchange: &Exchange,
+) -> Result<String, TransportError> {
+    let started = Instant::now();
+    let result = exchange_line(port, &exchange.line, exchange.max_idle).await;
+    transport::log_outcome(started, &result);
+    result
+}
+
+/// One attempt of `Transport::exchange`, awaiting the port instead of polling it
+async fn exchange_line(
+    port: &mut SerialStream,
+    line: &str,
+    max_idle: Duration,
+) -> Result<String, TransportError> {
+    // Drop anything left over from an exchange that timed out
+    port.clear(ClearBuffer::Input)
+        .map_err(|e| TransportError::Io(e.into()))?;
+
+    // Wake preamble, as in `Transport`
+    port.write_all(b"\n").await?;
+    port.flush().await?;
+    recording::sent(b"\n");
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    port.write_all(line.as_bytes()).await?;
+    port.write_all(b"\n").await?;
+    port.flush().await?;
+    recording::sent(format!("{}\n", line).as_bytes());
+
+    let mut reader = LineReader::new();
+    let mut chunk = [0u8; 64];
+    loop {
+        match tokio::time::timeout(max_idle, port.read(&mut chunk)).await {
+            Ok(Ok(0)) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            Ok(Ok(n)) => {
+                recording::received(&chunk[..n]);
+                for &byte in &chunk[..n] {
+                    if let Some(reply) = reader.push(byte) {
+                        return Ok(reply);
+                    }
+                }
+            }
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(reader.no_reply(max_idle)),
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! The device's audit log (firmware built with `audit`): what it signed and
+//! which settings changed, as a hash chain (`signer_core::audit`).
+//!
+//! The auditor makes an X25519 key pair with `audit keygen` and has the
+//! device's operator register the public half with `audit register`. From
+//! then on `audit export` gets the log signed by the device key and sealed to
+//! the auditor, so the host carrying it can neither read nor alter it.
+//! `audit verify` opens an export with the auditor's secret key and checks
+//! the device's signature and the chain; `--since` checks it carries on from
+//! the head an earlier verify printed, so entries can't go missing between
+//! exports unnoticed.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use rand::RngCore;
+use signer_core::audit::{self, AuditError, Export};
+use signer_core::encoding::{hex_decode, hex_encode};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use std::path::PathBuf;
+
+use crate::device::Device;
+
+#[derive(Args, Debug)]
+pub struct KeygenArgs {
+    /// Where to write the secret key (hex); keep it off the signing host
+    #[arg(long)]
+    out: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct RegisterArgs {
+    /// The auditor's public key, as `audit keygen` printed it
+    key: String,
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// Where to write the sealed export
+    #[arg(long)]
+    out: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// A sealed export from `audit export`
+    file: PathBuf,
+    /// The auditor's secret key file from `audit keygen`
+    #[arg(long)]
+    key: PathBuf,
+    /// The device's public key
+    #[arg(long)]
+    pubkey: Pubkey,
+    /// Head printed by an earlier verify, which this export has to carry on
+    /// from
+    #[arg(long)]
+    since: Option<String>,
+}
+
+fn describe<E>(e: AuditError<E>) -> anyhow::Error {
+    match e {
+        AuditError::Unsealed => {
+            anyhow!("the export isn't sealed to this auditor key, or was altered on the way")
+        }
+        AuditError::Truncated => anyhow!("the export is truncated"),
+        AuditError::BadFormat => anyhow!("not an audit log export (or a newer format)"),
+        AuditError::Broken { seq } => anyhow!("the chain is broken at entry {}", seq),
+        AuditError::Corrupt | AuditError::Storage(_) => anyhow!("the audit log is unreadable"),
+    }
+}
+
+fn parse_hash(hex: &str) -> Result<[u8; 32]> {
+    hex_decode(hex.trim())
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("not a 32-byte hex value: {}", hex.trim()))
+}
+
+/// Makes an auditor key pair: the secret goes to `--out`, the public key is
+/// printed for `audit register`
+pub fn keygen(args: &KeygenArgs) -> Result<()> {
+    if args.out.exists() {
+        bail!("{} already exists", args.out.display());
+    }
+    let mut secret = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    std::fs::write(&args.out, hex_encode(&secret) + "\n")
+        .with_context(|| format!("write {}", args.out.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&args.out, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("restrict {}", args.out.display()))?;
+    }
+    println!("Secret key written to {}", args.out.display());
+    println!(
+        "Public key: {}",
+        bs58::encode(audit::auditor_public(&secret)).into_string()
+    );
+    Ok(())
+}
+
+/// Registers the auditor's public key on the device (after a BOOT press)
+pub fn register(device: &mut Device, args: &RegisterArgs) -> Result<()> {
+    let key: [u8; 32] = bs58::decode(args.key.trim())
+        .into_vec()
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("not a base58 X25519 public key: {}", args.key))?;
+    println!("Press BOOT on the device to register the auditor key...");
+    device.audit_register(&key)?;
+    println!("Audit exports are now sealed to {}", args.key.trim());
+    Ok(())
+}
+
+pub fn export(device: &mut Device, args: &ExportArgs) -> Result<()> {
+    let sealed = device.audit_export()?;
+    std::fs::write(&args.out, &sealed).with_context(|| format!("write {}", args.out.display()))?;
+    println!(
+        "Sealed audit log written to {} ({} bytes)",
+        args.out.display(),
+        sealed.len()
+    );
+    Ok(())
+}
+
+/// Opens `sealed` and checks it came from `pubkey` unaltered and, with
+/// `since`, carries on from that head
+fn check(
+    sealed: &[u8],
+    secret: &[u8; 32],
+    pubkey: &Pubkey,
+    since: Option<&[u8; 32]>,
+) -> Result<Export> {
+    let plaintext = audit::open::<()>(sealed, secret).map_err(describe)?;
+    let export = Export::parse::<()>(&plaintext).map_err(describe)?;
+    if export.device != pubkey.to_bytes() {
+        bail!(
+            "the export is from {}, not {}",
+            Pubkey::new_from_array(export.device),
+            pubkey
+        );
+    }
+    let message = export.signed_message::<()>().map_err(describe)?;
+    if !Signature::from(export.signature).verify(pubkey.as_ref(), &message) {
+        bail!("the export isn't signed by {}", pubkey);
+    }
+
+    if let Some(since) = since {
+        let mut hash = export.start;
+        let mut seen = hash == *since;
+        for entry in &export.entries {
+            hash = entry.chain(&hash);
+            seen |= hash == *since;
+        }
+        if !seen {
+            bail!(
+                "the chain doesn't pass through {}: entries were dropped, or the log \
+                 wrapped past it (it keeps {})",
+                hex_encode(since),
+                audit::CAPACITY
+            );
+        }
+    }
+    Ok(export)
+}
+
+pub fn verify(args: &VerifyArgs) -> Result<()> {
+    let sealed =
+        std::fs::read(&args.file).with_context(|| format!("read {}", args.file.display()))?;
+    let secret = std::fs::read_to_string(&args.key)
+        .with_context(|| format!("read {}", args.key.display()))?;
+    let secret = parse_hash(&secret).context("auditor secret key")?;
+    let since = args.since.as_deref().map(parse_hash).transpose()?;
+    let export = check(&sealed, &secret, &args.pubkey, since.as_ref())?;
+
+    println!("Audit log of {}, signed by the device", args.pubkey);
+    for entry in &export.entries {
+        let time = match entry.time {
+            0 => "-".to_string(),
+            time => time.to_string(),
+        };
+        let digest = if entry.digest == [0; 32] {
+            "-".to_string()
+        } else {
+            hex_encode(&entry.digest)
+        };
+        println!(
+            "{:>6}  {:>10}  {:<11}  {}",
+            entry.seq,
+            time,
+            entry.event.name(),
+            digest
+        );
+    }
+    if export.entries.is_empty() {
+        println!("No entries");
+    }
+    // Checked above, so this can't fail
+    let head = export.head::<()>().map_err(describe)?;
+    println!("Head: {}", hex_encode(&head));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use signer_core::audit::{Entry, Event};
+    use solana_sdk::signature::{keypair_from_seed, Keypair, Signer};
+
+    fn sealed(key: &Keypair, auditor: &[u8; 32], entries: Vec<Entry>) -> Vec<u8> {
+        let mut export = Export {
+            device: key.pubkey().to_bytes(),
+            start: [0; 32],
+            entries,
+            signature: [0; audit::SIGNATURE_LEN],
+        };
+        let message = export.signed_message::<()>().unwrap();
+        export.signature = key.sign_message(&message).into();
+        audit::seal(&export.to_bytes(), auditor, [5; 32])
+    }
+
+    fn entries(count: u32) -> Vec<Entry> {
+        (0..count)
+            .map(|seq| Entry {
+                seq,
+                time: 0,
+                event: Event::Signed,
+                digest: [seq as u8; 32],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn verifies_exports_from_the_device() {
+        let key = keypair_from_seed(&[7; 32]).unwrap();
+        let pubkey = key.pubkey();
+        let secret = [9u8; 32];
+        let auditor = audit::auditor_public(&secret);
+
+        let first = check(&sealed(&key, &auditor, entries(2)), &secret, &pubkey, None).unwrap();
+        let head = first.head::<()>().unwrap();
+        let later = sealed(&key, &auditor, entries(4));
+        check(&later, &secret, &pubkey, Some(&head)).unwrap();
+
+        assert!(check(&later, &[1; 32], &pubkey, None).is_err());
+        assert!(check(&later, &secret, &Pubkey::new_unique(), None).is_err());
+        assert!(check(&later, &secret, &pubkey, Some(&[3; 32])).is_err());
+    }
+
+    #[test]
+    fn rejects_exports_not_signed_by_the_device() {
+        let key = keypair_from_seed(&[7; 32]).unwrap();
+        let pubkey = key.pubkey();
+        let secret = [9u8; 32];
+        let auditor = audit::auditor_public(&secret);
+
+        // Anyone can seal to the auditor's public key; only the device signs
+        let forger = keypair_from_seed(&[8; 32]).unwrap();
+        let mut export = Export::parse::<()>(
+            &audit::open::<()>(&sealed(&forger, &auditor, entries(3)), &secret).unwrap(),
+        )
+        .unwrap();
+        export.device = pubkey.to_bytes();
+        let forged = audit::seal(&export.to_bytes(), &auditor, [6; 32]);
+        assert!(check(&forged, &secret, &pubkey, None).is_err());
+    }
+}
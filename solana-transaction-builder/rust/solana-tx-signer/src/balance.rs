@@ -0,0 +1,122 @@
+//! SOL and SPL token balances of an account, straight from the RPC node.
+
+use anyhow::{anyhow, Result};
+use solana_account_decoder::UiAccountData;
+use solana_client::{rpc_client::RpcClient, rpc_request::TokenAccountsFilter};
+use solana_sdk::{pubkey, pubkey::Pubkey};
+use spl_token_2022::{
+    extension::{BaseStateWithExtensions, StateWithExtensions},
+    state::Mint,
+};
+use spl_token_metadata_interface::state::TokenMetadata;
+use std::collections::HashMap;
+
+use crate::amount;
+use crate::config::Settings;
+
+/// Metaplex Token Metadata program, where classic SPL mints keep their symbol
+const METAPLEX_METADATA_PROGRAM: Pubkey = pubkey!("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
+
+struct TokenBalance {
+    account: String,
+    mint: String,
+    amount: String,
+    decimals: u64,
+}
+
+/// Prints the SOL balance of `owner` and every token account it holds under
+/// the Token and Token-2022 programs
+pub fn balance(settings: &Settings, owner: &Pubkey) -> Result<()> {
+    let client = settings.rpc_client();
+
+    let lamports = client
+        .get_balance(owner)
+        .map_err(|e| anyhow!("Failed to fetch balance of {}: {}", owner, e))?;
+    println!("Account: {}", owner);
+    println!("SOL:     {}", amount::format_sol(lamports));
+
+    let mut tokens = Vec::new();
+    for program_id in [spl_token::id(), spl_token_2022::id()] {
+        let accounts = client
+            .get_token_accounts_by_owner(owner, TokenAccountsFilter::ProgramId(program_id))
+            .map_err(|e| anyhow!("Failed to fetch token accounts of {}: {}", owner, e))?;
+        tokens.extend(accounts.into_iter().filter_map(|keyed| {
+            let UiAccountData::Json(parsed) = keyed.account.data else {
+                return None;
+            };
+            let info = &parsed.parsed["info"];
+            let amount = &info["tokenAmount"];
+            Some(TokenBalance {
+                account: keyed.pubkey,
+                mint: info["mint"].as_str()?.to_string(),
+                amount: amount["uiAmountString"].as_str()?.to_string(),
+                decimals: amount["decimals"].as_u64()?,
+            })
+        }));
+    }
+
+    if tokens.is_empty() {
+        println!("Tokens:  none");
+        return Ok(());
+    }
+    println!("Tokens:");
+    let mut symbols = HashMap::new();
+    for token in &tokens {
+        let symbol = symbols
+            .entry(token.mint.clone())
+            .or_insert_with(|| token_symbol(&client, &token.mint));
+        println!(
+            "  {:>20} {:<10} mint {} ({} decimals), account {}",
+            token.amount,
+            symbol.as_deref().unwrap_or("?"),
+            token.mint,
+            token.decimals,
+            token.account
+        );
+    }
+    Ok(())
+}
+
+/// The mint's symbol from its Token-2022 metadata extension, or else from its
+/// Metaplex metadata account. `None` when neither exists.
+pub fn token_symbol(client: &RpcClient, mint: &str) -> Option<String> {
+    let mint: Pubkey = mint.parse().ok()?;
+    let data = client.get_account_data(&mint).ok()?;
+    if let Ok(state) = StateWithExtensions::<Mint>::unpack(&data) {
+        if let Ok(metadata) = state.get_variable_len_extension::<TokenMetadata>() {
+            return non_empty(&metadata.symbol);
+        }
+    }
+
+    let (metadata_address, _) = Pubkey::find_program_address(
+        &[
+            b"metadata",
+            METAPLEX_METADATA_PROGRAM.as_ref(),
+            mint.as_ref(),
+        ],
+        &METAPLEX_METADATA_PROGRAM,
+    );
+    let data = client.get_account_data(&metadata_address).ok()?;
+    metaplex_symbol(&data)
+}
+
+/// Reads the symbol out of a Metaplex metadata account: a key byte, the update
+/// authority and mint, then the borsh-encoded name and symbol strings
+fn metaplex_symbol(data: &[u8]) -> Option<String> {
+    let mut rest = data.get(1 + 32 + 32..)?;
+    borsh_string(&mut rest)?;
+    non_empty(borsh_string(&mut rest)?)
+}
+
+fn borsh_string<'a>(rest: &mut &'a [u8]) -> Option<&'a str> {
+    let len = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+    let bytes = rest.get(4..4 + len)?;
+    *rest = &rest[4 + len..];
+    std::str::from_utf8(bytes).ok()
+}
+
+/// Metaplex pads strings with NULs to a fixed width
+fn non_empty(symbol: &str) -> Option<String> {
+    let symbol = symbol.trim_matches('\0').trim();
+    (!symbol.is_empty()).then(|| symbol.to_string())
+}
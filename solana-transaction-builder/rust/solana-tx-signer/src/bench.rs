@@ -0,0 +1,193 @@
+//! `bench`: how fast the host and the device talk, so transport changes
+//! (DMA UART, binary framing, a bigger inbox) can be measured rather than
+//! guessed at.
+//!
+//! Three measurements: the round trip of `GET_PUBKEY`, signing latency for
+//! off-chain messages of growing size, and throughput with several requests
+//! in flight at once (up to the firmware's inbox depth; lines past it come
+//! back as `ERROR:BUSY` and are counted). Signing waits for the BOOT button,
+//! so `--sign` expects it held down for the whole run.
+
+use anyhow::{anyhow, Result};
+use clap::Args;
+use signer_protocol::error::ErrorCode;
+use signer_protocol::response::{self, Response};
+use solana_sdk::offchain_message::{v0, OffchainMessage};
+use std::time::{Duration, Instant};
+
+use crate::device::{Device, REPLY_IDLE_SECS};
+use crate::policy;
+
+/// Off-chain message sizes signed with `--sign`, up to the largest one a
+/// ledger-compatible message can be
+const SIGN_SIZES: &[usize] = &[32, 256, 512, 1024, v0::OffchainMessage::MAX_LEN_LEDGER];
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Round trips per measurement
+    #[arg(long, default_value_t = 100)]
+    rounds: usize,
+    /// Requests in flight at once for the throughput runs; separate with
+    /// commas
+    #[arg(long, value_delimiter = ',', default_value = "1,2,4,8")]
+    batch: Vec<usize>,
+    /// Also measure signing; hold BOOT down for the whole run
+    #[arg(long)]
+    sign: bool,
+    /// Signatures per message size with --sign
+    #[arg(long, default_value_t = 5)]
+    sign_rounds: usize,
+}
+
+/// Runs the measurements and prints a table of each
+pub fn run(device: &mut Device, args: &BenchArgs) -> Result<()> {
+    if args.rounds == 0 {
+        return Err(anyhow!("--rounds must be at least 1"));
+    }
+    device.ping()?;
+
+    match device.benchmark() {
+        Ok(fields) => {
+            println!("On the device:");
+            for (key, value) in response::fields(&fields) {
+                println!("  {:<18} {}", key, value);
+            }
+        }
+        Err(e) => println!("On the device: not reported ({:#})", e),
+    }
+
+    println!("\n{:<22} {}", "ROUND TRIP", Stats::HEADER);
+    let mut samples = Vec::with_capacity(args.rounds);
+    for _ in 0..args.rounds {
+        let start = Instant::now();
+        device.pubkey()?;
+        samples.push(start.elapsed());
+    }
+    println!("{:<22} {}", "GET_PUBKEY", Stats::of(samples));
+
+    if args.sign {
+        println!("\n{:<22} {}", "SIGN (BOOT held)", Stats::HEADER);
+        for &size in SIGN_SIZES {
+            let label = format!("{} bytes", size);
+            match sign_samples(device, size, args.sign_rounds) {
+                Ok(samples) => println!("{:<22} {}", label, Stats::of(samples)),
+                Err(e) => println!("{:<22} {:#}", label, e),
+            }
+        }
+    }
+
+    println!(
+        "\n{:<22} {:>10} {:>10} {:>8}",
+        "THROUGHPUT", "REQ/S", "PER REQ", "BUSY"
+    );
+    for &batch in &args.batch {
+        if batch == 0 {
+            continue;
+        }
+        let (rate, per_request, busy) = throughput(device, batch, args.rounds)?;
+        println!(
+            "{:<22} {:>10.1} {:>8.2}ms {:>8}",
+            format!("{} in flight", batch),
+            rate,
+            millis(per_request),
+            busy
+        );
+    }
+    Ok(())
+}
+
+/// `rounds` signatures over an off-chain message of `size` bytes, each
+/// timed from sending SIGN to the signature
+fn sign_samples(device: &mut Device, size: usize, rounds: usize) -> Result<Vec<Duration>> {
+    let message = OffchainMessage::new(0, &vec![b'a'; size])
+        .map_err(|e| anyhow!("not a valid off-chain message: {}", e))?;
+    let payload = message
+        .serialize()
+        .map_err(|e| anyhow!("cannot sign this message: {}", e))?;
+    policy::preflight(device, &payload)?;
+    let mut samples = Vec::with_capacity(rounds);
+    for _ in 0..rounds {
+        let start = Instant::now();
+        device.sign(&payload)?;
+        samples.push(start.elapsed());
+    }
+    Ok(samples)
+}
+
+/// Sends `rounds` GET_PUBKEYs, `batch` at a time without waiting between
+/// them; returns requests answered per second, the mean time per request,
+/// and how many the device turned away as busy
+fn throughput(device: &mut Device, batch: usize, rounds: usize) -> Result<(f64, Duration, usize)> {
+    let line = b"GET_PUBKEY";
+    let mut answered = 0;
+    let mut busy = 0;
+    let start = Instant::now();
+    while answered + busy < rounds {
+        let in_flight = batch.min(rounds - answered - busy);
+        for _ in 0..in_flight {
+            device.send_raw(line)?;
+        }
+        for _ in 0..in_flight {
+            let reply = device.receive(REPLY_IDLE_SECS)?;
+            match Response::parse(&reply) {
+                Some(Response::Pubkey(_)) => answered += 1,
+                Some(Response::Error(ErrorCode::Busy)) => busy += 1,
+                _ => return Err(anyhow!("unexpected reply to GET_PUBKEY: {}", reply)),
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    let per_request = elapsed / answered.max(1) as u32;
+    Ok((answered as f64 / elapsed.as_secs_f64(), per_request, busy))
+}
+
+/// Percentiles of a set of timings
+struct Stats {
+    count: usize,
+    p50: Duration,
+    p90: Duration,
+    p99: Duration,
+    max: Duration,
+}
+
+impl Stats {
+    const HEADER: &'static str = "        N      P50      P90      P99      MAX";
+
+    fn of(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        Stats {
+            count: samples.len(),
+            p50: percentile(&samples, 50),
+            p90: percentile(&samples, 90),
+            p99: percentile(&samples, 99),
+            max: samples.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>9} {:>6.1}ms {:>6.1}ms {:>6.1}ms {:>6.1}ms",
+            self.count,
+            millis(self.p50),
+            millis(self.p90),
+            millis(self.p99),
+            millis(self.max)
+        )
+    }
+}
+
+/// Nearest-rank percentile of `sorted`
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (percent * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,348 @@
+//! Compressed NFT (Bubblegum) transfers out of the device's account.
+//!
+//! A compressed NFT is a leaf in a concurrent Merkle tree, not a token
+//! account, so it moves with Bubblegum's `Transfer`, which proves the leaf
+//! against the tree's current root. The leaf's hashes and the proof come from
+//! a DAS (Digital Asset Standard) RPC, `getAsset` and `getAssetProof`, which
+//! the configured RPC node has to support unless `--das-url` names one that
+//! does. The top of the proof the tree keeps on chain (its canopy) is left
+//! out of the transaction.
+
+use anyhow::{anyhow, bail, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    pubkey,
+    pubkey::Pubkey,
+    system_program,
+};
+use std::str::FromStr;
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::send;
+
+pub const BUBBLEGUM_PROGRAM_ID: Pubkey = pubkey!("BGUMAp9Gq7iTEuizy4pqaxsTyUCBK68MDfK752saRPUY");
+const NOOP_PROGRAM_ID: Pubkey = pubkey!("noopb9bkMVfRPU8AsbpTUg8AQkHtKwMYZiFUjNRtMmV");
+const ACCOUNT_COMPRESSION_PROGRAM_ID: Pubkey =
+    pubkey!("cmtDvXumGCrqC1Age74AVPhSRVXJMd8PJS2onf6ZZKA");
+
+/// Anchor discriminator of Bubblegum's `transfer`: SHA-256("global:transfer")
+pub const TRANSFER_DISCRIMINATOR: [u8; 8] = [163, 52, 200, 231, 140, 3, 69, 186];
+
+/// Account type and the V1 header of a concurrent Merkle tree account
+const TREE_HEADER_LEN: usize = 2 + 54;
+
+#[derive(Deserialize)]
+struct Asset {
+    content: Content,
+    #[serde(default)]
+    grouping: Vec<Group>,
+    compression: Compression,
+    ownership: Ownership,
+}
+
+#[derive(Deserialize)]
+struct Content {
+    metadata: Metadata,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    #[serde(default)]
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Group {
+    group_key: String,
+    group_value: String,
+}
+
+#[derive(Deserialize)]
+struct Compression {
+    compressed: bool,
+    data_hash: String,
+    creator_hash: String,
+    leaf_id: u64,
+    tree: String,
+}
+
+#[derive(Deserialize)]
+struct Ownership {
+    owner: String,
+    delegate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AssetProof {
+    root: String,
+    proof: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    message: String,
+}
+
+/// Calls a DAS method, whose params are an object with the asset's ID
+fn das<T: DeserializeOwned>(url: &str, method: &str, asset: &Pubkey) -> Result<T> {
+    let response: RpcResponse<T> = reqwest::blocking::Client::new()
+        .post(url)
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": { "id": asset.to_string() },
+        }))
+        .timeout(std::time::Duration::from_secs(15))
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| anyhow!("{} request to {} failed: {}", method, url, e))?
+        .json()
+        .map_err(|e| anyhow!("unexpected {} response: {}", method, e))?;
+    match (response.result, response.error) {
+        (Some(result), _) => Ok(result),
+        (None, Some(error)) => Err(anyhow!(
+            "{} failed: {} (does {} support DAS? see --das-url)",
+            method,
+            error.message,
+            url
+        )),
+        (None, None) => Err(anyhow!("{} returned nothing", method)),
+    }
+}
+
+fn key(s: &str) -> Result<Pubkey> {
+    Pubkey::from_str(s).map_err(|_| anyhow!("DAS returned a bad address: {}", s))
+}
+
+fn hash(s: &str) -> Result<[u8; 32]> {
+    bs58::decode(s.trim())
+        .into_vec()
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("DAS returned a bad hash: {}", s))
+}
+
+/// How many levels of the tree's proofs are stored on chain, from the size
+/// of the tree account
+fn canopy_depth(data_len: usize, max_depth: usize, max_buffer_size: usize) -> Result<usize> {
+    // Sequence number, active index and buffer size; then the change log
+    // (root, path, index and padding per entry) and the rightmost proof
+    // (path, leaf, index and padding)
+    let path_len = 32 * max_depth + 32 + 8;
+    let tree_len = 24 + max_buffer_size * path_len + path_len;
+    let canopy_len = data_len
+        .checked_sub(TREE_HEADER_LEN + tree_len)
+        .ok_or_else(|| anyhow!("not a concurrent Merkle tree account"))?;
+    // A canopy of depth d holds the 2^(d+1) - 2 nodes above the proof
+    let nodes = canopy_len / 32 + 2;
+    if !nodes.is_power_of_two() {
+        bail!("not a concurrent Merkle tree account");
+    }
+    Ok(nodes.trailing_zeros() as usize - 1)
+}
+
+/// The tree's canopy depth, from its account
+fn tree_canopy_depth(client: &RpcClient, tree: &Pubkey) -> Result<usize> {
+    let account = client
+        .get_account(tree)
+        .map_err(|e| anyhow!("Failed to fetch Merkle tree {}: {}", tree, e))?;
+    if account.owner != ACCOUNT_COMPRESSION_PROGRAM_ID {
+        bail!("{} is not a Merkle tree account", tree);
+    }
+    let u32_at = |offset: usize| {
+        account
+            .data
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+            .ok_or_else(|| anyhow!("{} is not a Merkle tree account", tree))
+    };
+    // After the account type and header version: max buffer size, max depth
+    canopy_depth(account.data.len(), u32_at(6)?, u32_at(2)?)
+}
+
+/// Bubblegum's `Transfer` of the leaf at `index` from `owner` to `to`
+#[allow(clippy::too_many_arguments)]
+fn transfer_instruction(
+    tree: &Pubkey,
+    owner: &Pubkey,
+    delegate: &Pubkey,
+    to: &Pubkey,
+    root: [u8; 32],
+    data_hash: [u8; 32],
+    creator_hash: [u8; 32],
+    index: u64,
+    proof: &[Pubkey],
+) -> Instruction {
+    let (tree_authority, _) = Pubkey::find_program_address(&[tree.as_ref()], &BUBBLEGUM_PROGRAM_ID);
+    let mut data = TRANSFER_DISCRIMINATOR.to_vec();
+    data.extend_from_slice(&root);
+    data.extend_from_slice(&data_hash);
+    data.extend_from_slice(&creator_hash);
+    // The leaf's nonce and its index in the tree are the same number
+    data.extend_from_slice(&index.to_le_bytes());
+    data.extend_from_slice(&(index as u32).to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(tree_authority, false),
+        AccountMeta::new_readonly(*owner, true),
+        AccountMeta::new_readonly(*delegate, false),
+        AccountMeta::new_readonly(*to, false),
+        AccountMeta::new(*tree, false),
+        AccountMeta::new_readonly(NOOP_PROGRAM_ID, false),
+        AccountMeta::new_readonly(ACCOUNT_COMPRESSION_PROGRAM_ID, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    accounts.extend(
+        proof
+            .iter()
+            .map(|node| AccountMeta::new_readonly(*node, false)),
+    );
+    Instruction::new_with_bytes(BUBBLEGUM_PROGRAM_ID, &data, accounts)
+}
+
+/// Sends the compressed NFT `asset` held by the device to the wallet `to`
+pub fn transfer(
+    device: &mut Device,
+    settings: &Settings,
+    asset_id: &Pubkey,
+    to: &Pubkey,
+    das_url: Option<&str>,
+    nonce_account: Option<&Pubkey>,
+) -> Result<()> {
+    if settings.dry_run {
+        bail!("A compressed NFT transfer needs its proof from a DAS RPC; drop --dry-run");
+    }
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let url = das_url.unwrap_or(&settings.rpc_url);
+
+    let asset: Asset = das(url, "getAsset", asset_id)?;
+    if !asset.compression.compressed {
+        bail!("{} isn't compressed; use `nft transfer`", asset_id);
+    }
+    let owner = key(&asset.ownership.owner)?;
+    if owner != device_key {
+        bail!("{} belongs to {}, not the device", asset_id, owner);
+    }
+    let delegate = match &asset.ownership.delegate {
+        Some(delegate) => key(delegate)?,
+        None => owner,
+    };
+    let tree = key(&asset.compression.tree)?;
+    let proof: AssetProof = das(url, "getAssetProof", asset_id)?;
+    let nodes = proof
+        .proof
+        .iter()
+        .map(|node| key(node))
+        .collect::<Result<Vec<_>>>()?;
+    let canopy = tree_canopy_depth(&client, &tree)?;
+    let needed = &nodes[..nodes.len().saturating_sub(canopy)];
+
+    let name = match asset.content.metadata.name.trim() {
+        "" => "cNFT",
+        name => name,
+    };
+    let collection = asset
+        .grouping
+        .iter()
+        .find(|group| group.group_key == "collection")
+        .map(|group| group.group_value.as_str());
+    match collection {
+        Some(collection) => println!(
+            "Sending {} (compressed, collection {}) from {} to {}",
+            name, collection, device_key, to
+        ),
+        None => println!(
+            "Sending {} (compressed, no collection) from {} to {}",
+            name, device_key, to
+        ),
+    }
+    println!(
+        "Leaf {} of tree {}, with {} proof nodes ({} kept on chain)",
+        asset.compression.leaf_id,
+        tree,
+        needed.len(),
+        nodes.len() - needed.len()
+    );
+
+    let instruction = transfer_instruction(
+        &tree,
+        &owner,
+        &delegate,
+        to,
+        hash(&proof.root)?,
+        hash(&asset.compression.data_hash)?,
+        hash(&asset.compression.creator_hash)?,
+        asset.compression.leaf_id,
+        needed,
+    );
+    let mut fee_payer = send::load_fee_payer(settings)?;
+    send::sign_and_submit(
+        &client,
+        settings,
+        device,
+        &device_key,
+        fee_payer.as_mut(),
+        nonce_account,
+        &[instruction],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::hash::hashv;
+
+    #[test]
+    fn discriminator_is_anchors() {
+        assert_eq!(
+            hashv(&[b"global:transfer"]).to_bytes()[..8],
+            TRANSFER_DISCRIMINATOR
+        );
+    }
+
+    #[test]
+    fn finds_the_canopy_depth() {
+        let (depth, buffer) = (14, 64);
+        let path_len = 32 * depth + 40;
+        let tree_len = TREE_HEADER_LEN + 24 + buffer * path_len + path_len;
+        assert_eq!(canopy_depth(tree_len, depth, buffer).unwrap(), 0);
+        // Depth 3: 2 + 4 + 8 nodes
+        assert_eq!(canopy_depth(tree_len + 14 * 32, depth, buffer).unwrap(), 3);
+        assert!(canopy_depth(tree_len + 5 * 32, depth, buffer).is_err());
+        assert!(canopy_depth(100, depth, buffer).is_err());
+    }
+
+    #[test]
+    fn builds_the_transfer() {
+        let tree = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let to = Pubkey::new_unique();
+        let proof = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let instruction = transfer_instruction(
+            &tree, &owner, &owner, &to, [1; 32], [2; 32], [3; 32], 7, &proof,
+        );
+        assert_eq!(instruction.data.len(), 8 + 3 * 32 + 8 + 4);
+        assert_eq!(
+            instruction.data[8 + 96..],
+            [7, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0]
+        );
+        assert_eq!(instruction.accounts.len(), 8 + 2);
+        assert!(instruction.accounts[1].is_signer);
+        assert!(instruction.accounts[4].is_writable);
+        assert_eq!(instruction.accounts[9].pubkey, proof[1]);
+    }
+}
@@ -0,0 +1,483 @@
+//! Layered settings: command-line flags, then `ESP32_SIGNER_*` environment
+//! variables (both handled by clap), then the config file, then defaults.
+
+use anyhow::{anyhow, Context, Result};
+use clap::Args;
+use serde::Deserialize;
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::network::{self, Profile, ProfileConfig};
+
+const DEFAULT_BAUD: u32 = 115_200;
+const DEFAULT_MAX_RETRIES: u32 = 2;
+
+/// Connection settings shared by every subcommand
+#[derive(Args, Debug)]
+pub struct ConfigArgs {
+    /// Serial port the ESP32 is attached to [default: auto-detect]
+    #[arg(short, long, global = true, env = "ESP32_SIGNER_PORT")]
+    port: Option<String>,
+
+    /// Signer to use, by label, pubkey, device ID or serial number (see
+    /// `devices`); takes precedence over --port
+    #[arg(short, long, global = true, env = "ESP32_SIGNER_DEVICE")]
+    device: Option<String>,
+
+    /// Baud rate [default: 115200]
+    #[arg(long, global = true, env = "ESP32_SIGNER_BAUD")]
+    baud: Option<u32>,
+
+    /// Network profile: mainnet-beta, devnet, testnet, localnet, or one
+    /// defined under [networks.<name>] in the config file [default: devnet]
+    #[arg(long, global = true, env = "ESP32_SIGNER_NETWORK")]
+    network: Option<String>,
+
+    /// Solana JSON-RPC endpoint, instead of the network profile's
+    #[arg(long, global = true, env = "ESP32_SIGNER_RPC_URL")]
+    rpc_url: Option<String>,
+
+    /// Go ahead with transfers the network profile wants confirmed, e.g.
+    /// more than 1 SOL on mainnet-beta
+    #[arg(long, global = true)]
+    yes: bool,
+
+    /// Keypair file that pays transaction fees instead of the device key
+    #[arg(long, global = true, env = "ESP32_SIGNER_FEE_PAYER")]
+    fee_payer: Option<PathBuf>,
+
+    /// Second ESP32 that pays transaction fees instead of the device key, by
+    /// label, pubkey, device ID or serial number (see `devices`)
+    #[arg(
+        long,
+        global = true,
+        env = "ESP32_SIGNER_FEE_PAYER_DEVICE",
+        conflicts_with = "fee_payer"
+    )]
+    fee_payer_device: Option<String>,
+
+    /// Commitment for blockhashes and confirmation (processed, confirmed,
+    /// finalized) [default: finalized]
+    #[arg(long, global = true, env = "ESP32_SIGNER_COMMITMENT")]
+    commitment: Option<String>,
+
+    /// Priority fee in micro-lamports per compute unit [default: recent fees
+    /// paid for the same accounts]
+    #[arg(long, global = true, env = "ESP32_SIGNER_PRIORITY_FEE")]
+    priority_fee: Option<u64>,
+
+    /// Compute unit limit [default: simulated usage plus a margin]
+    #[arg(long, global = true, env = "ESP32_SIGNER_CU_LIMIT")]
+    cu_limit: Option<u32>,
+
+    /// Send without the node's preflight simulation
+    #[arg(long, global = true, env = "ESP32_SIGNER_SKIP_PREFLIGHT")]
+    skip_preflight: bool,
+
+    /// Send even when simulating the signed transaction fails
+    #[arg(long, global = true, env = "ESP32_SIGNER_ALLOW_FAILED_SIMULATION")]
+    allow_failed_simulation: bool,
+
+    /// Retries for failed sends, and rebuilds (with a new signature) when the
+    /// blockhash expires unconfirmed [default: 2]
+    #[arg(long, global = true, env = "ESP32_SIGNER_MAX_RETRIES")]
+    max_retries: Option<u32>,
+
+    /// Lookup table transactions too big for a packet may load accounts
+    /// from (see `alt`); repeat or separate with commas
+    #[arg(
+        long = "lookup-table",
+        global = true,
+        env = "ESP32_SIGNER_LOOKUP_TABLES",
+        value_delimiter = ','
+    )]
+    lookup_tables: Vec<Pubkey>,
+
+    /// Show US dollar equivalents of amounts and fees before signing, using
+    /// `[fiat_prices]` from the config file and fetching the rest
+    #[arg(long, global = true, env = "ESP32_SIGNER_FIAT")]
+    fiat: bool,
+
+    /// Price API for --fiat [default: Jupiter's]
+    #[arg(long, global = true, env = "ESP32_SIGNER_PRICE_URL")]
+    price_url: Option<String>,
+
+    /// Build, describe and price transactions, then print them instead of
+    /// signing and sending; contacts neither the ESP32 nor the network
+    #[arg(long, global = true, env = "ESP32_SIGNER_DRY_RUN")]
+    dry_run: bool,
+
+    /// Check every signature the ESP32 returns with ed25519-dalek and
+    /// solana-sdk, and transaction messages as solana-sdk decodes them
+    #[arg(long, global = true, env = "ESP32_SIGNER_VERIFY")]
+    verify: bool,
+
+    /// Config file [default: ~/.config/esp32-signer/config.toml]
+    #[arg(long, global = true, env = "ESP32_SIGNER_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+/// `config.toml`; every key is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    port: Option<String>,
+    device: Option<String>,
+    baud: Option<u32>,
+    network: Option<String>,
+    rpc_url: Option<String>,
+    /// Network profiles by name (see `network`)
+    networks: HashMap<String, ProfileConfig>,
+    fee_payer: Option<PathBuf>,
+    fee_payer_device: Option<String>,
+    commitment: Option<String>,
+    priority_fee: Option<u64>,
+    cu_limit: Option<u32>,
+    skip_preflight: Option<bool>,
+    allow_failed_simulation: Option<bool>,
+    max_retries: Option<u32>,
+    lookup_tables: Option<Vec<String>>,
+    fiat: Option<bool>,
+    price_url: Option<String>,
+    verify: Option<bool>,
+    /// USD prices by mint address, or `SOL`, used instead of fetching them
+    fiat_prices: HashMap<String, f64>,
+    /// Notification text by event (see `notify::Templates`)
+    notify_templates: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Auto-detected when not set
+    pub port: Option<String>,
+    /// Registry selector, used instead of `port` when set
+    pub device: Option<String>,
+    pub baud: u32,
+    pub network: Profile,
+    /// The profiles the config file adds or changes
+    pub networks: HashMap<String, ProfileConfig>,
+    pub rpc_url: String,
+    /// `--yes`: transactions moving more than the network's threshold may go
+    /// ahead
+    pub yes: bool,
+    /// Keypair file that pays fees; never set together with
+    /// `fee_payer_device`
+    pub fee_payer: Option<PathBuf>,
+    /// Registry selector of a second ESP32 that pays fees
+    pub fee_payer_device: Option<String>,
+    pub commitment: CommitmentConfig,
+    /// Estimated when not set
+    pub priority_fee: Option<u64>,
+    /// Estimated when not set
+    pub cu_limit: Option<u32>,
+    pub skip_preflight: bool,
+    pub allow_failed_simulation: bool,
+    pub max_retries: u32,
+    pub lookup_tables: Vec<Pubkey>,
+    pub fiat: bool,
+    pub price_url: String,
+    /// Fixed USD prices by mint, SOL under the native mint
+    pub fiat_prices: HashMap<Pubkey, f64>,
+    pub dry_run: bool,
+    /// Cross-check device signatures (see `cross_verify`)
+    pub verify: bool,
+    /// Overrides of the default notification text, by event
+    pub notify_templates: HashMap<String, String>,
+}
+
+impl Settings {
+    pub fn load(args: &ConfigArgs) -> Result<Self> {
+        let file = match &args.config {
+            // An explicitly named file has to exist
+            Some(path) => read_file(path)?,
+            None => match default_path() {
+                Some(path) if path.exists() => read_file(&path)?,
+                _ => FileConfig::default(),
+            },
+        };
+
+        let commitment = match args.commitment.as_ref().or(file.commitment.as_ref()) {
+            Some(level) => CommitmentConfig::from_str(level)
+                .map_err(|_| anyhow!("unknown commitment level '{}'", level))?,
+            None => CommitmentConfig::finalized(),
+        };
+
+        // A fee payer given as a flag replaces either kind from the file
+        let (fee_payer, fee_payer_device) =
+            match (args.fee_payer.clone(), args.fee_payer_device.clone()) {
+                (None, None) => (file.fee_payer, file.fee_payer_device),
+                flags => flags,
+            };
+        if fee_payer.is_some() && fee_payer_device.is_some() {
+            return Err(anyhow!(
+                "fee_payer and fee_payer_device are both set; pick one"
+            ));
+        }
+
+        let lookup_tables = match (&args.lookup_tables[..], file.lookup_tables) {
+            ([], Some(tables)) => tables
+                .iter()
+                .map(|table| {
+                    Pubkey::from_str(table)
+                        .map_err(|e| anyhow!("Bad lookup table '{}' in the config: {}", table, e))
+                })
+                .collect::<Result<_>>()?,
+            (tables, _) => tables.to_vec(),
+        };
+
+        let network = network::resolve(
+            args.network
+                .as_deref()
+                .or(file.network.as_deref())
+                .unwrap_or(network::DEFAULT_NETWORK),
+            &file.networks,
+        )?;
+        // A network picked on the command line isn't overridden by the
+        // config file's rpc_url
+        let rpc_url = match (&args.rpc_url, &args.network) {
+            (Some(url), _) => url.clone(),
+            (None, Some(_)) => network.rpc_url.clone(),
+            (None, None) => file.rpc_url.unwrap_or_else(|| network.rpc_url.clone()),
+        };
+
+        let fiat_prices = file
+            .fiat_prices
+            .iter()
+            .map(|(mint, price)| {
+                let mint = if mint.eq_ignore_ascii_case("sol") {
+                    spl_token::native_mint::id()
+                } else {
+                    Pubkey::from_str(mint)
+                        .map_err(|e| anyhow!("Bad mint '{}' in fiat_prices: {}", mint, e))?
+                };
+                Ok((mint, *price))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Settings {
+            port: args.port.clone().or(file.port),
+            device: args.device.clone().or(file.device),
+            baud: args.baud.or(file.baud).unwrap_or(DEFAULT_BAUD),
+            network,
+            networks: file.networks,
+            rpc_url,
+            yes: args.yes,
+            fee_payer,
+            fee_payer_device,
+            commitment,
+            priority_fee: args.priority_fee.or(file.priority_fee),
+            cu_limit: args.cu_limit.or(file.cu_limit),
+            skip_preflight: args.skip_preflight || file.skip_preflight.unwrap_or(false),
+            allow_failed_simulation: args.allow_failed_simulation
+                || file.allow_failed_simulation.unwrap_or(false),
+            max_retries: args
+                .max_retries
+                .or(file.max_retries)
+                .unwrap_or(DEFAULT_MAX_RETRIES),
+            lookup_tables,
+            fiat: args.fiat || file.fiat.unwrap_or(false),
+            price_url: args
+                .price_url
+                .clone()
+                .or(file.price_url)
+                .unwrap_or_else(|| crate::fiat::DEFAULT_PRICE_URL.to_string()),
+            fiat_prices,
+            dry_run: args.dry_run,
+            verify: args.verify || file.verify.unwrap_or(false),
+            notify_templates: file.notify_templates,
+        })
+    }
+
+    /// A client for the configured node, or an offline one for a dry run
+    pub fn rpc_client(&self) -> RpcClient {
+        if self.dry_run {
+            crate::dry_run::client(self.commitment)
+        } else {
+            RpcClient::new_with_commitment(self.rpc_url.clone(), self.commitment)
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/esp32-signer/config.toml`, falling back to `~/.config`
+fn default_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml"))
+}
+
+/// Where the REPL keeps its command history, next to the config file
+pub fn history_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("history"))
+}
+
+/// Where the signers seen so far are remembered (see `registry`)
+pub fn registry_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("devices.toml"))
+}
+
+/// Where the encrypted address book is kept (see `contacts`)
+pub fn contacts_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("contacts.enc"))
+}
+
+/// This host's pairing key (see `pairing`)
+pub fn host_key_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("host-key.json"))
+}
+
+/// The session keys of the devices this host paired with (see `pairing`)
+pub fn paired_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("paired.toml"))
+}
+
+/// What each device was enrolled for 2FA with, never the secret (see `otp`)
+pub fn otp_enrollments_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("otp.toml"))
+}
+
+/// The last presence token accepted from each device (see `presence`)
+pub fn presence_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("presence.toml"))
+}
+
+fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("esp32-signer"))
+}
+
+fn read_file(path: &Path) -> Result<FileConfig> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("parse {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::io::Write;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        config: ConfigArgs,
+    }
+
+    /// Settings from `config.toml` holding `file`, and the flags `flags`
+    fn load(file: &str, flags: &[&str]) -> Result<Settings> {
+        let mut config = tempfile::NamedTempFile::new().unwrap();
+        config.write_all(file.as_bytes()).unwrap();
+        let path = config.path().to_str().unwrap();
+        let args = ["test", "--config", path]
+            .into_iter()
+            .chain(flags.iter().copied());
+        Settings::load(&Cli::try_parse_from(args).unwrap().config)
+    }
+
+    #[test]
+    fn flags_win_over_the_file_and_the_file_over_defaults() {
+        let defaults = load("", &[]).unwrap();
+        assert_eq!(defaults.baud, DEFAULT_BAUD);
+        assert_eq!(defaults.max_retries, DEFAULT_MAX_RETRIES);
+        assert_eq!(defaults.network.name, network::DEFAULT_NETWORK);
+        assert_eq!(defaults.commitment, CommitmentConfig::finalized());
+        assert!(!defaults.skip_preflight);
+
+        let file = r#"
+            port = "/dev/ttyUSB1"
+            baud = 9600
+            commitment = "confirmed"
+            max_retries = 5
+            skip_preflight = true
+        "#;
+        let from_file = load(file, &[]).unwrap();
+        assert_eq!(from_file.port.as_deref(), Some("/dev/ttyUSB1"));
+        assert_eq!(from_file.baud, 9600);
+        assert_eq!(from_file.commitment, CommitmentConfig::confirmed());
+        assert_eq!(from_file.max_retries, 5);
+        assert!(from_file.skip_preflight);
+
+        let flagged = load(
+            file,
+            &[
+                "--port",
+                "/dev/ttyACM0",
+                "--baud",
+                "460800",
+                "--commitment",
+                "processed",
+            ],
+        )
+        .unwrap();
+        assert_eq!(flagged.port.as_deref(), Some("/dev/ttyACM0"));
+        assert_eq!(flagged.baud, 460_800);
+        assert_eq!(flagged.commitment, CommitmentConfig::processed());
+        // A switch can't be turned off from the command line
+        assert!(flagged.skip_preflight);
+        assert_eq!(flagged.max_retries, 5);
+    }
+
+    #[test]
+    fn the_files_rpc_url_applies_without_a_network_flag() {
+        let file = r#"
+            network = "mainnet-beta"
+            rpc_url = "https://my-rpc.example.com"
+        "#;
+        let settings = load(file, &[]).unwrap();
+        assert_eq!(settings.network.name, "mainnet-beta");
+        assert_eq!(settings.rpc_url, "https://my-rpc.example.com");
+
+        let settings = load(file, &["--network", "devnet"]).unwrap();
+        assert_eq!(settings.rpc_url, "https://api.devnet.solana.com");
+
+        let settings = load(file, &["--network", "devnet", "--rpc-url", "http://x"]).unwrap();
+        assert_eq!(settings.network.name, "devnet");
+        assert_eq!(settings.rpc_url, "http://x");
+    }
+
+    #[test]
+    fn a_fee_payer_flag_replaces_either_kind_from_the_file() {
+        let file = r#"fee_payer_device = "treasury""#;
+        let settings = load(file, &[]).unwrap();
+        assert_eq!(settings.fee_payer_device.as_deref(), Some("treasury"));
+
+        let settings = load(file, &["--fee-payer", "payer.json"]).unwrap();
+        assert_eq!(settings.fee_payer, Some(PathBuf::from("payer.json")));
+        assert_eq!(settings.fee_payer_device, None);
+
+        let both = "fee_payer = \"payer.json\"\nfee_payer_device = \"treasury\"";
+        assert!(load(both, &[]).is_err());
+    }
+
+    #[test]
+    fn checks_what_the_file_holds() {
+        assert!(load("colour = \"blue\"", &[]).is_err());
+        assert!(load("commitment = \"eventually\"", &[]).is_err());
+        assert!(load("lookup_tables = [\"not-a-key\"]", &[]).is_err());
+        assert!(load("[fiat_prices]\nDOGE = 0.1", &[]).is_err());
+
+        let table = Pubkey::new_unique();
+        let file = format!(
+            "lookup_tables = [\"{}\"]\n[fiat_prices]\nsol = 150.0",
+            table
+        );
+        let settings = load(&file, &[]).unwrap();
+        assert_eq!(settings.lookup_tables, [table]);
+        assert_eq!(
+            settings.fiat_prices.get(&spl_token::native_mint::id()),
+            Some(&150.0)
+        );
+        let flagged = Pubkey::new_unique().to_string();
+        let settings = load(&file, &["--lookup-table", &flagged]).unwrap();
+        assert_eq!(settings.lookup_tables.len(), 1);
+        assert_eq!(settings.lookup_tables[0].to_string(), flagged);
+    }
+
+    #[test]
+    fn a_named_config_file_has_to_exist() {
+        let args = Cli::try_parse_from(["test", "--config", "/nonexistent/config.toml"]).unwrap();
+        assert!(Settings::load(&args.config).is_err());
+    }
+}
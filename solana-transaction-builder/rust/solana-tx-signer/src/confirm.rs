@@ -0,0 +1,261 @@
+//! What the user sees while the ESP32 waits for its BOOT button: what is
+//! being signed, a spinner with the time waited so far, and a plain account
+//! of why nothing was signed when the device doesn't sign.
+
+use anyhow::{anyhow, Result};
+use signer_core::token_labels::TokenLabel;
+use signer_protocol::transport::TransportError;
+use solana_sdk::{
+    message::VersionedMessage,
+    signature::Signature,
+    system_instruction::SystemInstruction,
+    system_program,
+    vote::{instruction::VoteInstruction, state::VoteAuthorize},
+};
+use spl_token_2022::{
+    extension::transfer_fee::instruction::TransferFeeInstruction, instruction::TokenInstruction,
+};
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::amount::{format_sol, format_units};
+use crate::cnft;
+use crate::contacts;
+use crate::device::{Device, DeviceError};
+use crate::fees;
+use crate::fiat::Prices;
+use crate::policy;
+
+const MEMO_PROGRAM_ID: &str = "MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr";
+const SPINNER: &[char] = &['|', '/', '-', '\\'];
+const SPINNER_TICK: Duration = Duration::from_millis(120);
+
+/// Shows `summary`, then has the device sign `message` once the BOOT button
+/// is pressed
+pub fn sign(device: &mut Device, message: &[u8], summary: &[String]) -> Result<Signature> {
+    sign_labeled(device, message, summary, &[])
+}
+
+/// [`sign`], with `labels` for the device to describe token transfers by
+pub fn sign_labeled(
+    device: &mut Device,
+    message: &[u8],
+    summary: &[String],
+    labels: &[TokenLabel],
+) -> Result<Signature> {
+    if device.is_offline() {
+        return Err(anyhow!(
+            "Signing needs the ESP32, which --dry-run doesn't contact"
+        ));
+    }
+    policy::preflight(device, message)?;
+    println!("About to sign:");
+    for line in summary {
+        println!("  {}", line);
+    }
+    if !labels.is_empty() {
+        let symbols: Vec<&str> = labels.iter().map(|label| label.symbol.as_str()).collect();
+        println!("The ESP32 is told these tokens are {}.", symbols.join(", "));
+    }
+    println!(
+        "Check this matches what you expect, then press the BOOT button on the ESP32 to sign."
+    );
+    println!("To refuse, don't press it: press Ctrl-C, or wait for the request to time out.");
+
+    with_spinner(|| device.sign_labeled(message, labels)).map_err(explain)
+}
+
+/// Runs `work`, with an elapsed-time spinner on stderr if that is a terminal
+fn with_spinner<T>(work: impl FnOnce() -> T) -> T {
+    let stderr = std::io::stderr();
+    if !stderr.is_terminal() {
+        return work();
+    }
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let start = Instant::now();
+            for frame in SPINNER.iter().cycle() {
+                if done.load(Ordering::Relaxed) {
+                    break;
+                }
+                let mut stderr = stderr.lock();
+                let _ = write!(
+                    stderr,
+                    "\r{} Waiting for the BOOT button... {}s ",
+                    frame,
+                    start.elapsed().as_secs()
+                );
+                let _ = stderr.flush();
+                drop(stderr);
+                std::thread::sleep(SPINNER_TICK);
+            }
+            // Clear the spinner line
+            let _ = write!(stderr.lock(), "\r\x1b[2K");
+        });
+        let result = work();
+        done.store(true, Ordering::Relaxed);
+        result
+    })
+}
+
+/// Turns device errors into what happened to the signing request
+fn explain(e: anyhow::Error) -> anyhow::Error {
+    if let Some(TransportError::NoReply { idle, .. }) = e.downcast_ref() {
+        return anyhow!(
+            "Nothing was signed: the BOOT button wasn't pressed within {} seconds. \
+             The ESP32 may still be waiting; its signature will be discarded, so \
+             press BOOT or reset it before the next request",
+            idle.as_secs()
+        );
+    }
+    if let Some(device_error) = e.downcast_ref::<DeviceError>() {
+        return anyhow!("Nothing was signed: {}", device_error);
+    }
+    e
+}
+
+/// One line per instruction of `message`, plus the fee payer, decoding SOL
+/// and token transfers and memos. With `prices` enabled (`--fiat`), amounts
+/// come with their dollar value and the maximum fee is added.
+pub fn describe_transaction(message: &VersionedMessage, prices: &Prices) -> Vec<String> {
+    let keys = message.static_account_keys();
+    let key = |index: u8| {
+        keys.get(usize::from(index))
+            .map_or_else(|| "(lookup table account)".to_string(), contacts::name)
+    };
+
+    let mut lines = vec![format!("Fee payer: {}", key(0))];
+    for instruction in message.instructions() {
+        let program = keys.get(usize::from(instruction.program_id_index));
+        let account = |n: usize| {
+            instruction
+                .accounts
+                .get(n)
+                .map_or_else(|| "?".to_string(), |index| key(*index))
+        };
+        // Token transfers name the mint second
+        let token_value = |amount: u64, decimals: u8| {
+            instruction
+                .accounts
+                .get(1)
+                .and_then(|index| keys.get(usize::from(*index)))
+                .map_or_else(String::new, |mint| prices.annotate(mint, amount, decimals))
+        };
+        let line = match program {
+            Some(program) if *program == system_program::id() => {
+                match bincode::deserialize::<SystemInstruction>(&instruction.data) {
+                    Ok(SystemInstruction::Transfer { lamports }) => format!(
+                        "Transfer {} SOL{} from {} to {}",
+                        format_sol(lamports),
+                        prices.annotate_sol(lamports),
+                        account(0),
+                        account(1)
+                    ),
+                    Ok(SystemInstruction::AdvanceNonceAccount) => {
+                        format!("Advance nonce account {}", account(0))
+                    }
+                    _ => "System program instruction".to_string(),
+                }
+            }
+            Some(program) if *program == spl_token::id() || *program == spl_token_2022::id() => {
+                match TokenInstruction::unpack(&instruction.data) {
+                    Ok(TokenInstruction::TransferChecked { amount, decimals }) => format!(
+                        "Transfer {}{} of mint {} from {} to {}",
+                        format_units(amount, decimals),
+                        token_value(amount, decimals),
+                        account(1),
+                        account(0),
+                        account(2)
+                    ),
+                    Ok(TokenInstruction::TransferFeeExtension(
+                        TransferFeeInstruction::TransferCheckedWithFee {
+                            amount,
+                            decimals,
+                            fee,
+                        },
+                    )) => format!(
+                        "Transfer {}{} of mint {} from {} to {}, of which {} is a transfer fee",
+                        format_units(amount, decimals),
+                        token_value(amount, decimals),
+                        account(1),
+                        account(0),
+                        account(2),
+                        format_units(fee, decimals)
+                    ),
+                    _ => format!("Token program instruction ({})", program),
+                }
+            }
+            Some(program) if *program == mpl_token_metadata::ID => {
+                match instruction.data.get(..2) {
+                    // Transfer, version 1
+                    Some([49, 0]) => format!(
+                        "Transfer NFT {} from {} to {} (Token Metadata)",
+                        account(4),
+                        account(1),
+                        account(3)
+                    ),
+                    _ => "Token Metadata instruction".to_string(),
+                }
+            }
+            Some(program) if *program == cnft::BUBBLEGUM_PROGRAM_ID => {
+                match instruction.data.get(..8) {
+                    Some(discriminator) if discriminator == cnft::TRANSFER_DISCRIMINATOR => {
+                        format!(
+                            "Transfer compressed NFT from {} to {} (tree {})",
+                            account(1),
+                            account(3),
+                            account(4)
+                        )
+                    }
+                    _ => "Bubblegum instruction".to_string(),
+                }
+            }
+            Some(program) if *program == solana_sdk::vote::program::id() => {
+                match bincode::deserialize::<VoteInstruction>(&instruction.data) {
+                    Ok(VoteInstruction::Withdraw(lamports)) => format!(
+                        "Withdraw {} SOL{} from vote account {} to {}",
+                        format_sol(lamports),
+                        prices.annotate_sol(lamports),
+                        account(0),
+                        account(1)
+                    ),
+                    Ok(VoteInstruction::Authorize(new, VoteAuthorize::Voter)) => {
+                        format!("Make {} the vote authority of {}", new, account(0))
+                    }
+                    Ok(VoteInstruction::Authorize(new, VoteAuthorize::Withdrawer)) => {
+                        format!("Make {} the withdraw authority of {}", new, account(0))
+                    }
+                    Ok(VoteInstruction::UpdateCommission(commission)) => format!(
+                        "Set the commission of vote account {} to {}%",
+                        account(0),
+                        commission
+                    ),
+                    _ => "Vote program instruction".to_string(),
+                }
+            }
+            Some(program) if *program == solana_sdk::compute_budget::id() => continue,
+            Some(program) if program.to_string() == MEMO_PROGRAM_ID => {
+                format!("Memo: {}", String::from_utf8_lossy(&instruction.data))
+            }
+            Some(program) => format!(
+                "Program {} ({} accounts, {} bytes of data)",
+                program,
+                instruction.accounts.len(),
+                instruction.data.len()
+            ),
+            None => "Instruction for a program from a lookup table".to_string(),
+        };
+        lines.push(line);
+    }
+    if prices.enabled() {
+        let fee = fees::max_fee(message);
+        lines.push(format!(
+            "Max fee: {} SOL{}",
+            format_sol(fee),
+            prices.annotate_sol(fee)
+        ));
+    }
+    lines
+}
@@ -0,0 +1,340 @@
+//! Address book: labels for recipients, so `transfer --to @alice` replaces a
+//! pasted address, and confirmations name the contacts a transaction pays.
+//!
+//! The book is kept in `contacts.enc` next to the config file, encrypted
+//! with AES-256-GCM-SIV under a key derived (Argon2id) from a passphrase.
+//! The passphrase comes from `ESP32_SIGNER_CONTACTS_PASSPHRASE` or is asked
+//! for on the terminal. Once opened, the book stays open until the program
+//! exits, so a REPL session asks only once.
+
+use aes_gcm_siv::{
+    aead::{Aead, NewAead},
+    Aes256GcmSiv, Key, Nonce,
+};
+use anyhow::{anyhow, Context, Result};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::config;
+
+/// File header, followed by the salt, the nonce and the ciphertext
+const MAGIC: &[u8; 4] = b"ESC1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PASSPHRASE_ENV: &str = "ESP32_SIGNER_CONTACTS_PASSPHRASE";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Contact {
+    label: String,
+    pubkey: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Book {
+    #[serde(default, rename = "contact")]
+    contacts: Vec<Contact>,
+}
+
+impl Book {
+    fn find(&self, label: &str) -> Option<usize> {
+        self.contacts
+            .iter()
+            .position(|contact| contact.label == label)
+    }
+}
+
+/// The opened book, with what is needed to save it again
+struct Opened {
+    book: Book,
+    salt: [u8; SALT_LEN],
+    key: [u8; 32],
+}
+
+static OPENED: Mutex<Option<Opened>> = Mutex::new(None);
+
+/// Runs `work` on the book, opening it first if this is the first use
+fn with_book<T>(work: impl FnOnce(&mut Opened) -> Result<T>) -> Result<T> {
+    let mut opened = OPENED.lock().unwrap_or_else(|e| e.into_inner());
+    if opened.is_none() {
+        *opened = Some(open()?);
+    }
+    work(opened.as_mut().expect("opened above"))
+}
+
+/// `contacts.enc` next to the config file
+fn path() -> Result<PathBuf> {
+    config::contacts_path().ok_or_else(|| anyhow!("No config directory (set HOME)"))
+}
+
+fn open() -> Result<Opened> {
+    let path = path()?;
+    if !path.exists() {
+        let salt: [u8; SALT_LEN] = rand::random();
+        let key = derive_key(&passphrase(true)?, &salt)?;
+        return Ok(Opened {
+            book: Book::default(),
+            salt,
+            key,
+        });
+    }
+    let data = std::fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+    unseal(&data, || passphrase(false)).with_context(|| format!("open {}", path.display()))
+}
+
+fn save(opened: &Opened) -> Result<()> {
+    let path = path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let data = seal(opened)?;
+    std::fs::write(&path, data).with_context(|| format!("write {}", path.display()))
+}
+
+/// The book as `contacts.enc` holds it, under a fresh nonce
+fn seal(opened: &Opened) -> Result<Vec<u8>> {
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let text = toml::to_string(&opened.book)?;
+    let ciphertext = Aes256GcmSiv::new(Key::from_slice(&opened.key))
+        .encrypt(Nonce::from_slice(&nonce), text.as_bytes())
+        .map_err(|_| anyhow!("Failed to encrypt the contacts"))?;
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&opened.salt);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+/// Opens what `seal` wrote; `passphrase` is only asked once the header
+/// checks out
+fn unseal(data: &[u8], passphrase: impl FnOnce() -> Result<String>) -> Result<Opened> {
+    let header = MAGIC.len() + SALT_LEN + NONCE_LEN;
+    if data.len() < header || !data.starts_with(MAGIC) {
+        return Err(anyhow!("not a contacts file"));
+    }
+    let (salt, rest) = data[MAGIC.len()..].split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let salt: [u8; SALT_LEN] = salt.try_into().expect("split at SALT_LEN");
+    let key = derive_key(&passphrase()?, &salt)?;
+    let plaintext = Aes256GcmSiv::new(Key::from_slice(&key))
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("wrong contacts passphrase, or the file is damaged"))?;
+    let text = String::from_utf8(plaintext).map_err(|_| anyhow!("the file is damaged"))?;
+    let book = toml::from_str(&text).context("parse the contacts")?;
+    Ok(Opened { book, salt, key })
+}
+
+/// The passphrase from the environment, or asked for; `new` asks twice
+fn passphrase(new: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "The contacts are encrypted; set {} to open them",
+            PASSPHRASE_ENV
+        ));
+    }
+    let prompt = if new {
+        "New contacts passphrase: "
+    } else {
+        "Contacts passphrase: "
+    };
+    let passphrase = rpassword::prompt_password(prompt)?;
+    if new {
+        if passphrase.is_empty() {
+            return Err(anyhow!("The contacts passphrase can't be empty"));
+        }
+        if rpassword::prompt_password("Repeat it: ")? != passphrase {
+            return Err(anyhow!("The passphrases don't match"));
+        }
+    }
+    Ok(passphrase)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32]> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Failed to derive the contacts key: {}", e))?;
+    Ok(key)
+}
+
+/// `label` without its optional `@`, checked to be usable after `--to @`
+fn check_label(label: &str) -> Result<&str> {
+    let label = label.strip_prefix('@').unwrap_or(label);
+    if label.is_empty()
+        || !label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(anyhow!(
+            "'{}' isn't a usable label: use letters, digits, '-', '_' and '.'",
+            label
+        ));
+    }
+    Ok(label)
+}
+
+pub fn add(label: &str, pubkey: &Pubkey) -> Result<()> {
+    let label = check_label(label)?;
+    with_book(|opened| {
+        if let Some(i) = opened.book.find(label) {
+            return Err(anyhow!(
+                "@{} is already {}; remove it first",
+                label,
+                opened.book.contacts[i].pubkey
+            ));
+        }
+        opened.book.contacts.push(Contact {
+            label: label.to_string(),
+            pubkey: pubkey.to_string(),
+        });
+        save(opened)?;
+        println!("@{} is {}", label, pubkey);
+        Ok(())
+    })
+}
+
+pub fn list() -> Result<()> {
+    with_book(|opened| {
+        if opened.book.contacts.is_empty() {
+            println!("No contacts; add one with `contacts add`");
+            return Ok(());
+        }
+        println!("{:<20} PUBKEY", "LABEL");
+        for contact in &opened.book.contacts {
+            println!("{:<20} {}", format!("@{}", contact.label), contact.pubkey);
+        }
+        Ok(())
+    })
+}
+
+pub fn remove(label: &str) -> Result<()> {
+    let label = check_label(label)?;
+    with_book(|opened| {
+        let i = opened
+            .book
+            .find(label)
+            .ok_or_else(|| anyhow!("No contact @{}", label))?;
+        let contact = opened.book.contacts.remove(i);
+        save(opened)?;
+        println!("Removed @{} ({})", contact.label, contact.pubkey);
+        Ok(())
+    })
+}
+
+/// An address, or `@label` looked up in the book
+pub fn resolve(recipient: &str) -> Result<Pubkey> {
+    let Some(label) = recipient.strip_prefix('@') else {
+        return Pubkey::from_str(recipient)
+            .map_err(|e| anyhow!("'{}' is not an address: {}", recipient, e));
+    };
+    with_book(|opened| {
+        let i = opened.book.find(label).ok_or_else(|| {
+            anyhow!(
+                "No contact @{}; `contacts list` shows the known ones",
+                label
+            )
+        })?;
+        Pubkey::from_str(&opened.book.contacts[i].pubkey)
+            .map_err(|e| anyhow!("Contact @{} has a bad address: {}", label, e))
+    })
+}
+
+/// `pubkey`, followed by its contact label when the book is open and has it
+pub fn name(pubkey: &Pubkey) -> String {
+    let address = pubkey.to_string();
+    let opened = OPENED.lock().unwrap_or_else(|e| e.into_inner());
+    match opened
+        .as_ref()
+        .and_then(|opened| opened.book.contacts.iter().find(|c| c.pubkey == address))
+    {
+        Some(contact) => format!("{} (@{})", address, contact.label),
+        None => address,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opened(passphrase: &str) -> Opened {
+        let salt = [7; SALT_LEN];
+        let mut book = Book::default();
+        book.contacts.push(Contact {
+            label: "alice".to_string(),
+            pubkey: Pubkey::new_unique().to_string(),
+        });
+        Opened {
+            book,
+            salt,
+            key: derive_key(passphrase, &salt).unwrap(),
+        }
+    }
+
+    #[test]
+    fn only_the_passphrase_opens_the_book() {
+        let original = opened("correct horse");
+        let data = seal(&original).unwrap();
+        assert!(data.starts_with(MAGIC));
+        assert!(!data
+            .windows(original.book.contacts[0].pubkey.len())
+            .any(|window| window == original.book.contacts[0].pubkey.as_bytes()));
+
+        let reopened = unseal(&data, || Ok("correct horse".to_string())).unwrap();
+        assert_eq!(reopened.salt, original.salt);
+        assert_eq!(reopened.key, original.key);
+        assert_eq!(reopened.book.find("alice"), Some(0));
+        assert_eq!(
+            reopened.book.contacts[0].pubkey,
+            original.book.contacts[0].pubkey
+        );
+
+        assert!(unseal(&data, || Ok("battery staple".to_string())).is_err());
+    }
+
+    #[test]
+    fn refuses_damaged_files_and_never_reuses_a_nonce() {
+        let original = opened("correct horse");
+        let data = seal(&original).unwrap();
+        let passphrase = || Ok("correct horse".to_string());
+
+        let mut flipped = data.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(unseal(&flipped, passphrase).is_err());
+        // Another salt derives another key
+        let mut salted = data.clone();
+        salted[MAGIC.len()] ^= 1;
+        assert!(unseal(&salted, passphrase).is_err());
+
+        // Not even asked for a passphrase when the header is wrong
+        let never = || -> Result<String> { panic!("asked for a passphrase") };
+        assert!(unseal(b"ESC1", never).is_err());
+        assert!(unseal(&[b"ESC2", &data[4..]].concat(), never).is_err());
+
+        let again = seal(&original).unwrap();
+        let nonces = MAGIC.len() + SALT_LEN..MAGIC.len() + SALT_LEN + NONCE_LEN;
+        assert_ne!(data[nonces.clone()], again[nonces]);
+    }
+
+    #[test]
+    fn labels_are_usable_after_to() {
+        assert_eq!(check_label("@alice").unwrap(), "alice");
+        assert_eq!(check_label("bob.sol_2-x").unwrap(), "bob.sol_2-x");
+        assert!(check_label("@").is_err());
+        assert!(check_label("two words").is_err());
+        assert!(check_label("a@b").is_err());
+    }
+
+    #[test]
+    fn addresses_resolve_without_the_book() {
+        let pubkey = Pubkey::new_unique();
+        assert_eq!(resolve(&pubkey.to_string()).unwrap(), pubkey);
+        assert!(resolve("not-an-address").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! `--verify`: every signature the ESP32 returns is checked again, against
+//! the exact bytes that were sent, by each implementation that will later
+//! look at it, so byte-order or serialization drift between the firmware and
+//! the rest of the Solana world shows up at signing time rather than as a
+//! transaction the cluster rejects.
+//!
+//! - ed25519-dalek in strict mode (the 1.x solana-sdk builds with; the
+//!   firmware signs with 2.x, which applies the same rules)
+//! - solana-sdk's `Signature::verify`
+//! - for transaction messages, solana-sdk's transaction verification, after
+//!   decoding the message with solana-sdk and checking it encodes back to
+//!   the same bytes and matches what the firmware's parser makes of them
+//! - the pubkey, as the firmware prints it and as solana-sdk does
+
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{PublicKey as DalekKey, Signature as DalekSignature};
+use signer_core::encoding::key_to_string;
+use signer_core::tx_introspection;
+use solana_sdk::{
+    message::VersionedMessage, pubkey::Pubkey, signature::Signature,
+    transaction::VersionedTransaction,
+};
+
+/// Checks `signature` over `message` by `pubkey` every way above; the error
+/// lists each check that failed
+pub fn check(pubkey: &Pubkey, message: &[u8], signature: &Signature) -> Result<()> {
+    let mut failures = Vec::new();
+
+    let firmware_encoding = key_to_string(&pubkey.to_bytes());
+    if firmware_encoding != pubkey.to_string() {
+        failures.push(format!(
+            "the firmware prints the pubkey as {}, solana-sdk as {}",
+            firmware_encoding, pubkey
+        ));
+    }
+    if let Err(e) = verify_dalek(pubkey, message, signature) {
+        failures.push(format!("ed25519-dalek rejects it: {}", e));
+    }
+    if !signature.verify(pubkey.as_ref(), message) {
+        failures.push("solana-sdk rejects it".to_string());
+    }
+    // Off-chain messages and other payloads aren't transactions
+    if let Ok(parsed) = tx_introspection::parse_message(message) {
+        if let Err(e) = verify_transaction(pubkey, message, &parsed, signature) {
+            failures.push(format!("as a transaction: {}", e));
+        }
+    }
+
+    if failures.is_empty() {
+        tracing::debug!("Signature cross-checked");
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Signature cross-check failed: {}",
+            failures.join("; ")
+        ))
+    }
+}
+
+fn verify_dalek(pubkey: &Pubkey, message: &[u8], signature: &Signature) -> Result<()> {
+    let key = DalekKey::from_bytes(pubkey.as_ref())?;
+    let signature = DalekSignature::try_from(signature.as_ref())?;
+    Ok(key.verify_strict(message, &signature)?)
+}
+
+/// Decodes `message` with solana-sdk, compares it with `parsed`, the
+/// firmware parser's view, and verifies `signature` in `pubkey`'s slot of
+/// the transaction
+fn verify_transaction(
+    pubkey: &Pubkey,
+    message: &[u8],
+    parsed: &tx_introspection::Message,
+    signature: &Signature,
+) -> Result<()> {
+    let decoded: VersionedMessage = bincode::deserialize(message)
+        .map_err(|e| anyhow!("solana-sdk can't decode the message: {}", e))?;
+    if decoded.serialize() != message {
+        return Err(anyhow!("solana-sdk encodes the message differently"));
+    }
+
+    let header = decoded.header();
+    let keys = decoded.static_account_keys();
+    let same_view = header.num_required_signatures == parsed.header.num_required_signatures
+        && header.num_readonly_signed_accounts == parsed.header.num_readonly_signed_accounts
+        && header.num_readonly_unsigned_accounts == parsed.header.num_readonly_unsigned_accounts
+        && keys
+            .iter()
+            .map(|key| key.to_bytes())
+            .eq(parsed.account_keys.iter().copied())
+        && decoded.recent_blockhash().to_bytes() == parsed.recent_blockhash
+        && decoded.instructions().len() == parsed.instructions.len();
+    if !same_view {
+        return Err(anyhow!(
+            "the firmware's parser and solana-sdk read the message differently"
+        ));
+    }
+
+    let signers = usize::from(header.num_required_signatures);
+    let slot = keys[..signers.min(keys.len())]
+        .iter()
+        .position(|key| key == pubkey)
+        .ok_or_else(|| anyhow!("{} isn't one of its signers", pubkey))?;
+    let mut transaction = VersionedTransaction {
+        signatures: vec![Signature::default(); signers],
+        message: decoded,
+    };
+    transaction.signatures[slot] = *signature;
+    if !transaction.verify_with_results()[slot] {
+        return Err(anyhow!("solana-sdk rejects the signature in slot {}", slot));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::{
+        hash::Hash,
+        instruction::{AccountMeta, Instruction},
+        message::{v0, Message},
+        offchain_message::OffchainMessage,
+        signature::{Keypair, Signer},
+        system_instruction,
+    };
+
+    /// What the device does with a message, standing in for it
+    fn device_sign(keypair: &Keypair, message: &[u8]) -> Signature {
+        keypair.sign_message(message)
+    }
+
+    fn legacy(payer: &Pubkey) -> Vec<u8> {
+        let to = Pubkey::new_unique();
+        let ix = system_instruction::transfer(payer, &to, 42);
+        Message::new_with_blockhash(&[ix], Some(payer), &Hash::new_unique()).serialize()
+    }
+
+    fn v0(payer: &Pubkey, other_signer: &Pubkey) -> Vec<u8> {
+        let ix = Instruction::new_with_bytes(
+            Pubkey::new_unique(),
+            &[1, 2, 3],
+            vec![
+                AccountMeta::new(*other_signer, true),
+                AccountMeta::new_readonly(Pubkey::new_unique(), false),
+            ],
+        );
+        let message = v0::Message::try_compile(payer, &[ix], &[], Hash::new_unique()).unwrap();
+        VersionedMessage::V0(message).serialize()
+    }
+
+    #[test]
+    fn accepts_device_signatures() {
+        let device = Keypair::new();
+        let fee_payer = Pubkey::new_unique();
+        let offchain = OffchainMessage::new(0, b"hello")
+            .unwrap()
+            .serialize()
+            .unwrap();
+        for message in [
+            legacy(&device.pubkey()),
+            v0(&device.pubkey(), &Pubkey::new_unique()),
+            // The device signs in the second slot
+            v0(&fee_payer, &device.pubkey()),
+            offchain,
+            b"not a transaction".to_vec(),
+        ] {
+            let signature = device_sign(&device, &message);
+            check(&device.pubkey(), &message, &signature).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_flipped_signature_bits() {
+        let device = Keypair::new();
+        let message = legacy(&device.pubkey());
+        let good = device_sign(&device, &message);
+        for bit in [0, 255, 256, 511] {
+            let mut bytes = <[u8; 64]>::try_from(good.as_ref()).unwrap();
+            bytes[bit / 8] ^= 1 << (bit % 8);
+            let err = check(&device.pubkey(), &message, &Signature::from(bytes)).unwrap_err();
+            assert!(err.to_string().contains("ed25519-dalek"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn rejects_byte_order_drift() {
+        let device = Keypair::new();
+        let message = legacy(&device.pubkey());
+        let signature = device_sign(&device, &message);
+
+        // R and S swapped, as a firmware serializing them the wrong way
+        // round would send
+        let mut swapped = [0u8; 64];
+        swapped[..32].copy_from_slice(&signature.as_ref()[32..]);
+        swapped[32..].copy_from_slice(&signature.as_ref()[..32]);
+        assert!(check(&device.pubkey(), &message, &Signature::from(swapped)).is_err());
+
+        // The pubkey reversed
+        let mut reversed = device.pubkey().to_bytes();
+        reversed.reverse();
+        assert!(check(&Pubkey::new_from_array(reversed), &message, &signature).is_err());
+    }
+
+    #[test]
+    fn rejects_a_different_message() {
+        let device = Keypair::new();
+        let message = legacy(&device.pubkey());
+        let signature = device_sign(&device, &message);
+        let mut other = message.clone();
+        let last = other.len() - 1;
+        other[last] ^= 1;
+        assert!(check(&device.pubkey(), &other, &signature).is_err());
+    }
+
+    #[test]
+    fn rejects_a_signer_outside_the_signer_slots() {
+        let device = Keypair::new();
+        // The device key is in the message, but not as a signer
+        let ix = system_instruction::transfer(&Pubkey::new_unique(), &device.pubkey(), 1);
+        let payer = Pubkey::new_unique();
+        let message =
+            Message::new_with_blockhash(&[ix], Some(&payer), &Hash::new_unique()).serialize();
+        let signature = device_sign(&device, &message);
+        let err = check(&device.pubkey(), &message, &signature).unwrap_err();
+        assert!(
+            err.to_string().contains("isn't one of its signers"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn firmware_and_sdk_print_pubkeys_alike() {
+        for _ in 0..100 {
+            let key = Pubkey::new_unique();
+            assert_eq!(key_to_string(&key.to_bytes()), key.to_string());
+        }
+        let zero = Pubkey::default();
+        assert_eq!(key_to_string(&zero.to_bytes()), zero.to_string());
+    }
+}
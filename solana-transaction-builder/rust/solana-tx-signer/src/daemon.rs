@@ -0,0 +1,329 @@
+//! `daemon`: `serve` for running under a process supervisor (systemd, runit,
+//! launchd). On top of the signing API it
+//!
+//! - waits for the device at startup instead of exiting, so a signer plugged
+//!   in late doesn't make the supervisor restart it in a loop
+//! - pings the device between requests and reconnects when it goes away,
+//!   turning signing requests away with 503 meanwhile; a different signer
+//!   showing up on the port is not taken for it
+//! - on SIGTERM or SIGINT, lets the request in progress finish, puts the
+//!   device into deep sleep (SHUTDOWN) and exits 0
+//! - serves `GET /health` on `--health-listen`, without a token:
+//!   `{"status": "running" | "paused" | "disconnected", "pubkey": ...,
+//!   "uptime_secs": ...}`, with 503 while disconnected
+//! - takes commands on a control socket, a Unix socket taking one command a
+//!   line:
+//!
+//! ```text
+//! pause       turn signing requests away (503) until `resume`
+//! resume      sign again
+//! status      answered with running, paused or disconnected
+//! ```
+//!
+//! `pause` and `resume` are answered with `ok`, unknown commands with
+//! `error: <why>`.
+
+use anyhow::{anyhow, Context, Result};
+use clap::Args;
+use serde_json::json;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use solana_sdk::pubkey::Pubkey;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::config::Settings;
+use crate::device::{Device, DeviceError};
+use crate::metrics::METRICS;
+use crate::network;
+use crate::notify;
+use crate::serve::{self, AVAILABILITY};
+
+/// How often the device is pinged while no request is using it
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// How often waits check whether to stop
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Longest wait between two attempts to reach the device
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Args, Debug)]
+pub struct DaemonArgs {
+    /// Loopback address and port to listen on
+    #[arg(long, default_value = "127.0.0.1:8423")]
+    listen: String,
+    /// Bearer token clients must send [default: random, printed at startup]
+    #[arg(long, env = "ESP32_SIGNER_API_TOKEN")]
+    token: Option<String>,
+    /// Page origin allowed to open the WebSocket, as for `serve`; repeatable
+    #[arg(long = "allow-origin")]
+    allowed_origins: Vec<String>,
+    /// Where to push pending requests and their outcomes, as for `serve`
+    #[arg(long, env = "ESP32_SIGNER_NOTIFY", value_delimiter = ',')]
+    notify: Vec<notify::Sink>,
+    /// Loopback address and port to serve Prometheus metrics on
+    #[arg(long)]
+    metrics_listen: Option<String>,
+    /// Loopback address and port to serve GET /health on (no token)
+    #[arg(long)]
+    health_listen: Option<String>,
+    /// Control socket for pause, resume and status
+    /// [default: $XDG_RUNTIME_DIR/esp32-signer.sock, or in the temp dir]
+    #[arg(long, value_name = "PATH")]
+    control: Option<PathBuf>,
+    /// Start with signing paused
+    #[arg(long)]
+    paused: bool,
+    /// Leave the device running on exit instead of putting it to sleep
+    #[arg(long)]
+    no_shutdown: bool,
+}
+
+/// Runs until SIGTERM or SIGINT; `connect` opens the device as any other
+/// command would, pairing check included
+pub fn run(
+    args: &DaemonArgs,
+    settings: &Settings,
+    connect: &(dyn Fn() -> Result<Device> + Sync),
+) -> Result<()> {
+    // Registered first, so a stop while waiting for the device is clean too
+    let signalled = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&signalled))
+            .context("install signal handlers")?;
+    }
+    let control = args.control.clone().unwrap_or_else(default_control);
+    // A socket left by an earlier run that was killed
+    let _ = std::fs::remove_file(&control);
+    let listener = UnixListener::bind(&control)
+        .with_context(|| format!("bind the control socket {}", control.display()))?;
+    std::fs::set_permissions(&control, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("restrict {}", control.display()))?;
+    AVAILABILITY.paused.store(args.paused, Ordering::Relaxed);
+    std::thread::spawn(move || serve_control(listener));
+    println!("Control socket: {}", control.display());
+
+    AVAILABILITY.disconnected.store(true, Ordering::Relaxed);
+    let Some(mut device) = reconnect(connect, None, &|| signalled.load(Ordering::Relaxed)) else {
+        let _ = std::fs::remove_file(&control);
+        println!("Stopped before the ESP32 was found");
+        return Ok(());
+    };
+    AVAILABILITY.disconnected.store(false, Ordering::Relaxed);
+    let pubkey = device.pubkey()?;
+
+    if let Some(listen) = &args.health_listen {
+        serve_health(listen, pubkey)?;
+    }
+    let options = serve::ServeOptions {
+        listen: &args.listen,
+        token: args.token.as_deref(),
+        allowed_origins: &args.allowed_origins,
+        notify: &args.notify,
+        templates: notify::Templates::new(&settings.notify_templates)?,
+        metrics_listen: args.metrics_listen.as_deref(),
+        threshold: network::Threshold::new(settings),
+    };
+    let device = Mutex::new(&mut device);
+    let failed = AtomicBool::new(false);
+    let done = || signalled.load(Ordering::Relaxed) || failed.load(Ordering::Relaxed);
+    let result = std::thread::scope(|scope| {
+        scope.spawn(|| watch(&device, pubkey, connect, &done));
+        scope.spawn(|| {
+            while !done() {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            if signalled.load(Ordering::Relaxed) {
+                stop(&device, args.no_shutdown, &control);
+            }
+        });
+        // Only returns when the API couldn't start
+        let result = serve::serve_shared(&device, &options);
+        failed.store(true, Ordering::Relaxed);
+        result
+    });
+    let _ = std::fs::remove_file(&control);
+    result
+}
+
+/// Lets the request in progress finish, puts the device to sleep and exits
+fn stop(device: &Mutex<&mut Device>, no_shutdown: bool, control: &Path) -> ! {
+    tracing::info!("Stopping");
+    AVAILABILITY.paused.store(true, Ordering::Relaxed);
+    let mut device = serve::lock(device);
+    if !no_shutdown && !AVAILABILITY.disconnected.load(Ordering::Relaxed) {
+        match device.shutdown() {
+            Ok(()) => println!("ESP32 is in deep sleep; it is safe to unplug"),
+            Err(e) => tracing::warn!("Couldn't put the ESP32 to sleep: {:#}", e),
+        }
+    }
+    let _ = std::fs::remove_file(control);
+    println!("Stopped");
+    std::process::exit(0)
+}
+
+/// Pings the device whenever no request holds it, and reconnects when it
+/// stops answering
+fn watch(
+    device: &Mutex<&mut Device>,
+    pubkey: Pubkey,
+    connect: &(dyn Fn() -> Result<Device> + Sync),
+    done: &dyn Fn() -> bool,
+) {
+    let mut last_ping = Instant::now();
+    while !done() {
+        std::thread::sleep(POLL_INTERVAL);
+        if last_ping.elapsed() < WATCH_INTERVAL {
+            continue;
+        }
+        let mut guard = match device.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            // A request is using it; a lost link shows once it is done
+            Err(TryLockError::WouldBlock) => continue,
+        };
+        last_ping = Instant::now();
+        match guard.ping() {
+            Ok(()) => continue,
+            // It answered, if not with PONG: the link is there
+            Err(e) if e.downcast_ref::<DeviceError>().is_some() => continue,
+            Err(e) => tracing::warn!("Lost the ESP32: {:#}", e),
+        }
+        // Closes the port, so the device can come back at the same path
+        **guard = Device::offline(pubkey);
+        AVAILABILITY.disconnected.store(true, Ordering::Relaxed);
+        drop(guard);
+
+        let Some(fresh) = reconnect(connect, Some(pubkey), done) else {
+            return;
+        };
+        **serve::lock(device) = fresh;
+        AVAILABILITY.disconnected.store(false, Ordering::Relaxed);
+        METRICS.reconnected();
+        tracing::info!("Reconnected to the ESP32");
+    }
+}
+
+/// Tries `connect` with a growing delay until it gives the device with
+/// `pubkey` (any signer when `None`), or `done` says to give up
+fn reconnect(
+    connect: &(dyn Fn() -> Result<Device> + Sync),
+    pubkey: Option<Pubkey>,
+    done: &dyn Fn() -> bool,
+) -> Option<Device> {
+    let mut delay = Duration::from_secs(1);
+    let mut last_error = String::new();
+    loop {
+        let attempt = connect().and_then(|mut device| {
+            let found = device.pubkey()?;
+            match pubkey {
+                Some(expected) if found != expected => Err(anyhow!(
+                    "{} is attached, not {}; waiting for the right signer",
+                    found,
+                    expected
+                )),
+                _ => Ok(device),
+            }
+        });
+        match attempt {
+            Ok(device) => return Some(device),
+            Err(e) => {
+                // Said once, not every few seconds
+                let error = format!("{:#}", e);
+                if error != last_error {
+                    tracing::warn!("Waiting for the ESP32: {}", error);
+                    last_error = error;
+                }
+            }
+        }
+        let deadline = Instant::now() + delay;
+        while Instant::now() < deadline {
+            if done() {
+                return None;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+/// Takes control socket connections for as long as the daemon runs
+fn serve_control(listener: UnixListener) {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        std::thread::spawn(move || {
+            let Ok(mut writer) = stream.try_clone() else {
+                return;
+            };
+            for line in BufReader::new(stream).lines() {
+                let Ok(line) = line else {
+                    return;
+                };
+                let reply = match line.trim() {
+                    "pause" => {
+                        AVAILABILITY.paused.store(true, Ordering::Relaxed);
+                        tracing::info!("Signing paused");
+                        "ok".to_string()
+                    }
+                    "resume" => {
+                        AVAILABILITY.paused.store(false, Ordering::Relaxed);
+                        tracing::info!("Signing resumed");
+                        "ok".to_string()
+                    }
+                    "status" => AVAILABILITY.state().to_string(),
+                    "" => continue,
+                    other => format!("error: unknown control command {:?}", other),
+                };
+                if writeln!(writer, "{}", reply).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Serves `/health` on `listen` (a loopback address) from a thread of its
+/// own
+fn serve_health(listen: &str, pubkey: Pubkey) -> Result<()> {
+    let address = serve::loopback_address(listen)?;
+    let server =
+        Server::http(address).map_err(|e| anyhow!("Failed to listen on {}: {}", address, e))?;
+    println!("Health on http://{}/health", address);
+    let started = Instant::now();
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            if (request.method(), request.url()) != (&Method::Get, "/health") {
+                let _ = request.respond(Response::empty(404));
+                continue;
+            }
+            let state = AVAILABILITY.state();
+            let body = json!({
+                "status": state,
+                "pubkey": pubkey.to_string(),
+                "uptime_secs": started.elapsed().as_secs(),
+            });
+            let content_type = Header::from_bytes("Content-Type", "application/json")
+                .expect("static header is valid");
+            let status = if state == "disconnected" { 503 } else { 200 };
+            let _ = request.respond(
+                Response::from_string(body.to_string())
+                    .with_status_code(status)
+                    .with_header(content_type),
+            );
+        }
+    });
+    Ok(())
+}
+
+fn default_control() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("esp32-signer.sock")
+}
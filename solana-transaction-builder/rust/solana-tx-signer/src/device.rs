@@ -0,0 +1,865 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use serialport::{SerialPort, SerialPortInfo, SerialPortType};
+use signer_core::policy::Policy;
+use signer_core::token_labels::{self, TokenLabel};
+use signer_protocol::command::Command;
+use signer_protocol::error::ErrorCode;
+use signer_protocol::response::{self, Response};
+use signer_protocol::transport::Transport;
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cross_verify;
+use crate::faults::{self, Faulty};
+use crate::recording::{Recorded, Replay};
+
+/// Seconds without a byte before a plain command is given up on
+pub const REPLY_IDLE_SECS: u32 = 10;
+/// Commands that wait for the BOOT button get a human-sized timeout
+pub const BUTTON_IDLE_SECS: u32 = 120;
+/// How long a candidate port gets to answer GET_INFO during auto-detection
+const PROBE_IDLE_SECS: u32 = 2;
+/// Read timeout of the port: how often the idle deadline gets checked
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Protocol versions (`proto=` in GET_INFO) this tool speaks
+const SUPPORTED_PROTOCOLS: RangeInclusive<u32> = 1..=signer_protocol::VERSION;
+
+/// USB-UART bridges found on ESP32 dev boards, plus the ESP32-C3/S3 built-in
+/// USB Serial/JTAG controller
+const KNOWN_USB_IDS: &[(u16, u16)] = &[
+    (0x10c4, 0xea60), // Silicon Labs CP210x
+    (0x1a86, 0x7523), // WCH CH340
+    (0x1a86, 0x55d4), // WCH CH9102
+    (0x0403, 0x6001), // FTDI FT232R
+    (0x0403, 0x6015), // FTDI FT231X
+    (0x303a, 0x1001), // Espressif USB Serial/JTAG
+];
+
+/// A connection to the ESP32 signer over its serial protocol
+pub struct Device {
+    link: Link,
+    /// Proven to be the device this host paired with (see `pairing`)
+    paired: bool,
+    /// Check every signature again on the host (`--verify`)
+    cross_verify: bool,
+}
+
+/// The serial port, as recorded and fault-injected
+type SerialLink = Transport<Recorded<Faulty<Box<dyn SerialPort>>>>;
+
+enum Link {
+    /// Boxed, as the recording and fault layers make it large
+    Serial(Box<SerialLink>),
+    /// A recorded session, or the null device, standing in for the device
+    Replay(Transport<Replay>),
+    /// A dry run's stand-in, which only knows the device's pubkey
+    Offline(Pubkey),
+}
+
+/// An `ERROR:` reply
+#[derive(Debug)]
+pub struct DeviceError {
+    pub code: ErrorCode,
+    pub detail: Option<String>,
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let detail = self.detail.as_deref().unwrap_or("");
+        match (self.code, detail) {
+            (ErrorCode::Locked, _) => f.write_str(
+                "the ESP32 is locked; open the signing window with `otp unlock <CODE>` first",
+            ),
+            (ErrorCode::Policy, "UNAVAILABLE") => f.write_str(
+                "the ESP32 has no readable signing policy and refuses to sign until one is set",
+            ),
+            (ErrorCode::Policy, reason) => {
+                write!(f, "refused by the ESP32's signing policy: {}", reason)
+            }
+            (ErrorCode::Busy, _) => {
+                f.write_str("the ESP32 is busy with other requests; try again shortly")
+            }
+            (ErrorCode::OtpDisabled, _) => {
+                f.write_str("this firmware was built without 2FA (the `twofa` feature)")
+            }
+            (ErrorCode::OtaDisabled, _) => {
+                f.write_str("this firmware was built without updates (the `ota` feature)")
+            }
+            (ErrorCode::SdDisabled, _) => {
+                f.write_str("this firmware was built without SD backups (the `sd-backup` feature)")
+            }
+            (ErrorCode::AuditDisabled, _) => {
+                f.write_str("this firmware was built without the audit log (the `audit` feature)")
+            }
+            (ErrorCode::Audit, "NO_AUDITOR") => f.write_str(
+                "no auditor key is registered on the ESP32; register one with `audit register`",
+            ),
+            (ErrorCode::UnprotectedDevice, _) => f.write_str(
+                "the ESP32's policy is in production mode and its flash encryption or secure \
+                 boot is off, so it won't use its key (see `info`)",
+            ),
+            (ErrorCode::Restore, "BAD_CREDENTIALS") => f.write_str(
+                "the backup didn't open with that PIN and passphrase (or it was tampered with)",
+            ),
+            (ErrorCode::OtpBadCode, _) => f.write_str("wrong or reused OTP code"),
+            (ErrorCode::NotPaired, _) => {
+                f.write_str("the ESP32 isn't paired with a host; pair it with this one with `pair`")
+            }
+            (ErrorCode::Unauthenticated, _) => f.write_str(
+                "this host hasn't proven it is the ESP32's paired host; `pair` it if it isn't",
+            ),
+            (ErrorCode::Auth, _) => f.write_str(
+                "the ESP32 didn't accept this host's proof; it may be paired with another host",
+            ),
+            (ErrorCode::Labels, detail) => write!(
+                f,
+                "the ESP32 refused the token labels ({}): they don't match the transaction",
+                detail
+            ),
+            (ErrorCode::UnknownCommand, _) => {
+                f.write_str("the firmware doesn't know this command; update it with `ota`")
+            }
+            (code, "") => write!(f, "ESP32 error {}", code),
+            (code, detail) => write!(f, "ESP32 error {}: {}", code, detail),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+/// What GET_INFO says about the firmware
+pub struct FirmwareInfo {
+    pub version: String,
+    /// 0 for firmware from before the field existed
+    pub protocol: u32,
+}
+
+/// A freshly generated OTP secret, for the authenticator app
+pub struct OtpSecret {
+    /// Base32
+    pub secret: String,
+    pub digits: u32,
+    pub period: u64,
+}
+
+/// Where 2FA stands on the device
+pub struct OtpStatus {
+    pub enrolled: bool,
+    /// Device time until which signing is unlocked
+    pub unlocked_until: u64,
+    /// The device's clock
+    pub now: u64,
+}
+
+impl Device {
+    pub fn open(path: &str, baud: u32) -> Result<Self> {
+        let path = port_path(path);
+        let mut port = serialport::new(&path, baud)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to open serial port '{}': {}{}",
+                    path,
+                    e,
+                    open_hint(&e)
+                )
+            })?;
+        // Dev boards wire DTR and RTS to EN and GPIO0 for auto-reset; with
+        // both released the ESP32 runs normally. RTS goes first: RTS alone
+        // holds EN low, i.e. in reset, while DTR alone is harmless. Ports
+        // without modem lines (e.g. pseudo-terminals) refuse, which is fine.
+        let _ = port.write_request_to_send(false);
+        let _ = port.write_data_terminal_ready(false);
+        Ok(Device {
+            link: Link::Serial(Box::new(Transport::new(Recorded::new(Faulty::new(
+                port,
+                faults::schedule(),
+            ))))),
+            paired: false,
+            cross_verify: false,
+        })
+    }
+
+    /// A stand-in for the device with `pubkey`, for `--dry-run`: `pubkey()`
+    /// answers, every command fails
+    pub fn offline(pubkey: Pubkey) -> Self {
+        Device {
+            link: Link::Offline(pubkey),
+            paired: false,
+            cross_verify: false,
+        }
+    }
+
+    /// A stand-in for the device that answers from `replay`
+    pub fn replay(replay: Replay) -> Self {
+        Device {
+            link: Link::Replay(Transport::new(replay)),
+            paired: false,
+            cross_verify: false,
+        }
+    }
+
+    /// Opens `path`, or finds the signer among the attached serial ports
+    pub fn connect(path: Option<&str>, baud: u32) -> Result<Self> {
+        match path {
+            Some(path) => Self::open(path, baud),
+            None => Self::detect(baud),
+        }
+    }
+
+    /// Tries likely USB serial ports first, and takes the first one that
+    /// answers GET_INFO like the signer firmware does
+    pub fn detect(baud: u32) -> Result<Self> {
+        let ports = candidate_ports()?;
+        for path in &ports {
+            if let Some((device, fw)) = Self::probe(path, baud) {
+                tracing::info!("Using ESP32 signer on {} ({})", path, fw);
+                return Ok(device);
+            }
+        }
+        Err(anyhow!(
+            "No ESP32 signer found on {} candidate serial port(s); pass --port",
+            ports.len()
+        ))
+    }
+
+    /// Every attached signer, with the port it is on
+    pub fn detect_all(baud: u32) -> Result<Vec<(String, Self)>> {
+        Ok(candidate_ports()?
+            .into_iter()
+            .filter_map(|path| Self::probe(&path, baud).map(|(device, _)| (path, device)))
+            .collect())
+    }
+
+    /// Opens `path` if the signer firmware answers GET_INFO there, returning
+    /// its `fw=` field too
+    fn probe(path: &str, baud: u32) -> Option<(Self, String)> {
+        Self::try_probe(path, baud).ok().flatten()
+    }
+
+    /// Like `probe`, but tells a port that can't be opened (`Err`) from one
+    /// where nothing answers like the signer (`None`)
+    pub fn try_probe(path: &str, baud: u32) -> Result<Option<(Self, String)>> {
+        let mut device = Self::open(path, baud)?;
+        let info = device.request(
+            Command::GetInfo,
+            PROBE_IDLE_SECS,
+            |response| match response {
+                Response::Info(fields) => Some(fields.to_string()),
+                _ => None,
+            },
+        );
+        Ok(info.ok().map(|info| {
+            let fw = info.split(';').next().unwrap_or_default().to_string();
+            (device, fw)
+        }))
+    }
+
+    /// Whether this is a dry run's stand-in
+    pub fn is_offline(&self) -> bool {
+        matches!(self.link, Link::Offline(_))
+    }
+
+    /// Whether the device has proven it is the one this host paired with
+    pub fn is_paired(&self) -> bool {
+        self.paired
+    }
+
+    /// Records that the device proved it is the paired one
+    pub fn set_paired(&mut self) {
+        self.paired = true;
+    }
+
+    /// Has every later signature cross-checked (see `cross_verify`)
+    pub fn set_cross_verify(&mut self) {
+        self.cross_verify = true;
+    }
+
+    /// Sends one command line and returns the trimmed response line, past
+    /// any boot log or other noise. `max_idle_secs` bounds how long we wait
+    /// with no bytes arriving; failures are `TransportError`s.
+    pub fn command(&mut self, command: Command, max_idle_secs: u32) -> Result<String> {
+        let max_idle = Duration::from_secs(max_idle_secs.into());
+        match &mut self.link {
+            Link::Serial(transport) => Ok(transport.exchange(&command, max_idle)?),
+            Link::Replay(transport) => Ok(transport.exchange(&command, max_idle)?),
+            Link::Offline(_) => Err(anyhow!(
+                "{} needs the ESP32, which --dry-run doesn't contact",
+                command.name()
+            )),
+        }
+    }
+
+    /// Writes `bytes` as one line, as they are, without waiting for a reply
+    /// (see `Transport::send_raw`)
+    pub fn send_raw(&mut self, bytes: &[u8]) -> Result<()> {
+        match &mut self.link {
+            Link::Serial(transport) => Ok(transport.send_raw(bytes)?),
+            Link::Replay(transport) => Ok(transport.send_raw(bytes)?),
+            Link::Offline(_) => Err(anyhow!("--dry-run doesn't contact the ESP32")),
+        }
+    }
+
+    /// The next reply line, e.g. to a line from `send_raw`
+    pub fn receive(&mut self, max_idle_secs: u32) -> Result<String> {
+        let max_idle = Duration::from_secs(max_idle_secs.into());
+        match &mut self.link {
+            Link::Serial(transport) => Ok(transport.receive(max_idle)?),
+            Link::Replay(transport) => Ok(transport.receive(max_idle)?),
+            Link::Offline(_) => Err(anyhow!("--dry-run doesn't contact the ESP32")),
+        }
+    }
+
+    /// The next presence token the device sends (the whole `PRESENCE:`
+    /// line), from a press after this call
+    pub fn wait_presence(&mut self, max_idle_secs: u32) -> Result<String> {
+        let max_idle = Duration::from_secs(max_idle_secs.into());
+        match &mut self.link {
+            Link::Serial(transport) => Ok(transport.wait_presence(max_idle)?),
+            Link::Replay(transport) => Ok(transport.wait_presence(max_idle)?),
+            Link::Offline(_) => Err(anyhow!("--dry-run doesn't contact the ESP32")),
+        }
+    }
+
+    /// Sends `command` and hands the parsed reply to `accept`, which picks
+    /// out the expected response. `ERROR:` replies become errors.
+    pub fn request<T>(
+        &mut self,
+        command: Command,
+        max_idle_secs: u32,
+        accept: impl FnOnce(Response) -> Option<T>,
+    ) -> Result<T> {
+        let line = self.command(command, max_idle_secs)?;
+        reply(&line, accept)
+    }
+
+    /// The port's path and baud rate, to reopen it elsewhere
+    pub fn port_settings(&self) -> Result<(String, u32)> {
+        let port = match &self.link {
+            Link::Serial(transport) => transport.port().get_ref().get_ref(),
+            Link::Replay(_) => return Err(anyhow!("A replay has no serial port")),
+            Link::Offline(_) => return Err(anyhow!("A dry run has no serial port")),
+        };
+        let path = port
+            .name()
+            .ok_or_else(|| anyhow!("Serial port has no name"))?;
+        Ok((path, port.baud_rate()?))
+    }
+
+    /// Checks the device answers at all
+    pub fn ping(&mut self) -> Result<()> {
+        self.request(Command::Ping, REPLY_IDLE_SECS, |response| {
+            matches!(response, Response::Pong).then_some(())
+        })
+    }
+
+    pub fn pubkey(&mut self) -> Result<Pubkey> {
+        if let Link::Offline(pubkey) = self.link {
+            return Ok(pubkey);
+        }
+        let pubkey = self.request(
+            Command::GetPubkey,
+            REPLY_IDLE_SECS,
+            |response| match response {
+                Response::Pubkey(pubkey) => Some(pubkey.to_string()),
+                _ => None,
+            },
+        )?;
+        Pubkey::from_str(&pubkey).map_err(|e| anyhow!("Failed to parse public key: {}", e))
+    }
+
+    pub fn firmware_info(&mut self) -> Result<FirmwareInfo> {
+        self.request(Command::GetInfo, REPLY_IDLE_SECS, |reply| match reply {
+            Response::Info(payload) => {
+                let mut info = FirmwareInfo {
+                    version: "unknown".to_string(),
+                    protocol: 0,
+                };
+                for (key, value) in response::fields(payload) {
+                    match key {
+                        "fw" => info.version = value.to_string(),
+                        "proto" => info.protocol = value.parse().ok()?,
+                        _ => {}
+                    }
+                }
+                Some(info)
+            }
+            _ => None,
+        })
+    }
+
+    /// Fails, with what to update, if the firmware speaks a protocol version
+    /// this tool doesn't, before it is sent commands it would misread
+    pub fn check_compatible(&mut self) -> Result<()> {
+        let info = self.firmware_info()?;
+        let (oldest, newest) = SUPPORTED_PROTOCOLS.into_inner();
+        if info.protocol < oldest {
+            Err(anyhow!(
+                "ESP32 firmware {} speaks protocol version {}, this tool needs {} to {}; \
+                 update the firmware first (`ota <IMAGE> <IMAGE>.sig`, or reflash it over USB)",
+                info.version,
+                info.protocol,
+                oldest,
+                newest
+            ))
+        } else if info.protocol > newest {
+            Err(anyhow!(
+                "ESP32 firmware {} speaks protocol version {}, newer than this tool ({} to {}); \
+                 update this tool",
+                info.version,
+                info.protocol,
+                oldest,
+                newest
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The device's stable ID, and its serial number if provisioned
+    pub fn device_id(&mut self) -> Result<(String, Option<String>)> {
+        self.request(
+            Command::GetDeviceId,
+            REPLY_IDLE_SECS,
+            |response| match response {
+                Response::DeviceId { id, serial } => {
+                    Some((id.to_string(), serial.map(str::to_string)))
+                }
+                _ => None,
+            },
+        )
+    }
+
+    pub fn tx_info(&mut self) -> Result<String> {
+        self.request(
+            Command::TxInfo,
+            REPLY_IDLE_SECS,
+            |response| match response {
+                Response::TxInfo(fields) => Some(fields.to_string()),
+                _ => None,
+            },
+        )
+    }
+
+    /// The device's own timings (`key=value` fields), signing with a
+    /// throwaway key; takes about half a second
+    pub fn benchmark(&mut self) -> Result<String> {
+        self.request(
+            Command::Benchmark,
+            REPLY_IDLE_SECS,
+            |response| match response {
+                Response::Benchmark(fields) => Some(fields.to_string()),
+                _ => None,
+            },
+        )
+    }
+
+    /// The signing policy the device enforces
+    pub fn policy(&mut self) -> Result<Policy> {
+        let text = self.request(
+            Command::GetPolicy,
+            REPLY_IDLE_SECS,
+            |response| match response {
+                Response::Policy(text) => Some(text.to_string()),
+                _ => None,
+            },
+        )?;
+        Policy::parse(&text).map_err(|e| anyhow!("Failed to parse policy {}: {}", text, e))
+    }
+
+    /// The loosening policy change waiting out its delay, with the
+    /// powered-on seconds it has left
+    pub fn pending_policy(&mut self) -> Result<Option<(u32, Policy)>> {
+        let pending =
+            self.request(
+                Command::GetPendingPolicy,
+                REPLY_IDLE_SECS,
+                |response| match response {
+                    Response::PolicyPending {
+                        remaining_secs,
+                        policy,
+                    } => Some(Some((remaining_secs, policy.to_string()))),
+                    Response::NoPendingPolicy => Some(None),
+                    _ => None,
+                },
+            )?;
+        pending
+            .map(|(remaining, text)| {
+                Policy::parse(&text)
+                    .map(|policy| (remaining, policy))
+                    .map_err(|e| anyhow!("Failed to parse policy {}: {}", text, e))
+            })
+            .transpose()
+    }
+
+    /// The device's signed placeholder memo transaction, base64
+    pub fn create_tx(&mut self) -> Result<String> {
+        self.request(
+            Command::CreateTx,
+            REPLY_IDLE_SECS,
+            |response| match response {
+                Response::Transaction(tx) => Some(tx.to_string()),
+                _ => None,
+            },
+        )
+    }
+
+    /// Signs `message` once the BOOT button is pressed. Only the paired
+    /// device is trusted with the message.
+    pub fn sign(&mut self, message: &[u8]) -> Result<Signature> {
+        self.sign_labeled(message, &[])
+    }
+
+    /// [`Device::sign`] with token labels, sent as `SIGN_TX`. Firmware too
+    /// old to know it gets a plain `SIGN` and shows raw amounts.
+    pub fn sign_labeled(&mut self, message: &[u8], labels: &[TokenLabel]) -> Result<Signature> {
+        if !self.paired && !self.is_offline() {
+            return Err(anyhow!(
+                "This host isn't paired with the ESP32; pair them with `pair` first"
+            ));
+        }
+        let message_b64 = base64::engine::general_purpose::STANDARD.encode(message);
+        let tokens = token_labels::format(labels);
+        let accept = |response: Response<'_>| match response {
+            Response::Signature(signature) => Some(signature.to_string()),
+            _ => None,
+        };
+        let signed = if labels.is_empty() {
+            self.request(Command::Sign(&message_b64), BUTTON_IDLE_SECS, accept)
+        } else {
+            let command = Command::SignTx {
+                message: &message_b64,
+                tokens: &tokens,
+            };
+            match self.request(command, BUTTON_IDLE_SECS, accept) {
+                Err(e) if is_unknown_command(&e) => {
+                    tracing::debug!("Firmware predates SIGN_TX; signing without token labels");
+                    self.request(Command::Sign(&message_b64), BUTTON_IDLE_SECS, accept)
+                }
+                signed => signed,
+            }
+        };
+        let signature_b64 = signed?;
+        let signature_bytes = base64::engine::general_purpose::STANDARD.decode(signature_b64)?;
+        let signature = Signature::try_from(signature_bytes.as_slice())?;
+        if self.cross_verify {
+            let pubkey = self.pubkey()?;
+            cross_verify::check(&pubkey, message, &signature)?;
+        }
+        Ok(signature)
+    }
+
+    /// Puts the ESP32 into deep sleep so it can be unplugged safely
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.request(Command::Shutdown, REPLY_IDLE_SECS, |response| {
+            matches!(response, Response::ShutdownOk).then_some(())
+        })
+    }
+
+    /// Starts OTP enrollment
+    pub fn otp_begin(&mut self) -> Result<OtpSecret> {
+        self.request(
+            Command::OtpBegin,
+            REPLY_IDLE_SECS,
+            |response| match response {
+                Response::OtpSecret {
+                    secret,
+                    digits,
+                    period,
+                } => Some(OtpSecret {
+                    secret: secret.to_string(),
+                    digits,
+                    period,
+                }),
+                _ => None,
+            },
+        )
+    }
+
+    /// Completes enrollment. Sends the host clock since the device has no RTC
+    /// time of its own.
+    pub fn otp_confirm(&mut self, code: &str) -> Result<()> {
+        let command = Command::OtpConfirm {
+            code,
+            unix: Some(now_unix()),
+        };
+        self.request(command, REPLY_IDLE_SECS, |response| {
+            matches!(response, Response::OtpConfirmed).then_some(())
+        })
+        .map_err(|e| anyhow!("OTP confirmation rejected: {}", e))
+    }
+
+    pub fn otp_status(&mut self) -> Result<OtpStatus> {
+        self.request(Command::OtpStatus, REPLY_IDLE_SECS, |reply| match reply {
+            Response::OtpStatus(payload) => {
+                let mut status = OtpStatus {
+                    enrolled: false,
+                    unlocked_until: 0,
+                    now: 0,
+                };
+                for (key, value) in response::fields(payload) {
+                    match key {
+                        "enrolled" => status.enrolled = value == "yes",
+                        "unlocked_until" => status.unlocked_until = value.parse().ok()?,
+                        "now" => status.now = value.parse().ok()?,
+                        _ => {}
+                    }
+                }
+                Some(status)
+            }
+            _ => None,
+        })
+    }
+
+    /// Offers this host's session key for pairing; returns the device's,
+    /// while the device blinks the pairing code
+    pub fn pair_begin(&mut self, host_key: &Pubkey) -> Result<Pubkey> {
+        let session_key = self.request(
+            Command::PairBegin(&host_key.to_string()),
+            REPLY_IDLE_SECS,
+            |response| match response {
+                Response::Pairing(key) => Some(key.to_string()),
+                _ => None,
+            },
+        )?;
+        Pubkey::from_str(&session_key).map_err(|e| anyhow!("Failed to parse session key: {}", e))
+    }
+
+    /// Completes pairing once the BOOT button is pressed
+    pub fn pair_confirm(&mut self) -> Result<()> {
+        self.request(Command::PairConfirm, BUTTON_IDLE_SECS, |response| {
+            matches!(response, Response::Paired).then_some(())
+        })
+    }
+
+    /// Makes the device forget its paired host once the BOOT button is
+    /// pressed
+    pub fn unpair(&mut self) -> Result<()> {
+        self.request(Command::Unpair, BUTTON_IDLE_SECS, |response| {
+            matches!(response, Response::Unpaired).then_some(())
+        })?;
+        self.paired = false;
+        Ok(())
+    }
+
+    /// Registers the auditor's X25519 key, which audit exports are sealed
+    /// to, once the BOOT button is pressed
+    pub fn audit_register(&mut self, auditor: &[u8; 32]) -> Result<()> {
+        self.request(
+            Command::AuditRegister(&bs58::encode(auditor).into_string()),
+            BUTTON_IDLE_SECS,
+            |response| matches!(response, Response::AuditRegistered).then_some(()),
+        )
+    }
+
+    /// The device's audit log, signed and sealed to the registered auditor
+    pub fn audit_export(&mut self) -> Result<Vec<u8>> {
+        let sealed = self.request(
+            Command::AuditExport,
+            REPLY_IDLE_SECS,
+            |response| match response {
+                Response::Audit(sealed) => Some(sealed.to_string()),
+                _ => None,
+            },
+        )?;
+        Ok(base64::engine::general_purpose::STANDARD.decode(sealed)?)
+    }
+
+    /// Has the device write an encrypted backup to its SD card (after a BOOT
+    /// press); returns the file name
+    pub fn backup_to_sd(&mut self, pin: &str, passphrase: &str) -> Result<String> {
+        self.request(
+            Command::BackupToSd { pin, passphrase },
+            BUTTON_IDLE_SECS,
+            |response| match response {
+                Response::BackedUp(name) => Some(name.to_string()),
+                _ => None,
+            },
+        )
+    }
+
+    /// Has the device replace its key and policy with the newest backup on
+    /// its SD card (after a BOOT press); returns the restored pubkey. The
+    /// device restarts afterwards.
+    pub fn restore_from_sd(&mut self, pin: &str, passphrase: &str) -> Result<Pubkey> {
+        let pubkey = self.request(
+            Command::RestoreFromSd { pin, passphrase },
+            BUTTON_IDLE_SECS,
+            |response| match response {
+                Response::Restored(pubkey) => Some(pubkey.to_string()),
+                _ => None,
+            },
+        )?;
+        Pubkey::from_str(&pubkey).map_err(|e| anyhow!("Failed to parse public key: {}", e))
+    }
+
+    /// Sends the host's nonce; returns the device's nonce and its signature
+    pub fn auth_begin(&mut self, host_nonce: &[u8]) -> Result<(Vec<u8>, Signature)> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let (nonce, signature) = self.request(
+            Command::AuthBegin(&engine.encode(host_nonce)),
+            REPLY_IDLE_SECS,
+            |response| match response {
+                Response::AuthChallenge { nonce, signature } => {
+                    Some((nonce.to_string(), signature.to_string()))
+                }
+                _ => None,
+            },
+        )?;
+        let signature = engine.decode(signature)?;
+        Ok((
+            engine.decode(nonce)?,
+            Signature::try_from(signature.as_slice())?,
+        ))
+    }
+
+    /// Answers the device's nonce
+    pub fn auth_proof(&mut self, signature: &Signature) -> Result<()> {
+        let signature = base64::engine::general_purpose::STANDARD.encode(signature);
+        self.request(
+            Command::AuthProof(&signature),
+            REPLY_IDLE_SECS,
+            |response| matches!(response, Response::Authenticated).then_some(()),
+        )
+    }
+
+    /// Opens the signing window; returns the unix time it closes
+    pub fn otp_unlock(&mut self, code: &str) -> Result<u64> {
+        let command = Command::OtpUnlock {
+            code,
+            unix: Some(now_unix()),
+        };
+        self.request(command, REPLY_IDLE_SECS, |response| match response {
+            Response::UnlockedUntil(until) => Some(until),
+            _ => None,
+        })
+    }
+}
+
+/// Whether the device didn't know the command at all
+fn is_unknown_command(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<DeviceError>()
+        .is_some_and(|e| e.code == ErrorCode::UnknownCommand)
+}
+
+/// Parses a response line and hands it to `accept`; `ERROR:` replies become
+/// errors
+pub fn reply<T>(line: &str, accept: impl FnOnce(Response) -> Option<T>) -> Result<T> {
+    match Response::parse(line) {
+        Some(Response::Error(code)) => return Err(DeviceError { code, detail: None }.into()),
+        Some(Response::ErrorDetail(code, detail)) => {
+            return Err(DeviceError {
+                code,
+                detail: Some(detail.to_string()),
+            }
+            .into())
+        }
+        Some(response) => accept(response),
+        None => None,
+    }
+    .ok_or_else(|| anyhow!("Invalid response from ESP32: {}", line))
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `path` as serialport opens it. COM ports go without the `\\.\` prefix
+/// (serialport adds it, which is what makes COM10 and up work), and a bare
+/// device name such as `ttyUSB0` is looked up under `/dev`.
+fn port_path(path: &str) -> String {
+    let path = path.strip_prefix(r"\\.\").unwrap_or(path);
+    if cfg!(windows) {
+        return path.to_ascii_uppercase();
+    }
+    if !path.contains('/') && Path::new("/dev").join(path).exists() {
+        return format!("/dev/{}", path);
+    }
+    path.to_string()
+}
+
+/// What to do about a port that won't open
+fn open_hint(e: &serialport::Error) -> &'static str {
+    match e.kind() {
+        // Windows reports missing and in-use ports alike
+        serialport::ErrorKind::NoDevice if cfg!(windows) => {
+            "; check the COM number with `list-ports` and close any program using the port"
+        }
+        serialport::ErrorKind::NoDevice | serialport::ErrorKind::Io(io::ErrorKind::NotFound) => {
+            "; is the ESP32 plugged in? `list-ports` shows the attached ports"
+        }
+        serialport::ErrorKind::Io(io::ErrorKind::PermissionDenied) if cfg!(target_os = "linux") => {
+            "; add yourself to the `dialout` group (`sudo usermod -aG dialout $USER`, then log \
+             in again) or install the udev rule from the README"
+        }
+        serialport::ErrorKind::Io(io::ErrorKind::PermissionDenied) => {
+            "; your user may not open serial ports"
+        }
+        // EBUSY: the port is locked for exclusive use
+        _ if e.description.contains("busy") => {
+            "; is another program (a serial monitor, another copy of this tool) using it?"
+        }
+        _ => "",
+    }
+}
+
+/// Every serial port the OS reports
+pub fn serial_ports() -> Result<Vec<SerialPortInfo>> {
+    serialport::available_ports().map_err(|e| anyhow!("Failed to list serial ports: {}", e))
+}
+
+/// Serial ports that might have a signer attached, likeliest first
+fn candidate_ports() -> Result<Vec<String>> {
+    let mut ports = serial_ports()?;
+    ports.retain(|p| likelihood(p) > 0);
+    ports.sort_by_key(|p| std::cmp::Reverse(likelihood(p)));
+    Ok(ports.into_iter().map(|p| p.port_name).collect())
+}
+
+/// 0 for ports that can't be the signer, higher for better matches
+pub fn likelihood(port: &SerialPortInfo) -> u8 {
+    let name = port.port_name.as_str();
+    match &port.port_type {
+        SerialPortType::UsbPort(usb) => {
+            let product = usb.product.as_deref().unwrap_or("");
+            if KNOWN_USB_IDS.contains(&(usb.vid, usb.pid)) {
+                3
+            } else if ["CP210", "CH340", "CH910", "UART", "JTAG"]
+                .iter()
+                .any(|hint| product.contains(hint))
+            {
+                2
+            } else {
+                1
+            }
+        }
+        // Without USB details (e.g. no libudev) go by the device node name
+        _ if [
+            "usbserial",
+            "usbmodem",
+            "SLAB",
+            "ttyUSB",
+            "ttyACM",
+            "wchusbserial",
+        ]
+        .iter()
+        .any(|hint| name.contains(hint)) =>
+        {
+            1
+        }
+        // Windows COM ports carry no hints in the name
+        SerialPortType::Unknown if name.starts_with("COM") => 1,
+        _ => 0,
+    }
+}
@@ -0,0 +1,120 @@
+//! `--dry-run`: transactions are built, described and priced as usual, then
+//! printed instead of being signed and sent. Neither the ESP32 nor the RPC
+//! node is contacted.
+//!
+//! The device is stood in for by its pubkey, as remembered in the registry.
+//! The RPC client is backed by `OfflineSender`, which answers what can be
+//! known without a node (rent, a placeholder blockhash, no priority fees)
+//! and treats every account as not existing yet; anything else fails.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::Engine;
+use serde_json::{json, Value};
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_client::{RpcClient, RpcClientConfig},
+    rpc_request::RpcRequest,
+    rpc_sender::{RpcSender, RpcTransportStats},
+};
+use solana_sdk::{
+    commitment_config::CommitmentConfig, hash::Hash, pubkey::Pubkey, rent::Rent,
+    transaction::VersionedTransaction,
+};
+
+use crate::config::Settings;
+use crate::{confirm, fiat, registry};
+
+/// Answers RPC requests without a node
+struct OfflineSender;
+
+#[async_trait]
+impl RpcSender for OfflineSender {
+    async fn send(
+        &self,
+        request: RpcRequest,
+        params: Value,
+    ) -> solana_client::client_error::Result<Value> {
+        let context = json!({ "slot": 0 });
+        match request {
+            // Asked before most calls, to pick the request format
+            RpcRequest::GetVersion => Ok(json!({ "solana-core": "1.18.0" })),
+            RpcRequest::GetLatestBlockhash => Ok(json!({
+                "context": context,
+                "value": {
+                    "blockhash": Hash::default().to_string(),
+                    "lastValidBlockHeight": 0,
+                },
+            })),
+            RpcRequest::GetMinimumBalanceForRentExemption => {
+                let size = params[0].as_u64().unwrap_or_default();
+                Ok(json!(Rent::default().minimum_balance(size as usize)))
+            }
+            RpcRequest::GetAccountInfo => Ok(json!({ "context": context, "value": null })),
+            RpcRequest::GetRecentPrioritizationFees => Ok(json!([])),
+            _ => Err(ClientError::from(ClientErrorKind::Custom(format!(
+                "{} needs the network, which --dry-run doesn't contact",
+                request
+            )))),
+        }
+    }
+
+    fn get_transport_stats(&self) -> RpcTransportStats {
+        RpcTransportStats::default()
+    }
+
+    fn url(&self) -> String {
+        "dry-run".to_string()
+    }
+}
+
+/// An RPC client that never leaves this machine
+pub fn client(commitment: CommitmentConfig) -> RpcClient {
+    RpcClient::new_sender(OfflineSender, RpcClientConfig::with_commitment(commitment))
+}
+
+/// The signer's pubkey without asking it: the registry entry `--device`
+/// names, or the only signer remembered
+pub fn device_key(settings: &Settings) -> Result<Pubkey> {
+    registry::remembered_pubkey(settings.device.as_deref())
+}
+
+/// Prints what would have been signed and sent. `device_key` is the ESP32
+/// this tool would ask to sign, if any.
+pub fn report(
+    settings: &Settings,
+    transaction: &VersionedTransaction,
+    device_key: Option<&Pubkey>,
+) {
+    println!("Dry run: nothing was signed or sent");
+    let prices = fiat::Prices::for_message(settings, &transaction.message);
+    for line in confirm::describe_transaction(&transaction.message, &prices) {
+        println!("  {}", line);
+    }
+    let signers = transaction
+        .message
+        .static_account_keys()
+        .iter()
+        .take(transaction.signatures.len());
+    // The fee payer always takes the first signature slot
+    for (slot, key) in signers.enumerate() {
+        let who = match (Some(key) == device_key, slot == 0) {
+            (true, true) => "the ESP32 (BOOT button), paying the fee",
+            (true, false) => "the ESP32 (BOOT button)",
+            (false, true) => "the fee payer",
+            (false, false) => "a signer",
+        };
+        println!("Signer {}: {}", key, who);
+    }
+    if settings.priority_fee.is_none() || settings.cu_limit.is_none() {
+        println!(
+            "The fee assumes no priority fee and the default compute limit; pass \
+             --priority-fee and --cu-limit to price it as sent"
+        );
+    }
+    println!("The blockhash is a placeholder; a real run fetches a recent one");
+    println!(
+        "Message the ESP32 would be asked to sign (base64):\n{}",
+        base64::engine::general_purpose::STANDARD.encode(transaction.message.serialize())
+    );
+}
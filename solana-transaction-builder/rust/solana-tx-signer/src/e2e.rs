@@ -0,0 +1,373 @@
+//! `e2e`: SOL transfers, token transfers and durable nonces against a
+//! throwaway `solana-test-validator`, signed by the attached device, with
+//! what landed on chain checked after each one.
+//!
+//! Every signature needs a BOOT press; against `emulate --auto-press` the
+//! whole run is unattended, e.g. in CI. The device's account is funded by
+//! airdrop and pays its own fees; a local keypair stands in for the mint
+//! authority of a test token. The validator and its ledger are gone once
+//! the run ends.
+
+use anyhow::{anyhow, Context, Result};
+use clap::Args;
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    native_token::LAMPORTS_PER_SOL,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    system_instruction, system_program,
+    transaction::Transaction,
+};
+use spl_associated_token_account::{
+    get_associated_token_address, instruction::create_associated_token_account,
+};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::selftest::{self, Check, Verdict};
+use crate::{nonce, send, token};
+
+/// What the device's account gets from the faucet
+const AIRDROP_LAMPORTS: u64 = 10 * LAMPORTS_PER_SOL;
+/// Sent in each SOL transfer; above the rent-exempt minimum of an empty
+/// account, so a fresh recipient can hold it
+const TRANSFER_LAMPORTS: u64 = 5_000_000;
+const TOKEN_DECIMALS: u8 = 6;
+/// Minted to the device's token account, in base units
+const TOKEN_SUPPLY: u64 = 100_000_000;
+/// Sent in the token transfer, as `transfer-token --amount` takes it
+const TOKEN_AMOUNT: &str = "12.5";
+const TOKEN_AMOUNT_BASE_UNITS: u64 = 12_500_000;
+const NONCE_SEED: &str = "e2e";
+/// How long the validator gets to start answering
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Args, Debug)]
+pub struct E2eArgs {
+    /// The validator to run
+    #[arg(long, value_name = "PATH", default_value = "solana-test-validator")]
+    validator: String,
+    /// RPC port for the validator; the faucet gets the next one up
+    #[arg(long, default_value_t = 8899)]
+    rpc_port: u16,
+}
+
+/// The validator, killed when dropped
+struct Validator {
+    child: Child,
+    // Held until the validator is gone: dropping it deletes the ledger
+    _ledger: tempfile::TempDir,
+}
+
+impl Validator {
+    fn start(args: &E2eArgs) -> Result<(Self, RpcClient)> {
+        let url = format!("http://127.0.0.1:{}", args.rpc_port);
+        let client = RpcClient::new_with_commitment(url.clone(), CommitmentConfig::confirmed());
+        if client.get_health().is_ok() {
+            return Err(anyhow!(
+                "something already answers on {}; stop it or pick another --rpc-port",
+                url
+            ));
+        }
+        let ledger = tempfile::Builder::new()
+            .prefix("esp32-signer-e2e")
+            .tempdir()
+            .context("create a temporary ledger directory")?;
+        let child = Command::new(&args.validator)
+            .arg("--ledger")
+            .arg(ledger.path())
+            .args(["--reset", "--quiet", "--rpc-port"])
+            .arg(args.rpc_port.to_string())
+            .arg("--faucet-port")
+            .arg((args.rpc_port + 1).to_string())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("run {} (is the Solana CLI installed?)", args.validator))?;
+        let mut validator = Validator {
+            child,
+            _ledger: ledger,
+        };
+
+        let start = Instant::now();
+        while client.get_health().is_err() {
+            if let Some(status) = validator.child.try_wait()? {
+                return Err(anyhow!("{} exited with {}", args.validator, status));
+            }
+            if start.elapsed() > STARTUP_TIMEOUT {
+                return Err(anyhow!(
+                    "{} didn't answer on {} within {}s",
+                    args.validator,
+                    url,
+                    STARTUP_TIMEOUT.as_secs()
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(500));
+        }
+        // The faucet comes up with the RPC service, but the first slots
+        // can still be settling
+        client.get_latest_blockhash()?;
+        Ok((validator, client))
+    }
+}
+
+impl Drop for Validator {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The steps, each depending on the ones before it
+#[derive(Default)]
+struct Run {
+    checks: Vec<Check>,
+}
+
+impl Run {
+    fn step(&mut self, name: &'static str, step: impl FnOnce() -> Result<String>) {
+        let start = Instant::now();
+        let verdict = if self.failed() {
+            Verdict::Skip("an earlier step failed".to_string())
+        } else {
+            match step() {
+                Ok(detail) => Verdict::Pass(detail),
+                Err(e) => Verdict::Fail(e),
+            }
+        };
+        let check = Check {
+            name,
+            verdict,
+            elapsed: start.elapsed(),
+            log: String::new(),
+        };
+        selftest::print_check(&check);
+        self.checks.push(check);
+    }
+
+    fn failed(&self) -> bool {
+        selftest::count(&self.checks, |v| matches!(v, Verdict::Fail(_))) > 0
+    }
+}
+
+/// Starts the validator, runs every flow through `device` and checks the
+/// results on chain; fails if any step did
+pub fn run(device: &mut Device, settings: &Settings, args: &E2eArgs) -> Result<()> {
+    if settings.dry_run {
+        return Err(anyhow!("e2e submits transactions; drop --dry-run"));
+    }
+    let device_key = device.pubkey()?;
+    println!("Starting {} on port {}...", args.validator, args.rpc_port);
+    let (_validator, client) = Validator::start(args)?;
+    // The device pays its own fees, on the local validator only
+    let settings = Settings {
+        rpc_url: client.url(),
+        commitment: client.commitment(),
+        fee_payer: None,
+        fee_payer_device: None,
+        lookup_tables: Vec::new(),
+        fiat: false,
+        ..settings.clone()
+    };
+    let mut run = Run::default();
+
+    run.step("airdrop", || {
+        airdrop(&client, &device_key, AIRDROP_LAMPORTS)?;
+        expect_balance(&client, &device_key, AIRDROP_LAMPORTS)?;
+        Ok(format!(
+            "{} SOL to {}",
+            AIRDROP_LAMPORTS / LAMPORTS_PER_SOL,
+            device_key
+        ))
+    });
+
+    let recipient = Keypair::new().pubkey();
+    run.step("sol transfer", || {
+        let instructions =
+            send::transfer_instructions(&device_key, &[recipient], TRANSFER_LAMPORTS);
+        let signature = submit(&client, &settings, device, &device_key, None, &instructions)?;
+        expect_balance(&client, &recipient, TRANSFER_LAMPORTS)?;
+        Ok(signature)
+    });
+
+    let mint_authority = Keypair::new();
+    let mint = Keypair::new();
+    run.step("token setup", || {
+        // For the fees and rent of the setup transaction
+        airdrop(&client, &mint_authority.pubkey(), LAMPORTS_PER_SOL)?;
+        let rent = client.get_minimum_balance_for_rent_exemption(spl_token::state::Mint::LEN)?;
+        let device_account = get_associated_token_address(&device_key, &mint.pubkey());
+        let instructions = [
+            system_instruction::create_account(
+                &mint_authority.pubkey(),
+                &mint.pubkey(),
+                rent,
+                spl_token::state::Mint::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_mint2(
+                &spl_token::id(),
+                &mint.pubkey(),
+                &mint_authority.pubkey(),
+                None,
+                TOKEN_DECIMALS,
+            )?,
+            create_associated_token_account(
+                &mint_authority.pubkey(),
+                &device_key,
+                &mint.pubkey(),
+                &spl_token::id(),
+            ),
+            spl_token::instruction::mint_to(
+                &spl_token::id(),
+                &mint.pubkey(),
+                &device_account,
+                &mint_authority.pubkey(),
+                &[],
+                TOKEN_SUPPLY,
+            )?,
+        ];
+        let transaction = Transaction::new_signed_with_payer(
+            &instructions,
+            Some(&mint_authority.pubkey()),
+            &[&mint_authority, &mint],
+            client.get_latest_blockhash()?,
+        );
+        client.send_and_confirm_transaction(&transaction)?;
+        expect_tokens(&client, &device_account, TOKEN_SUPPLY)?;
+        Ok(format!(
+            "mint {}, {} base units to the device",
+            mint.pubkey(),
+            TOKEN_SUPPLY
+        ))
+    });
+
+    run.step("token transfer", || {
+        let instructions = token::transfer_instructions(
+            &settings,
+            &device_key,
+            &mint.pubkey(),
+            &recipient,
+            TOKEN_AMOUNT,
+            None,
+            &device_key,
+        )?;
+        let signature = submit(&client, &settings, device, &device_key, None, &instructions)?;
+        let account = get_associated_token_address(&recipient, &mint.pubkey());
+        expect_tokens(&client, &account, TOKEN_AMOUNT_BASE_UNITS)?;
+        expect_tokens(
+            &client,
+            &get_associated_token_address(&device_key, &mint.pubkey()),
+            TOKEN_SUPPLY - TOKEN_AMOUNT_BASE_UNITS,
+        )?;
+        Ok(signature)
+    });
+
+    let nonce_account = Pubkey::create_with_seed(&device_key, NONCE_SEED, &system_program::id())?;
+    run.step("nonce create", || {
+        nonce::create(device, &settings, NONCE_SEED, None)?;
+        let (_, authority) = nonce::durable_blockhash(&client, &nonce_account)?;
+        if authority != device_key {
+            return Err(anyhow!("nonce authority is {}, not the device", authority));
+        }
+        Ok(nonce_account.to_string())
+    });
+
+    run.step("nonce transfer", || {
+        let (before, _) = nonce::durable_blockhash(&client, &nonce_account)?;
+        let instructions =
+            send::transfer_instructions(&device_key, &[recipient], TRANSFER_LAMPORTS);
+        let signature = submit(
+            &client,
+            &settings,
+            device,
+            &device_key,
+            Some(&nonce_account),
+            &instructions,
+        )?;
+        expect_balance(&client, &recipient, 2 * TRANSFER_LAMPORTS)?;
+        let (after, _) = nonce::durable_blockhash(&client, &nonce_account)?;
+        if after == before {
+            return Err(anyhow!("the nonce didn't advance"));
+        }
+        Ok(signature)
+    });
+
+    let failed = selftest::count(&run.checks, |v| matches!(v, Verdict::Fail(_)));
+    println!(
+        "\n{} passed, {} failed, {} skipped",
+        selftest::count(&run.checks, |v| matches!(v, Verdict::Pass(_))),
+        failed,
+        selftest::count(&run.checks, |v| matches!(v, Verdict::Skip(_)))
+    );
+    if failed > 0 {
+        return Err(anyhow!("{} e2e step(s) failed", failed));
+    }
+    Ok(())
+}
+
+/// Signs `instructions` on the device and submits them; the signature
+fn submit(
+    client: &RpcClient,
+    settings: &Settings,
+    device: &mut Device,
+    device_key: &Pubkey,
+    nonce_account: Option<&Pubkey>,
+    instructions: &[solana_sdk::instruction::Instruction],
+) -> Result<String> {
+    let signature = send::sign_and_submit(
+        client,
+        settings,
+        device,
+        device_key,
+        None,
+        nonce_account,
+        instructions,
+    )?
+    .ok_or_else(|| anyhow!("nothing was submitted"))?;
+    Ok(signature.to_string())
+}
+
+/// Has the faucet send `lamports` to `key`, and waits for it to land
+fn airdrop(client: &RpcClient, key: &Pubkey, lamports: u64) -> Result<()> {
+    let signature = client.request_airdrop(key, lamports)?;
+    let start = Instant::now();
+    while !client.confirm_transaction(&signature)? {
+        if start.elapsed() > CONFIRM_TIMEOUT {
+            return Err(anyhow!("airdrop {} not confirmed", signature));
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+    Ok(())
+}
+
+fn expect_balance(client: &RpcClient, key: &Pubkey, lamports: u64) -> Result<()> {
+    let balance = client.get_balance(key)?;
+    if balance != lamports {
+        return Err(anyhow!(
+            "{} holds {} lamports, expected {}",
+            key,
+            balance,
+            lamports
+        ));
+    }
+    Ok(())
+}
+
+fn expect_tokens(client: &RpcClient, account: &Pubkey, amount: u64) -> Result<()> {
+    let balance: u64 = client.get_token_account_balance(account)?.amount.parse()?;
+    if balance != amount {
+        return Err(anyhow!(
+            "token account {} holds {}, expected {}",
+            account,
+            balance,
+            amount
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,956 @@
+//! `emulate`: a software signer speaking the device protocol on a
+//! pseudo-terminal, so this tool, programs built on `signer-protocol` and
+//! integration tests can run where there is no ESP32, e.g. in CI.
+//!
+//! The emulator keeps what the firmware keeps in NVS (signing and session
+//! seeds, the paired host, the provisioning salt, the policy and 2FA state)
+//! in a state file, by default a temporary one deleted on exit, and answers
+//! with the same checks the firmware makes: pairing, the 2FA signing window
+//! with --twofa, and the signing policy, holding back loosening changes for
+//! the policy's delay (counted in wall-clock time while the emulator runs).
+//! Its key lives in a plain file, so it reports itself as hardware without
+//! flash encryption or secure boot, and a `production=on` policy stops it
+//! signing as it would stop such a device.
+//! Commands that wait for BOOT wait for a `press` on the control socket, a
+//! Unix socket taking one command a line:
+//!
+//! ```text
+//! press       one BOOT press, used by the next command that waits for one
+//! auto on     every wait ends at once, as with --auto-press
+//! auto off    back to waiting for presses
+//! cancel      a short BOOT press while idle: cancels a pending policy change
+//! ```
+//!
+//! each answered with `ok` or `error: <why>`.
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use clap::Args;
+use data_encoding::BASE32_NOPAD;
+use nix::fcntl::OFlag;
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use rand::RngCore;
+use signer_core::encoding::{hex_decode, hex_encode};
+use signer_core::identity::{self, ProvisioningRecord};
+use signer_core::keys::{self, KeyError};
+use signer_core::pairing::{self as transcript, NONCE_LEN};
+use signer_core::platform::{EntropySource, Storage};
+use signer_core::policy::{ChainProfile, PendingChange, Policy, PolicyViolation};
+use signer_core::token_labels::{self, LabelError};
+use signer_core::totp::{self, OtpState, OTP_BYTES, OTP_DIGITS, OTP_PERIOD, UNLOCK_SECS};
+use signer_core::tx_introspection::{self, MEMO_PROGRAM_ID};
+use signer_protocol::command::Command;
+use signer_protocol::error::ErrorCode;
+use signer_protocol::response::Response;
+use solana_sdk::{
+    hash::Hash,
+    instruction::{AccountMeta, Instruction},
+    message::Message,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature, Signer},
+    signer::keypair::keypair_from_seed,
+    transaction::Transaction,
+};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Stands in for the factory MAC the device ID is derived from
+const EMULATOR_MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+/// The firmware's placeholder memo transaction
+const PLACEHOLDER_MEMO: &str = "Hello from ESP32 Solana Signer!";
+/// The firmware's placeholder blockhash, `11111111111111111111111111111112`
+const PLACEHOLDER_BLOCKHASH: [u8; 32] = {
+    let mut hash = [0u8; 32];
+    hash[31] = 1;
+    hash
+};
+
+/// State file keys the firmware has no signer-core name for
+const POLICY_KEY: &str = "policy";
+const PREVIOUS_POLICY_KEY: &str = "policy_prev";
+/// As the firmware's: remaining seconds (u32 LE), then the policy record
+const PENDING_POLICY_KEY: &str = "policy_pend";
+const OTP_STATE_KEY: &str = "otp_state";
+
+#[derive(Args, Debug)]
+pub struct EmulateArgs {
+    /// Where to keep the keys and other device state, so a later run is the
+    /// same device [default: a temporary file, deleted on exit]
+    #[arg(long, value_name = "FILE")]
+    state: Option<PathBuf>,
+    /// Control socket for BOOT presses [default: <STATE>.button]
+    #[arg(long, value_name = "PATH")]
+    control: Option<PathBuf>,
+    /// Also make the pseudo-terminal reachable at this path (a symlink),
+    /// for a port name that doesn't change between runs
+    #[arg(long, value_name = "PATH")]
+    link: Option<PathBuf>,
+    /// Take every BOOT wait as pressed at once
+    #[arg(long)]
+    auto_press: bool,
+    /// Emulate firmware built with `twofa`
+    #[arg(long)]
+    twofa: bool,
+}
+
+/// Device state as hex strings by NVS key, rewritten on every change
+struct FileStorage {
+    path: PathBuf,
+    values: BTreeMap<String, String>,
+}
+
+impl FileStorage {
+    fn open(path: &Path) -> Result<Self> {
+        let values = match std::fs::read_to_string(path) {
+            Ok(text) if !text.trim().is_empty() => {
+                serde_json::from_str(&text).with_context(|| format!("parse {}", path.display()))?
+            }
+            Ok(_) => BTreeMap::new(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        Ok(FileStorage {
+            path: path.to_path_buf(),
+            values,
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    type Error = anyhow::Error;
+
+    fn get(&mut self, key: &str, buf: &mut [u8]) -> Result<Option<usize>> {
+        let Some(hex) = self.values.get(key) else {
+            return Ok(None);
+        };
+        let value = hex_decode(hex)
+            .ok_or_else(|| anyhow!("{}: {} is not hex", self.path.display(), key))?;
+        let n = value.len().min(buf.len());
+        buf[..n].copy_from_slice(&value[..n]);
+        Ok(Some(value.len()))
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.values.insert(key.to_string(), hex_encode(value));
+        let text = serde_json::to_string_pretty(&self.values)?;
+        std::fs::write(&self.path, text).with_context(|| format!("write {}", self.path.display()))
+    }
+}
+
+/// The OS RNG, as the hardware RNG's stand-in
+struct OsEntropy;
+
+impl EntropySource for OsEntropy {
+    type Error = rand::Error;
+
+    fn fill(&mut self, buf: &mut [u8]) -> Result<(), rand::Error> {
+        rand::rngs::OsRng.try_fill_bytes(buf)
+    }
+}
+
+/// The BOOT button, pressed through the control socket
+#[derive(Clone)]
+struct Button(Arc<(Mutex<ButtonState>, Condvar)>);
+
+struct ButtonState {
+    presses: u32,
+    auto: bool,
+    /// An idle press, not yet seen by the emulator
+    cancel: bool,
+}
+
+impl Button {
+    fn new(auto: bool) -> Self {
+        Button(Arc::new((
+            Mutex::new(ButtonState {
+                presses: 0,
+                auto,
+                cancel: false,
+            }),
+            Condvar::new(),
+        )))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ButtonState> {
+        self.0 .0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn press(&self) {
+        self.lock().presses += 1;
+        self.0 .1.notify_all();
+    }
+
+    fn set_auto(&self, auto: bool) {
+        self.lock().auto = auto;
+        self.0 .1.notify_all();
+    }
+
+    fn cancel(&self) {
+        self.lock().cancel = true;
+    }
+
+    /// Whether there was an idle press since the last call
+    fn take_cancel(&self) -> bool {
+        std::mem::take(&mut self.lock().cancel)
+    }
+
+    /// Blocks until the button is pressed, like the firmware does
+    fn wait(&self) {
+        let mut state = self.lock();
+        while !state.auto && state.presses == 0 {
+            state = self.0 .1.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        if !state.auto {
+            state.presses -= 1;
+        }
+    }
+}
+
+/// Takes control socket connections for as long as the emulator runs
+fn serve_control(listener: UnixListener, button: Button) {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let button = button.clone();
+        std::thread::spawn(move || {
+            let Ok(mut writer) = stream.try_clone() else {
+                return;
+            };
+            for line in BufReader::new(stream).lines() {
+                let Ok(line) = line else {
+                    return;
+                };
+                let reply = match line.trim() {
+                    "press" => {
+                        button.press();
+                        "ok".to_string()
+                    }
+                    "auto on" => {
+                        button.set_auto(true);
+                        "ok".to_string()
+                    }
+                    "auto off" => {
+                        button.set_auto(false);
+                        "ok".to_string()
+                    }
+                    "cancel" => {
+                        button.cancel();
+                        "ok".to_string()
+                    }
+                    "" => continue,
+                    other => format!("error: unknown control command {:?}", other),
+                };
+                if writeln!(writer, "{}", reply).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// The emulated firmware: the state it loads at boot and keeps in RAM
+struct Emulator {
+    storage: FileStorage,
+    rng: OsEntropy,
+    button: Button,
+    signer: Keypair,
+    session: Keypair,
+    device_id: String,
+    provisioning: ProvisioningRecord,
+    policy: Policy,
+    /// A loosening policy change, counting down since the instant
+    delayed: Option<(PendingChange, Instant)>,
+    host: Option<Pubkey>,
+    /// Host key from PAIR_BEGIN, waiting for PAIR_CONFIRM
+    pending: Option<Pubkey>,
+    /// Nonces of the last AUTH_BEGIN, waiting for AUTH_PROOF
+    challenge: Option<([u8; NONCE_LEN], [u8; NONCE_LEN])>,
+    authenticated: bool,
+    twofa: bool,
+    unlocked_until: u64,
+}
+
+impl Emulator {
+    /// Boots from `storage`, generating what a first boot generates
+    fn boot(mut storage: FileStorage, button: Button, twofa: bool) -> Result<Self> {
+        let mut rng = OsEntropy;
+        let seed = keys::load_or_generate_seed(&mut storage, &mut rng).map_err(key_error)?;
+        let session_seed = keys::load_or_generate_named(
+            &mut storage,
+            &mut rng,
+            signer_core::pairing::SESSION_KEY_NAME,
+        )
+        .map_err(key_error)?;
+        let host = transcript::load_host(&mut storage)
+            .map_err(|e| anyhow!("paired host unreadable: {:?}", e))?
+            .map(Pubkey::new_from_array);
+        let provisioning = identity::load_or_create_record(&mut storage, &mut rng)
+            .map_err(|e| anyhow!("provisioning record unavailable: {:?}", e))?;
+        let device_id = identity::format_device_id(&provisioning.device_id(&EMULATOR_MAC));
+        let mut policy_bytes = [0u8; 1024];
+        let policy = match storage.get(POLICY_KEY, &mut policy_bytes)? {
+            Some(len) if len <= policy_bytes.len() => Policy::from_bytes(&policy_bytes[..len])
+                .ok_or_else(|| anyhow!("stored policy is corrupt"))?,
+            Some(_) => return Err(anyhow!("stored policy is corrupt")),
+            None => Policy::default(),
+        };
+        let delayed = match storage.get(PENDING_POLICY_KEY, &mut policy_bytes)? {
+            Some(len @ 5..=1024) => {
+                let (remaining, record) = policy_bytes[..len].split_at(4);
+                let policy = Policy::from_bytes(record)
+                    .ok_or_else(|| anyhow!("stored pending policy is corrupt"))?;
+                let change = PendingChange {
+                    policy,
+                    remaining_secs: u32::from_le_bytes(remaining.try_into()?),
+                };
+                Some((change, Instant::now()))
+            }
+            _ => None,
+        };
+        Ok(Emulator {
+            storage,
+            rng,
+            button,
+            signer: keypair_from_seed(&seed).map_err(|e| anyhow!("{}", e))?,
+            session: keypair_from_seed(&session_seed).map_err(|e| anyhow!("{}", e))?,
+            device_id,
+            provisioning,
+            policy,
+            delayed,
+            host,
+            pending: None,
+            challenge: None,
+            authenticated: false,
+            twofa,
+            unlocked_until: 0,
+        })
+    }
+
+    /// The reply to one command line; `None` for lines the firmware ignores
+    fn handle(&mut self, line: &str) -> Option<String> {
+        let command = Command::parse(line);
+        tracing::debug!(command = command.name(), "emulator received");
+        self.tick_delayed();
+        let response = match command {
+            Command::Empty => return None,
+            Command::Ping => Response::Pong.to_string(),
+            Command::GetPubkey => Response::Pubkey(&self.signer.pubkey().to_string()).to_string(),
+            Command::GetInfo => Response::Info(&self.info_fields()).to_string(),
+            Command::GetDeviceId => Response::DeviceId {
+                id: &self.device_id,
+                serial: self.provisioning.serial.as_deref(),
+            }
+            .to_string(),
+            Command::GetFeatures => {
+                let otp_secret = if self.twofa { "base32" } else { "none" };
+                let fields = format!("binary=base64;keys=base58;otp_secret={}", otp_secret);
+                Response::Features(&fields).to_string()
+            }
+            Command::Provision(serial) => self.provision(serial),
+            Command::Diag => Response::Diag(
+                "heap_free=200000;heap_min=200000;stack_hwm=4096;nvs_ns_used=0;nvs_used=0;\
+                 nvs_free=1000;nvs_total=1000;crashes=0;last_panic=none",
+            )
+            .to_string(),
+            Command::Benchmark => Response::Benchmark("emulated=yes").to_string(),
+            Command::Telemetry => Response::Telemetry("temp_c=na;vdd_mv=na;uptime_s=0").to_string(),
+            Command::LogLevel(level) => Response::LogLevelOk(level).to_string(),
+            Command::SetPolicy(spec) => self.set_policy(spec),
+            Command::PolicyRollback => self.rollback_policy(),
+            Command::GetPolicy => Response::Policy(&self.policy.to_string()).to_string(),
+            Command::GetPendingPolicy => match &self.delayed {
+                Some((change, _)) => Response::PolicyPending {
+                    remaining_secs: change.remaining_secs,
+                    policy: &change.policy.to_string(),
+                }
+                .to_string(),
+                None => Response::NoPendingPolicy.to_string(),
+            },
+            Command::CreateTx => self.create_tx(),
+            Command::TxInfo => Response::TxInfo(&format!(
+                "memo='{}';blockhash={};program={}",
+                PLACEHOLDER_MEMO,
+                Hash::new_from_array(PLACEHOLDER_BLOCKHASH),
+                Pubkey::new_from_array(MEMO_PROGRAM_ID)
+            ))
+            .to_string(),
+            Command::OtpBegin => self.otp_begin(),
+            Command::OtpConfirm { code, unix } => self.otp_confirm(code, unix),
+            Command::OtpUnlock { code, unix } => self.otp_unlock(code, unix),
+            Command::OtpStatus => self.otp_status(),
+            Command::Sign(message) => self.sign(message, ""),
+            Command::SignTx { message, tokens } => self.sign(message, tokens),
+            Command::OtaBegin { .. } | Command::OtaChunk(_) | Command::OtaEnd(_) => {
+                match self.check_trust() {
+                    Err(code) => Response::Error(code).to_string(),
+                    Ok(()) => Response::Error(ErrorCode::OtaDisabled).to_string(),
+                }
+            }
+            Command::OtaAbort => Response::Error(ErrorCode::OtaDisabled).to_string(),
+            Command::PairBegin(key) => self.pair_begin(key),
+            Command::PairConfirm => self.pair_confirm(),
+            Command::Unpair => self.unpair(),
+            Command::AuthBegin(nonce) => self.auth_begin(nonce),
+            Command::AuthProof(signature) => self.auth_proof(signature),
+            // No SD card slot in the emulator, as in a build without `sd-backup`
+            Command::BackupToSd { .. } | Command::RestoreFromSd { .. } => {
+                match self.check_trust() {
+                    Err(code) => Response::Error(code).to_string(),
+                    Ok(()) => Response::Error(ErrorCode::SdDisabled).to_string(),
+                }
+            }
+            // No audit log kept, as in a build without `audit`
+            Command::AuditRegister(_) | Command::AuditExport => match self.check_trust() {
+                Err(code) => Response::Error(code).to_string(),
+                Ok(()) => Response::Error(ErrorCode::AuditDisabled).to_string(),
+            },
+            Command::Shutdown => Response::ShutdownOk.to_string(),
+            Command::Malformed(name) => {
+                Response::ErrorDetail(ErrorCode::BadArguments, name).to_string()
+            }
+            Command::Unknown(_) => Response::Error(ErrorCode::UnknownCommand).to_string(),
+        };
+        Some(response)
+    }
+
+    fn info_fields(&self) -> String {
+        let features = if self.twofa {
+            "twofa,base64,bs58"
+        } else {
+            "base64,bs58"
+        };
+        format!(
+            "fw=emulator-{};proto={};commit=none;built=none;features={};chip=emulator;rev=v0.0;\
+             cores=1;flash=0;reset=PowerOn;jtag=disabled;download=disabled;flash_enc=off;\
+             secure_boot=off;device_id={}",
+            env!("CARGO_PKG_VERSION"),
+            signer_protocol::VERSION,
+            features,
+            self.device_id
+        )
+    }
+
+    /// Refusal for commands that need the paired host on this connection
+    fn check_trust(&self) -> Result<(), ErrorCode> {
+        match (&self.host, self.authenticated) {
+            (None, _) => Err(ErrorCode::NotPaired),
+            (Some(_), false) => Err(ErrorCode::Unauthenticated),
+            (Some(_), true) => Ok(()),
+        }
+    }
+
+    fn provision(&mut self, serial: &str) -> String {
+        if self.provisioning.serial.is_some() {
+            return Response::ErrorDetail(ErrorCode::Provision, "already provisioned").to_string();
+        }
+        if !identity::validate_serial(serial) {
+            return Response::ErrorDetail(ErrorCode::Provision, "invalid serial").to_string();
+        }
+        self.button.wait();
+        match identity::set_serial::<_, ()>(&mut self.storage, &mut self.provisioning, serial) {
+            Ok(()) => Response::Provisioned(serial).to_string(),
+            Err(e) => {
+                let detail = format!("{:?}", e);
+                Response::ErrorDetail(ErrorCode::Provision, &detail).to_string()
+            }
+        }
+    }
+
+    fn set_policy(&mut self, spec: &str) -> String {
+        if let Err(code) = self.check_trust() {
+            return Response::Error(code).to_string();
+        }
+        let policy = match Policy::parse(spec) {
+            Ok(policy) => policy,
+            Err(e) => {
+                return Response::ErrorDetail(ErrorCode::SetPolicy, &e.to_string()).to_string()
+            }
+        };
+        self.button.wait();
+        let delay = policy.change_delay_secs(&self.policy);
+        if delay > 0 {
+            return match self.hold(policy, delay) {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::error!("{:#}", e);
+                    Response::ErrorDetail(ErrorCode::SetPolicy, "STORAGE").to_string()
+                }
+            };
+        }
+        if let Err(e) = self.apply_policy(policy) {
+            tracing::error!("{:#}", e);
+            return Response::ErrorDetail(ErrorCode::SetPolicy, "STORAGE").to_string();
+        }
+        Response::Policy(&self.policy.to_string()).to_string()
+    }
+
+    /// Stores `policy` as the one in effect, keeping the current one for
+    /// POLICY_ROLLBACK, and drops any pending change
+    fn apply_policy(&mut self, policy: Policy) -> Result<()> {
+        self.storage
+            .set(PREVIOUS_POLICY_KEY, &self.policy.to_bytes())
+            .and_then(|()| self.storage.set(POLICY_KEY, &policy.to_bytes()))
+            .and_then(|()| self.storage.set(PENDING_POLICY_KEY, &[]))?;
+        self.policy = policy;
+        self.delayed = None;
+        Ok(())
+    }
+
+    /// Holds `policy` back for `secs`, replacing any change already pending
+    fn hold(&mut self, policy: Policy, secs: u32) -> Result<String> {
+        let mut record = secs.to_le_bytes().to_vec();
+        record.extend_from_slice(&policy.to_bytes());
+        self.storage.set(PENDING_POLICY_KEY, &record)?;
+        let response = Response::PolicyPending {
+            remaining_secs: secs,
+            policy: &policy.to_string(),
+        }
+        .to_string();
+        let change = PendingChange {
+            policy,
+            remaining_secs: secs,
+        };
+        self.delayed = Some((change, Instant::now()));
+        Ok(response)
+    }
+
+    /// Counts down a pending change, applying it once due, after dropping it
+    /// if BOOT was pressed while idle
+    fn tick_delayed(&mut self) {
+        if self.button.take_cancel() && self.delayed.take().is_some() {
+            if let Err(e) = self.storage.set(PENDING_POLICY_KEY, &[]) {
+                tracing::error!("{:#}", e);
+            }
+        }
+        let Some((change, since)) = &mut self.delayed else {
+            return;
+        };
+        let secs = since.elapsed().as_secs() as u32;
+        *since += Duration::from_secs(secs.into());
+        if change.elapse(secs) {
+            let policy = change.policy.clone();
+            if let Err(e) = self.apply_policy(policy) {
+                tracing::error!("{:#}", e);
+            }
+        }
+    }
+
+    fn rollback_policy(&mut self) -> String {
+        if let Err(code) = self.check_trust() {
+            return Response::Error(code).to_string();
+        }
+        self.button.wait();
+        let mut bytes = [0u8; 1024];
+        let previous = match self.storage.get(PREVIOUS_POLICY_KEY, &mut bytes) {
+            Ok(Some(len)) if len <= bytes.len() => Policy::from_bytes(&bytes[..len]),
+            _ => None,
+        };
+        let Some(previous) = previous else {
+            return Response::ErrorDetail(ErrorCode::PolicyRollback, "NO_PREVIOUS").to_string();
+        };
+        let delay = previous.change_delay_secs(&self.policy);
+        if delay > 0 {
+            return match self.hold(previous, delay) {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::error!("{:#}", e);
+                    Response::ErrorDetail(ErrorCode::PolicyRollback, "STORAGE").to_string()
+                }
+            };
+        }
+        let stored = self
+            .storage
+            .set(POLICY_KEY, &previous.to_bytes())
+            .and_then(|()| self.storage.set(PREVIOUS_POLICY_KEY, &[]))
+            .and_then(|()| self.storage.set(PENDING_POLICY_KEY, &[]));
+        if let Err(e) = stored {
+            tracing::error!("{:#}", e);
+            return Response::ErrorDetail(ErrorCode::PolicyRollback, "STORAGE").to_string();
+        }
+        self.policy = previous;
+        self.delayed = None;
+        Response::Policy(&self.policy.to_string()).to_string()
+    }
+
+    fn create_tx(&self) -> String {
+        if self.policy.production {
+            return Response::Error(ErrorCode::UnprotectedDevice).to_string();
+        }
+        if self.policy.chain != ChainProfile::Solana {
+            let violation = PolicyViolation::ChainMismatch.to_string();
+            return Response::ErrorDetail(ErrorCode::CreateTx, &violation).to_string();
+        }
+        let memo = Instruction::new_with_bytes(
+            Pubkey::new_from_array(MEMO_PROGRAM_ID),
+            PLACEHOLDER_MEMO.as_bytes(),
+            vec![AccountMeta::new(self.signer.pubkey(), true)],
+        );
+        let message = Message::new_with_blockhash(
+            &[memo],
+            Some(&self.signer.pubkey()),
+            &Hash::new_from_array(PLACEHOLDER_BLOCKHASH),
+        );
+        let mut transaction = Transaction::new_unsigned(message);
+        transaction.sign(&[&self.signer], transaction.message.recent_blockhash);
+        match bincode::serialize(&transaction) {
+            Ok(bytes) => {
+                let encoded = base64::engine::general_purpose::STANDARD.encode(bytes);
+                Response::Transaction(&encoded).to_string()
+            }
+            Err(e) => Response::ErrorDetail(ErrorCode::CreateTx, &e.to_string()).to_string(),
+        }
+    }
+
+    fn otp_state(&mut self) -> Option<OtpState> {
+        let mut bytes = [0u8; totp::OTP_STATE_LEN];
+        match self.storage.get(OTP_STATE_KEY, &mut bytes) {
+            Ok(Some(len)) if len <= bytes.len() => OtpState::from_bytes(&bytes[..len]),
+            _ => None,
+        }
+    }
+
+    fn otp_begin(&mut self) -> String {
+        if let Err(code) = self.check_trust() {
+            return Response::Error(code).to_string();
+        }
+        if !self.twofa {
+            return Response::Error(ErrorCode::OtpDisabled).to_string();
+        }
+        if self.otp_state().is_some_and(|state| state.enrolled) {
+            return Response::ErrorDetail(ErrorCode::OtpBegin, "already enrolled").to_string();
+        }
+        let mut secret = [0u8; OTP_BYTES];
+        if self.rng.fill(&mut secret).is_err() {
+            return Response::Error(ErrorCode::Entropy).to_string();
+        }
+        if let Err(e) = self
+            .storage
+            .set(OTP_STATE_KEY, &OtpState::new(secret).to_bytes())
+        {
+            return Response::ErrorDetail(ErrorCode::OtpBegin, &e.to_string()).to_string();
+        }
+        Response::OtpSecret {
+            secret: &BASE32_NOPAD.encode(&secret),
+            digits: OTP_DIGITS,
+            period: OTP_PERIOD,
+        }
+        .to_string()
+    }
+
+    /// Checks `code` and persists the step it matched; `None` if it doesn't
+    /// match, or there is no secret (enrolled if `enrolled`) to match it
+    fn accept_code(&mut self, code: &str, unix: Option<u64>, enrolled: bool) -> Option<u64> {
+        let mut state = self
+            .otp_state()
+            .filter(|state| state.enrolled || !enrolled)?;
+        let now = unix.unwrap_or_else(now_unix);
+        let step = totp::verify_code(code, &state.secret, now, state.last_step)?;
+        state.last_step = step;
+        state.enrolled = true;
+        self.storage
+            .set(OTP_STATE_KEY, &state.to_bytes())
+            .inspect_err(|e| tracing::error!("{:#}", e))
+            .ok()?;
+        Some(now)
+    }
+
+    fn otp_confirm(&mut self, code: &str, unix: Option<u64>) -> String {
+        if !self.twofa {
+            return Response::Error(ErrorCode::OtpDisabled).to_string();
+        }
+        match self.accept_code(code, unix, false) {
+            Some(_) => Response::OtpConfirmed.to_string(),
+            None => Response::Error(ErrorCode::OtpBadCode).to_string(),
+        }
+    }
+
+    fn otp_unlock(&mut self, code: &str, unix: Option<u64>) -> String {
+        if !self.twofa {
+            return Response::Error(ErrorCode::OtpDisabled).to_string();
+        }
+        match self.accept_code(code, unix, true) {
+            Some(now) => {
+                self.unlocked_until = now + UNLOCK_SECS;
+                Response::UnlockedUntil(self.unlocked_until).to_string()
+            }
+            None => Response::Error(ErrorCode::OtpBadCode).to_string(),
+        }
+    }
+
+    fn otp_status(&mut self) -> String {
+        if !self.twofa {
+            return Response::Error(ErrorCode::OtpDisabled).to_string();
+        }
+        let enrolled = self.otp_state().is_some_and(|state| state.enrolled);
+        let fields = format!(
+            "enrolled={};unlocked_until={};now={}",
+            if enrolled { "yes" } else { "no" },
+            self.unlocked_until,
+            now_unix()
+        );
+        Response::OtpStatus(&fields).to_string()
+    }
+
+    fn sign(&mut self, encoded: &str, tokens: &str) -> String {
+        if let Err(code) = self.check_trust() {
+            return Response::Error(code).to_string();
+        }
+        if self.policy.production {
+            return Response::Error(ErrorCode::UnprotectedDevice).to_string();
+        }
+        if self.twofa && now_unix() > self.unlocked_until {
+            return Response::Error(ErrorCode::Locked).to_string();
+        }
+        let Ok(message) = base64::engine::general_purpose::STANDARD.decode(encoded) else {
+            return Response::ErrorDetail(ErrorCode::Encoding, "base64").to_string();
+        };
+        let info =
+            tx_introspection::introspect_transaction(&message, &self.signer.pubkey().to_bytes());
+        let labels = token_labels::parse(tokens).and_then(|labels| match &info {
+            Ok(info) => token_labels::bind(&labels, info).map(|()| labels),
+            Err(_) if labels.is_empty() => Ok(labels),
+            Err(_) => Err(LabelError::UnknownMint),
+        });
+        match (&info, labels) {
+            (_, Err(e)) => {
+                return Response::ErrorDetail(ErrorCode::Labels, &e.to_string()).to_string()
+            }
+            (Ok(info), Ok(labels)) => tracing::debug!(
+                "Signing:\n{}",
+                tx_introspection::format_labeled_transaction_info(info, &labels)
+            ),
+            (Err(_), Ok(_)) => {}
+        }
+        let signing_input = match self.policy.signing_input(&message, info.as_ref().ok()) {
+            Ok(input) => input,
+            Err(violation) => {
+                return Response::ErrorDetail(ErrorCode::Policy, &violation.to_string()).to_string()
+            }
+        };
+        self.button.wait();
+        let signature = self.signer.sign_message(&signing_input);
+        let encoded = base64::engine::general_purpose::STANDARD.encode(signature);
+        Response::Signature(&encoded).to_string()
+    }
+
+    fn pair_begin(&mut self, host_key: &str) -> String {
+        let host_key = match Pubkey::from_str(host_key) {
+            Ok(key) => key,
+            Err(_) => return Response::ErrorDetail(ErrorCode::Pair, "BAD_KEY").to_string(),
+        };
+        if !host_key.is_on_curve() {
+            return Response::ErrorDetail(ErrorCode::Pair, "not a valid Ed25519 key").to_string();
+        }
+        self.pending = Some(host_key);
+        let code =
+            transcript::pairing_code(&host_key.to_bytes(), &self.session.pubkey().to_bytes());
+        println!(
+            "Pairing code: {}",
+            code.iter().map(u8::to_string).collect::<Vec<_>>().join(" ")
+        );
+        Response::Pairing(&self.session.pubkey().to_string()).to_string()
+    }
+
+    fn pair_confirm(&mut self) -> String {
+        let Some(host) = self.pending else {
+            return Response::ErrorDetail(ErrorCode::Pair, "NOT_STARTED").to_string();
+        };
+        self.button.wait();
+        if let Err(e) = transcript::store_host(&mut self.storage, &host.to_bytes()) {
+            tracing::error!("{:?}", e);
+            return Response::ErrorDetail(ErrorCode::Pair, "STORAGE").to_string();
+        }
+        self.pending = None;
+        self.host = Some(host);
+        self.challenge = None;
+        self.authenticated = false;
+        Response::Paired.to_string()
+    }
+
+    fn unpair(&mut self) -> String {
+        if let Err(code) = self.check_trust() {
+            return Response::Error(code).to_string();
+        }
+        self.button.wait();
+        if let Err(e) = transcript::clear_host(&mut self.storage) {
+            tracing::error!("{:?}", e);
+            return Response::ErrorDetail(ErrorCode::Pair, "STORAGE").to_string();
+        }
+        self.host = None;
+        self.pending = None;
+        self.challenge = None;
+        self.authenticated = false;
+        Response::Unpaired.to_string()
+    }
+
+    fn auth_begin(&mut self, encoded: &str) -> String {
+        self.authenticated = false;
+        self.challenge = None;
+        let Ok(host_nonce) = base64::engine::general_purpose::STANDARD.decode(encoded) else {
+            return Response::ErrorDetail(ErrorCode::Encoding, "base64").to_string();
+        };
+        let Some(host) = self.host else {
+            return Response::Error(ErrorCode::NotPaired).to_string();
+        };
+        let Ok(host_nonce) = <[u8; NONCE_LEN]>::try_from(host_nonce) else {
+            return Response::Error(ErrorCode::BadArguments).to_string();
+        };
+        let mut device_nonce = [0u8; NONCE_LEN];
+        if self.rng.fill(&mut device_nonce).is_err() {
+            return Response::Error(ErrorCode::Entropy).to_string();
+        }
+        let message =
+            transcript::device_proof_message(&host.to_bytes(), &host_nonce, &device_nonce);
+        let signature = self.session.sign_message(&message);
+        self.challenge = Some((host_nonce, device_nonce));
+        let engine = base64::engine::general_purpose::STANDARD;
+        Response::AuthChallenge {
+            nonce: &engine.encode(device_nonce),
+            signature: &engine.encode(signature),
+        }
+        .to_string()
+    }
+
+    fn auth_proof(&mut self, encoded: &str) -> String {
+        let Ok(signature) = base64::engine::general_purpose::STANDARD.decode(encoded) else {
+            return Response::ErrorDetail(ErrorCode::Encoding, "base64").to_string();
+        };
+        let (Some(host), Some((host_nonce, device_nonce))) = (self.host, self.challenge.take())
+        else {
+            return Response::Error(ErrorCode::Auth).to_string();
+        };
+        let Ok(signature) = Signature::try_from(signature.as_slice()) else {
+            return Response::Error(ErrorCode::Auth).to_string();
+        };
+        let message = transcript::host_proof_message(
+            &self.session.pubkey().to_bytes(),
+            &device_nonce,
+            &host_nonce,
+        );
+        if !signature.verify(host.as_ref(), &message) {
+            return Response::Error(ErrorCode::Auth).to_string();
+        }
+        self.authenticated = true;
+        Response::Authenticated.to_string()
+    }
+}
+
+fn key_error<E: std::fmt::Debug>(e: KeyError<anyhow::Error, E>) -> anyhow::Error {
+    match e {
+        KeyError::Storage(e) => e,
+        e => anyhow!("signing key unavailable: {:?}", e),
+    }
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Runs the emulator until SHUTDOWN, or until it is killed
+pub fn run(args: &EmulateArgs) -> Result<()> {
+    // Held until the end: dropping it deletes the temporary state
+    let temporary = match &args.state {
+        Some(_) => None,
+        None => Some(
+            tempfile::Builder::new()
+                .prefix("esp32-signer-emulator")
+                .tempdir()
+                .context("create a temporary state directory")?,
+        ),
+    };
+    let state = match (&args.state, &temporary) {
+        (Some(path), _) => path.clone(),
+        (None, Some(dir)) => dir.path().join("state.json"),
+        (None, None) => unreachable!("made above"),
+    };
+    let control = args
+        .control
+        .clone()
+        .unwrap_or_else(|| state.with_extension("button"));
+
+    let button = Button::new(args.auto_press);
+    let mut emulator = Emulator::boot(FileStorage::open(&state)?, button.clone(), args.twofa)?;
+
+    // A socket left by an earlier run that was killed
+    let _ = std::fs::remove_file(&control);
+    let listener = UnixListener::bind(&control)
+        .with_context(|| format!("bind the control socket {}", control.display()))?;
+    std::thread::spawn(move || serve_control(listener, button));
+
+    let mut master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+    grantpt(&master)?;
+    unlockpt(&master)?;
+    let tty = ptsname_r(&master)?;
+    // Held open so the master keeps reading while no client has the port,
+    // and in raw mode so lines come through as the host sent them
+    let slave: File = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&tty)
+        .with_context(|| format!("open {}", tty))?;
+    let mut termios = tcgetattr(&slave)?;
+    cfmakeraw(&mut termios);
+    tcsetattr(&slave, SetArg::TCSANOW, &termios)?;
+    if let Some(link) = &args.link {
+        let _ = std::fs::remove_file(link);
+        std::os::unix::fs::symlink(&tty, link)
+            .with_context(|| format!("link {} to {}", link.display(), tty))?;
+    }
+
+    println!("Emulated signer {}", emulator.signer.pubkey());
+    println!(
+        "Port: {}",
+        args.link
+            .as_ref()
+            .map_or(tty.clone(), |link| link.display().to_string())
+    );
+    println!("State: {}", state.display());
+    if args.auto_press {
+        println!("BOOT: pressed automatically");
+    } else {
+        println!("BOOT: send `press` to {}", control.display());
+    }
+    std::io::stdout().flush()?;
+
+    let mut line = Vec::new();
+    let mut chunk = [0u8; 256];
+    'serve: loop {
+        let n = master.read(&mut chunk)?;
+        for &byte in &chunk[..n] {
+            if byte != b'\n' {
+                line.push(byte);
+                continue;
+            }
+            let text = String::from_utf8_lossy(&line).into_owned();
+            line.clear();
+            let Some(reply) = emulator.handle(&text) else {
+                continue;
+            };
+            master.write_all(reply.as_bytes())?;
+            master.write_all(b"\r\n")?;
+            master.flush()?;
+            if matches!(Command::parse(&text), Command::Shutdown) {
+                break 'serve;
+            }
+        }
+    }
+
+    drop(slave);
+    let _ = std::fs::remove_file(&control);
+    if let Some(link) = &args.link {
+        let _ = std::fs::remove_file(link);
+    }
+    println!("Shut down");
+    Ok(())
+}
@@ -0,0 +1,412 @@
+//! Fault injection on the serial link, to reproduce a flaky USB adapter
+//! without one.
+//!
+//! With --faults, every byte received from the device may be dropped,
+//! duplicated, corrupted (one bit flipped) or held back for a while, each
+//! with its own probability, drawn from a seeded RNG:
+//!
+//! ```text
+//! --faults seed=7,drop=0.01,dup=0.005,corrupt=0.002,delay=0.001,delay-ms=1500
+//! ```
+//!
+//! The draws are made per byte, in order, so the same seed and the same
+//! traffic give the same faults: a field report plus its seed reruns
+//! exactly. Bytes sent to the device are left alone; a mangled command just
+//! gets an `ERROR:` reply, which tells the retry and resync logic nothing.
+
+use anyhow::{anyhow, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use signer_protocol::transport::Port;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+static SCHEDULE: OnceLock<Schedule> = OnceLock::new();
+
+/// Probabilities per received byte, and the seed they are drawn with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub seed: u64,
+    pub drop: f64,
+    pub duplicate: f64,
+    pub corrupt: f64,
+    pub delay: f64,
+    /// How long a delayed byte, and everything after it, is held back
+    pub delay_for: Duration,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            seed: 0,
+            drop: 0.0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            delay: 0.0,
+            delay_for: Duration::from_millis(500),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    /// `key=value` pairs separated by commas; keys left out are 0 (or
+    /// 500 ms for `delay-ms`)
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut schedule = Schedule::default();
+        for pair in spec.split(',').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected key=value in --faults, got '{}'", pair))?;
+            let bad = |e: &dyn std::fmt::Display| anyhow!("Bad --faults {}: {}", key, e);
+            let probability = || -> Result<f64> {
+                let p: f64 = value.parse().map_err(|e| bad(&e))?;
+                if !(0.0..=1.0).contains(&p) {
+                    return Err(bad(&"not between 0 and 1"));
+                }
+                Ok(p)
+            };
+            match key {
+                "seed" => schedule.seed = value.parse().map_err(|e| bad(&e))?,
+                "drop" => schedule.drop = probability()?,
+                "dup" => schedule.duplicate = probability()?,
+                "corrupt" => schedule.corrupt = probability()?,
+                "delay" => schedule.delay = probability()?,
+                "delay-ms" => {
+                    schedule.delay_for = Duration::from_millis(value.parse().map_err(|e| bad(&e))?)
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "Unknown --faults key '{}' (seed, drop, dup, corrupt, delay, delay-ms)",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(schedule)
+    }
+}
+
+/// Injects faults into every port opened from now on
+pub fn start(schedule: Schedule) -> Result<()> {
+    tracing::warn!("Injecting serial faults: {:?}", schedule);
+    SCHEDULE
+        .set(schedule)
+        .map_err(|_| anyhow!("Already injecting faults"))
+}
+
+/// The schedule --faults set, if any
+pub fn schedule() -> Option<Schedule> {
+    SCHEDULE.get().cloned()
+}
+
+/// A port whose received bytes go through a [`Schedule`], if one is set
+pub struct Faulty<P> {
+    port: P,
+    injector: Option<Injector>,
+}
+
+struct Injector {
+    schedule: Schedule,
+    rng: StdRng,
+    /// Faulted bytes not handed out yet
+    out: VecDeque<u8>,
+    /// Nothing is handed out before this
+    held_until: Option<Instant>,
+}
+
+impl<P> Faulty<P> {
+    pub fn new(port: P, schedule: Option<Schedule>) -> Self {
+        Faulty {
+            port,
+            injector: schedule.map(|schedule| Injector {
+                rng: StdRng::seed_from_u64(schedule.seed),
+                schedule,
+                out: VecDeque::new(),
+                held_until: None,
+            }),
+        }
+    }
+
+    pub fn get_ref(&self) -> &P {
+        &self.port
+    }
+
+    /// Stops injecting; bytes already faulted are still handed out
+    #[cfg(test)]
+    fn heal(&mut self) {
+        if let Some(injector) = &mut self.injector {
+            injector.schedule = Schedule {
+                seed: injector.schedule.seed,
+                ..Schedule::default()
+            };
+        }
+    }
+}
+
+impl Injector {
+    fn feed(&mut self, byte: u8) {
+        let s = &self.schedule;
+        if self.rng.gen_bool(s.drop) {
+            tracing::trace!(byte, "fault: dropped");
+            return;
+        }
+        let byte = if self.rng.gen_bool(s.corrupt) {
+            let flipped = byte ^ (1 << self.rng.gen_range(0..8));
+            tracing::trace!(byte, flipped, "fault: corrupted");
+            flipped
+        } else {
+            byte
+        };
+        if self.rng.gen_bool(s.delay) {
+            tracing::trace!(byte, "fault: delayed");
+            self.held_until = Some(Instant::now() + s.delay_for);
+        }
+        self.out.push_back(byte);
+        if self.rng.gen_bool(s.duplicate) {
+            tracing::trace!(byte, "fault: duplicated");
+            self.out.push_back(byte);
+        }
+    }
+
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        if let Some(until) = self.held_until {
+            if Instant::now() < until {
+                return 0;
+            }
+            self.held_until = None;
+        }
+        let n = buf.len().min(self.out.len());
+        for (slot, byte) in buf.iter_mut().zip(self.out.drain(..n)) {
+            *slot = byte;
+        }
+        n
+    }
+}
+
+impl<P: Read> Read for Faulty<P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(injector) = &mut self.injector else {
+            return self.port.read(buf);
+        };
+        if injector.out.is_empty() {
+            let mut raw = vec![0u8; buf.len()];
+            let n = self.port.read(&mut raw)?;
+            for &byte in &raw[..n] {
+                injector.feed(byte);
+            }
+        }
+        Ok(injector.take(buf))
+    }
+}
+
+impl<P: Write> Write for Faulty<P> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl<P: Port> Port for Faulty<P> {
+    fn discard_input(&mut self) -> io::Result<()> {
+        if let Some(injector) = &mut self.injector {
+            injector.out.clear();
+            injector.held_until = None;
+        }
+        self.port.discard_input()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use signer_protocol::command::Command;
+    use signer_protocol::transport::{response_code, Transport, TransportError};
+
+    const PUBKEY_REPLY: &str = "PUBKEY:9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin";
+    const IDLE: Duration = Duration::from_millis(40);
+
+    /// Answers PING and GET_PUBKEY, each reply after a line of log output
+    #[derive(Default)]
+    struct FakeDevice {
+        line: Vec<u8>,
+        pending: VecDeque<u8>,
+        commands: usize,
+    }
+
+    impl Read for FakeDevice {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.pending.len());
+            for (slot, byte) in buf.iter_mut().zip(self.pending.drain(..n)) {
+                *slot = byte;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for FakeDevice {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &byte in buf {
+                if byte != b'\n' {
+                    self.line.push(byte);
+                    continue;
+                }
+                let reply = match std::mem::take(&mut self.line).as_slice() {
+                    b"" => continue,
+                    b"PING" => "PONG",
+                    b"GET_PUBKEY" => PUBKEY_REPLY,
+                    _ => "ERROR:UNKNOWN_COMMAND",
+                };
+                self.commands += 1;
+                self.pending
+                    .extend(b"I (1234) signer: command received\r\n");
+                self.pending.extend(reply.as_bytes());
+                self.pending.extend(b"\r\n");
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Port for FakeDevice {
+        fn discard_input(&mut self) -> io::Result<()> {
+            self.pending.clear();
+            Ok(())
+        }
+    }
+
+    fn flaky(seed: u64) -> Schedule {
+        Schedule {
+            seed,
+            drop: 0.01,
+            duplicate: 0.01,
+            corrupt: 0.005,
+            delay: 0.002,
+            delay_for: IDLE * 2,
+        }
+    }
+
+    fn expected(command: &Command) -> &'static str {
+        match command {
+            Command::Ping => "PONG",
+            _ => PUBKEY_REPLY,
+        }
+    }
+
+    #[test]
+    fn parses_schedules() {
+        let schedule: Schedule = "seed=7,drop=0.5,dup=0.25,corrupt=0.1,delay=1,delay-ms=20"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            schedule,
+            Schedule {
+                seed: 7,
+                drop: 0.5,
+                duplicate: 0.25,
+                corrupt: 0.1,
+                delay: 1.0,
+                delay_for: Duration::from_millis(20),
+            }
+        );
+        assert_eq!("".parse::<Schedule>().unwrap(), Schedule::default());
+        assert!("drop=2".parse::<Schedule>().is_err());
+        assert!("drop".parse::<Schedule>().is_err());
+        assert!("jitter=0.1".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let traffic: Vec<u8> = (0..4096u32).map(|i| i as u8).collect();
+        let run = |seed| {
+            let mut device = FakeDevice::default();
+            device.pending.extend(&traffic);
+            let schedule = Schedule {
+                delay: 0.0,
+                ..flaky(seed)
+            };
+            let mut port = Faulty::new(device, Some(schedule));
+            let mut out = Vec::new();
+            port.read_to_end(&mut out).unwrap();
+            out
+        };
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+        assert_ne!(run(1), traffic);
+    }
+
+    #[test]
+    fn no_schedule_passes_bytes_through() {
+        let mut device = FakeDevice::default();
+        device.pending.extend(b"PONG\r\n");
+        let mut out = Vec::new();
+        Faulty::new(device, None).read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"PONG\r\n");
+    }
+
+    #[test]
+    fn a_lost_reply_is_asked_for_again() {
+        // Everything the device sends is dropped until the port heals
+        let mut transport = Transport::new(Faulty::new(
+            FakeDevice::default(),
+            Some(Schedule {
+                drop: 1.0,
+                ..Schedule::default()
+            }),
+        ));
+        assert!(matches!(
+            transport.exchange(&Command::GetPubkey, IDLE),
+            Err(TransportError::NoReply { .. })
+        ));
+        assert_eq!(transport.port().get_ref().commands, 2);
+    }
+
+    #[test]
+    fn recovers_from_seeded_faults() {
+        let commands = [Command::GetPubkey, Command::Ping];
+        let (mut answered, mut exchanges, mut sent) = (0, 0, 0);
+        for seed in 0..12 {
+            let mut transport =
+                Transport::new(Faulty::new(FakeDevice::default(), Some(flaky(seed))));
+            for command in commands.iter().cycle().take(8) {
+                exchanges += 1;
+                // A corrupted payload can still parse, but never as the
+                // reply to another command
+                if let Ok(reply) = transport.exchange(command, IDLE) {
+                    answered += 1;
+                    assert_eq!(
+                        response_code(&reply),
+                        response_code(expected(command)),
+                        "seed {}",
+                        seed
+                    );
+                }
+            }
+
+            // Once the link is clean again, every exchange is back in step
+            transport.port_mut().heal();
+            for command in commands.iter().cycle().take(4) {
+                assert_eq!(
+                    transport.exchange(command, IDLE).unwrap(),
+                    expected(command),
+                    "seed {}",
+                    seed
+                );
+            }
+            sent += transport.port().get_ref().commands;
+        }
+        assert!(answered * 2 > exchanges, "{} of {}", answered, exchanges);
+        // Some replies were lost and asked for again
+        assert!(sent > exchanges + 12 * 4, "{} commands sent", sent);
+    }
+}
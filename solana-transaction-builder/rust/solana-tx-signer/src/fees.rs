@@ -0,0 +1,369 @@
+//! Compute budget instructions, so device-signed transactions still land when
+//! the cluster is congested.
+
+use anyhow::Result;
+use solana_client::{rpc_client::RpcClient, rpc_config::RpcSimulateTransactionConfig};
+use solana_sdk::{
+    compute_budget::{self, ComputeBudgetInstruction},
+    hash::Hash,
+    instruction::Instruction,
+    message::{Message, VersionedMessage},
+    pubkey::Pubkey,
+    signature::Signature,
+    system_instruction::SystemInstruction,
+    system_program,
+    transaction::VersionedTransaction,
+};
+
+use crate::amount::format_sol;
+use crate::config::Settings;
+use crate::fiat::Prices;
+
+/// Base fee per signature
+const LAMPORTS_PER_SIGNATURE: u64 = 5_000;
+/// Runtime defaults when a transaction sets no compute unit limit
+const DEFAULT_CU_PER_INSTRUCTION: u64 = 200_000;
+const MAX_CU_LIMIT: u64 = 1_400_000;
+/// Which of the recent prioritization fees to match, in percent
+const PRIORITY_FEE_PERCENTILE: usize = 75;
+/// Headroom on top of the simulated compute units, in percent
+const CU_LIMIT_MARGIN: u64 = 20;
+/// Compute units the two compute budget instructions themselves use
+const CU_BUDGET_INSTRUCTIONS: u64 = 300;
+
+/// The compute budget instructions to put in front of `instructions`, using
+/// `--cu-limit`/`--priority-fee` when given and estimates otherwise. Prints
+/// the most the transaction can cost in fees.
+pub fn compute_budget(
+    client: &RpcClient,
+    settings: &Settings,
+    instructions: &[Instruction],
+    payer: &Pubkey,
+) -> Result<Vec<Instruction>> {
+    let cu_limit = match settings.cu_limit {
+        Some(limit) => Some(limit),
+        None => simulated_units(client, instructions, payer).map(|units| {
+            let units = units + units * CU_LIMIT_MARGIN / 100 + CU_BUDGET_INSTRUCTIONS;
+            u32::try_from(units).unwrap_or(u32::MAX)
+        }),
+    };
+    let priority_fee = match settings.priority_fee {
+        Some(fee) => fee,
+        None => recent_priority_fee(client, instructions)?,
+    };
+
+    let mut budget = Vec::new();
+    if let Some(limit) = cu_limit {
+        budget.push(ComputeBudgetInstruction::set_compute_unit_limit(limit));
+    }
+    if priority_fee > 0 {
+        budget.push(ComputeBudgetInstruction::set_compute_unit_price(
+            priority_fee,
+        ));
+    }
+
+    let signatures = Message::new(instructions, Some(payer))
+        .header
+        .num_required_signatures;
+    let base_fee = u64::from(signatures) * LAMPORTS_PER_SIGNATURE;
+    let units = cu_limit.map_or_else(
+        || (DEFAULT_CU_PER_INSTRUCTION * instructions.len() as u64).min(MAX_CU_LIMIT),
+        u64::from,
+    );
+    let priority_cost = priority_cost(units, priority_fee);
+    println!(
+        "Compute limit {} units at {} micro-lamports/unit; max fee {} lamports ({} base + {} priority)",
+        units,
+        priority_fee,
+        u128::from(base_fee) + priority_cost,
+        base_fee,
+        priority_cost
+    );
+    Ok(budget)
+}
+
+/// The most `message` can cost in fees: the base fee per signature plus its
+/// compute unit limit at its compute unit price
+pub fn max_fee(message: &VersionedMessage) -> u64 {
+    let keys = message.static_account_keys();
+    let mut limit = None;
+    let mut price = 0;
+    let mut instructions = 0;
+    for instruction in message.instructions() {
+        if keys.get(usize::from(instruction.program_id_index)) != Some(&compute_budget::id()) {
+            instructions += 1;
+            continue;
+        }
+        // SetComputeUnitLimit is 2, SetComputeUnitPrice 3, then the value
+        match instruction.data.split_first() {
+            Some((2, value)) => limit = value.try_into().ok().map(u32::from_le_bytes),
+            Some((3, value)) => price = value.try_into().map_or(0, u64::from_le_bytes),
+            _ => {}
+        }
+    }
+    let units = limit.map_or_else(
+        || (DEFAULT_CU_PER_INSTRUCTION * instructions).min(MAX_CU_LIMIT),
+        u64::from,
+    );
+    let priority_cost = priority_cost(units, price);
+    LAMPORTS_PER_SIGNATURE * u64::from(message.header().num_required_signatures)
+        + u64::try_from(priority_cost).unwrap_or(u64::MAX)
+}
+
+/// Lamports `units` compute units cost at `price` micro-lamports each,
+/// rounded up as the runtime does
+fn priority_cost(units: u64, price: u64) -> u128 {
+    (u128::from(units) * u128::from(price)).div_ceil(1_000_000)
+}
+
+/// Summary lines on what `message` will cost, for before the device is
+/// asked: the fee getFeeForMessage quotes for this exact message, with its
+/// priority part, and the SOL leaving `device_key`'s account in all. The
+/// device works out the same fee from the message for its own display, so a
+/// quote that differs from it is pointed out.
+pub fn preview(
+    client: &RpcClient,
+    message: &VersionedMessage,
+    device_key: &Pubkey,
+    prices: &Prices,
+) -> Vec<String> {
+    let most = max_fee(message);
+    let base = LAMPORTS_PER_SIGNATURE * u64::from(message.header().num_required_signatures);
+    let quoted = match message {
+        VersionedMessage::Legacy(message) => client.get_fee_for_message(message),
+        VersionedMessage::V0(message) => client.get_fee_for_message(message),
+    };
+    let payer = message.static_account_keys().first();
+    let payer = match payer {
+        Some(payer) if payer == device_key => "the device".to_string(),
+        Some(payer) => payer.to_string(),
+        None => "?".to_string(),
+    };
+
+    let mut lines = Vec::new();
+    let fee = match quoted {
+        Ok(fee) => {
+            lines.push(format!(
+                "Fee: {} SOL{} ({} SOL priority), paid by {}",
+                format_sol(fee),
+                prices.annotate_sol(fee),
+                format_sol(most.saturating_sub(base)),
+                payer
+            ));
+            if fee != most {
+                lines.push(format!(
+                    "Warning: the RPC node quotes {} SOL, but the message allows a fee of up \
+                     to {} SOL, which the device shows",
+                    format_sol(fee),
+                    format_sol(most)
+                ));
+            }
+            fee
+        }
+        Err(e) => {
+            tracing::debug!("getFeeForMessage failed: {}", e);
+            lines.push(format!(
+                "Fee: at most {} SOL{} ({} SOL priority), paid by {} (the RPC node gave no quote)",
+                format_sol(most),
+                prices.annotate_sol(most),
+                format_sol(most.saturating_sub(base)),
+                payer
+            ));
+            most
+        }
+    };
+
+    let sent = sol_sent(message, device_key);
+    let pays = message.static_account_keys().first() == Some(device_key);
+    let total = sent.saturating_add(if pays { fee } else { 0 });
+    if total > 0 {
+        lines.push(format!(
+            "Total cost to the device's account: {} SOL{}{}",
+            format_sol(total),
+            prices.annotate_sol(total),
+            match (sent > 0, pays) {
+                (true, true) => " (transfers and fee)",
+                (true, false) => " (transfers)",
+                _ => " (fee)",
+            }
+        ));
+    }
+    lines
+}
+
+/// Lamports the system transfers in `message` move out of `from`
+fn sol_sent(message: &VersionedMessage, from: &Pubkey) -> u64 {
+    let keys = message.static_account_keys();
+    message
+        .instructions()
+        .iter()
+        .filter(|ix| keys.get(usize::from(ix.program_id_index)) == Some(&system_program::id()))
+        .filter(|ix| {
+            ix.accounts
+                .first()
+                .and_then(|index| keys.get(usize::from(*index)))
+                == Some(from)
+        })
+        .filter_map(|ix| match bincode::deserialize(&ix.data) {
+            Ok(SystemInstruction::Transfer { lamports }) => Some(lamports),
+            _ => None,
+        })
+        .fold(0, u64::saturating_add)
+}
+
+/// Compute units `instructions` use in a simulation, or `None` if the node
+/// couldn't tell (e.g. the simulation failed)
+fn simulated_units(
+    client: &RpcClient,
+    instructions: &[Instruction],
+    payer: &Pubkey,
+) -> Option<u64> {
+    let message = Message::new_with_blockhash(instructions, Some(payer), &Hash::default());
+    let transaction = VersionedTransaction {
+        signatures: vec![Signature::default(); message.header.num_required_signatures as usize],
+        message: VersionedMessage::Legacy(message),
+    };
+    let config = RpcSimulateTransactionConfig {
+        sig_verify: false,
+        replace_recent_blockhash: true,
+        commitment: Some(client.commitment()),
+        ..Default::default()
+    };
+    let result = client
+        .simulate_transaction_with_config(&transaction, config)
+        .ok()?
+        .value;
+    match result.err {
+        None => result.units_consumed,
+        Some(_) => None,
+    }
+}
+
+/// Recent prioritization fee paid for the accounts `instructions` write to
+pub fn recent_priority_fee(client: &RpcClient, instructions: &[Instruction]) -> Result<u64> {
+    let mut writable: Vec<Pubkey> = instructions
+        .iter()
+        .flat_map(|ix| ix.accounts.iter())
+        .filter(|meta| meta.is_writable)
+        .map(|meta| meta.pubkey)
+        .collect();
+    writable.sort();
+    writable.dedup();
+
+    let mut fees: Vec<u64> = client
+        .get_recent_prioritization_fees(&writable)?
+        .into_iter()
+        .map(|fee| fee.prioritization_fee)
+        .collect();
+    if fees.is_empty() {
+        return Ok(0);
+    }
+    fees.sort_unstable();
+    Ok(fees[(fees.len() - 1) * PRIORITY_FEE_PERCENTILE / 100])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::{commitment_config::CommitmentConfig, system_instruction};
+
+    fn message(instructions: &[Instruction], payer: &Pubkey) -> VersionedMessage {
+        VersionedMessage::Legacy(Message::new(instructions, Some(payer)))
+    }
+
+    #[test]
+    fn priority_fees_round_up_to_a_lamport() {
+        assert_eq!(priority_cost(200_000, 0), 0);
+        assert_eq!(priority_cost(1, 1), 1);
+        assert_eq!(priority_cost(200_000, 5), 1);
+        assert_eq!(priority_cost(200_000, 10), 2);
+        assert_eq!(priority_cost(200_001, 10), 3);
+        assert_eq!(
+            priority_cost(u64::MAX, u64::MAX),
+            (u128::from(u64::MAX) * u128::from(u64::MAX)).div_ceil(1_000_000)
+        );
+    }
+
+    #[test]
+    fn the_most_a_message_costs_uses_runtime_defaults() {
+        let device = Pubkey::new_unique();
+        let transfer = system_instruction::transfer(&device, &Pubkey::new_unique(), 1);
+        assert_eq!(
+            max_fee(&message(std::slice::from_ref(&transfer), &device)),
+            LAMPORTS_PER_SIGNATURE
+        );
+
+        // No limit: 200,000 units per instruction other than the budget's
+        let price = ComputeBudgetInstruction::set_compute_unit_price(1_000_000);
+        let two = [price.clone(), transfer.clone(), transfer.clone()];
+        assert_eq!(
+            max_fee(&message(&two, &device)),
+            LAMPORTS_PER_SIGNATURE + 400_000
+        );
+        // ...up to the 1.4M a transaction may use
+        let mut many = vec![price];
+        many.extend(vec![transfer; 10]);
+        assert_eq!(
+            max_fee(&message(&many, &device)),
+            LAMPORTS_PER_SIGNATURE + MAX_CU_LIMIT
+        );
+    }
+
+    #[test]
+    fn previews_the_fee_and_the_total_offline() {
+        let client = crate::dry_run::client(CommitmentConfig::finalized());
+        let device = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let instructions = [
+            ComputeBudgetInstruction::set_compute_unit_limit(100_000),
+            ComputeBudgetInstruction::set_compute_unit_price(100_000),
+            system_instruction::transfer(&device, &other, 1_000_000_000),
+        ];
+        let lines = preview(
+            &client,
+            &message(&instructions, &device),
+            &device,
+            &Prices::default(),
+        );
+        // No node to quote it: 5,000 base plus 10,000 priority at most
+        assert_eq!(
+            lines,
+            [
+                "Fee: at most 0.000015000 SOL (0.000010000 SOL priority), paid by the device \
+                 (the RPC node gave no quote)",
+                "Total cost to the device's account: 1.000015000 SOL (transfers and fee)",
+            ]
+        );
+
+        // Someone else pays, and the device only receives
+        let receiving = [system_instruction::transfer(&other, &device, 5)];
+        let lines = preview(
+            &client,
+            &message(&receiving, &other),
+            &device,
+            &Prices::default(),
+        );
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with(&format!("paid by {} (the RPC node gave no quote)", other)));
+    }
+
+    #[test]
+    fn adds_up_what_the_device_sends() {
+        let device = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let message = VersionedMessage::Legacy(Message::new(
+            &[
+                ComputeBudgetInstruction::set_compute_unit_limit(1_000),
+                ComputeBudgetInstruction::set_compute_unit_price(2_000),
+                system_instruction::transfer(&device, &other, 700),
+                system_instruction::transfer(&device, &other, 300),
+                system_instruction::transfer(&other, &device, 5_000),
+            ],
+            Some(&device),
+        ));
+        assert_eq!(sol_sent(&message, &device), 1_000);
+        assert_eq!(sol_sent(&message, &other), 5_000);
+        // Two signers, and 1,000 units at 2,000 micro-lamports
+        assert_eq!(max_fee(&message), 2 * LAMPORTS_PER_SIGNATURE + 2);
+    }
+}
@@ -0,0 +1,144 @@
+//! US dollar equivalents for the amounts in a confirmation summary, with
+//! `--fiat`. Prices fixed in the config file's `[fiat_prices]` table are used
+//! as they are, which works offline and in dry runs; the rest are fetched
+//! from a price API (Jupiter's by default). A price that can't be had is
+//! simply left out: fiat values are a reading aid, never a reason not to
+//! sign.
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use solana_sdk::{message::VersionedMessage, pubkey::Pubkey};
+use spl_token_2022::{
+    extension::transfer_fee::instruction::TransferFeeInstruction, instruction::TokenInstruction,
+};
+use std::collections::HashMap;
+
+use crate::config::Settings;
+
+/// Jupiter's public Price API
+pub const DEFAULT_PRICE_URL: &str = "https://lite-api.jup.ag/price/v3";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Price {
+    usd_price: f64,
+}
+
+/// USD prices by mint, SOL under the native mint; empty unless `--fiat`
+#[derive(Debug, Default)]
+pub struct Prices {
+    usd: HashMap<Pubkey, f64>,
+    enabled: bool,
+}
+
+impl Prices {
+    /// Prices of SOL and the mints `message` transfers, when `--fiat` is on
+    pub fn for_message(settings: &Settings, message: &VersionedMessage) -> Self {
+        if !settings.fiat {
+            return Prices::default();
+        }
+        let mut mints = vec![spl_token::native_mint::id()];
+        mints.extend(transferred_mints(message));
+        mints.sort();
+        mints.dedup();
+
+        let mut usd: HashMap<Pubkey, f64> = settings
+            .fiat_prices
+            .iter()
+            .filter(|(mint, _)| mints.contains(mint))
+            .map(|(mint, price)| (*mint, *price))
+            .collect();
+        let missing: Vec<Pubkey> = mints
+            .into_iter()
+            .filter(|mint| !usd.contains_key(mint))
+            .collect();
+        if !missing.is_empty() && !settings.dry_run {
+            match fetch(&settings.price_url, &missing) {
+                Ok(fetched) => usd.extend(fetched),
+                Err(e) => tracing::warn!("No fiat prices: {:#}", e),
+            }
+        }
+        Prices { usd, enabled: true }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// ` (≈ $1.23)` for `amount` base units of `mint`, or nothing without a
+    /// price
+    pub fn annotate(&self, mint: &Pubkey, amount: u64, decimals: u8) -> String {
+        match self.usd.get(mint) {
+            Some(price) => {
+                let tokens = amount as f64 / 10f64.powi(i32::from(decimals));
+                format!(" (≈ {})", format_usd(tokens * price))
+            }
+            None => String::new(),
+        }
+    }
+
+    /// [`Prices::annotate`] for lamports
+    pub fn annotate_sol(&self, lamports: u64) -> String {
+        self.annotate(
+            &spl_token::native_mint::id(),
+            lamports,
+            spl_token::native_mint::DECIMALS,
+        )
+    }
+}
+
+/// Mints of the token transfers in `message` that are static account keys
+fn transferred_mints(message: &VersionedMessage) -> Vec<Pubkey> {
+    let keys = message.static_account_keys();
+    message
+        .instructions()
+        .iter()
+        .filter(|instruction| {
+            keys.get(usize::from(instruction.program_id_index))
+                .is_some_and(|program| {
+                    *program == spl_token::id() || *program == spl_token_2022::id()
+                })
+        })
+        .filter(|instruction| {
+            matches!(
+                TokenInstruction::unpack(&instruction.data),
+                Ok(TokenInstruction::TransferChecked { .. }
+                    | TokenInstruction::TransferFeeExtension(
+                        TransferFeeInstruction::TransferCheckedWithFee { .. }
+                    ))
+            )
+        })
+        .filter_map(|instruction| keys.get(usize::from(*instruction.accounts.get(1)?)))
+        .copied()
+        .collect()
+}
+
+fn fetch(url: &str, mints: &[Pubkey]) -> Result<HashMap<Pubkey, f64>> {
+    let ids: Vec<String> = mints.iter().map(Pubkey::to_string).collect();
+    let response = reqwest::blocking::Client::new()
+        .get(url)
+        .query(&[("ids", ids.join(","))])
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| anyhow!("price request failed: {}", e))?;
+    let prices: HashMap<String, Option<Price>> = response
+        .json()
+        .map_err(|e| anyhow!("unexpected price response: {}", e))?;
+    Ok(mints
+        .iter()
+        .filter_map(|mint| {
+            let price = prices.get(&mint.to_string())?.as_ref()?;
+            Some((*mint, price.usd_price))
+        })
+        .collect())
+}
+
+/// Dollars and cents, with more digits for amounts under a cent
+fn format_usd(usd: f64) -> String {
+    if usd != 0.0 && usd.abs() < 0.01 {
+        format!("${:.6}", usd)
+    } else {
+        format!("${:.2}", usd)
+    }
+}
@@ -0,0 +1,212 @@
+//! `fuzz`: malformed input for the firmware's line parser. Every case is a
+//! line (or a burst of lines) no correct host sends; the device has to
+//! answer each with a well-formed `ERROR:` reply and still answer `PING`
+//! afterwards.
+//!
+//! Cases never carry valid arguments for commands that change the device
+//! (`PROVISION`, `SET_POLICY`, pairing, OTP codes), so a run can't provision,
+//! re-pair or lock out the board it is hardening. Random cases come from
+//! `--seed`, so a failure can be reproduced.
+
+use anyhow::{anyhow, Result};
+use clap::Args;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use signer_protocol::error::ErrorCode;
+use signer_protocol::response::Response;
+
+use crate::device::{Device, REPLY_IDLE_SECS};
+
+/// Longest line the firmware buffers (`serial::MAX_LINE_LEN`)
+const FIRMWARE_MAX_LINE_LEN: usize = 2560;
+
+/// Command names, for truncation
+const NAMES: &[&str] = &[
+    "GET_PUBKEY",
+    "GET_INFO",
+    "GET_DEVICE_ID",
+    "GET_FEATURES",
+    "TX_INFO",
+    "OTP_STATUS",
+    "GET_POLICY",
+    "SIGN:",
+    "OTA_CHUNK:",
+    "AUTH_PROOF:",
+];
+
+/// Commands whose argument gets garbage, always starting with `%`, which no
+/// encoding, number or level name contains, so each refuses it before acting
+const WITH_ARGUMENTS: &[&str] = &[
+    "SIGN",
+    "OTA_BEGIN",
+    "OTA_CHUNK",
+    "OTA_END",
+    "AUTH_BEGIN",
+    "AUTH_PROOF",
+    "LOG_LEVEL",
+];
+
+#[derive(Args, Debug)]
+pub struct FuzzArgs {
+    /// Random cases per kind (bad arguments, binary garbage)
+    #[arg(long, default_value_t = 50)]
+    iterations: usize,
+    /// Lines sent back to back, without waiting, per burst
+    #[arg(long, default_value_t = 16)]
+    burst: usize,
+    /// Seed for the random cases [default: random, printed]
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(Default)]
+struct Tally {
+    cases: usize,
+    failures: Vec<String>,
+}
+
+impl Tally {
+    fn record(&mut self, kind: &str, case: &[u8], result: Result<()>) {
+        self.cases += 1;
+        if let Err(e) = result {
+            let failure = format!("{}: {} -> {:#}", kind, preview(case), e);
+            println!("FAIL {}", failure);
+            self.failures.push(failure);
+        }
+    }
+}
+
+/// Sends every kind of malformed input and checks the device after each
+pub fn run(device: &mut Device, args: &FuzzArgs) -> Result<()> {
+    device.ping()?;
+    let seed = args.seed.unwrap_or_else(rand::random);
+    println!("Fuzzing with seed {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut tally = Tally::default();
+
+    let oversized = [
+        vec![b'A'; FIRMWARE_MAX_LINE_LEN + 1],
+        [b"SIGN:".as_slice(), &[b'A'; FIRMWARE_MAX_LINE_LEN * 2]].concat(),
+    ];
+    for case in oversized {
+        let result = expect_error(device, &case, Some(ErrorCode::LineTooLong));
+        tally.record("oversized", &case, result);
+    }
+
+    for name in NAMES {
+        for end in 1..name.len() {
+            let case = name.as_bytes()[..end].to_vec();
+            let result = expect_error(device, &case, None);
+            tally.record("truncated", &case, result);
+        }
+    }
+
+    for _ in 0..args.iterations {
+        let name = WITH_ARGUMENTS[rng.gen_range(0..WITH_ARGUMENTS.len())];
+        let case = [name.as_bytes(), b":%", &printable(&mut rng)].concat();
+        let result = expect_error(device, &case, None);
+        tally.record("bad arguments", &case, result);
+    }
+
+    for _ in 0..args.iterations {
+        let case = garbage(&mut rng);
+        let result = expect_error(device, &case, None);
+        tally.record("binary garbage", &case, result);
+    }
+
+    for _ in 0..4 {
+        let lines: Vec<Vec<u8>> = (0..args.burst)
+            .map(|i| match i % 3 {
+                0 => b"GET_PUBKEY".to_vec(),
+                1 => garbage(&mut rng),
+                _ => b"SIGN:%%%".to_vec(),
+            })
+            .collect();
+        let result = burst(device, &lines);
+        tally.record("rapid fire", &lines.concat(), result);
+    }
+
+    println!("{} cases, {} failed", tally.cases, tally.failures.len());
+    if !tally.failures.is_empty() {
+        return Err(anyhow!(
+            "the device mishandled {} case(s) (seed {})",
+            tally.failures.len(),
+            seed
+        ));
+    }
+    Ok(())
+}
+
+/// Sends `case`, which has to be refused (with `code`, if given), then
+/// checks the device still answers
+fn expect_error(device: &mut Device, case: &[u8], code: Option<ErrorCode>) -> Result<()> {
+    device.send_raw(case)?;
+    let reply = device.receive(REPLY_IDLE_SECS)?;
+    let got = match Response::parse(&reply) {
+        Some(Response::Error(got) | Response::ErrorDetail(got, _)) => got,
+        _ => return Err(anyhow!("not an error reply: {:?}", reply)),
+    };
+    match code {
+        Some(code) if code != got => Err(anyhow!("expected ERROR:{}, got {}", code, reply)),
+        _ => still_alive(device),
+    }
+}
+
+/// Sends `lines` without waiting, then reads a reply for each: the answer
+/// to the line, or `ERROR:BUSY` for lines the device had no room for
+fn burst(device: &mut Device, lines: &[Vec<u8>]) -> Result<()> {
+    for line in lines {
+        device.send_raw(line)?;
+    }
+    let mut busy = 0;
+    for _ in lines {
+        let reply = device.receive(REPLY_IDLE_SECS)?;
+        match Response::parse(&reply) {
+            Some(Response::Error(ErrorCode::Busy)) => busy += 1,
+            Some(_) => {}
+            None => return Err(anyhow!("malformed reply in a burst: {:?}", reply)),
+        }
+    }
+    tracing::debug!(busy, lines = lines.len(), "burst answered");
+    still_alive(device)
+}
+
+fn still_alive(device: &mut Device) -> Result<()> {
+    device
+        .ping()
+        .map_err(|e| anyhow!("stopped answering PING: {:#}", e))
+}
+
+/// Up to 200 printable ASCII characters
+fn printable(rng: &mut StdRng) -> Vec<u8> {
+    let len = rng.gen_range(0..200);
+    (0..len).map(|_| rng.gen_range(0x20..0x7f)).collect()
+}
+
+/// Up to 200 random bytes with no newline. The first is 0xff, so the line
+/// can't start with a command name, nor trim down to a blank line (the
+/// firmware reads bytes as Latin-1, where 0x85 and 0xa0 are whitespace).
+fn garbage(rng: &mut StdRng) -> Vec<u8> {
+    let len = rng.gen_range(1..200);
+    let mut bytes: Vec<u8> = (0..len)
+        .map(|_| rng.gen::<u8>())
+        .map(|b| if b == b'\n' { b'?' } else { b })
+        .collect();
+    bytes[0] = 0xff;
+    bytes
+}
+
+/// The start of a case, escaped, for the report
+fn preview(case: &[u8]) -> String {
+    let shown: String = case
+        .iter()
+        .take(48)
+        .flat_map(|&b| std::ascii::escape_default(b))
+        .map(char::from)
+        .collect();
+    if case.len() > 48 {
+        format!("{}... ({} bytes)", shown, case.len())
+    } else {
+        shown
+    }
+}
@@ -0,0 +1,563 @@
+//! SPL Governance (Realms) with the device key as the token owner: deposit
+//! governing tokens into a realm, vote on its proposals, create proposals,
+//! and withdraw the tokens again.
+//!
+//! The instructions are built here from the program's borsh layout (version
+//! 3), the way `balance` reads Metaplex metadata, rather than through the
+//! `spl-governance` crate and its program dependencies. The device key is
+//! the token owner, the governance authority and the payer of the records
+//! the program creates; `--fee-payer` still pays the fees.
+
+use anyhow::{anyhow, Result};
+use clap::{Args, ValueEnum};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    pubkey,
+    pubkey::Pubkey,
+    signature::Keypair,
+    signer::Signer,
+    system_program,
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::{amount, send, token};
+
+/// The governance program most realms use; DAOs with their own deployment
+/// pass `--program`
+pub const GOVERNANCE_PROGRAM_ID: Pubkey = pubkey!("GovER5Lthms3bLBqWub97yVrMmEogzX7xNjdXpPPCVZw");
+
+const IX_DEPOSIT_GOVERNING_TOKENS: u8 = 1;
+const IX_WITHDRAW_GOVERNING_TOKENS: u8 = 2;
+const IX_CREATE_PROPOSAL: u8 = 6;
+const IX_SIGN_OFF_PROPOSAL: u8 = 12;
+const IX_CAST_VOTE: u8 = 13;
+
+/// `ProposalState::Voting`
+const PROPOSAL_VOTING: u8 = 2;
+
+#[derive(Args, Debug)]
+pub struct RealmArgs {
+    /// The realm (DAO)
+    #[arg(long)]
+    realm: Pubkey,
+    /// Governing token mint [default: the realm's community mint]
+    #[arg(long)]
+    mint: Option<Pubkey>,
+    /// Governance program the realm belongs to
+    #[arg(long, default_value_t = GOVERNANCE_PROGRAM_ID)]
+    program: Pubkey,
+}
+
+/// How the device votes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Choice {
+    Yes,
+    No,
+    Abstain,
+    /// Veto, for the council on a community proposal or the other way round
+    Veto,
+}
+
+fn read(client: &RpcClient, address: &Pubkey, program: &Pubkey, what: &str) -> Result<Vec<u8>> {
+    let account = client
+        .get_account(address)
+        .map_err(|e| anyhow!("Failed to fetch {} {}: {}", what, address, e))?;
+    if account.owner != *program {
+        return Err(anyhow!(
+            "{} is not a {} of governance program {}",
+            address,
+            what,
+            program
+        ));
+    }
+    Ok(account.data)
+}
+
+/// The pubkey at `offset` of an account's data
+fn key_at(data: &[u8], offset: usize) -> Result<Pubkey> {
+    data.get(offset..offset + 32)
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .map(Pubkey::new_from_array)
+        .ok_or_else(|| anyhow!("governance account too short"))
+}
+
+/// What voting needs from a proposal account. Governance accounts start
+/// with a type byte; a proposal then has its governance, governing token
+/// mint, state and owner record.
+struct Proposal {
+    governance: Pubkey,
+    mint: Pubkey,
+    state: u8,
+    owner_record: Pubkey,
+}
+
+fn proposal(client: &RpcClient, address: &Pubkey, program: &Pubkey) -> Result<Proposal> {
+    let data = read(client, address, program, "proposal")?;
+    Ok(Proposal {
+        governance: key_at(&data, 1)?,
+        mint: key_at(&data, 33)?,
+        state: *data
+            .get(65)
+            .ok_or_else(|| anyhow!("governance account too short"))?,
+        owner_record: key_at(&data, 66)?,
+    })
+}
+
+/// The realm and governing token mint `args` name
+fn realm_and_mint(client: &RpcClient, args: &RealmArgs) -> Result<(Pubkey, Pubkey)> {
+    let mint = match args.mint {
+        Some(mint) => mint,
+        // The community mint follows the realm's type byte
+        None => key_at(&read(client, &args.realm, &args.program, "realm")?, 1)?,
+    };
+    Ok((args.realm, mint))
+}
+
+fn holding_address(program: &Pubkey, realm: &Pubkey, mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"governance", realm.as_ref(), mint.as_ref()], program).0
+}
+
+fn token_owner_record_address(
+    program: &Pubkey,
+    realm: &Pubkey,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"governance", realm.as_ref(), mint.as_ref(), owner.as_ref()],
+        program,
+    )
+    .0
+}
+
+fn realm_config_address(program: &Pubkey, realm: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"realm-config", realm.as_ref()], program).0
+}
+
+fn borsh_string(data: &mut Vec<u8>, s: &str) {
+    data.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    data.extend_from_slice(s.as_bytes());
+}
+
+fn deposit_instruction(
+    program: &Pubkey,
+    token_program: &Pubkey,
+    realm: &Pubkey,
+    mint: &Pubkey,
+    source: &Pubkey,
+    owner: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let mut data = vec![IX_DEPOSIT_GOVERNING_TOKENS];
+    data.extend_from_slice(&amount.to_le_bytes());
+    Instruction::new_with_bytes(
+        *program,
+        &data,
+        vec![
+            AccountMeta::new_readonly(*realm, false),
+            AccountMeta::new(holding_address(program, realm, mint), false),
+            AccountMeta::new(*source, false),
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new(
+                token_owner_record_address(program, realm, mint, owner),
+                false,
+            ),
+            AccountMeta::new(*owner, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(*token_program, false),
+            AccountMeta::new_readonly(realm_config_address(program, realm), false),
+        ],
+    )
+}
+
+fn withdraw_instruction(
+    program: &Pubkey,
+    token_program: &Pubkey,
+    realm: &Pubkey,
+    mint: &Pubkey,
+    destination: &Pubkey,
+    owner: &Pubkey,
+) -> Instruction {
+    Instruction::new_with_bytes(
+        *program,
+        &[IX_WITHDRAW_GOVERNING_TOKENS],
+        vec![
+            AccountMeta::new_readonly(*realm, false),
+            AccountMeta::new(holding_address(program, realm, mint), false),
+            AccountMeta::new(*destination, false),
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new(
+                token_owner_record_address(program, realm, mint, owner),
+                false,
+            ),
+            AccountMeta::new_readonly(*token_program, false),
+            AccountMeta::new_readonly(realm_config_address(program, realm), false),
+        ],
+    )
+}
+
+/// `Vote`, borsh: `Approve` with one choice of full weight, or a bare
+/// variant
+fn vote_data(choice: Choice) -> Vec<u8> {
+    let mut data = vec![IX_CAST_VOTE];
+    match choice {
+        Choice::Yes => {
+            data.push(0);
+            data.extend_from_slice(&1u32.to_le_bytes());
+            // VoteChoice { rank: 0, weight_percentage: 100 }
+            data.extend_from_slice(&[0, 100]);
+        }
+        Choice::No => data.push(1),
+        Choice::Abstain => data.push(2),
+        Choice::Veto => data.push(3),
+    }
+    data
+}
+
+struct VoteAccounts {
+    program: Pubkey,
+    realm: Pubkey,
+    proposal_address: Pubkey,
+    proposal: Proposal,
+    voter_record: Pubkey,
+}
+
+fn vote_instruction(accounts: &VoteAccounts, voter: &Pubkey, choice: Choice) -> Instruction {
+    let VoteAccounts {
+        program,
+        realm,
+        proposal_address,
+        proposal,
+        voter_record,
+    } = accounts;
+    let vote_record = Pubkey::find_program_address(
+        &[
+            b"governance",
+            proposal_address.as_ref(),
+            voter_record.as_ref(),
+        ],
+        program,
+    )
+    .0;
+    Instruction::new_with_bytes(
+        *program,
+        &vote_data(choice),
+        vec![
+            AccountMeta::new_readonly(*realm, false),
+            AccountMeta::new(proposal.governance, false),
+            AccountMeta::new(*proposal_address, false),
+            AccountMeta::new(proposal.owner_record, false),
+            AccountMeta::new(*voter_record, false),
+            AccountMeta::new_readonly(*voter, true),
+            AccountMeta::new(vote_record, false),
+            AccountMeta::new_readonly(proposal.mint, false),
+            AccountMeta::new(*voter, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(realm_config_address(program, realm), false),
+        ],
+    )
+}
+
+/// A single-choice proposal with a deny option, and its address
+#[allow(clippy::too_many_arguments)]
+fn create_proposal_instruction(
+    program: &Pubkey,
+    realm: &Pubkey,
+    governance: &Pubkey,
+    mint: &Pubkey,
+    owner: &Pubkey,
+    name: &str,
+    description_link: &str,
+    seed: &Pubkey,
+) -> (Instruction, Pubkey) {
+    let proposal = Pubkey::find_program_address(
+        &[
+            b"governance",
+            governance.as_ref(),
+            mint.as_ref(),
+            seed.as_ref(),
+        ],
+        program,
+    )
+    .0;
+    let deposit = Pubkey::find_program_address(
+        &[b"proposal-deposit", proposal.as_ref(), owner.as_ref()],
+        program,
+    )
+    .0;
+
+    let mut data = vec![IX_CREATE_PROPOSAL];
+    borsh_string(&mut data, name);
+    borsh_string(&mut data, description_link);
+    // VoteType::SingleChoice
+    data.push(0);
+    // One option, "Approve"
+    data.extend_from_slice(&1u32.to_le_bytes());
+    borsh_string(&mut data, "Approve");
+    // use_deny_option
+    data.push(1);
+    data.extend_from_slice(seed.as_ref());
+
+    let instruction = Instruction::new_with_bytes(
+        *program,
+        &data,
+        vec![
+            AccountMeta::new_readonly(*realm, false),
+            AccountMeta::new(proposal, false),
+            AccountMeta::new(*governance, false),
+            AccountMeta::new(
+                token_owner_record_address(program, realm, mint, owner),
+                false,
+            ),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new(*owner, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(realm_config_address(program, realm), false),
+            AccountMeta::new(deposit, false),
+        ],
+    );
+    (instruction, proposal)
+}
+
+/// Signed off by the proposal's owner, which a proposal without other
+/// signatories needs to go to a vote
+fn sign_off_instruction(
+    program: &Pubkey,
+    realm: &Pubkey,
+    governance: &Pubkey,
+    proposal: &Pubkey,
+    owner: &Pubkey,
+    owner_record: &Pubkey,
+) -> Instruction {
+    Instruction::new_with_bytes(
+        *program,
+        &[IX_SIGN_OFF_PROPOSAL],
+        vec![
+            AccountMeta::new(*realm, false),
+            AccountMeta::new(*governance, false),
+            AccountMeta::new(*proposal, false),
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new_readonly(*owner_record, false),
+        ],
+    )
+}
+
+/// Deposits `amount` (in tokens, e.g. 1.5) of the realm's governing token
+/// from the device's associated token account
+pub fn deposit(
+    device: &mut Device,
+    settings: &Settings,
+    args: &RealmArgs,
+    amount: &str,
+) -> Result<()> {
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let (realm, mint) = realm_and_mint(&client, args)?;
+    let (token_program, decimals) = token::mint_info(&client, &mint)?;
+    let amount = amount::parse_units(amount, decimals)?;
+    let source = get_associated_token_address_with_program_id(&device_key, &mint, &token_program);
+    println!(
+        "Depositing {} of {} into realm {}",
+        amount::format_units(amount, decimals),
+        mint,
+        realm
+    );
+    let instruction = deposit_instruction(
+        &args.program,
+        &token_program,
+        &realm,
+        &mint,
+        &source,
+        &device_key,
+        amount,
+    );
+    submit(&client, device, settings, &device_key, &[instruction])
+}
+
+/// Withdraws all of the device's governing tokens from the realm to its
+/// associated token account; the program refuses while the device has votes
+/// on proposals still being voted on
+pub fn withdraw(device: &mut Device, settings: &Settings, args: &RealmArgs) -> Result<()> {
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let (realm, mint) = realm_and_mint(&client, args)?;
+    let (token_program, _) = token::mint_info(&client, &mint)?;
+    let destination =
+        get_associated_token_address_with_program_id(&device_key, &mint, &token_program);
+    println!(
+        "Withdrawing the device's {} tokens from realm {} to {}",
+        mint, realm, destination
+    );
+    let mut instructions = Vec::new();
+    if client.get_account(&destination).is_err() {
+        instructions.push(
+            spl_associated_token_account::instruction::create_associated_token_account_idempotent(
+                &device_key,
+                &device_key,
+                &mint,
+                &token_program,
+            ),
+        );
+    }
+    instructions.push(withdraw_instruction(
+        &args.program,
+        &token_program,
+        &realm,
+        &mint,
+        &destination,
+        &device_key,
+    ));
+    submit(&client, device, settings, &device_key, &instructions)
+}
+
+/// Casts the device's vote on `proposal`
+pub fn vote(
+    device: &mut Device,
+    settings: &Settings,
+    program: &Pubkey,
+    proposal_address: &Pubkey,
+    choice: Choice,
+) -> Result<()> {
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let proposal = proposal(&client, proposal_address, program)?;
+    if proposal.state != PROPOSAL_VOTING {
+        return Err(anyhow!(
+            "Proposal {} isn't being voted on (state {})",
+            proposal_address,
+            proposal.state
+        ));
+    }
+    let governance = read(&client, &proposal.governance, program, "governance")?;
+    let realm = key_at(&governance, 1)?;
+    let voter_record = token_owner_record_address(program, &realm, &proposal.mint, &device_key);
+    if client.get_account(&voter_record).is_err() {
+        return Err(anyhow!(
+            "The device has no {} tokens deposited in realm {}; `gov deposit` them first",
+            proposal.mint,
+            realm
+        ));
+    }
+    println!(
+        "Voting {:?} on proposal {} in realm {}",
+        choice, proposal_address, realm
+    );
+    let accounts = VoteAccounts {
+        program: *program,
+        realm,
+        proposal_address: *proposal_address,
+        proposal,
+        voter_record,
+    };
+    let instruction = vote_instruction(&accounts, &device_key, choice);
+    submit(&client, device, settings, &device_key, &[instruction])
+}
+
+/// Creates a yes/no proposal owned by the device under `governance` and,
+/// unless `draft`, signs it off so voting starts
+pub fn create_proposal(
+    device: &mut Device,
+    settings: &Settings,
+    args: &RealmArgs,
+    governance: &Pubkey,
+    name: &str,
+    description_link: &str,
+    draft: bool,
+) -> Result<()> {
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let (realm, mint) = realm_and_mint(&client, args)?;
+    let governance_realm = key_at(&read(&client, governance, &args.program, "governance")?, 1)?;
+    if governance_realm != realm {
+        return Err(anyhow!(
+            "Governance {} belongs to realm {}, not {}",
+            governance,
+            governance_realm,
+            realm
+        ));
+    }
+    let owner_record = token_owner_record_address(&args.program, &realm, &mint, &device_key);
+    // Any fresh key will do: it only makes the proposal address unique
+    let seed = Keypair::new().pubkey();
+    let (create, proposal) = create_proposal_instruction(
+        &args.program,
+        &realm,
+        governance,
+        &mint,
+        &device_key,
+        name,
+        description_link,
+        &seed,
+    );
+    let mut instructions = vec![create];
+    if !draft {
+        instructions.push(sign_off_instruction(
+            &args.program,
+            &realm,
+            governance,
+            &proposal,
+            &device_key,
+            &owner_record,
+        ));
+    }
+    println!("Creating proposal \"{}\" at {}", name, proposal);
+    submit(&client, device, settings, &device_key, &instructions)
+}
+
+fn submit(
+    client: &RpcClient,
+    device: &mut Device,
+    settings: &Settings,
+    device_key: &Pubkey,
+    instructions: &[Instruction],
+) -> Result<()> {
+    let mut fee_payer = send::load_fee_payer(settings)?;
+    send::sign_and_submit(
+        client,
+        settings,
+        device,
+        device_key,
+        fee_payer.as_mut(),
+        None,
+        instructions,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_votes_and_proposals() {
+        assert_eq!(vote_data(Choice::Yes), [13, 0, 1, 0, 0, 0, 0, 100]);
+        assert_eq!(vote_data(Choice::No), [13, 1]);
+        assert_eq!(vote_data(Choice::Veto), [13, 3]);
+
+        let owner = Pubkey::new_unique();
+        let seed = Pubkey::new_unique();
+        let (instruction, proposal) = create_proposal_instruction(
+            &GOVERNANCE_PROGRAM_ID,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &owner,
+            "Fund it",
+            "",
+            &seed,
+        );
+        assert_eq!(instruction.accounts[1].pubkey, proposal);
+        let mut expected = vec![IX_CREATE_PROPOSAL, 7, 0, 0, 0];
+        expected.extend_from_slice(b"Fund it");
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0]);
+        expected.extend_from_slice(b"Approve");
+        expected.push(1);
+        expected.extend_from_slice(seed.as_ref());
+        assert_eq!(instruction.data, expected);
+    }
+}
@@ -0,0 +1,331 @@
+//! The signing API as a gRPC service (`proto/signer.proto`), for backend
+//! services in other languages. Same rules as `serve`: loopback only, a
+//! bearer token on every call, and the BOOT button for every signature.
+//! Calls share the device through `AsyncDevice`, so one waiting for the
+//! button doesn't hold up a thread.
+
+use anyhow::{anyhow, Result};
+use solana_sdk::{pubkey::Pubkey, signature::Signature, transaction::VersionedTransaction};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio_stream::{wrappers::WatchStream, Stream, StreamExt};
+use tonic::{service::Interceptor, Request, Response, Status};
+
+use crate::async_device::AsyncDevice;
+use crate::confirm;
+use crate::device::Device;
+use crate::fiat::Prices;
+use crate::message;
+use crate::metrics::{self, Outcome, Timer, METRICS};
+use crate::network::Threshold;
+use crate::notify::Notifier;
+use crate::policy;
+use crate::{send, serve};
+
+mod proto {
+    tonic::include_proto!("esp32signer.v1");
+}
+
+use proto::device_status_response::State;
+use proto::signer_server::{Signer, SignerServer};
+use proto::{
+    DeviceStatusRequest, DeviceStatusResponse, GetPubkeyRequest, GetPubkeyResponse,
+    SignMessageRequest, SignMessageResponse, SignTransactionRequest, SignTransactionResponse,
+};
+
+/// Turns away calls without the right `authorization` metadata
+#[derive(Clone)]
+struct BearerToken {
+    token: String,
+}
+
+impl Interceptor for BearerToken {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let given = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match given {
+            Some(given) if serve::same_token(given, &self.token) => Ok(request),
+            _ => Err(Status::unauthenticated("missing or wrong bearer token")),
+        }
+    }
+}
+
+struct Service {
+    device: AsyncDevice,
+    pubkey: Pubkey,
+    status: Arc<watch::Sender<DeviceStatusResponse>>,
+    notifier: Arc<Notifier>,
+    threshold: Threshold,
+}
+
+/// Serves the gRPC API on `options.listen` (a loopback address) until
+/// interrupted. Without a token, a random one is generated and printed.
+pub fn serve(device: Device, options: &serve::ServeOptions) -> Result<()> {
+    let address = serve::loopback_address(options.listen)?;
+    let token = serve::token_or_random(options.token);
+    if let Some(listen) = options.metrics_listen {
+        metrics::serve_http(listen, token.clone())?;
+    }
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let device = AsyncDevice::from_device(device)?;
+        let pubkey = device.pubkey().await?;
+        for sink in options.notify {
+            println!("Notifying {}", sink);
+        }
+        let notifier = Notifier::start(options.notify.to_vec(), options.templates.clone(), pubkey);
+        let threshold = options.threshold.clone();
+        run(device, pubkey, notifier, threshold, address, token).await
+    })
+}
+
+async fn run(
+    device: AsyncDevice,
+    pubkey: Pubkey,
+    notifier: Notifier,
+    threshold: Threshold,
+    address: std::net::SocketAddr,
+    token: String,
+) -> Result<()> {
+    let (status, _) = watch::channel(DeviceStatusResponse {
+        state: State::Idle.into(),
+        pubkey: pubkey.to_string(),
+        detail: String::new(),
+    });
+    let service = Service {
+        device,
+        pubkey,
+        status: Arc::new(status),
+        notifier: Arc::new(notifier),
+        threshold,
+    };
+    let service = SignerServer::with_interceptor(
+        service,
+        BearerToken {
+            token: token.clone(),
+        },
+    );
+
+    println!("gRPC signing service for {} on {}", pubkey, address);
+    println!("authorization: Bearer {}", token);
+    tonic::transport::Server::builder()
+        .add_service(service)
+        .serve(address)
+        .await
+        .map_err(|e| anyhow!("gRPC server failed: {}", e))
+}
+
+impl Service {
+    /// Signs `message` on the device once the BOOT button is pressed. Status
+    /// subscribers see the wait and its outcome, even if the caller goes away
+    /// in the meantime.
+    async fn sign(
+        &self,
+        message: Vec<u8>,
+        what: String,
+        kind: &str,
+        summary: Vec<String>,
+    ) -> Result<Signature, Status> {
+        let timer = METRICS.request(kind);
+        let checked = match policy::supported(self.device.policy().await) {
+            Ok(Some(policy)) => policy::check(&policy, &self.pubkey, &message),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = checked {
+            return Err(self.refused(timer, e));
+        }
+        let device = self.device.clone();
+        let status = Arc::clone(&self.status);
+        let notifier = Arc::clone(&self.notifier);
+        let pubkey = self.pubkey;
+        let kind = kind.to_string();
+        tokio::spawn(async move {
+            println!("{} over gRPC", what);
+            println!("Press the BOOT button on the ESP32 to sign...");
+            status.send_modify(|status| {
+                status.state = State::WaitingForButton.into();
+                status.detail = what;
+            });
+            let request = notifier.pending("a gRPC client", &kind, &summary);
+
+            let result = device.sign(&message).await.and_then(|signature| {
+                if signature.verify(pubkey.as_ref(), &message) {
+                    Ok(signature)
+                } else {
+                    Err(anyhow!("ESP32 returned a signature that does not verify"))
+                }
+            });
+            notifier.finished(request, &result);
+            timer.finish(Outcome::of(&result));
+            status.send_modify(|status| {
+                status.state = State::Idle.into();
+                status.detail = match &result {
+                    Ok(signature) => format!("signed {}", signature),
+                    Err(e) => format!("not signed: {:#}", e),
+                };
+            });
+            result
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e| Status::aborted(format!("{:#}", e)))
+    }
+
+    /// Reports a call turned away before it reached the device
+    fn refused(&self, timer: Timer, e: anyhow::Error) -> Status {
+        let reason = format!("{:#}", e);
+        self.notifier.rejected(None, &reason);
+        timer.finish(Outcome::Rejected);
+        Status::failed_precondition(reason)
+    }
+}
+
+#[tonic::async_trait]
+impl Signer for Service {
+    type DeviceStatusStream =
+        Pin<Box<dyn Stream<Item = Result<DeviceStatusResponse, Status>> + Send>>;
+
+    async fn get_pubkey(
+        &self,
+        _request: Request<GetPubkeyRequest>,
+    ) -> Result<Response<GetPubkeyResponse>, Status> {
+        Ok(Response::new(GetPubkeyResponse {
+            pubkey: self.pubkey.to_string(),
+        }))
+    }
+
+    async fn sign_transaction(
+        &self,
+        request: Request<SignTransactionRequest>,
+    ) -> Result<Response<SignTransactionResponse>, Status> {
+        let mut transaction: VersionedTransaction =
+            bincode::deserialize(&request.into_inner().transaction)
+                .map_err(|e| Status::invalid_argument(format!("not a transaction: {}", e)))?;
+        let slot = send::signer_slot(&transaction, &self.pubkey)
+            .map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+
+        if let Err(e) = self.threshold.check(&transaction.message, &self.pubkey) {
+            return Err(self.refused(METRICS.request("transaction"), e));
+        }
+        let message = transaction.message.serialize();
+        let summary = confirm::describe_transaction(&transaction.message, &Prices::default());
+        let signature = self
+            .sign(
+                message,
+                format!("Transaction {}", transaction.message.recent_blockhash()),
+                "transaction",
+                summary,
+            )
+            .await?;
+        transaction.signatures[slot] = signature;
+        Ok(Response::new(SignTransactionResponse {
+            signature: signature.to_string(),
+            transaction: bincode::serialize(&transaction)
+                .map_err(|e| Status::internal(e.to_string()))?,
+        }))
+    }
+
+    async fn sign_message(
+        &self,
+        request: Request<SignMessageRequest>,
+    ) -> Result<Response<SignMessageResponse>, Status> {
+        let message = request.into_inner().message;
+        if message::is_transaction_message(&message) {
+            return Err(Status::invalid_argument(serve::NOT_A_MESSAGE));
+        }
+        let what = match std::str::from_utf8(&message) {
+            Ok(text) => format!("Message: {}", text),
+            Err(_) => format!("Message of {} bytes", message.len()),
+        };
+        let summary = vec![what.clone()];
+        let signature = self.sign(message, what, "message", summary).await?;
+        Ok(Response::new(SignMessageResponse {
+            signature: signature.to_string(),
+        }))
+    }
+
+    async fn device_status(
+        &self,
+        _request: Request<DeviceStatusRequest>,
+    ) -> Result<Response<Self::DeviceStatusStream>, Status> {
+        let updates = WatchStream::new(self.status.subscribe()).map(Ok);
+        Ok(Response::new(Box::pin(updates)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::Templates;
+    use solana_sdk::{
+        message::{Message, VersionedMessage},
+        native_token::LAMPORTS_PER_SOL,
+        system_instruction,
+    };
+    use std::collections::HashMap;
+
+    fn service(pubkey: Pubkey) -> Service {
+        let (status, _) = watch::channel(DeviceStatusResponse::default());
+        let templates = Templates::new(&HashMap::new()).unwrap();
+        Service {
+            device: AsyncDevice::closed(),
+            pubkey,
+            status: Arc::new(status),
+            notifier: Arc::new(Notifier::start(Vec::new(), templates, pubkey)),
+            threshold: serve::tests::mainnet(false),
+        }
+    }
+
+    #[tokio::test]
+    async fn sign_message_refuses_a_transaction_message() {
+        let pubkey = Pubkey::new_unique();
+        let service = service(pubkey);
+        let transfer = system_instruction::transfer(&pubkey, &Pubkey::new_unique(), 1);
+        let message = Message::new(&[transfer], Some(&pubkey)).serialize();
+        let status = service
+            .sign_message(Request::new(SignMessageRequest { message }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), serve::NOT_A_MESSAGE);
+
+        // Text goes on to the device (here, to find it gone)
+        let message = b"Sign in to example.com".to_vec();
+        let status = service
+            .sign_message(Request::new(SignMessageRequest { message }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn large_transfers_need_yes() {
+        let pubkey = Pubkey::new_unique();
+        let mut service = service(pubkey);
+        let transfer =
+            system_instruction::transfer(&pubkey, &Pubkey::new_unique(), 2 * LAMPORTS_PER_SOL);
+        let transaction = bincode::serialize(&VersionedTransaction {
+            signatures: vec![Signature::default()],
+            message: VersionedMessage::Legacy(Message::new(&[transfer], Some(&pubkey))),
+        })
+        .unwrap();
+
+        let request = || {
+            Request::new(SignTransactionRequest {
+                transaction: transaction.clone(),
+            })
+        };
+        let status = service.sign_transaction(request()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert!(status.message().contains("--yes"), "{}", status.message());
+
+        // With --yes it gets as far as the (closed) device
+        service.threshold = serve::tests::mainnet(true);
+        let status = service.sign_transaction(request()).await.unwrap_err();
+        assert!(!status.message().contains("--yes"), "{}", status.message());
+    }
+}
@@ -0,0 +1,232 @@
+//! `hil`: the `selftest` checks against a real, labeled signer, written out
+//! as JUnit XML and JSON for CI, so a bench of ESP32s can gate a firmware
+//! release on real-hardware results.
+//!
+//! The signer has to be picked with `--device` and carry a label (`devices
+//! label`), so every report says which board on the bench it came from.
+//! Each test in the reports carries the device traffic from while it ran,
+//! boot log and noise included, in the `--record` format.
+
+use anyhow::{anyhow, Context, Result};
+use clap::Args;
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::device::Device;
+use crate::registry;
+use crate::selftest::{self, Check, SelftestArgs, Verdict};
+
+#[derive(Args, Debug)]
+pub struct HilArgs {
+    #[command(flatten)]
+    checks: SelftestArgs,
+    /// Write a JUnit XML report here
+    #[arg(long, value_name = "FILE")]
+    junit: Option<PathBuf>,
+    /// Write a JSON report here
+    #[arg(long, value_name = "FILE")]
+    json: Option<PathBuf>,
+}
+
+/// The board the tests ran on
+#[derive(Serialize)]
+struct Board {
+    label: String,
+    device_id: String,
+    serial: Option<String>,
+    firmware: String,
+    protocol: u32,
+    pubkey: String,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    board: &'a Board,
+    /// Unix time the run started
+    started: u64,
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+    duration_secs: f64,
+    tests: Vec<TestCase<'a>>,
+}
+
+#[derive(Serialize)]
+struct TestCase<'a> {
+    name: &'a str,
+    /// "passed", "failed" or "skipped"
+    status: &'static str,
+    detail: String,
+    duration_secs: f64,
+    log: &'a str,
+}
+
+/// Runs the checks against `device`, which `selector` (from `--device`)
+/// picked, and writes the reports; fails if any check did
+pub fn run(device: &mut Device, selector: Option<&str>, args: &HilArgs) -> Result<()> {
+    if selector.is_none() {
+        return Err(anyhow!(
+            "hil runs against a labeled signer; pick it with --device <LABEL>"
+        ));
+    }
+    let board = board(device)?;
+    println!(
+        "Testing {} ({}), firmware {}\n",
+        board.label, board.device_id, board.firmware
+    );
+
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let checks = selftest::checks(device, &args.checks);
+    let total: Duration = checks.iter().map(|c| c.elapsed).sum();
+    let report = Report {
+        board: &board,
+        started,
+        passed: selftest::count(&checks, |v| matches!(v, Verdict::Pass(_))),
+        failed: selftest::count(&checks, |v| matches!(v, Verdict::Fail(_))),
+        skipped: selftest::count(&checks, |v| matches!(v, Verdict::Skip(_))),
+        duration_secs: total.as_secs_f64(),
+        tests: checks.iter().map(test_case).collect(),
+    };
+
+    println!(
+        "\n{} passed, {} failed, {} skipped in {:.1}s on {}",
+        report.passed, report.failed, report.skipped, report.duration_secs, board.label
+    );
+    if let Some(path) = &args.junit {
+        write(path, &junit(&report))?;
+        println!("JUnit report: {}", path.display());
+    }
+    if let Some(path) = &args.json {
+        write(path, &serde_json::to_string_pretty(&report)?)?;
+        println!("JSON report: {}", path.display());
+    }
+    if report.failed > 0 {
+        return Err(anyhow!(
+            "{} hil test(s) failed on {}",
+            report.failed,
+            board.label
+        ));
+    }
+    Ok(())
+}
+
+fn board(device: &mut Device) -> Result<Board> {
+    let (device_id, serial) = device.device_id()?;
+    let label = registry::label_of(&device_id).ok_or_else(|| {
+        anyhow!(
+            "Signer {} has no label; give it one with `devices label`",
+            device_id
+        )
+    })?;
+    let info = device.firmware_info()?;
+    Ok(Board {
+        label,
+        device_id,
+        serial,
+        firmware: info.version,
+        protocol: info.protocol,
+        pubkey: device.pubkey()?.to_string(),
+    })
+}
+
+fn test_case(check: &Check) -> TestCase<'_> {
+    let (status, detail) = match &check.verdict {
+        Verdict::Pass(detail) => ("passed", detail.clone()),
+        Verdict::Skip(reason) => ("skipped", reason.clone()),
+        Verdict::Fail(e) => ("failed", format!("{:#}", e)),
+    };
+    TestCase {
+        name: check.name,
+        status,
+        detail,
+        duration_secs: check.elapsed.as_secs_f64(),
+        log: &check.log,
+    }
+}
+
+/// One `<testsuite>` named after the board, with its details as properties
+fn junit(report: &Report) -> String {
+    let board = report.board;
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        xml,
+        "<testsuite name=\"hil {}\" hostname=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" \
+         time=\"{:.3}\">",
+        escape(&board.label),
+        escape(&board.label),
+        report.tests.len(),
+        report.failed,
+        report.skipped,
+        report.duration_secs
+    );
+    xml.push_str("  <properties>\n");
+    let properties = [
+        ("label", board.label.clone()),
+        ("device_id", board.device_id.clone()),
+        ("serial", board.serial.clone().unwrap_or_default()),
+        ("firmware", board.firmware.clone()),
+        ("protocol", board.protocol.to_string()),
+        ("pubkey", board.pubkey.clone()),
+        ("started", report.started.to_string()),
+    ];
+    for (name, value) in properties {
+        let _ = writeln!(
+            xml,
+            "    <property name=\"{}\" value=\"{}\"/>",
+            name,
+            escape(&value)
+        );
+    }
+    xml.push_str("  </properties>\n");
+    for test in &report.tests {
+        let _ = writeln!(
+            xml,
+            "  <testcase classname=\"selftest\" name=\"{}\" time=\"{:.3}\">",
+            escape(test.name),
+            test.duration_secs
+        );
+        match test.status {
+            "failed" => {
+                let _ = writeln!(xml, "    <failure message=\"{}\"/>", escape(&test.detail));
+            }
+            "skipped" => {
+                let _ = writeln!(xml, "    <skipped message=\"{}\"/>", escape(&test.detail));
+            }
+            _ => {}
+        }
+        let _ = writeln!(xml, "    <system-out>{}</system-out>", escape(test.log));
+        xml.push_str("  </testcase>\n");
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+/// Text safe in an XML attribute or element; control characters XML 1.0
+/// can't carry are dropped
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\n' | '\t' | '\r' => out.push(c),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn write(path: &Path, text: &str) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    }
+    std::fs::write(path, text).with_context(|| format!("write {}", path.display()))
+}
@@ -0,0 +1,127 @@
+//! Token labels sent with `SIGN_TX` (`signer_core::token_labels`): the symbol
+//! and decimals of each mint a transaction moves with `TransferChecked`, so
+//! the ESP32 can describe the transfer as "Send 12.5 USDC". Symbols come from
+//! a bundled list of well-known mints, then from the mint's metadata on chain
+//! (see `balance::token_symbol`). A mint without one goes unlabeled and the
+//! device shows its raw amount, as it always has.
+
+use signer_core::token_labels::{TokenLabel, MAX_LABELS};
+use signer_core::tx_introspection::{self, TransactionType};
+use solana_sdk::{message::VersionedMessage, pubkey::Pubkey};
+
+use crate::balance;
+use crate::config::Settings;
+
+/// Mainnet mints labeled without a lookup
+const WELL_KNOWN: &[(&str, &str)] = &[
+    ("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v", "USDC"),
+    ("Es9vMFrzaCERmJfrF4H2FYD4KCoNkY11McCe8BenwNYB", "USDT"),
+    ("So11111111111111111111111111111111111111112", "wSOL"),
+    ("mSoLzYCxHdYgdzU16g5QSh3i5K3z3KZK7ytfqcJm7So", "mSOL"),
+    ("J1toso1uCk3RLmjorhTtrVwY9HJ7X8V9yYac6Y7kGCPn", "JitoSOL"),
+    ("bSo13r4TkiE4KumL71LsHTPpL2euBYLFx6h9HP3piy1", "bSOL"),
+    ("JUPyiwrYJFskUPiHa7hkeR8VUtAeFoSYbKedZNsDvCN", "JUP"),
+    ("DezXAZ8z7PnrnRJjz3wXBoRgixCa6xjnB7YaB1pPB263", "Bonk"),
+    ("4k3Dyjzvzp8eMZWUXbBCjEvwSkkk59S5iCNLY3QrkX6R", "RAY"),
+    ("HZ1JovNiVvGrGNiiYvEozEVgZ58xaU3RKwX8eACQBCt3", "PYTH"),
+];
+
+fn well_known(mint: &Pubkey) -> Option<&'static str> {
+    WELL_KNOWN
+        .iter()
+        .find(|(address, _)| address.parse::<Pubkey>().is_ok_and(|a| a == *mint))
+        .map(|(_, symbol)| *symbol)
+}
+
+/// Mints and decimals of the `TransferChecked` instructions in `message`, as
+/// the device decodes them
+fn transfers(message: &VersionedMessage) -> Vec<(Pubkey, u8)> {
+    let bytes = message.serialize();
+    let Ok(info) = tx_introspection::introspect_transaction(&bytes, &[0; 32]) else {
+        return Vec::new();
+    };
+    let mut transfers: Vec<(Pubkey, u8)> = Vec::new();
+    for ix in &info.instructions {
+        if let TransactionType::TokenTransfer {
+            mint: Some(mint),
+            decimals: Some(decimals),
+            ..
+        } = ix
+        {
+            let mint = Pubkey::new_from_array(*mint);
+            if !transfers.iter().any(|(seen, _)| *seen == mint) {
+                transfers.push((mint, *decimals));
+            }
+        }
+    }
+    transfers
+}
+
+/// Labels for the mints `message` transfers. Only the bundled list is used
+/// on a dry run.
+pub fn for_message(settings: &Settings, message: &VersionedMessage) -> Vec<TokenLabel> {
+    let transfers = transfers(message);
+    if transfers.is_empty() {
+        return Vec::new();
+    }
+    let client = (!settings.dry_run).then(|| settings.rpc_client());
+    transfers
+        .into_iter()
+        .filter_map(|(mint, decimals)| {
+            let symbol = match well_known(&mint) {
+                Some(symbol) => Some(symbol.to_string()),
+                None => client
+                    .as_ref()
+                    .and_then(|client| balance::token_symbol(client, &mint.to_string())),
+            }?;
+            // Symbols the device can't show are left out, not truncated
+            match TokenLabel::new(&mint.to_bytes(), &symbol, decimals) {
+                Ok(label) => Some(label),
+                Err(_) => {
+                    tracing::debug!("No label for {}: symbol {:?}", mint, symbol);
+                    None
+                }
+            }
+        })
+        .take(MAX_LABELS)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use signer_core::token_labels;
+    use solana_sdk::{hash::Hash, message::Message, signature::Keypair, signer::Signer};
+
+    #[test]
+    fn labels_the_mints_a_transaction_moves() {
+        let owner = Keypair::new();
+        let usdc: Pubkey = WELL_KNOWN[0].0.parse().unwrap();
+        let transfer = spl_token::instruction::transfer_checked(
+            &spl_token::id(),
+            &Pubkey::new_unique(),
+            &usdc,
+            &Pubkey::new_unique(),
+            &owner.pubkey(),
+            &[],
+            12_500_000,
+            6,
+        )
+        .unwrap();
+        let message = VersionedMessage::Legacy(Message::new_with_blockhash(
+            &[transfer],
+            Some(&owner.pubkey()),
+            &Hash::default(),
+        ));
+        assert_eq!(transfers(&message), vec![(usdc, 6)]);
+        assert_eq!(well_known(&usdc), Some("USDC"));
+        assert_eq!(well_known(&Pubkey::new_unique()), None);
+
+        let labels = [TokenLabel::new(&usdc.to_bytes(), "USDC", 6).unwrap()];
+        let info =
+            tx_introspection::introspect_transaction(&message.serialize(), &[0; 32]).unwrap();
+        token_labels::bind(&labels, &info).unwrap();
+        let text = tx_introspection::format_labeled_transaction_info(&info, &labels);
+        assert!(text.contains("Send 12.5 USDC:"), "{}", text);
+    }
+}
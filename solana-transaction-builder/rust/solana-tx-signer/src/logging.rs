@@ -0,0 +1,78 @@
+//! Diagnostics on stderr through `tracing`: which port was picked, retries,
+//! and with --verbose every device round trip (command, latency, response
+//! code). Results and prompts stay on stdout as plain text. --record keeps
+//! the raw exchanges too (see `recording`).
+
+use anyhow::{anyhow, Result};
+use clap::{ArgAction, Args, ValueEnum};
+use std::path::PathBuf;
+use tracing_subscriber::EnvFilter;
+
+use crate::faults::{self, Schedule};
+use crate::recording;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per event, for log collectors
+    Json,
+}
+
+#[derive(Args, Debug)]
+pub struct LogArgs {
+    /// Log more: -v adds device round trips, -vv everything
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Log line format
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        env = "ESP32_SIGNER_LOG_FORMAT"
+    )]
+    log_format: LogFormat,
+
+    /// Write every byte exchanged with the device, timestamped, to FILE (see
+    /// `replay`)
+    #[arg(long, global = true, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Drop, duplicate, corrupt and delay bytes from the device on a seeded
+    /// schedule, e.g. seed=7,drop=0.01 (see `faults`)
+    #[arg(long, global = true, value_name = "SPEC")]
+    faults: Option<Schedule>,
+}
+
+/// Installs the global subscriber, and starts the --record recording and
+/// --faults injection.
+/// `RUST_LOG`, when set, overrides the verbosity flags.
+pub fn init(args: &LogArgs) -> Result<()> {
+    if let Some(path) = &args.record {
+        recording::start(path)?;
+    }
+    if let Some(schedule) = &args.faults {
+        faults::start(schedule.clone())?;
+    }
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => "error",
+        (false, 0) => "info",
+        (false, 1) => "debug",
+        (false, _) => "trace",
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match args.log_format {
+        LogFormat::Text => builder.without_time().with_target(false).try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    }
+    .map_err(|e| anyhow!("Failed to set up logging: {}", e))
+}
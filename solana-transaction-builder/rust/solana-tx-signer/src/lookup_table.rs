@@ -0,0 +1,327 @@
+//! Address lookup tables, so transactions touching many accounts (a transfer
+//! to dozens of recipients) still fit in a packet: an account loaded from a
+//! table takes one byte of the message instead of 32.
+//!
+//! Tables are created with the device key as authority. Transactions are
+//! built as legacy messages and only turned into v0 messages, loading
+//! accounts from the `--lookup-table` tables, when they are too big otherwise.
+
+use anyhow::{anyhow, Result};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    address_lookup_table::{
+        self,
+        instruction::{
+            close_lookup_table, create_lookup_table, deactivate_lookup_table, extend_lookup_table,
+        },
+        state::{AddressLookupTable, LookupTableMeta, LOOKUP_TABLE_MAX_ADDRESSES},
+        AddressLookupTableAccount,
+    },
+    commitment_config::CommitmentConfig,
+    instruction::{AccountMeta, Instruction},
+    message::{v0, Message, VersionedMessage},
+    packet::PACKET_DATA_SIZE,
+    pubkey::Pubkey,
+    signature::SIGNATURE_BYTES,
+};
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::send;
+
+/// Addresses added per transaction; more would overflow the packet
+const MAX_EXTEND: usize = 20;
+
+/// Creates a lookup table owned by the device key, filled with `addresses`
+pub fn create(device: &mut Device, settings: &Settings, addresses: &[Pubkey]) -> Result<()> {
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let mut fee_payer = send::load_fee_payer(settings)?;
+    let payer = fee_payer.as_ref().map_or(device_key, |p| p.pubkey());
+
+    // The table address is derived from a slot the cluster still remembers
+    let recent_slot = client
+        .get_slot_with_commitment(CommitmentConfig::finalized())
+        .map_err(|e| anyhow!("Failed to fetch a recent slot: {}", e))?;
+    let (create, table) = create_lookup_table(device_key, payer, recent_slot);
+    let (first, rest) = addresses.split_at(addresses.len().min(MAX_EXTEND));
+
+    println!("Creating lookup table {}", table);
+    let mut instructions = vec![create];
+    if !first.is_empty() {
+        instructions.push(extend_lookup_table(
+            table,
+            device_key,
+            Some(payer),
+            first.to_vec(),
+        ));
+    }
+    if send::sign_and_submit(
+        &client,
+        settings,
+        device,
+        &device_key,
+        fee_payer.as_mut(),
+        None,
+        &instructions,
+    )?
+    .is_none()
+    {
+        return Ok(());
+    }
+    for chunk in rest.chunks(MAX_EXTEND) {
+        add(
+            &client,
+            settings,
+            device,
+            &device_key,
+            fee_payer.as_mut(),
+            &table,
+            chunk,
+        )?;
+    }
+    println!(
+        "Lookup table {} holds {} addresses; pass --lookup-table {} to use it",
+        table,
+        addresses.len(),
+        table
+    );
+    Ok(())
+}
+
+/// Adds the `addresses` `table` doesn't hold yet
+pub fn extend(
+    device: &mut Device,
+    settings: &Settings,
+    table: &Pubkey,
+    addresses: &[Pubkey],
+) -> Result<()> {
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let (meta, held) = fetch(&client, table)?;
+    check_authority(table, &meta, &device_key)?;
+    if meta.deactivation_slot != u64::MAX {
+        return Err(anyhow!(
+            "Lookup table {} is deactivated and can't be extended",
+            table
+        ));
+    }
+
+    let mut new: Vec<Pubkey> = Vec::new();
+    for address in addresses {
+        if !held.contains(address) && !new.contains(address) {
+            new.push(*address);
+        }
+    }
+    if new.is_empty() {
+        println!("Lookup table {} already holds every address", table);
+        return Ok(());
+    }
+    if held.len() + new.len() > LOOKUP_TABLE_MAX_ADDRESSES {
+        return Err(anyhow!(
+            "Lookup table {} holds {} addresses; {} more would pass the limit of {}",
+            table,
+            held.len(),
+            new.len(),
+            LOOKUP_TABLE_MAX_ADDRESSES
+        ));
+    }
+    let mut fee_payer = send::load_fee_payer(settings)?;
+    for chunk in new.chunks(MAX_EXTEND) {
+        add(
+            &client,
+            settings,
+            device,
+            &device_key,
+            fee_payer.as_mut(),
+            table,
+            chunk,
+        )?;
+    }
+    Ok(())
+}
+
+/// Closes `table`, sending its rent to `recipient` (default: the device's
+/// account). A table has to be deactivated, and then cool down for about
+/// 513 slots, before it can be closed; the first call deactivates it.
+pub fn close(
+    device: &mut Device,
+    settings: &Settings,
+    table: &Pubkey,
+    recipient: Option<&Pubkey>,
+) -> Result<()> {
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let (meta, _) = fetch(&client, table)?;
+    check_authority(table, &meta, &device_key)?;
+    let mut fee_payer = send::load_fee_payer(settings)?;
+
+    if meta.deactivation_slot == u64::MAX {
+        println!("Deactivating lookup table {}", table);
+        let signature = send::sign_and_submit(
+            &client,
+            settings,
+            device,
+            &device_key,
+            fee_payer.as_mut(),
+            None,
+            &[deactivate_lookup_table(*table, device_key)],
+        )?;
+        if signature.is_some() {
+            println!("Run `alt close` again in about four minutes, once the table has cooled down");
+        }
+        return Ok(());
+    }
+
+    let recipient = recipient.copied().unwrap_or(device_key);
+    println!("Closing lookup table {}, rent to {}", table, recipient);
+    send::sign_and_submit(
+        &client,
+        settings,
+        device,
+        &device_key,
+        fee_payer.as_mut(),
+        None,
+        &[close_lookup_table(*table, device_key, recipient)],
+    )?;
+    Ok(())
+}
+
+/// One extend transaction, paid for (fees and rent) by the fee payer
+fn add(
+    client: &RpcClient,
+    settings: &Settings,
+    device: &mut Device,
+    device_key: &Pubkey,
+    fee_payer: Option<&mut send::FeePayer>,
+    table: &Pubkey,
+    addresses: &[Pubkey],
+) -> Result<()> {
+    let payer = fee_payer.as_ref().map_or(*device_key, |p| p.pubkey());
+    println!(
+        "Adding {} addresses to lookup table {}",
+        addresses.len(),
+        table
+    );
+    send::sign_and_submit(
+        client,
+        settings,
+        device,
+        device_key,
+        fee_payer,
+        None,
+        &[extend_lookup_table(
+            *table,
+            *device_key,
+            Some(payer),
+            addresses.to_vec(),
+        )],
+    )?;
+    Ok(())
+}
+
+/// The table's metadata and addresses
+fn fetch(client: &RpcClient, table: &Pubkey) -> Result<(LookupTableMeta, Vec<Pubkey>)> {
+    let account = client
+        .get_account(table)
+        .map_err(|e| anyhow!("Failed to fetch lookup table {}: {}", table, e))?;
+    if account.owner != address_lookup_table::program::id() {
+        return Err(anyhow!("{} is not an address lookup table", table));
+    }
+    let state = AddressLookupTable::deserialize(&account.data)
+        .map_err(|e| anyhow!("{} is not an address lookup table: {}", table, e))?;
+    Ok((state.meta, state.addresses.to_vec()))
+}
+
+fn check_authority(table: &Pubkey, meta: &LookupTableMeta, device_key: &Pubkey) -> Result<()> {
+    match meta.authority {
+        Some(authority) if authority == *device_key => Ok(()),
+        Some(authority) => Err(anyhow!(
+            "Lookup table {} belongs to {}, not this device",
+            table,
+            authority
+        )),
+        None => Err(anyhow!("Lookup table {} is frozen", table)),
+    }
+}
+
+/// `message` as it will be signed: unchanged while it fits in a packet,
+/// otherwise a v0 message loading what it can from the configured tables
+pub fn fit(client: &RpcClient, settings: &Settings, message: Message) -> Result<VersionedMessage> {
+    let legacy_size = transaction_size(&VersionedMessage::Legacy(message.clone()));
+    if legacy_size <= PACKET_DATA_SIZE {
+        return Ok(VersionedMessage::Legacy(message));
+    }
+    if settings.lookup_tables.is_empty() {
+        return Err(anyhow!(
+            "The transaction is {} bytes, over the {}-byte limit; put its accounts in a lookup \
+             table (`alt create`) and pass it with --lookup-table",
+            legacy_size,
+            PACKET_DATA_SIZE
+        ));
+    }
+
+    let mut tables = Vec::new();
+    for table in &settings.lookup_tables {
+        let (meta, addresses) = fetch(client, table)?;
+        if meta.deactivation_slot == u64::MAX {
+            tables.push(AddressLookupTableAccount {
+                key: *table,
+                addresses,
+            });
+        } else {
+            tracing::warn!("Skipping deactivated lookup table {}", table);
+        }
+    }
+    let compiled = v0::Message::try_compile(
+        &message.account_keys[0],
+        &instructions(&message),
+        &tables,
+        message.recent_blockhash,
+    )
+    .map_err(|e| anyhow!("Failed to use the lookup tables: {}", e))?;
+    let versioned = VersionedMessage::V0(compiled);
+    let size = transaction_size(&versioned);
+    if size > PACKET_DATA_SIZE {
+        return Err(anyhow!(
+            "The transaction is {} bytes even with lookup tables, over the {}-byte limit; add \
+             more of its accounts to a table (`alt extend`)",
+            size,
+            PACKET_DATA_SIZE
+        ));
+    }
+    tracing::info!(
+        "Using lookup tables: {} bytes as a v0 transaction instead of {}",
+        size,
+        legacy_size
+    );
+    Ok(versioned)
+}
+
+/// The signed transaction's size on the wire
+fn transaction_size(message: &VersionedMessage) -> usize {
+    let signatures = usize::from(message.header().num_required_signatures);
+    // The signature count is a compact-u16, one byte below 128
+    1 + signatures * SIGNATURE_BYTES + message.serialize().len()
+}
+
+/// `message`'s instructions, with their accounts spelled out again
+fn instructions(message: &Message) -> Vec<Instruction> {
+    let meta = |i: u8| {
+        let i = usize::from(i);
+        AccountMeta {
+            pubkey: message.account_keys[i],
+            is_signer: message.is_signer(i),
+            is_writable: message.is_writable(i),
+        }
+    };
+    message
+        .instructions
+        .iter()
+        .map(|ix| Instruction {
+            program_id: message.account_keys[usize::from(ix.program_id_index)],
+            accounts: ix.accounts.iter().copied().map(meta).collect(),
+            data: ix.data.clone(),
+        })
+        .collect()
+}
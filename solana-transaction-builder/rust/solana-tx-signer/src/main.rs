@@ -1,354 +1,1416 @@
 use anyhow::Result;
 use base64::Engine;
-use serialport::SerialPort;
-use solana_client::rpc_client::RpcClient;
-use solana_sdk::{
-    commitment_config::CommitmentConfig,
-    message::{Message, VersionedMessage},
-    pubkey::Pubkey,
-    signature::Signature,
-    system_instruction,
-    transaction::VersionedTransaction,
-};
-use std::str::FromStr;
-<<<<<<< HEAD
-
-=======
-use base64::Engine;
-use anyhow::Result;
->>>>>>> 3ed93ca357f4000782500396077be8e4845fe976
-// Constants for serial port, RPC URL, recipient public key, and lamports to send
-// FIXME: Change this to the correct serial port for your system.
-const SERIAL_PORT: &str = "/dev/ttyUSB0";
-const RPC_URL: &str = "https://api.devnet.solana.com";
-const RECIPIENT_PUBLIC_KEY: &str = "aQQjEjpLuDGq7f7dHC2uqaQt5QWcdYFgvpro74V66hD";
-const LAMPORTS_TO_SEND: u64 = 2_000_000;
-
-/// Creates a placeholder transaction with memo on the ESP32 and returns the base64-encoded transaction
-fn create_esp32_transaction(port: &mut Box<dyn SerialPort>) -> Result<String> {
-    // Send "CREATE_TX" with a newline as expected by ESP32
-    port.write_all("CREATE_TX\n".as_bytes())?;
-    port.flush()?;
-    println!("Requested transaction creation from ESP32");
-
-    // Read the response until newline
-    let mut buffer = String::new();
-    let mut byte = [0u8; 1];
-    let mut timeout_count = 0;
-    while timeout_count < 10 {
-        match port.read(&mut byte) {
-            Ok(1) => {
-                let ch = byte[0] as char;
-                if ch == '\n' {
-                    break;
-                }
-                buffer.push(ch);
-            }
-            Ok(0) => {
-                timeout_count += 1;
-                std::thread::sleep(std::time::Duration::from_secs(1));
-            }
-            Err(_) => {
-                timeout_count += 1;
-                std::thread::sleep(std::time::Duration::from_secs(1));
-            }
-            Ok(n) => unreachable!("Unexpected read size: {}", n),
-        }
-    }
-    let response = buffer.trim();
-    // Check for the expected "TRANSACTION:" prefix and extract the base64 transaction
-    if response.starts_with("TRANSACTION:") {
-        let transaction_str = &response[12..]; // Skip "TRANSACTION:"
-        println!("Received ESP32 transaction: {}", transaction_str);
-        Ok(transaction_str.to_string())
-    } else {
-        Err(anyhow::anyhow!("Invalid response from ESP32: {}", response))
-    }
+use clap::{Args, Parser, Subcommand};
+use solana_sdk::pubkey::Pubkey;
+use std::path::PathBuf;
+
+mod amount;
+mod async_device;
+mod audit;
+mod balance;
+mod bench;
+mod cnft;
+mod config;
+mod confirm;
+mod contacts;
+mod cross_verify;
+#[cfg(unix)]
+mod daemon;
+mod device;
+mod dry_run;
+mod e2e;
+#[cfg(unix)]
+mod emulate;
+mod faults;
+mod fees;
+mod fiat;
+mod fuzz;
+mod governance;
+mod grpc;
+mod hil;
+mod labels;
+mod logging;
+mod lookup_table;
+mod memo;
+mod message;
+mod metrics;
+mod multisig;
+#[cfg(unix)]
+mod native;
+mod network;
+mod nft;
+mod nonce;
+mod notify;
+mod ota;
+mod otp;
+mod pairing;
+mod payload;
+mod policy;
+mod ports;
+mod presence;
+mod qr;
+mod recording;
+mod registry;
+mod rent;
+mod repl;
+mod sd;
+mod selftest;
+mod send;
+mod serve;
+mod soak;
+mod stake;
+mod swap;
+mod sweep;
+mod token;
+mod vote;
+
+use config::{ConfigArgs, Settings};
+use device::Device;
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Build, sign, and submit Solana transactions with the ESP32 signer"
+)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: logging::LogArgs,
+
+    #[command(subcommand)]
+    command: Commands,
 }
 
-/// Gets transaction information from the ESP32
-fn get_esp32_transaction_info(port: &mut Box<dyn SerialPort>) -> Result<String> {
-    // Send "TX_INFO" with a newline as expected by ESP32
-    port.write_all("TX_INFO\n".as_bytes())?;
-    port.flush()?;
-    println!("Requested transaction info from ESP32");
-
-    // Read the response until newline
-    let mut buffer = String::new();
-    let mut byte = [0u8; 1];
-    let mut timeout_count = 0;
-    while timeout_count < 10 {
-        match port.read(&mut byte) {
-            Ok(1) => {
-                let ch = byte[0] as char;
-                if ch == '\n' {
-                    break;
-                }
-                buffer.push(ch);
-            }
-            Ok(0) => {
-                timeout_count += 1;
-                std::thread::sleep(std::time::Duration::from_secs(1));
-            }
-            Err(_) => {
-                timeout_count += 1;
-                std::thread::sleep(std::time::Duration::from_secs(1));
-            }
-            Ok(n) => unreachable!("Unexpected read size: {}", n),
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Print the device's public key
+    Pubkey,
+    /// Show the SOL and SPL token balances of the device's account
+    Balance {
+        /// Look up this address instead of the device's
+        #[arg(long)]
+        address: Option<Pubkey>,
+    },
+    /// Send SOL from the device's account (signed after a BOOT button press)
+    Transfer {
+        /// Recipient address or @contact; repeat to pay several in one
+        /// transaction
+        #[arg(long, required = true, value_name = "ADDRESS|@CONTACT")]
+        to: Vec<String>,
+        /// Amount per recipient, in lamports or as in `0.5SOL`
+        #[arg(long, value_parser = amount::parse_lamports)]
+        amount: u64,
+        /// Build against this durable nonce instead of a recent blockhash
+        #[arg(long)]
+        nonce_account: Option<Pubkey>,
+        #[command(flatten)]
+        rent: rent::RentArgs,
+    },
+    /// Send SPL tokens from the device's token account
+    TransferToken {
+        /// Token mint address
+        #[arg(long)]
+        mint: Pubkey,
+        /// Recipient wallet (or token account)
+        #[arg(long)]
+        to: Pubkey,
+        /// Amount in tokens, e.g. 1.5 (scaled by the mint's decimals)
+        #[arg(long)]
+        amount: String,
+        /// Memo to put before the transfer; required by recipient accounts
+        /// that only accept transfers with a memo
+        #[arg(long)]
+        memo: Option<String>,
+        /// Build against this durable nonce instead of a recent blockhash
+        #[arg(long)]
+        nonce_account: Option<Pubkey>,
+    },
+    /// NFTs held by the device's account
+    #[command(subcommand)]
+    Nft(NftCommand),
+    /// Swap tokens held by the device's account, routed and built by Jupiter
+    Swap {
+        /// Token to sell: SOL or a mint address
+        #[arg(long)]
+        from: String,
+        /// Token to buy: SOL or a mint address
+        #[arg(long)]
+        to: String,
+        /// Amount of `from` to sell, in tokens, e.g. 1.5
+        #[arg(long)]
+        amount: String,
+        /// Slippage tolerance in basis points
+        #[arg(long, default_value_t = 50)]
+        slippage_bps: u16,
+        /// Jupiter Swap API base URL
+        #[arg(long, env = "ESP32_SIGNER_JUPITER_URL", default_value = swap::DEFAULT_API_URL)]
+        jupiter_url: String,
+    },
+    /// Send all of the device's SOL, less the fee, to one address
+    Sweep {
+        /// Where the SOL goes
+        #[arg(long)]
+        to: Pubkey,
+    },
+    /// Close the device's empty token accounts and reclaim their rent
+    SweepRent {
+        /// Where the reclaimed rent goes (default: the device's account)
+        #[arg(long)]
+        to: Option<Pubkey>,
+    },
+    /// Stake accounts with the device key as stake and withdraw authority
+    #[command(subcommand)]
+    Stake(StakeCommand),
+    /// Validator vote accounts with the device key as withdraw authority
+    #[command(subcommand)]
+    Vote(VoteCommand),
+    /// SPL Governance (Realms): deposit governing tokens, vote and propose
+    /// with the device key
+    #[command(subcommand)]
+    Gov(GovCommand),
+    /// Durable nonce accounts with the device key as authority
+    #[command(subcommand)]
+    Nonce(NonceCommand),
+    /// Address lookup tables with the device key as authority, for
+    /// transactions too big for a packet otherwise
+    #[command(subcommand)]
+    Alt(AltCommand),
+    /// Transactions that need several signers, collected one at a time
+    #[command(subcommand)]
+    Multisig(MultisigCommand),
+    /// Write an unsigned transaction file, to be signed later or elsewhere
+    #[command(subcommand)]
+    Build(BuildCommand),
+    /// Sign a transaction file on the ESP32 (no network needed)
+    Sign {
+        /// Transaction file to sign
+        #[arg(long = "in")]
+        input: PathBuf,
+        /// Where to write the signed transaction [default: overwrite --in]
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Submit a signed transaction file (no device needed)
+    Broadcast {
+        /// Signed transaction file
+        #[arg(long = "in")]
+        input: PathBuf,
+    },
+    /// Move transaction files to and signatures from an air-gapped signer as
+    /// BC-UR QR codes
+    #[command(subcommand)]
+    Qr(QrCommand),
+    /// Sign text or a file as a Solana off-chain message and write a
+    /// detached signature
+    #[command(group(clap::ArgGroup::new("content").required(true)))]
+    SignMessage {
+        /// Text to sign
+        #[arg(long, group = "content")]
+        text: Option<String>,
+        /// File whose SHA-256 digest to sign
+        #[arg(long, group = "content")]
+        file: Option<PathBuf>,
+        /// Signature file [default: <FILE>.sig; none for --text]
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Check a signature over text or a file locally
+    #[command(group(clap::ArgGroup::new("content").required(true)))]
+    #[command(group(clap::ArgGroup::new("signer").required(true)))]
+    Verify {
+        /// Signed text
+        #[arg(long, group = "content")]
+        message: Option<String>,
+        /// Signed file
+        #[arg(long, group = "content")]
+        file: Option<PathBuf>,
+        /// Base58 signature, or a signature file written by sign-message
+        #[arg(long)]
+        signature: String,
+        /// Expected signer
+        #[arg(long, group = "signer")]
+        pubkey: Option<Pubkey>,
+        /// Expect the attached device's key (read from the ESP32)
+        #[arg(long, group = "signer")]
+        against_device: bool,
+    },
+    /// Show what the device's placeholder transaction contains
+    TxInfo,
+    /// Have the device build and sign its placeholder memo transaction (see
+    /// `memo` for one that can be submitted)
+    CreateTx,
+    /// Sign and submit a transaction holding only a memo from the device's
+    /// account, and print its explorer link
+    Memo {
+        /// The memo's text
+        text: String,
+        /// Build against this durable nonce instead of a recent blockhash
+        #[arg(long)]
+        nonce_account: Option<Pubkey>,
+    },
+    /// Put the device into deep sleep so it can be unplugged
+    Shutdown,
+    /// Run a command against a session recorded with --record, or against a
+    /// device that never answers, instead of the attached ESP32
+    Replay {
+        /// The recording [default: the null device]
+        #[arg(long, value_name = "FILE")]
+        from: Option<PathBuf>,
+        /// The command to run, e.g. `pubkey`, with its arguments
+        #[arg(
+            required = true,
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            value_name = "COMMAND"
+        )]
+        command: Vec<String>,
+    },
+    /// Pair this host with the device, which it then needs for signing:
+    /// check the code the device blinks, then press BOOT
+    Pair,
+    /// Have the device forget its paired host (after a BOOT press), and this
+    /// host forget the device
+    Unpair,
+    /// Signers attached to or remembered on this workstation
+    #[command(subcommand)]
+    Devices(DevicesCommand),
+    /// Labelled recipient addresses, kept in an encrypted file
+    #[command(subcommand)]
+    Contacts(ContactsCommand),
+    /// List the network profiles, marking the one in use
+    Networks,
+    /// List serial ports with their USB IDs, and whether the signer answers
+    /// on each
+    ListPorts {
+        /// Also show ports auto-detection skips (not probed)
+        #[arg(long)]
+        all: bool,
+    },
+    /// Keep the device connection open and type commands interactively
+    Repl,
+    /// Serve a localhost HTTP signing API for dApps (each signature still
+    /// needs the BOOT button)
+    Serve {
+        /// Loopback address and port to listen on
+        #[arg(long, default_value = "127.0.0.1:8423")]
+        listen: String,
+        /// Bearer token clients must send [default: random, printed at startup]
+        #[arg(long, env = "ESP32_SIGNER_API_TOKEN")]
+        token: Option<String>,
+        /// Page origin (e.g. https://app.example) allowed to open the
+        /// WebSocket; repeatable
+        #[arg(long = "allow-origin")]
+        allowed_origins: Vec<String>,
+        /// Push pending requests and their outcomes to a webhook
+        /// (http[s]://...), MQTT topic (mqtt://[user:pass@]host[:port]/topic),
+        /// telegram:<chat id>, slack:<webhook URL> or desktop; repeatable
+        #[arg(long, env = "ESP32_SIGNER_NOTIFY", value_delimiter = ',')]
+        notify: Vec<notify::Sink>,
+        /// Loopback address and port to serve Prometheus metrics on
+        #[arg(long)]
+        metrics_listen: Option<String>,
+    },
+    /// Serve the signing API over gRPC (proto/signer.proto) for backend
+    /// services
+    GrpcServe {
+        /// Loopback address and port to listen on
+        #[arg(long, default_value = "127.0.0.1:8424")]
+        listen: String,
+        /// Bearer token clients must send [default: random, printed at startup]
+        #[arg(long, env = "ESP32_SIGNER_API_TOKEN")]
+        token: Option<String>,
+        /// Where to push pending requests and their outcomes, as for `serve`
+        #[arg(long, env = "ESP32_SIGNER_NOTIFY", value_delimiter = ',')]
+        notify: Vec<notify::Sink>,
+        /// Loopback address and port to serve Prometheus metrics on
+        #[arg(long)]
+        metrics_listen: Option<String>,
+    },
+    /// Run `serve` under a process supervisor: wait for and reconnect to the
+    /// device, answer health checks, pause and resume over a control socket,
+    /// and put the device to sleep on SIGTERM
+    #[cfg(unix)]
+    Daemon(daemon::DaemonArgs),
+    /// Answer a browser extension over native messaging, as a wallet-standard
+    /// backend (started by the browser); `--install` registers the host
+    #[cfg(unix)]
+    NativeHost(native::NativeHostArgs),
+    /// The signing policy the ESP32 enforces
+    #[command(subcommand)]
+    Policy(PolicyCommand),
+    /// Presence tokens: proof that someone pressed BOOT (firmware built
+    /// with `presence`)
+    #[command(subcommand)]
+    Presence(PresenceCommand),
+    /// The device's audit log, sealed to a registered auditor (firmware
+    /// built with `audit`)
+    #[command(subcommand)]
+    Audit(AuditCommand),
+    /// Two-factor authentication (firmware built with `twofa`)
+    #[command(subcommand)]
+    Otp(OtpCommand),
+    /// Encrypted key and policy backups on the device's SD card (firmware
+    /// built with `sd-backup`)
+    #[command(subcommand)]
+    Sd(SdCommand),
+    /// Check the whole device protocol against the attached ESP32 and
+    /// report each check with its timing
+    Selftest(selftest::SelftestArgs),
+    /// Run the selftest checks against a labeled signer (--device) and
+    /// write JUnit and JSON reports with each test's device traffic
+    Hil(hil::HilArgs),
+    /// Send the device malformed lines and bursts, checking every one gets
+    /// a well-formed error and the device keeps answering PING
+    Fuzz(fuzz::FuzzArgs),
+    /// Measure round-trip latency, signing latency and throughput with
+    /// several requests in flight
+    Bench(bench::BenchArgs),
+    /// Run transfers, token transfers and durable nonces against a local
+    /// solana-test-validator, signed by the device, and check the results
+    /// on chain
+    E2e(e2e::E2eArgs),
+    /// Send random read-only commands for hours, watching DIAG for leaks
+    /// and crashes and failing on lost replies or a desync
+    Soak(soak::SoakArgs),
+    /// Run a software signer on a pseudo-terminal, for tests without an
+    /// ESP32 (BOOT presses come through a control socket)
+    #[cfg(unix)]
+    Emulate(emulate::EmulateArgs),
+    /// Sign a firmware image with the vendor keypair, writing <IMAGE>.sig
+    OtaSign { image: String, keypair: String },
+    /// Upload a signed firmware image and install it
+    Ota { image: String, signature: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum DevicesCommand {
+    /// Find the attached signers and list every known one
+    List,
+    /// Name a known signer, for use with --device
+    Label {
+        /// Its current label, pubkey, device ID or serial number
+        device: String,
+        label: String,
+    },
+    /// Remove a signer from the list
+    Forget {
+        /// Its label, pubkey, device ID or serial number
+        device: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ContactsCommand {
+    /// Name an address, for use as `--to @LABEL`
+    Add { label: String, pubkey: Pubkey },
+    /// List the contacts
+    List,
+    /// Remove a contact
+    Remove { label: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum PolicyCommand {
+    /// Show the limit, blind-signing rule and recipient allowlist
+    Show,
+}
+
+#[derive(Subcommand, Debug)]
+enum PresenceCommand {
+    /// Wait for a press on the attached device, check its token and print
+    /// it
+    Wait(presence::WaitArgs),
+    /// Check a token printed by `wait` against the device's public key
+    Verify(presence::VerifyArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum AuditCommand {
+    /// Make an auditor key pair; the public key goes to `register`
+    Keygen(audit::KeygenArgs),
+    /// Register the auditor's public key on the device (after a BOOT press)
+    Register(audit::RegisterArgs),
+    /// Save the device's audit log, signed and sealed to the auditor
+    Export(audit::ExportArgs),
+    /// Open an export with the auditor's secret key, check the device's
+    /// signature and the chain, and list the entries
+    Verify(audit::VerifyArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum OtpCommand {
+    /// Generate a new OTP secret on the device, shown as an otpauth URI and
+    /// a QR code for the authenticator app
+    #[command(alias = "begin")]
+    Enroll(otp::EnrollArgs),
+    /// Finish enrollment with a code from the authenticator app
+    Confirm { code: String },
+    /// Open the signing window with a current code
+    Unlock { code: String },
+    /// Whether 2FA is enrolled and signing unlocked
+    Status,
+    /// Enroll, confirm, unlock and sign a test message, end to end
+    Test(otp::TestArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum SdCommand {
+    /// Write an encrypted backup of the key and policy (after a BOOT press)
+    Backup,
+    /// Replace the key and policy with the newest backup (after a BOOT
+    /// press); the device restarts
+    Restore,
+    /// Put a transaction file's message on a card (mounted here) for a
+    /// signer built with `sneakernet`
+    Export {
+        /// Transaction file
+        #[arg(long = "in")]
+        input: PathBuf,
+        /// Where the card is mounted
+        #[arg(long)]
+        card: PathBuf,
+    },
+    /// Add the signature the signer wrote to the card to a transaction file
+    Import {
+        /// Transaction file
+        #[arg(long = "in")]
+        input: PathBuf,
+        /// Where the card is mounted
+        #[arg(long)]
+        card: PathBuf,
+        /// Where to write the signed transaction [default: overwrite --in]
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum BuildCommand {
+    /// An unsigned SOL transfer
+    Transfer {
+        /// Sending account [default: the device's, read from the ESP32]
+        #[arg(long)]
+        from: Option<Pubkey>,
+        /// Recipient address; repeat to pay several in one transaction
+        #[arg(long, required = true)]
+        to: Vec<Pubkey>,
+        /// Amount per recipient, in lamports or as in `0.5SOL`
+        #[arg(long, value_parser = amount::parse_lamports)]
+        amount: u64,
+        #[command(flatten)]
+        rent: rent::RentArgs,
+        #[command(flatten)]
+        payload: PayloadArgs,
+    },
+    /// An unsigned SPL token transfer
+    TransferToken {
+        /// Owner of the sending token account [default: the device's key]
+        #[arg(long)]
+        from: Option<Pubkey>,
+        #[arg(long)]
+        mint: Pubkey,
+        /// Recipient wallet (or token account)
+        #[arg(long)]
+        to: Pubkey,
+        /// Amount in tokens, e.g. 1.5
+        #[arg(long)]
+        amount: String,
+        /// Memo to put before the transfer; required by recipient accounts
+        /// that only accept transfers with a memo
+        #[arg(long)]
+        memo: Option<String>,
+        #[command(flatten)]
+        payload: PayloadArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum MultisigCommand {
+    /// Write an unsigned SOL transfer from any account to a payload file
+    Transfer {
+        #[arg(long)]
+        from: Pubkey,
+        #[arg(long)]
+        to: Pubkey,
+        /// Amount, in lamports or as in `0.5SOL`
+        #[arg(long, value_parser = amount::parse_lamports)]
+        amount: u64,
+        #[command(flatten)]
+        rent: rent::RentArgs,
+        #[command(flatten)]
+        payload: PayloadArgs,
+    },
+    /// Write an unsigned token transfer out of an SPL multisig's token account
+    TransferToken {
+        /// SPL token multisig account that owns the tokens
+        #[arg(long)]
+        multisig: Pubkey,
+        #[arg(long)]
+        mint: Pubkey,
+        /// Recipient wallet (or token account)
+        #[arg(long)]
+        to: Pubkey,
+        /// Amount in tokens, e.g. 1.5
+        #[arg(long)]
+        amount: String,
+        /// Multisig members who will sign; at least its threshold
+        #[arg(long = "signer", required = true)]
+        signers: Vec<Pubkey>,
+        #[command(flatten)]
+        payload: PayloadArgs,
+    },
+    /// Add a signature from the attached ESP32, or from a keypair file
+    Sign {
+        file: PathBuf,
+        /// Sign with this keypair file instead of the device
+        #[arg(long)]
+        keypair: Option<PathBuf>,
+    },
+    /// Combine the signatures of copies of the same payload
+    Merge {
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Show which required signers have signed
+    Status { file: PathBuf },
+    /// Submit the transaction once every required signature is in
+    Submit { file: PathBuf },
+}
+
+#[derive(Subcommand, Debug)]
+enum QrCommand {
+    /// Show a transaction file's message as an animated sol-sign-request QR
+    /// sequence
+    ExportRequest {
+        /// Transaction file
+        #[arg(long = "in")]
+        input: PathBuf,
+        /// Signer the request is for [default: the remembered device]
+        #[arg(long)]
+        signer: Option<Pubkey>,
+        /// Largest fragment per QR code, in bytes
+        #[arg(long, default_value_t = 100)]
+        fragment_len: usize,
+        /// Time each QR code is shown, in milliseconds
+        #[arg(long, default_value_t = 300)]
+        interval_ms: u64,
+        /// Print the UR parts as text instead of QR codes
+        #[arg(long)]
+        text: bool,
+    },
+    /// Read a sol-signature UR from stdin and add it to a transaction file
+    ImportSignature {
+        /// Transaction file
+        #[arg(long = "in")]
+        input: PathBuf,
+        /// Where to write the signed transaction [default: overwrite --in]
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+/// How a transaction file is put together and where it goes
+#[derive(Args, Debug)]
+struct PayloadArgs {
+    /// Fee payer [default: the configured fee payer, else the first signer]
+    #[arg(long)]
+    payer: Option<Pubkey>,
+    /// Build against this durable nonce instead of a recent blockhash, so
+    /// the signatures can be collected without a deadline
+    #[arg(long)]
+    nonce_account: Option<Pubkey>,
+    /// Transaction file to write
+    #[arg(long)]
+    out: PathBuf,
+}
+
+impl PayloadArgs {
+    fn options(&self) -> payload::BuildOptions {
+        payload::BuildOptions {
+            payer: self.payer,
+            nonce_account: self.nonce_account,
         }
     }
-    let response = buffer.trim();
-    // Check for the expected "TX_INFO:" prefix
-    if response.starts_with("TX_INFO:") {
-        let info_str = &response[8..]; // Skip "TX_INFO:"
-        println!("Received ESP32 transaction info: {}", info_str);
-        Ok(info_str.to_string())
-    } else {
-        Err(anyhow::anyhow!("Invalid response from ESP32: {}", response))
-    }
 }
 
-/// Retrieves the public key from the ESP32 board via serial communication
-fn get_esp32_public_key(port: &mut Box<dyn SerialPort>) -> Result<Pubkey> {
-    // Send "GET_PUBKEY" with a newline as expected by ESP32
-    port.write_all("GET_PUBKEY\n".as_bytes())?;
-    port.flush()?;
-    println!("Requested public key from ESP32");
-
-    // Read the response until newline
-    let mut buffer = String::new();
-    let mut byte = [0u8; 1];
-    let mut timeout_count = 0;
-    while timeout_count < 10 {
-        match port.read(&mut byte) {
-            Ok(1) => {
-                let ch = byte[0] as char;
-                if ch == '\n' {
-                    break;
-                }
-                buffer.push(ch);
-            }
-            Ok(0) => {
-                timeout_count += 1;
-                std::thread::sleep(std::time::Duration::from_secs(1));
-            }
-            Err(_) => {
-                timeout_count += 1;
-                std::thread::sleep(std::time::Duration::from_secs(1));
-            }
-            Ok(n) => unreachable!("Unexpected read size: {}", n),
+#[derive(Subcommand, Debug)]
+enum VoteCommand {
+    /// Withdraw lamports from a vote account
+    Withdraw {
+        #[arg(long)]
+        vote: Pubkey,
+        /// Recipient [default: the device's account]
+        #[arg(long)]
+        to: Option<Pubkey>,
+        /// Amount to withdraw, in lamports or as in `0.5SOL` [default:
+        /// everything above the rent-exempt reserve]
+        #[arg(long, value_parser = amount::parse_lamports)]
+        amount: Option<u64>,
+    },
+    /// Hand the vote or withdraw authority to another key
+    Authorize {
+        #[arg(long)]
+        vote: Pubkey,
+        /// Which authority to hand over
+        #[arg(long, value_enum)]
+        authority: vote::Authority,
+        /// The new authority
+        #[arg(long)]
+        new: Pubkey,
+    },
+    /// Change the validator's commission
+    UpdateCommission {
+        #[arg(long)]
+        vote: Pubkey,
+        /// New commission, in percent
+        #[arg(long)]
+        commission: u8,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum GovCommand {
+    /// Deposit governing tokens from the device's token account into a
+    /// realm, which gives the device its voting weight
+    Deposit {
+        #[command(flatten)]
+        realm: governance::RealmArgs,
+        /// Amount in tokens, e.g. 1.5
+        #[arg(long)]
+        amount: String,
+    },
+    /// Withdraw the device's governing tokens from a realm
+    Withdraw {
+        #[command(flatten)]
+        realm: governance::RealmArgs,
+    },
+    /// Vote on a proposal
+    Vote {
+        #[arg(long)]
+        proposal: Pubkey,
+        #[arg(long, value_enum)]
+        choice: governance::Choice,
+        /// Governance program the proposal belongs to
+        #[arg(long, default_value_t = governance::GOVERNANCE_PROGRAM_ID)]
+        program: Pubkey,
+    },
+    /// Create a yes/no proposal owned by the device, and start its vote
+    CreateProposal {
+        #[command(flatten)]
+        realm: governance::RealmArgs,
+        /// Governance (of the realm) the proposal is put to
+        #[arg(long)]
+        governance: Pubkey,
+        #[arg(long)]
+        name: String,
+        /// Link to the proposal's description
+        #[arg(long, default_value = "")]
+        description: String,
+        /// Leave the proposal as a draft instead of signing it off
+        #[arg(long)]
+        draft: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum NonceCommand {
+    /// Create a nonce account derived from the device key and a seed
+    Create {
+        /// Seed the nonce account address is derived from
+        #[arg(long, default_value = "nonce:0")]
+        seed: String,
+        /// Amount to fund it with, in lamports or as in `0.5SOL` [default: the
+        /// rent-exempt minimum]
+        #[arg(long, value_parser = amount::parse_lamports)]
+        amount: Option<u64>,
+    },
+    /// Show a nonce account's authority and current nonce
+    Show { nonce_account: Pubkey },
+    /// Advance the nonce, voiding transactions signed against the current one
+    Advance { nonce_account: Pubkey },
+}
+
+#[derive(Subcommand, Debug)]
+enum NftCommand {
+    /// Send an NFT (plain or programmable) to a wallet
+    Transfer {
+        /// The NFT's mint address
+        #[arg(long)]
+        mint: Pubkey,
+        /// Recipient wallet
+        #[arg(long)]
+        to: Pubkey,
+        /// Build against this durable nonce instead of a recent blockhash
+        #[arg(long)]
+        nonce_account: Option<Pubkey>,
+    },
+    /// Send a compressed (Bubblegum) NFT to a wallet, proved with a DAS RPC
+    TransferCompressed {
+        /// The asset ID
+        #[arg(long)]
+        asset: Pubkey,
+        /// Recipient wallet
+        #[arg(long)]
+        to: Pubkey,
+        /// DAS RPC to look the asset and its proof up with, if not --rpc-url
+        #[arg(long)]
+        das_url: Option<String>,
+        /// Build against this durable nonce instead of a recent blockhash
+        #[arg(long)]
+        nonce_account: Option<Pubkey>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AltCommand {
+    /// Create a lookup table, optionally filled with addresses
+    Create {
+        /// Address to add; repeat for several
+        #[arg(long = "address")]
+        addresses: Vec<Pubkey>,
+    },
+    /// Add addresses to a lookup table
+    Extend {
+        #[arg(long)]
+        table: Pubkey,
+        /// Address to add; repeat for several
+        #[arg(long = "address", required = true)]
+        addresses: Vec<Pubkey>,
+    },
+    /// Deactivate a lookup table, or close it once it has cooled down
+    Close {
+        #[arg(long)]
+        table: Pubkey,
+        /// Recipient of the rent [default: the device's account]
+        #[arg(long)]
+        to: Option<Pubkey>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum StakeCommand {
+    /// Create a stake account derived from the device key and a seed
+    Create {
+        /// Amount to move from the device's account into the stake account,
+        /// in lamports or as in `0.5SOL`
+        #[arg(long, value_parser = amount::parse_lamports)]
+        amount: u64,
+        /// Seed the stake account address is derived from
+        #[arg(long, default_value = "stake:0")]
+        seed: String,
+        /// Delegate to this vote account in the same transaction
+        #[arg(long)]
+        vote: Option<Pubkey>,
+    },
+    /// Delegate a stake account to a validator
+    Delegate {
+        #[arg(long)]
+        stake: Pubkey,
+        /// Validator vote account
+        #[arg(long)]
+        vote: Pubkey,
+    },
+    /// Start cooling down a delegated stake account
+    Deactivate {
+        #[arg(long)]
+        stake: Pubkey,
+    },
+    /// Withdraw lamports from an inactive stake account
+    Withdraw {
+        #[arg(long)]
+        stake: Pubkey,
+        /// Recipient [default: the device's account]
+        #[arg(long)]
+        to: Option<Pubkey>,
+        /// Amount to withdraw, in lamports or as in `0.5SOL` [default: the
+        /// whole balance]
+        #[arg(long, value_parser = amount::parse_lamports)]
+        amount: Option<u64>,
+    },
+    /// Move part of a stake account into a new one, derived from the device
+    /// key and a seed
+    Split {
+        #[arg(long)]
+        stake: Pubkey,
+        /// Amount to move, in lamports or as in `0.5SOL`
+        #[arg(long, value_parser = amount::parse_lamports)]
+        amount: u64,
+        /// Seed the new stake account address is derived from
+        #[arg(long)]
+        seed: String,
+    },
+    /// Merge a stake account into another, closing it
+    Merge {
+        /// The account that remains
+        #[arg(long)]
+        stake: Pubkey,
+        /// The account merged in and closed
+        #[arg(long)]
+        source: Pubkey,
+    },
+}
+
+/// The device connection, opened on first use and kept for later commands
+struct Session<'a> {
+    settings: &'a Settings,
+    device: Option<Device>,
+}
+
+impl<'a> Session<'a> {
+    fn new(settings: &'a Settings) -> Self {
+        Session {
+            settings,
+            device: None,
         }
     }
-    let response = buffer.trim();
-    // Check for the expected "PUBKEY:" prefix and extract the base58 public key
-    if response.starts_with("PUBKEY:") {
-        let pubkey_str = &response[7..]; // Skip "PUBKEY:"
-        println!("Received ESP32 public key: {}", pubkey_str);
-        Pubkey::from_str(pubkey_str)
-            .map_err(|e| anyhow::anyhow!("Failed to parse public key: {}", e))
-    } else {
-        Err(anyhow::anyhow!("Invalid response from ESP32: {}", response))
+
+    /// The connection, made on first use; firmware this tool can't talk to
+    /// is refused, and a device this host paired with has to prove it is
+    /// that device
+    fn device(&mut self) -> Result<&mut Device> {
+        self.connect(true, true)
     }
-}
 
-/// Sends the transaction message to the ESP32 and retrieves the signature
-fn send_to_esp32_and_get_signature(
-    port: &mut Box<dyn SerialPort>,
-    base64_message: &str,
-) -> Result<String> {
-    let sign_command = format!("SIGN:{}", base64_message);
-    port.write_all(sign_command.as_bytes())?;
-    port.write_all(b"\n")?;
-    port.flush()?;
-    println!("Sent to ESP32: {}", sign_command);
-
-    // Clear the input buffer to ensure we read the new response
-    port.clear(serialport::ClearBuffer::Input)?;
-
-    // Rest of your function remains unchanged
-    let mut buffer = String::new();
-    let mut byte = [0u8; 1];
-    let mut timeout_count = 0;
-
-
-    while timeout_count < 10 {
-        match port.read(&mut byte) {
-            Ok(1) => {
-                let ch = byte[0] as char;
-                if ch == '\n' {
-                    break;
-                }
-                buffer.push(ch);
-            }
-            Ok(0) => {
-                timeout_count += 1;
-                std::thread::sleep(std::time::Duration::from_secs(1));
-            }
-            Err(_) => {
-                timeout_count += 1;
-                std::thread::sleep(std::time::Duration::from_secs(1));
-            }
-            Ok(n) => unreachable!("Unexpected read size: {}", n),
-        }
+    /// Skips the protocol check, for `ota`, which has to reach old firmware
+    fn device_for_update(&mut self) -> Result<&mut Device> {
+        self.connect(false, true)
     }
-    let response = buffer.trim();
-    if response.starts_with("SIGNATURE:") {
-        let base64_signature = &response[10..];
-        println!("Received signature from ESP32: {}", base64_signature);
-        Ok(base64_signature.to_string())
-    } else {
-        Err(anyhow::anyhow!("Invalid response from ESP32: {}", response))
+
+    /// Skips the pairing challenge, for `pair`, which has to reach a device
+    /// that was reset or paired elsewhere
+    fn device_for_pairing(&mut self) -> Result<&mut Device> {
+        self.connect(true, false)
     }
-}
 
-/// Sends the SHUTDOWN command to the ESP32 to prepare it for safe disconnection
-fn shutdown_esp32(port: &mut Box<dyn SerialPort>) -> Result<()> {
-    // Send "SHUTDOWN" with a newline as expected by ESP32
-    port.write_all("SHUTDOWN\n".as_bytes())?;
-    port.flush()?;
-    println!("Sent SHUTDOWN command to ESP32");
-
-    // Read the confirmation response until newline (similar to other reads)
-    let mut buffer = String::new();
-    let mut byte = [0u8; 1];
-    let mut timeout_count = 0;
-    while timeout_count < 10 {
-        match port.read(&mut byte) {
-            Ok(1) => {
-                let ch = byte[0] as char;
-                if ch == '\n' {
-                    break;
-                }
-                buffer.push(ch);
+    fn connect(&mut self, check_compatible: bool, authenticate: bool) -> Result<&mut Device> {
+        if self.device.is_none() && self.settings.dry_run {
+            self.device = Some(Device::offline(dry_run::device_key(self.settings)?));
+        }
+        if self.device.is_none() {
+            let mut device = match &self.settings.device {
+                Some(selector) => registry::connect(selector, self.settings.baud)?,
+                None => Device::connect(self.settings.port.as_deref(), self.settings.baud)?,
+            };
+            if self.settings.verify {
+                device.set_cross_verify();
             }
-            Ok(0) => {
-                timeout_count += 1;
-                std::thread::sleep(std::time::Duration::from_secs(1));
+            if check_compatible {
+                device.check_compatible()?;
             }
-            Err(_) => {
-                timeout_count += 1;
-                std::thread::sleep(std::time::Duration::from_secs(1));
+            if authenticate {
+                pairing::authenticate(&mut device)?;
             }
-            Ok(n) => unreachable!("Unexpected read size: {}", n),
+            self.device = Some(device);
         }
+        Ok(self.device.as_mut().expect("connected above"))
     }
-    let response = buffer.trim();
-    if response == "SHUTDOWN_OK" {
-        println!("Received shutdown confirmation from ESP32: {}", response);
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!(
-            "Invalid or no shutdown confirmation from ESP32: {}",
-            response
-        ))
-    }
-}
 
-fn main() -> Result<()> {
-    println!("=== ESP32 Solana Transaction Builder ===");
-
-    // Initialize the Solana RPC client
-    let client = RpcClient::new(RPC_URL.to_string());
-
-    // Open the serial port to communicate with the ESP32
-    let mut port = match serialport::new(SERIAL_PORT, 115_200)
-        .timeout(std::time::Duration::from_secs(1))
-        .open() {
-            Ok(port) => port,
-            Err(e) => {
-                eprintln!("Failed to open serial port '{}': {}", SERIAL_PORT, e);
-                return Err(e.into());
-            }
-        };
-
-    println!("\n1. Getting ESP32 public key...");
-    // Get the ESP32 public key, which will be the fee payer and signer
-    let esp32_pubkey = get_esp32_public_key(&mut port)?;
-
-    println!("\n2. Getting transaction info from ESP32...");
-    // Get transaction information from ESP32
-    let _tx_info = get_esp32_transaction_info(&mut port)?;
-
-    println!("\n3. Creating placeholder transaction on ESP32...");
-    // Create a placeholder transaction with memo on ESP32
-    let base64_transaction = create_esp32_transaction(&mut port)?;
-
-    // Decode the transaction to inspect it
-    let transaction_bytes =
-        base64::engine::general_purpose::STANDARD.decode(&base64_transaction)?;
-    println!(
-        "ESP32 created transaction ({} bytes)",
-        transaction_bytes.len()
-    );
-
-    // For demonstration, we can also create a traditional transfer transaction
-    println!("\n4. Creating traditional transfer transaction...");
-
-    // Parse the recipient public key from the constant string
-    let recipient_pubkey = Pubkey::from_str(RECIPIENT_PUBLIC_KEY)?;
-
-    // Fetch the latest blockhash with finalized commitment
-    let (recent_blockhash, _last_valid_slot) =
-        client.get_latest_blockhash_with_commitment(CommitmentConfig::finalized())?;
-
-    // Create a transfer instruction
-    let instruction =
-        system_instruction::transfer(&esp32_pubkey, &recipient_pubkey, LAMPORTS_TO_SEND);
-    let mut message = Message::new(&[instruction], Some(&esp32_pubkey));
-    message.recent_blockhash = recent_blockhash;
-
-    // Create a VersionedTransaction with the message and an empty signature slot
-    let mut transaction = VersionedTransaction {
-        signatures: vec![Signature::default(); message.header.num_required_signatures as usize],
-        message: VersionedMessage::Legacy(message),
-    };
-
-    // Print the number of signatures expected for verification
-    println!(
-        "Number of signatures expected: {}",
-        transaction.message.header().num_required_signatures
-    );
-
-    // Serialize the transaction message to bytes for signing
-    let message_bytes = transaction.message.serialize();
-    let base64_message_to_sign = base64::engine::general_purpose::STANDARD.encode(&message_bytes);
-    println!(
-        "Serialized Transaction Message (Base64): {}",
-        base64_message_to_sign
-    );
-
-    println!("\n5. Signing transaction with ESP32...");
-    // Send the serialized message to the ESP32 and get the base64-encoded signature
-    let base64_signature = send_to_esp32_and_get_signature(&mut port, &base64_message_to_sign)?;
-
-    // Decode the base64 signature into bytes and convert to a Solana Signature
-    let signature_bytes = base64::engine::general_purpose::STANDARD.decode(&base64_signature)?;
-    let signature = Signature::try_from(signature_bytes.as_slice())?;
-
-    // Verify that the transaction expects exactly one signature
-    if transaction.signatures.len() != 1 {
-        return Err(anyhow::anyhow!(
-            "Expected 1 signature slot, found {}",
-            transaction.signatures.len()
-        ));
+    /// Uses `device`, a replay, in place of the ESP32. It counts as paired:
+    /// the pairing challenge can't be answered from a recording, as both
+    /// sides' nonces are fresh.
+    fn replay(&mut self, mut device: Device) {
+        device.set_paired();
+        self.device = Some(device);
     }
 
-    // Assign the signature received from ESP32 to the transaction
-    transaction.signatures[0] = signature;
+    /// The connection itself, for commands that need to own it
+    fn take_device(&mut self) -> Result<Device> {
+        self.device()?;
+        Ok(self.device.take().expect("connected above"))
+    }
+}
 
-    println!("\n6. Sending transaction to Solana network...");
-    // Send the signed transaction to the Solana network
-    let signature = client.send_transaction(&transaction)?;
-    println!("Transaction sent with signature: {}", signature);
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    logging::init(&cli.log)?;
+    let settings = Settings::load(&cli.config)?;
+    run(&cli.command, &settings, &mut Session::new(&settings))
+}
 
-    // Confirm the transaction has been processed on the network
-    client.confirm_transaction(&signature)?;
-    println!("Transaction confirmed");
+fn run(command: &Commands, settings: &Settings, session: &mut Session) -> Result<()> {
+    match command {
+        Commands::Pubkey => println!("{}", session.device()?.pubkey()?),
+        Commands::Balance { address } => {
+            let owner = match address {
+                Some(address) => *address,
+                None => session.device()?.pubkey()?,
+            };
+            balance::balance(settings, &owner)?
+        }
+        Commands::Transfer {
+            to,
+            amount,
+            nonce_account,
+            rent,
+        } => {
+            let to = to
+                .iter()
+                .map(|to| contacts::resolve(to))
+                .collect::<Result<Vec<_>>>()?;
+            transfer(
+                session.device()?,
+                settings,
+                &to,
+                *amount,
+                nonce_account.as_ref(),
+                rent.rent,
+            )?
+        }
+        Commands::TransferToken {
+            mint,
+            to,
+            amount,
+            memo,
+            nonce_account,
+        } => token::transfer_token(
+            session.device()?,
+            settings,
+            mint,
+            to,
+            amount,
+            memo.as_deref(),
+            nonce_account.as_ref(),
+        )?,
+        Commands::Nft(NftCommand::Transfer {
+            mint,
+            to,
+            nonce_account,
+        }) => nft::transfer(
+            session.device()?,
+            settings,
+            mint,
+            to,
+            nonce_account.as_ref(),
+        )?,
+        Commands::Nft(NftCommand::TransferCompressed {
+            asset,
+            to,
+            das_url,
+            nonce_account,
+        }) => cnft::transfer(
+            session.device()?,
+            settings,
+            asset,
+            to,
+            das_url.as_deref(),
+            nonce_account.as_ref(),
+        )?,
+        Commands::Swap {
+            from,
+            to,
+            amount,
+            slippage_bps,
+            jupiter_url,
+        } => swap::swap(
+            session.device()?,
+            settings,
+            jupiter_url,
+            from,
+            to,
+            amount,
+            *slippage_bps,
+        )?,
+        Commands::Sweep { to } => sweep::sweep_all(session.device()?, settings, to)?,
+        Commands::SweepRent { to } => sweep::sweep(session.device()?, settings, to.as_ref())?,
+        Commands::Stake(StakeCommand::Create { amount, seed, vote }) => {
+            stake::create(session.device()?, settings, seed, *amount, vote.as_ref())?
+        }
+        Commands::Stake(StakeCommand::Delegate { stake, vote }) => {
+            stake::delegate(session.device()?, settings, stake, vote)?
+        }
+        Commands::Stake(StakeCommand::Deactivate { stake }) => {
+            stake::deactivate(session.device()?, settings, stake)?
+        }
+        Commands::Stake(StakeCommand::Withdraw { stake, to, amount }) => {
+            stake::withdraw(session.device()?, settings, stake, to.as_ref(), *amount)?
+        }
+        Commands::Stake(StakeCommand::Split {
+            stake,
+            amount,
+            seed,
+        }) => stake::split(session.device()?, settings, stake, *amount, seed)?,
+        Commands::Stake(StakeCommand::Merge { stake, source }) => {
+            stake::merge(session.device()?, settings, stake, source)?
+        }
+        Commands::Vote(VoteCommand::Withdraw { vote, to, amount }) => {
+            vote::withdraw(session.device()?, settings, vote, to.as_ref(), *amount)?
+        }
+        Commands::Vote(VoteCommand::Authorize {
+            vote,
+            authority,
+            new,
+        }) => vote::authorize(session.device()?, settings, vote, *authority, new)?,
+        Commands::Vote(VoteCommand::UpdateCommission { vote, commission }) => {
+            vote::update_commission(session.device()?, settings, vote, *commission)?
+        }
+        Commands::Gov(GovCommand::Deposit { realm, amount }) => {
+            governance::deposit(session.device()?, settings, realm, amount)?
+        }
+        Commands::Gov(GovCommand::Withdraw { realm }) => {
+            governance::withdraw(session.device()?, settings, realm)?
+        }
+        Commands::Gov(GovCommand::Vote {
+            proposal,
+            choice,
+            program,
+        }) => governance::vote(session.device()?, settings, program, proposal, *choice)?,
+        Commands::Gov(GovCommand::CreateProposal {
+            realm,
+            governance,
+            name,
+            description,
+            draft,
+        }) => governance::create_proposal(
+            session.device()?,
+            settings,
+            realm,
+            governance,
+            name,
+            description,
+            *draft,
+        )?,
+        Commands::Nonce(NonceCommand::Create { seed, amount }) => {
+            nonce::create(session.device()?, settings, seed, *amount)?
+        }
+        Commands::Nonce(NonceCommand::Show { nonce_account }) => {
+            nonce::show(settings, nonce_account)?
+        }
+        Commands::Nonce(NonceCommand::Advance { nonce_account }) => {
+            nonce::advance(session.device()?, settings, nonce_account)?
+        }
+        Commands::Alt(AltCommand::Create { addresses }) => {
+            lookup_table::create(session.device()?, settings, addresses)?
+        }
+        Commands::Alt(AltCommand::Extend { table, addresses }) => {
+            lookup_table::extend(session.device()?, settings, table, addresses)?
+        }
+        Commands::Alt(AltCommand::Close { table, to }) => {
+            lookup_table::close(session.device()?, settings, table, to.as_ref())?
+        }
+        Commands::Multisig(MultisigCommand::Transfer {
+            from,
+            to,
+            amount,
+            rent,
+            payload,
+        }) => payload::create(
+            settings,
+            &payload::build_transfer(
+                settings,
+                from,
+                std::slice::from_ref(to),
+                *amount,
+                rent.rent,
+                &payload.options(),
+            )?,
+            &payload.out,
+        )?,
+        Commands::Multisig(MultisigCommand::TransferToken {
+            multisig,
+            mint,
+            to,
+            amount,
+            signers,
+            payload,
+        }) => payload::create(
+            settings,
+            &multisig::build_token_transfer(
+                settings,
+                multisig,
+                mint,
+                to,
+                amount,
+                signers,
+                &payload.options(),
+            )?,
+            &payload.out,
+        )?,
+        Commands::Multisig(MultisigCommand::Sign { file, keypair }) => match keypair {
+            Some(keypair) => payload::sign_with_keypair(keypair, file, file)?,
+            None => payload::sign_with_device(session.device()?, settings, file, file)?,
+        },
+        Commands::Multisig(MultisigCommand::Merge { out, files }) => multisig::merge(files, out)?,
+        Commands::Multisig(MultisigCommand::Status { file }) => payload::status(file)?,
+        Commands::Multisig(MultisigCommand::Submit { file }) => payload::submit(settings, file)?,
+        Commands::Build(BuildCommand::Transfer {
+            from,
+            to,
+            amount,
+            rent,
+            payload,
+        }) => {
+            let from = match from {
+                Some(from) => *from,
+                None => session.device()?.pubkey()?,
+            };
+            payload::create(
+                settings,
+                &payload::build_transfer(
+                    settings,
+                    &from,
+                    to,
+                    *amount,
+                    rent.rent,
+                    &payload.options(),
+                )?,
+                &payload.out,
+            )?
+        }
+        Commands::Build(BuildCommand::TransferToken {
+            from,
+            mint,
+            to,
+            amount,
+            memo,
+            payload,
+        }) => {
+            let from = match from {
+                Some(from) => *from,
+                None => session.device()?.pubkey()?,
+            };
+            payload::create(
+                settings,
+                &payload::build_token_transfer(
+                    settings,
+                    &from,
+                    mint,
+                    to,
+                    amount,
+                    memo.as_deref(),
+                    &payload.options(),
+                )?,
+                &payload.out,
+            )?
+        }
+        Commands::Sign { input, out } => payload::sign_with_device(
+            session.device()?,
+            settings,
+            input,
+            out.as_deref().unwrap_or(input),
+        )?,
+        Commands::Broadcast { input } => payload::submit(settings, input)?,
+        Commands::Qr(QrCommand::ExportRequest {
+            input,
+            signer,
+            fragment_len,
+            interval_ms,
+            text,
+        }) => {
+            let signer = match signer {
+                Some(signer) => *signer,
+                None => dry_run::device_key(settings)?,
+            };
+            qr::export_request(
+                input,
+                &signer,
+                *fragment_len,
+                std::time::Duration::from_millis(*interval_ms),
+                *text,
+            )?
+        }
+        Commands::Qr(QrCommand::ImportSignature { input, out }) => {
+            qr::import_signature(input, out.as_deref().unwrap_or(input))?
+        }
+        Commands::SignMessage { text, file, out } => {
+            let content = match (text, file) {
+                (Some(text), _) => message::Content::Text(text),
+                (None, Some(file)) => message::Content::File(file),
+                (None, None) => unreachable!("clap requires --text or --file"),
+            };
+            message::sign(session.device()?, content, out.as_deref())?
+        }
+        Commands::Verify {
+            message,
+            file,
+            signature,
+            pubkey,
+            against_device,
+        } => {
+            let content = match (message, file) {
+                (Some(text), _) => message::Content::Text(text),
+                (None, Some(file)) => message::Content::File(file),
+                (None, None) => unreachable!("clap requires --message or --file"),
+            };
+            let signature = message::parse_signature(signature)?;
+            let pubkey = match pubkey {
+                Some(pubkey) => *pubkey,
+                None if *against_device => session.device()?.pubkey()?,
+                None => unreachable!("clap requires --pubkey or --against-device"),
+            };
+            message::verify(content, &pubkey, &signature)?
+        }
+        Commands::TxInfo => println!("{}", session.device()?.tx_info()?),
+        Commands::CreateTx => {
+            let base64_transaction = session.device()?.create_tx()?;
+            let transaction_bytes =
+                base64::engine::general_purpose::STANDARD.decode(&base64_transaction)?;
+            println!("{}", base64_transaction);
+            tracing::info!(
+                "ESP32 created transaction ({} bytes)",
+                transaction_bytes.len()
+            );
+        }
+        Commands::Memo {
+            text,
+            nonce_account,
+        } => memo::send(session.device()?, settings, text, nonce_account.as_ref())?,
+        Commands::Repl => repl::run(settings, session)?,
+        Commands::GrpcServe {
+            listen,
+            token,
+            notify,
+            metrics_listen,
+        } => grpc::serve(
+            session.take_device()?,
+            &serve::ServeOptions {
+                listen,
+                token: token.as_deref(),
+                allowed_origins: &[],
+                notify,
+                templates: notify::Templates::new(&settings.notify_templates)?,
+                metrics_listen: metrics_listen.as_deref(),
+                threshold: network::Threshold::new(settings),
+            },
+        )?,
+        Commands::Serve {
+            listen,
+            token,
+            allowed_origins,
+            notify,
+            metrics_listen,
+        } => serve::serve(
+            session.device()?,
+            &serve::ServeOptions {
+                listen,
+                token: token.as_deref(),
+                allowed_origins,
+                notify,
+                templates: notify::Templates::new(&settings.notify_templates)?,
+                metrics_listen: metrics_listen.as_deref(),
+                threshold: network::Threshold::new(settings),
+            },
+        )?,
+        #[cfg(unix)]
+        Commands::Daemon(args) => {
+            daemon::run(args, settings, &|| Session::new(settings).take_device())?
+        }
+        #[cfg(unix)]
+        Commands::NativeHost(args) => native::run(args, settings, || session.device())?,
+        Commands::Shutdown => {
+            session.device()?.shutdown()?;
+            println!("ESP32 is in deep sleep; it is safe to unplug");
+        }
+        Commands::Replay { from, command } => {
+            let replay = match from {
+                Some(path) => recording::Replay::load(path)?,
+                None => recording::Replay::null(),
+            };
+            // Global options before `replay` already took effect
+            let inner = Cli::try_parse_from(
+                std::iter::once("replay").chain(command.iter().map(String::as_str)),
+            )
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+            // In the REPL, the connection is back in use afterwards
+            let connected = session.device.take();
+            session.replay(Device::replay(replay));
+            let result = run(&inner.command, settings, session);
+            session.device = connected;
+            result?
+        }
+        Commands::Pair => pairing::pair(session.device_for_pairing()?)?,
+        Commands::Unpair => pairing::unpair(session.device()?)?,
+        Commands::Devices(DevicesCommand::List) => {
+            // The scan opens every port, including the session's
+            session.device = None;
+            registry::list(settings.baud)?
+        }
+        Commands::Devices(DevicesCommand::Label { device, label }) => {
+            registry::label(device, label)?
+        }
+        Commands::Devices(DevicesCommand::Forget { device }) => registry::forget(device)?,
+        Commands::Contacts(ContactsCommand::Add { label, pubkey }) => contacts::add(label, pubkey)?,
+        Commands::Contacts(ContactsCommand::List) => contacts::list()?,
+        Commands::Contacts(ContactsCommand::Remove { label }) => contacts::remove(label)?,
+        Commands::Networks => network::list(settings)?,
+        Commands::ListPorts { all } => {
+            // Probing opens every port, including the session's
+            session.device = None;
+            ports::list(settings.baud, *all)?
+        }
+        Commands::Policy(PolicyCommand::Show) => policy::show(session.device()?)?,
+        Commands::Presence(PresenceCommand::Wait(args)) => presence::wait(session.device()?, args)?,
+        Commands::Presence(PresenceCommand::Verify(args)) => presence::verify(args)?,
+        Commands::Audit(AuditCommand::Keygen(args)) => audit::keygen(args)?,
+        Commands::Audit(AuditCommand::Register(args)) => audit::register(session.device()?, args)?,
+        Commands::Audit(AuditCommand::Export(args)) => audit::export(session.device()?, args)?,
+        Commands::Audit(AuditCommand::Verify(args)) => audit::verify(args)?,
+        Commands::Otp(OtpCommand::Enroll(args)) => {
+            otp::enroll(session.device()?, args)?;
+            println!("Then finish with `otp confirm <CODE>`");
+        }
+        Commands::Otp(OtpCommand::Confirm { code }) => otp::confirm(session.device()?, code)?,
+        Commands::Otp(OtpCommand::Unlock { code }) => otp::unlock(session.device()?, code)?,
+        Commands::Otp(OtpCommand::Status) => otp::status(session.device()?)?,
+        Commands::Otp(OtpCommand::Test(args)) => otp::test(session.device()?, args)?,
+        Commands::Sd(SdCommand::Backup) => sd::backup(session.device()?)?,
+        Commands::Sd(SdCommand::Restore) => sd::restore(session.device()?)?,
+        Commands::Sd(SdCommand::Export { input, card }) => sd::export_request(input, card)?,
+        Commands::Sd(SdCommand::Import { input, card, out }) => {
+            sd::import_signature(input, card, out.as_deref().unwrap_or(input))?
+        }
+        Commands::Selftest(args) => selftest::run(session.device()?, args)?,
+        Commands::Hil(args) => hil::run(session.device()?, settings.device.as_deref(), args)?,
+        Commands::Fuzz(args) => fuzz::run(session.device()?, args)?,
+        Commands::Bench(args) => bench::run(session.device()?, args)?,
+        Commands::Soak(args) => soak::run(session.device()?, args)?,
+        Commands::E2e(args) => e2e::run(session.device()?, settings, args)?,
+        #[cfg(unix)]
+        Commands::Emulate(args) => emulate::run(args)?,
+        Commands::OtaSign { image, keypair } => ota::ota_sign(image, keypair)?,
+        Commands::Ota { image, signature } => {
+            ota::ota_update(session.device_for_update()?, image, signature)?
+        }
+    }
+    Ok(())
+}
 
-    println!("\n7. Shutting down ESP32...");
-    // Shutdown the ESP32 after transaction confirmation
-    shutdown_esp32(&mut port)?;
+/// Sends SOL from the device's account to each of `to`
+fn transfer(
+    device: &mut Device,
+    settings: &Settings,
+    to: &[Pubkey],
+    lamports: u64,
+    nonce_account: Option<&Pubkey>,
+    rent_mode: rent::RentMode,
+) -> Result<()> {
+    let client = settings.rpc_client();
+    let mut fee_payer = send::load_fee_payer(settings)?;
 
-    println!("\n=== Transaction process completed successfully! ===");
+    let esp32_pubkey = device.pubkey()?;
+    let lamports = rent::check(
+        &client,
+        settings,
+        &esp32_pubkey,
+        to,
+        lamports,
+        fee_payer.is_none(),
+        rent_mode,
+    )?;
+    // A total that overflows is refused before the device sees it
+    amount::total(lamports, to.len())?;
+    let instructions = send::transfer_instructions(&esp32_pubkey, to, lamports);
+    send::sign_and_submit(
+        &client,
+        settings,
+        device,
+        &esp32_pubkey,
+        fee_payer.as_mut(),
+        nonce_account,
+        &instructions,
+    )?;
     Ok(())
 }
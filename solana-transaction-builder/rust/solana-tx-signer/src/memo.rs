@@ -0,0 +1,53 @@
+//! `memo`: a transaction holding nothing but an SPL Memo signed by the
+//! device, over a fresh blockhash, for notes on chain and for checking the
+//! device, the RPC node and the fee payer work end to end. Unlike the
+//! firmware's `CREATE_TX` placeholder, it is built here and submitted.
+
+use anyhow::{bail, Result};
+use signer_core::tx_introspection::MEMO_PROGRAM_ID;
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+};
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::{network, send};
+
+/// Signs and submits `text` as a memo from the device's account
+pub fn send(
+    device: &mut Device,
+    settings: &Settings,
+    text: &str,
+    nonce_account: Option<&Pubkey>,
+) -> Result<()> {
+    if text.is_empty() {
+        bail!("The memo is empty");
+    }
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let mut fee_payer = send::load_fee_payer(settings)?;
+
+    println!("Writing memo {:?} from {}", text, device_key);
+    // Signed by the device, so the memo is attributed to its account
+    let instruction = Instruction {
+        program_id: Pubkey::new_from_array(MEMO_PROGRAM_ID),
+        accounts: vec![AccountMeta::new_readonly(device_key, true)],
+        data: text.as_bytes().to_vec(),
+    };
+    if let Some(signature) = send::sign_and_submit(
+        &client,
+        settings,
+        device,
+        &device_key,
+        fee_payer.as_mut(),
+        nonce_account,
+        &[instruction],
+    )? {
+        println!(
+            "{}",
+            network::explorer_url(&settings.network, &settings.rpc_url, &signature)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! Off-chain message signing: text, or a file's digest, wrapped in Solana's
+//! off-chain message envelope (`\xffsolana offchain`, version 0) so the
+//! signature can never double as a transaction signature.
+
+use anyhow::{anyhow, Result};
+use solana_sdk::{
+    hash::hash,
+    message::VersionedMessage,
+    offchain_message::{v0, OffchainMessage},
+    pubkey::Pubkey,
+    signature::Signature,
+};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::confirm;
+use crate::device::Device;
+
+/// What gets signed or verified
+pub enum Content<'a> {
+    Text(&'a str),
+    /// Signed as `sha256:<hex digest>` so files of any size fit on the wire
+    File(&'a Path),
+}
+
+/// Signs `content` on the device, checks the signature against the device
+/// key, and writes it (base58) to `out`, defaulting to `<FILE>.sig` for files
+pub fn sign(device: &mut Device, content: Content, out: Option<&Path>) -> Result<()> {
+    let out = match (out, &content) {
+        (Some(out), _) => Some(out.to_path_buf()),
+        (None, Content::File(path)) => {
+            let mut sig = path.as_os_str().to_owned();
+            sig.push(".sig");
+            Some(PathBuf::from(sig))
+        }
+        (None, Content::Text(_)) => None,
+    };
+    let text = message_text(&content)?;
+    let message = envelope(&text)?;
+    let payload = message
+        .serialize()
+        .map_err(|e| anyhow!("cannot sign this message: {}", e))?;
+
+    let pubkey = device.pubkey()?;
+    let signature = confirm::sign(device, &payload, &[format!("Message: {}", text)])?;
+    if !message.verify(&pubkey, &signature).unwrap_or(false) {
+        return Err(anyhow!("ESP32 returned a signature that does not verify"));
+    }
+
+    println!("Pubkey:    {}", pubkey);
+    println!("Signature: {}", signature);
+    if let Some(out) = out {
+        std::fs::write(&out, format!("{}\n", signature))
+            .map_err(|e| anyhow!("write {}: {}", out.display(), e))?;
+        println!("Wrote signature to {}", out.display());
+    }
+    Ok(())
+}
+
+/// Checks `signature` over `content` locally. Accepts signatures made by
+/// `sign` (off-chain envelope) as well as ones over the bare message bytes.
+pub fn verify(content: Content, pubkey: &Pubkey, signature: &Signature) -> Result<()> {
+    let text = message_text(&content)?;
+    if envelope(&text)?.verify(pubkey, signature).unwrap_or(false) {
+        println!("Valid off-chain message signature by {}", pubkey);
+    } else if signature.verify(pubkey.as_ref(), text.as_bytes()) {
+        println!("Valid signature by {} over the raw message bytes", pubkey);
+    } else {
+        return Err(anyhow!(
+            "signature does not match {} and this message",
+            pubkey
+        ));
+    }
+    Ok(())
+}
+
+/// Reads a base58 signature given directly or as the path of a `.sig` file
+pub fn parse_signature(value: &str) -> Result<Signature> {
+    let path = Path::new(value);
+    let text = if path.is_file() {
+        std::fs::read_to_string(path).map_err(|e| anyhow!("read {}: {}", path.display(), e))?
+    } else {
+        value.to_string()
+    };
+    Signature::from_str(text.trim()).map_err(|_| {
+        anyhow!(
+            "'{}' is neither a base58 signature nor a signature file",
+            value
+        )
+    })
+}
+
+/// Whether `bytes` are exactly a serialized transaction message. The server
+/// modes sign raw bytes as a "message", so they turn these away: the
+/// signature would be good for the transaction.
+pub fn is_transaction_message(bytes: &[u8]) -> bool {
+    bincode::deserialize::<VersionedMessage>(bytes)
+        .is_ok_and(|message| message.serialize() == bytes)
+}
+
+/// The string that actually goes into the off-chain message
+fn message_text(content: &Content) -> Result<String> {
+    match content {
+        Content::Text(text) => Ok(text.to_string()),
+        Content::File(path) => {
+            let data =
+                std::fs::read(path).map_err(|e| anyhow!("read {}: {}", path.display(), e))?;
+            Ok(format!("sha256:{}", hex(hash(&data).as_ref())))
+        }
+    }
+}
+
+fn envelope(text: &str) -> Result<OffchainMessage> {
+    if text.len() > v0::OffchainMessage::MAX_LEN_LEDGER {
+        return Err(anyhow!(
+            "message is {} bytes; at most {} fit in one signing request",
+            text.len(),
+            v0::OffchainMessage::MAX_LEN_LEDGER
+        ));
+    }
+    OffchainMessage::new(0, text.as_bytes())
+        .map_err(|e| anyhow!("not a valid off-chain message: {}", e))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::{
+        hash::Hash,
+        message::{v0, Message},
+        system_instruction,
+    };
+
+    #[test]
+    fn recognizes_transaction_messages_exactly() {
+        let payer = Pubkey::new_unique();
+        let transfer = system_instruction::transfer(&payer, &Pubkey::new_unique(), 1);
+        let legacy =
+            VersionedMessage::Legacy(Message::new(std::slice::from_ref(&transfer), Some(&payer)));
+        let versioned = VersionedMessage::V0(
+            v0::Message::try_compile(&payer, &[transfer], &[], Hash::new_unique()).unwrap(),
+        );
+        for message in [legacy, versioned] {
+            let bytes = message.serialize();
+            assert!(is_transaction_message(&bytes));
+            // Trailing bytes make it something no transaction signs
+            assert!(!is_transaction_message(&[bytes.as_slice(), b"!"].concat()));
+        }
+        assert!(!is_transaction_message(b"Sign in to example.com"));
+        assert!(!is_transaction_message(&[]));
+        let offchain = envelope("hello").unwrap().serialize().unwrap();
+        assert!(!is_transaction_message(&offchain));
+    }
+}
@@ -0,0 +1,288 @@
+//! Prometheus metrics for `serve` and `grpc-serve`: `GET /metrics` on the
+//! `--metrics-listen` address, in the text exposition format. It needs the
+//! same bearer token as the signing API, and has a listener of its own so a
+//! scrape is answered while a signing request waits for the button.
+//!
+//! - `esp32_signer_sign_requests_total{kind}`: requests received, by kind
+//!   (`transaction` or `message`)
+//! - `esp32_signer_sign_approved_total`: requests the device signed
+//! - `esp32_signer_sign_rejected_total`: requests the policy or the device
+//!   refused
+//! - `esp32_signer_device_errors_total`: requests that failed because the
+//!   device couldn't be reached or misbehaved
+//! - `esp32_signer_serial_reconnects_total`: times the serial port was
+//!   reopened after it went away
+//! - `esp32_signer_sign_duration_seconds`: histogram of the time from a
+//!   request arriving to its outcome, the button wait included
+
+use anyhow::{anyhow, Result};
+use solana_sdk::signature::Signature;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::device::DeviceError;
+use crate::serve;
+
+/// Upper bounds of the duration buckets, in seconds: signing waits for a
+/// human, so they go well past the usual web latencies
+const BUCKETS: [f64; 10] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0];
+const KINDS: [&str; 2] = ["transaction", "message"];
+
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    requests: [AtomicU64; KINDS.len()],
+    approved: AtomicU64,
+    rejected: AtomicU64,
+    device_errors: AtomicU64,
+    reconnects: AtomicU64,
+    buckets: [AtomicU64; BUCKETS.len()],
+    duration_count: AtomicU64,
+    duration_sum_micros: AtomicU64,
+}
+
+/// How a request ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Approved,
+    Rejected,
+    DeviceError,
+}
+
+impl Outcome {
+    /// The device answering with an error is it (or its operator) saying no;
+    /// anything else is the link or the device misbehaving
+    pub fn of(result: &Result<Signature>) -> Outcome {
+        match result {
+            Ok(_) => Outcome::Approved,
+            Err(e) if e.downcast_ref::<DeviceError>().is_some() => Outcome::Rejected,
+            Err(_) => Outcome::DeviceError,
+        }
+    }
+}
+
+/// One request being timed, from [`Metrics::request`]
+pub struct Timer<'a> {
+    metrics: &'a Metrics,
+    start: Instant,
+}
+
+impl Timer<'_> {
+    pub fn finish(self, outcome: Outcome) {
+        let metrics = self.metrics;
+        let counter = match outcome {
+            Outcome::Approved => &metrics.approved,
+            Outcome::Rejected => &metrics.rejected,
+            Outcome::DeviceError => &metrics.device_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        let elapsed = self.start.elapsed();
+        for (bound, bucket) in BUCKETS.iter().zip(&metrics.buckets) {
+            if elapsed.as_secs_f64() <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        metrics.duration_count.fetch_add(1, Ordering::Relaxed);
+        metrics
+            .duration_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            requests: [const { AtomicU64::new(0) }; KINDS.len()],
+            approved: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            device_errors: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            duration_count: AtomicU64::new(0),
+            duration_sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a request of `kind` (`transaction` or `message`) and starts
+    /// timing it
+    pub fn request(&self, kind: &str) -> Timer<'_> {
+        if let Some(index) = KINDS.iter().position(|k| *k == kind) {
+            self.requests[index].fetch_add(1, Ordering::Relaxed);
+        }
+        Timer {
+            metrics: self,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The text exposition format
+    pub fn render(&self) -> String {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "sign_requests_total",
+            "counter",
+            "Signing requests received.",
+        );
+        for (kind, counter) in KINDS.iter().zip(&self.requests) {
+            let _ = writeln!(
+                out,
+                "esp32_signer_sign_requests_total{{kind=\"{}\"}} {}",
+                kind,
+                get(counter)
+            );
+        }
+        for (name, help, counter) in [
+            (
+                "sign_approved_total",
+                "Requests the device signed.",
+                &self.approved,
+            ),
+            (
+                "sign_rejected_total",
+                "Requests the policy or the device refused.",
+                &self.rejected,
+            ),
+            (
+                "device_errors_total",
+                "Requests that failed because the device couldn't be reached or misbehaved.",
+                &self.device_errors,
+            ),
+            (
+                "serial_reconnects_total",
+                "Times the serial port was reopened after it went away.",
+                &self.reconnects,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "esp32_signer_{} {}", name, get(counter));
+        }
+
+        let name = "sign_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Time from a signing request to its outcome, the button wait included.",
+        );
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            let _ = writeln!(
+                out,
+                "esp32_signer_{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                get(bucket)
+            );
+        }
+        let count = get(&self.duration_count);
+        let sum = get(&self.duration_sum_micros) as f64 / 1e6;
+        let _ = writeln!(out, "esp32_signer_{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "esp32_signer_{}_sum {}", name, sum);
+        let _ = writeln!(out, "esp32_signer_{}_count {}", name, count);
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP esp32_signer_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE esp32_signer_{} {}", name, kind);
+}
+
+/// Serves `/metrics` on `listen` (a loopback address) from a thread of its
+/// own
+pub fn serve_http(listen: &str, token: String) -> Result<()> {
+    let address = serve::loopback_address(listen)?;
+    let server =
+        Server::http(address).map_err(|e| anyhow!("Failed to listen on {}: {}", address, e))?;
+    println!("Metrics on http://{}/metrics", address);
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let _ = match (request.method(), request.url()) {
+                (Method::Get, "/metrics") if serve::authorized(&request, &token) => {
+                    let content_type =
+                        Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+                            .expect("static header is valid");
+                    request
+                        .respond(Response::from_string(METRICS.render()).with_header(content_type))
+                }
+                (Method::Get, "/metrics") => request.respond(Response::empty(401)),
+                _ => request.respond(Response::empty(404)),
+            };
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_outcomes_and_durations() {
+        let metrics = Metrics::new();
+        metrics.request("transaction").finish(Outcome::Approved);
+        metrics.request("transaction").finish(Outcome::Rejected);
+        metrics.request("message").finish(Outcome::DeviceError);
+        metrics.request("something else");
+        metrics.reconnected();
+
+        let text = metrics.render();
+        for line in [
+            "esp32_signer_sign_requests_total{kind=\"transaction\"} 2",
+            "esp32_signer_sign_requests_total{kind=\"message\"} 1",
+            "esp32_signer_sign_approved_total 1",
+            "esp32_signer_sign_rejected_total 1",
+            "esp32_signer_device_errors_total 1",
+            "esp32_signer_serial_reconnects_total 1",
+            "esp32_signer_sign_duration_seconds_bucket{le=\"0.5\"} 3",
+            "esp32_signer_sign_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "esp32_signer_sign_duration_seconds_count 3",
+        ] {
+            assert!(text.lines().any(|l| l == line), "no {} in\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn every_family_is_declared_once() {
+        let text = Metrics::new().render();
+        let types: Vec<&str> = text
+            .lines()
+            .filter_map(|l| l.strip_prefix("# TYPE "))
+            .collect();
+        assert_eq!(types.len(), 6);
+        for line in text.lines().filter(|l| !l.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let family = name
+                .trim_end_matches("_bucket")
+                .trim_end_matches("_sum")
+                .trim_end_matches("_count");
+            assert!(
+                types.iter().any(|t| t.starts_with(&format!("{} ", family))),
+                "{} has no TYPE",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn classifies_outcomes() {
+        let refused: Result<Signature> = Err(DeviceError {
+            code: signer_protocol::error::ErrorCode::Policy,
+            detail: Some("over limit".to_string()),
+        }
+        .into());
+        assert_eq!(Outcome::of(&refused), Outcome::Rejected);
+        let gone: Result<Signature> = Err(anyhow!("serial port closed"));
+        assert_eq!(Outcome::of(&gone), Outcome::DeviceError);
+        assert_eq!(Outcome::of(&Ok(Signature::default())), Outcome::Approved);
+    }
+}
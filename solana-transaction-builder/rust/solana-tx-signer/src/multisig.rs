@@ -0,0 +1,117 @@
+//! Transactions that need more than one signer, passed around as a
+//! transaction file (see `payload`) until every required signature is in.
+//! Each ESP32, or keypair file, signs its own copy or the same file in turn;
+//! copies signed in parallel can be merged.
+
+use anyhow::{anyhow, Result};
+use solana_sdk::{
+    program_pack::Pack, pubkey::Pubkey, signature::Signature, transaction::VersionedTransaction,
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use std::path::Path;
+
+use crate::config::Settings;
+use crate::payload::{self, BuildOptions};
+use crate::{amount, token};
+
+/// A token transfer out of the associated token account of an SPL
+/// multisig `authority`, to be signed by `signers` (at least the multisig's
+/// threshold of its members)
+pub fn build_token_transfer(
+    settings: &Settings,
+    authority: &Pubkey,
+    mint: &Pubkey,
+    to: &Pubkey,
+    amount: &str,
+    signers: &[Pubkey],
+    options: &BuildOptions,
+) -> Result<VersionedTransaction> {
+    let client = payload::client(settings);
+    let (program_id, decimals) = token::mint_info(&client, mint)?;
+    let raw_amount = amount::parse_units(amount, decimals)?;
+
+    let account = client
+        .get_account(authority)
+        .map_err(|e| anyhow!("Failed to fetch multisig {}: {}", authority, e))?;
+    if account.owner != program_id {
+        return Err(anyhow!(
+            "{} is not a multisig of the mint's token program",
+            authority
+        ));
+    }
+    let multisig = spl_token_2022::state::Multisig::unpack(&account.data)
+        .map_err(|e| anyhow!("{} is not a token multisig: {}", authority, e))?;
+    let members = &multisig.signers[..usize::from(multisig.n)];
+    if let Some(stranger) = signers.iter().find(|s| !members.contains(s)) {
+        return Err(anyhow!("{} is not a member of {}", stranger, authority));
+    }
+    if signers.len() < usize::from(multisig.m) {
+        return Err(anyhow!(
+            "{} needs {} of its {} signers, only {} given",
+            authority,
+            multisig.m,
+            multisig.n,
+            signers.len()
+        ));
+    }
+
+    let (payer, fee_payer) = payload::resolve_payer(settings, options, &signers[0])?;
+    let source = get_associated_token_address_with_program_id(authority, mint, &program_id);
+    let mut instructions = Vec::new();
+    let destination = token::destination(
+        &client,
+        settings,
+        mint,
+        &program_id,
+        to,
+        &payer,
+        &mut instructions,
+    )?;
+    let signer_refs: Vec<&Pubkey> = signers.iter().collect();
+    instructions.push(spl_token_2022::instruction::transfer_checked(
+        &program_id,
+        &source,
+        mint,
+        &destination,
+        authority,
+        &signer_refs,
+        raw_amount,
+        decimals,
+    )?);
+
+    println!(
+        "Sending {} of mint {} ({} base units) from {} to {}",
+        amount, mint, raw_amount, source, destination
+    );
+    payload::build(settings, options, &payer, fee_payer.as_ref(), &instructions)
+}
+
+/// Combines the signatures of several copies of the same payload into `out`
+pub fn merge(inputs: &[impl AsRef<Path>], out: &Path) -> Result<()> {
+    let (first, rest) = inputs
+        .split_first()
+        .ok_or_else(|| anyhow!("nothing to merge"))?;
+    let mut merged = payload::load(first.as_ref())?;
+    let message_bytes = merged.message.serialize();
+    let keys = merged.message.static_account_keys().to_vec();
+
+    for input in rest {
+        let other = payload::load(input.as_ref())?;
+        if other.message.serialize() != message_bytes {
+            return Err(anyhow!(
+                "{} holds a different transaction than {}",
+                input.as_ref().display(),
+                first.as_ref().display()
+            ));
+        }
+        for (slot, signature) in other.signatures.iter().enumerate() {
+            if merged.signatures[slot] == Signature::default()
+                && signature.verify(keys[slot].as_ref(), &message_bytes)
+            {
+                merged.signatures[slot] = *signature;
+            }
+        }
+    }
+    payload::save(&merged, out)?;
+    payload::status(out)
+}
@@ -0,0 +1,367 @@
+//! `native-host`: the device as a browser wallet backend, through native
+//! messaging. A browser extension implementing the wallet standard opens the
+//! host with `runtime.connectNative("esp32_signer")` and forwards its
+//! `signTransaction` and `signMessage` calls; the browser starts this
+//! command, and each message in either direction is a 32-bit length in the
+//! machine's byte order followed by that many bytes of UTF-8 JSON.
+//!
+//! The messages are those of `serve`'s WebSocket:
+//!
+//! - `{"id": 1, "method": "pubkey"}` -> `{"id": 1, "status": "ok", "pubkey": ...}`
+//! - `{"id": 2, "method": "signTransaction", "transaction": "<base64>"}` or
+//!   `{"id": 3, "method": "signMessage", "message": "<base64>"}` ->
+//!   `{"id": 2, "status": "waiting-for-button"}`, then `"signed"` or
+//!   `"rejected"`
+//!
+//! There is no token: the browser only starts the host for the extensions
+//! its manifest names, and passes the caller's origin, which is what the
+//! terminal and notifications show. The signing policy, the network's
+//! `--yes` threshold and the BOOT button apply as for any other request.
+//! Stdout carries the messages, so anything else printed goes to stderr,
+//! which browsers keep in their logs.
+//!
+//! `native-host --install <EXTENSION>` writes the manifest for one browser,
+//! with a launcher script next to it that runs this binary with the
+//! `--port` or `--device` given, and `--yes` if it was.
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, ValueEnum};
+use nix::unistd::{dup, dup2};
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::network::Threshold;
+use crate::notify::{Notifier, Templates};
+use crate::serve;
+
+/// The name extensions connect to
+const HOST_NAME: &str = "esp32_signer";
+/// Largest message a browser accepts from a host
+const MAX_REPLY: usize = 1024 * 1024;
+
+#[derive(Args, Debug)]
+pub struct NativeHostArgs {
+    /// Write the manifest allowing this extension (`chrome-extension://<id>/`
+    /// or a bare ID for Chromium browsers, the add-on ID for Firefox) and
+    /// exit
+    #[arg(long, value_name = "EXTENSION")]
+    install: Option<String>,
+    /// The browser to install for
+    #[arg(long, value_enum, default_value_t = Browser::Chrome, requires = "install")]
+    browser: Browser,
+    /// What the browser passes: the caller's origin (Chromium), or the
+    /// manifest path and add-on ID (Firefox)
+    #[arg(hide = true, allow_hyphen_values = true)]
+    caller: Vec<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Browser {
+    Chrome,
+    Chromium,
+    Brave,
+    Firefox,
+}
+
+impl Browser {
+    /// The per-user manifest directory
+    fn manifest_dir(self) -> Result<PathBuf> {
+        let home =
+            PathBuf::from(std::env::var_os("HOME").ok_or_else(|| anyhow!("HOME is not set"))?);
+        let dir = if cfg!(target_os = "macos") {
+            let support = home.join("Library/Application Support");
+            match self {
+                Browser::Chrome => support.join("Google/Chrome"),
+                Browser::Chromium => support.join("Chromium"),
+                Browser::Brave => support.join("BraveSoftware/Brave-Browser"),
+                Browser::Firefox => support.join("Mozilla"),
+            }
+            .join("NativeMessagingHosts")
+        } else {
+            match self {
+                Browser::Chrome => home.join(".config/google-chrome/NativeMessagingHosts"),
+                Browser::Chromium => home.join(".config/chromium/NativeMessagingHosts"),
+                Browser::Brave => {
+                    home.join(".config/BraveSoftware/Brave-Browser/NativeMessagingHosts")
+                }
+                Browser::Firefox => home.join(".mozilla/native-messaging-hosts"),
+            }
+        };
+        Ok(dir)
+    }
+}
+
+/// Answers the browser until it closes the connection; `connect` opens the
+/// device, once stdout is set aside for the messages
+pub fn run<'a>(
+    args: &NativeHostArgs,
+    settings: &Settings,
+    connect: impl FnOnce() -> Result<&'a mut Device>,
+) -> Result<()> {
+    if let Some(extension) = &args.install {
+        return install(args.browser, extension, settings);
+    }
+    let mut output = take_stdout()?;
+    let origin = caller(&args.caller);
+    tracing::info!("Started by {}", origin);
+
+    let device = connect()?;
+    let pubkey = device.pubkey()?;
+    let notifier = Notifier::start(
+        Vec::new(),
+        Templates::new(&settings.notify_templates)?,
+        pubkey,
+    );
+    relay(
+        &Mutex::new(device),
+        &pubkey,
+        &notifier,
+        &Threshold::new(settings),
+        &origin,
+        &mut io::stdin().lock(),
+        &mut output,
+    )?;
+    tracing::info!("{} closed the connection", origin);
+    Ok(())
+}
+
+/// Answers the messages on `input` on `output` until the browser is gone
+fn relay(
+    device: &Mutex<&mut Device>,
+    pubkey: &Pubkey,
+    notifier: &Notifier,
+    threshold: &Threshold,
+    origin: &str,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<()> {
+    while let Some(text) = read_message(input)? {
+        let reply = match text {
+            Ok(text) => serve::answer(
+                device,
+                pubkey,
+                notifier,
+                threshold,
+                &text,
+                origin,
+                &mut |update| write_message(output, update).is_ok(),
+            ),
+            Err(error) => Some(json!({ "id": null, "status": "error", "error": error })),
+        };
+        let Some(reply) = reply else {
+            break;
+        };
+        write_message(output, &reply)?;
+    }
+    Ok(())
+}
+
+/// Moves stdout out of the way of `println!`: the returned file is the
+/// browser's end, and fd 1 becomes a copy of stderr
+fn take_stdout() -> Result<File> {
+    io::stdout().flush()?;
+    let fd = dup(io::stdout().as_raw_fd()).context("duplicate stdout")?;
+    dup2(io::stderr().as_raw_fd(), io::stdout().as_raw_fd()).context("redirect stdout")?;
+    // SAFETY: `fd` was just duplicated and nothing else owns it
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Who started the host, from the arguments the browser passed
+fn caller(args: &[String]) -> String {
+    match args {
+        [origin, ..] if origin.starts_with("chrome-extension://") => origin.clone(),
+        // Firefox: the manifest's path, then the add-on's ID
+        [_, id, ..] => format!("add-on {}", id),
+        _ => "a browser extension".to_string(),
+    }
+}
+
+/// The next message, `None` once the browser closed stdin; a message too
+/// large or not UTF-8 is skipped and given as the error to answer it with
+fn read_message(input: &mut impl Read) -> Result<Option<Result<String, String>>> {
+    let mut length = [0; 4];
+    match input.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e).context("read from the browser"),
+    }
+    let length = u32::from_ne_bytes(length) as u64;
+    if length > serve::MAX_BODY {
+        io::copy(&mut input.by_ref().take(length), &mut io::sink())?;
+        return Ok(Some(Err(format!(
+            "message of {} bytes; at most {} are taken",
+            length,
+            serve::MAX_BODY
+        ))));
+    }
+    let mut bytes = vec![0; length as usize];
+    input
+        .read_exact(&mut bytes)
+        .context("read from the browser")?;
+    Ok(Some(
+        String::from_utf8(bytes).map_err(|_| "message is not UTF-8".to_string()),
+    ))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> Result<()> {
+    let bytes = message.to_string().into_bytes();
+    if bytes.len() > MAX_REPLY {
+        return Err(anyhow!(
+            "reply of {} bytes is too large for the browser",
+            bytes.len()
+        ));
+    }
+    output.write_all(&(bytes.len() as u32).to_ne_bytes())?;
+    output.write_all(&bytes)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Writes the launcher and the manifest allowing `extension` into
+/// `browser`'s manifest directory
+fn install(browser: Browser, extension: &str, settings: &Settings) -> Result<()> {
+    let dir = browser.manifest_dir()?;
+    std::fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+
+    let exe = std::env::current_exe().context("find this executable")?;
+    let mut command = vec![quote(&exe.to_string_lossy())];
+    if let Some(device) = &settings.device {
+        command.extend(["--device".to_string(), quote(device)]);
+    } else if let Some(port) = &settings.port {
+        command.extend(["--port".to_string(), quote(port)]);
+    }
+    if settings.yes {
+        command.push("--yes".to_string());
+    }
+    command.push("native-host".to_string());
+    let launcher = dir.join(format!("{}.sh", HOST_NAME));
+    std::fs::write(
+        &launcher,
+        format!("#!/bin/sh\nexec {} \"$@\"\n", command.join(" ")),
+    )
+    .with_context(|| format!("write {}", launcher.display()))?;
+    std::fs::set_permissions(&launcher, std::fs::Permissions::from_mode(0o755))?;
+
+    let mut manifest = json!({
+        "name": HOST_NAME,
+        "description": "ESP32 Solana signer",
+        "path": launcher,
+        "type": "stdio",
+    });
+    if browser == Browser::Firefox {
+        manifest["allowed_extensions"] = json!([extension]);
+    } else {
+        let id = extension
+            .trim_start_matches("chrome-extension://")
+            .trim_end_matches('/');
+        manifest["allowed_origins"] = json!([format!("chrome-extension://{}/", id)]);
+    }
+    let path = dir.join(format!("{}.json", HOST_NAME));
+    std::fs::write(&path, serde_json::to_string_pretty(&manifest)?)
+        .with_context(|| format!("write {}", path.display()))?;
+    println!("Wrote {}", path.display());
+    println!("Extensions connect to \"{}\"", HOST_NAME);
+    Ok(())
+}
+
+/// `value` as one shell word
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_framed_messages() {
+        let mut stream = Vec::new();
+        for text in ["{\"id\":1}", ""] {
+            stream.extend((text.len() as u32).to_ne_bytes());
+            stream.extend(text.as_bytes());
+        }
+        stream.extend(((serve::MAX_BODY + 1) as u32).to_ne_bytes());
+        stream.extend(vec![b' '; serve::MAX_BODY as usize + 1]);
+        stream.extend(1u32.to_ne_bytes());
+        stream.push(0xff);
+
+        let mut input = stream.as_slice();
+        assert_eq!(
+            read_message(&mut input).unwrap(),
+            Some(Ok("{\"id\":1}".to_string()))
+        );
+        assert_eq!(read_message(&mut input).unwrap(), Some(Ok(String::new())));
+        assert!(matches!(read_message(&mut input).unwrap(), Some(Err(_))));
+        assert!(matches!(read_message(&mut input).unwrap(), Some(Err(_))));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn writes_framed_messages() {
+        let mut output = Vec::new();
+        write_message(&mut output, &json!({ "id": 1, "status": "ok" })).unwrap();
+        let length = u32::from_ne_bytes(output[..4].try_into().unwrap()) as usize;
+        assert_eq!(length, output.len() - 4);
+        let reply: Value = serde_json::from_slice(&output[4..]).unwrap();
+        assert_eq!(reply["status"], "ok");
+    }
+
+    #[test]
+    fn refuses_a_transaction_message() {
+        let pubkey = Pubkey::new_unique();
+        let mut offline = Device::offline(pubkey);
+        let notifier = Notifier::start(
+            Vec::new(),
+            Templates::new(&Default::default()).unwrap(),
+            pubkey,
+        );
+        let request = json!({
+            "id": 3,
+            "method": "signMessage",
+            "message": serve::tests::transfer_message(&pubkey),
+        });
+        let mut input = Vec::new();
+        write_message(&mut input, &request).unwrap();
+
+        let mut output = Vec::new();
+        relay(
+            &Mutex::new(&mut offline),
+            &pubkey,
+            &notifier,
+            &serve::tests::mainnet(false),
+            "chrome-extension://abc/",
+            &mut input.as_slice(),
+            &mut output,
+        )
+        .unwrap();
+        // One reply, and no waiting-for-button before it
+        let reply: Value = serde_json::from_slice(&output[4..]).unwrap();
+        assert_eq!(
+            output.len() - 4,
+            u32::from_ne_bytes(output[..4].try_into().unwrap()) as usize
+        );
+        assert_eq!(reply["id"], 3);
+        assert_eq!(reply["status"], "error");
+        assert_eq!(reply["error"], serve::NOT_A_MESSAGE);
+    }
+
+    #[test]
+    fn names_the_caller() {
+        let chrome = ["chrome-extension://abc/".to_string()];
+        assert_eq!(caller(&chrome), "chrome-extension://abc/");
+        let firefox = [
+            "/path/esp32_signer.json".to_string(),
+            "wallet@example".to_string(),
+        ];
+        assert_eq!(caller(&firefox), "add-on wallet@example");
+        assert_eq!(caller(&[]), "a browser extension");
+    }
+}
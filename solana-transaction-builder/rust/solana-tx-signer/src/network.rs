@@ -0,0 +1,374 @@
+//! Network profiles: the cluster a command talks to, picked by name with
+//! `--network` or the config file's `network` key. `mainnet-beta`, `devnet`,
+//! `testnet` and `localnet` are built in; `[networks.<name>]` tables in the
+//! config file change their endpoints or add custom ones.
+//!
+//! A profile also carries its safety defaults: on `mainnet-beta`, a
+//! transaction that moves more than 1 SOL out of the device's hands needs
+//! `--yes` on top of the BOOT button, so a command meant for devnet doesn't
+//! move real funds unnoticed. The check sits on the signing path, so every
+//! command that has the device sign is held to it, and the server modes hold
+//! each transaction they are sent to it too (`Threshold`).
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use solana_sdk::{
+    message::VersionedMessage,
+    native_token::LAMPORTS_PER_SOL,
+    pubkey::Pubkey,
+    signature::Signature,
+    stake::{self, instruction::StakeInstruction},
+    system_instruction::SystemInstruction,
+    system_program,
+    vote::{self, instruction::VoteInstruction},
+};
+use std::collections::HashMap;
+
+use crate::amount::format_sol;
+use crate::config::Settings;
+
+pub const DEFAULT_NETWORK: &str = "devnet";
+
+/// A profile as it is used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    pub rpc_url: String,
+    /// PubSub endpoint, where it isn't the RPC URL's
+    pub ws_url: Option<String>,
+    /// SOL transfers of more lamports than this need `--yes`
+    pub confirm_above: Option<u64>,
+}
+
+/// A `[networks.<name>]` table; every key is optional for a built-in
+/// profile, and `rpc_url` is required for a new one
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    rpc_url: Option<String>,
+    ws_url: Option<String>,
+    confirm_above: Option<u64>,
+}
+
+pub const BUILT_IN: &[&str] = &["mainnet-beta", "devnet", "testnet", "localnet"];
+
+fn built_in(name: &str) -> Option<Profile> {
+    let (rpc_url, ws_url, confirm_above) = match name {
+        "mainnet-beta" => (
+            "https://api.mainnet-beta.solana.com",
+            None,
+            Some(LAMPORTS_PER_SOL),
+        ),
+        "devnet" => ("https://api.devnet.solana.com", None, None),
+        "testnet" => ("https://api.testnet.solana.com", None, None),
+        "localnet" => ("http://127.0.0.1:8899", Some("ws://127.0.0.1:8900"), None),
+        _ => return None,
+    };
+    Some(Profile {
+        name: name.to_string(),
+        rpc_url: rpc_url.to_string(),
+        ws_url: ws_url.map(str::to_string),
+        confirm_above,
+    })
+}
+
+/// The profile called `name`: a built-in one with the config file's changes,
+/// or one the config file defines
+pub fn resolve(name: &str, configured: &HashMap<String, ProfileConfig>) -> Result<Profile> {
+    // The name the Solana CLI accepts too
+    let name = if name == "mainnet" {
+        "mainnet-beta"
+    } else {
+        name
+    };
+    let config = configured.get(name).cloned().unwrap_or_default();
+    let profile = match built_in(name) {
+        Some(profile) => profile,
+        None => Profile {
+            name: name.to_string(),
+            rpc_url: config.rpc_url.clone().ok_or_else(|| {
+                if configured.contains_key(name) {
+                    anyhow!("network '{}' in the config has no rpc_url", name)
+                } else {
+                    anyhow!(
+                        "unknown network '{}'; use one of {} or define it under [networks.{}]",
+                        name,
+                        BUILT_IN.join(", "),
+                        name
+                    )
+                }
+            })?,
+            ws_url: None,
+            confirm_above: None,
+        },
+    };
+    Ok(Profile {
+        rpc_url: config.rpc_url.unwrap_or(profile.rpc_url),
+        ws_url: config.ws_url.or(profile.ws_url),
+        confirm_above: config.confirm_above.or(profile.confirm_above),
+        name: profile.name,
+    })
+}
+
+/// Names of every profile: the built-in ones, then the config file's own
+pub fn names(configured: &HashMap<String, ProfileConfig>) -> Vec<String> {
+    let mut custom: Vec<String> = configured
+        .keys()
+        .filter(|name| !BUILT_IN.contains(&name.as_str()))
+        .cloned()
+        .collect();
+    custom.sort();
+    BUILT_IN
+        .iter()
+        .map(|name| name.to_string())
+        .chain(custom)
+        .collect()
+}
+
+/// Prints every profile, with `*` before the one in use
+pub fn list(settings: &Settings) -> Result<()> {
+    for name in names(&settings.networks) {
+        let profile = if name == settings.network.name {
+            settings.network.clone()
+        } else {
+            resolve(&name, &settings.networks)?
+        };
+        let current = if name == settings.network.name {
+            "*"
+        } else {
+            " "
+        };
+        print!("{} {:<14} {}", current, profile.name, profile.rpc_url);
+        if let Some(ws_url) = &profile.ws_url {
+            print!("  ws {}", ws_url);
+        }
+        if let Some(limit) = profile.confirm_above {
+            print!("  --yes above {} SOL", format_sol(limit));
+        }
+        println!();
+    }
+    if settings.rpc_url != settings.network.rpc_url {
+        println!("RPC URL in use: {}", settings.rpc_url);
+    }
+    Ok(())
+}
+
+/// Refuses a transfer of `lamports` in total above the profile's threshold
+/// unless `yes` was given
+pub fn check_transfer(profile: &Profile, lamports: u64, yes: bool) -> Result<()> {
+    match profile.confirm_above {
+        Some(limit) if lamports > limit && !yes => bail!(
+            "This sends {} SOL on {}, more than its {} SOL without --yes; \
+             check the amount and the network, then run it again with --yes",
+            format_sol(lamports),
+            profile.name,
+            format_sol(limit)
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Refuses to have the device (`device_key`) sign `message` when it moves
+/// more than the network's threshold, unless `--yes` was given
+pub fn check_message(
+    settings: &Settings,
+    message: &VersionedMessage,
+    device_key: &Pubkey,
+) -> Result<()> {
+    Threshold::new(settings).check(message, device_key)
+}
+
+/// The network's threshold and `--yes`, for the server modes to hold each
+/// transaction they are sent to, as `check_message` does for a command
+#[derive(Debug, Clone)]
+pub struct Threshold {
+    pub profile: Profile,
+    pub yes: bool,
+}
+
+impl Threshold {
+    pub fn new(settings: &Settings) -> Self {
+        Threshold {
+            profile: settings.network.clone(),
+            yes: settings.yes,
+        }
+    }
+
+    /// Like `check_message`
+    pub fn check(&self, message: &VersionedMessage, device_key: &Pubkey) -> Result<()> {
+        let lamports = lamports_moved(message, device_key);
+        check_transfer(&self.profile, lamports, self.yes)
+    }
+}
+
+/// Lamports `message` moves on `authority`'s say-so: system transfers and
+/// the accounts it funds, and withdrawals from stake and vote accounts
+fn lamports_moved(message: &VersionedMessage, authority: &Pubkey) -> u64 {
+    let keys = message.static_account_keys();
+    message
+        .instructions()
+        .iter()
+        .filter_map(|ix| {
+            let program = keys.get(usize::from(ix.program_id_index))?;
+            let (lamports, signer_at) = if *program == system_program::id() {
+                match bincode::deserialize(&ix.data).ok()? {
+                    SystemInstruction::Transfer { lamports }
+                    | SystemInstruction::CreateAccount { lamports, .. }
+                    | SystemInstruction::CreateAccountWithSeed { lamports, .. } => (lamports, 0),
+                    _ => return None,
+                }
+            } else if *program == stake::program::id() {
+                match bincode::deserialize(&ix.data).ok()? {
+                    StakeInstruction::Withdraw(lamports) => (lamports, 4),
+                    _ => return None,
+                }
+            } else if *program == vote::program::id() {
+                match bincode::deserialize(&ix.data).ok()? {
+                    VoteInstruction::Withdraw(lamports) => (lamports, 2),
+                    _ => return None,
+                }
+            } else {
+                return None;
+            };
+            let signer = keys.get(usize::from(*ix.accounts.get(signer_at)?))?;
+            (signer == authority).then_some(lamports)
+        })
+        .fold(0, u64::saturating_add)
+}
+
+/// Solana Explorer link to `signature`, on the profile's cluster, or on
+/// `rpc_url` itself when that isn't the profile's public endpoint
+pub fn explorer_url(profile: &Profile, rpc_url: &str, signature: &Signature) -> String {
+    let base = format!("https://explorer.solana.com/tx/{}", signature);
+    let built_in = built_in(&profile.name).filter(|built_in| built_in.rpc_url == rpc_url);
+    match built_in {
+        Some(profile) if profile.name == "mainnet-beta" => base,
+        Some(profile) if profile.name != "localnet" => {
+            format!("{}?cluster={}", base, profile.name)
+        }
+        _ => {
+            reqwest::Url::parse_with_params(&base, &[("cluster", "custom"), ("customUrl", rpc_url)])
+                .map_or(base, String::from)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::{instruction::Instruction, message::Message, system_instruction};
+
+    fn configured(entries: &[(&str, ProfileConfig)]) -> HashMap<String, ProfileConfig> {
+        entries
+            .iter()
+            .map(|(name, config)| (name.to_string(), config.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn resolves_built_in_and_custom_profiles() {
+        let none = HashMap::new();
+        let mainnet = resolve("mainnet", &none).unwrap();
+        assert_eq!(mainnet.name, "mainnet-beta");
+        assert_eq!(mainnet.confirm_above, Some(LAMPORTS_PER_SOL));
+        assert!(resolve("moonnet", &none).is_err());
+
+        let networks = configured(&[
+            (
+                "devnet",
+                ProfileConfig {
+                    rpc_url: Some("https://devnet.example.com".to_string()),
+                    ..ProfileConfig::default()
+                },
+            ),
+            (
+                "staging",
+                ProfileConfig {
+                    rpc_url: Some("https://rpc.example.com".to_string()),
+                    ws_url: Some("wss://rpc.example.com".to_string()),
+                    confirm_above: Some(5),
+                },
+            ),
+            ("broken", ProfileConfig::default()),
+        ]);
+        assert_eq!(
+            resolve("devnet", &networks).unwrap().rpc_url,
+            "https://devnet.example.com"
+        );
+        let staging = resolve("staging", &networks).unwrap();
+        assert_eq!(staging.ws_url.as_deref(), Some("wss://rpc.example.com"));
+        assert_eq!(staging.confirm_above, Some(5));
+        assert!(resolve("broken", &networks).is_err());
+        assert_eq!(names(&networks)[4..], ["broken", "staging"]);
+    }
+
+    #[test]
+    fn large_mainnet_transfers_need_yes() {
+        let mainnet = resolve("mainnet-beta", &HashMap::new()).unwrap();
+        assert!(check_transfer(&mainnet, LAMPORTS_PER_SOL, false).is_ok());
+        assert!(check_transfer(&mainnet, LAMPORTS_PER_SOL + 1, false).is_err());
+        assert!(check_transfer(&mainnet, LAMPORTS_PER_SOL + 1, true).is_ok());
+
+        let devnet = resolve("devnet", &HashMap::new()).unwrap();
+        assert!(check_transfer(&devnet, u64::MAX, false).is_ok());
+    }
+
+    #[test]
+    fn large_moves_need_yes_whatever_the_command() {
+        let device = Pubkey::new_unique();
+        let mainnet = resolve("mainnet-beta", &HashMap::new()).unwrap();
+        let check = |instruction: Instruction, yes: bool| {
+            let message = Message::new(&[instruction], Some(&device));
+            let lamports = lamports_moved(&VersionedMessage::Legacy(message), &device);
+            check_transfer(&mainnet, lamports, yes)
+        };
+
+        // As `stake create` funds its account
+        let stake_account =
+            Pubkey::create_with_seed(&device, "stake", &stake::program::id()).unwrap();
+        let create = system_instruction::create_account_with_seed(
+            &device,
+            &stake_account,
+            &device,
+            "stake",
+            2 * LAMPORTS_PER_SOL,
+            200,
+            &stake::program::id(),
+        );
+        assert!(check(create.clone(), false).is_err());
+        assert!(check(create, true).is_ok());
+
+        let withdraw = vote::instruction::withdraw(
+            &Pubkey::new_unique(),
+            &device,
+            2 * LAMPORTS_PER_SOL,
+            &Pubkey::new_unique(),
+        );
+        assert!(check(withdraw, false).is_err());
+
+        // Someone else's transfer isn't the device's to confirm
+        let transfer =
+            system_instruction::transfer(&Pubkey::new_unique(), &device, 2 * LAMPORTS_PER_SOL);
+        assert!(check(transfer, false).is_ok());
+    }
+
+    #[test]
+    fn links_to_the_explorer() {
+        let signature = Signature::default();
+        let tx = format!("https://explorer.solana.com/tx/{}", signature);
+        let mainnet = resolve("mainnet-beta", &HashMap::new()).unwrap();
+        let devnet = resolve("devnet", &HashMap::new()).unwrap();
+        assert_eq!(explorer_url(&mainnet, &mainnet.rpc_url, &signature), tx);
+        assert_eq!(
+            explorer_url(&devnet, &devnet.rpc_url, &signature),
+            format!("{}?cluster=devnet", tx)
+        );
+        assert_eq!(
+            explorer_url(&devnet, "http://127.0.0.1:8899", &signature),
+            format!(
+                "{}?cluster=custom&customUrl=http%3A%2F%2F127.0.0.1%3A8899",
+                tx
+            )
+        );
+    }
+}
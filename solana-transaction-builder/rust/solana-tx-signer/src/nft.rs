@@ -0,0 +1,165 @@
+//! NFT transfers out of the device's account.
+//!
+//! Plain NFTs (and ones without Metaplex metadata) move with an SPL
+//! `TransferChecked` of 1. Programmable NFTs are frozen in the owner's token
+//! account and can only move through Metaplex Token Metadata's `Transfer`,
+//! which checks the token records and the collection's rule set, if any, and
+//! creates the recipient's token account itself.
+
+use anyhow::{anyhow, Result};
+use mpl_token_metadata::{
+    accounts::{MasterEdition, Metadata, TokenRecord},
+    instructions::TransferV1Builder,
+    types::{ProgrammableConfig, TokenStandard},
+};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{instruction::Instruction, pubkey, pubkey::Pubkey};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use spl_token_2022::{extension::StateWithExtensions, state::Account};
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::{send, token};
+
+/// Metaplex Token Authorization Rules, which evaluates pNFT rule sets
+const AUTH_RULES_PROGRAM_ID: Pubkey = pubkey!("auth9SigNpDKz4sJJ1DfCTuZrZNSAgh9sFD3rboVmgg");
+
+/// Sends the NFT `mint` held by the device's associated token account to the
+/// wallet `to`
+pub fn transfer(
+    device: &mut Device,
+    settings: &Settings,
+    mint: &Pubkey,
+    to: &Pubkey,
+    nonce_account: Option<&Pubkey>,
+) -> Result<()> {
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let mut fee_payer = send::load_fee_payer(settings)?;
+    let payer = fee_payer.as_ref().map_or(device_key, |p| p.pubkey());
+
+    let (program_id, decimals) = token::mint_info(&client, mint)?;
+    if decimals != 0 {
+        return Err(anyhow!(
+            "{} has {} decimals, so it isn't an NFT; use transfer-token",
+            mint,
+            decimals
+        ));
+    }
+    let source = get_associated_token_address_with_program_id(&device_key, mint, &program_id);
+    if held(&client, &source)? == 0 {
+        return Err(anyhow!(
+            "The device's token account {} doesn't hold {}",
+            source,
+            mint
+        ));
+    }
+
+    let metadata = metadata(&client, mint)?;
+    let name = metadata
+        .as_ref()
+        .map(|metadata| metadata.name.trim_matches('\0').trim())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("NFT");
+    println!("Sending {} ({}) from {} to {}", name, mint, device_key, to);
+
+    let programmable = metadata.as_ref().is_some_and(|metadata| {
+        matches!(
+            metadata.token_standard,
+            Some(
+                TokenStandard::ProgrammableNonFungible
+                    | TokenStandard::ProgrammableNonFungibleEdition
+            )
+        )
+    });
+    let instructions = match metadata {
+        Some(metadata) if programmable => {
+            vec![programmable_transfer(
+                &metadata,
+                &program_id,
+                &source,
+                &device_key,
+                to,
+                &payer,
+            )]
+        }
+        _ => token::transfer_instructions(settings, &device_key, mint, to, "1", None, &payer)?,
+    };
+    send::sign_and_submit(
+        &client,
+        settings,
+        device,
+        &device_key,
+        fee_payer.as_mut(),
+        nonce_account,
+        &instructions,
+    )?;
+    Ok(())
+}
+
+/// Token Metadata's `Transfer` of a programmable NFT from `owner`'s token
+/// account `source` to `to`'s associated token account
+fn programmable_transfer(
+    metadata: &Metadata,
+    program_id: &Pubkey,
+    source: &Pubkey,
+    owner: &Pubkey,
+    to: &Pubkey,
+    payer: &Pubkey,
+) -> Instruction {
+    let mint = metadata.mint;
+    let destination = get_associated_token_address_with_program_id(to, &mint, program_id);
+    let rule_set = match &metadata.programmable_config {
+        Some(ProgrammableConfig::V1 { rule_set }) => *rule_set,
+        None => None,
+    };
+    if let Some(rule_set) = rule_set {
+        println!("Transfer checked against rule set {}", rule_set);
+    }
+
+    TransferV1Builder::new()
+        .token(*source)
+        .token_owner(*owner)
+        .destination_token(destination)
+        .destination_owner(*to)
+        .mint(mint)
+        .metadata(Metadata::find_pda(&mint).0)
+        .edition(Some(MasterEdition::find_pda(&mint).0))
+        .token_record(Some(TokenRecord::find_pda(&mint, source).0))
+        .destination_token_record(Some(TokenRecord::find_pda(&mint, &destination).0))
+        .authority(*owner)
+        .payer(*payer)
+        .spl_token_program(*program_id)
+        .authorization_rules_program(rule_set.map(|_| AUTH_RULES_PROGRAM_ID))
+        .authorization_rules(rule_set)
+        .amount(1)
+        .instruction()
+}
+
+/// The mint's Metaplex metadata, if it has any
+fn metadata(client: &RpcClient, mint: &Pubkey) -> Result<Option<Metadata>> {
+    let (address, _) = Metadata::find_pda(mint);
+    let Some(account) = client
+        .get_account_with_commitment(&address, client.commitment())?
+        .value
+    else {
+        return Ok(None);
+    };
+    Metadata::safe_deserialize(&account.data)
+        .map(Some)
+        .map_err(|e| anyhow!("Bad Metaplex metadata for {}: {}", mint, e))
+}
+
+/// Tokens in `account`; 0 if it doesn't exist
+fn held(client: &RpcClient, account: &Pubkey) -> Result<u64> {
+    match client
+        .get_account_with_commitment(account, client.commitment())?
+        .value
+    {
+        Some(account) => Ok(StateWithExtensions::<Account>::unpack(&account.data)
+            .map_err(|e| anyhow!("Bad token account: {}", e))?
+            .base
+            .amount),
+        None => Ok(0),
+    }
+}
@@ -0,0 +1,137 @@
+//! Durable nonce accounts, so a transaction built now can still be submitted
+//! after however long the BOOT button press (or a multisig round) takes.
+//!
+//! Like stake accounts, nonce accounts are derived from the device key and a
+//! seed, with the device as nonce authority.
+
+use anyhow::{anyhow, Result};
+use solana_client::{nonce_utils, rpc_client::RpcClient};
+use solana_sdk::{hash::Hash, nonce::State, pubkey::Pubkey, system_instruction, system_program};
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::send;
+
+/// Creates a nonce account derived from the device key and `seed`, funded
+/// with `lamports` (default: its rent-exempt minimum) from the device's account
+pub fn create(
+    device: &mut Device,
+    settings: &Settings,
+    seed: &str,
+    lamports: Option<u64>,
+) -> Result<()> {
+    let client = client(settings);
+    let device_key = device.pubkey()?;
+    let nonce_account = Pubkey::create_with_seed(&device_key, seed, &system_program::id())
+        .map_err(|e| anyhow!("invalid nonce seed '{}': {}", seed, e))?;
+
+    let rent_exempt = client.get_minimum_balance_for_rent_exemption(State::size())?;
+    let lamports = lamports.unwrap_or(rent_exempt);
+    if lamports < rent_exempt {
+        return Err(anyhow!(
+            "a nonce account needs at least {} lamports",
+            rent_exempt
+        ));
+    }
+    if client
+        .get_account_with_commitment(&nonce_account, settings.commitment)?
+        .value
+        .is_some()
+    {
+        return Err(anyhow!(
+            "nonce account {} (seed '{}') already exists",
+            nonce_account,
+            seed
+        ));
+    }
+
+    println!(
+        "Creating nonce account {} (seed '{}') with {} lamports",
+        nonce_account, seed, lamports
+    );
+    let instructions = system_instruction::create_nonce_account_with_seed(
+        &device_key,
+        &nonce_account,
+        &device_key,
+        seed,
+        &device_key,
+        lamports,
+    );
+    let mut fee_payer = send::load_fee_payer(settings)?;
+    send::sign_and_submit(
+        &client,
+        settings,
+        device,
+        &device_key,
+        fee_payer.as_mut(),
+        None,
+        &instructions,
+    )?;
+    Ok(())
+}
+
+/// Prints the authority and current nonce value
+pub fn show(settings: &Settings, nonce_account: &Pubkey) -> Result<()> {
+    let account = nonce_utils::get_account_with_commitment(
+        &client(settings),
+        nonce_account,
+        settings.commitment,
+    )
+    .map_err(|e| anyhow!("Failed to fetch nonce account {}: {}", nonce_account, e))?;
+    let data = nonce_utils::data_from_account(&account).map_err(|e| {
+        anyhow!(
+            "{} is not an initialized nonce account: {}",
+            nonce_account,
+            e
+        )
+    })?;
+    println!("Nonce account: {}", nonce_account);
+    println!("Authority:     {}", data.authority);
+    println!("Nonce:         {}", data.blockhash());
+    println!(
+        "Fee:           {} lamports per signature",
+        data.get_lamports_per_signature()
+    );
+    Ok(())
+}
+
+/// Moves the nonce on, invalidating anything signed against the current value
+pub fn advance(device: &mut Device, settings: &Settings, nonce_account: &Pubkey) -> Result<()> {
+    let client = client(settings);
+    let device_key = device.pubkey()?;
+    println!("Advancing nonce account {}", nonce_account);
+    let mut fee_payer = send::load_fee_payer(settings)?;
+    send::sign_and_submit(
+        &client,
+        settings,
+        device,
+        &device_key,
+        fee_payer.as_mut(),
+        None,
+        &[system_instruction::advance_nonce_account(
+            nonce_account,
+            &device_key,
+        )],
+    )?;
+    Ok(())
+}
+
+/// The stored nonce to use as a transaction's blockhash, and the authority
+/// that has to sign the advance instruction
+pub fn durable_blockhash(client: &RpcClient, nonce_account: &Pubkey) -> Result<(Hash, Pubkey)> {
+    let account =
+        nonce_utils::get_account_with_commitment(client, nonce_account, client.commitment())
+            .map_err(|e| anyhow!("Failed to fetch nonce account {}: {}", nonce_account, e))?;
+    let data = nonce_utils::data_from_account(&account).map_err(|e| {
+        anyhow!(
+            "{} is not an initialized nonce account: {}",
+            nonce_account,
+            e
+        )
+    })?;
+    Ok((data.blockhash(), data.authority))
+}
+
+fn client(settings: &Settings) -> RpcClient {
+    settings.rpc_client()
+}
@@ -0,0 +1,98 @@
+use anyhow::Result;
+use base64::Engine;
+use signer_protocol::command::Command;
+use signer_protocol::response::Response;
+use solana_sdk::signature::{read_keypair_file, Signer};
+
+use crate::device::Device;
+
+// Domain tag the firmware expects in front of the signed OTA digest
+const OTA_DOMAIN: &[u8] = b"ESP32_SIGNER_OTA_V1";
+// Retries per OTA chunk before giving up on the upload
+const OTA_CHUNK_RETRIES: u32 = 3;
+
+/// Builds the exact byte string the firmware verifies an OTA image against
+fn ota_signed_payload(image: &[u8]) -> Vec<u8> {
+    let digest = solana_sdk::hash::hash(image);
+    let mut payload = Vec::with_capacity(OTA_DOMAIN.len() + 4 + 32);
+    payload.extend_from_slice(OTA_DOMAIN);
+    payload.extend_from_slice(&(image.len() as u32).to_le_bytes());
+    payload.extend_from_slice(digest.as_ref());
+    payload
+}
+
+/// Signs a firmware image with the vendor keypair and writes `<image>.sig`
+pub fn ota_sign(image_path: &str, keypair_path: &str) -> Result<()> {
+    let image = std::fs::read(image_path)?;
+    let keypair = read_keypair_file(keypair_path)
+        .map_err(|e| anyhow::anyhow!("Failed to read keypair {}: {}", keypair_path, e))?;
+    let signature = keypair.sign_message(&ota_signed_payload(&image));
+    let sig_path = format!("{}.sig", image_path);
+    std::fs::write(
+        &sig_path,
+        base64::engine::general_purpose::STANDARD.encode(signature.as_ref()),
+    )?;
+    println!(
+        "Vendor pubkey (ESP32_SIGNER_OTA_PUBKEY): {}",
+        keypair.pubkey()
+    );
+    println!("Wrote signature to {}", sig_path);
+    Ok(())
+}
+
+/// Streams a signed firmware image to the ESP32 and activates it
+pub fn ota_update(device: &mut Device, image_path: &str, sig_path: &str) -> Result<()> {
+    let image = std::fs::read(image_path)?;
+    let signature_b64 = std::fs::read_to_string(sig_path)?.trim().to_string();
+    println!("Uploading {} ({} bytes)", image_path, image.len());
+
+    let size = u32::try_from(image.len())
+        .map_err(|_| anyhow::anyhow!("{} is too large for OTA", image_path))?;
+    let chunk_max = device.request(Command::OtaBegin { size }, 10, |response| match response {
+        Response::OtaReady { chunk_max } => Some(chunk_max),
+        _ => None,
+    })?;
+
+    let mut offset = 0usize;
+    while offset < image.len() {
+        let end = (offset + chunk_max).min(image.len());
+        let chunk_b64 = base64::engine::general_purpose::STANDARD.encode(&image[offset..end]);
+        let args = format!("{}:{}", offset, chunk_b64);
+
+        let mut attempt = 0;
+        let next = loop {
+            attempt += 1;
+            let response = device.command(Command::OtaChunk(&args), 5)?;
+            match Response::parse(&response) {
+                Some(Response::OtaAck { next }) => break next as usize,
+                _ if attempt < OTA_CHUNK_RETRIES => {
+                    println!(
+                        "Chunk at {} not acknowledged ({}), retrying",
+                        offset, response
+                    );
+                }
+                _ => {
+                    let _ = device.command(Command::OtaAbort, 5);
+                    return Err(anyhow::anyhow!(
+                        "OTA chunk at {} failed: {}",
+                        offset,
+                        response
+                    ));
+                }
+            }
+        };
+        offset = next;
+        print!("\r{} / {} bytes", offset, image.len());
+        std::io::Write::flush(&mut std::io::stdout())?;
+    }
+    println!();
+
+    println!("Image sent. Press the BOOT button on the ESP32 to install it...");
+    device
+        .request(Command::OtaEnd(&signature_b64), 120, |response| {
+            matches!(response, Response::OtaOk).then_some(())
+        })
+        .map_err(|e| anyhow::anyhow!("OTA rejected by ESP32: {}", e))?;
+    println!("Firmware installed; the ESP32 is rebooting into the new image");
+    Ok(())
+}
@@ -0,0 +1,488 @@
+//! Two-factor authentication on firmware built with `twofa`: enrolling an
+//! authenticator app, opening the signing window with its codes, and an end
+//! to end check of the whole flow.
+//!
+//! `test` can stand in for the authenticator (`--headless`): it computes the
+//! codes itself from the secret the device just generated, which only works
+//! on a device it enrolls in the same run.
+//!
+//! Each enrollment is remembered in `otp.toml` next to the config file, by
+//! device ID: issuer, account, digits, period and date, never the secret.
+//! Later enrollments of the same device default to its issuer and account,
+//! and the other commands use the record to say which device and account
+//! they are talking about.
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, ValueEnum};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use qrcode::{
+    render::{svg, unicode::Dense1x2},
+    EcLevel, QrCode,
+};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::device::{Device, OtpSecret};
+use crate::message::{self, Content};
+use crate::registry;
+
+const DEFAULT_ISSUER: &str = "unruggable";
+const DEFAULT_ACCOUNT: &str = "user@unruggable.com";
+
+/// One device's enrollment as the authenticator app knows it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Enrollment {
+    device_id: String,
+    issuer: String,
+    account: String,
+    digits: u32,
+    period: u64,
+    /// UTC, `YYYY-MM-DD`
+    enrolled: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Enrollments {
+    #[serde(default, rename = "enrollment")]
+    enrollments: Vec<Enrollment>,
+}
+
+impl Enrollments {
+    fn load() -> Result<Self> {
+        let path = enrollments_path()?;
+        if !path.exists() {
+            return Ok(Enrollments::default());
+        }
+        let text =
+            std::fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parse {}", path.display()))
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = enrollments_path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text = toml::to_string(self)?;
+        std::fs::write(&path, text).with_context(|| format!("write {}", path.display()))
+    }
+
+    fn get(&self, device_id: &str) -> Option<&Enrollment> {
+        self.enrollments
+            .iter()
+            .find(|entry| entry.device_id == device_id)
+    }
+
+    /// Records `enrollment`, replacing the device's previous one
+    fn record(&mut self, enrollment: Enrollment) {
+        self.enrollments
+            .retain(|entry| entry.device_id != enrollment.device_id);
+        self.enrollments.push(enrollment);
+    }
+}
+
+fn enrollments_path() -> Result<PathBuf> {
+    config::otp_enrollments_path().ok_or_else(|| anyhow!("No config directory (set HOME)"))
+}
+
+/// The attached device's ID, and what it was enrolled with, if known
+fn lookup(device: &mut Device) -> Result<(String, Option<Enrollment>)> {
+    let (device_id, _) = device.device_id()?;
+    let enrollment = Enrollments::load()?.get(&device_id).cloned();
+    Ok((device_id, enrollment))
+}
+
+/// `device_id`, with its `devices` label and enrolled account when known
+fn describe(device_id: &str, enrollment: Option<&Enrollment>) -> String {
+    let mut name = match registry::label_of(device_id) {
+        Some(label) => format!("{} ({})", label, device_id),
+        None => device_id.to_string(),
+    };
+    if let Some(enrollment) = enrollment {
+        name.push_str(&format!(", {}:{}", enrollment.issuer, enrollment.account));
+    }
+    name
+}
+
+/// How the authenticator app lists the account
+#[derive(Args, Debug)]
+pub struct EnrollArgs {
+    /// Issuer shown by the authenticator app [default: the device's last
+    /// enrollment's, else "unruggable"]
+    #[arg(long)]
+    issuer: Option<String>,
+    /// Account name shown by the authenticator app [default: the device's
+    /// last enrollment's, else "user@unruggable.com"]
+    #[arg(long)]
+    account: Option<String>,
+    /// Also write the QR code of the otpauth URI to a file
+    #[arg(long, visible_alias = "qr", value_name = "FILE")]
+    qr_out: Option<PathBuf>,
+    /// Format of the --qr-out file (default: PNG for a `.png` name, SVG
+    /// otherwise)
+    #[arg(long, value_enum, requires = "qr_out")]
+    qr_format: Option<QrFormat>,
+    /// Error correction of the QR code; higher levels survive print
+    /// damage and logos at the cost of a denser code
+    #[arg(long, value_enum, default_value_t = QrEcLevel::M)]
+    qr_ec: QrEcLevel,
+    /// Smallest width and height of the --qr-out image, in pixels
+    #[arg(long, value_name = "PIXELS", default_value_t = 256)]
+    qr_size: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum QrFormat {
+    Png,
+    Svg,
+}
+
+/// QR error correction levels, recovering about 7, 15, 25 and 30% of the
+/// code
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "UPPER")]
+pub enum QrEcLevel {
+    L,
+    M,
+    Q,
+    H,
+}
+
+impl From<QrEcLevel> for EcLevel {
+    fn from(level: QrEcLevel) -> Self {
+        match level {
+            QrEcLevel::L => EcLevel::L,
+            QrEcLevel::M => EcLevel::M,
+            QrEcLevel::Q => EcLevel::Q,
+            QrEcLevel::H => EcLevel::H,
+        }
+    }
+}
+
+/// Generates a secret on the device and shows it as an otpauth URI and a
+/// QR code drawn in the terminal, so enrolling works over SSH too
+pub fn enroll(device: &mut Device, args: &EnrollArgs) -> Result<OtpSecret> {
+    let (device_id, previous) = lookup(device)?;
+    let issuer = args
+        .issuer
+        .clone()
+        .or_else(|| previous.as_ref().map(|p| p.issuer.clone()))
+        .unwrap_or_else(|| DEFAULT_ISSUER.to_string());
+    let account = args
+        .account
+        .clone()
+        .or_else(|| previous.as_ref().map(|p| p.account.clone()))
+        .unwrap_or_else(|| DEFAULT_ACCOUNT.to_string());
+
+    let otp = device.otp_begin()?;
+    let mut enrollments = Enrollments::load()?;
+    enrollments.record(Enrollment {
+        device_id: device_id.clone(),
+        issuer,
+        account,
+        digits: otp.digits,
+        period: otp.period,
+        enrolled: utc_date(now_unix()),
+    });
+    enrollments.save()?;
+    let enrollment = enrollments.get(&device_id).expect("just recorded");
+
+    println!("Enrolling {}", describe(&device_id, Some(enrollment)));
+    let uri = otpauth_uri(&otp, enrollment);
+    let code = QrCode::with_error_correction_level(uri.as_bytes(), args.qr_ec.into())
+        .map_err(|e| anyhow!("Failed to make a QR code: {}", e))?;
+    println!("{}", render(&code));
+    println!("otpauth URI:\n{}", uri);
+    if let Some(path) = &args.qr_out {
+        let format = args.qr_format.unwrap_or_else(|| format_of(path));
+        save_qr(&code, path, format, args.qr_size)?;
+        println!("Saved QR to {}", path.display());
+    }
+    println!(
+        "Scan it (or add secret {}, SHA1, {} digits, {}s period) in your authenticator app",
+        otp.secret, otp.digits, otp.period
+    );
+    Ok(otp)
+}
+
+/// Finishes enrollment with a code from the authenticator app
+pub fn confirm(device: &mut Device, code: &str) -> Result<()> {
+    let (device_id, enrollment) = lookup(device)?;
+    device.otp_confirm(code)?;
+    println!(
+        "OTP enrollment confirmed for {}",
+        describe(&device_id, enrollment.as_ref())
+    );
+    Ok(())
+}
+
+/// Opens the signing window with a current code
+pub fn unlock(device: &mut Device, code: &str) -> Result<()> {
+    let (device_id, enrollment) = lookup(device)?;
+    let until = device.otp_unlock(code)?;
+    println!(
+        "{} unlocked until {}",
+        describe(&device_id, enrollment.as_ref()),
+        until
+    );
+    Ok(())
+}
+
+/// Prints whether 2FA is enrolled and whether signing is unlocked
+pub fn status(device: &mut Device) -> Result<()> {
+    let (device_id, enrollment) = lookup(device)?;
+    println!("Device: {}", describe(&device_id, None));
+    let status = device.otp_status()?;
+    if !status.enrolled {
+        println!("2FA: not enrolled (`otp enroll` to set it up)");
+        return Ok(());
+    }
+    match &enrollment {
+        Some(e) => println!(
+            "2FA: enrolled as {}:{} on {} ({} digits, {}s period)",
+            e.issuer, e.account, e.enrolled, e.digits, e.period
+        ),
+        None => println!("2FA: enrolled (not from this host)"),
+    }
+    if status.unlocked_until > status.now {
+        println!(
+            "Signing: unlocked for {}s more",
+            status.unlocked_until - status.now
+        );
+    } else {
+        println!("Signing: locked (`otp unlock <CODE>` to open the window)");
+    }
+    Ok(())
+}
+
+/// What `test` signs, and how it gets its codes
+#[derive(Args, Debug)]
+pub struct TestArgs {
+    /// Compute the codes here instead of asking for them; needs a device
+    /// that isn't enrolled yet
+    #[arg(long)]
+    headless: bool,
+    /// Message to sign once unlocked
+    #[arg(long, default_value = "hello from the 2FA test")]
+    message: String,
+    #[command(flatten)]
+    enroll: EnrollArgs,
+}
+
+/// Enrolls (unless the device already is), confirms, unlocks, then signs
+/// a test message and checks the signature
+pub fn test(device: &mut Device, args: &TestArgs) -> Result<()> {
+    let enrolled = device.otp_status()?.enrolled;
+    if enrolled && args.headless {
+        return Err(anyhow!(
+            "The ESP32 is already enrolled, so its secret can't be known here; \
+             run without --headless and use the authenticator app"
+        ));
+    }
+
+    let secret = if enrolled {
+        println!("Already enrolled; skipping enrollment");
+        None
+    } else {
+        let otp = enroll(device, &args.enroll)?;
+        let secret = BASE32_NOPAD
+            .decode(otp.secret.as_bytes())
+            .map_err(|e| anyhow!("Failed to decode the OTP secret: {}", e))?;
+        let code = code(
+            args.headless,
+            &secret,
+            otp.period,
+            "Code from your authenticator",
+        )?;
+        device.otp_confirm(&code)?;
+        println!("Enrollment confirmed");
+        // The code just used can't unlock: wait for the next one
+        wait_for_next_step(otp.period);
+        Some((secret, otp.period))
+    };
+
+    let code = match &secret {
+        Some((secret, period)) => code(args.headless, secret, *period, "A fresh code to unlock")?,
+        None => {
+            let (device_id, enrollment) = lookup(device)?;
+            prompt(&format!(
+                "Code from your authenticator for {} to unlock",
+                describe(&device_id, enrollment.as_ref())
+            ))?
+        }
+    };
+    let until = device.otp_unlock(&code)?;
+    println!("Unlocked until {}", until);
+
+    message::sign(device, Content::Text(&args.message), None)?;
+    println!("All 2FA checks passed");
+    Ok(())
+}
+
+fn otpauth_uri(otp: &OtpSecret, enrollment: &Enrollment) -> String {
+    let label = format!("{}:{}", enrollment.issuer, enrollment.account);
+    format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        urlencoding(&label),
+        otp.secret,
+        urlencoding(&enrollment.issuer),
+        otp.digits,
+        otp.period
+    )
+}
+
+/// Percent-encodes everything but unreserved URI characters
+fn urlencoding(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// `code` in half-height blocks, light on dark so it scans on the usual
+/// dark terminal background
+fn render(code: &QrCode) -> String {
+    code.render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .quiet_zone(true)
+        .build()
+}
+
+/// PNG for a `.png` name, SVG otherwise
+fn format_of(path: &Path) -> QrFormat {
+    match path.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("png") => QrFormat::Png,
+        _ => QrFormat::Svg,
+    }
+}
+
+/// Writes `code` at least `size` pixels across, quiet zone included
+fn save_qr(code: &QrCode, path: &Path, format: QrFormat, size: u32) -> Result<()> {
+    let written = match format {
+        QrFormat::Png => code
+            .render::<image::Luma<u8>>()
+            .min_dimensions(size, size)
+            .build()
+            .save(path)
+            .map_err(|e| e.to_string()),
+        QrFormat::Svg => {
+            let svg = code
+                .render::<svg::Color>()
+                .min_dimensions(size, size)
+                .build();
+            std::fs::write(path, svg).map_err(|e| e.to_string())
+        }
+    };
+    written.map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))
+}
+
+/// The current code: computed from `secret` when headless, else asked for
+fn code(headless: bool, secret: &[u8], period: u64, question: &str) -> Result<String> {
+    if !headless {
+        return prompt(question);
+    }
+    let code = totp(secret, now_unix(), period);
+    println!("(headless) code {}", code);
+    Ok(code)
+}
+
+fn prompt(question: &str) -> Result<String> {
+    print!("{}: ", question);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+pub fn wait_for_next_step(period: u64) {
+    let wait = period - now_unix() % period + 1;
+    println!("Waiting {}s for the next code...", wait);
+    std::thread::sleep(Duration::from_secs(wait));
+}
+
+/// RFC 6238 with SHA1 and six digits, as the firmware checks it
+pub fn totp(secret: &[u8], unix: u64, period: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes any key length");
+    mac.update(&(unix / period).to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = usize::from(digest[19] & 0x0f);
+    let value = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!("{:06}", value % 1_000_000)
+}
+
+pub fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `unix` as a UTC calendar date (Howard Hinnant's `civil_from_days`)
+fn utc_date(unix: u64) -> String {
+    let days = (unix / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use signer_core::totp::OTP_PERIOD;
+    use signer_core::totp_vectors::{self, HOTP, TOTP, WINDOW};
+
+    #[test]
+    fn matches_the_shared_vectors() {
+        for (counter, want) in HOTP.iter().enumerate() {
+            let unix = counter as u64 * OTP_PERIOD;
+            assert_eq!(
+                totp(totp_vectors::SECRET, unix, OTP_PERIOD),
+                format!("{:06}", want)
+            );
+        }
+        for vector in TOTP {
+            assert_eq!(
+                totp(totp_vectors::SECRET, vector.unix, OTP_PERIOD),
+                vector.code,
+                "at {}",
+                vector.unix
+            );
+        }
+    }
+
+    #[test]
+    fn accepted_window_codes_are_what_this_host_generates() {
+        for vector in WINDOW {
+            if let Some(step) = vector.accepted {
+                assert_eq!(
+                    totp(totp_vectors::SECRET, step * OTP_PERIOD, OTP_PERIOD),
+                    vector.code,
+                    "{}",
+                    vector.name
+                );
+            }
+        }
+    }
+}
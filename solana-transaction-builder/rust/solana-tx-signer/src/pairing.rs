@@ -0,0 +1,180 @@
+//! Pairing this host with a signer, so neither talks to a stand-in for the
+//! other. `pair` swaps session keys with the device: the device blinks a code,
+//! this tool prints the same code worked out from both keys, and the operator
+//! presses BOOT only if they match. The device keeps this host's key; the
+//! host keeps `host-key.json` and, in `paired.toml`, each device's session key
+//! by its signing pubkey.
+//!
+//! Every later connection to a paired device starts with a challenge in both
+//! directions. A device that can't answer with the remembered session key is
+//! refused; the device in turn refuses to sign, change its policy, enroll 2FA
+//! or take an update for a connection that hasn't answered its challenge.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use signer_core::pairing::{self as transcript, NONCE_LEN};
+use solana_sdk::{
+    pubkey::Pubkey,
+    signature::{read_keypair_file, write_keypair_file, Keypair, Signer},
+};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::config;
+use crate::device::Device;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// The device's signing pubkey
+    pubkey: String,
+    session_key: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Paired {
+    #[serde(default, rename = "device")]
+    devices: Vec<Entry>,
+}
+
+impl Paired {
+    fn load() -> Result<Self> {
+        let path = paired_path()?;
+        if !path.exists() {
+            return Ok(Paired::default());
+        }
+        let text =
+            std::fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parse {}", path.display()))
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = paired_path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text = toml::to_string(self)?;
+        std::fs::write(&path, text).with_context(|| format!("write {}", path.display()))
+    }
+
+    fn session_key(&self, device: &Pubkey) -> Result<Option<Pubkey>> {
+        let address = device.to_string();
+        self.devices
+            .iter()
+            .find(|entry| entry.pubkey == address)
+            .map(|entry| {
+                Pubkey::from_str(&entry.session_key)
+                    .map_err(|e| anyhow!("Bad session key for {} in paired.toml: {}", device, e))
+            })
+            .transpose()
+    }
+}
+
+fn paired_path() -> Result<PathBuf> {
+    config::paired_path().ok_or_else(|| anyhow!("No config directory (set HOME)"))
+}
+
+/// This host's session keypair, made on first use
+fn host_key() -> Result<Keypair> {
+    let path = config::host_key_path().ok_or_else(|| anyhow!("No config directory (set HOME)"))?;
+    if path.exists() {
+        return read_keypair_file(&path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e));
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let keypair = Keypair::new();
+    write_keypair_file(&keypair, &path)
+        .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))?;
+    Ok(keypair)
+}
+
+/// The code both sides show, digits separated as the device blinks them
+fn format_code(host_key: &Pubkey, session_key: &Pubkey) -> String {
+    transcript::pairing_code(&host_key.to_bytes(), &session_key.to_bytes())
+        .iter()
+        .map(u8::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Pairs the attached device with this host, replacing whatever host it was
+/// paired with
+pub fn pair(device: &mut Device) -> Result<()> {
+    let pubkey = device.pubkey()?;
+    let host = host_key()?;
+    let session_key = device.pair_begin(&host.pubkey())?;
+    println!(
+        "Pairing code: {}",
+        format_code(&host.pubkey(), &session_key)
+    );
+    println!(
+        "The ESP32 blinks each digit as that many flashes, with a pause between digits and a \
+         longer one before it repeats."
+    );
+    println!("If it blinks this code, press the BOOT button; if not, press Ctrl-C.");
+    device.pair_confirm()?;
+
+    let mut paired = Paired::load()?;
+    let address = pubkey.to_string();
+    paired.devices.retain(|entry| entry.pubkey != address);
+    paired.devices.push(Entry {
+        pubkey: address,
+        session_key: session_key.to_string(),
+    });
+    paired.save()?;
+    device.set_paired();
+    println!("Paired with {}", pubkey);
+    Ok(())
+}
+
+/// Has the attached device forget its host (after a BOOT press), and this
+/// host forget the device
+pub fn unpair(device: &mut Device) -> Result<()> {
+    let pubkey = device.pubkey()?;
+    device.unpair()?;
+    let mut paired = Paired::load()?;
+    let address = pubkey.to_string();
+    paired.devices.retain(|entry| entry.pubkey != address);
+    paired.save()?;
+    println!("Unpaired {}", pubkey);
+    Ok(())
+}
+
+/// Proves each side to the other when this host paired with `device`.
+/// Devices it never paired with are left unauthenticated, which is enough
+/// for everything but signing; a device that fails the challenge is refused.
+pub fn authenticate(device: &mut Device) -> Result<()> {
+    let pubkey = device.pubkey()?;
+    let Some(session_key) = Paired::load()?.session_key(&pubkey)? else {
+        return Ok(());
+    };
+    let host = host_key()?;
+    let host_nonce: [u8; NONCE_LEN] = rand::random();
+    let (device_nonce, proof) = device.auth_begin(&host_nonce).map_err(|e| {
+        anyhow!(
+            "The ESP32 {} didn't take the pairing challenge: {}",
+            pubkey,
+            e
+        )
+    })?;
+    let device_nonce: [u8; NONCE_LEN] = device_nonce
+        .try_into()
+        .map_err(|_| anyhow!("The ESP32 {} sent a malformed challenge", pubkey))?;
+
+    let message =
+        transcript::device_proof_message(&host.pubkey().to_bytes(), &host_nonce, &device_nonce);
+    if !proof.verify(session_key.as_ref(), &message) {
+        return Err(anyhow!(
+            "The ESP32 {} isn't the device this host paired with: it doesn't hold the paired \
+             session key. If it was reset or re-paired on purpose, run `pair` again",
+            pubkey
+        ));
+    }
+    let message =
+        transcript::host_proof_message(&session_key.to_bytes(), &device_nonce, &host_nonce);
+    device.auth_proof(&host.sign_message(&message))?;
+    device.set_paired();
+    tracing::debug!("Authenticated to paired ESP32 {}", pubkey);
+    Ok(())
+}
@@ -0,0 +1,291 @@
+//! Transaction files: a transaction saved unsigned or partly signed, so that
+//! building, signing (possibly on several devices, or air-gapped) and sending
+//! can happen at different times and places.
+//!
+//! The file is JSON holding the base64 bincode transaction, plus the signer
+//! list and blockhash for people reading it. Only `transaction` is read back.
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    instruction::Instruction,
+    pubkey::Pubkey,
+    signature::{read_keypair_file, Keypair, Signature, Signer},
+    transaction::VersionedTransaction,
+};
+use std::path::Path;
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::rent::{self, RentMode};
+use crate::{confirm, dry_run, fiat, labels, lookup_table, network, send, token};
+
+#[derive(Serialize, Deserialize)]
+struct PayloadFile {
+    /// base64 of the bincode-serialized transaction
+    transaction: String,
+    #[serde(default, skip_deserializing)]
+    blockhash: String,
+    #[serde(default, skip_deserializing)]
+    signers: Vec<SignerEntry>,
+}
+
+#[derive(Serialize)]
+struct SignerEntry {
+    pubkey: String,
+    signed: bool,
+}
+
+/// How a payload transaction is put together
+pub struct BuildOptions {
+    /// Default: the configured fee payer, else the first signer
+    pub payer: Option<Pubkey>,
+    /// Durable nonce to use instead of a recent blockhash
+    pub nonce_account: Option<Pubkey>,
+}
+
+/// A transfer of `lamports` from `from` to each of `to`, checked against
+/// rent as `rent_mode` says
+pub fn build_transfer(
+    settings: &Settings,
+    from: &Pubkey,
+    to: &[Pubkey],
+    lamports: u64,
+    rent_mode: RentMode,
+    options: &BuildOptions,
+) -> Result<VersionedTransaction> {
+    let (payer, fee_payer) = resolve_payer(settings, options, from)?;
+    let lamports = rent::check(
+        &client(settings),
+        settings,
+        from,
+        to,
+        lamports,
+        payer == *from,
+        rent_mode,
+    )?;
+    let instructions = send::transfer_instructions(from, to, lamports);
+    build(settings, options, &payer, fee_payer.as_ref(), &instructions)
+}
+
+/// A token transfer out of `owner`'s associated token account
+pub fn build_token_transfer(
+    settings: &Settings,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    to: &Pubkey,
+    amount: &str,
+    memo: Option<&str>,
+    options: &BuildOptions,
+) -> Result<VersionedTransaction> {
+    let (payer, fee_payer) = resolve_payer(settings, options, owner)?;
+    let instructions =
+        token::transfer_instructions(settings, owner, mint, to, amount, memo, &payer)?;
+    build(settings, options, &payer, fee_payer.as_ref(), &instructions)
+}
+
+/// Adds the attached ESP32's signature (after a BOOT button press) to the
+/// transaction in `input`, writing the result to `output`
+pub fn sign_with_device(
+    device: &mut Device,
+    settings: &Settings,
+    input: &Path,
+    output: &Path,
+) -> Result<()> {
+    let mut transaction = load(input)?;
+    let pubkey = device.pubkey()?;
+    let slot = send::signer_slot(&transaction, &pubkey)?;
+    network::check_message(settings, &transaction.message, &pubkey)?;
+    let message_bytes = transaction.message.serialize();
+
+    println!("Signing as {}", pubkey);
+    let prices = fiat::Prices::for_message(settings, &transaction.message);
+    let summary = confirm::describe_transaction(&transaction.message, &prices);
+    let labels = labels::for_message(settings, &transaction.message);
+    let signature = confirm::sign_labeled(device, &message_bytes, &summary, &labels)?;
+    if !signature.verify(pubkey.as_ref(), &message_bytes) {
+        return Err(anyhow!("ESP32 returned a signature that does not verify"));
+    }
+    transaction.signatures[slot] = signature;
+    save(&transaction, output)?;
+    status(output)
+}
+
+/// Adds a local keypair's signature to the transaction in `input`, writing
+/// the result to `output`
+pub fn sign_with_keypair(keypair: &Path, input: &Path, output: &Path) -> Result<()> {
+    let keypair = read_keypair_file(keypair)
+        .map_err(|e| anyhow!("Failed to read keypair {}: {}", keypair.display(), e))?;
+    let mut transaction = load(input)?;
+    let slot = send::signer_slot(&transaction, &keypair.pubkey())?;
+    transaction.signatures[slot] = keypair.sign_message(&transaction.message.serialize());
+    println!("Signed as {}", keypair.pubkey());
+    save(&transaction, output)?;
+    status(output)
+}
+
+/// Lists the required signers and which of them have signed
+pub fn status(file: &Path) -> Result<()> {
+    let transaction = load(file)?;
+    let (signed, required) = signature_count(&transaction);
+    println!("{}: {} of {} signatures", file.display(), signed, required);
+    let message_bytes = transaction.message.serialize();
+    for (signature, key) in transaction
+        .signatures
+        .iter()
+        .zip(transaction.message.static_account_keys())
+    {
+        let state = if *signature == Signature::default() {
+            "missing"
+        } else if signature.verify(key.as_ref(), &message_bytes) {
+            "signed"
+        } else {
+            "INVALID"
+        };
+        println!("  {} {}", key, state);
+    }
+    Ok(())
+}
+
+/// Submits the transaction once every required signature is present and valid
+pub fn submit(settings: &Settings, file: &Path) -> Result<()> {
+    let transaction = load(file)?;
+    let (signed, required) = signature_count(&transaction);
+    if signed < required {
+        status(file)?;
+        return Err(anyhow!(
+            "only {} of {} required signatures collected",
+            signed,
+            required
+        ));
+    }
+    if settings.dry_run {
+        dry_run::report(settings, &transaction, None);
+        return Ok(());
+    }
+    match send::submit(&client(settings), settings, &transaction)? {
+        send::Outcome::Confirmed(_) => Ok(()),
+        send::Outcome::Expired(signature) => Err(anyhow!(
+            "{} expired before it was confirmed; build and sign it again",
+            signature
+        )),
+    }
+}
+
+/// The requested payer, else the configured fee payer, else `default`. A fee
+/// payer keypair comes back too when it is the one paying, so it can sign at
+/// once; a fee payer device signs the file later, like any other signer.
+pub fn resolve_payer(
+    settings: &Settings,
+    options: &BuildOptions,
+    default: &Pubkey,
+) -> Result<(Pubkey, Option<Keypair>)> {
+    let payer = match options.payer {
+        Some(payer) => payer,
+        None => send::fee_payer_pubkey(settings)?.unwrap_or(*default),
+    };
+    let keypair = send::fee_payer_keypair(settings)?;
+    Ok((payer, keypair.filter(|k| k.pubkey() == payer)))
+}
+
+/// The new transaction, signed only by `fee_payer` if given
+pub fn build(
+    settings: &Settings,
+    options: &BuildOptions,
+    payer: &Pubkey,
+    fee_payer: Option<&Keypair>,
+    instructions: &[Instruction],
+) -> Result<VersionedTransaction> {
+    let client = client(settings);
+    let message = send::new_message(
+        &client,
+        settings,
+        instructions,
+        payer,
+        options.nonce_account.as_ref(),
+    )?;
+    let signatures = vec![Signature::default(); message.header.num_required_signatures as usize];
+    let mut transaction = VersionedTransaction {
+        signatures,
+        message: lookup_table::fit(&client, settings, message)?,
+    };
+
+    if let Some(fee_payer) = fee_payer {
+        // The payer always takes the first signature slot
+        transaction.signatures[0] = fee_payer.sign_message(&transaction.message.serialize());
+    }
+    if options.nonce_account.is_none() {
+        println!(
+            "Without --nonce-account the signatures have to be collected within about a minute"
+        );
+    }
+    Ok(transaction)
+}
+
+/// Writes a freshly built transaction to `out`; a dry run prints it instead
+pub fn create(settings: &Settings, transaction: &VersionedTransaction, out: &Path) -> Result<()> {
+    if settings.dry_run {
+        dry_run::report(settings, transaction, None);
+        println!("Would write {}", out.display());
+        return Ok(());
+    }
+    save(transaction, out)?;
+    println!("Wrote {}", out.display());
+    status(out)
+}
+
+/// (valid signatures, required signatures)
+fn signature_count(transaction: &VersionedTransaction) -> (usize, usize) {
+    let message_bytes = transaction.message.serialize();
+    let signed = transaction
+        .signatures
+        .iter()
+        .zip(transaction.message.static_account_keys())
+        .filter(|(signature, key)| signature.verify(key.as_ref(), &message_bytes))
+        .count();
+    (signed, transaction.signatures.len())
+}
+
+pub fn load(path: &Path) -> Result<VersionedTransaction> {
+    let text =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("read {}: {}", path.display(), e))?;
+    let file: PayloadFile = serde_json::from_str(&text)
+        .map_err(|e| anyhow!("{} is not a transaction file: {}", path.display(), e))?;
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(&file.transaction)
+        .map_err(|e| anyhow!("{} is not a transaction file: {}", path.display(), e))?;
+    let transaction: VersionedTransaction = bincode::deserialize(&bytes)
+        .map_err(|e| anyhow!("{} is not a transaction file: {}", path.display(), e))?;
+    if transaction.signatures.len()
+        != usize::from(transaction.message.header().num_required_signatures)
+    {
+        return Err(anyhow!("{} has a malformed signature list", path.display()));
+    }
+    Ok(transaction)
+}
+
+pub fn save(transaction: &VersionedTransaction, path: &Path) -> Result<()> {
+    let message_bytes = transaction.message.serialize();
+    let file = PayloadFile {
+        transaction: base64::engine::general_purpose::STANDARD
+            .encode(bincode::serialize(transaction)?),
+        blockhash: transaction.message.recent_blockhash().to_string(),
+        signers: transaction
+            .signatures
+            .iter()
+            .zip(transaction.message.static_account_keys())
+            .map(|(signature, key)| SignerEntry {
+                pubkey: key.to_string(),
+                signed: signature.verify(key.as_ref(), &message_bytes),
+            })
+            .collect(),
+    };
+    std::fs::write(path, serde_json::to_string_pretty(&file)? + "\n")
+        .map_err(|e| anyhow!("write {}: {}", path.display(), e))
+}
+
+pub fn client(settings: &Settings) -> RpcClient {
+    settings.rpc_client()
+}
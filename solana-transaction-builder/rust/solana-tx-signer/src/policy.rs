@@ -0,0 +1,196 @@
+//! The device's signing policy, mirrored on the host. Before a message goes
+//! to the ESP32, it is checked against the policy the device reports, with
+//! the device's own rules (signer-core's `Policy::evaluate`), so a transfer
+//! over the limit or to an address off the allowlist fails here with the
+//! reason spelled out, instead of as a bare refusal after the round trip.
+
+use anyhow::{anyhow, Result};
+use signer_core::policy::{ChainProfile, Policy, PolicyViolation};
+use signer_core::tx_introspection;
+use signer_protocol::error::ErrorCode;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::amount::format_sol;
+use crate::contacts;
+use crate::device::{Device, DeviceError};
+
+/// The device's policy; `None` for firmware from before GET_POLICY, which
+/// gets no preflight check
+pub fn fetch(device: &mut Device) -> Result<Option<Policy>> {
+    supported(device.policy())
+}
+
+/// A GET_POLICY reply, with firmware that doesn't know the command as `None`
+pub fn supported(reply: Result<Policy>) -> Result<Option<Policy>> {
+    match reply {
+        Ok(policy) => Ok(Some(policy)),
+        Err(e) => match e.downcast_ref::<DeviceError>() {
+            Some(DeviceError {
+                code: ErrorCode::UnknownCommand,
+                ..
+            }) => Ok(None),
+            _ => Err(e),
+        },
+    }
+}
+
+/// Fails, saying why, if the device's policy would refuse to sign `message`
+pub fn preflight(device: &mut Device, message: &[u8]) -> Result<()> {
+    if device.is_offline() {
+        return Ok(());
+    }
+    let Some(policy) = fetch(device).map_err(|e| anyhow!("Nothing was signed: {}", e))? else {
+        return Ok(());
+    };
+    check(&policy, &device.pubkey()?, message)
+}
+
+/// Fails, saying why, if `policy` refuses `message` for `signer`
+pub fn check(policy: &Policy, signer: &Pubkey, message: &[u8]) -> Result<()> {
+    if policy.chain != ChainProfile::Solana {
+        return Err(anyhow!(
+            "Nothing was signed: the ESP32's key is kept for another protocol \
+             (chain profile `{}`), not for Solana",
+            policy.chain
+        ));
+    }
+    let info = tx_introspection::introspect_transaction(message, &signer.to_bytes()).ok();
+    policy.evaluate(info.as_ref()).map_err(|violation| {
+        anyhow!(
+            "Nothing was signed: the ESP32 would refuse this, because {}. \
+             Its policy is `{}` (see `policy show`)",
+            explain(&violation),
+            describe_text(policy)
+        )
+    })
+}
+
+/// `violation` as a reason a person can act on
+fn explain(violation: &PolicyViolation) -> String {
+    match violation {
+        PolicyViolation::BlindSigningDisabled => "it contains instructions the ESP32 can't \
+             decode, and its policy forbids blind signing"
+            .to_string(),
+        PolicyViolation::AmountExceedsLimit { amount, limit } => format!(
+            "it moves {} SOL, over the limit of {} SOL per transaction",
+            format_sol(*amount),
+            format_sol(*limit)
+        ),
+        PolicyViolation::RecipientNotAllowed(to) => format!(
+            "it sends to {}, which isn't on the recipient allowlist",
+            contacts::name(&Pubkey::new_from_array(*to))
+        ),
+        PolicyViolation::ChainMismatch => {
+            "it is a Solana message, and the ESP32's key is kept for another protocol".to_string()
+        }
+        PolicyViolation::ReservedDomain => {
+            "it is shaped like a presence token, which only a BOOT press makes".to_string()
+        }
+    }
+}
+
+/// The policy as `SET_POLICY` takes it, with base58 addresses
+fn describe_text(policy: &Policy) -> String {
+    let max = policy
+        .max_lamports_per_tx
+        .map_or_else(|| "none".to_string(), |limit| limit.to_string());
+    let blind = if policy.allow_blind_signing {
+        "on"
+    } else {
+        "off"
+    };
+    let allow = if policy.allowed_recipients.is_empty() {
+        "any".to_string()
+    } else {
+        policy
+            .allowed_recipients
+            .iter()
+            .map(|key| Pubkey::new_from_array(*key).to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let delay = match policy.change_delay_hours {
+        0 => String::new(),
+        hours => format!(";delay={}", hours),
+    };
+    let production = if policy.production {
+        ";production=on"
+    } else {
+        ""
+    };
+    let chain = match &policy.chain {
+        ChainProfile::Solana => String::new(),
+        chain => format!(";chain={}", chain),
+    };
+    format!(
+        "max={};blind={};allow={}{}{}{}",
+        max, blind, allow, delay, production, chain
+    )
+}
+
+/// Prints the device's policy, one rule per line
+pub fn show(device: &mut Device) -> Result<()> {
+    let policy = fetch(device)?
+        .ok_or_else(|| anyhow!("This firmware can't report its policy; update it with `ota`"))?;
+    match policy.max_lamports_per_tx {
+        Some(limit) => println!("Limit: {} SOL per transaction", format_sol(limit)),
+        None => println!("Limit: none"),
+    }
+    println!(
+        "Blind signing: {}",
+        if policy.allow_blind_signing {
+            "allowed"
+        } else {
+            "refused"
+        }
+    );
+    if policy.allowed_recipients.is_empty() {
+        println!("Recipients: any");
+    } else {
+        println!("Recipients:");
+        for key in &policy.allowed_recipients {
+            println!("  {}", contacts::name(&Pubkey::new_from_array(*key)));
+        }
+    }
+    match &policy.chain {
+        ChainProfile::Solana => println!("Chain: Solana"),
+        ChainProfile::Raw => println!("Chain: raw (another protocol's messages, never Solana's)"),
+        ChainProfile::Prefixed(prefix) => println!(
+            "Chain: prefixed (another protocol's messages, signed after {} bytes of {})",
+            prefix.len(),
+            signer_core::encoding::hex_encode(prefix)
+        ),
+    }
+    match policy.change_delay_hours {
+        0 => println!("Loosening changes: immediate"),
+        hours => println!(
+            "Loosening changes: after {} h of powered-on time, unless cancelled with BOOT",
+            hours
+        ),
+    }
+    if policy.production {
+        println!("Production: the key is only used with flash encryption and secure boot on");
+    }
+    println!("As text: {}", describe_text(&policy));
+
+    // Firmware from before delayed changes doesn't know the command
+    let pending = match device.pending_policy() {
+        Ok(pending) => pending,
+        Err(e) => match e.downcast_ref::<DeviceError>() {
+            Some(DeviceError {
+                code: ErrorCode::UnknownCommand,
+                ..
+            }) => None,
+            _ => return Err(e),
+        },
+    };
+    if let Some((remaining, pending)) = pending {
+        println!(
+            "Pending: {} in {}h{:02}m (press BOOT while idle to cancel)",
+            describe_text(&pending),
+            remaining / 3600,
+            remaining % 3600 / 60
+        );
+    }
+    Ok(())
+}
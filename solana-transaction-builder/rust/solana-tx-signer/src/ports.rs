@@ -0,0 +1,65 @@
+//! `list-ports`: the serial ports auto-detection would look at, what they
+//! are, and whether the signer firmware answers on them, for working out
+//! which port to pass to --port (or why none is found).
+
+use anyhow::Result;
+use serialport::{SerialPortInfo, SerialPortType};
+
+use crate::device::{self, Device};
+
+/// Prints the candidate ports, likeliest first, probing each; with `all`,
+/// also the ports detection skips (without probing them)
+pub fn list(baud: u32, all: bool) -> Result<()> {
+    let mut ports = device::serial_ports()?;
+    if !all {
+        ports.retain(|port| device::likelihood(port) > 0);
+    }
+    ports.sort_by_key(|port| std::cmp::Reverse(device::likelihood(port)));
+    if ports.is_empty() {
+        println!("No serial ports found; is the ESP32 plugged in (with a data cable)?");
+        return Ok(());
+    }
+
+    println!(
+        "{:<24} {:<10} {:<36} PROBE",
+        "PORT", "USB ID", "DESCRIPTION"
+    );
+    for port in &ports {
+        let probe = if device::likelihood(port) == 0 {
+            "not probed".to_string()
+        } else {
+            match Device::try_probe(&port.port_name, baud) {
+                Ok(Some((_, fw))) => format!("signer ({})", fw),
+                Ok(None) => "no signer answered".to_string(),
+                Err(e) => format!("{:#}", e),
+            }
+        };
+        let (usb_id, description) = describe(port);
+        println!(
+            "{:<24} {:<10} {:<36} {}",
+            port.port_name, usb_id, description, probe
+        );
+    }
+    Ok(())
+}
+
+/// VID:PID and a human-readable name
+fn describe(port: &SerialPortInfo) -> (String, String) {
+    match &port.port_type {
+        SerialPortType::UsbPort(usb) => {
+            let name = [usb.manufacturer.as_deref(), usb.product.as_deref()]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(" ");
+            let name = match &usb.serial_number {
+                Some(serial) => format!("{} (serial {})", name, serial),
+                None => name,
+            };
+            (format!("{:04x}:{:04x}", usb.vid, usb.pid), name)
+        }
+        SerialPortType::PciPort => ("-".to_string(), "PCI serial port".to_string()),
+        SerialPortType::BluetoothPort => ("-".to_string(), "Bluetooth serial port".to_string()),
+        SerialPortType::Unknown => ("-".to_string(), "-".to_string()),
+    }
+}
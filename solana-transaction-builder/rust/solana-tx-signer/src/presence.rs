@@ -0,0 +1,260 @@
+//! Presence tokens from firmware built with `presence`: a short BOOT press
+//! while the device is idle sends a token signed by its key, with a counter
+//! that goes up with every press, the device ID and the device clock.
+//!
+//! `presence wait` is the step a release pipeline or access workflow runs to
+//! need a human at the device: it waits for a press, checks the token and
+//! prints it. The highest counter accepted from each device is kept in
+//! `presence.toml` next to the config file, so an old token is never taken
+//! twice. `presence verify` checks a saved token elsewhere, against the
+//! device's public key.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use signer_core::encoding::hex_decode;
+use signer_core::identity::{self, DEVICE_ID_LEN};
+use signer_core::presence::Token;
+use signer_protocol::response::{fields, Response};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::device::Device;
+
+#[derive(Args, Debug)]
+pub struct WaitArgs {
+    /// Give up after this many seconds without a press
+    #[arg(long, default_value_t = 120)]
+    timeout: u32,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// The token, as `presence wait` printed it
+    token: String,
+    /// The device's public key
+    #[arg(long)]
+    pubkey: Pubkey,
+    /// Only accept counters above this one, e.g. the last token accepted
+    #[arg(long, default_value_t = 0)]
+    after: u32,
+    /// Refuse tokens whose device time is older than this many seconds (or
+    /// unset)
+    #[arg(long)]
+    max_age: Option<u64>,
+}
+
+/// A token as the device sends it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresenceToken {
+    pub token: Token,
+    pub signature: Signature,
+}
+
+impl PresenceToken {
+    /// Reads a `PRESENCE:` line, or just its payload
+    pub fn parse(line: &str) -> Result<Self> {
+        let line = line.trim();
+        let payload = match Response::parse(line) {
+            Some(Response::Presence(payload)) => payload,
+            _ => line,
+        };
+        let (mut counter, mut device_id, mut time, mut signature) = (None, None, None, None);
+        for (name, value) in fields(payload) {
+            match name {
+                "counter" => counter = value.parse().ok(),
+                "device_id" => device_id = parse_device_id(value),
+                "time" => time = value.parse().ok(),
+                "sig" => {
+                    signature = base64::engine::general_purpose::STANDARD
+                        .decode(value)
+                        .ok()
+                        .and_then(|bytes| Signature::try_from(bytes.as_slice()).ok())
+                }
+                _ => {}
+            }
+        }
+        let missing = |field| anyhow!("not a presence token: no valid {}", field);
+        Ok(PresenceToken {
+            token: Token {
+                counter: counter.ok_or_else(|| missing("counter"))?,
+                device_id: device_id.ok_or_else(|| missing("device_id"))?,
+                time: time.ok_or_else(|| missing("time"))?,
+            },
+            signature: signature.ok_or_else(|| missing("sig"))?,
+        })
+    }
+
+    pub fn device_id(&self) -> String {
+        identity::format_device_id(&self.token.device_id)
+    }
+
+    /// Checks the signature against `pubkey`, and that the counter is past
+    /// `after`
+    pub fn verify(&self, pubkey: &Pubkey, after: u32) -> Result<()> {
+        if !self
+            .signature
+            .verify(pubkey.as_ref(), &self.token.message())
+        {
+            bail!("presence token not signed by {}", pubkey);
+        }
+        if self.token.counter <= after {
+            bail!(
+                "presence token {} was already used (last accepted: {})",
+                self.token.counter,
+                after
+            );
+        }
+        Ok(())
+    }
+}
+
+fn parse_device_id(hex_id: &str) -> Option<[u8; DEVICE_ID_LEN]> {
+    hex_decode(hex_id)?.try_into().ok()
+}
+
+/// The last counter accepted from each device, by device ID
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Accepted {
+    #[serde(default)]
+    counters: BTreeMap<String, u32>,
+}
+
+impl Accepted {
+    fn load() -> Result<Self> {
+        let path = accepted_path()?;
+        if !path.exists() {
+            return Ok(Accepted::default());
+        }
+        let text =
+            std::fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parse {}", path.display()))
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = accepted_path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text = toml::to_string(self)?;
+        std::fs::write(&path, text).with_context(|| format!("write {}", path.display()))
+    }
+}
+
+fn accepted_path() -> Result<PathBuf> {
+    config::presence_path().ok_or_else(|| anyhow!("No config directory (set HOME)"))
+}
+
+/// Waits for a press on the attached device and prints its token
+pub fn wait(device: &mut Device, args: &WaitArgs) -> Result<()> {
+    let pubkey = device.pubkey()?;
+    let (device_id, _) = device.device_id()?;
+    let mut accepted = Accepted::load()?;
+    let after = accepted.counters.get(&device_id).copied().unwrap_or(0);
+
+    eprintln!("Press the BOOT button on the ESP32 ({})...", device_id);
+    let line = device
+        .wait_presence(args.timeout)
+        .context("no press (firmware built with `presence`?)")?;
+    let token = PresenceToken::parse(&line)?;
+    if token.device_id() != device_id {
+        bail!(
+            "presence token is from device {}, not {}",
+            token.device_id(),
+            device_id
+        );
+    }
+    token.verify(&pubkey, after)?;
+
+    accepted.counters.insert(device_id, token.token.counter);
+    accepted.save()?;
+    println!("{}", line);
+    Ok(())
+}
+
+/// Checks a saved token against the device's key
+pub fn verify(args: &VerifyArgs) -> Result<()> {
+    let token = PresenceToken::parse(&args.token)?;
+    token.verify(&args.pubkey, args.after)?;
+    if let Some(max_age) = args.max_age {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        if token.token.time == 0 {
+            bail!("the device clock wasn't set, so the token's age is unknown");
+        }
+        if now.saturating_sub(token.token.time) > max_age {
+            bail!(
+                "presence token is {} s old (at most {} s accepted)",
+                now.saturating_sub(token.token.time),
+                max_age
+            );
+        }
+    }
+    println!(
+        "Valid presence token {} from device {}",
+        token.token.counter,
+        token.device_id()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::signature::{keypair_from_seed, Keypair, Signer};
+
+    fn signed(key: &Keypair, token: Token) -> String {
+        let signature = key.sign_message(&token.message());
+        format!(
+            "PRESENCE:counter={};device_id={};time={};sig={}",
+            token.counter,
+            identity::format_device_id(&token.device_id),
+            token.time,
+            base64::engine::general_purpose::STANDARD.encode(signature)
+        )
+    }
+
+    #[test]
+    fn verifies_tokens_from_the_device_key() {
+        let key = keypair_from_seed(&[7; 32]).unwrap();
+        let pubkey = key.pubkey();
+        let token = Token {
+            counter: 5,
+            device_id: [0x11; DEVICE_ID_LEN],
+            time: 1_700_000_000,
+        };
+        let line = signed(&key, token);
+
+        let parsed = PresenceToken::parse(&line).unwrap();
+        assert_eq!(parsed.token, token);
+        assert_eq!(parsed.device_id(), "1111111111111111");
+        parsed.verify(&pubkey, 4).unwrap();
+        assert!(parsed.verify(&pubkey, 5).is_err());
+        assert!(parsed.verify(&Pubkey::new_unique(), 0).is_err());
+
+        // The payload alone reads the same
+        let payload = line.strip_prefix("PRESENCE:").unwrap();
+        assert_eq!(PresenceToken::parse(payload).unwrap(), parsed);
+    }
+
+    #[test]
+    fn rejects_altered_tokens() {
+        let key = keypair_from_seed(&[7; 32]).unwrap();
+        let pubkey = key.pubkey();
+        let line = signed(
+            &key,
+            Token {
+                counter: 5,
+                device_id: [0x11; DEVICE_ID_LEN],
+                time: 0,
+            },
+        );
+        let altered = PresenceToken::parse(&line.replace("counter=5", "counter=50")).unwrap();
+        assert!(altered.verify(&pubkey, 0).is_err());
+        assert!(PresenceToken::parse(&line.replace(";time=0", "")).is_err());
+    }
+}
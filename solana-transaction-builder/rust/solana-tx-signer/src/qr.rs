@@ -0,0 +1,225 @@
+//! Air-gapped signing over QR codes: the message of a transaction file goes
+//! out as a BC-UR `sol-sign-request`, shown as an animated QR sequence for a
+//! camera-equipped signer, and the signature comes back as a `sol-signature`
+//! UR, typed or piped in (e.g. from `zbarcam --raw`).
+//!
+//! The CBOR layouts follow the `sol-sign-request`/`sol-signature` registry
+//! types: request ID (key 1, a tagged UUID), sign data (2), signer address
+//! (4), origin (5) and sign type (6, 1 for transactions). The ESP32 holds a
+//! single key, so the derivation path (3) is left out.
+
+use anyhow::{anyhow, Result};
+use minicbor::data::Tag;
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::payload;
+
+const SIGN_REQUEST: &str = "sol-sign-request";
+const SIGNATURE: &str = "sol-signature";
+const UUID_TAG: u64 = 37;
+const SIGN_TYPE_TRANSACTION: u8 = 1;
+const ORIGIN: &str = "unruggable-rust";
+
+/// Shows the message of the transaction file `file`, to be signed by
+/// `signer`, as a QR sequence of fragments up to `fragment_len` bytes. On a
+/// terminal the codes cycle every `interval` until Ctrl-C; otherwise (or
+/// with `text`) the UR parts are printed one per line.
+pub fn export_request(
+    file: &Path,
+    signer: &Pubkey,
+    fragment_len: usize,
+    interval: Duration,
+    text: bool,
+) -> Result<()> {
+    let transaction = payload::load(file)?;
+    if !transaction
+        .message
+        .static_account_keys()
+        .iter()
+        .take(transaction.signatures.len())
+        .any(|key| key == signer)
+    {
+        return Err(anyhow!("{} is not a signer of {}", signer, file.display()));
+    }
+    let request_id: [u8; 16] = rand::random();
+    let request = sign_request(&request_id, &transaction.message.serialize(), signer)?;
+
+    let mut encoder = ur::Encoder::new(&request, fragment_len, SIGN_REQUEST)
+        .map_err(|e| anyhow!("Failed to encode the request: {:?}", e))?;
+    let count = encoder.fragment_count();
+    if text || !std::io::stdout().is_terminal() {
+        for _ in 0..count {
+            println!("{}", next_part(&mut encoder)?);
+        }
+        return Ok(());
+    }
+    if count == 1 {
+        let part = next_part(&mut encoder)?;
+        println!("{}", render(&part)?);
+        println!("Scan this with the signer, then run `qr import-signature`");
+        return Ok(());
+    }
+    // Fountain parts past the first `count` mix fragments, so a scanner
+    // that missed some frames still finishes
+    loop {
+        let part = next_part(&mut encoder)?;
+        let mut stdout = std::io::stdout().lock();
+        write!(stdout, "\x1b[2J\x1b[H{}", render(&part)?)?;
+        writeln!(
+            stdout,
+            "Part {} ({} fragments); scan until the signer has it all, then press Ctrl-C",
+            encoder.current_index(),
+            count
+        )?;
+        stdout.flush()?;
+        drop(stdout);
+        std::thread::sleep(interval);
+    }
+}
+
+/// Reads `sol-signature` UR parts from stdin until the signature is
+/// complete, checks it against the transaction in `input`, and writes the
+/// signed transaction to `output`
+pub fn import_signature(input: &Path, output: &Path) -> Result<()> {
+    let mut transaction = payload::load(input)?;
+    let message_bytes = transaction.message.serialize();
+    if std::io::stdin().is_terminal() {
+        println!(
+            "Type or paste the signer's ur:{}/... parts, one per line",
+            SIGNATURE
+        );
+    }
+
+    let mut decoder = ur::Decoder::default();
+    let mut payload = None;
+    for line in std::io::stdin().lock().lines() {
+        let line = line?.to_ascii_lowercase();
+        // Scanners may prefix the code, e.g. zbarcam's "QR-Code:"
+        let Some(start) = line.find("ur:") else {
+            continue;
+        };
+        let part = line[start..].trim();
+        let prefix = format!("ur:{}/", SIGNATURE);
+        if !part.starts_with(&prefix) {
+            return Err(anyhow!("Expected a ur:{} part, got {}", SIGNATURE, part));
+        }
+        match ur::decode(part).map_err(|e| anyhow!("Bad UR part: {:?}", e))? {
+            (ur::ur::Kind::SinglePart, bytes) => {
+                payload = Some(bytes);
+                break;
+            }
+            (ur::ur::Kind::MultiPart, _) => {
+                decoder
+                    .receive(part)
+                    .map_err(|e| anyhow!("Bad UR part: {:?}", e))?;
+                if decoder.complete() {
+                    payload = decoder
+                        .message()
+                        .map_err(|e| anyhow!("Bad UR sequence: {:?}", e))?;
+                    break;
+                }
+            }
+        }
+    }
+    let payload =
+        payload.ok_or_else(|| anyhow!("Input ended before the signature was complete"))?;
+    let (request_id, signature) = parse_signature(&payload)?;
+
+    let keys = transaction.message.static_account_keys();
+    let slot = keys
+        .iter()
+        .take(transaction.signatures.len())
+        .position(|key| signature.verify(key.as_ref(), &message_bytes))
+        .ok_or_else(|| {
+            anyhow!(
+                "The signature (request {}) is not from a signer of {}",
+                hex(&request_id),
+                input.display()
+            )
+        })?;
+    transaction.signatures[slot] = signature;
+    println!(
+        "Signature of {} for request {}",
+        keys[slot],
+        hex(&request_id)
+    );
+    payload::save(&transaction, output)?;
+    payload::status(output)
+}
+
+fn sign_request(request_id: &[u8], sign_data: &[u8], signer: &Pubkey) -> Result<Vec<u8>> {
+    let mut encoder = minicbor::Encoder::new(Vec::new());
+    encoder
+        .map(5)
+        .and_then(|e| e.u8(1))
+        .and_then(|e| e.tag(Tag::Unassigned(UUID_TAG)))
+        .and_then(|e| e.bytes(request_id))
+        .and_then(|e| e.u8(2))
+        .and_then(|e| e.bytes(sign_data))
+        .and_then(|e| e.u8(4))
+        .and_then(|e| e.bytes(signer.as_ref()))
+        .and_then(|e| e.u8(5))
+        .and_then(|e| e.str(ORIGIN))
+        .and_then(|e| e.u8(6))
+        .and_then(|e| e.u8(SIGN_TYPE_TRANSACTION))
+        .map_err(|e| anyhow!("Failed to encode the request: {}", e))?;
+    Ok(encoder.into_writer())
+}
+
+/// The request ID and signature of a `sol-signature`
+fn parse_signature(cbor: &[u8]) -> Result<(Vec<u8>, Signature)> {
+    let bad = |e: minicbor::decode::Error| anyhow!("Malformed {}: {}", SIGNATURE, e);
+    let mut decoder = minicbor::Decoder::new(cbor);
+    let entries = decoder
+        .map()
+        .map_err(bad)?
+        .ok_or_else(|| anyhow!("Malformed {}: indefinite map", SIGNATURE))?;
+    let mut request_id = Vec::new();
+    let mut signature = None;
+    for _ in 0..entries {
+        match decoder.u32().map_err(bad)? {
+            1 => {
+                if decoder.tag().map_err(bad)? != Tag::Unassigned(UUID_TAG) {
+                    return Err(anyhow!("Malformed {}: request ID isn't a UUID", SIGNATURE));
+                }
+                request_id = decoder.bytes().map_err(bad)?.to_vec();
+            }
+            2 => {
+                let bytes = decoder.bytes().map_err(bad)?;
+                signature = Some(Signature::try_from(bytes).map_err(|_| {
+                    anyhow!("Malformed {}: {}-byte signature", SIGNATURE, bytes.len())
+                })?);
+            }
+            _ => decoder.skip().map_err(bad)?,
+        }
+    }
+    let signature = signature.ok_or_else(|| anyhow!("Malformed {}: no signature", SIGNATURE))?;
+    Ok((request_id, signature))
+}
+
+fn next_part(encoder: &mut ur::Encoder<'_>) -> Result<String> {
+    encoder
+        .next_part()
+        .map_err(|e| anyhow!("Failed to encode the request: {:?}", e))
+}
+
+/// `part` as a terminal QR code. Uppercase URs fit QR's denser
+/// alphanumeric mode.
+fn render(part: &str) -> Result<String> {
+    let code = QrCode::new(part.to_ascii_uppercase())
+        .map_err(|e| anyhow!("Failed to make a QR code: {}", e))?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .quiet_zone(true)
+        .build())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,432 @@
+//! Session recordings, so a protocol problem seen on someone's device can be
+//! looked at, and run again, without the device.
+//!
+//! With --record, every byte written to or read from the device goes to the
+//! file as it happens, one line per write or read:
+//!
+//! ```text
+//! 0.012 > \n
+//! 0.023 > GET_PUBKEY\n
+//! 0.061 < PUBKEY:9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin\r\n
+//! ```
+//!
+//! with seconds since the recording started, `>` for bytes sent and `<` for
+//! bytes received, and the bytes escaped as Rust escapes ASCII.
+//!
+//! `replay` answers commands from such a file: each command line the tool
+//! sends gets what the device sent back after the next recorded command of
+//! the same name. Arguments may differ (fresh blockhashes and nonces make
+//! sure they do); recorded commands with nothing to match are skipped. With
+//! no file, the null device takes every command and never answers.
+
+use anyhow::{anyhow, Context, Result};
+use signer_protocol::transport::Port;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+static RECORDING: OnceLock<Mutex<Recording>> = OnceLock::new();
+
+/// Lines noted since `capture` was called, for `hil` to attach the device's
+/// traffic to each test
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+struct Capture {
+    lines: String,
+    started: Instant,
+}
+
+struct Recording {
+    file: File,
+    started: Instant,
+}
+
+/// Records every later exchange with a device to `path`, replacing it
+pub fn start(path: &Path) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let recording = Mutex::new(Recording {
+        file,
+        started: Instant::now(),
+    });
+    RECORDING
+        .set(recording)
+        .map_err(|_| anyhow!("Already recording"))
+}
+
+/// Notes bytes written to the device
+pub fn sent(bytes: &[u8]) {
+    record('>', bytes);
+}
+
+/// Notes bytes read from the device
+pub fn received(bytes: &[u8]) {
+    record('<', bytes);
+}
+
+fn record(direction: char, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    if let Some(capture) = CAPTURE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        let line = line(capture.started, direction, bytes);
+        capture.lines.push_str(&line);
+    }
+    let Some(recording) = RECORDING.get() else {
+        return;
+    };
+    let mut recording = recording.lock().unwrap_or_else(|e| e.into_inner());
+    let line = line(recording.started, direction, bytes);
+    // A recording is a debugging aid; losing it mustn't fail the command
+    if let Err(e) = recording.file.write_all(line.as_bytes()) {
+        tracing::warn!("Failed to write the session recording: {}", e);
+    }
+}
+
+fn line(started: Instant, direction: char, bytes: &[u8]) -> String {
+    let elapsed = started.elapsed();
+    format!(
+        "{}.{:03} {} {}\n",
+        elapsed.as_secs(),
+        elapsed.subsec_millis(),
+        direction,
+        bytes.escape_ascii()
+    )
+}
+
+/// Starts keeping the traffic in memory, in the recording's format, with
+/// times from now; replaces anything kept before
+pub fn capture() {
+    *CAPTURE.lock().unwrap_or_else(|e| e.into_inner()) = Some(Capture {
+        lines: String::new(),
+        started: Instant::now(),
+    });
+}
+
+/// Stops keeping the traffic and returns what was kept
+pub fn take_capture() -> String {
+    CAPTURE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take()
+        .map(|capture| capture.lines)
+        .unwrap_or_default()
+}
+
+/// A port whose traffic goes into the recording and the capture, if
+/// started
+pub struct Recorded<P>(P);
+
+impl<P> Recorded<P> {
+    pub fn new(port: P) -> Self {
+        Recorded(port)
+    }
+
+    pub fn get_ref(&self) -> &P {
+        &self.0
+    }
+}
+
+impl<P: Read> Read for Recorded<P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.0.read(buf)?;
+        received(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<P: Write> Write for Recorded<P> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        sent(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<P: Port> Port for Recorded<P> {
+    fn discard_input(&mut self) -> io::Result<()> {
+        self.0.discard_input()
+    }
+}
+
+/// One recorded command line and everything received until the next one
+struct Exchange {
+    line: String,
+    received: Vec<u8>,
+}
+
+/// A stand-in port answering from a recording, or never (the null device)
+pub struct Replay {
+    exchanges: VecDeque<Exchange>,
+    /// False for the null device, which has nothing to run out of
+    recorded: bool,
+    /// The command line being written
+    line: Vec<u8>,
+    /// Reply bytes for the last command, still to be read
+    pending: VecDeque<u8>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let mut sent = Vec::new();
+        let mut exchanges: Vec<Exchange> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let bad = || anyhow!("{} line {}: not a recording", path.display(), number + 1);
+            let mut fields = line.splitn(3, ' ');
+            let (Some(_time), Some(direction), Some(bytes)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(bad());
+            };
+            let bytes = unescape(bytes).ok_or_else(bad)?;
+            match direction {
+                ">" => {
+                    for byte in bytes {
+                        if byte != b'\n' {
+                            sent.push(byte);
+                        } else if !sent.is_empty() {
+                            exchanges.push(Exchange {
+                                line: String::from_utf8_lossy(&sent).into_owned(),
+                                received: Vec::new(),
+                            });
+                            sent.clear();
+                        }
+                    }
+                }
+                "<" => {
+                    // Bytes before the first command were left from earlier
+                    if let Some(exchange) = exchanges.last_mut() {
+                        exchange.received.extend(bytes);
+                    }
+                }
+                _ => return Err(bad()),
+            }
+        }
+        tracing::debug!(
+            "Replaying {} recorded exchanges from {}",
+            exchanges.len(),
+            path.display()
+        );
+        Ok(Replay {
+            exchanges: exchanges.into(),
+            recorded: true,
+            line: Vec::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// A device that takes every command and never answers
+    pub fn null() -> Self {
+        Replay {
+            exchanges: VecDeque::new(),
+            recorded: false,
+            line: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Queues the reply recorded for the next command named like `line`
+    fn answer(&mut self, line: &str) {
+        let name = command_name(line);
+        let Some(index) = self
+            .exchanges
+            .iter()
+            .position(|exchange| command_name(&exchange.line) == name)
+        else {
+            if !self.recorded {
+                return;
+            }
+            tracing::warn!(
+                command = name,
+                "no recorded exchange left for this command; it gets no reply"
+            );
+            return;
+        };
+        for skipped in self.exchanges.drain(..index) {
+            tracing::debug!(
+                command = command_name(&skipped.line),
+                "skipped a recorded exchange"
+            );
+        }
+        let exchange = self.exchanges.pop_front().expect("found above");
+        if exchange.line != line {
+            tracing::debug!(
+                command = name,
+                recorded = %exchange.line,
+                "recorded with other arguments"
+            );
+        }
+        self.pending.extend(exchange.received);
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.pending.len());
+        for (slot, byte) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte != b'\n' {
+                self.line.push(byte);
+            } else if !self.line.is_empty() {
+                let line = String::from_utf8_lossy(&self.line).into_owned();
+                self.line.clear();
+                self.answer(&line);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Port for Replay {
+    fn discard_input(&mut self) -> io::Result<()> {
+        // Whatever was still pending was never read during the recording
+        // either: the device sent it after the exchange was given up
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl Drop for Replay {
+    fn drop(&mut self) {
+        if !self.exchanges.is_empty() {
+            tracing::info!(
+                "{} recorded exchange(s) were not replayed",
+                self.exchanges.len()
+            );
+        }
+    }
+}
+
+/// `SIGN:...` -> `SIGN`
+fn command_name(line: &str) -> &str {
+    line.split(':').next().unwrap_or(line)
+}
+
+/// Undoes `escape_ascii`; `None` for anything it can't have produced
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.bytes();
+    while let Some(byte) = chars.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        bytes.push(match chars.next()? {
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'x' => {
+                let hex = [chars.next()?, chars.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            escaped @ (b'\\' | b'\'' | b'"') => escaped,
+            _ => return None,
+        });
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn replay(recording: &str) -> Replay {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(recording.as_bytes()).unwrap();
+        Replay::load(file.path()).unwrap()
+    }
+
+    /// What `replay` answers `line` with
+    fn exchange(replay: &mut Replay, line: &str) -> String {
+        replay.write_all(format!("{}\n", line).as_bytes()).unwrap();
+        let mut reply = String::new();
+        replay.read_to_string(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn answers_commands_by_name_in_recorded_order() {
+        let mut replay = replay(
+            "0.001 < boot noise\\r\\n\n\
+             0.012 > \\n\n\
+             0.023 > GET_PUBKEY\\n\n\
+             0.061 < PUBKEY:abc\\r\\n\n\
+             0.070 > SIGN:AAAA\n\
+             0.071 > \\n\n\
+             0.900 < SIGNATURE:\\x31\\r\\n\n\
+             1.000 > GET_INFO\\n\n\
+             1.010 < INFO:fw=1\\r\\n\n\
+             1.100 > SIGN:BBBB\\n\n\
+             1.900 < SIGNATURE:2\\r\\n\n",
+        );
+        assert_eq!(exchange(&mut replay, "GET_PUBKEY"), "PUBKEY:abc\r\n");
+        // Other arguments still get the next recorded SIGN's reply
+        assert_eq!(exchange(&mut replay, "SIGN:CCCC"), "SIGNATURE:1\r\n");
+        // Skips GET_INFO, which was never asked
+        assert_eq!(exchange(&mut replay, "SIGN:DDDD"), "SIGNATURE:2\r\n");
+        assert_eq!(exchange(&mut replay, "SIGN:EEEE"), "");
+        assert_eq!(exchange(&mut replay, "GET_INFO"), "");
+    }
+
+    #[test]
+    fn discarded_input_is_gone() {
+        let mut replay = replay("0.0 > PING\\n\n0.1 < PONG\\r\\n\n");
+        replay.write_all(b"PING\n").unwrap();
+        replay.discard_input().unwrap();
+        let mut reply = Vec::new();
+        replay.read_to_end(&mut reply).unwrap();
+        assert!(reply.is_empty());
+    }
+
+    #[test]
+    fn the_null_device_never_answers() {
+        let mut null = Replay::null();
+        assert_eq!(exchange(&mut null, "GET_PUBKEY"), "");
+    }
+
+    #[test]
+    fn refuses_what_isnt_a_recording() {
+        for text in ["0.0 GET_PUBKEY\n", "0.0 = GET_PUBKEY\n", "0.0 > bad\\q\n"] {
+            let mut file = tempfile::NamedTempFile::new().unwrap();
+            file.write_all(text.as_bytes()).unwrap();
+            assert!(Replay::load(file.path()).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn lines_are_timed_and_escaped() {
+        let line = line(Instant::now(), '<', b"OK:\"\\\"\r\n\x00");
+        let (time, rest) = line.split_once(' ').unwrap();
+        assert!(time.starts_with("0.0"));
+        assert_eq!(rest, "< OK:\\\"\\\\\\\"\\r\\n\\x00\n");
+    }
+
+    proptest! {
+        #[test]
+        fn unescape_undoes_escape_ascii(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            let escaped = bytes.escape_ascii().to_string();
+            prop_assert_eq!(unescape(&escaped), Some(bytes));
+        }
+    }
+}
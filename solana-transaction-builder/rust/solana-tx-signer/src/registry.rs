@@ -0,0 +1,324 @@
+//! Several signers on one workstation: every signer seen is remembered in
+//! `devices.toml` next to the config file (port, device ID, pubkey, and an
+//! optional label), so `--device` can pick one by label or pubkey.
+//!
+//! Ports change when boards are replugged; the device ID doesn't. A
+//! remembered port is only trusted after the device on it answers with the
+//! right ID, otherwise the attached signers are scanned again.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::config;
+use crate::device::Device;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    device_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    serial: Option<String>,
+    pubkey: String,
+    /// Where it was last seen
+    port: String,
+}
+
+impl Entry {
+    /// `selector` is this signer's label, pubkey, device ID, or serial number
+    fn matches(&self, selector: &str) -> bool {
+        self.label.as_deref() == Some(selector)
+            || self.pubkey == selector
+            || self.device_id == selector
+            || self.serial.as_deref() == Some(selector)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Registry {
+    #[serde(default, rename = "device")]
+    devices: Vec<Entry>,
+}
+
+impl Registry {
+    fn load() -> Result<Self> {
+        let path = path()?;
+        if !path.exists() {
+            return Ok(Registry::default());
+        }
+        let text =
+            std::fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parse {}", path.display()))
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text = toml::to_string(self)?;
+        std::fs::write(&path, text).with_context(|| format!("write {}", path.display()))
+    }
+
+    fn find(&self, selector: &str) -> Result<usize> {
+        let mut matches = self
+            .devices
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.matches(selector))
+            .map(|(i, _)| i);
+        match (matches.next(), matches.next()) {
+            (Some(i), None) => Ok(i),
+            (Some(_), Some(_)) => Err(anyhow!("'{}' matches more than one signer", selector)),
+            (None, _) => Err(anyhow!(
+                "No known signer '{}'; `devices list` shows the attached ones",
+                selector
+            )),
+        }
+    }
+
+    /// Records a signer found attached, keeping its label
+    fn update(&mut self, seen: Entry) -> usize {
+        match self
+            .devices
+            .iter()
+            .position(|entry| entry.device_id == seen.device_id)
+        {
+            Some(i) => {
+                let label = self.devices[i].label.take();
+                self.devices[i] = Entry { label, ..seen };
+                i
+            }
+            None => {
+                self.devices.push(seen);
+                self.devices.len() - 1
+            }
+        }
+    }
+}
+
+/// `devices.toml` next to the config file
+fn path() -> Result<PathBuf> {
+    config::registry_path().ok_or_else(|| anyhow!("No config directory (set HOME)"))
+}
+
+/// Asks a freshly opened signer who it is
+fn identify(port: &str, device: &mut Device) -> Result<Entry> {
+    let (device_id, serial) = device.device_id()?;
+    Ok(Entry {
+        label: None,
+        device_id,
+        serial,
+        pubkey: device.pubkey()?.to_string(),
+        port: port.to_string(),
+    })
+}
+
+/// Scans the attached signers into the registry; returns their indices
+fn scan(registry: &mut Registry, baud: u32) -> Result<Vec<usize>> {
+    let mut attached = Vec::new();
+    for (port, mut device) in Device::detect_all(baud)? {
+        match identify(&port, &mut device) {
+            Ok(entry) => attached.push(registry.update(entry)),
+            Err(e) => tracing::warn!("Skipping signer on {}: {:#}", port, e),
+        }
+    }
+    Ok(attached)
+}
+
+/// Connects to the signer `selector` names (see `Entry::matches`)
+pub fn connect(selector: &str, baud: u32) -> Result<Device> {
+    let mut registry = Registry::load()?;
+    if let Ok(i) = registry.find(selector) {
+        let known = &registry.devices[i];
+        if let Ok(mut device) = Device::open(&known.port, baud) {
+            if matches!(device.device_id(), Ok((id, _)) if id == known.device_id) {
+                return Ok(device);
+            }
+        }
+    }
+
+    // Moved to another port, or never seen: look at what is attached
+    let attached = scan(&mut registry, baud)?;
+    registry.save()?;
+    let i = registry.find(selector)?;
+    if !attached.contains(&i) {
+        return Err(anyhow!(
+            "Signer '{}' ({}) is not attached",
+            selector,
+            registry.devices[i].device_id
+        ));
+    }
+    let entry = &registry.devices[i];
+    tracing::info!("Using ESP32 signer {} on {}", selector, entry.port);
+    Device::open(&entry.port, baud)
+}
+
+/// The pubkey remembered for `selector`, or for the only signer remembered
+/// when there is no selector; the device isn't contacted
+pub fn remembered_pubkey(selector: Option<&str>) -> Result<Pubkey> {
+    let registry = Registry::load()?;
+    let entry = match selector {
+        Some(selector) => &registry.devices[registry.find(selector)?],
+        None => match registry.devices.as_slice() {
+            [entry] => entry,
+            _ => {
+                return Err(anyhow!(
+                    "Without the device, its pubkey comes from the signers `devices list` has \
+                     seen; pass --device to pick one of the {} remembered",
+                    registry.devices.len()
+                ))
+            }
+        },
+    };
+    Pubkey::from_str(&entry.pubkey)
+        .map_err(|e| anyhow!("Bad pubkey for {} in the registry: {}", entry.device_id, e))
+}
+
+/// The label given to the signer with ID `device_id`, if any
+pub fn label_of(device_id: &str) -> Option<String> {
+    Registry::load()
+        .ok()?
+        .devices
+        .into_iter()
+        .find(|entry| entry.device_id == device_id)?
+        .label
+}
+
+/// Scans the attached signers and prints every known one
+pub fn list(baud: u32) -> Result<()> {
+    let mut registry = Registry::load()?;
+    let attached = scan(&mut registry, baud)?;
+    registry.save()?;
+
+    if registry.devices.is_empty() {
+        println!("No signers attached or remembered");
+        return Ok(());
+    }
+    println!(
+        "{:<12} {:<16} {:<44} {:<10} PORT",
+        "LABEL", "DEVICE ID", "PUBKEY", "STATUS"
+    );
+    for (i, entry) in registry.devices.iter().enumerate() {
+        let status = if attached.contains(&i) {
+            "attached"
+        } else {
+            "absent"
+        };
+        println!(
+            "{:<12} {:<16} {:<44} {:<10} {}",
+            entry.label.as_deref().unwrap_or("-"),
+            entry.device_id,
+            entry.pubkey,
+            status,
+            entry.port
+        );
+    }
+    Ok(())
+}
+
+/// Names a known signer; `label` then works wherever `--device` is taken
+pub fn label(selector: &str, label: &str) -> Result<()> {
+    let mut registry = Registry::load()?;
+    let i = registry.find(selector)?;
+    if let Some(other) = registry
+        .devices
+        .iter()
+        .enumerate()
+        .find(|(j, entry)| *j != i && entry.matches(label))
+    {
+        return Err(anyhow!(
+            "'{}' already names signer {}",
+            label,
+            other.1.device_id
+        ));
+    }
+    registry.devices[i].label = Some(label.to_string());
+    registry.save()?;
+    println!("{} is now '{}'", registry.devices[i].pubkey, label);
+    Ok(())
+}
+
+/// Drops a signer from the registry
+pub fn forget(selector: &str) -> Result<()> {
+    let mut registry = Registry::load()?;
+    let i = registry.find(selector)?;
+    let entry = registry.devices.remove(i);
+    registry.save()?;
+    println!("Forgot {} ({})", entry.pubkey, entry.device_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(device_id: &str, port: &str) -> Entry {
+        Entry {
+            label: None,
+            device_id: device_id.to_string(),
+            serial: Some(format!("SN-{}", device_id)),
+            pubkey: Pubkey::new_unique().to_string(),
+            port: port.to_string(),
+        }
+    }
+
+    #[test]
+    fn finds_a_signer_by_any_of_its_names() {
+        let mut registry = Registry::default();
+        registry.update(entry("a1", "/dev/ttyUSB0"));
+        registry.update(entry("b2", "/dev/ttyUSB1"));
+        registry.devices[1].label = Some("cold".to_string());
+        let pubkey = registry.devices[0].pubkey.clone();
+
+        assert_eq!(registry.find("a1").unwrap(), 0);
+        assert_eq!(registry.find(&pubkey).unwrap(), 0);
+        assert_eq!(registry.find("SN-b2").unwrap(), 1);
+        assert_eq!(registry.find("cold").unwrap(), 1);
+        assert!(registry.find("hot").is_err());
+
+        // A label that is another signer's serial number picks neither
+        registry.devices[0].label = Some("SN-b2".to_string());
+        assert!(registry.find("SN-b2").is_err());
+    }
+
+    #[test]
+    fn a_signer_seen_again_keeps_its_label_and_moves_port() {
+        let mut registry = Registry::default();
+        registry.update(entry("a1", "/dev/ttyUSB0"));
+        registry.devices[0].label = Some("desk".to_string());
+
+        let moved = entry("a1", "/dev/ttyUSB3");
+        assert_eq!(registry.update(moved.clone()), 0);
+        assert_eq!(registry.devices.len(), 1);
+        assert_eq!(registry.devices[0].port, "/dev/ttyUSB3");
+        assert_eq!(registry.devices[0].pubkey, moved.pubkey);
+        assert_eq!(registry.devices[0].label.as_deref(), Some("desk"));
+
+        assert_eq!(registry.update(entry("c3", "/dev/ttyUSB0")), 1);
+    }
+
+    #[test]
+    fn round_trips_through_toml() {
+        let mut registry = Registry::default();
+        registry.update(entry("a1", "/dev/ttyUSB0"));
+        registry.update(Entry {
+            serial: None,
+            label: Some("desk".to_string()),
+            ..entry("b2", "/dev/ttyUSB1")
+        });
+        let text = toml::to_string(&registry).unwrap();
+        assert_eq!(text.matches("[[device]]").count(), 2);
+        // Unset fields are left out rather than written empty
+        assert_eq!(text.matches("serial").count(), 1);
+
+        let read: Registry = toml::from_str(&text).unwrap();
+        assert_eq!(read.devices.len(), 2);
+        assert_eq!(read.find("desk").unwrap(), 1);
+        assert_eq!(read.devices[0].serial.as_deref(), Some("SN-a1"));
+        assert!(toml::from_str::<Registry>("").unwrap().devices.is_empty());
+    }
+}
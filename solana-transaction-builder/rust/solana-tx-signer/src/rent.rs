@@ -0,0 +1,250 @@
+//! Rent checks on SOL transfers, made before the device is asked to sign, so
+//! a transaction confirmed on the device doesn't fail on chain for a reason
+//! known beforehand.
+//!
+//! A system account holds either nothing or at least the rent-exempt
+//! minimum, so a transfer fails if it would leave the sender, or fund a
+//! recipient that doesn't exist yet, with less. `--rent warn` (the default)
+//! says so and builds the transfer as asked; `--rent adjust` changes the
+//! amount instead, up to the minimum for new recipients or down so the
+//! sender keeps it; `--rent off` skips the lookups.
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, ValueEnum};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{pubkey::Pubkey, system_program};
+
+use crate::config::Settings;
+
+/// Set aside for the fee when the sender pays it: the base fee for two
+/// signatures, with room for a priority fee on a transfer's few hundred
+/// compute units
+const FEE_ALLOWANCE: u64 = 10_000;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RentMode {
+    /// Warn about a transfer that would fail, and build it as asked
+    #[default]
+    Warn,
+    /// Change the amount so the transfer doesn't fail
+    Adjust,
+    /// Don't look the accounts up
+    Off,
+}
+
+#[derive(Args, Debug, Clone, Copy)]
+pub struct RentArgs {
+    /// What to do about a transfer that would leave an account below the
+    /// rent-exempt minimum
+    #[arg(long, value_enum, default_value_t = RentMode::Warn)]
+    pub rent: RentMode,
+}
+
+/// A recipient as the cluster knows it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Recipient {
+    address: Pubkey,
+    /// `None` if the account doesn't exist
+    owner: Option<Pubkey>,
+    lamports: u64,
+}
+
+/// The amount to send each recipient, and what was found on the way
+#[derive(Debug, PartialEq, Eq)]
+struct Plan {
+    lamports: u64,
+    notes: Vec<String>,
+}
+
+/// Checks a transfer of `lamports` from `from` to each of `to` against the
+/// accounts on chain; returns the amount to send, which only `--rent adjust`
+/// changes. `sender_pays_fee` is whether `from` is the fee payer.
+pub fn check(
+    client: &RpcClient,
+    settings: &Settings,
+    from: &Pubkey,
+    to: &[Pubkey],
+    lamports: u64,
+    sender_pays_fee: bool,
+    mode: RentMode,
+) -> Result<u64> {
+    // A dry run has no accounts to look at
+    if mode == RentMode::Off || settings.dry_run {
+        return Ok(lamports);
+    }
+    let minimum = client
+        .get_minimum_balance_for_rent_exemption(0)
+        .map_err(|e| anyhow!("Failed to fetch the rent-exempt minimum: {}", e))?;
+    let keys: Vec<Pubkey> = std::iter::once(*from).chain(to.iter().copied()).collect();
+    let accounts = client
+        .get_multiple_accounts(&keys)
+        .map_err(|e| anyhow!("Failed to fetch the transfer's accounts: {}", e))?;
+    let sender = accounts[0].as_ref().map_or(0, |account| account.lamports);
+    let recipients: Vec<Recipient> = to
+        .iter()
+        .zip(&accounts[1..])
+        .map(|(address, account)| Recipient {
+            address: *address,
+            owner: account.as_ref().map(|account| account.owner),
+            lamports: account.as_ref().map_or(0, |account| account.lamports),
+        })
+        .collect();
+    let fee = if sender_pays_fee { FEE_ALLOWANCE } else { 0 };
+
+    let plan = plan(minimum, sender, fee, &recipients, lamports, mode)?;
+    for note in &plan.notes {
+        println!("{}", note);
+    }
+    Ok(plan.lamports)
+}
+
+fn plan(
+    minimum: u64,
+    sender: u64,
+    fee: u64,
+    recipients: &[Recipient],
+    lamports: u64,
+    mode: RentMode,
+) -> Result<Plan> {
+    let mut notes = Vec::new();
+    let mut lamports = lamports;
+
+    // An account that doesn't exist, or holds nothing, is created by the
+    // transfer and has to end up rent-exempt
+    let new: Vec<&Recipient> = recipients.iter().filter(|r| r.lamports == 0).collect();
+    for recipient in &new {
+        notes.push(format!(
+            "{} doesn't exist yet; the transfer creates it",
+            recipient.address
+        ));
+    }
+    for recipient in recipients {
+        match recipient.owner {
+            Some(owner) if owner != system_program::id() => notes.push(format!(
+                "Warning: {} is an account of program {}, not a wallet",
+                recipient.address, owner
+            )),
+            _ => {}
+        }
+    }
+    if !new.is_empty() && lamports < minimum {
+        match mode {
+            RentMode::Adjust => {
+                notes.push(format!(
+                    "Raising the amount to {} lamports, the rent-exempt minimum for a new account",
+                    minimum
+                ));
+                lamports = minimum;
+            }
+            _ => notes.push(format!(
+                "Warning: {} lamports is below the rent-exempt minimum of {} for a new account; \
+                 the transfer will fail (--rent adjust raises it)",
+                lamports, minimum
+            )),
+        }
+    }
+
+    // The sender can be emptied, or keep the minimum, but nothing in between
+    let count = recipients.len() as u64;
+    let left = lamports
+        .checked_mul(count)
+        .and_then(|total| total.checked_add(fee))
+        .and_then(|spent| sender.checked_sub(spent));
+    match left {
+        None => {
+            let message = format!(
+                "the sender holds {} lamports, less than the {} lamports sent{}",
+                sender,
+                lamports.saturating_mul(count),
+                if fee > 0 { " plus fees" } else { "" }
+            );
+            if mode == RentMode::Adjust {
+                bail!("Can't adjust the transfer: {}", message);
+            }
+            notes.push(format!("Warning: {}; the transfer will fail", message));
+        }
+        Some(left) if left > 0 && left < minimum => {
+            let most = sender.saturating_sub(fee + minimum) / count.max(1);
+            if mode != RentMode::Adjust {
+                notes.push(format!(
+                    "Warning: the sender would keep {} lamports, below the rent-exempt minimum \
+                     of {}; the transfer will fail (send at most {} each, or --rent adjust)",
+                    left, minimum, most
+                ));
+            } else if most == 0 || (!new.is_empty() && most < minimum) {
+                bail!(
+                    "Can't adjust the transfer: the sender can't keep the rent-exempt minimum \
+                     of {} lamports and still fund the recipients",
+                    minimum
+                );
+            } else {
+                notes.push(format!(
+                    "Lowering the amount to {} lamports, so the sender keeps the rent-exempt \
+                     minimum of {}",
+                    most, minimum
+                ));
+                lamports = most;
+            }
+        }
+        Some(_) => {}
+    }
+    Ok(Plan { lamports, notes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMUM: u64 = 890_880;
+
+    fn wallet(lamports: u64) -> Recipient {
+        Recipient {
+            address: Pubkey::new_unique(),
+            owner: (lamports > 0).then(system_program::id),
+            lamports,
+        }
+    }
+
+    #[test]
+    fn raises_transfers_to_new_accounts() {
+        let to = [wallet(0)];
+        let warned = plan(MINIMUM, 10 * MINIMUM, 0, &to, 1_000, RentMode::Warn).unwrap();
+        assert_eq!(warned.lamports, 1_000);
+        assert!(warned.notes.iter().any(|note| note.contains("will fail")));
+
+        let adjusted = plan(MINIMUM, 10 * MINIMUM, 0, &to, 1_000, RentMode::Adjust).unwrap();
+        assert_eq!(adjusted.lamports, MINIMUM);
+
+        // An existing account takes any amount
+        let to = [wallet(5)];
+        let plan = plan(MINIMUM, 10 * MINIMUM, 0, &to, 1_000, RentMode::Adjust).unwrap();
+        assert_eq!(plan.lamports, 1_000);
+        assert!(plan.notes.is_empty());
+    }
+
+    #[test]
+    fn keeps_the_sender_rent_exempt() {
+        let to = [wallet(1), wallet(1)];
+        let sender = 3 * MINIMUM;
+        // Emptying the sender exactly is fine
+        let emptied = plan(MINIMUM, sender, 0, &to[..1], sender, RentMode::Warn).unwrap();
+        assert!(emptied.notes.is_empty());
+
+        let lowered = plan(MINIMUM, sender, 5_000, &to, MINIMUM, RentMode::Adjust).unwrap();
+        assert_eq!(lowered.lamports, (sender - 5_000 - MINIMUM) / 2);
+        let warned = plan(MINIMUM, sender, 5_000, &to, MINIMUM, RentMode::Warn).unwrap();
+        assert_eq!(warned.lamports, MINIMUM);
+        assert!(warned.notes[0].contains("will fail"));
+
+        assert!(plan(MINIMUM, 1_000, 0, &to, 2_000, RentMode::Adjust).is_err());
+        assert!(plan(
+            MINIMUM,
+            MINIMUM + 10,
+            0,
+            &[wallet(0)],
+            100,
+            RentMode::Adjust
+        )
+        .is_err());
+    }
+}
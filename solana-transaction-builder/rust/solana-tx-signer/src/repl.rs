@@ -0,0 +1,184 @@
+//! Interactive mode: one serial connection (and GET_INFO handshake) for a
+//! whole session of commands, with line editing, history and tab completion.
+
+use anyhow::{anyhow, Result};
+use clap::{CommandFactory, Parser};
+use rustyline::{
+    completion::Completer, error::ReadlineError, history::DefaultHistory, Context, Editor,
+};
+use rustyline::{Helper, Highlighter, Hinter, Validator};
+
+use crate::config::{self, Settings};
+use crate::{amount, Commands, Session};
+
+const PROMPT: &str = "esp32> ";
+/// Lines that aren't subcommands
+const BUILTINS: &[&str] = &["exit", "quit", "reconnect"];
+
+/// One REPL line: any subcommand, without the connection flags (those are
+/// fixed for the session)
+#[derive(Parser, Debug)]
+#[command(
+    name = "",
+    no_binary_name = true,
+    disable_version_flag = true,
+    about = "Subcommands as on the command line, minus the connection flags",
+    after_help = "Also: `transfer <SOL> SOL to <ADDRESS>`, `reconnect`, `exit`"
+)]
+struct Line {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct LineHelper {
+    commands: clap::Command,
+}
+
+impl Completer for LineHelper {
+    type Candidate = String;
+
+    /// Subcommand names, or `--flags` of the subcommand typed so far
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let prefix = &line[start..pos];
+
+        let mut command = &self.commands;
+        for word in line[..start].split_whitespace() {
+            match command.find_subcommand(word) {
+                Some(subcommand) => command = subcommand,
+                None => break,
+            }
+        }
+
+        let candidates: Vec<String> = if prefix.starts_with('-') {
+            command
+                .get_arguments()
+                .filter_map(|arg| arg.get_long())
+                .map(|long| format!("--{}", long))
+                .collect()
+        } else {
+            let top_level = std::ptr::eq(command, &self.commands);
+            command
+                .get_subcommands()
+                .map(|subcommand| subcommand.get_name().to_string())
+                .filter(|name| name != "repl")
+                .chain(BUILTINS.iter().filter(|_| top_level).map(|b| b.to_string()))
+                .collect()
+        };
+        let mut candidates: Vec<String> = candidates
+            .into_iter()
+            .filter(|c| c.starts_with(prefix))
+            .collect();
+        candidates.sort();
+        Ok((start, candidates))
+    }
+}
+
+/// Reads and runs commands until `exit` or end of input. A failing command
+/// prints its error and the session carries on.
+pub fn run(settings: &Settings, session: &mut Session) -> Result<()> {
+    let mut editor: Editor<LineHelper, DefaultHistory> =
+        Editor::new().map_err(|e| anyhow!("Failed to start line editor: {}", e))?;
+    editor.set_helper(Some(LineHelper {
+        commands: Line::command(),
+    }));
+    let history = config::history_path();
+    if let Some(history) = &history {
+        // No history yet on the first run
+        let _ = editor.load_history(history);
+    }
+
+    match session.device().and_then(|device| device.pubkey()) {
+        Ok(pubkey) => println!("Connected to {}", pubkey),
+        Err(e) => eprintln!("Not connected ({:#}); attach the ESP32 and `reconnect`", e),
+    }
+    println!("`help` lists commands, `exit` quits");
+
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            // Ctrl-C drops the current line, Ctrl-D ends the session
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(anyhow!("Failed to read input: {}", e)),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+
+        let Some(words) = shlex::split(line) else {
+            eprintln!("Error: unbalanced quotes");
+            continue;
+        };
+        match words[0].as_str() {
+            "exit" | "quit" => break,
+            "reconnect" => {
+                session.device = None;
+                match session.device().and_then(|device| device.pubkey()) {
+                    Ok(pubkey) => println!("Connected to {}", pubkey),
+                    Err(e) => eprintln!("Error: {:#}", e),
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let line = match Line::try_parse_from(expand(words)) {
+            Ok(line) => line,
+            Err(e) => {
+                // Also how `help` and `--help` get printed
+                let _ = e.print();
+                continue;
+            }
+        };
+        if let Commands::Repl = line.command {
+            eprintln!("Error: already in the REPL");
+            continue;
+        }
+        if let Err(e) = crate::run(&line.command, settings, session) {
+            eprintln!("Error: {:#}", e);
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Some(dir) = history.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("Could not save history to {}: {}", history.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites `transfer <SOL> SOL to <ADDRESS>` into `transfer` flags, with the
+/// amount converted to lamports; anything else passes through unchanged
+fn expand(words: Vec<String>) -> Vec<String> {
+    match words.as_slice() {
+        [transfer, amount, unit, to, address]
+            if transfer == "transfer"
+                && unit.eq_ignore_ascii_case("sol")
+                && to.eq_ignore_ascii_case("to") =>
+        {
+            match amount::parse_units(amount, amount::SOL_DECIMALS) {
+                Ok(lamports) => vec![
+                    transfer.clone(),
+                    "--to".to_string(),
+                    address.clone(),
+                    "--amount".to_string(),
+                    lamports.to_string(),
+                ],
+                Err(_) => words,
+            }
+        }
+        _ => words,
+    }
+}
@@ -0,0 +1,178 @@
+//! The device's SD card.
+//!
+//! `sd backup` and `sd restore` are the device's encrypted seed and policy
+//! backups on its own card (firmware built with `sd-backup`). The seed never
+//! crosses the serial link; only the PIN and passphrase do, going in.
+//!
+//! Both the PIN (4 to 8 digits) and the passphrase are needed to restore:
+//! the passphrase can be written down and kept with the card, the PIN kept
+//! in someone's head. Either comes from the environment for scripts, or is
+//! asked for.
+//!
+//! `sd export` and `sd import` carry transactions to and from a signer built
+//! with `sneakernet` on the card alone, mounted on this machine; the layout
+//! is in `signer_core::sneakernet`.
+
+use anyhow::{anyhow, Context, Result};
+use signer_core::backup::valid_pin;
+use signer_core::sneakernet;
+use solana_sdk::signature::Signature;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+
+use crate::device::Device;
+use crate::payload;
+
+const PIN_ENV: &str = "ESP32_SIGNER_SD_PIN";
+const PASSPHRASE_ENV: &str = "ESP32_SIGNER_SD_PASSPHRASE";
+
+pub fn backup(device: &mut Device) -> Result<()> {
+    let pin = secret(PIN_ENV, "Backup PIN (4-8 digits): ", true)?;
+    if !valid_pin(&pin) {
+        return Err(anyhow!("The PIN must be 4 to 8 digits"));
+    }
+    let passphrase = secret(PASSPHRASE_ENV, "Backup passphrase: ", true)?;
+    if passphrase.is_empty() {
+        return Err(anyhow!("The backup passphrase can't be empty"));
+    }
+
+    println!("Press BOOT on the device to write the backup...");
+    let name = device.backup_to_sd(&pin, &passphrase)?;
+    println!("Backed up to {} on the device's SD card", name);
+    Ok(())
+}
+
+pub fn restore(device: &mut Device) -> Result<()> {
+    let pin = secret(PIN_ENV, "Backup PIN: ", false)?;
+    if !valid_pin(&pin) {
+        return Err(anyhow!("The PIN must be 4 to 8 digits"));
+    }
+    let passphrase = secret(PASSPHRASE_ENV, "Backup passphrase: ", false)?;
+
+    println!("The device's current key will be replaced by the newest backup on its SD card.");
+    println!("Press BOOT on the device to restore...");
+    let pubkey = device.restore_from_sd(&pin, &passphrase)?;
+    println!("Restored {}; the device is restarting", pubkey);
+    Ok(())
+}
+
+/// Writes the message of the transaction file `file` to the card mounted at
+/// `card` as the next signing request
+pub fn export_request(file: &Path, card: &Path) -> Result<()> {
+    let transaction = payload::load(file)?;
+    let message = transaction.message.serialize();
+    if message.len() > sneakernet::MAX_MESSAGE_LEN {
+        return Err(anyhow!(
+            "{} has a {}-byte message; the signer takes at most {}",
+            file.display(),
+            message.len(),
+            sneakernet::MAX_MESSAGE_LEN
+        ));
+    }
+    let names = list(card)?;
+    if let Some(name) = find_request(card, &names, &message)? {
+        println!("{} is already on the card as {}", file.display(), name);
+        return Ok(());
+    }
+    let seq = sneakernet::next_seq(names.iter().map(String::as_str))
+        .ok_or_else(|| anyhow!("The card has run out of request numbers; clear it"))?;
+    let name = sneakernet::request_name(seq);
+    fs::write(card.join(&name), &message).with_context(|| format!("write {}", name))?;
+    println!(
+        "Wrote {}; take the card to the signer, power it up and press BOOT for each request",
+        name
+    );
+    Ok(())
+}
+
+/// Finds the request for the transaction file `input` on the card mounted
+/// at `card`, and writes the transaction with the signer's signature added
+/// to `output`
+pub fn import_signature(input: &Path, card: &Path, output: &Path) -> Result<()> {
+    let mut transaction = payload::load(input)?;
+    let message = transaction.message.serialize();
+    let names = list(card)?;
+    let name = find_request(card, &names, &message)?
+        .ok_or_else(|| anyhow!("No request for {} on the card", input.display()))?;
+    let seq = sneakernet::parse_request_name(&name).expect("found among request names");
+
+    let answer = |answer_name: String| -> Result<Option<Vec<u8>>> {
+        match names.iter().find(|n| n.eq_ignore_ascii_case(&answer_name)) {
+            Some(n) => Ok(Some(
+                fs::read(card.join(n)).with_context(|| format!("read {}", n))?,
+            )),
+            None => Ok(None),
+        }
+    };
+    if let Some(reason) = answer(sneakernet::rejection_name(seq))? {
+        return Err(anyhow!(
+            "The signer rejected {}: {}",
+            name,
+            String::from_utf8_lossy(&reason).trim()
+        ));
+    }
+    let signature = answer(sneakernet::signature_name(seq))?
+        .ok_or_else(|| anyhow!("The signer hasn't answered {} yet", name))?;
+    let signature = Signature::try_from(signature.as_slice()).map_err(|_| {
+        anyhow!(
+            "{} doesn't hold a signature",
+            sneakernet::signature_name(seq)
+        )
+    })?;
+
+    let keys = transaction.message.static_account_keys();
+    let slot = keys
+        .iter()
+        .take(transaction.signatures.len())
+        .position(|key| signature.verify(key.as_ref(), &message))
+        .ok_or_else(|| {
+            anyhow!(
+                "The signature for {} is not from a signer of {}",
+                name,
+                input.display()
+            )
+        })?;
+    transaction.signatures[slot] = signature;
+    println!("Signature of {} from {}", keys[slot], name);
+    payload::save(&transaction, output)?;
+    payload::status(output)
+}
+
+/// The names in the card's root
+fn list(card: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(card).with_context(|| format!("list {}", card.display()))? {
+        if let Some(name) = entry?.file_name().to_str() {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// The request among `names` holding exactly `message`
+fn find_request(card: &Path, names: &[String], message: &[u8]) -> Result<Option<String>> {
+    for name in names {
+        if sneakernet::parse_request_name(name).is_some()
+            && fs::read(card.join(name)).with_context(|| format!("read {}", name))? == message
+        {
+            return Ok(Some(name.clone()));
+        }
+    }
+    Ok(None)
+}
+
+/// `env`, or asked for on the terminal; `new` asks twice
+fn secret(env: &str, prompt: &str, new: bool) -> Result<String> {
+    if let Ok(value) = std::env::var(env) {
+        return Ok(value);
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!("Not a terminal; set {}", env));
+    }
+    let value = rpassword::prompt_password(prompt)?;
+    if new && rpassword::prompt_password("Repeat it: ")? != value {
+        return Err(anyhow!("They don't match"));
+    }
+    Ok(value)
+}
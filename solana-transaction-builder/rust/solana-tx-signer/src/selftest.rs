@@ -0,0 +1,329 @@
+//! `selftest`: the whole device protocol, checked against the attached
+//! ESP32, with a pass/fail line and the time taken for every check.
+//!
+//! Checks that would change the device are opt-in: `--otp` enrolls 2FA on a
+//! device that isn't enrolled yet (computing the codes here, as
+//! `otp test --headless` does), which is the only way the unlock can be
+//! tested without a person reading codes off an authenticator app. Signing
+//! needs a BOOT press; `--no-sign` leaves it out for unattended runs.
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use clap::Args;
+use data_encoding::BASE32_NOPAD;
+use signer_protocol::command::Command;
+use signer_protocol::error::ErrorCode;
+use solana_sdk::{offchain_message::OffchainMessage, transaction::Transaction};
+use std::time::{Duration, Instant};
+
+use crate::confirm;
+use crate::device::{Device, DeviceError, OtpStatus, REPLY_IDLE_SECS};
+use crate::otp;
+use crate::recording;
+
+const SIGN_TEXT: &str = "unruggable selftest";
+
+#[derive(Args, Debug)]
+pub struct SelftestArgs {
+    /// Enroll 2FA if the device isn't enrolled, to test confirming and
+    /// unlocking; leaves the device enrolled with a secret nobody keeps
+    #[arg(long)]
+    otp: bool,
+    /// Leave out the checks that need the BOOT button
+    #[arg(long)]
+    no_sign: bool,
+}
+
+/// How one check went
+pub enum Verdict {
+    Pass(String),
+    Skip(String),
+    Fail(anyhow::Error),
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub verdict: Verdict,
+    pub elapsed: Duration,
+    /// Everything sent to and received from the device during the check
+    pub log: String,
+}
+
+#[derive(Default)]
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    /// Runs `check`, printing its line as soon as it is done
+    fn run(&mut self, name: &'static str, check: impl FnOnce() -> Result<Outcome>) {
+        recording::capture();
+        let start = Instant::now();
+        let verdict = match check() {
+            Ok(Outcome::Passed(detail)) => Verdict::Pass(detail),
+            Ok(Outcome::Skipped(reason)) => Verdict::Skip(reason),
+            Err(e) => Verdict::Fail(e),
+        };
+        let check = Check {
+            name,
+            verdict,
+            elapsed: start.elapsed(),
+            log: recording::take_capture(),
+        };
+        print_check(&check);
+        self.checks.push(check);
+    }
+}
+
+/// How many of `checks` ended in a verdict `matches` accepts
+pub fn count(checks: &[Check], matches: impl Fn(&Verdict) -> bool) -> usize {
+    checks.iter().filter(|c| matches(&c.verdict)).count()
+}
+
+enum Outcome {
+    Passed(String),
+    Skipped(String),
+}
+
+fn passed(detail: impl Into<String>) -> Result<Outcome> {
+    Ok(Outcome::Passed(detail.into()))
+}
+
+fn skipped(reason: impl Into<String>) -> Result<Outcome> {
+    Ok(Outcome::Skipped(reason.into()))
+}
+
+/// What the 2FA checks found out, for the checks after them
+enum TwoFa {
+    /// Firmware built without `twofa`
+    Disabled,
+    Status(OtpStatus),
+    /// Enrolled in this run, so the codes can be computed here
+    EnrolledHere {
+        secret: Vec<u8>,
+        period: u64,
+    },
+}
+
+/// Runs every check against `device` and prints a summary; fails if any
+/// check did
+pub fn run(device: &mut Device, args: &SelftestArgs) -> Result<()> {
+    let checks = checks(device, args);
+    let failed = count(&checks, |v| matches!(v, Verdict::Fail(_)));
+    let total: Duration = checks.iter().map(|c| c.elapsed).sum();
+    println!(
+        "\n{} passed, {} failed, {} skipped in {:.1}s",
+        count(&checks, |v| matches!(v, Verdict::Pass(_))),
+        failed,
+        count(&checks, |v| matches!(v, Verdict::Skip(_))),
+        total.as_secs_f64()
+    );
+    if failed > 0 {
+        return Err(anyhow!("{} selftest check(s) failed", failed));
+    }
+    Ok(())
+}
+
+/// Runs every check against `device`, printing each line as it finishes
+pub fn checks(device: &mut Device, args: &SelftestArgs) -> Vec<Check> {
+    let mut report = Report::default();
+    let mut pubkey = None;
+
+    report.run("get pubkey", || {
+        let key = device.pubkey()?;
+        pubkey = Some(key);
+        passed(key.to_string())
+    });
+    report.run("firmware info", || {
+        let info = device.firmware_info()?;
+        device.check_compatible()?;
+        passed(format!("{}, protocol {}", info.version, info.protocol))
+    });
+    report.run("device id", || {
+        let (id, serial) = device.device_id()?;
+        passed(match serial {
+            Some(serial) => format!("{}, serial {}", id, serial),
+            None => id,
+        })
+    });
+    report.run("tx-info", || {
+        let info = device.tx_info()?;
+        if signer_protocol::response::fields(&info).next().is_none() {
+            return Err(anyhow!("no fields in {:?}", info));
+        }
+        passed(info)
+    });
+    report.run("placeholder tx", || {
+        let Some(pubkey) = pubkey else {
+            return skipped("no pubkey to check it against");
+        };
+        let encoded = device.create_tx()?;
+        let bytes = base64::engine::general_purpose::STANDARD.decode(&encoded)?;
+        let tx: Transaction =
+            bincode::deserialize(&bytes).map_err(|e| anyhow!("not a legacy transaction: {}", e))?;
+        let required = usize::from(tx.message.header.num_required_signatures);
+        if tx.signatures.len() != required {
+            return Err(anyhow!(
+                "{} signatures for {} required signers",
+                tx.signatures.len(),
+                required
+            ));
+        }
+        if tx.message.account_keys.first() != Some(&pubkey) {
+            return Err(anyhow!("fee payer isn't the device key"));
+        }
+        tx.verify()
+            .map_err(|e| anyhow!("signature doesn't verify: {}", e))?;
+        passed(format!(
+            "{} bytes, {} instruction(s)",
+            bytes.len(),
+            tx.message.instructions.len()
+        ))
+    });
+
+    let mut twofa = TwoFa::Disabled;
+    report.run("otp status", || match device.otp_status() {
+        Ok(status) => {
+            let detail = if !status.enrolled {
+                "not enrolled".to_string()
+            } else if status.unlocked_until > status.now {
+                format!("unlocked for {}s", status.unlocked_until - status.now)
+            } else {
+                "enrolled, locked".to_string()
+            };
+            twofa = TwoFa::Status(status);
+            passed(detail)
+        }
+        Err(e) if is_error(&e, ErrorCode::OtpDisabled) => skipped("firmware without twofa"),
+        Err(e) => Err(e),
+    });
+    report.run("otp enroll and confirm", || {
+        match &twofa {
+            TwoFa::Disabled => return skipped("firmware without twofa"),
+            TwoFa::Status(status) if status.enrolled => return skipped("already enrolled"),
+            TwoFa::Status(_) if !args.otp => return skipped("changes the device; pass --otp"),
+            _ => {}
+        }
+        let otp = device.otp_begin()?;
+        let secret = BASE32_NOPAD
+            .decode(otp.secret.as_bytes())
+            .map_err(|e| anyhow!("OTP secret isn't base32: {}", e))?;
+        let code = otp::totp(&secret, otp::now_unix(), otp.period);
+        device.otp_confirm(&code)?;
+        twofa = TwoFa::EnrolledHere {
+            secret,
+            period: otp.period,
+        };
+        passed(format!("{} digits, {}s period", otp.digits, otp.period))
+    });
+    report.run("locked sign rejected", || {
+        let locked = match &twofa {
+            TwoFa::Disabled => return skipped("firmware without twofa"),
+            TwoFa::Status(status) => status.enrolled && status.unlocked_until <= status.now,
+            // Confirming doesn't open the signing window
+            TwoFa::EnrolledHere { .. } => true,
+        };
+        if !locked {
+            return skipped("signing isn't locked");
+        }
+        if !device.is_paired() {
+            return skipped("this host isn't paired with the device");
+        }
+        match device.sign(SIGN_TEXT.as_bytes()) {
+            Ok(_) => Err(anyhow!("signed while locked")),
+            Err(e) if is_error(&e, ErrorCode::Locked) => passed("ERROR:LOCKED"),
+            Err(e) => Err(e),
+        }
+    });
+    report.run("otp unlock", || {
+        let TwoFa::EnrolledHere { secret, period } = &twofa else {
+            return skipped("needs a device enrolled in this run (--otp)");
+        };
+        // The code that confirmed can't unlock
+        otp::wait_for_next_step(*period);
+        let code = otp::totp(secret, otp::now_unix(), *period);
+        let until = device.otp_unlock(&code)?;
+        passed(format!("until {}", until))
+    });
+    // Refresh: the window may have opened or closed since
+    let can_sign = match device.otp_status() {
+        Ok(status) => !status.enrolled || status.unlocked_until > status.now,
+        Err(_) => true,
+    };
+
+    report.run("sign and verify", || {
+        if args.no_sign {
+            return skipped("--no-sign");
+        }
+        if !can_sign {
+            return skipped("signing is locked");
+        }
+        let Some(pubkey) = pubkey else {
+            return skipped("no pubkey to verify against");
+        };
+        let message = OffchainMessage::new(0, SIGN_TEXT.as_bytes())
+            .map_err(|e| anyhow!("not a valid off-chain message: {}", e))?;
+        let payload = message
+            .serialize()
+            .map_err(|e| anyhow!("cannot sign this message: {}", e))?;
+        let signature = confirm::sign(device, &payload, &[format!("Message: {}", SIGN_TEXT)])?;
+        if !message.verify(&pubkey, &signature).unwrap_or(false) {
+            return Err(anyhow!("signature doesn't verify"));
+        }
+        passed(signature.to_string())
+    });
+    report.run("unknown command rejected", || {
+        expect_error(
+            device,
+            Command::Unknown("SELFTEST_NO_SUCH_COMMAND"),
+            ErrorCode::UnknownCommand,
+        )
+    });
+    report.run("malformed arguments rejected", || {
+        expect_error(
+            device,
+            Command::Unknown("OTA_BEGIN:not-a-size"),
+            ErrorCode::BadArguments,
+        )
+    });
+    report.run("bad encoding rejected", || {
+        if !can_sign {
+            return skipped("signing is locked");
+        }
+        expect_error(device, Command::Sign("%%%"), ErrorCode::Encoding)
+    });
+    report.run("still answering", || {
+        device.pubkey()?;
+        passed("GET_PUBKEY after the errors")
+    });
+
+    report.checks
+}
+
+pub fn print_check(check: &Check) {
+    let (status, detail) = match &check.verdict {
+        Verdict::Pass(detail) => ("PASS", detail.clone()),
+        Verdict::Skip(reason) => ("SKIP", reason.clone()),
+        Verdict::Fail(e) => ("FAIL", format!("{:#}", e)),
+    };
+    println!(
+        "{:<4} {:<30} {:>7.0}ms  {}",
+        status,
+        check.name,
+        check.elapsed.as_secs_f64() * 1000.0,
+        detail
+    );
+}
+
+/// Sends `command`, which the device has to refuse with `code`
+fn expect_error(device: &mut Device, command: Command, code: ErrorCode) -> Result<Outcome> {
+    match device.request(command, REPLY_IDLE_SECS, |_| Some(())) {
+        Ok(()) => Err(anyhow!("accepted; expected ERROR:{}", code)),
+        Err(e) if is_error(&e, code) => passed(format!("ERROR:{}", code)),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_error(e: &anyhow::Error, expected: ErrorCode) -> bool {
+    matches!(e.downcast_ref::<DeviceError>(), Some(DeviceError { code, .. }) if *code == expected)
+}
@@ -0,0 +1,589 @@
+//! Signing instructions with the device (and an optional fee payer: a local
+//! keypair or a second device) and submitting the result.
+
+use anyhow::{anyhow, Result};
+use signer_core::token_labels::TokenLabel;
+use solana_client::{
+    rpc_client::RpcClient,
+    rpc_config::{RpcSendTransactionConfig, RpcSimulateTransactionConfig},
+};
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    instruction::Instruction,
+    message::Message,
+    pubkey::Pubkey,
+    signature::{read_keypair_file, Keypair, Signature, Signer},
+    system_instruction,
+    transaction::VersionedTransaction,
+};
+use solana_transaction_status::TransactionStatus;
+use std::time::{Duration, Instant};
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::{
+    confirm, dry_run, fees, fiat, labels, lookup_table, network, nonce, pairing, registry,
+};
+
+/// How often to ask the node whether a sent transaction has landed
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Re-send an unconfirmed transaction this often
+const RESEND_INTERVAL: Duration = Duration::from_secs(10);
+/// Durable nonce transactions never expire, so give up waiting after this
+const DURABLE_CONFIRM_TIMEOUT: Duration = Duration::from_secs(90);
+/// First wait after a failed send; doubles on every retry
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Whoever pays the fees when the device account doesn't
+pub enum FeePayer {
+    /// A local keypair file, which signs without asking
+    Keypair(Keypair),
+    /// A second ESP32, whose BOOT button has to be pressed too
+    Device(Device, Pubkey),
+}
+
+impl FeePayer {
+    pub fn pubkey(&self) -> Pubkey {
+        match self {
+            FeePayer::Keypair(keypair) => keypair.pubkey(),
+            FeePayer::Device(_, pubkey) => *pubkey,
+        }
+    }
+
+    fn sign(
+        &mut self,
+        message: &[u8],
+        summary: &[String],
+        labels: &[TokenLabel],
+    ) -> Result<Signature> {
+        match self {
+            FeePayer::Keypair(keypair) => Ok(keypair.sign_message(message)),
+            FeePayer::Device(device, pubkey) => {
+                println!("Now the fee payer, ESP32 {}, has to sign", pubkey);
+                confirm::sign_labeled(device, message, summary, labels)
+            }
+        }
+    }
+}
+
+/// The configured fee payer keypair, if any
+pub fn fee_payer_keypair(settings: &Settings) -> Result<Option<Keypair>> {
+    settings
+        .fee_payer
+        .as_ref()
+        .map(|path| {
+            read_keypair_file(path)
+                .map_err(|e| anyhow!("Failed to read fee payer keypair {}: {}", path.display(), e))
+        })
+        .transpose()
+}
+
+/// The configured fee payer, if any, connected and checked when it is a
+/// device. Without one the device account pays its own fees.
+pub fn load_fee_payer(settings: &Settings) -> Result<Option<FeePayer>> {
+    if let Some(keypair) = fee_payer_keypair(settings)? {
+        return Ok(Some(FeePayer::Keypair(keypair)));
+    }
+    let Some(selector) = &settings.fee_payer_device else {
+        return Ok(None);
+    };
+    let mut device = if settings.dry_run {
+        Device::offline(registry::remembered_pubkey(Some(selector))?)
+    } else {
+        let mut device = registry::connect(selector, settings.baud)?;
+        device.check_compatible()?;
+        pairing::authenticate(&mut device)?;
+        device
+    };
+    let pubkey = device.pubkey()?;
+    Ok(Some(FeePayer::Device(device, pubkey)))
+}
+
+/// The configured fee payer's pubkey, without connecting to it when it is a
+/// device (the registry remembers its key)
+pub fn fee_payer_pubkey(settings: &Settings) -> Result<Option<Pubkey>> {
+    match &settings.fee_payer_device {
+        Some(selector) => registry::remembered_pubkey(Some(selector)).map(Some),
+        None => Ok(fee_payer_keypair(settings)?.map(|keypair| keypair.pubkey())),
+    }
+}
+
+/// Builds a transaction from `instructions`, has the device (`device_key`,
+/// after a BOOT button press) and the fee payer sign it, and submits it. With
+/// `nonce_account` the transaction uses that durable nonce instead of a recent
+/// blockhash. If the blockhash expires before the transaction confirms, it is
+/// rebuilt and signed again, up to `max_retries` more times. A dry run
+/// prints the transaction instead and returns `None`. Moving more than the
+/// network's threshold needs `--yes`.
+pub fn sign_and_submit(
+    client: &RpcClient,
+    settings: &Settings,
+    device: &mut Device,
+    device_key: &Pubkey,
+    mut fee_payer: Option<&mut FeePayer>,
+    nonce_account: Option<&Pubkey>,
+    instructions: &[Instruction],
+) -> Result<Option<Signature>> {
+    let payer = fee_payer.as_ref().map_or(*device_key, |p| p.pubkey());
+    if fee_payer.is_some() && payer == *device_key {
+        return Err(anyhow!(
+            "The fee payer is the signing device itself; drop the fee payer setting"
+        ));
+    }
+    let attempts = settings.max_retries + 1;
+    for attempt in 1..=attempts {
+        if attempt > 1 {
+            println!(
+                "Rebuilding the transaction (attempt {} of {})",
+                attempt, attempts
+            );
+        }
+        let message = new_message(client, settings, instructions, &payer, nonce_account)?;
+        if let Some(stranger) = message
+            .signer_keys()
+            .into_iter()
+            .find(|key| *key != device_key && *key != &payer)
+        {
+            return Err(anyhow!(
+                "{} would have to sign too; only the device and fee payer can",
+                stranger
+            ));
+        }
+
+        // Create a VersionedTransaction with the message and empty signature slots
+        let signatures =
+            vec![Signature::default(); message.header.num_required_signatures as usize];
+        let mut transaction = VersionedTransaction {
+            signatures,
+            message: lookup_table::fit(client, settings, message)?,
+        };
+        if settings.dry_run {
+            dry_run::report(settings, &transaction, Some(device_key));
+            return Ok(None);
+        }
+        network::check_message(settings, &transaction.message, device_key)?;
+        let message_bytes = transaction.message.serialize();
+        let prices = fiat::Prices::for_message(settings, &transaction.message);
+        let mut summary = confirm::describe_transaction(&transaction.message, &prices);
+        summary.extend(fees::preview(
+            client,
+            &transaction.message,
+            device_key,
+            &prices,
+        ));
+        let labels = labels::for_message(settings, &transaction.message);
+
+        let slot = signer_slot(&transaction, device_key)?;
+        transaction.signatures[slot] =
+            confirm::sign_labeled(device, &message_bytes, &summary, &labels)?;
+
+        if let Some(fee_payer) = fee_payer.as_deref_mut() {
+            let slot = signer_slot(&transaction, &payer)?;
+            transaction.signatures[slot] = fee_payer.sign(&message_bytes, &summary, &labels)?;
+        }
+
+        match submit(client, settings, &transaction)? {
+            Outcome::Confirmed(signature) => return Ok(Some(signature)),
+            Outcome::Expired(signature) => {
+                println!("{} expired before it was confirmed", signature)
+            }
+        }
+    }
+    Err(anyhow!(
+        "Transaction not confirmed after {} attempt(s)",
+        attempts
+    ))
+}
+
+/// Transfers of `lamports` from `from` to each of `to`, announced on stdout
+pub fn transfer_instructions(from: &Pubkey, to: &[Pubkey], lamports: u64) -> Vec<Instruction> {
+    match to {
+        [to] => println!("Sending {} lamports from {} to {}", lamports, from, to),
+        _ => println!(
+            "Sending {} lamports from {} to each of {} recipients",
+            lamports,
+            from,
+            to.len()
+        ),
+    }
+    to.iter()
+        .map(|to| system_instruction::transfer(from, to, lamports))
+        .collect()
+}
+
+/// A message over the latest blockhash at the client's commitment, or, with
+/// `nonce_account`, over that account's durable nonce (advancing it first).
+/// Compute budget instructions go in front of `instructions`.
+pub fn new_message(
+    client: &RpcClient,
+    settings: &Settings,
+    instructions: &[Instruction],
+    payer: &Pubkey,
+    nonce_account: Option<&Pubkey>,
+) -> Result<Message> {
+    match nonce_account {
+        Some(nonce_account) => {
+            let (nonce, authority) = nonce::durable_blockhash(client, nonce_account)?;
+            let advance = system_instruction::advance_nonce_account(nonce_account, &authority);
+            let estimated: Vec<Instruction> = std::iter::once(advance)
+                .chain(instructions.iter().cloned())
+                .collect();
+            let mut budgeted = fees::compute_budget(client, settings, &estimated, payer)?;
+            budgeted.extend_from_slice(instructions);
+            let mut message =
+                Message::new_with_nonce(budgeted, Some(payer), nonce_account, &authority);
+            message.recent_blockhash = nonce;
+            Ok(message)
+        }
+        None => {
+            let mut budgeted = fees::compute_budget(client, settings, instructions, payer)?;
+            budgeted.extend_from_slice(instructions);
+            let recent_blockhash = client.get_latest_blockhash()?;
+            Ok(Message::new_with_blockhash(
+                &budgeted,
+                Some(payer),
+                &recent_blockhash,
+            ))
+        }
+    }
+}
+
+/// How a submitted transaction ended up
+pub enum Outcome {
+    /// Reached the configured commitment
+    Confirmed(Signature),
+    /// Its blockhash expired (or, for durable nonce transactions, the wait
+    /// timed out) before it landed
+    Expired(Signature),
+}
+
+/// Runs the signed transaction through simulateTransaction and prints its logs
+/// and compute usage. A failing simulation is an error unless
+/// `allow_failed_simulation` is set.
+pub fn simulate(
+    client: &RpcClient,
+    settings: &Settings,
+    transaction: &VersionedTransaction,
+) -> Result<()> {
+    let config = RpcSimulateTransactionConfig {
+        sig_verify: true,
+        commitment: Some(settings.commitment),
+        ..Default::default()
+    };
+    let result = client
+        .simulate_transaction_with_config(transaction, config)
+        .map_err(|e| anyhow!("Simulation request failed: {}", e))?
+        .value;
+
+    for line in result.logs.iter().flatten() {
+        tracing::debug!("Simulation log: {}", line);
+    }
+    if let Some(units) = result.units_consumed {
+        tracing::info!("Simulation consumed {} compute units", units);
+    }
+    match result.err {
+        None => Ok(()),
+        Some(err) if settings.allow_failed_simulation => {
+            tracing::warn!("Simulation failed ({}); sending anyway", err);
+            Ok(())
+        }
+        Some(err) => Err(anyhow!(
+            "Simulation failed, not sending: {} (pass --allow-failed-simulation to send anyway)",
+            err
+        )),
+    }
+}
+
+/// Sends a fully signed transaction and waits until it reaches the configured
+/// commitment, re-sending it now and then in case a node dropped it.
+/// Transactions that land but fail are an error, and so are ones that fail
+/// simulation first (see `simulate`).
+pub fn submit(
+    client: &RpcClient,
+    settings: &Settings,
+    transaction: &VersionedTransaction,
+) -> Result<Outcome> {
+    simulate(client, settings, transaction)?;
+
+    let config = RpcSendTransactionConfig {
+        skip_preflight: settings.skip_preflight,
+        preflight_commitment: Some(settings.commitment.commitment),
+        ..Default::default()
+    };
+    let signature = send_with_backoff(client, settings, transaction, config)?;
+    println!("Transaction sent with signature: {}", signature);
+
+    let durable = transaction.uses_durable_nonce();
+    let blockhash = *transaction.message.recent_blockhash();
+    let started = Instant::now();
+    let mut last_send = Instant::now();
+    loop {
+        std::thread::sleep(STATUS_POLL_INTERVAL);
+        if let Some(status) = signature_status(client, &signature)? {
+            if let Some(err) = status.err {
+                return Err(anyhow!("Transaction {} failed: {}", signature, err));
+            }
+            if status.satisfies_commitment(settings.commitment) {
+                println!(
+                    "Transaction confirmed ({:?}, slot {})",
+                    settings.commitment.commitment, status.slot
+                );
+                return Ok(Outcome::Confirmed(signature));
+            }
+            // Landed; waiting for the commitment level
+            continue;
+        }
+
+        let expired = if durable {
+            started.elapsed() > DURABLE_CONFIRM_TIMEOUT
+        } else {
+            !client.is_blockhash_valid(&blockhash, CommitmentConfig::processed())?
+        };
+        if expired {
+            // It may have landed in the meantime
+            if signature_status(client, &signature)?.is_some() {
+                continue;
+            }
+            return Ok(Outcome::Expired(signature));
+        }
+        if last_send.elapsed() >= RESEND_INTERVAL {
+            let resend = RpcSendTransactionConfig {
+                skip_preflight: true,
+                ..config
+            };
+            let _ = client.send_transaction_with_config(transaction, resend);
+            last_send = Instant::now();
+        }
+    }
+}
+
+/// Sends once, retrying transport errors up to `max_retries` times with
+/// exponential backoff. A transaction the node rejects in preflight isn't
+/// retried.
+fn send_with_backoff(
+    client: &RpcClient,
+    settings: &Settings,
+    transaction: &VersionedTransaction,
+    config: RpcSendTransactionConfig,
+) -> Result<Signature> {
+    let mut delay = INITIAL_BACKOFF;
+    let mut retries = 0;
+    loop {
+        match client.send_transaction_with_config(transaction, config) {
+            Ok(signature) => return Ok(signature),
+            Err(e) if e.get_transaction_error().is_some() => {
+                return Err(anyhow!("Transaction rejected in preflight: {}", e))
+            }
+            Err(e) if retries < settings.max_retries => {
+                tracing::warn!("Send failed ({}); retrying in {:?}", e, delay);
+                std::thread::sleep(delay);
+                delay *= 2;
+                retries += 1;
+            }
+            Err(e) => return Err(anyhow!("Failed to send transaction: {}", e)),
+        }
+    }
+}
+
+fn signature_status(
+    client: &RpcClient,
+    signature: &Signature,
+) -> Result<Option<TransactionStatus>> {
+    Ok(client
+        .get_signature_statuses(&[*signature])?
+        .value
+        .into_iter()
+        .next()
+        .flatten())
+}
+
+/// Index of `signer`'s entry in the transaction's signature list
+pub fn signer_slot(transaction: &VersionedTransaction, signer: &Pubkey) -> Result<usize> {
+    transaction
+        .message
+        .static_account_keys()
+        .iter()
+        .take(transaction.signatures.len())
+        .position(|key| key == signer)
+        .ok_or_else(|| anyhow!("{} is not a signer of this transaction", signer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use signer_core::tx_introspection::{self, TransactionType};
+    use solana_sdk::{
+        hash::Hash,
+        instruction::AccountMeta,
+        message::{v0, VersionedMessage},
+        signer::keypair::keypair_from_seed,
+    };
+
+    fn keypair(seed: [u8; 32]) -> Keypair {
+        keypair_from_seed(&seed).expect("32-byte seed")
+    }
+
+    fn pubkey() -> impl Strategy<Value = Pubkey> {
+        any::<[u8; 32]>().prop_map(Pubkey::new_from_array)
+    }
+
+    /// Instructions for arbitrary programs, with up to a few accounts each
+    fn instruction() -> impl Strategy<Value = Instruction> {
+        (
+            pubkey(),
+            prop::collection::vec((pubkey(), any::<bool>()), 0..4),
+            prop::collection::vec(any::<u8>(), 0..64),
+        )
+            .prop_map(|(program_id, accounts, data)| {
+                let accounts = accounts
+                    .into_iter()
+                    .map(|(key, writable)| match writable {
+                        true => AccountMeta::new(key, false),
+                        false => AccountMeta::new_readonly(key, false),
+                    })
+                    .collect();
+                Instruction::new_with_bytes(program_id, &data, accounts)
+            })
+    }
+
+    proptest! {
+        /// What the device decodes from a transfer is what was built
+        #[test]
+        fn transfers_decode_as_built(
+            from_seed in any::<[u8; 32]>(),
+            payer_seed in prop::option::of(any::<[u8; 32]>()),
+            to in prop::collection::hash_set(pubkey(), 1..8),
+            lamports in any::<u64>(),
+            blockhash in any::<[u8; 32]>(),
+        ) {
+            let from = keypair(from_seed).pubkey();
+            let payer = payer_seed.map_or(from, |seed| keypair(seed).pubkey());
+            let to: Vec<Pubkey> = to.into_iter().filter(|to| *to != from && *to != payer).collect();
+            let message = Message::new_with_blockhash(
+                &transfer_instructions(&from, &to, lamports),
+                Some(&payer),
+                &Hash::new_from_array(blockhash),
+            );
+
+            let info = tx_introspection::introspect_transaction(&message.serialize(), &from.to_bytes())
+                .expect("a legacy transfer parses");
+            prop_assert_eq!(info.version, None);
+            prop_assert_eq!(info.fee_payer, payer.to_bytes());
+            prop_assert_eq!(info.fee_payer_is_signer, payer == from);
+            prop_assert_eq!(info.blockhash, blockhash);
+            prop_assert_eq!(
+                info.num_signatures_required,
+                message.header.num_required_signatures
+            );
+            prop_assert_eq!(info.num_signatures_required, if payer == from { 1 } else { 2 });
+            let expected: Vec<_> = to
+                .iter()
+                .map(|to| TransactionType::SystemTransfer {
+                    from: from.to_bytes(),
+                    to: to.to_bytes(),
+                    lamports,
+                })
+                .collect();
+            prop_assert_eq!(info.instructions, expected);
+        }
+
+        /// The device's parser reads legacy and v0 messages as solana-sdk
+        /// compiled them
+        #[test]
+        fn messages_parse_as_compiled(
+            payer in pubkey(),
+            instructions in prop::collection::vec(instruction(), 0..6),
+            blockhash in any::<[u8; 32]>(),
+            versioned in any::<bool>(),
+        ) {
+            let blockhash = Hash::new_from_array(blockhash);
+            let message = match versioned {
+                false => VersionedMessage::Legacy(Message::new_with_blockhash(
+                    &instructions,
+                    Some(&payer),
+                    &blockhash,
+                )),
+                true => VersionedMessage::V0(
+                    v0::Message::try_compile(&payer, &instructions, &[], blockhash)
+                        .expect("no lookup tables to miss"),
+                ),
+            };
+
+            let parsed = tx_introspection::parse_message(&message.serialize())
+                .expect("a compiled message parses");
+            prop_assert_eq!(parsed.version, versioned.then_some(0));
+            let header = message.header();
+            prop_assert_eq!(parsed.header.num_required_signatures, header.num_required_signatures);
+            prop_assert_eq!(
+                parsed.header.num_readonly_signed_accounts,
+                header.num_readonly_signed_accounts
+            );
+            prop_assert_eq!(
+                parsed.header.num_readonly_unsigned_accounts,
+                header.num_readonly_unsigned_accounts
+            );
+            let keys: Vec<[u8; 32]> = message
+                .static_account_keys()
+                .iter()
+                .map(|key| key.to_bytes())
+                .collect();
+            prop_assert_eq!(&parsed.account_keys, &keys);
+            prop_assert_eq!(parsed.account_keys[0], payer.to_bytes());
+            prop_assert_eq!(parsed.recent_blockhash, blockhash.to_bytes());
+            prop_assert_eq!(parsed.instructions.len(), message.instructions().len());
+            for (parsed, compiled) in parsed.instructions.iter().zip(message.instructions()) {
+                prop_assert_eq!(parsed.program_id_index, compiled.program_id_index);
+                prop_assert_eq!(&parsed.accounts, &compiled.accounts);
+                prop_assert_eq!(&parsed.data, &compiled.data);
+            }
+            prop_assert!(parsed.address_table_lookups.is_empty());
+        }
+
+        /// Signatures over the serialized message, as the device makes them,
+        /// verify in the slot `signer_slot` picks, whoever pays the fee
+        #[test]
+        fn signatures_verify_in_their_slot(
+            from_seed in any::<[u8; 32]>(),
+            payer_seed in prop::option::of(any::<[u8; 32]>()),
+            to in pubkey(),
+            lamports in any::<u64>(),
+            blockhash in any::<[u8; 32]>(),
+        ) {
+            let from = keypair(from_seed);
+            let payer = payer_seed.map(keypair);
+            let fee_payer = payer.as_ref().map_or(from.pubkey(), Signer::pubkey);
+            prop_assume!(to != from.pubkey() && to != fee_payer);
+            let message = Message::new_with_blockhash(
+                &transfer_instructions(&from.pubkey(), &[to], lamports),
+                Some(&fee_payer),
+                &Hash::new_from_array(blockhash),
+            );
+            let message = VersionedMessage::Legacy(message);
+            let bytes = message.serialize();
+            let required = usize::from(message.header().num_required_signatures);
+            let mut transaction = VersionedTransaction {
+                signatures: vec![Signature::default(); required],
+                message,
+            };
+
+            let mut signers = vec![&from];
+            signers.extend(payer.as_ref());
+            for signer in signers {
+                let slot = signer_slot(&transaction, &signer.pubkey()).expect("a required signer");
+                prop_assert_eq!(
+                    transaction.message.static_account_keys()[slot],
+                    signer.pubkey()
+                );
+                transaction.signatures[slot] = signer.sign_message(&bytes);
+            }
+            prop_assert_eq!(signer_slot(&transaction, &fee_payer).ok(), Some(0));
+            prop_assert!(transaction.verify_with_results().into_iter().all(|ok| ok));
+            prop_assert!(signer_slot(&transaction, &to).is_err());
+
+            let wire = bincode::serialize(&transaction).expect("serializes");
+            let decoded: VersionedTransaction = bincode::deserialize(&wire).expect("deserializes");
+            prop_assert_eq!(decoded, transaction);
+        }
+    }
+}
@@ -0,0 +1,688 @@
+//! A localhost HTTP API in front of the device, so browser dApps (through a
+//! wallet-adapter / wallet-standard bridge) can ask the ESP32 for signatures.
+//! Every request needs the bearer token printed at startup, and nothing is
+//! signed until the BOOT button is pressed.
+//!
+//! - `GET  /pubkey`           -> `{"pubkey": "<base58>"}`
+//! - `POST /sign-transaction` `{"transaction": "<base64>"}` -> `{"signature": "<base58>", "transaction": "<base64>"}`
+//! - `POST /sign-message`     `{"message": "<base64>"}` -> `{"signature": "<base58>"}`
+//!
+//! `GET /ws?token=<TOKEN>` upgrades to a WebSocket for extensions and web
+//! wallets that want to stream requests and hear how each one is going:
+//!
+//! - `{"id": 1, "method": "pubkey"}` -> `{"id": 1, "status": "ok", "pubkey": ...}`
+//! - `{"id": 2, "method": "signTransaction", "transaction": "<base64>"}` or
+//!   `{"id": 3, "method": "signMessage", "message": "<base64>"}` ->
+//!   `{"id": 2, "status": "waiting-for-button"}`, then `"signed"` (with the
+//!   same fields as over HTTP) or `"rejected"` (with `error`)
+//! - malformed requests get `{"id": ..., "status": "error", "error": ...}`
+//!
+//! Browsers can't set headers on a WebSocket, so the token goes in the query
+//! string, and a page's `Origin` has to be on the `--allow-origin` list.
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use solana_sdk::{pubkey::Pubkey, signature::Signature, transaction::VersionedTransaction};
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use tiny_http::{Header, Method, ReadWrite, Request, Response, Server};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+use crate::confirm;
+use crate::device::Device;
+use crate::fiat::Prices;
+use crate::message;
+use crate::metrics::{self, Outcome, Timer, METRICS};
+use crate::network::Threshold;
+use crate::notify::{Notifier, Sink, Templates};
+use crate::policy;
+use crate::send;
+
+/// Largest request body accepted; a transaction is at most 1232 bytes
+pub const MAX_BODY: u64 = 64 * 1024;
+
+/// Why a sign-message request holding a transaction message is turned away
+pub const NOT_A_MESSAGE: &str =
+    "that is a transaction message; send the transaction to sign-transaction";
+
+#[derive(Deserialize)]
+struct SignTransaction {
+    /// base64 of the serialized (legacy or versioned) transaction
+    transaction: String,
+}
+
+#[derive(Deserialize)]
+struct SignMessage {
+    /// base64 of the message bytes
+    message: String,
+}
+
+/// One WebSocket request; `id` is echoed back on every reply to it
+#[derive(Deserialize)]
+struct SocketRequest {
+    #[serde(default)]
+    id: Value,
+    #[serde(flatten)]
+    call: SocketCall,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "method", rename_all = "camelCase")]
+enum SocketCall {
+    Pubkey,
+    SignTransaction(SignTransaction),
+    SignMessage(SignMessage),
+}
+
+/// Set by `daemon`: while either flag is, signing requests are turned away
+/// before they reach the device, and `/pubkey` still answers
+pub struct Availability {
+    /// Paused on the control socket
+    pub paused: AtomicBool,
+    /// The device went away and hasn't been reconnected yet
+    pub disconnected: AtomicBool,
+}
+
+pub static AVAILABILITY: Availability = Availability {
+    paused: AtomicBool::new(false),
+    disconnected: AtomicBool::new(false),
+};
+
+impl Availability {
+    /// `running`, `paused` or `disconnected`
+    pub fn state(&self) -> &'static str {
+        if self.disconnected.load(Ordering::Relaxed) {
+            "disconnected"
+        } else if self.paused.load(Ordering::Relaxed) {
+            "paused"
+        } else {
+            "running"
+        }
+    }
+}
+
+/// An error reply: HTTP status and message. 4xx is a bad request, 5xx means
+/// the device didn't sign (refused, timed out, or unreachable).
+struct Failure(u16, String);
+
+impl From<anyhow::Error> for Failure {
+    fn from(e: anyhow::Error) -> Self {
+        Failure(500, format!("{:#}", e))
+    }
+}
+
+/// Where to listen and who may connect
+pub struct ServeOptions<'a> {
+    /// A loopback address
+    pub listen: &'a str,
+    /// Random and printed when `None`
+    pub token: Option<&'a str>,
+    /// Page origins allowed to open the WebSocket
+    pub allowed_origins: &'a [String],
+    /// Where to push pending requests and their outcomes
+    pub notify: &'a [Sink],
+    pub templates: Templates,
+    /// A loopback address for `GET /metrics`, if any
+    pub metrics_listen: Option<&'a str>,
+    /// What each transaction is held to, as for a command
+    pub threshold: Threshold,
+}
+
+/// Serves the API until interrupted
+pub fn serve(device: &mut Device, options: &ServeOptions) -> Result<()> {
+    serve_shared(&Mutex::new(device), options)
+}
+
+/// `serve` on a device others (`daemon`) can reach in between requests
+pub fn serve_shared(device: &Mutex<&mut Device>, options: &ServeOptions) -> Result<()> {
+    let address = loopback_address(options.listen)?;
+    let token = token_or_random(options.token);
+    if let Some(listen) = options.metrics_listen {
+        metrics::serve_http(listen, token.clone())?;
+    }
+
+    let pubkey = lock(device).pubkey()?;
+    let notifier = Notifier::start(options.notify.to_vec(), options.templates.clone(), pubkey);
+    for sink in options.notify {
+        println!("Notifying {}", sink);
+    }
+    let server =
+        Server::http(address).map_err(|e| anyhow!("Failed to listen on {}: {}", address, e))?;
+    println!("Signing API for {} on http://{}", pubkey, address);
+    println!("Authorization: Bearer {}", token);
+
+    // One device and one BOOT button: requests take turns on it, while each
+    // WebSocket gets a thread so it can stay open
+    std::thread::scope(|scope| {
+        for mut request in server.incoming_requests() {
+            if *request.method() == Method::Options {
+                // CORS preflight from a browser page
+                let _ = request.respond(with_cors(Response::empty(204)));
+                continue;
+            }
+            let reply = if request.url().split('?').next() == Some("/ws") {
+                match accept_websocket(&request, &token, options.allowed_origins) {
+                    Ok((key, origin)) => {
+                        tracing::info!("WebSocket opened by {}", origin);
+                        let accept = header("Sec-WebSocket-Accept", &key);
+                        let socket =
+                            request.upgrade("websocket", Response::empty(101).with_header(accept));
+                        let (pubkey, notifier) = (&pubkey, &notifier);
+                        let threshold = &options.threshold;
+                        scope.spawn(move || {
+                            websocket(device, pubkey, notifier, threshold, socket, &origin)
+                        });
+                        continue;
+                    }
+                    Err(failure) => Err(failure),
+                }
+            } else if authorized(&request, &token) {
+                let threshold = &options.threshold;
+                handle(
+                    &mut lock(device),
+                    &pubkey,
+                    &notifier,
+                    threshold,
+                    &mut request,
+                )
+            } else {
+                Err(Failure(401, "missing or wrong bearer token".to_string()))
+            };
+            let (status, body) = match reply {
+                Ok(body) => (200, body),
+                Err(Failure(status, message)) => {
+                    tracing::warn!("{} {}: {}", request.method(), request.url(), message);
+                    (status, json!({ "error": message }))
+                }
+            };
+            let response = Response::from_string(body.to_string())
+                .with_status_code(status)
+                .with_header(header("Content-Type", "application/json"));
+            let _ = request.respond(with_cors(response));
+        }
+    });
+    Ok(())
+}
+
+fn handle(
+    device: &mut Device,
+    pubkey: &Pubkey,
+    notifier: &Notifier,
+    threshold: &Threshold,
+    request: &mut Request,
+) -> Result<Value, Failure> {
+    let origin = origin(request).unwrap_or_else(|| "a local client".to_string());
+    match (request.method(), request.url()) {
+        (Method::Get, "/pubkey") => Ok(json!({ "pubkey": pubkey.to_string() })),
+        (Method::Post, "/sign-transaction") => {
+            let body: SignTransaction = body(request)?;
+            sign_transaction(device, pubkey, notifier, threshold, &body, &origin, || {})
+        }
+        (Method::Post, "/sign-message") => {
+            let body: SignMessage = body(request)?;
+            sign_message(device, pubkey, notifier, &body, &origin, || {})
+        }
+        (_, "/pubkey" | "/sign-transaction" | "/sign-message") => {
+            Err(Failure(405, "method not allowed".to_string()))
+        }
+        (_, url) => Err(Failure(404, format!("no such endpoint {}", url))),
+    }
+}
+
+/// Adds the device's signature to the transaction, if `threshold` allows
+/// it; `waiting` runs just before the device starts waiting for the BOOT
+/// button
+fn sign_transaction(
+    device: &mut Device,
+    pubkey: &Pubkey,
+    notifier: &Notifier,
+    threshold: &Threshold,
+    request: &SignTransaction,
+    origin: &str,
+    waiting: impl FnOnce(),
+) -> Result<Value, Failure> {
+    let bytes = decode(&request.transaction)?;
+    let mut transaction: VersionedTransaction = bincode::deserialize(&bytes)
+        .map_err(|e| Failure(400, format!("not a transaction: {}", e)))?;
+    let slot =
+        send::signer_slot(&transaction, pubkey).map_err(|e| Failure(400, format!("{:#}", e)))?;
+    let message_bytes = transaction.message.serialize();
+
+    let timer = preflight(device, notifier, "transaction", &message_bytes)?;
+    if let Err(e) = threshold.check(&transaction.message, pubkey) {
+        return Err(refused(notifier, timer, Failure(403, format!("{:#}", e))));
+    }
+    println!("Transaction from {}", origin);
+    println!("Press the BOOT button on the ESP32 to sign...");
+    waiting();
+    let summary = confirm::describe_transaction(&transaction.message, &Prices::default());
+    let request = notifier.pending(origin, "transaction", &summary);
+    let signature = notified_sign(device, pubkey, notifier, timer, request, &message_bytes)?;
+    transaction.signatures[slot] = signature;
+    let signed = bincode::serialize(&transaction).map_err(|e| anyhow!(e))?;
+    Ok(json!({
+        "signature": signature.to_string(),
+        "transaction": base64::engine::general_purpose::STANDARD.encode(signed),
+    }))
+}
+
+/// Signs the raw message bytes, unless they are a transaction message;
+/// `waiting` as for `sign_transaction`
+fn sign_message(
+    device: &mut Device,
+    pubkey: &Pubkey,
+    notifier: &Notifier,
+    request: &SignMessage,
+    origin: &str,
+    waiting: impl FnOnce(),
+) -> Result<Value, Failure> {
+    let message = decode(&request.message)?;
+    if message::is_transaction_message(&message) {
+        return Err(Failure(400, NOT_A_MESSAGE.to_string()));
+    }
+    let timer = preflight(device, notifier, "message", &message)?;
+    let shown = match std::str::from_utf8(&message) {
+        Ok(text) => text.to_string(),
+        Err(_) => format!("{} bytes", message.len()),
+    };
+    println!("Message from {}: {}", origin, shown);
+    println!("Press the BOOT button on the ESP32 to sign...");
+    waiting();
+    let request = notifier.pending(origin, "message", &[format!("Message: {}", shown)]);
+    let signature = notified_sign(device, pubkey, notifier, timer, request, &message)?;
+    Ok(json!({ "signature": signature.to_string() }))
+}
+
+/// Counts a request of `kind`, then checks signing is available and makes
+/// the policy check before bothering anyone; a refusal is reported
+fn preflight(
+    device: &mut Device,
+    notifier: &Notifier,
+    kind: &str,
+    message: &[u8],
+) -> Result<Timer<'static>, Failure> {
+    let timer = METRICS.request(kind);
+    let checked = match AVAILABILITY.state() {
+        "running" => {
+            policy::preflight(device, message).map_err(|e| Failure(403, format!("{:#}", e)))
+        }
+        state => Err(Failure(503, format!("signing is {}", state))),
+    };
+    match checked {
+        Ok(()) => Ok(timer),
+        Err(failure) => Err(refused(notifier, timer, failure)),
+    }
+}
+
+/// Reports a request turned away before it reached the device
+fn refused(notifier: &Notifier, timer: Timer, failure: Failure) -> Failure {
+    notifier.rejected(None, &failure.1);
+    timer.finish(Outcome::Rejected);
+    failure
+}
+
+/// Has the device sign `message`, reporting the outcome of `request`
+fn notified_sign(
+    device: &mut Device,
+    pubkey: &Pubkey,
+    notifier: &Notifier,
+    timer: Timer,
+    request: u64,
+    message: &[u8],
+) -> Result<Signature, Failure> {
+    let result = device.sign(message).and_then(|signature| {
+        if signature.verify(pubkey.as_ref(), message) {
+            Ok(signature)
+        } else {
+            Err(anyhow!("ESP32 returned a signature that does not verify"))
+        }
+    });
+    notifier.finished(request, &result);
+    timer.finish(Outcome::of(&result));
+    Ok(result?)
+}
+
+/// Checks a WebSocket handshake's token and origin; returns the
+/// `Sec-WebSocket-Accept` key and the origin to show
+fn accept_websocket(
+    request: &Request,
+    token: &str,
+    allowed_origins: &[String],
+) -> Result<(String, String), Failure> {
+    let given = request
+        .url()
+        .split_once('?')
+        .and_then(|(_, query)| query.split('&').find_map(|p| p.strip_prefix("token=")));
+    if !given.is_some_and(|given| same_token(given, token)) {
+        return Err(Failure(401, "missing or wrong token".to_string()));
+    }
+    let origin = origin(request);
+    if let Some(origin) = &origin {
+        if !allowed_origins.contains(origin) {
+            return Err(Failure(
+                403,
+                format!("origin {} is not on --allow-origin", origin),
+            ));
+        }
+    }
+    let key = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Sec-WebSocket-Key"))
+        .map(|h| derive_accept_key(h.value.as_bytes()))
+        .ok_or_else(|| Failure(400, "not a WebSocket handshake".to_string()))?;
+    Ok((key, origin.unwrap_or_else(|| "a local client".to_string())))
+}
+
+/// Answers requests on one WebSocket until the client goes away
+fn websocket(
+    device: &Mutex<&mut Device>,
+    pubkey: &Pubkey,
+    notifier: &Notifier,
+    threshold: &Threshold,
+    stream: Box<dyn ReadWrite + Send>,
+    origin: &str,
+) {
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+    loop {
+        let text = match socket.read() {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) | Err(_) => break,
+            // Pings are answered by tungstenite itself
+            Ok(_) => continue,
+        };
+        let reply = answer(
+            device,
+            pubkey,
+            notifier,
+            threshold,
+            &text,
+            origin,
+            &mut |update| socket.send(Message::Text(update.to_string())).is_ok(),
+        );
+        let Some(reply) = reply else {
+            break;
+        };
+        if socket.send(Message::Text(reply.to_string())).is_err() {
+            break;
+        }
+    }
+    tracing::info!("WebSocket from {} closed", origin);
+}
+
+/// Answers one request of the WebSocket protocol (`native` speaks it too);
+/// `send` passes the `waiting-for-button` update on and says whether the
+/// client is still there. `None` when it went away.
+pub fn answer(
+    device: &Mutex<&mut Device>,
+    pubkey: &Pubkey,
+    notifier: &Notifier,
+    threshold: &Threshold,
+    text: &str,
+    origin: &str,
+    send: &mut dyn FnMut(&Value) -> bool,
+) -> Option<Value> {
+    let request: SocketRequest = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(e) => return Some(json!({ "id": null, "status": "error", "error": e.to_string() })),
+    };
+
+    let id = request.id;
+    let mut waiting_sent = true;
+    let result = {
+        let mut device = lock(device);
+        let mut waiting = || {
+            waiting_sent = send(&json!({ "id": id, "status": "waiting-for-button" }));
+        };
+        match &request.call {
+            SocketCall::Pubkey => Ok(json!({ "pubkey": pubkey.to_string() })),
+            SocketCall::SignTransaction(call) => sign_transaction(
+                &mut device,
+                pubkey,
+                notifier,
+                threshold,
+                call,
+                origin,
+                &mut waiting,
+            ),
+            SocketCall::SignMessage(call) => {
+                sign_message(&mut device, pubkey, notifier, call, origin, &mut waiting)
+            }
+        }
+    };
+    if !waiting_sent {
+        return None;
+    }
+
+    let mut reply = match result {
+        Ok(Value::Object(fields)) => fields,
+        Ok(_) => unreachable!("replies are JSON objects"),
+        Err(Failure(status, message)) => {
+            tracing::warn!("Request from {}: {}", origin, message);
+            let status = if status >= 500 { "rejected" } else { "error" };
+            let mut fields = serde_json::Map::new();
+            fields.insert("status".to_string(), json!(status));
+            fields.insert("error".to_string(), json!(message));
+            fields
+        }
+    };
+    let status = match &request.call {
+        SocketCall::Pubkey => "ok",
+        _ => "signed",
+    };
+    reply.entry("status").or_insert(json!(status));
+    reply.insert("id".to_string(), id);
+    Some(Value::Object(reply))
+}
+
+/// Parses `listen`, which has to be a loopback address
+pub fn loopback_address(listen: &str) -> Result<SocketAddr> {
+    let address: SocketAddr = listen
+        .parse()
+        .map_err(|e| anyhow!("invalid listen address '{}': {}", listen, e))?;
+    if !address.ip().is_loopback() {
+        return Err(anyhow!(
+            "refusing to listen on {}; the signing API is for this machine only",
+            address
+        ));
+    }
+    Ok(address)
+}
+
+pub fn token_or_random(token: Option<&str>) -> String {
+    match token {
+        Some(token) => token.to_string(),
+        None => bs58::encode(rand::thread_rng().gen::<[u8; 24]>()).into_string(),
+    }
+}
+
+pub fn lock<'a, 'b>(device: &'a Mutex<&'b mut Device>) -> MutexGuard<'a, &'b mut Device> {
+    // A panicked request leaves the device itself usable
+    device.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn origin(request: &Request) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Origin"))
+        .map(|h| h.value.to_string())
+}
+
+pub fn authorized(request: &Request, token: &str) -> bool {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+        .is_some_and(|given| same_token(given, token))
+}
+
+/// Compares without an early exit on the first differing byte
+pub fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn body<T: for<'de> Deserialize<'de>>(request: &mut Request) -> Result<T, Failure> {
+    let mut text = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY)
+        .read_to_string(&mut text)
+        .map_err(|e| Failure(400, format!("unreadable body: {}", e)))?;
+    serde_json::from_str(&text).map_err(|e| Failure(400, format!("bad request body: {}", e)))
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, Failure> {
+    base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .map_err(|e| Failure(400, format!("invalid base64: {}", e)))
+}
+
+fn with_cors<R: Read>(response: Response<R>) -> Response<R> {
+    response
+        .with_header(header("Access-Control-Allow-Origin", "*"))
+        .with_header(header(
+            "Access-Control-Allow-Headers",
+            "Authorization, Content-Type",
+        ))
+        .with_header(header("Access-Control-Allow-Methods", "GET, POST"))
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("static header is valid")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::network;
+    use solana_sdk::{message::Message, native_token::LAMPORTS_PER_SOL, system_instruction};
+    use std::collections::HashMap;
+
+    /// A transfer's message, base64 as a client would send it
+    pub(crate) fn transfer_message(from: &Pubkey) -> String {
+        let transfer = system_instruction::transfer(from, &Pubkey::new_unique(), 1_000_000_000);
+        let message = Message::new(&[transfer], Some(from));
+        base64::engine::general_purpose::STANDARD.encode(message.serialize())
+    }
+
+    /// `mainnet-beta`'s threshold, with or without `--yes`
+    pub(crate) fn mainnet(yes: bool) -> Threshold {
+        Threshold {
+            profile: network::resolve("mainnet-beta", &HashMap::new()).unwrap(),
+            yes,
+        }
+    }
+
+    fn notifier(pubkey: Pubkey) -> Notifier {
+        Notifier::start(Vec::new(), Templates::new(&HashMap::new()).unwrap(), pubkey)
+    }
+
+    #[test]
+    fn sign_message_refuses_a_transaction_message() {
+        let pubkey = Pubkey::new_unique();
+        let mut device = Device::offline(pubkey);
+        let request = SignMessage {
+            message: transfer_message(&pubkey),
+        };
+        let mut waited = false;
+        let result = sign_message(
+            &mut device,
+            &pubkey,
+            &notifier(pubkey),
+            &request,
+            "a test",
+            || waited = true,
+        );
+        let Err(Failure(status, message)) = result else {
+            panic!("a transaction message was accepted");
+        };
+        assert_eq!((status, message.as_str()), (400, NOT_A_MESSAGE));
+        assert!(!waited);
+    }
+
+    #[test]
+    fn websocket_refuses_a_transaction_message() {
+        let pubkey = Pubkey::new_unique();
+        let mut offline = Device::offline(pubkey);
+        let device = Mutex::new(&mut offline);
+        let text =
+            json!({ "id": 7, "method": "signMessage", "message": transfer_message(&pubkey) });
+        let mut updates = Vec::new();
+        let reply = answer(
+            &device,
+            &pubkey,
+            &notifier(pubkey),
+            &mainnet(false),
+            &text.to_string(),
+            "a test",
+            &mut |update| {
+                updates.push(update.clone());
+                true
+            },
+        )
+        .unwrap();
+        assert_eq!(reply["id"], 7);
+        assert_eq!(reply["status"], "error");
+        assert_eq!(reply["error"], NOT_A_MESSAGE);
+        assert!(updates.is_empty());
+    }
+
+    #[test]
+    fn large_transfers_need_yes() {
+        let pubkey = Pubkey::new_unique();
+        let transfer =
+            system_instruction::transfer(&pubkey, &Pubkey::new_unique(), 2 * LAMPORTS_PER_SOL);
+        let transaction = VersionedTransaction {
+            signatures: vec![Signature::default()],
+            message: solana_sdk::message::VersionedMessage::Legacy(Message::new(
+                &[transfer],
+                Some(&pubkey),
+            )),
+        };
+        let text = json!({
+            "id": 1,
+            "method": "signTransaction",
+            "transaction": base64::engine::general_purpose::STANDARD
+                .encode(bincode::serialize(&transaction).unwrap()),
+        })
+        .to_string();
+
+        for (yes, status) in [(false, "error"), (true, "rejected")] {
+            let mut offline = Device::offline(pubkey);
+            let device = Mutex::new(&mut offline);
+            let mut waited = false;
+            let reply = answer(
+                &device,
+                &pubkey,
+                &notifier(pubkey),
+                &mainnet(yes),
+                &text,
+                "a test",
+                &mut |_| {
+                    waited = true;
+                    true
+                },
+            )
+            .unwrap();
+            // Without --yes the device is never asked; with it, the offline
+            // stand-in is asked and can't sign
+            assert_eq!(reply["status"], status, "{}", reply);
+            assert_eq!(waited, yes);
+            if !yes {
+                assert!(reply["error"].as_str().unwrap().contains("--yes"));
+            }
+        }
+    }
+}
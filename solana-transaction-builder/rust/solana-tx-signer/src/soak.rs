@@ -0,0 +1,231 @@
+//! `soak`: hours of random, valid, read-only traffic, to catch what only
+//! shows up over time: heap leaks, NVS entries used up, crashes and resets,
+//! and the host and device falling out of step.
+//!
+//! Commands go out raw (`Device::send_raw`), without the transport's retry
+//! or its discarding of stale input, so a lost reply is counted instead of
+//! papered over and a late one shows up as the wrong answer to the next
+//! command: a desync, which ends the run. `DIAG` is sampled every
+//! `--diag-every` seconds for the heap, NVS and crash counters.
+
+use anyhow::{anyhow, Result};
+use clap::Args;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use signer_protocol::command::Command;
+use signer_protocol::error::ErrorCode;
+use signer_protocol::response::{self, Response};
+use std::time::{Duration, Instant};
+
+use crate::device::{Device, REPLY_IDLE_SECS};
+
+/// Lets a light-sleeping device wake before the command line arrives
+const WAKE_DELAY: Duration = Duration::from_millis(10);
+
+/// The traffic: commands that only read
+const COMMANDS: &[Command<'static>] = &[
+    Command::Ping,
+    Command::GetPubkey,
+    Command::GetInfo,
+    Command::GetDeviceId,
+    Command::GetFeatures,
+    Command::TxInfo,
+    Command::GetPolicy,
+    Command::OtpStatus,
+    Command::Telemetry,
+];
+
+#[derive(Args, Debug)]
+pub struct SoakArgs {
+    /// How long to run, in minutes
+    #[arg(long, default_value_t = 120)]
+    minutes: u64,
+    /// Seconds between DIAG samples
+    #[arg(long, default_value_t = 60)]
+    diag_every: u64,
+    /// Fail if free heap ends up this many bytes below where it started
+    #[arg(long, default_value_t = 4096)]
+    max_heap_drop: u64,
+    /// Pause between commands, in milliseconds
+    #[arg(long, default_value_t = 0)]
+    pause_ms: u64,
+    /// Seed for the command sequence [default: random, printed]
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+/// One DIAG reading
+#[derive(Clone, Copy, Default)]
+struct Sample {
+    heap_free: u64,
+    heap_min: u64,
+    nvs_free: u64,
+    crashes: u64,
+}
+
+impl Sample {
+    fn parse(fields: &str) -> Self {
+        let mut sample = Sample::default();
+        for (key, value) in response::fields(fields) {
+            let value = value.parse().unwrap_or(0);
+            match key {
+                "heap_free" => sample.heap_free = value,
+                "heap_min" => sample.heap_min = value,
+                "nvs_free" => sample.nvs_free = value,
+                "crashes" => sample.crashes = value,
+                _ => {}
+            }
+        }
+        sample
+    }
+}
+
+/// Sends random read-only commands until the time is up, failing on a
+/// desync straight away and on lost replies, crashes or a shrinking heap
+/// at the end
+pub fn run(device: &mut Device, args: &SoakArgs) -> Result<()> {
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let pubkey = device.pubkey()?.to_string();
+    let first = diag(device)?;
+    println!(
+        "Soaking for {} minutes with seed {}; heap {} bytes free, {} NVS entries free, {} crashes",
+        args.minutes, seed, first.heap_free, first.nvs_free, first.crashes
+    );
+    println!(
+        "{:>8} {:>10} {:>6} {:>10} {:>10} {:>8} {:>7}",
+        "ELAPSED", "COMMANDS", "MISSED", "HEAP FREE", "HEAP MIN", "NVS FREE", "CRASHES"
+    );
+
+    let start = Instant::now();
+    let end = start + Duration::from_secs(args.minutes * 60);
+    let diag_every = Duration::from_secs(args.diag_every.max(1));
+    let mut next_diag = start + diag_every;
+    let mut sent = 0u64;
+    let mut missed = 0u64;
+    let mut last = first;
+
+    while Instant::now() < end {
+        let command = COMMANDS[rng.gen_range(0..COMMANDS.len())];
+        device.send_raw(b"")?;
+        std::thread::sleep(WAKE_DELAY);
+        device.send_raw(command.to_string().as_bytes())?;
+        sent += 1;
+        match device.receive(REPLY_IDLE_SECS) {
+            Ok(reply) => check_reply(&command, &reply, &pubkey).map_err(|e| {
+                anyhow!(
+                    "desync after {} commands ({:?} elapsed, seed {}): {}",
+                    sent,
+                    start.elapsed(),
+                    seed,
+                    e
+                )
+            })?,
+            Err(e) => {
+                missed += 1;
+                tracing::warn!("No reply to {}: {:#}", command.name(), e);
+            }
+        }
+
+        if Instant::now() >= next_diag {
+            last = diag(device)?;
+            print_row(start.elapsed(), sent, missed, &last);
+            next_diag += diag_every;
+        }
+        if args.pause_ms > 0 {
+            std::thread::sleep(Duration::from_millis(args.pause_ms));
+        }
+    }
+
+    last = diag(device).unwrap_or(last);
+    print_row(start.elapsed(), sent, missed, &last);
+
+    let mut problems = Vec::new();
+    if missed > 0 {
+        problems.push(format!("{} of {} replies never came", missed, sent));
+    }
+    if last.crashes > first.crashes {
+        problems.push(format!(
+            "{} crash(es) during the run",
+            last.crashes - first.crashes
+        ));
+    }
+    let heap_drop = first.heap_free.saturating_sub(last.heap_free);
+    if heap_drop > args.max_heap_drop {
+        problems.push(format!(
+            "free heap fell by {} bytes ({} -> {})",
+            heap_drop, first.heap_free, last.heap_free
+        ));
+    }
+    if last.nvs_free < first.nvs_free {
+        problems.push(format!(
+            "{} NVS entries used up by read-only traffic",
+            first.nvs_free - last.nvs_free
+        ));
+    }
+    if problems.is_empty() {
+        println!(
+            "Soak passed: {} commands, no leaks, crashes or lost replies",
+            sent
+        );
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("FAIL {}", problem);
+    }
+    Err(anyhow!("soak failed (seed {})", seed))
+}
+
+/// Whether `reply` answers `command`: the right response, or an error the
+/// command may get on some builds
+fn check_reply(command: &Command, reply: &str, pubkey: &str) -> Result<()> {
+    let response =
+        Response::parse(reply).ok_or_else(|| anyhow!("unparseable reply {:?}", reply))?;
+    let answers = match (command, response) {
+        (Command::Ping, Response::Pong) => true,
+        (Command::GetPubkey, Response::Pubkey(key)) => {
+            if key != pubkey {
+                return Err(anyhow!("pubkey changed to {}", key));
+            }
+            true
+        }
+        (Command::GetInfo, Response::Info(_)) => true,
+        (Command::GetDeviceId, Response::DeviceId { .. }) => true,
+        (Command::GetFeatures, Response::Features(_)) => true,
+        (Command::TxInfo, Response::TxInfo(_)) => true,
+        (Command::GetPolicy, Response::Policy(_)) => true,
+        (Command::GetPolicy, Response::ErrorDetail(ErrorCode::Policy, _)) => true,
+        (Command::OtpStatus, Response::OtpStatus(_)) => true,
+        (Command::OtpStatus, Response::Error(ErrorCode::OtpDisabled)) => true,
+        (Command::Telemetry, Response::Telemetry(_)) => true,
+        // Firmware built without the command
+        (_, Response::Error(ErrorCode::UnknownCommand)) => true,
+        _ => false,
+    };
+    if !answers {
+        return Err(anyhow!("{} got {:?}", command.name(), reply));
+    }
+    Ok(())
+}
+
+fn diag(device: &mut Device) -> Result<Sample> {
+    let fields = device.request(Command::Diag, REPLY_IDLE_SECS, |response| match response {
+        Response::Diag(fields) => Some(fields.to_string()),
+        _ => None,
+    })?;
+    Ok(Sample::parse(&fields))
+}
+
+fn print_row(elapsed: Duration, sent: u64, missed: u64, sample: &Sample) {
+    let secs = elapsed.as_secs();
+    println!(
+        "{:>8} {:>10} {:>6} {:>10} {:>10} {:>8} {:>7}",
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60),
+        sent,
+        missed,
+        sample.heap_free,
+        sample.heap_min,
+        sample.nvs_free,
+        sample.crashes
+    )
+}
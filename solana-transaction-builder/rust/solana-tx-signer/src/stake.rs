@@ -0,0 +1,387 @@
+//! Stake accounts with the device key as both stake and withdraw authority.
+//!
+//! New stake accounts are derived from the device key and a seed
+//! (`create_account_with_seed`), so the device is the only signer needed and
+//! no throwaway keypair has to be generated or kept. Accounts split off an
+//! existing one are derived the same way.
+
+use anyhow::{anyhow, bail, Result};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    instruction::Instruction,
+    pubkey::Pubkey,
+    stake::{
+        self, instruction as stake_instruction,
+        state::{Authorized, Lockup, Meta, StakeStateV2},
+    },
+    system_instruction,
+};
+
+use crate::amount::format_sol;
+use crate::config::Settings;
+use crate::device::Device;
+use crate::send;
+
+/// Address of the stake account `seed` derives from the device key
+fn stake_address(device_key: &Pubkey, seed: &str) -> Result<Pubkey> {
+    Pubkey::create_with_seed(device_key, seed, &stake::program::id())
+        .map_err(|e| anyhow!("invalid stake seed '{}': {}", seed, e))
+}
+
+/// Funds a new stake account with `lamports` from the device's account, and
+/// delegates it to `vote` right away when given
+pub fn create(
+    device: &mut Device,
+    settings: &Settings,
+    seed: &str,
+    lamports: u64,
+    vote: Option<&Pubkey>,
+) -> Result<()> {
+    let client = client(settings);
+    let device_key = device.pubkey()?;
+    let stake_account = stake_address(&device_key, seed)?;
+
+    let rent_exempt = client.get_minimum_balance_for_rent_exemption(StakeStateV2::size_of())?;
+    if lamports <= rent_exempt {
+        return Err(anyhow!(
+            "a stake account needs more than {} lamports (its rent-exempt reserve)",
+            rent_exempt
+        ));
+    }
+    if client
+        .get_account_with_commitment(&stake_account, settings.commitment)?
+        .value
+        .is_some()
+    {
+        return Err(anyhow!(
+            "stake account {} (seed '{}') already exists",
+            stake_account,
+            seed
+        ));
+    }
+
+    let authorized = Authorized::auto(&device_key);
+    let instructions = match vote {
+        Some(vote) => stake_instruction::create_account_with_seed_and_delegate_stake(
+            &device_key,
+            &stake_account,
+            &device_key,
+            seed,
+            vote,
+            &authorized,
+            &Lockup::default(),
+            lamports,
+        ),
+        None => stake_instruction::create_account_with_seed(
+            &device_key,
+            &stake_account,
+            &device_key,
+            seed,
+            &authorized,
+            &Lockup::default(),
+            lamports,
+        ),
+    };
+
+    println!(
+        "Creating stake account {} (seed '{}') with {} lamports",
+        stake_account, seed, lamports
+    );
+    if let Some(vote) = vote {
+        println!("Delegating to vote account {}", vote);
+    }
+    submit(&client, device, settings, &device_key, &instructions)
+}
+
+pub fn delegate(
+    device: &mut Device,
+    settings: &Settings,
+    stake_account: &Pubkey,
+    vote: &Pubkey,
+) -> Result<()> {
+    let client = client(settings);
+    let device_key = device.pubkey()?;
+    println!("Delegating {} to vote account {}", stake_account, vote);
+    let instruction = stake_instruction::delegate_stake(stake_account, &device_key, vote);
+    submit(&client, device, settings, &device_key, &[instruction])
+}
+
+pub fn deactivate(device: &mut Device, settings: &Settings, stake_account: &Pubkey) -> Result<()> {
+    let client = client(settings);
+    let device_key = device.pubkey()?;
+    println!("Deactivating {}", stake_account);
+    let instruction = stake_instruction::deactivate_stake(stake_account, &device_key);
+    submit(&client, device, settings, &device_key, &[instruction])
+}
+
+/// Withdraws `lamports` (or everything, when `None`) from an inactive stake
+/// account to `to`, defaulting to the device's own account
+pub fn withdraw(
+    device: &mut Device,
+    settings: &Settings,
+    stake_account: &Pubkey,
+    to: Option<&Pubkey>,
+    lamports: Option<u64>,
+) -> Result<()> {
+    let client = client(settings);
+    let device_key = device.pubkey()?;
+    let to = to.copied().unwrap_or(device_key);
+    let lamports = match lamports {
+        Some(lamports) => lamports,
+        None => client
+            .get_balance(stake_account)
+            .map_err(|e| anyhow!("Failed to fetch balance of {}: {}", stake_account, e))?,
+    };
+    println!(
+        "Withdrawing {} lamports from {} to {}",
+        lamports, stake_account, to
+    );
+    let instruction = stake_instruction::withdraw(stake_account, &device_key, &to, lamports, None);
+    submit(&client, device, settings, &device_key, &[instruction])
+}
+
+/// Moves `lamports` of `stake_account` into a new stake account derived from
+/// the device key and `seed`. The new account gets the same authorities,
+/// lockup and delegation; its rent-exempt reserve comes from the device's
+/// account, as the stake program requires when splitting active stake.
+pub fn split(
+    device: &mut Device,
+    settings: &Settings,
+    stake_account: &Pubkey,
+    lamports: u64,
+    seed: &str,
+) -> Result<()> {
+    let client = client(settings);
+    let device_key = device.pubkey()?;
+    let new_account = stake_address(&device_key, seed)?;
+    let (balance, state) = stake_state(&client, stake_account)?;
+    let meta = staked_by(&state, stake_account, &device_key)?;
+    if client
+        .get_account_with_commitment(&new_account, settings.commitment)?
+        .value
+        .is_some()
+    {
+        bail!(
+            "stake account {} (seed '{}') already exists",
+            new_account,
+            seed
+        );
+    }
+    let reserve = client.get_minimum_balance_for_rent_exemption(StakeStateV2::size_of())?;
+    let minimum_delegation = match state.delegation() {
+        Some(_) => client
+            .get_stake_minimum_delegation()
+            .map_err(|e| anyhow!("Failed to fetch the minimum delegation: {}", e))?,
+        None => 0,
+    };
+    check_split(
+        balance,
+        meta.rent_exempt_reserve,
+        lamports,
+        minimum_delegation,
+    )?;
+
+    println!(
+        "Splitting {} SOL from {} into {} (seed '{}'), plus its {} SOL rent-exempt reserve \
+         from the device's account",
+        format_sol(lamports),
+        stake_account,
+        new_account,
+        seed,
+        format_sol(reserve)
+    );
+    if let Some(delegation) = state.delegation() {
+        println!("Both stay delegated to {}", delegation.voter_pubkey);
+    }
+    if meta.lockup != Lockup::default() {
+        println!("The new account inherits the lockup of {}", stake_account);
+    }
+    println!(
+        "After the split: {} holds {} SOL, {} holds {} SOL",
+        stake_account,
+        format_sol(balance - lamports),
+        new_account,
+        format_sol(lamports + reserve)
+    );
+
+    let mut instructions = vec![system_instruction::transfer(
+        &device_key,
+        &new_account,
+        reserve,
+    )];
+    instructions.extend(stake_instruction::split_with_seed(
+        stake_account,
+        &device_key,
+        lamports,
+        &new_account,
+        &device_key,
+        seed,
+    ));
+    submit(&client, device, settings, &device_key, &instructions)
+}
+
+/// Merges `source` into `destination`, closing `source`. Both need the
+/// device as stake authority, the same authorities and lockup, and, when
+/// both are delegated, the same validator.
+pub fn merge(
+    device: &mut Device,
+    settings: &Settings,
+    destination: &Pubkey,
+    source: &Pubkey,
+) -> Result<()> {
+    if destination == source {
+        bail!("can't merge {} into itself", source);
+    }
+    let client = client(settings);
+    let device_key = device.pubkey()?;
+    let (destination_balance, destination_state) = stake_state(&client, destination)?;
+    let (source_balance, source_state) = stake_state(&client, source)?;
+    let destination_meta = staked_by(&destination_state, destination, &device_key)?;
+    let source_meta = staked_by(&source_state, source, &device_key)?;
+    if destination_meta.authorized != source_meta.authorized {
+        bail!(
+            "{} and {} have different stake or withdraw authorities",
+            destination,
+            source
+        );
+    }
+    if destination_meta.lockup != source_meta.lockup {
+        bail!("{} and {} have different lockups", destination, source);
+    }
+    if let (Some(a), Some(b)) = (destination_state.delegation(), source_state.delegation()) {
+        if a.voter_pubkey != b.voter_pubkey {
+            bail!(
+                "{} is delegated to {} and {} to {}; deactivate one first",
+                destination,
+                a.voter_pubkey,
+                source,
+                b.voter_pubkey
+            );
+        }
+    }
+
+    println!(
+        "Merging {} ({} SOL) into {} ({} SOL)",
+        source,
+        format_sol(source_balance),
+        destination,
+        format_sol(destination_balance)
+    );
+    println!(
+        "After the merge: {} holds {} SOL and {} is closed",
+        destination,
+        format_sol(destination_balance + source_balance),
+        source
+    );
+    let instructions = stake_instruction::merge(destination, source, &device_key);
+    submit(&client, device, settings, &device_key, &instructions)
+}
+
+/// Balance and state of a stake account
+fn stake_state(client: &RpcClient, address: &Pubkey) -> Result<(u64, StakeStateV2)> {
+    let account = client
+        .get_account(address)
+        .map_err(|e| anyhow!("Failed to fetch stake account {}: {}", address, e))?;
+    if account.owner != stake::program::id() {
+        bail!("{} is not a stake account", address);
+    }
+    let state = bincode::deserialize(&account.data)
+        .map_err(|_| anyhow!("{} is not a stake account", address))?;
+    Ok((account.lamports, state))
+}
+
+/// The account's meta, if it is initialized with the device as its stake
+/// authority
+fn staked_by(state: &StakeStateV2, address: &Pubkey, device_key: &Pubkey) -> Result<Meta> {
+    let meta = state
+        .meta()
+        .ok_or_else(|| anyhow!("stake account {} isn't initialized", address))?;
+    if meta.authorized.staker != *device_key {
+        bail!(
+            "{}'s stake authority is {}, not the device",
+            address,
+            meta.authorized.staker
+        );
+    }
+    Ok(meta)
+}
+
+/// Checks a split of `lamports` off an account holding `balance`: a partial
+/// split has to leave the source its reserve, and, for delegated stake, both
+/// accounts at least the minimum delegation
+fn check_split(balance: u64, reserve: u64, lamports: u64, minimum_delegation: u64) -> Result<()> {
+    if lamports == 0 || lamports > balance {
+        bail!(
+            "can't split {} SOL off an account holding {} SOL",
+            format_sol(lamports),
+            format_sol(balance)
+        );
+    }
+    if lamports == balance {
+        return Ok(());
+    }
+    let left = (balance - lamports).saturating_sub(reserve);
+    if balance - lamports < reserve || left < minimum_delegation {
+        bail!(
+            "the split would leave {} SOL staked in the source, less than its {} SOL reserve \
+             plus the {} SOL minimum delegation",
+            format_sol(left),
+            format_sol(reserve),
+            format_sol(minimum_delegation)
+        );
+    }
+    if lamports < minimum_delegation {
+        bail!(
+            "the new account needs at least the {} SOL minimum delegation",
+            format_sol(minimum_delegation)
+        );
+    }
+    Ok(())
+}
+
+fn client(settings: &Settings) -> RpcClient {
+    settings.rpc_client()
+}
+
+fn submit(
+    client: &RpcClient,
+    device: &mut Device,
+    settings: &Settings,
+    device_key: &Pubkey,
+    instructions: &[Instruction],
+) -> Result<()> {
+    let mut fee_payer = send::load_fee_payer(settings)?;
+    send::sign_and_submit(
+        client,
+        settings,
+        device,
+        device_key,
+        fee_payer.as_mut(),
+        None,
+        instructions,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESERVE: u64 = 2_282_880;
+    const SOL: u64 = 1_000_000_000;
+
+    #[test]
+    fn checks_splits() {
+        let balance = 10 * SOL + RESERVE;
+        assert!(check_split(balance, RESERVE, 4 * SOL, SOL).is_ok());
+        // The whole account moves
+        assert!(check_split(balance, RESERVE, balance, SOL).is_ok());
+        assert!(check_split(balance, RESERVE, balance + 1, SOL).is_err());
+        assert!(check_split(balance, RESERVE, 0, 0).is_err());
+        // Leaves the source below its minimum delegation, or eats its reserve
+        assert!(check_split(balance, RESERVE, 9 * SOL + 1, SOL).is_err());
+        assert!(check_split(balance, RESERVE, 10 * SOL + 1, 0).is_err());
+        // The new account would be below the minimum delegation
+        assert!(check_split(balance, RESERVE, SOL - 1, SOL).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+//! `swap`: token swaps routed by Jupiter. Jupiter quotes a route and builds
+//! the transaction; the quote is decoded into a summary (amount in, quoted
+//! and minimum amount out, slippage, price impact, route) that is shown next
+//! to the decoded instructions before the BOOT button press.
+//!
+//! Jupiter only routes mainnet liquidity, so this needs a mainnet --rpc-url.
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{pubkey::Pubkey, transaction::VersionedTransaction};
+use std::str::FromStr;
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::{amount, balance, confirm, fees, fiat, labels, network, send, token};
+
+/// Jupiter's public Swap API
+pub const DEFAULT_API_URL: &str = "https://lite-api.jup.ag/swap/v1";
+
+/// The fields of a quote shown to the user; the quote itself is passed back
+/// to Jupiter untouched
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Quote {
+    in_amount: String,
+    out_amount: String,
+    /// The least the swap may return after slippage
+    other_amount_threshold: String,
+    slippage_bps: u16,
+    price_impact_pct: String,
+    route_plan: Vec<RouteStep>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RouteStep {
+    swap_info: SwapInfo,
+    /// Share of the input sent down this step
+    percent: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct SwapInfo {
+    label: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SwapResponse {
+    swap_transaction: String,
+}
+
+/// A token to swap, as given on the command line
+struct Token {
+    mint: Pubkey,
+    decimals: u8,
+    name: String,
+}
+
+/// Swaps `amount` (in whole tokens) of `from` for `to` out of the device's
+/// account, allowing `slippage_bps` of slippage. Either side may be `SOL`.
+pub fn swap(
+    device: &mut Device,
+    settings: &Settings,
+    api_url: &str,
+    from: &str,
+    to: &str,
+    amount: &str,
+    slippage_bps: u16,
+) -> Result<()> {
+    if settings.dry_run {
+        return Err(anyhow!(
+            "swap needs a quote from Jupiter, which --dry-run doesn't contact"
+        ));
+    }
+    if settings.fee_payer.is_some() || settings.fee_payer_device.is_some() {
+        tracing::warn!(
+            "Jupiter builds swaps paid for by the device account; ignoring the fee payer"
+        );
+    }
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let input = token(&client, from)?;
+    let output = token(&client, to)?;
+    let raw_amount = amount::parse_units(amount, input.decimals)?;
+
+    let http = reqwest::blocking::Client::new();
+    let api_url = api_url.trim_end_matches('/');
+    let quote_response: Value = check(
+        http.get(format!("{}/quote", api_url))
+            .query(&[
+                ("inputMint", input.mint.to_string()),
+                ("outputMint", output.mint.to_string()),
+                ("amount", raw_amount.to_string()),
+                ("slippageBps", slippage_bps.to_string()),
+            ])
+            .send(),
+        "quote",
+    )?;
+    let quote: Quote = serde_json::from_value(quote_response.clone())
+        .map_err(|e| anyhow!("Unexpected quote from Jupiter: {}", e))?;
+
+    let swap: SwapResponse = serde_json::from_value(check(
+        http.post(format!("{}/swap", api_url))
+            .json(&json!({
+                "quoteResponse": quote_response,
+                "userPublicKey": device_key.to_string(),
+                "wrapAndUnwrapSol": true,
+                "dynamicComputeUnitLimit": true,
+            }))
+            .send(),
+        "swap",
+    )?)
+    .map_err(|e| anyhow!("Unexpected swap response from Jupiter: {}", e))?;
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(&swap.swap_transaction)
+        .map_err(|e| anyhow!("Jupiter returned a transaction that isn't base64: {}", e))?;
+    let mut transaction: VersionedTransaction = bincode::deserialize(&bytes)
+        .map_err(|e| anyhow!("Jupiter returned a malformed transaction: {}", e))?;
+
+    // Nothing but the device may have to sign, and it pays the fee
+    if transaction.message.static_account_keys().first() != Some(&device_key)
+        || transaction.signatures.len() != 1
+    {
+        return Err(anyhow!(
+            "Jupiter's transaction isn't signed by the device alone; not signing it"
+        ));
+    }
+    network::check_message(settings, &transaction.message, &device_key)?;
+
+    let mut summary = describe(&quote, &input, &output)?;
+    let prices = fiat::Prices::for_message(settings, &transaction.message);
+    summary.extend(confirm::describe_transaction(&transaction.message, &prices));
+    summary.extend(fees::preview(
+        &client,
+        &transaction.message,
+        &device_key,
+        &prices,
+    ));
+    let labels = labels::for_message(settings, &transaction.message);
+    let message_bytes = transaction.message.serialize();
+    transaction.signatures[0] = confirm::sign_labeled(device, &message_bytes, &summary, &labels)?;
+
+    match send::submit(&client, settings, &transaction)? {
+        send::Outcome::Confirmed(_) => Ok(()),
+        send::Outcome::Expired(signature) => Err(anyhow!(
+            "{} expired before it was confirmed; run swap again for a fresh quote",
+            signature
+        )),
+    }
+}
+
+/// `SOL` or a mint address, with the mint's decimals and a name to show
+fn token(client: &RpcClient, name: &str) -> Result<Token> {
+    if name.eq_ignore_ascii_case("sol") {
+        return Ok(Token {
+            mint: spl_token::native_mint::id(),
+            decimals: spl_token::native_mint::DECIMALS,
+            name: "SOL".to_string(),
+        });
+    }
+    let mint = Pubkey::from_str(name)
+        .map_err(|e| anyhow!("'{}' is neither SOL nor a mint: {}", name, e))?;
+    let (_, decimals) = token::mint_info(client, &mint)?;
+    let name = balance::token_symbol(client, name).unwrap_or_else(|| mint.to_string());
+    Ok(Token {
+        mint,
+        decimals,
+        name,
+    })
+}
+
+/// The body of a successful API call, or an error with Jupiter's message
+fn check(response: reqwest::Result<reqwest::blocking::Response>, call: &str) -> Result<Value> {
+    let response = response.map_err(|e| anyhow!("Jupiter {} request failed: {}", call, e))?;
+    let status = response.status();
+    let body = response
+        .text()
+        .map_err(|e| anyhow!("Jupiter {} request failed: {}", call, e))?;
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Jupiter {} request failed ({}): {}",
+            call,
+            status,
+            body
+        ));
+    }
+    serde_json::from_str(&body)
+        .map_err(|e| anyhow!("Unexpected {} response from Jupiter: {}", call, e))
+}
+
+/// The quote in the tokens' own units
+fn describe(quote: &Quote, input: &Token, output: &Token) -> Result<Vec<String>> {
+    let units = |amount: &str, token: &Token| -> Result<String> {
+        let amount: u64 = amount
+            .parse()
+            .map_err(|_| anyhow!("Unexpected amount '{}' in Jupiter's quote", amount))?;
+        Ok(format!(
+            "{} {}",
+            amount::format_units(amount, token.decimals),
+            token.name
+        ))
+    };
+    let route: Vec<String> = quote
+        .route_plan
+        .iter()
+        .map(|step| {
+            let label = step.swap_info.label.as_deref().unwrap_or("unknown");
+            match step.percent {
+                Some(percent) => format!("{} ({}%)", label, percent),
+                None => label.to_string(),
+            }
+        })
+        .collect();
+    Ok(vec![
+        format!(
+            "Swap {} for {} (at least {} with {}% slippage)",
+            units(&quote.in_amount, input)?,
+            units(&quote.out_amount, output)?,
+            units(&quote.other_amount_threshold, output)?,
+            f64::from(quote.slippage_bps) / 100.0
+        ),
+        format!("Price impact: {}%", price_impact(&quote.price_impact_pct)),
+        format!("Route: {}", route.join(" -> ")),
+    ])
+}
+
+/// Jupiter gives the impact as a fraction, e.g. "0.0012" for 0.12%
+fn price_impact(fraction: &str) -> String {
+    match fraction.parse::<f64>() {
+        Ok(fraction) => format!("{:.2}", fraction * 100.0),
+        Err(_) => fraction.to_string(),
+    }
+}
@@ -0,0 +1,272 @@
+//! `sweep-rent`: closes the device key's empty token accounts, under both the
+//! Token and Token-2022 programs, returning the rent they lock up.
+//!
+//! `sweep`: sends all of the device's SOL to one address, for retiring a
+//! wallet. The compute budget is fixed before the amount is worked out, so
+//! the fee the device's account pays is known exactly and nothing is left.
+
+use anyhow::{anyhow, bail, Result};
+use solana_account_decoder::UiAccountData;
+use solana_client::rpc_request::TokenAccountsFilter;
+use solana_sdk::{
+    compute_budget::ComputeBudgetInstruction,
+    message::{Message, VersionedMessage},
+    pubkey::Pubkey,
+    system_instruction, system_program,
+};
+use std::str::FromStr;
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::{amount, fees, send};
+
+/// Compute unit limit of a sweep without `--cu-limit`: the transfer's 150
+/// units and the compute budget instructions' own
+const SWEEP_CU_LIMIT: u32 = 450;
+
+/// Closes per transaction. Each adds an account key and a short instruction
+/// (about 40 bytes), so 20 stay well under the packet limit even with the
+/// compute budget instructions and a separate fee payer's signature.
+const CLOSES_PER_TRANSACTION: usize = 20;
+
+/// An empty token account the device may close
+struct Empty {
+    address: Pubkey,
+    program_id: Pubkey,
+    lamports: u64,
+}
+
+/// Closes every empty token account owned by the device key, sending the
+/// rent to `recipient` (default: the device's account). One transaction,
+/// and BOOT button press, per `CLOSES_PER_TRANSACTION` accounts.
+pub fn sweep(device: &mut Device, settings: &Settings, recipient: Option<&Pubkey>) -> Result<()> {
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let recipient = recipient.copied().unwrap_or(device_key);
+
+    let mut empty = Vec::new();
+    for program_id in [spl_token::id(), spl_token_2022::id()] {
+        let accounts = client
+            .get_token_accounts_by_owner(&device_key, TokenAccountsFilter::ProgramId(program_id))
+            .map_err(|e| anyhow!("Failed to fetch token accounts of {}: {}", device_key, e))?;
+        for keyed in accounts {
+            let UiAccountData::Json(parsed) = &keyed.account.data else {
+                continue;
+            };
+            let info = &parsed.parsed["info"];
+            if info["tokenAmount"]["amount"].as_str() != Some("0") {
+                continue;
+            }
+            if let Some(reason) = unclosable(info, &device_key) {
+                println!("Skipping {}: {}", keyed.pubkey, reason);
+                continue;
+            }
+            empty.push(Empty {
+                address: Pubkey::from_str(&keyed.pubkey)
+                    .map_err(|e| anyhow!("Bad token account {}: {}", keyed.pubkey, e))?,
+                program_id,
+                lamports: keyed.account.lamports,
+            });
+        }
+    }
+    if empty.is_empty() {
+        println!("No empty token accounts to close");
+        return Ok(());
+    }
+
+    let total: u64 = empty.iter().map(|account| account.lamports).sum();
+    println!(
+        "Closing {} empty token accounts, reclaiming {} SOL to {}",
+        empty.len(),
+        amount::format_sol(total),
+        recipient
+    );
+    let mut fee_payer = send::load_fee_payer(settings)?;
+    let batches = empty.chunks(CLOSES_PER_TRANSACTION);
+    let count = batches.len();
+    for (n, batch) in batches.enumerate() {
+        if count > 1 {
+            println!(
+                "Transaction {} of {} ({} accounts)",
+                n + 1,
+                count,
+                batch.len()
+            );
+        }
+        let instructions = batch
+            .iter()
+            .map(|account| {
+                spl_token_2022::instruction::close_account(
+                    &account.program_id,
+                    &account.address,
+                    &recipient,
+                    &device_key,
+                    &[],
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        send::sign_and_submit(
+            &client,
+            settings,
+            device,
+            &device_key,
+            fee_payer.as_mut(),
+            None,
+            &instructions,
+        )?;
+    }
+    Ok(())
+}
+
+/// Why an account holding no tokens still can't be closed by the device, as
+/// read from its jsonParsed info
+fn unclosable(info: &serde_json::Value, device_key: &Pubkey) -> Option<&'static str> {
+    if info["state"].as_str() == Some("frozen") {
+        return Some("frozen");
+    }
+    if let Some(authority) = info["closeAuthority"].as_str() {
+        if authority != device_key.to_string() {
+            return Some("another key is its close authority");
+        }
+    }
+    let withheld = info["extensions"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|extension| extension["extension"] == "transferFeeAmount")
+        .any(|extension| extension["state"]["withheldAmount"].as_u64() != Some(0));
+    if withheld {
+        return Some("it holds withheld transfer fees");
+    }
+    None
+}
+
+/// Sends the device account's whole balance, less the fee when it pays it,
+/// to `to`. The account is left empty, which closes it.
+pub fn sweep_all(device: &mut Device, settings: &Settings, to: &Pubkey) -> Result<()> {
+    if settings.dry_run {
+        bail!("A sweep needs the device's balance from the cluster; drop --dry-run");
+    }
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    if *to == device_key {
+        bail!("{} is the device's own account", to);
+    }
+    let mut fee_payer = send::load_fee_payer(settings)?;
+
+    let accounts = client
+        .get_multiple_accounts(&[device_key, *to])
+        .map_err(|e| anyhow!("Failed to fetch the sweep's accounts: {}", e))?;
+    let Some(account) = &accounts[0] else {
+        bail!("{} holds no SOL", device_key);
+    };
+    if account.owner != system_program::id() || !account.data.is_empty() {
+        bail!(
+            "{} holds data for program {}; a transfer can't empty it",
+            device_key,
+            account.owner
+        );
+    }
+
+    // Pinned, so the transaction built below pays exactly this fee
+    let transfer = system_instruction::transfer(&device_key, to, account.lamports);
+    let cu_limit = settings.cu_limit.unwrap_or(SWEEP_CU_LIMIT);
+    let priority_fee = match settings.priority_fee {
+        Some(fee) => fee,
+        None => fees::recent_priority_fee(&client, std::slice::from_ref(&transfer))?,
+    };
+    let fee = match fee_payer {
+        Some(_) => 0,
+        None => fees::max_fee(&VersionedMessage::Legacy(Message::new(
+            &[
+                ComputeBudgetInstruction::set_compute_unit_limit(cu_limit),
+                ComputeBudgetInstruction::set_compute_unit_price(priority_fee),
+                transfer,
+            ],
+            Some(&device_key),
+        ))),
+    };
+    let mut pinned = settings.clone();
+    pinned.cu_limit = Some(cu_limit);
+    pinned.priority_fee = Some(priority_fee);
+
+    let minimum = client
+        .get_minimum_balance_for_rent_exemption(0)
+        .map_err(|e| anyhow!("Failed to fetch the rent-exempt minimum: {}", e))?;
+    let recipient = accounts[1].as_ref().map_or(0, |account| account.lamports);
+    let lamports = sweep_amount(account.lamports, fee, recipient, minimum)?;
+    if let Some(owner) = accounts[1].as_ref().map(|account| account.owner) {
+        if owner != system_program::id() {
+            println!(
+                "Warning: {} is an account of program {}, not a wallet",
+                to, owner
+            );
+        }
+    }
+    println!(
+        "Sweeping {} SOL from {} to {} ({} SOL balance, {} SOL fee); the device's account \
+         will be empty",
+        amount::format_sol(lamports),
+        device_key,
+        to,
+        amount::format_sol(account.lamports),
+        amount::format_sol(fee)
+    );
+
+    send::sign_and_submit(
+        &client,
+        &pinned,
+        device,
+        &device_key,
+        fee_payer.as_mut(),
+        None,
+        &[system_instruction::transfer(&device_key, to, lamports)],
+    )?;
+    Ok(())
+}
+
+/// What a sweep of `balance` sends after `fee`, or why it can't: a
+/// recipient holding `recipient` lamports has to end up rent-exempt
+fn sweep_amount(balance: u64, fee: u64, recipient: u64, minimum: u64) -> Result<u64> {
+    let lamports = match balance.checked_sub(fee) {
+        Some(lamports) if lamports > 0 => lamports,
+        _ => bail!(
+            "The device holds {} lamports, no more than the {} lamport fee; nothing to sweep",
+            balance,
+            fee
+        ),
+    };
+    if recipient.saturating_add(lamports) < minimum {
+        bail!(
+            "The recipient would hold {} lamports, below the rent-exempt minimum of {}; \
+             the transfer would fail",
+            recipient.saturating_add(lamports),
+            minimum
+        );
+    }
+    Ok(lamports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMUM: u64 = 890_880;
+
+    #[test]
+    fn sweeps_everything_but_the_fee() {
+        assert_eq!(
+            sweep_amount(2_000_000, 5_001, 0, MINIMUM).unwrap(),
+            1_994_999
+        );
+        // A separate fee payer pays the fee
+        assert_eq!(sweep_amount(2_000_000, 0, 0, MINIMUM).unwrap(), 2_000_000);
+        assert!(sweep_amount(5_000, 5_000, MINIMUM, MINIMUM).is_err());
+        // Too little to create the recipient, but enough to top one up
+        assert!(sweep_amount(100_000, 5_000, 0, MINIMUM).is_err());
+        assert_eq!(
+            sweep_amount(100_000, 5_000, MINIMUM - 95_000, MINIMUM).unwrap(),
+            95_000
+        );
+    }
+}
@@ -0,0 +1,260 @@
+//! SPL token transfers, for mints owned by either the Token or the Token-2022
+//! program.
+//!
+//! Token-2022 mints may charge a transfer fee, which comes out of what the
+//! recipient gets, and recipient accounts may only accept transfers with a
+//! memo; both are handled and shown before the device is asked to sign.
+
+use anyhow::{anyhow, Result};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    account::Account as SolanaAccount,
+    instruction::{AccountMeta, Instruction},
+    program_pack::Pack,
+    pubkey,
+    pubkey::Pubkey,
+};
+use spl_associated_token_account::{
+    get_associated_token_address_with_program_id,
+    instruction::create_associated_token_account_idempotent,
+};
+use spl_token_2022::{
+    extension::{
+        memo_transfer::MemoTransfer, non_transferable::NonTransferable,
+        transfer_fee::TransferFeeConfig, transfer_hook::TransferHook, BaseStateWithExtensions,
+        StateWithExtensions,
+    },
+    state::{Account, Mint},
+};
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::{amount, send};
+
+const MEMO_PROGRAM_ID: Pubkey = pubkey!("MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr");
+
+/// Sends `amount` (in whole tokens, e.g. `1.5`) of `mint` from the device's
+/// associated token account to `to`. `to` may be a wallet, whose associated
+/// token account is created if missing, or a token account for `mint`.
+/// `memo` goes right before the transfer.
+pub fn transfer_token(
+    device: &mut Device,
+    settings: &Settings,
+    mint: &Pubkey,
+    to: &Pubkey,
+    amount: &str,
+    memo: Option<&str>,
+    nonce_account: Option<&Pubkey>,
+) -> Result<()> {
+    let client = settings.rpc_client();
+    let mut fee_payer = send::load_fee_payer(settings)?;
+    let esp32_pubkey = device.pubkey()?;
+    let payer = fee_payer.as_ref().map_or(esp32_pubkey, |p| p.pubkey());
+
+    let instructions =
+        transfer_instructions(settings, &esp32_pubkey, mint, to, amount, memo, &payer)?;
+    send::sign_and_submit(
+        &client,
+        settings,
+        device,
+        &esp32_pubkey,
+        fee_payer.as_mut(),
+        nonce_account,
+        &instructions,
+    )?;
+    Ok(())
+}
+
+/// A `TransferChecked` of `amount` (in whole tokens) of `mint` out of
+/// `owner`'s associated token account to `to`, preceded by creating the
+/// recipient's token account (paid by `payer`) if needed and by `memo`.
+/// For mints with a transfer fee it is a `TransferCheckedWithFee`, and the
+/// amount the recipient nets is printed.
+pub fn transfer_instructions(
+    settings: &Settings,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    to: &Pubkey,
+    amount: &str,
+    memo: Option<&str>,
+    payer: &Pubkey,
+) -> Result<Vec<Instruction>> {
+    let client = &settings.rpc_client();
+    let mint_account = fetch_mint(client, mint)?;
+    let program_id = mint_account.owner;
+    let state = unpack_mint(mint, &mint_account.data)?;
+    let decimals = state.base.decimals;
+    if state.get_extension::<NonTransferable>().is_ok() {
+        return Err(anyhow!("{} is a non-transferable token", mint));
+    }
+    if let Ok(hook) = state.get_extension::<TransferHook>() {
+        if let Some(hook_program) = Option::<Pubkey>::from(hook.program_id) {
+            return Err(anyhow!(
+                "{} runs transfer hook program {}, whose extra accounts transfer-token can't \
+                 resolve",
+                mint,
+                hook_program
+            ));
+        }
+    }
+    let raw_amount = amount::parse_units(amount, decimals)?;
+    let fee = match state.get_extension::<TransferFeeConfig>() {
+        Ok(config) => {
+            let epoch = client.get_epoch_info()?.epoch;
+            let basis_points = u16::from(config.get_epoch_fee(epoch).transfer_fee_basis_points);
+            let fee = config
+                .calculate_epoch_fee(epoch, raw_amount)
+                .ok_or_else(|| anyhow!("Failed to compute the transfer fee of {}", mint))?;
+            Some((fee, basis_points))
+        }
+        Err(_) => None,
+    };
+
+    let source = get_associated_token_address_with_program_id(owner, mint, &program_id);
+    let mut instructions = Vec::new();
+    let destination = destination(
+        client,
+        settings,
+        mint,
+        &program_id,
+        to,
+        payer,
+        &mut instructions,
+    )?;
+
+    match memo {
+        Some(memo) => instructions.push(Instruction {
+            program_id: MEMO_PROGRAM_ID,
+            accounts: vec![AccountMeta::new_readonly(*owner, true)],
+            data: memo.as_bytes().to_vec(),
+        }),
+        None if requires_memo(client, settings, &destination)? => {
+            return Err(anyhow!(
+                "{} only accepts transfers with a memo; pass --memo",
+                destination
+            ))
+        }
+        None => {}
+    }
+
+    instructions.push(match fee {
+        Some((fee, _)) => {
+            spl_token_2022::extension::transfer_fee::instruction::transfer_checked_with_fee(
+                &program_id,
+                &source,
+                mint,
+                &destination,
+                owner,
+                &[],
+                raw_amount,
+                decimals,
+                fee,
+            )?
+        }
+        None => spl_token_2022::instruction::transfer_checked(
+            &program_id,
+            &source,
+            mint,
+            &destination,
+            owner,
+            &[],
+            raw_amount,
+            decimals,
+        )?,
+    });
+
+    println!(
+        "Sending {} of mint {} ({} base units) from {} to {}",
+        amount, mint, raw_amount, source, destination
+    );
+    if let Some((fee, basis_points)) = fee {
+        println!(
+            "Warning: mint {} charges a {}% transfer fee of {}; the recipient receives {}",
+            mint,
+            f64::from(basis_points) / 100.0,
+            amount::format_units(fee, decimals),
+            amount::format_units(raw_amount.saturating_sub(fee), decimals)
+        );
+    }
+    Ok(instructions)
+}
+
+/// The program that owns `mint` (Token or Token-2022) and the mint's decimals
+pub fn mint_info(client: &RpcClient, mint: &Pubkey) -> Result<(Pubkey, u8)> {
+    let mint_account = fetch_mint(client, mint)?;
+    let decimals = unpack_mint(mint, &mint_account.data)?.base.decimals;
+    Ok((mint_account.owner, decimals))
+}
+
+/// The mint's account, checked to belong to a token program
+fn fetch_mint(client: &RpcClient, mint: &Pubkey) -> Result<SolanaAccount> {
+    let mint_account = client
+        .get_account(mint)
+        .map_err(|e| anyhow!("Failed to fetch mint {}: {}", mint, e))?;
+    spl_token_2022::check_spl_token_program_account(&mint_account.owner)
+        .map_err(|_| anyhow!("{} is not a token mint", mint))?;
+    Ok(mint_account)
+}
+
+fn unpack_mint<'a>(mint: &Pubkey, data: &'a [u8]) -> Result<StateWithExtensions<'a, Mint>> {
+    StateWithExtensions::<Mint>::unpack(data)
+        .map_err(|e| anyhow!("{} is not a token mint: {}", mint, e))
+}
+
+/// Whether the existing token account `account` only accepts transfers
+/// preceded by a memo (Token-2022's `MemoTransfer`)
+fn requires_memo(client: &RpcClient, settings: &Settings, account: &Pubkey) -> Result<bool> {
+    let Some(account) = client
+        .get_account_with_commitment(account, settings.commitment)?
+        .value
+    else {
+        return Ok(false);
+    };
+    Ok(StateWithExtensions::<Account>::unpack(&account.data)
+        .ok()
+        .and_then(|state| state.get_extension::<MemoTransfer>().ok().copied())
+        .is_some_and(|memo| bool::from(memo.require_incoming_transfer_memos)))
+}
+
+/// The token account to pay `to`: `to` itself when it already is a token
+/// account for `mint`, otherwise its associated token account. Pushes the
+/// instruction creating that account (paid by `payer`) when it's missing.
+pub fn destination(
+    client: &RpcClient,
+    settings: &Settings,
+    mint: &Pubkey,
+    program_id: &Pubkey,
+    to: &Pubkey,
+    payer: &Pubkey,
+    instructions: &mut Vec<Instruction>,
+) -> Result<Pubkey> {
+    let existing = client
+        .get_account_with_commitment(to, settings.commitment)?
+        .value;
+    match existing {
+        // Already a token account
+        Some(account) if account.owner == *program_id && account.data.len() >= Account::LEN => {
+            let state = StateWithExtensions::<Account>::unpack(&account.data)
+                .map_err(|e| anyhow!("{} is not a token account: {}", to, e))?;
+            if state.base.mint != *mint {
+                return Err(anyhow!("{} holds {}, not {}", to, state.base.mint, mint));
+            }
+            Ok(*to)
+        }
+        // A wallet: pay into its associated token account
+        _ => {
+            let ata = get_associated_token_address_with_program_id(to, mint, program_id);
+            let ata_exists = client
+                .get_account_with_commitment(&ata, settings.commitment)?
+                .value
+                .is_some();
+            if !ata_exists {
+                println!("Creating token account {} for {}", ata, to);
+                instructions.push(create_associated_token_account_idempotent(
+                    payer, to, mint, program_id,
+                ));
+            }
+            Ok(ata)
+        }
+    }
+}
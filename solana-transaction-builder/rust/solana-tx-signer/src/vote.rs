@@ -0,0 +1,201 @@
+//! Vote accounts with the device key as withdraw authority, so a validator's
+//! commission and rewards are controlled from the ESP32 rather than a
+//! keypair on the validator host.
+//!
+//! The vote account must already exist and name the device key as its
+//! authorized withdrawer; each command checks that before anything is signed.
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    instruction::Instruction,
+    pubkey::Pubkey,
+    vote::{
+        self, instruction as vote_instruction,
+        state::{VoteAuthorize, VoteState},
+    },
+};
+
+use crate::config::Settings;
+use crate::device::Device;
+use crate::{amount, send};
+
+/// Which vote account authority `vote authorize` hands over
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Authority {
+    /// Signs the validator's votes
+    Voter,
+    /// Withdraws rewards and changes the commission and authorities
+    Withdrawer,
+}
+
+/// Withdraws `lamports` from `vote_account` to `to` (default: the device's
+/// account). Without an amount, withdraws everything above the rent-exempt
+/// reserve, so the account stays open and keeps voting.
+pub fn withdraw(
+    device: &mut Device,
+    settings: &Settings,
+    vote_account: &Pubkey,
+    to: Option<&Pubkey>,
+    lamports: Option<u64>,
+) -> Result<()> {
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let (balance, reserve, _) = fetch(&client, vote_account, &device_key)?;
+    let available = balance.saturating_sub(reserve);
+    let lamports = match lamports {
+        Some(lamports) if lamports > available && lamports != balance => {
+            return Err(anyhow!(
+                "{} holds {} SOL above its rent-exempt reserve; withdraw at most that, or the \
+                 whole balance to close the account",
+                vote_account,
+                amount::format_sol(available)
+            ))
+        }
+        Some(lamports) => lamports,
+        None if available == 0 => {
+            return Err(anyhow!(
+                "{} holds nothing above its rent-exempt reserve",
+                vote_account
+            ))
+        }
+        None => available,
+    };
+    let to = to.copied().unwrap_or(device_key);
+    println!(
+        "Withdrawing {} SOL from vote account {} to {}",
+        amount::format_sol(lamports),
+        vote_account,
+        to
+    );
+    if lamports == balance {
+        println!("This empties and closes the vote account");
+    }
+    let instruction = vote_instruction::withdraw(vote_account, &device_key, lamports, &to);
+    submit(&client, device, settings, &device_key, &[instruction])
+}
+
+/// Makes `new` the vote account's voter or withdraw authority
+pub fn authorize(
+    device: &mut Device,
+    settings: &Settings,
+    vote_account: &Pubkey,
+    authority: Authority,
+    new: &Pubkey,
+) -> Result<()> {
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    fetch(&client, vote_account, &device_key)?;
+    let vote_authorize = match authority {
+        Authority::Voter => {
+            println!(
+                "Making {} the vote authority of {} from the next epoch",
+                new, vote_account
+            );
+            VoteAuthorize::Voter
+        }
+        Authority::Withdrawer => {
+            if *new == device_key {
+                return Err(anyhow!(
+                    "The device is already the withdraw authority of {}",
+                    vote_account
+                ));
+            }
+            println!(
+                "Making {} the withdraw authority of {}; the device will no longer control it",
+                new, vote_account
+            );
+            VoteAuthorize::Withdrawer
+        }
+    };
+    let instruction = vote_instruction::authorize(vote_account, &device_key, new, vote_authorize);
+    submit(&client, device, settings, &device_key, &[instruction])
+}
+
+/// Sets the vote account's commission, a percentage of inflation rewards
+pub fn update_commission(
+    device: &mut Device,
+    settings: &Settings,
+    vote_account: &Pubkey,
+    commission: u8,
+) -> Result<()> {
+    if commission > 100 {
+        return Err(anyhow!(
+            "Commission is a percentage; {} is over 100",
+            commission
+        ));
+    }
+    let client = settings.rpc_client();
+    let device_key = device.pubkey()?;
+    let (_, _, current) = fetch(&client, vote_account, &device_key)?;
+    if current == commission {
+        println!(
+            "The commission of {} is already {}%",
+            vote_account, commission
+        );
+        return Ok(());
+    }
+    // The vote program refuses commission increases in the second half of
+    // an epoch; say so up front rather than after a failed simulation
+    if commission > current {
+        let epoch = client
+            .get_epoch_info()
+            .map_err(|e| anyhow!("Failed to fetch the epoch: {}", e))?;
+        if epoch.slot_index * 2 > epoch.slots_in_epoch {
+            return Err(anyhow!(
+                "Commission can only be raised in the first half of an epoch; try again in \
+                 epoch {}",
+                epoch.epoch + 1
+            ));
+        }
+    }
+    println!(
+        "Changing the commission of {} from {}% to {}%",
+        vote_account, current, commission
+    );
+    let instruction = vote_instruction::update_commission(vote_account, &device_key, commission);
+    submit(&client, device, settings, &device_key, &[instruction])
+}
+
+/// The vote account's balance, rent-exempt reserve and commission, after
+/// checking the device is its withdraw authority
+fn fetch(client: &RpcClient, vote_account: &Pubkey, device_key: &Pubkey) -> Result<(u64, u64, u8)> {
+    let account = client
+        .get_account(vote_account)
+        .map_err(|e| anyhow!("Failed to fetch vote account {}: {}", vote_account, e))?;
+    if account.owner != vote::program::id() {
+        return Err(anyhow!("{} is not a vote account", vote_account));
+    }
+    let state = VoteState::deserialize(&account.data)
+        .map_err(|e| anyhow!("{} is not a vote account: {}", vote_account, e))?;
+    if state.authorized_withdrawer != *device_key {
+        return Err(anyhow!(
+            "The withdraw authority of {} is {}, not this device",
+            vote_account,
+            state.authorized_withdrawer
+        ));
+    }
+    let reserve = client.get_minimum_balance_for_rent_exemption(account.data.len())?;
+    Ok((account.lamports, reserve, state.commission))
+}
+
+fn submit(
+    client: &RpcClient,
+    device: &mut Device,
+    settings: &Settings,
+    device_key: &Pubkey,
+    instructions: &[Instruction],
+) -> Result<()> {
+    let mut fee_payer = send::load_fee_payer(settings)?;
+    send::sign_and_submit(
+        client,
+        settings,
+        device,
+        device_key,
+        fee_payer.as_mut(),
+        None,
+        instructions,
+    )?;
+    Ok(())
+}